@@ -0,0 +1,160 @@
+//! The CSRs of the Smaia and Ssaia extensions: the IMSIC interrupt file
+//! registers reached through miselect and siselect, the top external
+//! interrupt CSRs that claim from the files, and the top interrupt CSRs.
+
+use crate::cpu::*;
+use crate::imsic::*;
+use crate::interrupt::*;
+
+// The major interrupt priorities, iprio0 to iprio15
+const ISELECT_IPRIO0: u64 = 0x30;
+const ISELECT_IPRIO15: u64 = 0x3f;
+
+impl Cpu {
+    /// Whether mireg or sireg can be accessed with the register number held
+    /// in miselect or siselect. Only the even registers of a pair exist on
+    /// RV64, and the interrupt file ones only with IMSICs.
+    pub fn ireg_accessible(&self, addr: usize) -> bool {
+        let select = if addr == MIREG { self.csrs[MISELECT] } else { self.csrs[SISELECT] };
+        let pair_ok = self.xlen == 32 || select & 1 == 0;
+        match select {
+            ISELECT_IPRIO0..=ISELECT_IPRIO15 => pair_ok,
+            ISELECT_EIDELIVERY | ISELECT_EITHRESHOLD => self.imsic,
+            ISELECT_EIP0..=ISELECT_EIE63 => self.imsic && pair_ok,
+            _ => false,
+        }
+    }
+
+    /// Reads mireg or sireg. The priorities of the major interrupts are
+    /// read-only zero, leaving them in their default order.
+    pub fn load_ireg(&self, addr: usize) -> u64 {
+        let select = if addr == MIREG { self.csrs[MISELECT] } else { self.csrs[SISELECT] };
+        if select <= ISELECT_IPRIO15 {
+            return 0;
+        }
+        let mut bus = self.bus.lock().unwrap();
+        let imsic = bus.imsic.as_mut().expect("interrupt file registers without IMSICs");
+        imsic.file(self.hartid, aia_level(addr)).load(select, self.xlen)
+    }
+
+    pub fn store_ireg(&mut self, addr: usize, value: u64) {
+        let select = if addr == MIREG { self.csrs[MISELECT] } else { self.csrs[SISELECT] };
+        if select <= ISELECT_IPRIO15 {
+            return;
+        }
+        let mut bus = self.bus.lock().unwrap();
+        let imsic = bus.imsic.as_mut().expect("interrupt file registers without IMSICs");
+        imsic.file(self.hartid, aia_level(addr)).store(select, value, self.xlen);
+    }
+
+    /// Reads mtopei or stopei: the identity the hart's interrupt file of
+    /// that level would deliver next.
+    pub fn load_topei(&self, addr: usize) -> u64 {
+        let mut bus = self.bus.lock().unwrap();
+        let imsic = bus.imsic.as_mut().expect("mtopei and stopei without IMSICs");
+        imsic.file(self.hartid, aia_level(addr)).topei()
+    }
+
+    /// Any write to mtopei or stopei claims the identity it reads.
+    pub fn claim_topei(&mut self, addr: usize) {
+        let mut bus = self.bus.lock().unwrap();
+        let imsic = bus.imsic.as_mut().expect("mtopei and stopei without IMSICs");
+        imsic.file(self.hartid, aia_level(addr)).claim();
+    }
+
+    /// Reads mtopi, stopi or vstopi: the highest-priority interrupt pending
+    /// and enabled at that level, whether or not interrupts are globally
+    /// enabled. As the priority array is read-only zero, IPRIO is always 1.
+    pub fn load_topi(&self, addr: usize) -> u64 {
+        let pending = self.load_csr(MIP) & self.csrs[MIE];
+        let (candidates, shift) = match addr {
+            MTOPI => (pending & !self.csrs[MIDELEG], 0),
+            STOPI => (pending & self.csrs[MIDELEG] & !self.csrs[HIDELEG], 0),
+            // A guest sees its interrupts as the supervisor ones.
+            _ => (pending & self.csrs[HIDELEG] & VS_INTERRUPTS, 1),
+        };
+        Interrupt::ALL
+            .into_iter()
+            .find(|i| candidates >> i.code() & 1 == 1)
+            .map_or(0, |i| ((i.code() - shift) << 16) | 1)
+    }
+}
+
+/// The privilege level whose indirect registers and interrupt file an AIA
+/// CSR reaches.
+fn aia_level(addr: usize) -> u64 {
+    if matches!(addr, MIREG | MTOPEI) {
+        MACHINE
+    } else {
+        SUPERVISOR
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::*;
+    use crate::exception::*;
+    use crate::testing::*;
+
+    fn hart(interrupt_controller: InterruptController) -> Cpu {
+        let config = MachineConfig { interrupt_controller, ..virt() };
+        machine(&config, &[SPIN]).harts.remove(0)
+    }
+
+    fn message(cpu: &Cpu, addr: u64, id: u32) {
+        cpu.bus.lock().unwrap().imsic.as_mut().unwrap().message(addr, id);
+    }
+
+    #[test]
+    fn claims_from_the_interrupt_files() {
+        let mut cpu = hart(InterruptController::AplicImsic);
+        cpu.store_csr(MISELECT, ISELECT_EIE0);
+        cpu.store_csr(MIREG, 1 << 5 | 1 << 9);
+        assert_eq!(cpu.load_csr(MIREG), 1 << 5 | 1 << 9);
+        message(&cpu, IMSIC_BASE, 9);
+        message(&cpu, IMSIC_BASE, 5);
+        assert_eq!(cpu.load_csr(MTOPEI), 5 << 16 | 5);
+
+        cpu.store_csr(MISELECT, ISELECT_EIDELIVERY);
+        cpu.store_csr(MIREG, 1);
+        cpu.step().unwrap();
+        assert_ne!(cpu.load_csr(MIP) & MEIP_BIT, 0);
+        assert_eq!(cpu.load_csr(MTOPI), 0);
+        cpu.store_csr(MIE, MEIP_BIT);
+        assert_eq!(cpu.load_csr(MTOPI), 11 << 16 | 1);
+
+        // Any write claims the top identity.
+        cpu.store_csr(MTOPEI, 0);
+        assert_eq!(cpu.load_csr(MTOPEI), 9 << 16 | 9);
+        cpu.store_csr(MTOPEI, 0);
+        assert_eq!(cpu.load_csr(MTOPEI), 0);
+
+        // The S-level file is separate.
+        cpu.store_csr(SISELECT, ISELECT_EIE0);
+        cpu.store_csr(SIREG, 1 << 3);
+        message(&cpu, IMSIC_S_BASE, 3);
+        assert_eq!(cpu.load_csr(STOPEI), 3 << 16 | 3);
+        assert_eq!(cpu.load_csr(MTOPEI), 0);
+    }
+
+    #[test]
+    fn selects_only_registers_that_exist() {
+        let mut cpu = hart(InterruptController::AplicImsic);
+        let read = csrrs(T0, MIREG as u16, ZERO);
+        // The priorities read as zero; only the even ones exist on RV64,
+        // as with the interrupt file registers.
+        for (select, ok) in [(0x30, true), (0x31, false), (0x20, false), (ISELECT_EIP0 + 1, false), (0x100, false)] {
+            cpu.store_csr(MISELECT, select);
+            let expected = if ok { Ok(()) } else { Err(Exception::IllegalInstruction(read as u64)) };
+            assert_eq!(cpu.execute(read), expected, "{select:#x}");
+        }
+
+        // Without IMSICs there are no interrupt files to reach.
+        let mut cpu = hart(InterruptController::Aplic);
+        cpu.store_csr(MISELECT, ISELECT_EIDELIVERY);
+        assert_eq!(cpu.execute(read), Err(Exception::IllegalInstruction(read as u64)));
+        let topei = csrrs(T0, MTOPEI as u16, ZERO);
+        assert_eq!(cpu.execute(topei), Err(Exception::IllegalInstruction(topei as u64)));
+    }
+}
@@ -0,0 +1,810 @@
+use std::io;
+
+use crate::device::*;
+use crate::exception::*;
+use crate::fdt::*;
+use crate::imsic::*;
+use crate::snapshot::*;
+
+/// The M-level root domain, with the S-level domain it delegates to at
+/// `APLIC_S_BASE`, at the same addresses as on QEMU's `virt` board.
+pub const APLIC_BASE: u64 = 0x0c00_0000;
+pub const APLIC_S_BASE: u64 = 0x0d00_0000;
+const DOMAIN_SIZE: u64 = 0x8000;
+pub const APLIC_SIZE: u64 = APLIC_S_BASE - APLIC_BASE + DOMAIN_SIZE;
+/// Interrupt sources 1 to 95, the same as the PLIC's.
+pub const APLIC_SOURCES: u32 = 96;
+
+// Registers of a domain
+const DOMAINCFG: u64 = 0x0000;
+const SOURCECFG: u64 = 0x0004;
+const MMSIADDRCFG: u64 = 0x1bc0;
+const MMSIADDRCFGH: u64 = 0x1bc4;
+const SMSIADDRCFGH: u64 = 0x1bcc;
+const SETIP: u64 = 0x1c00;
+const SETIPNUM: u64 = 0x1cdc;
+const IN_CLRIP: u64 = 0x1d00;
+const CLRIPNUM: u64 = 0x1ddc;
+const SETIE: u64 = 0x1e00;
+const SETIENUM: u64 = 0x1edc;
+const CLRIE: u64 = 0x1f00;
+const CLRIENUM: u64 = 0x1fdc;
+const SETIPNUM_LE: u64 = 0x2000;
+const SETIPNUM_BE: u64 = 0x2004;
+const GENMSI: u64 = 0x3000;
+const TARGET: u64 = 0x3004;
+const IDC: u64 = 0x4000;
+const IDC_STRIDE: u64 = 0x20;
+
+// Registers of an interrupt delivery control structure
+const IDELIVERY: u64 = 0x00;
+const IFORCE: u64 = 0x04;
+const ITHRESHOLD: u64 = 0x08;
+const TOPI: u64 = 0x18;
+const CLAIMI: u64 = 0x1c;
+
+// domaincfg fields; the top byte reads as 0x80
+const DOMAINCFG_IE: u32 = 1 << 8;
+const DOMAINCFG_DM: u32 = 1 << 2;
+const DOMAINCFG_FIXED: u32 = 0x80 << 24;
+
+// sourcecfg fields: delegated to the child domain, or the source mode
+const SOURCECFG_D: u32 = 1 << 10;
+const SOURCECFG_SM: u32 = 0b111;
+const INACTIVE: u32 = 0;
+const DETACHED: u32 = 1;
+const EDGE1: u32 = 4;
+const EDGE0: u32 = 5;
+const LEVEL1: u32 = 6;
+const LEVEL0: u32 = 7;
+
+// msiaddrcfgh fields
+const MSIADDRCFGH_L: u32 = 1 << 31;
+const MSIADDRCFGH_MASK: u32 = 0x1f77_ffff;
+const SMSIADDRCFGH_MASK: u32 = 0x0070_0fff;
+
+/// An interrupt delivery control structure, through which a domain in
+/// direct delivery mode interrupts one hart.
+#[derive(Clone, Default)]
+struct Idc {
+    delivery: bool,
+    force: bool,
+    threshold: u32,
+}
+
+/// An interrupt domain: the sources it owns and how it delivers them.
+struct Domain {
+    config: u32,
+    sourcecfg: Vec<u32>,
+    /// The hart and priority, or the hart and MSI identity, of each source.
+    target: Vec<u32>,
+    pending: u128,
+    enabled: u128,
+    idcs: Vec<Idc>,
+}
+
+impl Domain {
+    fn new(harts: usize) -> Self {
+        Self {
+            config: 0,
+            sourcecfg: vec![0; APLIC_SOURCES as usize],
+            target: vec![0; APLIC_SOURCES as usize],
+            pending: 0,
+            enabled: 0,
+            idcs: vec![Idc::default(); harts],
+        }
+    }
+
+    /// The mode of a source, which is inactive unless the domain owns it.
+    fn mode(&self, source: u32) -> u32 {
+        let cfg = self.sourcecfg[source as usize];
+        match cfg & SOURCECFG_SM {
+            _ if cfg & SOURCECFG_D != 0 => INACTIVE,
+            DETACHED | EDGE1 | EDGE0 | LEVEL1 | LEVEL0 => cfg & SOURCECFG_SM,
+            _ => INACTIVE,
+        }
+    }
+
+    /// The sources the domain owns and has not switched off.
+    fn active(&self) -> u128 {
+        (1..APLIC_SOURCES)
+            .filter(|&source| self.mode(source) != INACTIVE)
+            .fold(0, |active, source| active | (1 << source))
+    }
+
+    fn msi_mode(&self) -> bool {
+        self.config & DOMAINCFG_DM != 0
+    }
+
+    /// The sources whose rectified input is high: the line as it is for
+    /// the modes triggered by a high level or a rising edge, inverted for
+    /// the others.
+    fn rectified(&self, lines: u128) -> u128 {
+        (1..APLIC_SOURCES).fold(0, |rectified, source| {
+            let line = lines >> source & 1 == 1;
+            let high = match self.mode(source) {
+                EDGE1 | LEVEL1 => line,
+                EDGE0 | LEVEL0 => !line,
+                _ => false,
+            };
+            rectified | ((high as u128) << source)
+        })
+    }
+
+    /// Sets or clears the pending bit of a source on behalf of software. A
+    /// level-triggered source follows its input in direct mode, and can
+    /// only be made pending while its input is high in MSI mode.
+    fn set_pending(&mut self, source: u32, pending: bool, lines: u128) {
+        if source == 0 || source >= APLIC_SOURCES {
+            return;
+        }
+        match self.mode(source) {
+            INACTIVE => return,
+            LEVEL1 | LEVEL0 if !self.msi_mode() => return,
+            LEVEL1 | LEVEL0 if pending && self.rectified(lines) >> source & 1 == 0 => return,
+            _ => {}
+        }
+        if pending {
+            self.pending |= 1 << source;
+        } else {
+            self.pending &= !(1 << source);
+        }
+    }
+
+    fn set_enabled(&mut self, source: u32, enabled: bool) {
+        if source == 0 || source >= APLIC_SOURCES || self.mode(source) == INACTIVE {
+            return;
+        }
+        if enabled {
+            self.enabled |= 1 << source;
+        } else {
+            self.enabled &= !(1 << source);
+        }
+    }
+
+    /// Follows the devices' lines from `old` to `new`: edges make sources
+    /// pending, and level-triggered ones track their input.
+    fn sample(&mut self, old: u128, new: u128) {
+        let (before, after) = (self.rectified(old), self.rectified(new));
+        for source in 1..APLIC_SOURCES {
+            let bit = 1 << source;
+            match self.mode(source) {
+                EDGE1 | EDGE0 if after & !before & bit != 0 => self.pending |= bit,
+                LEVEL1 | LEVEL0 if !self.msi_mode() => self.pending = (self.pending & !bit) | (after & bit),
+                LEVEL1 | LEVEL0 => {
+                    if after & !before & bit != 0 {
+                        self.pending |= bit;
+                    } else if after & bit == 0 {
+                        self.pending &= !bit;
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// The value of a hart's topi in direct mode: the pending and enabled
+    /// source aimed at it with the highest priority, the lowest number,
+    /// below the threshold if one is set. The lowest source wins a tie.
+    fn topi(&self, hartid: usize) -> u32 {
+        let threshold = self.idcs[hartid].threshold;
+        let candidates = self.pending & self.enabled;
+        if candidates == 0 {
+            return 0;
+        }
+        (1..APLIC_SOURCES)
+            .filter(|&source| candidates >> source & 1 == 1 && (self.target[source as usize] >> 18) as usize == hartid)
+            .map(|source| (self.target[source as usize] & 0xff, source))
+            .filter(|&(priority, _)| threshold == 0 || priority < threshold)
+            .min()
+            .map_or(0, |(priority, source)| (source << 16) | priority)
+    }
+
+    /// Whether the domain raises the external interrupt of a hart.
+    fn interrupting(&self, hartid: usize) -> bool {
+        let idc = &self.idcs[hartid];
+        self.config & DOMAINCFG_IE != 0
+            && !self.msi_mode()
+            && idc.delivery
+            && (idc.force || self.topi(hartid) != 0)
+    }
+
+    /// Reads claimi: topi, clearing the pending bit of the source it names,
+    /// or iforce if there is none.
+    fn claim(&mut self, hartid: usize) -> u32 {
+        let topi = self.topi(hartid);
+        if topi == 0 {
+            self.idcs[hartid].force = false;
+        } else if !matches!(self.mode(topi >> 16), LEVEL1 | LEVEL0) {
+            self.pending &= !(1 << (topi >> 16));
+        }
+        topi
+    }
+
+    fn save(&self, out: &mut StateWriter) {
+        out.u32(self.config);
+        for (&cfg, &target) in self.sourcecfg.iter().zip(&self.target) {
+            out.u32(cfg);
+            out.u32(target);
+        }
+        out.u128(self.pending);
+        out.u128(self.enabled);
+        out.u64(self.idcs.len() as u64);
+        for idc in &self.idcs {
+            out.bool(idc.delivery);
+            out.bool(idc.force);
+            out.u32(idc.threshold);
+        }
+    }
+
+    fn restore(&mut self, input: &mut StateReader) -> io::Result<()> {
+        self.config = input.u32()?;
+        for (cfg, target) in self.sourcecfg.iter_mut().zip(self.target.iter_mut()) {
+            *cfg = input.u32()?;
+            *target = input.u32()?;
+        }
+        self.pending = input.u128()?;
+        self.enabled = input.u128()?;
+        input.count(self.idcs.len(), "harts")?;
+        for idc in self.idcs.iter_mut() {
+            idc.delivery = input.bool()?;
+            idc.force = input.bool()?;
+            idc.threshold = input.u32()?;
+        }
+        Ok(())
+    }
+}
+
+/// Advanced platform-level interrupt controller of the AIA, with an M-level
+/// root domain and an S-level child domain it can delegate sources to.
+///
+/// Each domain delivers its sources either directly, raising the external
+/// interrupt of the target hart through an interrupt delivery control
+/// structure, or as MSIs to the harts' IMSIC interrupt files. MSI delivery
+/// can only be selected when the machine has IMSICs.
+pub struct Aplic {
+    /// The root domain, then the S-level one.
+    domains: [Domain; 2],
+    /// mmsiaddrcfg, mmsiaddrcfgh, smsiaddrcfg and smsiaddrcfgh of the root
+    /// domain.
+    msi_config: [u32; 4],
+    msi: bool,
+    /// Interrupt lines raised by the devices, updated by `Bus`.
+    lines: u128,
+    /// MSIs sent and not yet delivered by `Bus`, as addresses and data.
+    pub messages: Vec<(u64, u32)>,
+}
+
+impl Aplic {
+    /// An APLIC whose MSI address registers point at the IMSICs, if `msi`
+    /// is set, the way firmware would set them up.
+    pub fn new(harts: usize, msi: bool) -> Self {
+        let mut msi_config = [0; 4];
+        if msi {
+            // Hart h's file is page h: LHXW is the width of a hart number,
+            // and LHXS 0.
+            let lhxw = (usize::BITS - harts.saturating_sub(1).leading_zeros()).min(15);
+            msi_config = [(IMSIC_BASE >> 12) as u32, lhxw << 12, (IMSIC_S_BASE >> 12) as u32, 0];
+        }
+        Self {
+            domains: [Domain::new(harts), Domain::new(harts)],
+            msi_config,
+            msi,
+            lines: 0,
+            messages: Vec::new(),
+        }
+    }
+
+    /// Samples the devices' interrupt lines.
+    pub fn set_lines(&mut self, lines: u128) {
+        let old = self.lines;
+        self.lines = lines & !1;
+        if old != self.lines {
+            for domain in self.domains.iter_mut() {
+                domain.sample(old, self.lines);
+            }
+            self.forward();
+        }
+    }
+
+    /// Whether the root domain raises the machine external interrupt of a
+    /// hart, or the S-level domain its supervisor external interrupt.
+    pub fn interrupting(&self, hartid: usize, supervisor: bool) -> bool {
+        self.domains[supervisor as usize].interrupting(hartid)
+    }
+
+    /// Makes every source belong to the S-level domain, as firmware does
+    /// before it starts a kernel that drives the APLIC.
+    pub fn delegate_all(&mut self) {
+        for source in 1..APLIC_SOURCES {
+            self.write_sourcecfg(0, source, SOURCECFG_D);
+        }
+    }
+
+    /// The address of the MSI a domain sends to a hart: that of the hart's
+    /// interrupt file of the domain's level, laid out as the msiaddrcfg
+    /// registers describe.
+    fn msi_address(&self, domain: usize, hartid: u32) -> u64 {
+        let [m_low, m_high, s_low, s_high] = self.msi_config;
+        let lhxw = (m_high >> 12) & 0xf;
+        let hhxw = (m_high >> 16) & 0x7;
+        let hhxs = (m_high >> 24) & 0x1f;
+        let (ppn, lhxs) = if domain == 0 {
+            (((m_high as u64 & 0xfff) << 32) | m_low as u64, (m_high >> 20) & 0x7)
+        } else {
+            (((s_high as u64 & 0xfff) << 32) | s_low as u64, (s_high >> 20) & 0x7)
+        };
+        let group = (hartid >> lhxw) as u64 & ((1 << hhxw) - 1);
+        let hart = hartid as u64 & ((1 << lhxw) - 1);
+        (ppn | (group << (hhxs + 12)) | (hart << lhxs)) << 12
+    }
+
+    /// Sends the sources that are pending and enabled in the domains in MSI
+    /// mode as messages, which clears their pending bits.
+    fn forward(&mut self) {
+        for index in 0..2 {
+            let domain = &self.domains[index];
+            if !domain.msi_mode() || domain.config & DOMAINCFG_IE == 0 {
+                continue;
+            }
+            let ready = domain.pending & domain.enabled;
+            if ready == 0 {
+                continue;
+            }
+            for source in (1..APLIC_SOURCES).filter(|&source| ready >> source & 1 == 1) {
+                let target = self.domains[index].target[source as usize];
+                let message = (self.msi_address(index, target >> 18), target & 0x7ff);
+                self.messages.push(message);
+            }
+            self.domains[index].pending &= !ready;
+        }
+    }
+
+    /// Writes sourcecfg in a domain. The root domain hands a source over to
+    /// the child by setting D, and takes it back by clearing it; either
+    /// way, the domain that loses the source forgets its state.
+    fn write_sourcecfg(&mut self, index: usize, source: u32, value: u32) {
+        let s = source as usize;
+        let bit = 1 << source;
+        if index == 0 {
+            let value = if value & SOURCECFG_D != 0 { SOURCECFG_D } else { value & SOURCECFG_SM };
+            let was_delegated = self.domains[0].sourcecfg[s] & SOURCECFG_D != 0;
+            if was_delegated != (value & SOURCECFG_D != 0) {
+                let loser = &mut self.domains[was_delegated as usize];
+                loser.sourcecfg[s] = 0;
+                loser.target[s] = 0;
+                loser.pending &= !bit;
+                loser.enabled &= !bit;
+            }
+            self.domains[0].sourcecfg[s] = value;
+        } else if self.domains[0].sourcecfg[s] & SOURCECFG_D != 0 {
+            // The S-level domain has no children of its own.
+            self.domains[1].sourcecfg[s] = value & SOURCECFG_SM;
+        } else {
+            return;
+        }
+        let domain = &mut self.domains[index];
+        if domain.mode(source) == INACTIVE {
+            domain.pending &= !bit;
+            domain.enabled &= !bit;
+            domain.target[s] = 0;
+        } else if matches!(domain.mode(source), LEVEL1 | LEVEL0) && !domain.msi_mode() {
+            domain.pending = (domain.pending & !bit) | (domain.rectified(self.lines) & bit);
+        }
+    }
+
+    /// Writes target: the hart and priority in direct mode, where priority
+    /// 0 becomes 1, or the hart and MSI identity in MSI mode. There are no
+    /// guest files, so the guest index is always 0.
+    fn write_target(&mut self, index: usize, source: u32, value: u32) {
+        let domain = &mut self.domains[index];
+        if domain.mode(source) == INACTIVE {
+            return;
+        }
+        domain.target[source as usize] = if domain.msi_mode() {
+            value & 0xfffc_07ff
+        } else {
+            (value & 0xfffc_0000) | (value & 0xff).max(1)
+        };
+    }
+
+    fn read32(&mut self, index: usize, offset: u64) -> Option<u32> {
+        let lines = self.lines;
+        let domain = &mut self.domains[index];
+        let harts = domain.idcs.len() as u64;
+        let word = |bits: u128, offset: u64, base: u64| (bits >> ((offset - base) * 8)) as u32;
+        let value = match offset {
+            DOMAINCFG => DOMAINCFG_FIXED | domain.config,
+            SOURCECFG..MMSIADDRCFG => {
+                let source = (offset - SOURCECFG) / 4 + 1;
+                domain.sourcecfg.get(source as usize).copied().unwrap_or(0)
+            }
+            MMSIADDRCFG..=SMSIADDRCFGH if index == 0 && self.msi => self.msi_config[(offset - MMSIADDRCFG) as usize / 4],
+            SETIP..SETIPNUM if offset - SETIP < 12 => word(domain.pending & domain.active(), offset, SETIP),
+            IN_CLRIP..CLRIPNUM if offset - IN_CLRIP < 12 => word(domain.rectified(lines), offset, IN_CLRIP),
+            SETIE..SETIENUM if offset - SETIE < 12 => word(domain.enabled & domain.active(), offset, SETIE),
+            TARGET..IDC => {
+                let source = (offset - TARGET) / 4 + 1;
+                domain.target.get(source as usize).copied().unwrap_or(0)
+            }
+            IDC.. => {
+                let (hart, reg) = ((offset - IDC) / IDC_STRIDE, (offset - IDC) % IDC_STRIDE);
+                if hart >= harts {
+                    return None;
+                }
+                let hart = hart as usize;
+                let idc = &domain.idcs[hart];
+                match reg {
+                    IDELIVERY => idc.delivery as u32,
+                    IFORCE => idc.force as u32,
+                    ITHRESHOLD => idc.threshold,
+                    TOPI => domain.topi(hart),
+                    CLAIMI => domain.claim(hart),
+                    _ => 0,
+                }
+            }
+            // Write-only registers, such as the ones numbering a source,
+            // and reserved ones read as zero. genmsi is never busy.
+            _ => 0,
+        };
+        Some(value)
+    }
+
+    fn write32(&mut self, index: usize, offset: u64, value: u32) -> Option<()> {
+        let lines = self.lines;
+        let harts = self.domains[index].idcs.len() as u64;
+        let locked = self.msi_config[1] & MSIADDRCFGH_L != 0;
+        let domain = &mut self.domains[index];
+        let bits = |value: u32, offset: u64, base: u64| (value as u128) << ((offset - base) * 8);
+        match offset {
+            DOMAINCFG => {
+                let mask = if self.msi { DOMAINCFG_IE | DOMAINCFG_DM } else { DOMAINCFG_IE };
+                domain.config = value & mask;
+            }
+            SOURCECFG..MMSIADDRCFG => {
+                let source = (offset - SOURCECFG) / 4 + 1;
+                if source < APLIC_SOURCES as u64 {
+                    self.write_sourcecfg(index, source as u32, value);
+                }
+            }
+            MMSIADDRCFG..=SMSIADDRCFGH if index == 0 && self.msi && !locked => {
+                let reg = (offset - MMSIADDRCFG) as usize / 4;
+                self.msi_config[reg] = match offset {
+                    MMSIADDRCFGH => value & (MSIADDRCFGH_L | MSIADDRCFGH_MASK),
+                    SMSIADDRCFGH => value & SMSIADDRCFGH_MASK,
+                    _ => value,
+                };
+            }
+            SETIP..SETIPNUM if offset - SETIP < 12 => {
+                let set = bits(value, offset, SETIP);
+                for source in (1..APLIC_SOURCES).filter(|&source| set >> source & 1 == 1) {
+                    domain.set_pending(source, true, lines);
+                }
+            }
+            SETIPNUM | SETIPNUM_LE => domain.set_pending(value, true, lines),
+            SETIPNUM_BE => domain.set_pending(value.swap_bytes(), true, lines),
+            IN_CLRIP..CLRIPNUM if offset - IN_CLRIP < 12 => {
+                let clear = bits(value, offset, IN_CLRIP);
+                for source in (1..APLIC_SOURCES).filter(|&source| clear >> source & 1 == 1) {
+                    domain.set_pending(source, false, lines);
+                }
+            }
+            CLRIPNUM => domain.set_pending(value, false, lines),
+            SETIE..SETIENUM if offset - SETIE < 12 => {
+                let set = bits(value, offset, SETIE);
+                for source in (1..APLIC_SOURCES).filter(|&source| set >> source & 1 == 1) {
+                    domain.set_enabled(source, true);
+                }
+            }
+            SETIENUM => domain.set_enabled(value, true),
+            CLRIE..CLRIENUM if offset - CLRIE < 12 => {
+                let clear = bits(value, offset, CLRIE);
+                for source in (1..APLIC_SOURCES).filter(|&source| clear >> source & 1 == 1) {
+                    domain.set_enabled(source, false);
+                }
+            }
+            CLRIENUM => domain.set_enabled(value, false),
+            GENMSI if domain.msi_mode() => {
+                let message = (self.msi_address(index, value >> 18), value & 0x7ff);
+                self.messages.push(message);
+            }
+            TARGET..IDC => {
+                let source = (offset - TARGET) / 4 + 1;
+                if source < APLIC_SOURCES as u64 {
+                    self.write_target(index, source as u32, value);
+                }
+            }
+            IDC.. => {
+                let (hart, reg) = ((offset - IDC) / IDC_STRIDE, (offset - IDC) % IDC_STRIDE);
+                if hart >= harts {
+                    return None;
+                }
+                let idc = &mut domain.idcs[hart as usize];
+                match reg {
+                    IDELIVERY => idc.delivery = value & 1 == 1,
+                    IFORCE => idc.force = value & 1 == 1,
+                    ITHRESHOLD => idc.threshold = value & 0xff,
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+        self.forward();
+        Some(())
+    }
+
+    /// The domain whose registers hold byte `offset`, and the offset within
+    /// it.
+    fn domain_at(&self, offset: u64) -> Option<(usize, u64)> {
+        if offset < DOMAIN_SIZE {
+            Some((0, offset))
+        } else if offset >= APLIC_S_BASE - APLIC_BASE {
+            Some((1, offset - (APLIC_S_BASE - APLIC_BASE)))
+        } else {
+            None
+        }
+    }
+
+    fn describe_domain(&self, fdt: &mut Fdt, harts: usize, supervisor: bool) {
+        let (base, phandle, irq, imsic) = if supervisor {
+            (APLIC_S_BASE, IRQ_PHANDLE, 9, IMSIC_S_PHANDLE)
+        } else {
+            (APLIC_BASE, APLIC_M_PHANDLE, 11, IMSIC_M_PHANDLE)
+        };
+        fdt.begin_node(&format!("interrupt-controller@{:x}", base));
+        fdt.property_string("compatible", "riscv,aplic");
+        fdt.property_reg(base, DOMAIN_SIZE);
+        fdt.property_u32("#address-cells", 0);
+        fdt.property_u32("#interrupt-cells", 2);
+        fdt.property_null("interrupt-controller");
+        fdt.property_u32("riscv,num-sources", APLIC_SOURCES - 1);
+        fdt.property_u32("phandle", phandle);
+        if self.msi {
+            fdt.property_u32("msi-parent", imsic);
+        } else {
+            let cells: Vec<u32> = (0..harts).flat_map(|hart| [cpu_intc_phandle(hart), irq]).collect();
+            fdt.property_cells("interrupts-extended", &cells);
+        }
+        if !supervisor {
+            fdt.property_u32("riscv,children", IRQ_PHANDLE);
+            fdt.property_cells("riscv,delegation", &[IRQ_PHANDLE, 1, APLIC_SOURCES - 1]);
+        }
+        fdt.end_node();
+    }
+}
+
+impl Device for Aplic {
+    fn base(&self) -> u64 {
+        APLIC_BASE
+    }
+
+    fn size(&self) -> u64 {
+        APLIC_SIZE
+    }
+
+    fn device_tree(&self, fdt: &mut Fdt, harts: usize) {
+        self.describe_domain(fdt, harts, false);
+        self.describe_domain(fdt, harts, true);
+    }
+
+    fn load(&mut self, offset: u64, size: u64) -> Result<u64, Exception> {
+        let fault = Exception::LoadAccessFault(APLIC_BASE + offset);
+        let (index, offset) = self.domain_at(offset).ok_or(fault)?;
+        // Only whole registers can be read.
+        if offset & 3 != 0 || size != 32 {
+            return Err(fault);
+        }
+        self.read32(index, offset).map(|value| value as u64).ok_or(fault)
+    }
+
+    fn store(&mut self, offset: u64, size: u64, value: u64) -> Result<(), Exception> {
+        let fault = Exception::StoreAMOAccessFault(APLIC_BASE + offset);
+        let (index, offset) = self.domain_at(offset).ok_or(fault)?;
+        if offset & 3 != 0 || size != 32 {
+            return Err(fault);
+        }
+        self.write32(index, offset, value as u32).ok_or(fault)
+    }
+
+    fn dump_state(&self) -> Option<String> {
+        let mut state = format!("aplic: lines={:#x}", self.lines);
+        for (domain, level) in self.domains.iter().zip(['M', 'S']) {
+            let mode = if domain.msi_mode() { "msi" } else { "direct" };
+            state += &format!(
+                "\n  {}-level: ie={} {} active={:#x} pending={:#x} enabled={:#x}",
+                level,
+                (domain.config & DOMAINCFG_IE != 0) as u8,
+                mode,
+                domain.active(),
+                domain.pending,
+                domain.enabled
+            );
+            for (hart, idc) in domain.idcs.iter().enumerate().filter(|(_, idc)| idc.delivery) {
+                state += &format!(
+                    "\n    hart {}: ithreshold={} topi={:#x}",
+                    hart,
+                    idc.threshold,
+                    domain.topi(hart)
+                );
+            }
+        }
+        Some(state)
+    }
+
+    /// The lines are saved so that edges are not seen twice.
+    fn save(&self, out: &mut StateWriter) {
+        for domain in &self.domains {
+            domain.save(out);
+        }
+        for &config in &self.msi_config {
+            out.u32(config);
+        }
+        out.u128(self.lines);
+    }
+
+    fn restore(&mut self, input: &mut StateReader) -> io::Result<()> {
+        for domain in self.domains.iter_mut() {
+            domain.restore(input)?;
+        }
+        for config in self.msi_config.iter_mut() {
+            *config = input.u32()?;
+        }
+        self.lines = input.u128()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const S_DOMAIN: u64 = APLIC_S_BASE - APLIC_BASE;
+
+    fn read(aplic: &mut Aplic, offset: u64) -> u32 {
+        aplic.load(offset, 32).unwrap() as u32
+    }
+
+    fn write(aplic: &mut Aplic, offset: u64, value: u32) {
+        aplic.store(offset, 32, value as u64).unwrap();
+    }
+
+    fn sourcecfg(source: u64) -> u64 {
+        SOURCECFG + (source - 1) * 4
+    }
+
+    fn target(source: u64) -> u64 {
+        TARGET + (source - 1) * 4
+    }
+
+    fn idc(hart: u64, reg: u64) -> u64 {
+        IDC + hart * IDC_STRIDE + reg
+    }
+
+    #[test]
+    fn delivers_directly_by_priority() {
+        let mut aplic = Aplic::new(2, false);
+        write(&mut aplic, DOMAINCFG, DOMAINCFG_IE);
+        assert_eq!(read(&mut aplic, DOMAINCFG), DOMAINCFG_FIXED | DOMAINCFG_IE);
+        // Source 1 is a rising edge at priority 3, source 2 a high level at
+        // priority 2, both aimed at hart 1.
+        write(&mut aplic, sourcecfg(1), EDGE1);
+        write(&mut aplic, sourcecfg(2), LEVEL1);
+        write(&mut aplic, target(1), 1 << 18 | 3);
+        write(&mut aplic, target(2), 1 << 18 | 2);
+        assert_eq!(read(&mut aplic, target(1)), 1 << 18 | 3);
+        write(&mut aplic, SETIE, 0b110);
+        write(&mut aplic, idc(1, IDELIVERY), 1);
+
+        aplic.set_lines(0b110);
+        assert_eq!(read(&mut aplic, SETIP), 0b110);
+        assert_eq!(read(&mut aplic, IN_CLRIP), 0b110);
+        assert!(aplic.interrupting(1, false));
+        assert!(!aplic.interrupting(0, false) && !aplic.interrupting(1, true));
+        assert_eq!(read(&mut aplic, idc(1, TOPI)), 2 << 16 | 2);
+
+        // Claiming leaves a level-triggered source pending while its line
+        // is high, and only the threshold hides it.
+        assert_eq!(read(&mut aplic, idc(1, CLAIMI)), 2 << 16 | 2);
+        write(&mut aplic, idc(1, ITHRESHOLD), 2);
+        assert_eq!(read(&mut aplic, idc(1, TOPI)), 0);
+        write(&mut aplic, idc(1, ITHRESHOLD), 0);
+        aplic.set_lines(0b010);
+        assert_eq!(read(&mut aplic, idc(1, CLAIMI)), 1 << 16 | 3);
+        assert_eq!(read(&mut aplic, SETIP), 0);
+        assert!(!aplic.interrupting(1, false));
+
+        // Software can make an edge-triggered source pending, but not a
+        // level-triggered one in direct mode.
+        write(&mut aplic, SETIPNUM, 2);
+        write(&mut aplic, SETIPNUM_BE, 1u32.swap_bytes());
+        assert_eq!(read(&mut aplic, SETIP), 0b10);
+        write(&mut aplic, CLRIPNUM, 1);
+        write(&mut aplic, CLRIENUM, 2);
+        assert_eq!(read(&mut aplic, SETIE), 0b10);
+
+        // iforce interrupts with nothing pending until claimi is read.
+        write(&mut aplic, idc(1, IFORCE), 1);
+        assert!(aplic.interrupting(1, false));
+        assert_eq!(read(&mut aplic, idc(1, CLAIMI)), 0);
+        assert_eq!(read(&mut aplic, idc(1, IFORCE)), 0);
+    }
+
+    #[test]
+    fn delegates_sources_to_the_supervisor_domain() {
+        let mut aplic = Aplic::new(1, false);
+        // The S-level domain cannot touch sources it has not been given.
+        write(&mut aplic, S_DOMAIN + sourcecfg(5), EDGE1);
+        assert_eq!(read(&mut aplic, S_DOMAIN + sourcecfg(5)), 0);
+
+        write(&mut aplic, sourcecfg(5), SOURCECFG_D | EDGE1);
+        assert_eq!(read(&mut aplic, sourcecfg(5)), SOURCECFG_D);
+        write(&mut aplic, S_DOMAIN + DOMAINCFG, DOMAINCFG_IE);
+        write(&mut aplic, S_DOMAIN + sourcecfg(5), EDGE1);
+        write(&mut aplic, S_DOMAIN + target(5), 7);
+        write(&mut aplic, S_DOMAIN + SETIENUM, 5);
+        write(&mut aplic, S_DOMAIN + idc(0, IDELIVERY), 1);
+        // The root domain sees the source as inactive.
+        write(&mut aplic, SETIENUM, 5);
+        assert_eq!(read(&mut aplic, SETIE), 0);
+
+        aplic.set_lines(1 << 5);
+        assert!(aplic.interrupting(0, true));
+        assert!(!aplic.interrupting(0, false));
+        assert_eq!(read(&mut aplic, S_DOMAIN + idc(0, TOPI)), 5 << 16 | 7);
+
+        // Taking the source back clears everything the child knew of it.
+        write(&mut aplic, sourcecfg(5), EDGE0);
+        assert_eq!(read(&mut aplic, S_DOMAIN + sourcecfg(5)), 0);
+        assert_eq!(read(&mut aplic, S_DOMAIN + target(5)), 0);
+        assert!(!aplic.interrupting(0, true));
+    }
+
+    #[test]
+    fn sends_msis_to_the_interrupt_files() {
+        let mut aplic = Aplic::new(2, true);
+        assert_eq!(read(&mut aplic, MMSIADDRCFG), (IMSIC_BASE >> 12) as u32);
+        assert_eq!(read(&mut aplic, MMSIADDRCFGH), 1 << 12);
+        write(&mut aplic, DOMAINCFG, DOMAINCFG_IE | DOMAINCFG_DM);
+        write(&mut aplic, sourcecfg(3), EDGE0);
+        write(&mut aplic, target(3), 1 << 18 | 0x45);
+        write(&mut aplic, SETIENUM, 3);
+
+        // A falling edge sends the message, leaving nothing pending.
+        aplic.set_lines(1 << 3);
+        assert!(aplic.messages.is_empty());
+        aplic.set_lines(0);
+        assert_eq!(aplic.messages, [(IMSIC_BASE + 0x1000, 0x45)]);
+        assert_eq!(read(&mut aplic, SETIP), 0);
+        assert!(!aplic.interrupting(1, false));
+
+        // genmsi goes to the files of the domain's level, and the S-level
+        // domain's addresses follow smsiaddrcfg.
+        aplic.messages.clear();
+        write(&mut aplic, S_DOMAIN + DOMAINCFG, DOMAINCFG_DM);
+        write(&mut aplic, S_DOMAIN + GENMSI, 1 << 18 | 9);
+        write(&mut aplic, GENMSI, 12);
+        assert_eq!(aplic.messages, [(IMSIC_S_BASE + 0x1000, 9), (IMSIC_BASE, 12)]);
+
+        // Locking mmsiaddrcfgh freezes the address registers.
+        write(&mut aplic, MMSIADDRCFGH, MSIADDRCFGH_L | 1 << 12);
+        write(&mut aplic, MMSIADDRCFG, 0);
+        assert_eq!(read(&mut aplic, MMSIADDRCFG), (IMSIC_BASE >> 12) as u32);
+    }
+
+    #[test]
+    fn registers_are_whole_words() {
+        let mut aplic = Aplic::new(1, false);
+        // Without IMSICs, MSI mode cannot be chosen.
+        write(&mut aplic, DOMAINCFG, DOMAINCFG_DM);
+        assert_eq!(read(&mut aplic, DOMAINCFG), DOMAINCFG_FIXED);
+        assert_eq!(read(&mut aplic, MMSIADDRCFG), 0);
+
+        assert!(aplic.load(DOMAINCFG, 64).is_err());
+        assert!(aplic.load(sourcecfg(1) + 2, 32).is_err());
+        assert!(aplic.store(sourcecfg(1), 16, 0).is_err());
+        // Between the domains, and past the last IDC
+        assert!(aplic.load(DOMAIN_SIZE, 32).is_err());
+        assert!(aplic.load(idc(1, IDELIVERY), 32).is_err());
+        assert!(aplic.store(S_DOMAIN + idc(1, IDELIVERY), 32, 1).is_err());
+    }
+}
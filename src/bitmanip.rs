@@ -0,0 +1,243 @@
+use crate::cpu::*;
+use crate::exception::*;
+use crate::isa::*;
+
+impl Cpu {
+    /// Executes the Zba, Zbb, Zbc and Zbs instructions, which use encodings
+    /// of the OP, OP-IMM, OP-32 and OP-IMM-32 opcodes that the base ISA
+    /// leaves free. Extensions the ISA string leaves out are illegal, and
+    /// other encodings go on to the cryptography extensions.
+    pub fn execute_bitmanip(&mut self, instruction: u32) -> Result<(), Exception> {
+        let opcode = instruction & 0x7f;
+        let rd = ((instruction >> 7) & 0x1f) as usize;
+        let rs1 = ((instruction >> 15) & 0x1f) as usize;
+        let rs2 = ((instruction >> 20) & 0x1f) as usize;
+        let funct3 = (instruction >> 12) & 0x7;
+        let funct7 = (instruction >> 25) & 0x7f;
+        let imm = (instruction >> 20) & 0xfff;
+
+        let xlen = self.xlen;
+        let rv64 = xlen == 64;
+        let (zba, zbb, zbc, zbs) = (self.isa.has(ZBA), self.isa.has(ZBB), self.isa.has(ZBC), self.isa.has(ZBS));
+        // XLEN-bit operands, zero-extended
+        let a = self.truncate(self.regs[rs1]);
+        let b = self.truncate(self.regs[rs2]);
+        let word = a as u32 as u64;
+        // Shift amounts and bit indexes, from rs2 or the immediate
+        let index = (if matches!(opcode, 0x13 | 0x1b) { imm } else { b as u32 }) & (xlen - 1);
+        let bit = 1u64 << index;
+        let sext32 = |value: u32| value as i32 as i64 as u64;
+
+        self.regs[rd] = match (opcode, funct3, funct7) {
+            // Zba
+            // sh1add, sh2add, sh3add and their .uw forms
+            (0x33, 0x2 | 0x4 | 0x6, 0x10) if zba => (a << (funct3 >> 1)).wrapping_add(b),
+            (0x3b, 0x2 | 0x4 | 0x6, 0x10) if zba => (word << (funct3 >> 1)).wrapping_add(b),
+            (0x3b, 0x0, 0x04) if zba => word.wrapping_add(b), // add.uw
+            (0x1b, 0x1, 0x04 | 0x05) if zba => word << index, // slli.uw
+
+            // Zbb
+            (0x33, 0x7, 0x20) if zbb => a & !b,   // andn
+            (0x33, 0x6, 0x20) if zbb => a | !b,   // orn
+            (0x33, 0x4, 0x20) if zbb => !(a ^ b), // xnor
+            (0x13, 0x1, 0x30) if zbb => match rs2 {
+                0 => (a.leading_zeros() - (64 - xlen)) as u64, // clz
+                1 => a.trailing_zeros().min(xlen) as u64,      // ctz
+                2 => a.count_ones() as u64,                    // cpop
+                4 => a as i8 as i64 as u64,                    // sext.b
+                5 => a as i16 as i64 as u64,                   // sext.h
+                _ => return Err(Exception::IllegalInstruction(instruction as u64)),
+            },
+            (0x1b, 0x1, 0x30) if zbb => match rs2 {
+                0 => (word as u32).leading_zeros() as u64,  // clzw
+                1 => (word as u32).trailing_zeros() as u64, // ctzw
+                2 => word.count_ones() as u64,              // cpopw
+                _ => return Err(Exception::IllegalInstruction(instruction as u64)),
+            },
+            (0x33, 0x4..=0x7, 0x05) if zbb => {
+                let (sa, sb) = (self.regs[rs1] as i64, self.regs[rs2] as i64);
+                match funct3 {
+                    0x4 => sa.min(sb) as u64, // min
+                    0x5 => a.min(b),          // minu
+                    0x6 => sa.max(sb) as u64, // max
+                    _ => a.max(b),            // maxu
+                }
+            }
+            // zext.h is `pack` with rs2 = x0 in OP on RV32 and OP-32 on RV64.
+            (0x33, 0x4, 0x04) if zbb && !rv64 && rs2 == 0 => a & 0xffff,
+            (0x3b, 0x4, 0x04) if zbb && rs2 == 0 => a & 0xffff,
+            // rol, ror, rori and their word forms
+            (0x33, 0x1, 0x30) if zbb => rotate_left(a, index, xlen),
+            (0x33, 0x5, 0x30) | (0x13, 0x5, 0x30 | 0x31) if zbb => rotate_left(a, xlen - index, xlen),
+            (0x3b, 0x1, 0x30) if zbb => sext32((word as u32).rotate_left(b as u32 & 0x1f)),
+            (0x3b, 0x5, 0x30) if zbb => sext32((word as u32).rotate_right(b as u32 & 0x1f)),
+            (0x1b, 0x5, 0x30) if zbb => sext32((word as u32).rotate_right(imm & 0x1f)),
+            (0x13, 0x5, 0x14) if zbb && rs2 == 0x07 => {
+                // orc.b
+                (0..8).filter(|i| (a >> (i * 8)) & 0xff != 0).fold(0, |value, i| value | (0xff << (i * 8)))
+            }
+            (0x13, 0x5, 0x34 | 0x35) if zbb && imm == if rv64 { 0x6b8 } else { 0x698 } => {
+                // rev8
+                if rv64 {
+                    a.swap_bytes()
+                } else {
+                    (a as u32).swap_bytes() as u64
+                }
+            }
+
+            // Zbc
+            (0x33, 0x1..=0x3, 0x05) if zbc => {
+                let product = clmul(a, b);
+                match funct3 {
+                    0x1 => product as u64,                 // clmul
+                    0x2 => (product >> (xlen - 1)) as u64, // clmulr
+                    _ => (product >> xlen) as u64,         // clmulh
+                }
+            }
+
+            // Zbs
+            (0x33, 0x1, 0x24) | (0x13, 0x1, 0x24 | 0x25) if zbs => a & !bit,        // bclr, bclri
+            (0x33, 0x5, 0x24) | (0x13, 0x5, 0x24 | 0x25) if zbs => (a >> index) & 1, // bext, bexti
+            (0x33, 0x1, 0x34) | (0x13, 0x1, 0x34 | 0x35) if zbs => a ^ bit,         // binv, binvi
+            (0x33, 0x1, 0x14) | (0x13, 0x1, 0x14 | 0x15) if zbs => a | bit,         // bset, bseti
+
+            _ => return self.execute_crypto(instruction),
+        };
+        Ok(())
+    }
+}
+
+/// Rotates the low `xlen` bits of `value` left by `n` modulo `xlen`.
+fn rotate_left(value: u64, n: u32, xlen: u32) -> u64 {
+    if xlen == 32 {
+        (value as u32).rotate_left(n) as u64
+    } else {
+        value.rotate_left(n)
+    }
+}
+
+/// The full carry-less product of two 64-bit values.
+fn clmul(a: u64, b: u64) -> u128 {
+    (0..64).filter(|i| (b >> i) & 1 != 0).fold(0, |product, i| product ^ ((a as u128) << i))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::*;
+    use crate::testing::*;
+
+    fn hart(isa: &str) -> Cpu {
+        let (xlen, isa) = Isa::parse(isa).unwrap();
+        let config = MachineConfig { xlen: Some(xlen), isa, ..Default::default() };
+        machine(&config, &[]).harts.remove(0)
+    }
+
+    /// Runs `inst`, which reads t0 and t1 and writes t2, on `a` and `b`.
+    fn run(cpu: &mut Cpu, inst: u32, a: u64, b: u64) -> Result<u64, Exception> {
+        cpu.regs[T0 as usize] = cpu.sign_extend(a);
+        cpu.regs[T1 as usize] = cpu.sign_extend(b);
+        cpu.execute(inst)?;
+        Ok(cpu.truncate(cpu.regs[T2 as usize]))
+    }
+
+    fn op(funct3: u32, funct7: u32) -> u32 {
+        r_type(0x33, T2, funct3, T0, T1, funct7)
+    }
+
+    fn op32(funct3: u32, funct7: u32) -> u32 {
+        r_type(0x3b, T2, funct3, T0, T1, funct7)
+    }
+
+    fn op_imm(funct3: u32, imm: u32) -> u32 {
+        i_type(0x13, T2, funct3, T0, imm as i32)
+    }
+
+    fn op_imm32(funct3: u32, imm: u32) -> u32 {
+        i_type(0x1b, T2, funct3, T0, imm as i32)
+    }
+
+    #[test]
+    fn rv64_results() {
+        let cpu = &mut hart("rv64gc_zba_zbb_zbc_zbs");
+        let m = u64::MAX;
+        let cases = [
+            // Zba
+            (op(4, 0x10), 0x10, 3, 0x43),                                      // sh2add
+            (op32(2, 0x10), 0xffff_ffff_0000_0001, 4, 6),                      // sh1add.uw
+            (op32(0, 0x04), 0xffff_ffff_8000_0000, 1, 0x8000_0001),            // add.uw
+            (op_imm32(1, 0x080 | 4), 0xffff_ffff_8000_0000, 0, 0x8_0000_0000), // slli.uw
+            // Zbb
+            (op(7, 0x20), 0xff, 0x0f, 0xf0),                                     // andn
+            (op(6, 0x20), 0, 0, m),                                              // orn
+            (op(4, 0x20), 0xf0, 0xff, !0x0f),                                    // xnor
+            (op_imm(1, 0x600), 1, 0, 63),                                        // clz
+            (op_imm(1, 0x601), 0, 0, 64),                                        // ctz
+            (op_imm(1, 0x602), 0xff00, 0, 8),                                    // cpop
+            (op_imm(1, 0x604), 0x80, 0, !0x7f),                                  // sext.b
+            (op_imm(1, 0x605), 0x8000, 0, !0x7fff),                              // sext.h
+            (op_imm32(1, 0x600), 1, 0, 31),                                      // clzw
+            (op_imm32(1, 0x601), 0, 0, 32),                                      // ctzw
+            (op_imm32(1, 0x602), 0xffff_ffff_0000_00ff, 0, 8),                   // cpopw
+            (op(4, 0x05), m, 1, m),                                              // min
+            (op(5, 0x05), m, 1, 1),                                              // minu
+            (op(6, 0x05), m, 1, 1),                                              // max
+            (op(7, 0x05), m, 1, m),                                              // maxu
+            (op32(4, 0x04) & !(0x1f << 20), 0x12345, 0, 0x2345),                 // zext.h
+            (op(1, 0x30), 0x8000_0000_0000_0001, 1, 3),                          // rol
+            (op(5, 0x30), 1, 65, 1 << 63),                                       // ror
+            (op_imm(5, 0x600 | 4), 1, 0, 1 << 60),                               // rori
+            (op32(1, 0x30), 0x8000_0001, 1, 3),                                  // rolw
+            (op32(5, 0x30), 1, 1, 0xffff_ffff_8000_0000),                        // rorw
+            (op_imm32(5, 0x600 | 1), 1, 0, 0xffff_ffff_8000_0000),               // roriw
+            (op_imm(5, 0x287), 0x0100_0000_0002_0000, 0, 0xff00_0000_00ff_0000), // orc.b
+            (op_imm(5, 0x6b8), 0x0102_0304_0506_0708, 0, 0x0807_0605_0403_0201), // rev8
+            // Zbc
+            (op(1, 0x05), 0b101, 0b11, 0b1111),       // clmul
+            (op(3, 0x05), 1 << 63, 1 << 63, 1 << 62), // clmulh
+            (op(2, 0x05), 1 << 63, 1 << 63, 1 << 63), // clmulr
+            // Zbs
+            (op(1, 0x24), 0xff, 3, 0xf7),           // bclr
+            (op(5, 0x24), 0x8, 67, 1),              // bext, the index modulo XLEN
+            (op(1, 0x34), 0, 63, 1 << 63),          // binv
+            (op_imm(1, 0x280 | 40), 0, 0, 1 << 40), // bseti
+        ];
+        for (inst, a, b, result) in cases {
+            assert_eq!(run(cpu, inst, a, b), Ok(result), "{:#010x}", inst);
+        }
+    }
+
+    #[test]
+    fn rv32_results() {
+        let cpu = &mut hart("rv32gc_zba_zbb_zbc_zbs");
+        let cases = [
+            (op(2, 0x10), 0x8000_0000, 1, 1),                     // sh1add
+            (op_imm(1, 0x600), 1, 0, 31),                         // clz
+            (op_imm(1, 0x601), 0, 0, 32),                         // ctz
+            (op_imm(1, 0x602), 0xffff_ffff, 0, 32),               // cpop
+            (op(4, 0x04) & !(0x1f << 20), 0x12345, 0, 0x2345),    // zext.h
+            (op(1, 0x30), 0x8000_0001, 1, 3),                     // rol
+            (op_imm(5, 0x600 | 4), 1, 0, 1 << 28),                // rori
+            (op_imm(5, 0x698), 0x0102_0304, 0, 0x0403_0201),      // rev8
+            (op(3, 0x05), 0x8000_0000, 0x8000_0000, 0x4000_0000), // clmulh
+            (op(1, 0x34), 0, 35, 1 << 3),                         // binv
+        ];
+        for (inst, a, b, result) in cases {
+            assert_eq!(run(cpu, inst, a, b), Ok(result), "{:#010x}", inst);
+        }
+        // The RV64 encodings of rev8 and zext.h, and the word forms, are not
+        // in RV32.
+        for inst in [op_imm(5, 0x6b8), op32(4, 0x04) & !(0x1f << 20), op32(1, 0x30)] {
+            assert_eq!(run(cpu, inst, 0, 0), Err(Exception::IllegalInstruction(inst as u64)), "{:#010x}", inst);
+        }
+    }
+
+    #[test]
+    fn extensions_left_out_are_illegal() {
+        let cpu = &mut hart("rv64gc_zbb");
+        assert_eq!(run(cpu, op(7, 0x20), 0xff, 0x0f), Ok(0xf0));
+        for inst in [op(4, 0x10), op(1, 0x05), op(1, 0x24)] {
+            assert_eq!(run(cpu, inst, 0, 0), Err(Exception::IllegalInstruction(inst as u64)), "{:#010x}", inst);
+        }
+    }
+}
@@ -1,31 +1,478 @@
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::sync::{Arc, Mutex};
+
+use crate::aplic::*;
+use crate::clint::*;
+use crate::config::*;
+use crate::cpu::*;
+use crate::device::*;
 use crate::dram::*;
+use crate::entropy::*;
+use crate::exception::*;
+use crate::fdt::*;
+use crate::finisher::*;
+use crate::imsic::*;
+use crate::isa::*;
+use crate::plic::*;
+use crate::replay::*;
+use crate::snapshot::*;
+use crate::uart::*;
+use crate::virtio::*;
+use crate::virtio_9p::*;
+use crate::virtio_blk::*;
+use crate::virtio_console::*;
+use crate::virtio_net::*;
+use crate::virtio_rng::*;
 
 pub const DRAM_BASE: u64 = 0x8000_0000;
 
 pub struct Bus {
     dram: Dram,
+    regions: Vec<(RegionKind, Dram)>,
+    /// LR/SC reservation of each hart, as an 8-byte aligned address.
+    reservations: Vec<Option<u64>>,
+    pub clint: Option<Clint>,
+    /// Ticks since the machine started, the time base when there is no
+    /// CLINT.
+    ticks: u64,
+    pub plic: Option<Plic>,
+    /// The AIA interrupt controllers, which take the place of the PLIC.
+    pub aplic: Option<Aplic>,
+    pub imsic: Option<Imsic>,
+    /// The other memory-mapped peripherals.
+    pub devices: Vec<Box<dyn Device>>,
+    /// What the harts read from the Zkr `seed` CSR.
+    pub entropy: Option<Entropy>,
+    /// Where host input is recorded to or replayed from, if anywhere.
+    pub input_log: Option<SharedLog>,
 }
 
 impl Bus {
-    pub fn new(code: Vec<u8>) -> Self {
-        Self {
-            dram: Dram::new(code),
+    pub fn new(config: &MachineConfig, code: Vec<u8>) -> io::Result<Self> {
+        let mut regions = Vec::new();
+        for region in &config.regions {
+            let mut image = Vec::new();
+            if let Some(path) = &region.image {
+                File::open(path)?.read_to_end(&mut image)?;
+            }
+            if image.len() as u64 > region.size {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("image for region `{}` does not fit in {:#x} bytes", region.name, region.size),
+                ));
+            }
+            regions.push((region.kind, Dram::new(region.base, region.size, image)));
+        }
+
+        if code.len() as u64 > config.dram_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("program does not fit in {:#x} bytes of DRAM", config.dram_size),
+            ));
+        }
+
+        let input_log = match (&config.record, &config.replay) {
+            (Some(path), _) => Some(InputLog::create(path).map_err(|e| with_path(path, e))?),
+            (_, Some(path)) => Some(InputLog::open(path).map_err(|e| with_path(path, e))?),
+            _ => None,
+        };
+        let input_log = input_log.map(|log| Arc::new(Mutex::new(log)));
+        let log = input_log.as_ref();
+
+        let mut bus = Self {
+            dram: Dram::new(config.dram_base, config.dram_size, code),
+            regions,
+            reservations: vec![None; config.harts],
+            clint: None,
+            ticks: 0,
+            plic: None,
+            aplic: None,
+            imsic: None,
+            devices: Vec::new(),
+            entropy: None,
+            input_log: None,
+        };
+        if config.profile == Profile::Virt {
+            bus.clint = Some(Clint::new(config.harts));
+            match config.interrupt_controller {
+                InterruptController::Plic => bus.plic = Some(Plic::new(config.harts)),
+                InterruptController::Aplic => bus.aplic = Some(Aplic::new(config.harts, false)),
+                InterruptController::AplicImsic => {
+                    bus.aplic = Some(Aplic::new(config.harts, true));
+                    bus.imsic = Some(Imsic::new(config.harts));
+                }
+            }
+            // The monitor reads its commands from stdin.
+            bus.devices.push(Box::new(Uart::new(!config.monitor, log)));
+            bus.devices.push(Box::new(Finisher::new()));
+        }
+        let mut virtio: Vec<Box<dyn VirtioDevice>> = Vec::new();
+        for drive in &config.drives {
+            virtio.push(Box::new(Block::open(&drive.image, drive.overlay.as_deref())?));
+        }
+        if let Some(backend) = &config.net {
+            virtio.push(Box::new(Net::new(backend, config.mac, config.net_capture.as_deref(), log)?));
+        }
+        if !config.console_ports.is_empty() {
+            virtio.push(Box::new(Console::new(&config.console_ports, log)?));
+        }
+        if let Some(source) = config.rng {
+            virtio.push(Box::new(Rng::new(source, log)?));
+        }
+        for share in &config.shares {
+            virtio.push(Box::new(Share9p::new(share)?));
+        }
+        for (slot, device) in virtio.into_iter().enumerate() {
+            bus.devices.push(Box::new(VirtioMmio::new(slot, device)));
+        }
+        if config.isa.has(ZKR) {
+            bus.entropy = Some(Entropy::new(config.entropy, "seed", log)?);
+        }
+        bus.input_log = input_log;
+
+        Ok(bus)
+    }
+
+    /// Mutes or unmutes the output of every device.
+    pub fn mute(&mut self, muted: bool) {
+        for device in self.devices.iter_mut() {
+            device.mute(muted);
+        }
+    }
+
+    /// Writes memory and the state of every device to a snapshot.
+    pub fn save(&self, out: &mut StateWriter) {
+        out.memory(&self.dram.dram);
+        out.u64(self.regions.len() as u64);
+        for (_, region) in &self.regions {
+            out.memory(&region.dram);
+        }
+        out.u64(self.reservations.len() as u64);
+        for reservation in &self.reservations {
+            out.bool(reservation.is_some());
+            out.u64(reservation.unwrap_or(0));
+        }
+        out.u64(self.ticks);
+        if let Some(clint) = &self.clint {
+            clint.save(out);
+        }
+        if let Some(plic) = &self.plic {
+            plic.save(out);
+        }
+        if let Some(aplic) = &self.aplic {
+            aplic.save(out);
+        }
+        if let Some(imsic) = &self.imsic {
+            imsic.save(out);
+        }
+        out.u64(self.devices.len() as u64);
+        for device in &self.devices {
+            out.u64(device.base());
+            device.save(out);
+        }
+        if let Some(entropy) = &self.entropy {
+            entropy.save(out);
+        }
+    }
+
+    /// Restores what `save` wrote on a bus built from the same
+    /// configuration.
+    pub fn restore(&mut self, input: &mut StateReader) -> io::Result<()> {
+        input.memory(&mut self.dram.dram)?;
+        input.count(self.regions.len(), "memory regions")?;
+        for (_, region) in self.regions.iter_mut() {
+            input.memory(&mut region.dram)?;
+        }
+        input.count(self.reservations.len(), "harts")?;
+        for reservation in self.reservations.iter_mut() {
+            let held = input.bool()?;
+            let addr = input.u64()?;
+            *reservation = held.then_some(addr);
+        }
+        self.ticks = input.u64()?;
+        if let Some(clint) = self.clint.as_mut() {
+            clint.restore(input)?;
+        }
+        if let Some(plic) = self.plic.as_mut() {
+            plic.restore(input)?;
+        }
+        if let Some(aplic) = self.aplic.as_mut() {
+            aplic.restore(input)?;
+        }
+        if let Some(imsic) = self.imsic.as_mut() {
+            imsic.restore(input)?;
+        }
+        input.count(self.devices.len(), "devices")?;
+        for device in self.devices.iter_mut() {
+            if input.u64()? != device.base() {
+                return Err(invalid_snapshot("the devices are mapped differently"));
+            }
+            device.restore(input)?;
+        }
+        if let Some(entropy) = self.entropy.as_mut() {
+            entropy.restore(input)?;
+        }
+        Ok(())
+    }
+
+    /// Advances the machine timer.
+    pub fn tick(&mut self, ticks: u64) {
+        if let Some(clint) = self.clint.as_mut() {
+            clint.tick(ticks);
+        }
+        self.ticks = self.ticks.wrapping_add(ticks);
+        self.dma();
+    }
+
+    /// What the `time` CSR reads: the CLINT's `mtime`, or the ticks so far
+    /// on machines without one.
+    pub fn time(&self) -> u64 {
+        self.clint.as_ref().map_or(self.ticks, |clint| clint.mtime)
+    }
+
+    /// Lets the devices move data between the host and guest memory.
+    fn dma(&mut self) {
+        for device in self.devices.iter_mut() {
+            device.dma(&mut self.dram);
+        }
+    }
+
+    /// The interrupt lines driven into a hart by the CLINT and the PLIC, or
+    /// the APLIC and the hart's IMSIC interrupt files, as `mip` bits.
+    pub fn pending_interrupts(&mut self, hartid: usize) -> u64 {
+        let mut pending = 0;
+        if let Some(clint) = &self.clint {
+            if clint.msip[hartid] {
+                pending |= MSIP_BIT;
+            }
+            if clint.timer_pending(hartid) {
+                pending |= MTIP_BIT;
+            }
+        }
+        if self.plic.is_some() {
+            self.update_lines();
+            let plic = self.plic.as_ref().unwrap();
+            if plic.interrupting(2 * hartid) {
+                pending |= MEIP_BIT;
+            }
+            if plic.interrupting(2 * hartid + 1) {
+                pending |= SEIP_BIT;
+            }
+        }
+        if self.aplic.is_some() {
+            self.update_lines();
+            let aplic = self.aplic.as_ref().unwrap();
+            if aplic.interrupting(hartid, false) {
+                pending |= MEIP_BIT;
+            }
+            if aplic.interrupting(hartid, true) {
+                pending |= SEIP_BIT;
+            }
+        }
+        if let Some(imsic) = self.imsic.as_mut() {
+            if imsic.file(hartid, MACHINE).interrupting() {
+                pending |= MEIP_BIT;
+            }
+            if imsic.file(hartid, SUPERVISOR).interrupting() {
+                pending |= SEIP_BIT;
+            }
         }
+        pending
     }
 
-    pub fn load(&self, addr: u64, size: u64) -> Result<u64, ()> {
-        if DRAM_BASE <= addr {
+    /// Samples the interrupt lines of the devices into the PLIC or the
+    /// APLIC.
+    fn update_lines(&mut self) {
+        let mut lines = 0;
+        for device in self.devices.iter_mut() {
+            if let Some(irq) = device.irq() {
+                if device.interrupting() {
+                    lines |= 1 << irq;
+                }
+            }
+        }
+        if let Some(plic) = self.plic.as_mut() {
+            plic.lines = lines;
+        }
+        if let Some(aplic) = self.aplic.as_mut() {
+            aplic.set_lines(lines);
+            self.send_messages();
+        }
+    }
+
+    /// Delivers the MSIs the APLIC has sent. Only the IMSICs take them; a
+    /// message addressed anywhere else is lost, like a write to nothing.
+    fn send_messages(&mut self) {
+        let (Some(aplic), Some(imsic)) = (self.aplic.as_mut(), self.imsic.as_mut()) else {
+            return;
+        };
+        for (addr, data) in aplic.messages.drain(..) {
+            imsic.message(addr, data);
+        }
+    }
+
+    /// Adds the nodes of every device to the device tree.
+    pub fn describe(&self, fdt: &mut Fdt, harts: usize) {
+        if let Some(clint) = &self.clint {
+            clint.device_tree(fdt, harts);
+        }
+        if let Some(plic) = &self.plic {
+            plic.device_tree(fdt, harts);
+        }
+        if let Some(imsic) = &self.imsic {
+            imsic.device_tree(fdt, harts);
+        }
+        if let Some(aplic) = &self.aplic {
+            aplic.device_tree(fdt, harts);
+        }
+        for device in &self.devices {
+            device.device_tree(fdt, harts);
+        }
+    }
+
+    /// The state of every device that describes it, one per entry.
+    pub fn dump_state(&self) -> Vec<String> {
+        let clint = self.clint.iter().map(|clint| clint as &dyn Device);
+        let plic = self.plic.iter().map(|plic| plic as &dyn Device);
+        let aplic = self.aplic.iter().map(|aplic| aplic as &dyn Device);
+        let imsic = self.imsic.iter().map(|imsic| imsic as &dyn Device);
+        clint
+            .chain(plic)
+            .chain(aplic)
+            .chain(imsic)
+            .chain(self.devices.iter().map(|device| device.as_ref()))
+            .filter_map(|device| Some(format!("{:#010x} {}", device.base(), device.dump_state()?)))
+            .collect()
+    }
+
+    pub fn console_path(&self) -> Option<String> {
+        self.devices.iter().find_map(|device| device.stdout_path())
+    }
+
+    /// Exit status requested by the guest through a device, if any.
+    pub fn exit_status(&self) -> Option<i32> {
+        self.devices.iter().find_map(|device| device.exit_status())
+    }
+
+    /// The peripheral mapped at `[addr, last]`, if any.
+    fn device(&mut self, addr: u64, last: u64) -> Option<&mut dyn Device> {
+        let within = |device: &dyn Device| addr >= device.base() && last < device.base() + device.size();
+        if let Some(clint) = self.clint.as_mut().filter(|clint| within(*clint)) {
+            return Some(clint);
+        }
+        if let Some(plic) = self.plic.as_mut().filter(|plic| within(*plic)) {
+            return Some(plic);
+        }
+        if let Some(aplic) = self.aplic.as_mut().filter(|aplic| within(*aplic)) {
+            return Some(aplic);
+        }
+        if let Some(imsic) = self.imsic.as_mut().filter(|imsic| within(*imsic)) {
+            return Some(imsic);
+        }
+        for device in self.devices.iter_mut() {
+            if within(device.as_ref()) {
+                return Some(device.as_mut());
+            }
+        }
+        None
+    }
+
+    pub fn reserve(&mut self, hartid: usize, addr: u64) {
+        self.reservations[hartid] = Some(addr & !7);
+    }
+
+    /// Clears the hart's reservation and returns whether it covered `addr`.
+    pub fn take_reservation(&mut self, hartid: usize, addr: u64) -> bool {
+        self.reservations[hartid].take() == Some(addr & !7)
+    }
+
+    /// Whether `addr` is in DRAM or a memory region rather than a device.
+    pub fn is_memory(&self, addr: u64) -> bool {
+        self.dram.contains(addr) || self.regions.iter().any(|(_, mem)| mem.contains(addr))
+    }
+
+    pub fn load(&mut self, addr: u64, size: u64) -> Result<u64, Exception> {
+        let last = addr.wrapping_add(size / 8 - 1);
+        if self.dram.contains(addr) && self.dram.contains(last) {
             return self.dram.load(addr, size);
         }
+        for (_, mem) in &self.regions {
+            if mem.contains(addr) && mem.contains(last) {
+                return mem.load(addr, size);
+            }
+        }
+        let controller = self.plic.as_ref().map(|plic| plic as &dyn Device);
+        let controller = controller.or(self.aplic.as_ref().map(|aplic| aplic as &dyn Device));
+        if controller.is_some_and(|device| addr >= device.base() && addr < device.base() + device.size()) {
+            // A claim has to see the current lines.
+            self.update_lines();
+        }
+        if let Some(device) = self.device(addr, last) {
+            return device.load(addr - device.base(), size);
+        }
 
-        Err(())
+        Err(Exception::LoadAccessFault(addr))
     }
 
-    pub fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), ()> {
-        if DRAM_BASE <= addr {
+    pub fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
+        // Any store breaks the reservations that cover it, whichever hart
+        // made them.
+        for reservation in self.reservations.iter_mut() {
+            if *reservation == Some(addr & !7) {
+                *reservation = None;
+            }
+        }
+
+        let last = addr.wrapping_add(size / 8 - 1);
+        if self.dram.contains(addr) && self.dram.contains(last) {
             return self.dram.store(addr, size, value);
         }
+        for (kind, mem) in &mut self.regions {
+            if mem.contains(addr) && mem.contains(last) {
+                return match kind {
+                    RegionKind::Rom => Err(Exception::StoreAMOAccessFault(addr)),
+                    RegionKind::Sram => mem.store(addr, size, value),
+                };
+            }
+        }
+        if let Some(device) = self.device(addr, last) {
+            let result = device.store(addr - device.base(), size, value);
+            self.send_messages();
+            self.dma();
+            return result;
+        }
+
+        Err(Exception::StoreAMOAccessFault(addr))
+    }
+
+    /// Copies `data` to guest memory starting at `addr`.
+    pub fn write_bytes(&mut self, addr: u64, data: &[u8]) -> Result<(), Exception> {
+        for (i, byte) in data.iter().enumerate() {
+            self.store(addr.wrapping_add(i as u64), 8, *byte as u64)?;
+        }
+        Ok(())
+    }
+
+    /// Whether the `len` bytes from `addr` are all in DRAM or one other
+    /// memory region, so that a length the guest passes can be trusted with
+    /// a host buffer of that size.
+    pub fn is_memory_range(&self, addr: u64, len: u64) -> bool {
+        if len == 0 {
+            return true;
+        }
+        let Some(last) = addr.checked_add(len - 1) else {
+            return false;
+        };
+        (self.dram.contains(addr) && self.dram.contains(last))
+            || self.regions.iter().any(|(_, mem)| mem.contains(addr) && mem.contains(last))
+    }
 
-        Err(())
+    /// Reads `len` bytes of guest memory starting at `addr`.
+    pub fn read_bytes(&mut self, addr: u64, len: u64) -> Result<Vec<u8>, Exception> {
+        (0..len)
+            .map(|i| self.load(addr.wrapping_add(i), 8).map(|byte| byte as u8))
+            .collect()
     }
-}
\ No newline at end of file
+}
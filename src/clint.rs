@@ -0,0 +1,176 @@
+use std::io;
+
+use crate::device::*;
+use crate::exception::*;
+use crate::fdt::*;
+use crate::snapshot::*;
+
+pub const CLINT_BASE: u64 = 0x0200_0000;
+pub const CLINT_SIZE: u64 = 0x10000;
+
+const MSIP: u64 = 0x0;
+const MTIMECMP: u64 = 0x4000;
+const MTIME: u64 = 0xbff8;
+
+/// Core-local interruptor: the machine timer and software interrupts.
+///
+/// `mtime` advances with `tick` as instructions retire, so timer interrupts
+/// arrive at the same point on every run.
+pub struct Clint {
+    pub mtime: u64,
+    pub mtimecmp: Vec<u64>,
+    pub msip: Vec<bool>,
+}
+
+impl Clint {
+    pub fn new(harts: usize) -> Self {
+        Self {
+            mtime: 0,
+            mtimecmp: vec![u64::MAX; harts],
+            msip: vec![false; harts],
+        }
+    }
+
+    pub fn tick(&mut self, ticks: u64) {
+        self.mtime = self.mtime.wrapping_add(ticks);
+    }
+
+    pub fn timer_pending(&self, hartid: usize) -> bool {
+        self.mtime >= self.mtimecmp[hartid]
+    }
+
+    /// Finds the register holding byte `offset`, and the offset it starts at.
+    fn reg(&self, offset: u64) -> Option<(u64, Register)> {
+        let harts = self.msip.len() as u64;
+        match offset {
+            MSIP..=0x3fff if offset / 4 < harts => Some((offset & !3, Register::Msip((offset / 4) as usize))),
+            MTIMECMP..=0xbff7 if (offset - MTIMECMP) / 8 < harts => {
+                Some((offset & !7, Register::Mtimecmp(((offset - MTIMECMP) / 8) as usize)))
+            }
+            MTIME..=0xbfff => Some((MTIME, Register::Mtime)),
+            _ => None,
+        }
+    }
+}
+
+enum Register {
+    Msip(usize),
+    Mtimecmp(usize),
+    Mtime,
+}
+
+impl Device for Clint {
+    fn base(&self) -> u64 {
+        CLINT_BASE
+    }
+
+    fn size(&self) -> u64 {
+        CLINT_SIZE
+    }
+
+    fn device_tree(&self, fdt: &mut Fdt, harts: usize) {
+        fdt.begin_node(&format!("clint@{:x}", CLINT_BASE));
+        fdt.property_strings("compatible", &["sifive,clint0", "riscv,clint0"]);
+        fdt.property_reg(CLINT_BASE, CLINT_SIZE);
+        // Machine software and timer interrupts of every hart
+        let cells: Vec<u32> = (0..harts)
+            .flat_map(|hart| [cpu_intc_phandle(hart), 3, cpu_intc_phandle(hart), 7])
+            .collect();
+        fdt.property_cells("interrupts-extended", &cells);
+        fdt.end_node();
+    }
+
+    fn load(&mut self, offset: u64, size: u64) -> Result<u64, Exception> {
+        let (start, reg) = self.reg(offset).ok_or(Exception::LoadAccessFault(CLINT_BASE + offset))?;
+        let value = match reg {
+            Register::Msip(hart) => self.msip[hart] as u64,
+            Register::Mtimecmp(hart) => self.mtimecmp[hart],
+            Register::Mtime => self.mtime,
+        };
+        Ok(read_reg(value, offset - start, size))
+    }
+
+    fn store(&mut self, offset: u64, size: u64, value: u64) -> Result<(), Exception> {
+        let (start, reg) = self.reg(offset).ok_or(Exception::StoreAMOAccessFault(CLINT_BASE + offset))?;
+        match reg {
+            Register::Msip(hart) => self.msip[hart] = value & 1 == 1,
+            Register::Mtimecmp(hart) => {
+                self.mtimecmp[hart] = write_reg(self.mtimecmp[hart], offset - start, size, value);
+            }
+            Register::Mtime => self.mtime = write_reg(self.mtime, offset - start, size, value),
+        }
+        Ok(())
+    }
+
+    fn dump_state(&self) -> Option<String> {
+        let mut state = format!("clint: mtime={:#x}", self.mtime);
+        for (hart, (&mtimecmp, &msip)) in self.mtimecmp.iter().zip(&self.msip).enumerate() {
+            state += &format!("\n  hart {}: mtimecmp={:#x} msip={}", hart, mtimecmp, msip as u8);
+        }
+        Some(state)
+    }
+
+    fn save(&self, out: &mut StateWriter) {
+        out.u64(self.mtime);
+        out.u64(self.mtimecmp.len() as u64);
+        for (&mtimecmp, &msip) in self.mtimecmp.iter().zip(&self.msip) {
+            out.u64(mtimecmp);
+            out.bool(msip);
+        }
+    }
+
+    fn restore(&mut self, input: &mut StateReader) -> io::Result<()> {
+        self.mtime = input.u64()?;
+        input.count(self.mtimecmp.len(), "harts")?;
+        for (mtimecmp, msip) in self.mtimecmp.iter_mut().zip(self.msip.iter_mut()) {
+            *mtimecmp = input.u64()?;
+            *msip = input.bool()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registers_are_per_hart() {
+        let mut clint = Clint::new(2);
+        clint.store(MSIP + 4, 32, 3).unwrap();
+        assert_eq!((clint.msip[0], clint.msip[1]), (false, true));
+        assert_eq!(clint.load(MSIP + 4, 32), Ok(1));
+
+        // RV32 guests write mtimecmp a half at a time.
+        clint.store(MTIMECMP + 8, 32, 0x1234_5678).unwrap();
+        clint.store(MTIMECMP + 12, 32, 0x9).unwrap();
+        assert_eq!(clint.mtimecmp, [u64::MAX, 0x9_1234_5678]);
+        assert_eq!(clint.load(MTIMECMP + 12, 32), Ok(0x9));
+        assert_eq!(clint.load(MTIMECMP, 64), Ok(u64::MAX));
+    }
+
+    #[test]
+    fn mtime_advances_and_raises_the_timer() {
+        let mut clint = Clint::new(1);
+        clint.store(MTIMECMP, 64, 100).unwrap();
+        clint.tick(99);
+        assert!(!clint.timer_pending(0));
+        clint.tick(1);
+        assert!(clint.timer_pending(0));
+        assert_eq!(clint.load(MTIME, 64), Ok(100));
+
+        clint.store(MTIME + 4, 32, 1).unwrap();
+        assert_eq!(clint.load(MTIME, 64), Ok(0x1_0000_0064));
+    }
+
+    #[test]
+    fn registers_of_missing_harts_fault() {
+        let mut clint = Clint::new(1);
+        assert_eq!(clint.load(MSIP + 4, 32), Err(Exception::LoadAccessFault(CLINT_BASE + 4)));
+        assert_eq!(
+            clint.store(MTIMECMP + 8, 64, 0),
+            Err(Exception::StoreAMOAccessFault(CLINT_BASE + MTIMECMP + 8))
+        );
+        assert!(clint.load(0xc000, 32).is_err());
+    }
+}
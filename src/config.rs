@@ -0,0 +1,800 @@
+use std::fs;
+use std::path::PathBuf;
+
+use crate::aplic::*;
+use crate::bus::DRAM_BASE;
+use crate::clint::*;
+use crate::dram::DRAM_SIZE;
+use crate::finisher::*;
+use crate::imsic::*;
+use crate::isa::*;
+use crate::plic::*;
+use crate::uart::*;
+use crate::virtio::*;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RegionKind {
+    Rom,
+    Sram,
+}
+
+/// The set of peripherals on the bus.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Profile {
+    /// Memory only.
+    Bare,
+    /// A subset of QEMU's `virt` board: CLINT, PLIC or AIA interrupt
+    /// controllers, a 16550 UART and the SiFive test finisher, at the same
+    /// addresses.
+    Virt,
+}
+
+/// What the devices' interrupt lines go to under the `virt` profile.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InterruptController {
+    Plic,
+    /// An APLIC of the AIA, delivering interrupts directly to the harts.
+    Aplic,
+    /// An APLIC delivering interrupts as MSIs to per-hart IMSICs.
+    AplicImsic,
+}
+
+/// An additional memory region mapped next to DRAM.
+#[derive(Clone, Debug)]
+pub struct Region {
+    pub name: String,
+    pub kind: RegionKind,
+    pub base: u64,
+    pub size: u64,
+    /// Optional host file copied to the start of the region.
+    pub image: Option<PathBuf>,
+}
+
+impl Region {
+    pub fn end(&self) -> u64 {
+        self.base + self.size
+    }
+}
+
+/// A disk image attached as a virtio block device.
+#[derive(Clone, Debug)]
+pub struct Drive {
+    pub image: PathBuf,
+    /// Copy-on-write overlay receiving the writes, leaving `image` untouched.
+    pub overlay: Option<PathBuf>,
+}
+
+/// What the virtio network card is connected to.
+#[derive(Clone, Debug)]
+pub enum NetBackend {
+    /// Wait for another emulator to connect to a Unix socket at this path.
+    Listen(PathBuf),
+    /// Connect to another emulator listening on a Unix socket.
+    Connect(PathBuf),
+    /// Receive the frames of a capture file.
+    Replay(PathBuf),
+}
+
+/// Where the output of a console port goes and its input comes from.
+#[derive(Clone, Debug)]
+pub enum PortBackend {
+    /// Wait for a program to connect to a Unix socket at this path.
+    Listen(PathBuf),
+    /// Append the output to a file. The port has no input.
+    File(PathBuf),
+}
+
+/// A port of the virtio console.
+#[derive(Clone, Debug)]
+pub struct ConsolePort {
+    pub name: String,
+    pub backend: PortBackend,
+}
+
+/// Where the virtio entropy device or the `seed` CSR gets its bytes.
+#[derive(Clone, Copy, Debug)]
+pub enum RngSource {
+    /// The host's `/dev/urandom`.
+    Host,
+    /// A pseudo-random sequence from a seed, the same on every run.
+    Seed(u64),
+}
+
+/// A host directory exported to the guest over 9P.
+#[derive(Clone, Debug)]
+pub struct Share {
+    /// Mount tag the guest names the share by.
+    pub tag: String,
+    pub path: PathBuf,
+}
+
+/// MAC address of the network card unless configured, the same as QEMU's.
+pub const DEFAULT_MAC: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+
+/// Memory map and harts of the emulated board.
+///
+/// Built from the defaults, then a machine description file (`--machine`),
+/// then the individual command line flags, in that order.
+#[derive(Clone, Debug)]
+pub struct MachineConfig {
+    pub dram_base: u64,
+    pub dram_size: u64,
+    pub regions: Vec<Region>,
+    /// Initial `sp`. Defaults to the top of DRAM.
+    pub initial_sp: Option<u64>,
+    /// Number of harts sharing the bus.
+    pub harts: usize,
+    /// Register width of the harts, 32 or 64. Follows the class of the ELF
+    /// program when not set, and defaults to 64.
+    pub xlen: Option<u32>,
+    /// Optional extensions of the harts, from an ISA string.
+    pub isa: Isa,
+    /// Width of the vector registers, and of the widest vector element, in
+    /// bits.
+    pub vlen: u32,
+    pub elen: u32,
+    /// Size in bytes of the cache blocks the Zicbom, Zicboz and Zicbop
+    /// instructions work on.
+    pub cache_block_size: u64,
+    /// Where the `seed` CSR of Zkr gets its entropy.
+    pub entropy: RngSource,
+    /// Instructions each hart runs before the scheduler moves on to the next.
+    pub quantum: u64,
+    /// Run every hart on its own host thread instead of interleaving them.
+    pub parallel: bool,
+    /// Run a Linux user-mode ELF with emulated system calls.
+    pub user: bool,
+    /// Log every emulated system call to stderr.
+    pub strace: bool,
+    /// Service semihosting calls on the host.
+    pub semihosting: bool,
+    pub profile: Profile,
+    pub interrupt_controller: InterruptController,
+    /// Firmware to start the harts in, when no program is named on the
+    /// command line.
+    pub bios: Option<PathBuf>,
+    /// Service SBI calls in the emulator and start the kernel in S-mode,
+    /// without firmware.
+    pub sbi: bool,
+    /// Raw kernel image, loaded `KERNEL_OFFSET` bytes into DRAM.
+    pub kernel: Option<PathBuf>,
+    /// Initial ramdisk, loaded at `initrd_addr()`.
+    pub initrd: Option<PathBuf>,
+    /// Device tree blob whose address is passed to the firmware in a1.
+    /// Generated from the machine when not given.
+    pub dtb: Option<PathBuf>,
+    /// Kernel command line for the generated device tree.
+    pub bootargs: Option<String>,
+    /// Write the device tree to this file and exit instead of running.
+    pub dump_dtb: Option<PathBuf>,
+    /// Disks, one virtio-mmio slot each.
+    pub drives: Vec<Drive>,
+    /// Adds a virtio network card, in the slot after the disks.
+    pub net: Option<NetBackend>,
+    pub mac: [u8; 6],
+    /// Record the frames the network card sends and receives in a pcap file.
+    pub net_capture: Option<PathBuf>,
+    /// Ports of a virtio console, in the slot after the network card. The
+    /// first one is the guest's console.
+    pub console_ports: Vec<ConsolePort>,
+    /// Adds a virtio entropy device, in the slot after the console.
+    pub rng: Option<RngSource>,
+    /// Host directories, one virtio-mmio slot each after the entropy device.
+    pub shares: Vec<Share>,
+    /// Save a snapshot of the machine to this file when the guest asks for
+    /// one or after `snapshot_at` instructions.
+    pub snapshot: Option<PathBuf>,
+    pub snapshot_at: Option<u64>,
+    /// Start from a snapshot instead of the program's entry point.
+    pub resume: Option<PathBuf>,
+    /// Log the inputs the machine receives from the host to this file.
+    pub record: Option<PathBuf>,
+    /// Take the machine's host inputs from a log written by `record`.
+    pub replay: Option<PathBuf>,
+    /// Wait for GDB on this TCP address and run under its control.
+    pub gdb: Option<String>,
+    /// Run under the built-in monitor, driven from the terminal.
+    pub monitor: bool,
+    /// Instructions between the checkpoints kept for reverse execution.
+    pub checkpoint_interval: u64,
+}
+
+/// Where the kernel goes, relative to the start of DRAM. This is where
+/// OpenSBI's fw_jump jumps to on QEMU's `virt` board.
+pub const KERNEL_OFFSET: u64 = 0x20_0000;
+
+/// The stack each hart gets below the one of the hart before it.
+pub const HART_STACK_SIZE: u64 = 0x1_0000;
+
+impl Default for MachineConfig {
+    fn default() -> Self {
+        Self {
+            dram_base: DRAM_BASE,
+            dram_size: DRAM_SIZE,
+            regions: Vec::new(),
+            initial_sp: None,
+            harts: 1,
+            xlen: None,
+            isa: Isa::default(),
+            vlen: 128,
+            elen: 64,
+            cache_block_size: 64,
+            entropy: RngSource::Host,
+            quantum: 1000,
+            parallel: false,
+            user: false,
+            strace: false,
+            semihosting: false,
+            profile: Profile::Bare,
+            interrupt_controller: InterruptController::Plic,
+            bios: None,
+            sbi: false,
+            kernel: None,
+            initrd: None,
+            dtb: None,
+            bootargs: None,
+            dump_dtb: None,
+            drives: Vec::new(),
+            net: None,
+            mac: DEFAULT_MAC,
+            net_capture: None,
+            console_ports: Vec::new(),
+            rng: None,
+            shares: Vec::new(),
+            snapshot: None,
+            snapshot_at: None,
+            resume: None,
+            record: None,
+            replay: None,
+            gdb: None,
+            monitor: false,
+            checkpoint_interval: 10_000_000,
+        }
+    }
+}
+
+impl MachineConfig {
+    pub fn dram_end(&self) -> u64 {
+        self.dram_base + self.dram_size
+    }
+
+    pub fn xlen(&self) -> u32 {
+        self.xlen.unwrap_or(64)
+    }
+
+    /// The initial `sp` of a hart. Each hart gets its own stack, the ones
+    /// after hart 0 `HART_STACK_SIZE` further down each.
+    pub fn stack_pointer(&self, hartid: usize) -> u64 {
+        self.initial_sp.unwrap_or(self.dram_end()).wrapping_sub(hartid as u64 * HART_STACK_SIZE)
+    }
+
+    /// The initrd goes in the middle of DRAM, well clear of the kernel.
+    pub fn initrd_addr(&self) -> u64 {
+        self.dram_base + self.dram_size / 2
+    }
+
+    /// Address ranges of the memory-mapped peripherals of the profile.
+    pub fn device_regions(&self) -> Vec<Region> {
+        let devices = match self.profile {
+            Profile::Bare => vec![],
+            Profile::Virt => {
+                let mut devices = vec![
+                    ("finisher", FINISHER_BASE, FINISHER_SIZE),
+                    ("clint", CLINT_BASE, CLINT_SIZE),
+                    ("uart", UART_BASE, UART_SIZE),
+                ];
+                devices.extend(match self.interrupt_controller {
+                    InterruptController::Plic => vec![("plic", PLIC_BASE, PLIC_SIZE)],
+                    InterruptController::Aplic => vec![("aplic", APLIC_BASE, APLIC_SIZE)],
+                    InterruptController::AplicImsic => {
+                        vec![("aplic", APLIC_BASE, APLIC_SIZE), ("imsic", IMSIC_BASE, IMSIC_SIZE)]
+                    }
+                });
+                devices
+            }
+        };
+        let virtio = (0..self.virtio_devices()).map(|slot| {
+            let base = VIRTIO_BASE + slot as u64 * VIRTIO_SIZE;
+            (format!("virtio{}", slot), base, VIRTIO_SIZE)
+        });
+        devices
+            .into_iter()
+            .map(|(name, base, size)| (name.to_string(), base, size))
+            .chain(virtio)
+            .map(|(name, base, size)| Region {
+                name,
+                kind: RegionKind::Sram,
+                base,
+                size,
+                image: None,
+            })
+            .collect()
+    }
+
+    /// Number of virtio-mmio slots in use.
+    pub fn virtio_devices(&self) -> usize {
+        self.drives.len()
+            + self.net.is_some() as usize
+            + !self.console_ports.is_empty() as usize
+            + self.rng.is_some() as usize
+            + self.shares.len()
+    }
+
+    /// Reads a machine description file.
+    ///
+    /// Each non-empty line is `key = value`, where the keys are the long
+    /// command line flags without the leading dashes, e.g.
+    ///
+    /// ```text
+    /// # 64 MiB of RAM at 0x4000_0000 plus a boot ROM
+    /// dram-base = 0x40000000
+    /// dram-size = 64M
+    /// rom = boot@0x1000:0x1000:bootrom.bin
+    /// sp = 0x43fff000
+    /// ```
+    pub fn load_file(&mut self, path: &str) -> Result<(), String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+
+        for (lineno, line) in text.lines().enumerate() {
+            let line = match line.find('#') {
+                Some(i) => &line[..i],
+                None => line,
+            }
+            .trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| format!("{}:{}: expected `key = value`", path, lineno + 1))?;
+            self.set(key.trim(), value.trim())
+                .map_err(|e| format!("{}:{}: {}", path, lineno + 1, e))?;
+        }
+
+        Ok(())
+    }
+
+    /// Applies one `key = value` setting, shared by the machine file and the
+    /// command line.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "dram-base" => self.dram_base = parse_size(value)?,
+            "dram-size" => self.dram_size = parse_size(value)?,
+            "sp" => self.initial_sp = Some(parse_size(value)?),
+            "rom" => self.regions.push(parse_region(RegionKind::Rom, value)?),
+            "sram" => self.regions.push(parse_region(RegionKind::Sram, value)?),
+            "harts" => self.harts = parse_size(value)? as usize,
+            "xlen" => {
+                self.xlen = match value {
+                    "32" => Some(32),
+                    "64" => Some(64),
+                    _ => return Err(format!("unsupported XLEN `{}`", value)),
+                }
+            }
+            "isa" => {
+                let (xlen, isa) = Isa::parse(value)?;
+                if self.xlen.is_some_and(|x| x != xlen) {
+                    return Err(format!("ISA string `{}` does not match `xlen`", value));
+                }
+                self.xlen = Some(xlen);
+                self.isa = isa;
+            }
+            "vlen" => self.vlen = parse_size(value)?.try_into().map_err(|_| format!("unsupported VLEN `{}`", value))?,
+            "elen" => {
+                self.elen = match value {
+                    "32" => 32,
+                    "64" => 64,
+                    _ => return Err(format!("unsupported ELEN `{}`", value)),
+                }
+            }
+            "cache-block-size" => self.cache_block_size = parse_size(value)?,
+            "entropy" => self.entropy = parse_rng(value)?,
+            "quantum" => self.quantum = parse_size(value)?,
+            "parallel" => self.parallel = parse_bool(value)?,
+            "user" => {
+                self.user = parse_bool(value)?;
+                // User programs are linked at low addresses and run without
+                // address translation, so memory has to start at zero.
+                if self.user {
+                    self.dram_base = 0;
+                }
+            }
+            "strace" => self.strace = parse_bool(value)?,
+            "semihosting" => self.semihosting = parse_bool(value)?,
+            "profile" => {
+                self.profile = match value {
+                    "bare" => Profile::Bare,
+                    "virt" => Profile::Virt,
+                    _ => return Err(format!("unknown profile `{}`", value)),
+                }
+            }
+            "interrupt-controller" => {
+                self.interrupt_controller = match value {
+                    "plic" => InterruptController::Plic,
+                    "aplic" => InterruptController::Aplic,
+                    "aplic-imsic" => InterruptController::AplicImsic,
+                    _ => return Err(format!("unknown interrupt controller `{}`", value)),
+                }
+            }
+            "bios" => self.bios = Some(PathBuf::from(value)),
+            "sbi" => self.sbi = parse_bool(value)?,
+            "kernel" => self.kernel = Some(PathBuf::from(value)),
+            "initrd" => self.initrd = Some(PathBuf::from(value)),
+            "dtb" => self.dtb = Some(PathBuf::from(value)),
+            "bootargs" => self.bootargs = Some(value.to_string()),
+            "dump-dtb" => self.dump_dtb = Some(PathBuf::from(value)),
+            "drive" => self.drives.push(parse_drive(value)),
+            "net" => self.net = Some(parse_net(value)?),
+            "mac" => self.mac = parse_mac(value)?,
+            "net-capture" => self.net_capture = Some(PathBuf::from(value)),
+            "console-port" => self.console_ports.push(parse_console_port(value)?),
+            "rng" => self.rng = Some(parse_rng(value)?),
+            "share" => self.shares.push(parse_share(value)?),
+            "snapshot" => self.snapshot = Some(PathBuf::from(value)),
+            "snapshot-at" => self.snapshot_at = Some(parse_size(value)?),
+            "resume" => self.resume = Some(PathBuf::from(value)),
+            "record" => self.record = Some(PathBuf::from(value)),
+            "replay" => self.replay = Some(PathBuf::from(value)),
+            "gdb" => {
+                // A bare port listens on the loopback interface.
+                self.gdb = Some(match value.parse::<u16>() {
+                    Ok(port) => format!("127.0.0.1:{}", port),
+                    Err(_) => value.to_string(),
+                })
+            }
+            "monitor" => self.monitor = parse_bool(value)?,
+            "checkpoint-interval" => self.checkpoint_interval = parse_size(value)?,
+            _ => return Err(format!("unknown machine setting `{}`", key)),
+        }
+
+        Ok(())
+    }
+
+    /// Checks the hart settings and that every region is non-empty, fits in
+    /// the address space and does not overlap DRAM, a device or any other
+    /// region.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.harts == 0 {
+            return Err(String::from("at least one hart is required"));
+        }
+        if self.quantum == 0 {
+            return Err(String::from("the scheduling quantum must be at least 1"));
+        }
+        if self.profile != Profile::Virt
+            && (self.kernel.is_some() || self.initrd.is_some() || self.dtb.is_some() || self.bootargs.is_some())
+        {
+            return Err(String::from(
+                "loading a kernel, initrd or device tree requires `profile = virt`",
+            ));
+        }
+        if self.profile != Profile::Virt && self.interrupt_controller != InterruptController::Plic {
+            return Err(String::from("an APLIC requires `profile = virt`"));
+        }
+        if self.interrupt_controller == InterruptController::AplicImsic
+            && !(self.isa.has(SMAIA) && self.isa.has(SSAIA))
+        {
+            return Err(String::from("IMSICs require the Smaia and Ssaia extensions"));
+        }
+        if self.initrd.is_some() && self.kernel.is_none() {
+            return Err(String::from("an initrd requires a kernel"));
+        }
+        if self.profile != Profile::Virt && self.virtio_devices() > 0 {
+            return Err(String::from("virtio devices require `profile = virt`"));
+        }
+        if self.net_capture.is_some() && self.net.is_none() {
+            return Err(String::from("capturing network traffic requires `net`"));
+        }
+        if self.virtio_devices() > VIRTIO_SLOTS {
+            return Err(format!("at most {} virtio devices are supported", VIRTIO_SLOTS));
+        }
+        if self.snapshot_at.is_some() && self.snapshot.is_none() {
+            return Err(String::from("`snapshot-at` requires a `snapshot` file"));
+        }
+        if (self.snapshot.is_some() || self.resume.is_some()) && (self.parallel || self.user) {
+            return Err(String::from("snapshots are not supported with `parallel` or `user`"));
+        }
+        if self.record.is_some() && self.replay.is_some() {
+            return Err(String::from("`record` and `replay` cannot be combined"));
+        }
+        if (self.record.is_some() || self.replay.is_some()) && (self.parallel || self.user) {
+            return Err(String::from("recording and replaying are not supported with `parallel` or `user`"));
+        }
+        if (self.gdb.is_some() || self.monitor) && (self.parallel || self.user) {
+            return Err(String::from("debugging is not supported with `parallel` or `user`"));
+        }
+        if self.gdb.is_some() && self.monitor {
+            return Err(String::from("`gdb` and `monitor` cannot be combined"));
+        }
+        if self.checkpoint_interval == 0 {
+            return Err(String::from("the checkpoint interval must be at least 1"));
+        }
+        if self.sbi && self.kernel.is_none() {
+            return Err(String::from("the built-in SBI requires a kernel"));
+        }
+        if self.sbi && self.bios.is_some() {
+            return Err(String::from("the built-in SBI replaces the firmware; drop `bios`"));
+        }
+        if self.xlen() == 32 && (self.user || self.sbi) {
+            return Err(String::from("`user` and `sbi` are not supported on RV32"));
+        }
+        if self.xlen() == 32 && self.dram_end() > 1 << 32 {
+            return Err(String::from("DRAM must end below 4 GiB on RV32"));
+        }
+        if !self.vlen.is_power_of_two() || self.vlen < self.elen || self.vlen > 65536 {
+            return Err(String::from("`vlen` must be a power of two from `elen` to 65536"));
+        }
+        if !self.cache_block_size.is_power_of_two() || !(8..=4096).contains(&self.cache_block_size) {
+            return Err(String::from("`cache-block-size` must be a power of two from 8 to 4096"));
+        }
+
+        let dram = Region {
+            name: String::from("dram"),
+            kind: RegionKind::Sram,
+            base: self.dram_base,
+            size: self.dram_size,
+            image: None,
+        };
+        let devices = self.device_regions();
+        let all: Vec<&Region> = std::iter::once(&dram)
+            .chain(self.regions.iter())
+            .chain(devices.iter())
+            .collect();
+
+        for region in &all {
+            if region.size == 0 {
+                return Err(format!("region `{}` has zero size", region.name));
+            }
+            if region.base.checked_add(region.size).is_none() {
+                return Err(format!("region `{}` wraps around the address space", region.name));
+            }
+        }
+
+        for (i, a) in all.iter().enumerate() {
+            for b in &all[i + 1..] {
+                if a.base < b.end() && b.base < a.end() {
+                    return Err(format!(
+                        "region `{}` [{:#x}, {:#x}) overlaps `{}` [{:#x}, {:#x})",
+                        a.name,
+                        a.base,
+                        a.end(),
+                        b.name,
+                        b.base,
+                        b.end()
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Parses a decimal or `0x` hexadecimal number with an optional binary
+/// `K`/`M`/`G` suffix. Underscores are ignored.
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let cleaned: String = s.trim().chars().filter(|&c| c != '_').collect();
+    let (digits, shift) = match cleaned.chars().last() {
+        Some('k') | Some('K') => (&cleaned[..cleaned.len() - 1], 10),
+        Some('m') | Some('M') => (&cleaned[..cleaned.len() - 1], 20),
+        Some('g') | Some('G') => (&cleaned[..cleaned.len() - 1], 30),
+        _ => (&cleaned[..], 0),
+    };
+
+    let value = match digits.strip_prefix("0x").or_else(|| digits.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => digits.parse::<u64>(),
+    }
+    .map_err(|_| format!("invalid number `{}`", s))?;
+
+    value
+        .checked_mul(1 << shift)
+        .ok_or_else(|| format!("number `{}` is too large", s))
+}
+
+pub fn parse_bool(s: &str) -> Result<bool, String> {
+    match s {
+        "true" | "yes" | "on" | "1" => Ok(true),
+        "false" | "no" | "off" | "0" => Ok(false),
+        _ => Err(format!("invalid boolean `{}`", s)),
+    }
+}
+
+/// Parses `image[:overlay]`.
+fn parse_drive(spec: &str) -> Drive {
+    let (image, overlay) = match spec.split_once(':') {
+        Some((image, overlay)) => (image, Some(PathBuf::from(overlay))),
+        None => (spec, None),
+    };
+    Drive {
+        image: PathBuf::from(image),
+        overlay,
+    }
+}
+
+/// Parses `listen:<socket>`, `connect:<socket>` or `replay:<pcap>`.
+fn parse_net(spec: &str) -> Result<NetBackend, String> {
+    match spec.split_once(':') {
+        Some(("listen", path)) => Ok(NetBackend::Listen(PathBuf::from(path))),
+        Some(("connect", path)) => Ok(NetBackend::Connect(PathBuf::from(path))),
+        Some(("replay", path)) => Ok(NetBackend::Replay(PathBuf::from(path))),
+        _ => Err(format!(
+            "expected `listen:<socket>`, `connect:<socket>` or `replay:<pcap>`, got `{}`",
+            spec
+        )),
+    }
+}
+
+/// Parses `<name>:listen:<socket>` or `<name>:file:<path>`.
+fn parse_console_port(spec: &str) -> Result<ConsolePort, String> {
+    let backend = match spec.split_once(':').map(|(_, backend)| backend.split_once(':')) {
+        Some(Some(("listen", path))) => PortBackend::Listen(PathBuf::from(path)),
+        Some(Some(("file", path))) => PortBackend::File(PathBuf::from(path)),
+        _ => {
+            return Err(format!(
+                "expected `<name>:listen:<socket>` or `<name>:file:<path>`, got `{}`",
+                spec
+            ))
+        }
+    };
+    let name = spec.split(':').next().unwrap_or_default();
+    if name.is_empty() {
+        return Err(format!("console port `{}` has no name", spec));
+    }
+    Ok(ConsolePort {
+        name: name.to_string(),
+        backend,
+    })
+}
+
+/// Parses `host` or `seed:<n>`.
+fn parse_rng(spec: &str) -> Result<RngSource, String> {
+    match spec.split_once(':') {
+        None if spec == "host" => Ok(RngSource::Host),
+        Some(("seed", seed)) => Ok(RngSource::Seed(parse_size(seed)?)),
+        _ => Err(format!("expected `host` or `seed:<n>`, got `{}`", spec)),
+    }
+}
+
+/// Parses `<tag>:<directory>`.
+fn parse_share(spec: &str) -> Result<Share, String> {
+    match spec.split_once(':') {
+        Some((tag, path)) if !tag.is_empty() && !path.is_empty() => Ok(Share {
+            tag: tag.to_string(),
+            path: PathBuf::from(path),
+        }),
+        _ => Err(format!("expected `<tag>:<directory>`, got `{}`", spec)),
+    }
+}
+
+/// Parses a MAC address such as `52:54:00:12:34:56`.
+fn parse_mac(s: &str) -> Result<[u8; 6], String> {
+    let invalid = || format!("invalid MAC address `{}`", s);
+    let bytes = s
+        .split(':')
+        .map(|byte| u8::from_str_radix(byte, 16).map_err(|_| invalid()))
+        .collect::<Result<Vec<u8>, String>>()?;
+    bytes.try_into().map_err(|_| invalid())
+}
+
+/// Parses `name@base:size[:image]`.
+fn parse_region(kind: RegionKind, spec: &str) -> Result<Region, String> {
+    let (name, rest) = spec
+        .split_once('@')
+        .ok_or_else(|| format!("expected `name@base:size[:image]`, got `{}`", spec))?;
+    let mut parts = rest.splitn(3, ':');
+    let base = parse_size(parts.next().unwrap_or(""))?;
+    let size = parse_size(
+        parts
+            .next()
+            .ok_or_else(|| format!("missing size in region `{}`", spec))?,
+    )?;
+    let image = parts.next().map(PathBuf::from);
+
+    Ok(Region {
+        name: name.to_string(),
+        kind,
+        base,
+        size,
+        image,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exception::*;
+    use crate::testing::*;
+
+    #[test]
+    fn parses_sizes() {
+        assert_eq!(parse_size("0x8000_0000"), Ok(0x8000_0000));
+        assert_eq!(parse_size("64M"), Ok(64 << 20));
+        assert_eq!(parse_size("4k"), Ok(4096));
+        assert_eq!(parse_size("0x10G"), Ok(16 << 30));
+        assert_eq!(parse_size(" 12 "), Ok(12));
+        assert!(parse_size("0x").is_err());
+        assert!(parse_size("12Q").is_err());
+        assert!(parse_size("0xffff_ffff_ffff_ffffK").is_err());
+        assert_eq!(parse_bool("on"), Ok(true));
+        assert!(parse_bool("maybe").is_err());
+    }
+
+    #[test]
+    fn reads_a_machine_file() {
+        let path = temp_path("board.cfg");
+        let text = "# 64 MiB of RAM at 0x4000_0000 plus a boot ROM\n\
+                    dram-base = 0x40000000\n\
+                    dram-size = 64M\n\
+                    \n\
+                    rom = boot@0x1000:0x1000:bootrom.bin   # the reset vector\n\
+                    sram = scratch@0x2000_0000:16K\n\
+                    sp = 0x43fff000\n";
+        fs::write(&path, text).unwrap();
+        let mut config = MachineConfig::default();
+        config.load_file(path.to_str().unwrap()).unwrap();
+        assert_eq!((config.dram_base, config.dram_size, config.dram_end()), (0x4000_0000, 64 << 20, 0x4400_0000));
+        assert_eq!(config.stack_pointer(0), 0x43ff_f000);
+        assert_eq!(config.stack_pointer(2), 0x43ff_f000 - 2 * HART_STACK_SIZE);
+        let [rom, sram] = &config.regions[..] else { panic!("{:?}", config.regions) };
+        assert_eq!((rom.name.as_str(), rom.kind, rom.base, rom.end()), ("boot", RegionKind::Rom, 0x1000, 0x2000));
+        assert_eq!(rom.image, Some(PathBuf::from("bootrom.bin")));
+        assert_eq!((sram.kind, sram.base, sram.size, &sram.image), (RegionKind::Sram, 0x2000_0000, 0x4000, &None));
+        assert_eq!(config.validate(), Ok(()));
+
+        // Errors name the line.
+        fs::write(&path, "dram-size = 64M\n\ndram-base 0x1000\n").unwrap();
+        let error = config.load_file(path.to_str().unwrap()).unwrap_err();
+        assert!(error.ends_with(":3: expected `key = value`"), "{}", error);
+        fs::write(&path, "rom = boot@0x1000\n").unwrap();
+        let error = config.load_file(path.to_str().unwrap()).unwrap_err();
+        assert!(error.ends_with(":1: missing size in region `boot@0x1000`"), "{}", error);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn regions_must_not_overlap() {
+        let with = |settings: &[(&str, &str)]| {
+            let mut config = MachineConfig::default();
+            for (key, value) in settings {
+                config.set(key, value).unwrap();
+            }
+            config.validate()
+        };
+        assert_eq!(with(&[("rom", "boot@0x1000:0x1000"), ("sram", "tcm@0x2000:0x1000")]), Ok(()));
+        assert_eq!(
+            with(&[("rom", "boot@0x1000:0x1000"), ("sram", "tcm@0x1800:0x1000")]),
+            Err(String::from("region `boot` [0x1000, 0x2000) overlaps `tcm` [0x1800, 0x2800)"))
+        );
+        assert!(with(&[("rom", "boot@0x87ff_f000:0x2000")]).unwrap_err().contains("overlaps `boot`"));
+        assert!(with(&[("profile", "virt"), ("sram", "tcm@0x200_0000:0x1000")]).unwrap_err().contains("`clint`"));
+        assert_eq!(with(&[("sram", "tcm@0x1000:0")]), Err(String::from("region `tcm` has zero size")));
+        assert!(with(&[("dram-base", "0xffff_ffff_ffff_0000")]).unwrap_err().contains("wraps around"));
+        assert!(with(&[("xlen", "32"), ("dram-base", "0xfc00_0000")]).unwrap_err().contains("below 4 GiB"));
+        assert!(MachineConfig::default().set("dram-size", "lots").is_err());
+        assert!(MachineConfig::default().set("rom", "boot:0x1000").is_err());
+    }
+
+    #[test]
+    fn harts_use_the_memory_map() {
+        let image = temp_path("bootrom.bin");
+        fs::write(&image, 0x1234_5678u32.to_le_bytes()).unwrap();
+        let mut config = MachineConfig::default();
+        config.set("dram-base", "0x4000_0000").unwrap();
+        config.set("dram-size", "1M").unwrap();
+        config.set("rom", &format!("boot@0x1000:0x1000:{}", image.display())).unwrap();
+        config.set("sram", "tcm@0x2000_0000:4K").unwrap();
+        let mut rv64 = machine(&config, &[SPIN]);
+        fs::remove_file(image).unwrap();
+
+        let cpu = &mut rv64.harts[0];
+        assert_eq!((cpu.pc, cpu.regs[2]), (0x4000_0000, 0x4010_0000));
+        assert_eq!(cpu.load(0x4000_0000, 32), Ok(SPIN as u64));
+        assert_eq!(cpu.load(0x1000, 32), Ok(0x1234_5678));
+        assert_eq!(cpu.store(0x1000, 32, 0), Err(Exception::StoreAMOAccessFault(0x1000)));
+        cpu.store(0x2000_0ff8, 64, 7).unwrap();
+        assert_eq!(cpu.load(0x2000_0ff8, 64), Ok(7));
+        // Nothing is mapped at the old DRAM base.
+        assert_eq!(cpu.load(DRAM_BASE, 32), Err(Exception::LoadAccessFault(DRAM_BASE)));
+    }
+}
@@ -0,0 +1,295 @@
+//! The counters of Zicntr and Zihpm, with the overflow interrupt of
+//! Sscofpmf, and the supervisor timer of Sstc. A hart spends one cycle on
+//! every step, whether the instruction retires or traps.
+
+use crate::cpu::*;
+use crate::isa::*;
+
+// mhpmevent fields: the event, and with Sscofpmf the overflow flag and the
+// modes the counter stops in
+const MHPMEVENT_EVENT: u64 = (1 << 56) - 1;
+const MHPMEVENT_OF: u64 = 1 << 63;
+const MHPMEVENT_MINH: u64 = 1 << 62;
+const MHPMEVENT_SINH: u64 = 1 << 61;
+const MHPMEVENT_UINH: u64 = 1 << 60;
+const MHPMEVENT_VSINH: u64 = 1 << 59;
+const MHPMEVENT_VUINH: u64 = 1 << 58;
+
+/// The events the programmable counters can count. Any other selects no
+/// event and reads back as 0.
+pub const HPM_EVENT_CYCLES: u64 = 1;
+pub const HPM_EVENT_INSTRUCTIONS: u64 = 2;
+
+impl Cpu {
+    /// Advances the counters over a step: mcycle and the ones counting
+    /// cycles, and if the instruction retired minstret and the ones
+    /// counting instructions. Those mcountinhibit stops, or the instruction
+    /// wrote, keep their value.
+    pub fn count(&mut self, retired: bool) {
+        let stopped = self.csrs[MCOUNTINHIBIT] as u32 | std::mem::take(&mut self.counters_written);
+        if stopped & 1 == 0 {
+            self.csrs[MCYCLE] = self.csrs[MCYCLE].wrapping_add(1);
+        }
+        if retired && stopped & 4 == 0 {
+            self.csrs[MINSTRET] = self.csrs[MINSTRET].wrapping_add(1);
+        }
+        let counting = self.hpm_counting & !stopped;
+        if counting != 0 {
+            self.count_events(counting, retired);
+        }
+    }
+
+    /// Advances the programmable counters in `counting` whose event happened
+    /// and whose mhpmevent does not stop them in the current mode. A counter
+    /// wrapping around sets its OF bit, and raises the local counter
+    /// overflow interrupt unless OF was already set.
+    fn count_events(&mut self, counting: u32, retired: bool) {
+        let inhibit = match (self.mode, self.virt) {
+            (MACHINE, _) => MHPMEVENT_MINH,
+            (SUPERVISOR, false) => MHPMEVENT_SINH,
+            (SUPERVISOR, true) => MHPMEVENT_VSINH,
+            (_, false) => MHPMEVENT_UINH,
+            (_, true) => MHPMEVENT_VUINH,
+        };
+        for n in (3..32).filter(|&n| counting >> n & 1 == 1) {
+            let event = self.csrs[MHPMEVENT3 + n - 3];
+            if event & inhibit != 0 || (event & MHPMEVENT_EVENT == HPM_EVENT_INSTRUCTIONS && !retired) {
+                continue;
+            }
+            let counter = self.csrs[MHPMCOUNTER3 + n - 3].wrapping_add(1);
+            self.csrs[MHPMCOUNTER3 + n - 3] = counter;
+            if counter == 0 && self.isa.has(SSCOFPMF) && event & MHPMEVENT_OF == 0 {
+                self.csrs[MHPMEVENT3 + n - 3] |= MHPMEVENT_OF;
+                self.csrs[MIP] |= LCOFIP_BIT;
+            }
+        }
+    }
+
+    /// Writes mhpmevent3 to mhpmevent31, or on RV32 the upper half of one
+    /// if `high` is set.
+    pub fn store_hpmevent(&mut self, addr: usize, value: u64, high: bool) {
+        self.store_wide(addr, value, high);
+        let mut writable = MHPMEVENT_EVENT;
+        if self.isa.has(SSCOFPMF) {
+            writable |= MHPMEVENT_OF | MHPMEVENT_MINH | MHPMEVENT_SINH | MHPMEVENT_UINH;
+            if self.isa.has(H) {
+                writable |= MHPMEVENT_VSINH | MHPMEVENT_VUINH;
+            }
+        }
+        let mut event = self.csrs[addr] & writable;
+        if !matches!(event & MHPMEVENT_EVENT, HPM_EVENT_CYCLES | HPM_EVENT_INSTRUCTIONS) {
+            event &= !MHPMEVENT_EVENT;
+        }
+        self.csrs[addr] = event;
+        self.update_hpm_counting();
+    }
+
+    /// Works out which programmable counters have an event to count.
+    pub fn update_hpm_counting(&mut self) {
+        self.hpm_counting = (3..32)
+            .filter(|&n| self.csrs[MHPMEVENT3 + n - 3] & MHPMEVENT_EVENT != 0)
+            .fold(0, |counting, n| counting | (1 << n));
+    }
+
+    /// Reads scountovf: the OF bits of the mhpmevents, limited below M-mode
+    /// to the counters mcounteren, and in a guest also hcounteren, expose.
+    pub fn load_scountovf(&self) -> u64 {
+        let overflowed = (3..32)
+            .filter(|&n| self.csrs[MHPMEVENT3 + n - 3] & MHPMEVENT_OF != 0)
+            .fold(0, |overflowed, n| overflowed | (1 << n));
+        match self.mode {
+            MACHINE => overflowed,
+            _ if self.virt => overflowed & self.csrs[MCOUNTEREN] & self.csrs[HCOUNTEREN],
+            _ => overflowed & self.csrs[MCOUNTEREN],
+        }
+    }
+
+    /// The supervisor timer interrupts Sstc raises, as mip bits: STIP once
+    /// `time` reaches stimecmp, and VSTIP once a guest's `time` reaches
+    /// vstimecmp if henvcfg turns that timer on too.
+    pub fn sstc_interrupts(&self) -> u64 {
+        let time = self.time;
+        let mut pending = 0;
+        if time >= self.csrs[STIMECMP] {
+            pending |= STIP_BIT;
+        }
+        if self.csrs[HENVCFG] & ENVCFG_STCE != 0 && time.wrapping_add(self.htimedelta()) >= self.csrs[VSTIMECMP] {
+            pending |= VSTIP_BIT;
+        }
+        pending
+    }
+
+    /// STIP if Sstc's timer drives it, leaving software unable to write it.
+    pub fn sstc_stip(&self) -> u64 {
+        if self.csrs[MENVCFG] & ENVCFG_STCE != 0 {
+            STIP_BIT
+        } else {
+            0
+        }
+    }
+
+    /// Whether `mode`, in a guest if `virt` is set, may access stimecmp or
+    /// vstimecmp: menvcfg.STCE and mcounteren.TM must allow it below M-mode,
+    /// and in a guest henvcfg.STCE and hcounteren.TM as well.
+    pub fn stimecmp_accessible(&self, mode: u64, virt: bool) -> bool {
+        let tm = 1 << (TIME & 0x1f);
+        let allowed = |envcfg: usize, counteren: usize| {
+            self.csrs[envcfg] & ENVCFG_STCE != 0 && self.csrs[counteren] & tm != 0
+        };
+        mode == MACHINE || (allowed(MENVCFG, MCOUNTEREN) && (!virt || allowed(HENVCFG, HCOUNTEREN)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::*;
+    use crate::exception::*;
+    use crate::testing::*;
+
+    fn hart() -> Cpu {
+        machine(&MachineConfig::default(), &[SPIN]).harts.remove(0)
+    }
+
+    /// Reads a CSR with csrrs, giving the exception as a cause.
+    fn read(cpu: &mut Cpu, csr: usize) -> Result<u64, u64> {
+        let inst = csrrs(T0, csr as u16, ZERO);
+        match cpu.execute(inst) {
+            Ok(()) => Ok(cpu.regs[T0 as usize]),
+            Err(Exception::IllegalInstruction(_)) => Err(2),
+            Err(Exception::VirtualInstruction(_)) => Err(22),
+            Err(e) => panic!("{:?}", e),
+        }
+    }
+
+    #[test]
+    fn counteren_gates_the_counters() {
+        let mut cpu = hart();
+        cpu.csrs[MINSTRET] = 42;
+        cpu.mode = SUPERVISOR;
+        assert_eq!(read(&mut cpu, INSTRET), Err(2));
+        cpu.store_csr(MCOUNTEREN, 1 << 2);
+        assert_eq!(read(&mut cpu, INSTRET), Ok(42));
+        assert_eq!(read(&mut cpu, CYCLE), Err(2));
+
+        // U-mode needs scounteren as well.
+        cpu.mode = USER;
+        assert_eq!(read(&mut cpu, INSTRET), Err(2));
+        cpu.store_csr(SCOUNTEREN, 1 << 2);
+        assert_eq!(read(&mut cpu, INSTRET), Ok(42));
+
+        // A guest needs hcounteren too, and traps to the hypervisor when
+        // only that is missing.
+        cpu.virt = true;
+        assert_eq!(read(&mut cpu, INSTRET), Err(22));
+        assert_eq!(read(&mut cpu, CYCLE), Err(2));
+        cpu.store_csr(HCOUNTEREN, 1 << 2);
+        assert_eq!(read(&mut cpu, INSTRET), Ok(42));
+
+        // The machine counters are M-mode's alone.
+        cpu.mode = SUPERVISOR;
+        cpu.virt = false;
+        assert_eq!(read(&mut cpu, MINSTRET), Err(2));
+    }
+
+    #[test]
+    fn guests_see_time_with_the_delta() {
+        let rv32 = MachineConfig { xlen: Some(32), ..Default::default() };
+        let mut cpu = machine(&rv32, &[SPIN]).harts.remove(0);
+        cpu.store_csr(MCOUNTEREN, 1 << 1);
+        cpu.store_csr(HCOUNTEREN, 1 << 1);
+        cpu.store_csr(HTIMEDELTA, 0xffff_fff0);
+        cpu.store_csr(HTIMEDELTAH, 1);
+        cpu.time = 0x20;
+        cpu.mode = SUPERVISOR;
+        assert_eq!(read(&mut cpu, TIME), Ok(0x20));
+        cpu.virt = true;
+        assert_eq!(read(&mut cpu, TIME), Ok(0x10));
+        assert_eq!(read(&mut cpu, TIMEH), Ok(2));
+    }
+
+    #[test]
+    fn counts_cycles_instructions_and_events() {
+        let mut cpu = hart();
+        cpu.store_csr(MHPMEVENT3, HPM_EVENT_INSTRUCTIONS);
+        cpu.store_csr(MHPMEVENT3 + 1, HPM_EVENT_CYCLES | MHPMEVENT_MINH);
+        // Unknown events select none.
+        cpu.store_csr(MHPMEVENT3 + 2, 0x99);
+        assert_eq!(cpu.load_csr(MHPMEVENT3 + 2), 0);
+        cpu.count(true);
+        cpu.count(false);
+        assert_eq!((cpu.csrs[MCYCLE], cpu.csrs[MINSTRET]), (2, 1));
+        assert_eq!(cpu.csrs[MHPMCOUNTER3], 1);
+        // Counter 4 stops in M-mode.
+        assert_eq!(cpu.csrs[MHPMCOUNTER3 + 1], 0);
+        cpu.mode = SUPERVISOR;
+        cpu.count(false);
+        assert_eq!(cpu.csrs[MHPMCOUNTER3 + 1], 1);
+
+        // mcountinhibit stops them, and a counter written by the step keeps
+        // the value written.
+        cpu.store_csr(MCOUNTINHIBIT, 1 << 3);
+        cpu.store_csr(MCYCLE, 100);
+        cpu.count(true);
+        assert_eq!((cpu.csrs[MCYCLE], cpu.csrs[MINSTRET]), (100, 2));
+        assert_eq!(cpu.csrs[MHPMCOUNTER3], 1);
+    }
+
+    #[test]
+    fn overflows_raise_the_counter_interrupt() {
+        let mut cpu = hart();
+        cpu.store_csr(MHPMEVENT3 + 2, HPM_EVENT_CYCLES);
+        cpu.csrs[MHPMCOUNTER3 + 2] = u64::MAX;
+        cpu.count(false);
+        assert_eq!(cpu.csrs[MHPMCOUNTER3 + 2], 0);
+        assert_eq!(cpu.load_csr(MHPMEVENT3 + 2), HPM_EVENT_CYCLES | MHPMEVENT_OF);
+        assert_ne!(cpu.load_csr(MIP) & LCOFIP_BIT, 0);
+        assert_eq!(cpu.load_csr(SCOUNTOVF), 1 << 5);
+
+        // Below M-mode, scountovf only shows the counters mcounteren does.
+        cpu.mode = SUPERVISOR;
+        assert_eq!(cpu.load_csr(SCOUNTOVF), 0);
+        cpu.csrs[MCOUNTEREN] = 1 << 5;
+        assert_eq!(cpu.load_csr(SCOUNTOVF), 1 << 5);
+    }
+
+    #[test]
+    fn sstc_drives_the_supervisor_timers() {
+        let mut cpu = hart();
+        cpu.mode = SUPERVISOR;
+        // S-mode has no stimecmp, and software drives STIP, until
+        // menvcfg.STCE and mcounteren.TM hand it the timer.
+        assert_eq!(read(&mut cpu, STIMECMP), Err(2));
+        cpu.store_csr(MIP, STIP_BIT);
+        assert_eq!(cpu.load_csr(MIP) & STIP_BIT, STIP_BIT);
+        cpu.store_csr(MENVCFG, ENVCFG_STCE);
+        assert_eq!(read(&mut cpu, STIMECMP), Err(2));
+        cpu.store_csr(MCOUNTEREN, 1 << 1);
+        assert_eq!(read(&mut cpu, STIMECMP), Ok(u64::MAX));
+        // The STIP software wrote is gone, and writes no longer set it.
+        assert_eq!(cpu.load_csr(MIP) & STIP_BIT, 0);
+        cpu.store_csr(MIP, STIP_BIT);
+        assert_eq!(cpu.load_csr(MIP) & STIP_BIT, 0);
+
+        cpu.store_csr(STIMECMP, 100);
+        cpu.time = 99;
+        assert_eq!(cpu.sstc_interrupts(), 0);
+        cpu.time = 100;
+        assert_eq!(cpu.sstc_interrupts(), STIP_BIT);
+
+        // A guest's timer counts its own time, and needs henvcfg.STCE and
+        // hcounteren.TM as well.
+        cpu.store_csr(VSTIMECMP, 50);
+        cpu.store_csr(HTIMEDELTA, (-60i64) as u64);
+        cpu.time = 110;
+        assert_eq!(cpu.sstc_interrupts(), STIP_BIT);
+        cpu.store_csr(HENVCFG, ENVCFG_STCE);
+        assert_eq!(cpu.sstc_interrupts(), STIP_BIT | VSTIP_BIT);
+        cpu.time = 109;
+        assert_eq!(cpu.sstc_interrupts(), STIP_BIT);
+        cpu.virt = true;
+        assert_eq!(read(&mut cpu, STIMECMP), Err(22));
+        cpu.store_csr(HCOUNTEREN, 1 << 1);
+        assert_eq!(read(&mut cpu, STIMECMP), Ok(50));
+    }
+}
@@ -1,25 +1,775 @@
+use std::io;
+use std::sync::{Arc, Mutex};
+
 use crate::bus::*;
-use crate::dram::*;
+use crate::config::*;
+use crate::debugger::*;
+use crate::exception::*;
+use crate::interrupt::*;
+use crate::isa::*;
+use crate::rvc::*;
+use crate::sbi::*;
+use crate::semihosting::*;
+use crate::snapshot::*;
+
+// Privilege modes
+pub const USER: u64 = 0b00;
+pub const SUPERVISOR: u64 = 0b01;
+pub const MACHINE: u64 = 0b11;
+
+// Floating-point CSRs
+pub const FFLAGS: usize = 0x001;
+pub const FRM: usize = 0x002;
+pub const FCSR: usize = 0x003;
+
+// Vector CSRs
+pub const VSTART: usize = 0x008;
+pub const VXSAT: usize = 0x009;
+pub const VXRM: usize = 0x00a;
+pub const VCSR: usize = 0x00f;
+pub const VL: usize = 0xc20;
+pub const VTYPE: usize = 0xc21;
+pub const VLENB: usize = 0xc22;
+
+// Entropy source of Zkr
+pub const SEED: usize = 0x015;
+pub const MSECCFG: usize = 0x747;
+pub const MSECCFGH: usize = 0x757;
+
+// Counters, the upper halves of which are separate CSRs on RV32
+pub const CYCLE: usize = 0xc00;
+pub const TIME: usize = 0xc01;
+pub const INSTRET: usize = 0xc02;
+pub const HPMCOUNTER3: usize = 0xc03;
+pub const HPMCOUNTER31: usize = 0xc1f;
+pub const CYCLEH: usize = 0xc80;
+pub const TIMEH: usize = 0xc81;
+pub const INSTRETH: usize = 0xc82;
+pub const HPMCOUNTER3H: usize = 0xc83;
+pub const HPMCOUNTER31H: usize = 0xc9f;
+pub const MCYCLE: usize = 0xb00;
+pub const MINSTRET: usize = 0xb02;
+pub const MHPMCOUNTER3: usize = 0xb03;
+pub const MHPMCOUNTER31: usize = 0xb1f;
+pub const MCYCLEH: usize = 0xb80;
+pub const MINSTRETH: usize = 0xb82;
+pub const MHPMCOUNTER3H: usize = 0xb83;
+pub const MHPMCOUNTER31H: usize = 0xb9f;
+pub const MCOUNTINHIBIT: usize = 0x320;
+pub const MHPMEVENT3: usize = 0x323;
+pub const MHPMEVENT31: usize = 0x33f;
+pub const MHPMEVENT3H: usize = 0x723;
+pub const MHPMEVENT31H: usize = 0x73f;
+pub const SCOUNTOVF: usize = 0xda0;
+
+// Supervisor-level CSRs
+pub const SSTATUS: usize = 0x100;
+pub const SIE: usize = 0x104;
+pub const STVEC: usize = 0x105;
+pub const SCOUNTEREN: usize = 0x106;
+pub const SENVCFG: usize = 0x10a;
+pub const SSCRATCH: usize = 0x140;
+pub const SEPC: usize = 0x141;
+pub const SCAUSE: usize = 0x142;
+pub const STVAL: usize = 0x143;
+pub const SIP: usize = 0x144;
+pub const STIMECMP: usize = 0x14d;
+pub const STIMECMPH: usize = 0x15d;
+pub const SISELECT: usize = 0x150;
+pub const SIREG: usize = 0x151;
+pub const STOPEI: usize = 0x15c;
+pub const STOPI: usize = 0xdb0;
+pub const SIEH: usize = 0x114;
+pub const SIPH: usize = 0x154;
+pub const SATP: usize = 0x180;
+
+// Virtual supervisor CSRs, which stand in for the supervisor ones in a guest
+pub const VSSTATUS: usize = 0x200;
+pub const VSIE: usize = 0x204;
+pub const VSTVEC: usize = 0x205;
+pub const VSSCRATCH: usize = 0x240;
+pub const VSEPC: usize = 0x241;
+pub const VSCAUSE: usize = 0x242;
+pub const VSTVAL: usize = 0x243;
+pub const VSIP: usize = 0x244;
+pub const VSTIMECMP: usize = 0x24d;
+pub const VSTIMECMPH: usize = 0x25d;
+pub const VSISELECT: usize = 0x250;
+pub const VSIREG: usize = 0x251;
+pub const VSTOPEI: usize = 0x25c;
+pub const VSTOPI: usize = 0xeb0;
+pub const VSIEH: usize = 0x214;
+pub const VSIPH: usize = 0x254;
+pub const VSATP: usize = 0x280;
+
+// Hypervisor CSRs
+pub const HSTATUS: usize = 0x600;
+pub const HEDELEG: usize = 0x602;
+pub const HIDELEG: usize = 0x603;
+pub const HIE: usize = 0x604;
+pub const HTIMEDELTA: usize = 0x605;
+pub const HCOUNTEREN: usize = 0x606;
+pub const HGEIE: usize = 0x607;
+pub const HENVCFG: usize = 0x60a;
+pub const HVIEN: usize = 0x608;
+pub const HVICTL: usize = 0x609;
+pub const HVIPRIO1: usize = 0x646;
+pub const HVIPRIO2: usize = 0x647;
+pub const HIDELEGH: usize = 0x613;
+pub const HVIENH: usize = 0x618;
+pub const HVIPH: usize = 0x655;
+pub const HVIPRIO1H: usize = 0x656;
+pub const HVIPRIO2H: usize = 0x657;
+pub const HTIMEDELTAH: usize = 0x615;
+pub const HENVCFGH: usize = 0x61a;
+pub const HTVAL: usize = 0x643;
+pub const HIP: usize = 0x644;
+pub const HVIP: usize = 0x645;
+pub const HTINST: usize = 0x64a;
+pub const HGATP: usize = 0x680;
+pub const HGEIP: usize = 0xe12;
+
+// Machine-level CSRs
+pub const MSTATUS: usize = 0x300;
+pub const MSTATUSH: usize = 0x310;
+pub const MISA: usize = 0x301;
+pub const MEDELEG: usize = 0x302;
+pub const MIDELEG: usize = 0x303;
+pub const MIE: usize = 0x304;
+pub const MTVEC: usize = 0x305;
+pub const MCOUNTEREN: usize = 0x306;
+pub const MENVCFG: usize = 0x30a;
+pub const MENVCFGH: usize = 0x31a;
+pub const MVIEN: usize = 0x308;
+pub const MVIP: usize = 0x309;
+pub const MIDELEGH: usize = 0x313;
+pub const MIEH: usize = 0x314;
+pub const MVIENH: usize = 0x318;
+pub const MVIPH: usize = 0x319;
+pub const MSCRATCH: usize = 0x340;
+pub const MEPC: usize = 0x341;
+pub const MCAUSE: usize = 0x342;
+pub const MTVAL: usize = 0x343;
+pub const MIP: usize = 0x344;
+pub const MTINST: usize = 0x34a;
+pub const MTVAL2: usize = 0x34b;
+pub const MISELECT: usize = 0x350;
+pub const MIREG: usize = 0x351;
+pub const MIPH: usize = 0x354;
+pub const MTOPEI: usize = 0x35c;
+pub const MTOPI: usize = 0xfb0;
+
+// Physical memory protection
+pub const PMPCFG0: usize = 0x3a0;
+pub const PMPCFG3: usize = 0x3a3;
+pub const PMPADDR0: usize = 0x3b0;
+pub const PMPADDR15: usize = 0x3bf;
+
+// Machine information registers
+pub const MVENDORID: usize = 0xf11;
+pub const MARCHID: usize = 0xf12;
+pub const MIMPID: usize = 0xf13;
+pub const MHARTID: usize = 0xf14;
+pub const MCONFIGPTR: usize = 0xf15;
+
+// mstatus fields
+pub const MSTATUS_SIE: u64 = 1 << 1;
+pub const MSTATUS_MIE: u64 = 1 << 3;
+pub const MSTATUS_SPIE: u64 = 1 << 5;
+pub const MSTATUS_UBE: u64 = 1 << 6;
+pub const MSTATUS_MPIE: u64 = 1 << 7;
+pub const MSTATUS_SPP: u64 = 1 << 8;
+pub const MSTATUS_VS: u64 = 0b11 << 9;
+pub const MSTATUS_MPP: u64 = 0b11 << 11;
+pub const MSTATUS_FS: u64 = 0b11 << 13;
+pub const MSTATUS_XS: u64 = 0b11 << 15;
+pub const MSTATUS_MPRV: u64 = 1 << 17;
+pub const MSTATUS_SUM: u64 = 1 << 18;
+pub const MSTATUS_MXR: u64 = 1 << 19;
+pub const MSTATUS_TVM: u64 = 1 << 20;
+pub const MSTATUS_TW: u64 = 1 << 21;
+pub const MSTATUS_TSR: u64 = 1 << 22;
+pub const MSTATUS_UXL: u64 = 0b11 << 32;
+pub const MSTATUS_GVA: u64 = 1 << 38;
+pub const MSTATUS_MPV: u64 = 1 << 39;
+pub const MSTATUS_SD: u64 = 1 << 63;
+
+pub const FS_INITIAL: u64 = 0b01 << 13;
+pub const FS_DIRTY: u64 = 0b11 << 13;
+pub const VS_INITIAL: u64 = 0b01 << 9;
+pub const VS_DIRTY: u64 = 0b11 << 9;
+
+/// The mstatus bits visible through sstatus.
+pub const SSTATUS_MASK: u64 = MSTATUS_SIE
+    | MSTATUS_SPIE
+    | MSTATUS_UBE
+    | MSTATUS_SPP
+    | MSTATUS_VS
+    | MSTATUS_FS
+    | MSTATUS_XS
+    | MSTATUS_SUM
+    | MSTATUS_MXR
+    | MSTATUS_UXL
+    | MSTATUS_SD;
+
+const MSTATUS_WRITABLE: u64 = MSTATUS_SIE
+    | MSTATUS_MIE
+    | MSTATUS_SPIE
+    | MSTATUS_MPIE
+    | MSTATUS_SPP
+    | MSTATUS_MPP
+    | MSTATUS_FS
+    | MSTATUS_MPRV
+    | MSTATUS_SUM
+    | MSTATUS_MXR
+    | MSTATUS_TVM
+    | MSTATUS_TW
+    | MSTATUS_TSR;
+
+// hstatus fields
+pub const HSTATUS_GVA: u64 = 1 << 6;
+pub const HSTATUS_SPV: u64 = 1 << 7;
+pub const HSTATUS_SPVP: u64 = 1 << 8;
+pub const HSTATUS_HU: u64 = 1 << 9;
+pub const HSTATUS_VTVM: u64 = 1 << 20;
+pub const HSTATUS_VTW: u64 = 1 << 21;
+pub const HSTATUS_VTSR: u64 = 1 << 22;
+
+// mip/mie bits
+pub const SSIP_BIT: u64 = 1 << 1;
+pub const VSSIP_BIT: u64 = 1 << 2;
+pub const MSIP_BIT: u64 = 1 << 3;
+pub const STIP_BIT: u64 = 1 << 5;
+pub const VSTIP_BIT: u64 = 1 << 6;
+pub const MTIP_BIT: u64 = 1 << 7;
+pub const SEIP_BIT: u64 = 1 << 9;
+pub const VSEIP_BIT: u64 = 1 << 10;
+pub const MEIP_BIT: u64 = 1 << 11;
+pub const SGEIP_BIT: u64 = 1 << 12;
+pub const LCOFIP_BIT: u64 = 1 << 13;
+
+/// The interrupts of VS-mode, which hideleg can pass on to a guest.
+pub const VS_INTERRUPTS: u64 = VSSIP_BIT | VSTIP_BIT | VSEIP_BIT;
+/// The interrupts only a hypervisor sees, always delegated past M-mode.
+const HYPERVISOR_INTERRUPTS: u64 = VS_INTERRUPTS | SGEIP_BIT;
+
+// menvcfg and senvcfg fields
+pub const ENVCFG_FIOM: u64 = 1 << 0;
+pub const ENVCFG_CBIE: u64 = 0b11 << 4;
+pub const ENVCFG_CBCFE: u64 = 1 << 6;
+pub const ENVCFG_CBZE: u64 = 1 << 7;
+pub const ENVCFG_STCE: u64 = 1 << 63;
+pub const ENVCFG_PBMTE: u64 = 1 << 62;
+pub const ENVCFG_ADUE: u64 = 1 << 61;
+
+// mseccfg fields
+pub const MSECCFG_MML: u64 = 1 << 0;
+pub const MSECCFG_MMWP: u64 = 1 << 1;
+pub const MSECCFG_RLB: u64 = 1 << 2;
+pub const MSECCFG_USEED: u64 = 1 << 8;
+pub const MSECCFG_SSEED: u64 = 1 << 9;
+
+// Status of a read of seed: 16 bits of entropy, or none yet
+const SEED_ES16: u64 = 0b10 << 30;
+const SEED_WAIT: u64 = 0b01 << 30;
+
+/// The ABI names of the integer registers.
+pub const ABI_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4", "a5", "a6", "a7",
+    "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4", "t5", "t6",
+];
+
+/// The ABI names of the floating-point registers.
+pub const FP_ABI_NAMES: [&str; 32] = [
+    "ft0", "ft1", "ft2", "ft3", "ft4", "ft5", "ft6", "ft7", "fs0", "fs1", "fa0", "fa1", "fa2", "fa3", "fa4", "fa5",
+    "fa6", "fa7", "fs2", "fs3", "fs4", "fs5", "fs6", "fs7", "fs8", "fs9", "fs10", "fs11", "ft8", "ft9", "ft10", "ft11",
+];
+
+/// The CSRs that exist, by name, in address order.
+pub const CSR_NAMES: &[(&str, usize)] = &[
+    ("fflags", FFLAGS),
+    ("frm", FRM),
+    ("fcsr", FCSR),
+    ("vstart", VSTART),
+    ("vxsat", VXSAT),
+    ("vxrm", VXRM),
+    ("vcsr", VCSR),
+    ("seed", SEED),
+    ("sstatus", SSTATUS),
+    ("sie", SIE),
+    ("stvec", STVEC),
+    ("scounteren", SCOUNTEREN),
+    ("senvcfg", SENVCFG),
+    ("sscratch", SSCRATCH),
+    ("sepc", SEPC),
+    ("scause", SCAUSE),
+    ("stval", STVAL),
+    ("sip", SIP),
+    ("stimecmp", STIMECMP),
+    ("siselect", SISELECT),
+    ("sireg", SIREG),
+    ("stopei", STOPEI),
+    ("satp", SATP),
+    ("vsstatus", VSSTATUS),
+    ("vsie", VSIE),
+    ("vstvec", VSTVEC),
+    ("vsscratch", VSSCRATCH),
+    ("vsepc", VSEPC),
+    ("vscause", VSCAUSE),
+    ("vstval", VSTVAL),
+    ("vsip", VSIP),
+    ("vstimecmp", VSTIMECMP),
+    ("vsiselect", VSISELECT),
+    ("vsatp", VSATP),
+    ("hstatus", HSTATUS),
+    ("hedeleg", HEDELEG),
+    ("hideleg", HIDELEG),
+    ("hie", HIE),
+    ("htimedelta", HTIMEDELTA),
+    ("hcounteren", HCOUNTEREN),
+    ("hgeie", HGEIE),
+    ("hvien", HVIEN),
+    ("hvictl", HVICTL),
+    ("henvcfg", HENVCFG),
+    ("htval", HTVAL),
+    ("hip", HIP),
+    ("hvip", HVIP),
+    ("hviprio1", HVIPRIO1),
+    ("hviprio2", HVIPRIO2),
+    ("htinst", HTINST),
+    ("hgatp", HGATP),
+    ("hgeip", HGEIP),
+    ("mstatus", MSTATUS),
+    ("misa", MISA),
+    ("medeleg", MEDELEG),
+    ("mideleg", MIDELEG),
+    ("mie", MIE),
+    ("mtvec", MTVEC),
+    ("mcounteren", MCOUNTEREN),
+    ("mvien", MVIEN),
+    ("mvip", MVIP),
+    ("menvcfg", MENVCFG),
+    ("mcountinhibit", MCOUNTINHIBIT),
+    ("mhpmevent3", MHPMEVENT3),
+    ("mhpmevent4", MHPMEVENT3 + 1),
+    ("mhpmevent5", MHPMEVENT3 + 2),
+    ("mhpmevent6", MHPMEVENT3 + 3),
+    ("mhpmevent7", MHPMEVENT3 + 4),
+    ("mhpmevent8", MHPMEVENT3 + 5),
+    ("mhpmevent9", MHPMEVENT3 + 6),
+    ("mhpmevent10", MHPMEVENT3 + 7),
+    ("mhpmevent11", MHPMEVENT3 + 8),
+    ("mhpmevent12", MHPMEVENT3 + 9),
+    ("mhpmevent13", MHPMEVENT3 + 10),
+    ("mhpmevent14", MHPMEVENT3 + 11),
+    ("mhpmevent15", MHPMEVENT3 + 12),
+    ("mhpmevent16", MHPMEVENT3 + 13),
+    ("mhpmevent17", MHPMEVENT3 + 14),
+    ("mhpmevent18", MHPMEVENT3 + 15),
+    ("mhpmevent19", MHPMEVENT3 + 16),
+    ("mhpmevent20", MHPMEVENT3 + 17),
+    ("mhpmevent21", MHPMEVENT3 + 18),
+    ("mhpmevent22", MHPMEVENT3 + 19),
+    ("mhpmevent23", MHPMEVENT3 + 20),
+    ("mhpmevent24", MHPMEVENT3 + 21),
+    ("mhpmevent25", MHPMEVENT3 + 22),
+    ("mhpmevent26", MHPMEVENT3 + 23),
+    ("mhpmevent27", MHPMEVENT3 + 24),
+    ("mhpmevent28", MHPMEVENT3 + 25),
+    ("mhpmevent29", MHPMEVENT3 + 26),
+    ("mhpmevent30", MHPMEVENT3 + 27),
+    ("mhpmevent31", MHPMEVENT31),
+    ("mscratch", MSCRATCH),
+    ("mepc", MEPC),
+    ("mcause", MCAUSE),
+    ("mtval", MTVAL),
+    ("mip", MIP),
+    ("mtinst", MTINST),
+    ("mtval2", MTVAL2),
+    ("miselect", MISELECT),
+    ("mireg", MIREG),
+    ("mtopei", MTOPEI),
+    ("pmpcfg0", PMPCFG0),
+    ("pmpcfg1", PMPCFG0 + 1),
+    ("pmpcfg2", PMPCFG0 + 2),
+    ("pmpcfg3", PMPCFG3),
+    ("pmpaddr0", PMPADDR0),
+    ("pmpaddr1", PMPADDR0 + 1),
+    ("pmpaddr2", PMPADDR0 + 2),
+    ("pmpaddr3", PMPADDR0 + 3),
+    ("pmpaddr4", PMPADDR0 + 4),
+    ("pmpaddr5", PMPADDR0 + 5),
+    ("pmpaddr6", PMPADDR0 + 6),
+    ("pmpaddr7", PMPADDR0 + 7),
+    ("pmpaddr8", PMPADDR0 + 8),
+    ("pmpaddr9", PMPADDR0 + 9),
+    ("pmpaddr10", PMPADDR0 + 10),
+    ("pmpaddr11", PMPADDR0 + 11),
+    ("pmpaddr12", PMPADDR0 + 12),
+    ("pmpaddr13", PMPADDR0 + 13),
+    ("pmpaddr14", PMPADDR0 + 14),
+    ("pmpaddr15", PMPADDR15),
+    ("mseccfg", MSECCFG),
+    ("mcycle", MCYCLE),
+    ("minstret", MINSTRET),
+    ("mhpmcounter3", MHPMCOUNTER3),
+    ("mhpmcounter4", MHPMCOUNTER3 + 1),
+    ("mhpmcounter5", MHPMCOUNTER3 + 2),
+    ("mhpmcounter6", MHPMCOUNTER3 + 3),
+    ("mhpmcounter7", MHPMCOUNTER3 + 4),
+    ("mhpmcounter8", MHPMCOUNTER3 + 5),
+    ("mhpmcounter9", MHPMCOUNTER3 + 6),
+    ("mhpmcounter10", MHPMCOUNTER3 + 7),
+    ("mhpmcounter11", MHPMCOUNTER3 + 8),
+    ("mhpmcounter12", MHPMCOUNTER3 + 9),
+    ("mhpmcounter13", MHPMCOUNTER3 + 10),
+    ("mhpmcounter14", MHPMCOUNTER3 + 11),
+    ("mhpmcounter15", MHPMCOUNTER3 + 12),
+    ("mhpmcounter16", MHPMCOUNTER3 + 13),
+    ("mhpmcounter17", MHPMCOUNTER3 + 14),
+    ("mhpmcounter18", MHPMCOUNTER3 + 15),
+    ("mhpmcounter19", MHPMCOUNTER3 + 16),
+    ("mhpmcounter20", MHPMCOUNTER3 + 17),
+    ("mhpmcounter21", MHPMCOUNTER3 + 18),
+    ("mhpmcounter22", MHPMCOUNTER3 + 19),
+    ("mhpmcounter23", MHPMCOUNTER3 + 20),
+    ("mhpmcounter24", MHPMCOUNTER3 + 21),
+    ("mhpmcounter25", MHPMCOUNTER3 + 22),
+    ("mhpmcounter26", MHPMCOUNTER3 + 23),
+    ("mhpmcounter27", MHPMCOUNTER3 + 24),
+    ("mhpmcounter28", MHPMCOUNTER3 + 25),
+    ("mhpmcounter29", MHPMCOUNTER3 + 26),
+    ("mhpmcounter30", MHPMCOUNTER3 + 27),
+    ("mhpmcounter31", MHPMCOUNTER31),
+    ("cycle", CYCLE),
+    ("time", TIME),
+    ("instret", INSTRET),
+    ("hpmcounter3", HPMCOUNTER3),
+    ("hpmcounter4", HPMCOUNTER3 + 1),
+    ("hpmcounter5", HPMCOUNTER3 + 2),
+    ("hpmcounter6", HPMCOUNTER3 + 3),
+    ("hpmcounter7", HPMCOUNTER3 + 4),
+    ("hpmcounter8", HPMCOUNTER3 + 5),
+    ("hpmcounter9", HPMCOUNTER3 + 6),
+    ("hpmcounter10", HPMCOUNTER3 + 7),
+    ("hpmcounter11", HPMCOUNTER3 + 8),
+    ("hpmcounter12", HPMCOUNTER3 + 9),
+    ("hpmcounter13", HPMCOUNTER3 + 10),
+    ("hpmcounter14", HPMCOUNTER3 + 11),
+    ("hpmcounter15", HPMCOUNTER3 + 12),
+    ("hpmcounter16", HPMCOUNTER3 + 13),
+    ("hpmcounter17", HPMCOUNTER3 + 14),
+    ("hpmcounter18", HPMCOUNTER3 + 15),
+    ("hpmcounter19", HPMCOUNTER3 + 16),
+    ("hpmcounter20", HPMCOUNTER3 + 17),
+    ("hpmcounter21", HPMCOUNTER3 + 18),
+    ("hpmcounter22", HPMCOUNTER3 + 19),
+    ("hpmcounter23", HPMCOUNTER3 + 20),
+    ("hpmcounter24", HPMCOUNTER3 + 21),
+    ("hpmcounter25", HPMCOUNTER3 + 22),
+    ("hpmcounter26", HPMCOUNTER3 + 23),
+    ("hpmcounter27", HPMCOUNTER3 + 24),
+    ("hpmcounter28", HPMCOUNTER3 + 25),
+    ("hpmcounter29", HPMCOUNTER3 + 26),
+    ("hpmcounter30", HPMCOUNTER3 + 27),
+    ("hpmcounter31", HPMCOUNTER31),
+    ("vl", VL),
+    ("vtype", VTYPE),
+    ("vlenb", VLENB),
+    ("scountovf", SCOUNTOVF),
+    ("stopi", STOPI),
+    ("vstopi", VSTOPI),
+    ("mvendorid", MVENDORID),
+    ("marchid", MARCHID),
+    ("mimpid", MIMPID),
+    ("mhartid", MHARTID),
+    ("mconfigptr", MCONFIGPTR),
+    ("mtopi", MTOPI),
+];
+
+/// The A, C, D, F, I, M, S and U extensions, below the MXL field.
+const MISA_EXTENSIONS: u64 = isa_bits(b"acdfimsu");
+const MISA_V: u64 = isa_bits(b"v");
+const MISA_H: u64 = isa_bits(b"h");
+
+/// Page size and Sv32/Sv39 parameters
+pub const PAGE_SIZE: u64 = 4096;
+const SATP_MODE_SV39: u64 = 8;
+const SATP_MODE_SV32: u64 = 1;
+const HGATP_MODE_SV39X4: u64 = 8;
+const HGATP_MODE_SV48X4: u64 = 9;
+const PTE_V: u64 = 1 << 0;
+const PTE_R: u64 = 1 << 1;
+const PTE_W: u64 = 1 << 2;
+const PTE_X: u64 = 1 << 3;
+const PTE_U: u64 = 1 << 4;
+const PTE_A: u64 = 1 << 6;
+const PTE_D: u64 = 1 << 7;
+// The memory type of Svpbmt and the NAPOT bit of Svnapot; bits 60:54 are
+// reserved.
+const PTE_PBMT: u64 = 0b11 << 61;
+const PTE_N: u64 = 1 << 63;
+const PTE_RESERVED: u64 = 0x7f << 54;
+
+const fn isa_bits(extensions: &[u8]) -> u64 {
+    let mut bits = 0;
+    let mut i = 0;
+    while i < extensions.len() {
+        bits |= 1 << (extensions[i] - b'a');
+        i += 1;
+    }
+    bits
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessType {
+    Instruction,
+    Load,
+    Store,
+}
+
+impl AccessType {
+    fn page_fault(self, addr: u64) -> Exception {
+        match self {
+            AccessType::Instruction => Exception::InstructionPageFault(addr),
+            AccessType::Load => Exception::LoadPageFault(addr),
+            AccessType::Store => Exception::StoreAMOPageFault(addr),
+        }
+    }
+
+    fn guest_page_fault(self, addr: u64, guest_addr: u64) -> Exception {
+        match self {
+            AccessType::Instruction => Exception::InstructionGuestPageFault(addr, guest_addr),
+            AccessType::Load => Exception::LoadGuestPageFault(addr, guest_addr),
+            AccessType::Store => Exception::StoreAMOGuestPageFault(addr, guest_addr),
+        }
+    }
+
+    fn access_fault(self, addr: u64) -> Exception {
+        match self {
+            AccessType::Instruction => Exception::InstructionAccessFault(addr),
+            AccessType::Load => Exception::LoadAccessFault(addr),
+            AccessType::Store => Exception::StoreAMOAccessFault(addr),
+        }
+    }
+}
+
+/// A stage of address translation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Stage {
+    /// satp, for an access from the given mode
+    Supervisor(u64),
+    /// vsatp, for an access from the given mode of a guest
+    VirtualSupervisor(u64),
+    /// hgatp, for the guest physical address of an access
+    Guest,
+    /// hgatp, for a read of the guest's page tables
+    GuestPageTable,
+}
+
+/// The shape of a page table: Sv32, Sv39 or Sv48, or for the G-stage one of
+/// them with a root table four times as large.
+#[derive(Clone, Copy)]
+struct PageTableFormat {
+    levels: u64,
+    vpn_bits: u64,
+    pte_size: u64,
+    ppn_mask: u64,
+    /// Extra VPN bits indexing the root table.
+    root_bits: u64,
+}
+
+const SV32: PageTableFormat = PageTableFormat { levels: 2, vpn_bits: 10, pte_size: 4, ppn_mask: 0x3f_ffff, root_bits: 0 };
+const SV39: PageTableFormat =
+    PageTableFormat { levels: 3, vpn_bits: 9, pte_size: 8, ppn_mask: 0xfff_ffff_ffff, root_bits: 0 };
+const SV48: PageTableFormat =
+    PageTableFormat { levels: 4, vpn_bits: 9, pte_size: 8, ppn_mask: 0xfff_ffff_ffff, root_bits: 0 };
+
+impl PageTableFormat {
+    /// The Sv32x4, Sv39x4 or Sv48x4 G-stage format.
+    const fn widened(self) -> Self {
+        PageTableFormat { root_bits: 2, ..self }
+    }
+
+    /// The width of the addresses it translates.
+    fn address_bits(self) -> u64 {
+        12 + self.levels * self.vpn_bits + self.root_bits
+    }
+}
 
 pub struct Cpu {
     pub regs: [u64; 32],
+    /// Floating-point registers, holding NaN-boxed single-precision values.
+    pub fregs: [u64; 32],
+    /// The 32 vector registers, VLEN bits each, as little-endian bytes.
+    pub vregs: Vec<u8>,
+    /// VLEN and ELEN in bits.
+    pub vlen: u32,
+    pub elen: u32,
+    /// Size of the blocks the cache-block instructions work on, in bytes.
+    pub cache_block_size: u64,
     pub pc: u64,
-    // pub csrs: [u64; 4096],
-    pub bus: Bus,
+    pub csrs: [u64; 4096],
+    pub mode: u64,
+    /// The V bit of the hypervisor extension: set while a guest runs, in
+    /// VS-mode or VU-mode depending on `mode`.
+    pub virt: bool,
+    /// Whether the last address translated was a guest virtual one, so that
+    /// a trap it causes sets GVA.
+    pub virtual_access: bool,
+    /// Set while a hypervisor load or store runs, to whether it is an HLVX
+    /// one reading executable rather than readable memory.
+    pub guest_access: Option<bool>,
+    pub hartid: usize,
+    /// 32 or 64. On RV32 the registers hold sign-extended 32-bit values.
+    pub xlen: u32,
+    /// The optional extensions the hart implements.
+    pub isa: Isa,
+    /// Whether the hart has IMSIC interrupt files, reached through the AIA
+    /// CSRs.
+    pub imsic: bool,
+    pub bus: Arc<Mutex<Bus>>,
+    /// Host services for semihosting calls, when enabled.
+    pub semihosting: Option<Arc<Mutex<Semihosting>>>,
+    /// Built-in SBI servicing `ecall`s from S-mode, when enabled.
+    pub sbi: Option<Arc<Mutex<Sbi>>>,
+    /// Length of the instruction being executed, 2 for compressed ones.
+    pub inst_len: u64,
+    /// Interrupt lines driven by the CLINT and the PLIC, as mip bits.
+    pub mip_hw: u64,
+    /// The machine timer as of this step, read through the `time` CSR.
+    pub time: u64,
+    /// The programmable counters whose mhpmevent selects an event, as
+    /// mcountinhibit bits.
+    pub hpm_counting: u32,
+    /// The counters the instruction being executed wrote, which it then
+    /// does not advance.
+    pub counters_written: u32,
+    /// Set when the guest executes `SNAPSHOT_HINT`, until the machine takes
+    /// the snapshot.
+    pub snapshot_requested: bool,
+    /// Memory the debugger watches, by virtual address.
+    pub watchpoints: Vec<Watchpoint>,
+    /// The last watchpoint an access hit, until the debugger takes it.
+    pub watch_hit: Option<Watchpoint>,
 }
 
 impl Cpu {
-    pub fn new(binary: Vec<u8>) -> Self {
+    pub fn new(bus: Arc<Mutex<Bus>>, config: &MachineConfig, hartid: usize) -> Self {
+        let xlen = config.xlen();
         let mut regs = [0; 32];
-        regs[2] = DRAM_BASE + DRAM_SIZE;
+        regs[2] = config.stack_pointer(hartid);
+        if xlen == 32 {
+            regs[2] = regs[2] as i32 as i64 as u64;
+        }
+        // Like most boot loaders, hand every hart its id in a0.
+        regs[10] = hartid as u64;
+
+        let mut csrs = [0; 4096];
+        csrs[MHARTID] = hartid as u64;
+        // The FPU is usable straight away for bare metal programs that do
+        // not set it up themselves.
+        if xlen == 32 {
+            csrs[MISA] = (1 << 30) | MISA_EXTENSIONS;
+            csrs[MSTATUS] = FS_INITIAL;
+        } else {
+            // 64-bit U and S modes
+            csrs[MISA] = (2 << 62) | MISA_EXTENSIONS;
+            csrs[MSTATUS] = (2 << 32) | (2 << 34) | FS_INITIAL;
+        }
+        // So is the vector unit, with vtype illegal until the first vsetvl.
+        if config.isa.has(V) {
+            csrs[MISA] |= MISA_V;
+            csrs[MSTATUS] |= VS_INITIAL;
+        }
+        if config.isa.has(H) {
+            csrs[MISA] |= MISA_H;
+            // Guests run at the XLEN of the hypervisor.
+            csrs[HSTATUS] = if xlen == 32 { 0 } else { 2 << 32 };
+            csrs[MIDELEG] = HYPERVISOR_INTERRUPTS;
+        }
+        if config.isa.has(SSTC) {
+            // No supervisor timer interrupt until the kernel sets one up.
+            csrs[STIMECMP] = u64::MAX;
+            csrs[VSTIMECMP] = u64::MAX;
+        }
+        csrs[VTYPE] = 1 << (xlen - 1);
+        csrs[VLENB] = config.vlen as u64 / 8;
 
         Self {
             regs,
-            pc: DRAM_BASE,
-            bus: Bus::new(binary)
+            fregs: [0; 32],
+            vregs: vec![0; 32 * config.vlen as usize / 8],
+            vlen: config.vlen,
+            elen: config.elen,
+            cache_block_size: config.cache_block_size,
+            pc: config.dram_base,
+            csrs,
+            mode: MACHINE,
+            virt: false,
+            virtual_access: false,
+            guest_access: None,
+            hartid,
+            xlen,
+            isa: config.isa,
+            imsic: config.interrupt_controller == InterruptController::AplicImsic,
+            bus,
+            semihosting: None,
+            sbi: None,
+            inst_len: 4,
+            mip_hw: 0,
+            time: 0,
+            hpm_counting: 0,
+            counters_written: 0,
+            snapshot_requested: false,
+            watchpoints: Vec::new(),
+            watch_hit: None,
+        }
+    }
+
+    /// Writes the registers, CSRs, privilege mode and V bit to a snapshot.
+    pub fn save(&self, out: &mut StateWriter) {
+        for &reg in self.regs.iter().chain(&self.fregs) {
+            out.u64(reg);
+        }
+        out.bytes(&self.vregs);
+        out.u64(self.pc);
+        out.u64(self.mode);
+        out.bool(self.virt);
+        // Most CSRs are zero or do not exist.
+        let csrs: Vec<(usize, u64)> = self.csrs.iter().copied().enumerate().filter(|&(_, v)| v != 0).collect();
+        out.u64(csrs.len() as u64);
+        for (addr, value) in csrs {
+            out.u16(addr as u16);
+            out.u64(value);
         }
     }
 
+    pub fn restore(&mut self, input: &mut StateReader) -> io::Result<()> {
+        for reg in self.regs.iter_mut().chain(self.fregs.iter_mut()) {
+            *reg = input.u64()?;
+        }
+        let vregs = input.bytes()?;
+        if vregs.len() != self.vregs.len() {
+            return Err(invalid_snapshot("the vector registers have a different length"));
+        }
+        self.vregs.copy_from_slice(vregs);
+        self.pc = input.u64()?;
+        self.mode = input.u64()?;
+        self.virt = input.bool()?;
+        self.csrs = [0; 4096];
+        for _ in 0..input.u64()? {
+            let addr = input.u16()? as usize;
+            let value = input.u64()?;
+            *self.csrs.get_mut(addr).ok_or_else(|| invalid_snapshot("bad CSR address"))? = value;
+        }
+        if self.csrs[MHARTID] != self.hartid as u64 {
+            return Err(invalid_snapshot("harts are out of order"));
+        }
+        self.update_hpm_counting();
+        Ok(())
+    }
+
     // pub fn fetch(&self) -> u32 {
     //     let index = self.pc as usize;
     //     return (self.dram[index] as u32)
@@ -28,44 +778,1132 @@ impl Cpu {
     //         | ((self.dram[index + 3] as u32) << 24);
     // }
 
-    pub fn load(&mut self, addr: u64, size: u64) -> Result<u64, ()> {
-        self.bus.load(addr, size)
+    pub fn load(&mut self, addr: u64, size: u64) -> Result<u64, Exception> {
+        let addr = self.truncate(addr);
+        if !self.watchpoints.is_empty() {
+            self.watch(addr, size, false);
+        }
+        if crosses_page(addr, size) {
+            let mut value = 0;
+            for i in 0..size / 8 {
+                value |= self.load(addr.wrapping_add(i), 8)? << (i * 8);
+            }
+            return Ok(value);
+        }
+        let paddr = self.translate(addr, AccessType::Load)?;
+        self.check_pmp(addr, paddr, size, AccessType::Load)?;
+        self.bus
+            .lock()
+            .unwrap()
+            .load(paddr, size)
+            .map_err(|_| Exception::LoadAccessFault(addr))
     }
 
-    pub fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), ()> {
-        self.bus.store(addr, size, value)
+    pub fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
+        let addr = self.truncate(addr);
+        if !self.watchpoints.is_empty() {
+            self.watch(addr, size, true);
+        }
+        if crosses_page(addr, size) {
+            // Translate every byte first so that a fault leaves memory alone.
+            for i in 0..size / 8 {
+                let paddr = self.translate(addr.wrapping_add(i), AccessType::Store)?;
+                self.check_pmp(addr.wrapping_add(i), paddr, 8, AccessType::Store)?;
+            }
+            for i in 0..size / 8 {
+                self.store(addr.wrapping_add(i), 8, value >> (i * 8))?;
+            }
+            return Ok(());
+        }
+        let paddr = self.translate(addr, AccessType::Store)?;
+        self.check_pmp(addr, paddr, size, AccessType::Store)?;
+        self.bus
+            .lock()
+            .unwrap()
+            .store(paddr, size, value)
+            .map_err(|_| Exception::StoreAMOAccessFault(addr))
     }
 
-    pub fn fetch(&mut self) -> Result<u64, ()> {
-        match self.bus.load(self.pc, 32) {
-            Ok(inst) => Ok(inst),
-            Err(_) => Err(()),
+    /// Executes the Zicbom and Zicboz instructions on the cache block
+    /// holding the address in rs1. Without caches only cbo.zero has an
+    /// effect, but all of them check that the envcfg CSRs allow them and
+    /// that the block may be accessed.
+    fn cache_block_op(&mut self, instruction: u32) -> Result<(), Exception> {
+        let illegal = Exception::IllegalInstruction(instruction as u64);
+        let rd = (instruction >> 7) & 0x1f;
+        let rs1 = ((instruction >> 15) & 0x1f) as usize;
+        let (extension, field) = match instruction >> 20 {
+            0x000 => (ZICBOM, ENVCFG_CBIE),  // cbo.inval
+            0x001 => (ZICBOM, ENVCFG_CBCFE), // cbo.clean
+            0x002 => (ZICBOM, ENVCFG_CBCFE), // cbo.flush
+            0x004 => (ZICBOZ, ENVCFG_CBZE),  // cbo.zero
+            _ => return Err(illegal),
+        };
+        if rd != 0 || !self.isa.has(extension) {
+            return Err(illegal);
+        }
+        self.envcfg_allows(field, instruction)?;
+
+        let block = self.regs[rs1] & !(self.cache_block_size - 1);
+        if extension == ZICBOZ {
+            for offset in (0..self.cache_block_size).step_by(8) {
+                self.store(block.wrapping_add(offset), 64, 0)?;
+            }
+            return Ok(());
+        }
+        // Management needs memory behind a page that can be read or
+        // written, and that PMP lets the hart read or write; faults are
+        // reported as store ones.
+        let addr = self.truncate(block);
+        match self.translate(addr, AccessType::Load) {
+            Err(Exception::LoadPageFault(addr)) => Err(Exception::StoreAMOPageFault(addr)),
+            Err(Exception::LoadAccessFault(addr)) => Err(Exception::StoreAMOAccessFault(addr)),
+            Err(Exception::LoadGuestPageFault(addr, gpa)) => Err(Exception::StoreAMOGuestPageFault(addr, gpa)),
+            Err(e) => Err(e),
+            Ok(paddr) => {
+                let (mode, _) = self.effective_privilege(AccessType::Load);
+                let size = self.cache_block_size * 8;
+                let pmp = self.pmp_allows(paddr, size, AccessType::Load, mode)
+                    || self.pmp_allows(paddr, size, AccessType::Store, mode);
+                if pmp && self.bus.lock().unwrap().is_memory(paddr) {
+                    Ok(())
+                } else {
+                    Err(Exception::StoreAMOAccessFault(addr))
+                }
+            }
         }
     }
 
-    // fn load_csr(&self, addr: usize) -> u64 {
-    //     match addr {
-    //         SIE => self.csrs[MIE] & self.csrs[MIDELEG],
-    //         _ => self.csrs[addr],
-    //     }
-    // }
+    /// Cuts an address or register value down to XLEN bits.
+    pub fn truncate(&self, value: u64) -> u64 {
+        if self.xlen == 32 {
+            value as u32 as u64
+        } else {
+            value
+        }
+    }
 
-    // fn store_csr(&mut self, addr: usize, value: u64)  {
-    //     match addr {
-    //         SIE => {
-    //             self.csrs[MIE] = (self.csrs[MIE] & !self.csrs[MIDELEG]) | (value & self.csrs[MIDELEG]);
-    //         }
-    //         _ => self.csrs[addr] = value,
-    //     }
-    // }
+    /// Sign-extends an XLEN-bit value to the 64 bits the registers hold.
+    pub fn sign_extend(&self, value: u64) -> u64 {
+        if self.xlen == 32 {
+            value as i32 as i64 as u64
+        } else {
+            value
+        }
+    }
+
+    /// Notes an access of `size` bits at `addr` that a watchpoint covers.
+    fn watch(&mut self, addr: u64, size: u64, write: bool) {
+        let end = addr.wrapping_add(size / 8);
+        let hit = self.watchpoints.iter().find(|watch| {
+            watch.addr < end && addr < watch.addr.wrapping_add(watch.len) && watch.kind.covers(write)
+        });
+        if let Some(&watch) = hit {
+            self.watch_hit = Some(watch);
+        }
+    }
+
+    /// Fetches 16 bits of instruction memory.
+    fn fetch16(&mut self, addr: u64) -> Result<u64, Exception> {
+        let paddr = self.translate(addr, AccessType::Instruction)?;
+        self.check_pmp(addr, paddr, 16, AccessType::Instruction)?;
+        self.bus
+            .lock()
+            .unwrap()
+            .load(paddr, 16)
+            .map_err(|_| Exception::InstructionAccessFault(addr))
+    }
+
+    /// Fetches the instruction at pc, expanding compressed instructions, and
+    /// sets `inst_len`.
+    pub fn fetch(&mut self) -> Result<u64, Exception> {
+        let low = self.fetch16(self.pc)?;
+        if low & 0x3 != 0x3 {
+            self.inst_len = 2;
+            return match expand_compressed(low as u16, self.xlen) {
+                Some(inst) => Ok(inst as u64),
+                None => Err(Exception::IllegalInstruction(low)),
+            };
+        }
+
+        self.inst_len = 4;
+        let high = self.fetch16(self.pc.wrapping_add(2))?;
+        Ok(low | (high << 16))
+    }
+
+    /// Runs a single instruction, first taking any pending interrupt.
+    ///
+    /// Exceptions are returned with pc still pointing at the faulting
+    /// instruction; `handle_exception` turns them into traps.
+    pub fn step(&mut self) -> Result<(), Exception> {
+        if let Some(sbi) = self.sbi.clone() {
+            if !sbi.lock().unwrap().running(self) {
+                return Ok(());
+            }
+        }
+
+        let mut bus = self.bus.lock().unwrap();
+        self.mip_hw = bus.pending_interrupts(self.hartid);
+        self.time = bus.time();
+        if self.sbi.is_some() {
+            // Do what M-mode firmware would: hand the machine timer to the
+            // supervisor and turn IPIs into supervisor software interrupts.
+            if self.mip_hw & MSIP_BIT != 0 {
+                bus.clint.as_mut().unwrap().msip[self.hartid] = false;
+                self.csrs[MIP] |= SSIP_BIT;
+            }
+            let timer = if self.mip_hw & MTIP_BIT != 0 { STIP_BIT } else { 0 };
+            self.mip_hw = (self.mip_hw & SEIP_BIT) | timer;
+        }
+        drop(bus);
+        if self.csrs[MENVCFG] & ENVCFG_STCE != 0 {
+            self.mip_hw |= self.sstc_interrupts();
+        }
+        if let Some(interrupt) = self.pending_interrupt() {
+            self.handle_interrupt(interrupt);
+        }
+
+        let pc = self.pc;
+        let result = self.fetch().and_then(|instruction| {
+            // Add the instruction length to the program counter, then decode
+            // and execute.
+            self.pc = self.pc.wrapping_add(self.inst_len);
+            self.execute(instruction as u32)
+        });
+        // Instructions that name x0 as their destination write it anyway.
+        self.regs[0] = 0;
+        if self.xlen == 32 {
+            // Most instructions compute in 64 bits; keeping the registers
+            // sign-extended makes that give the RV32 result.
+            for reg in &mut self.regs[1..] {
+                *reg = *reg as i32 as i64 as u64;
+            }
+            self.pc = self.pc as u32 as u64;
+        }
+        if result.is_err() {
+            self.pc = pc;
+        }
+        self.count(result.is_ok());
+        result
+    }
+
+    /// Whether the `ebreak` just executed sits between the semihosting
+    /// entry and exit markers.
+    fn is_semihosting_call(&mut self) -> bool {
+        let ebreak = self.pc.wrapping_sub(4);
+        self.inst_len == 4
+            && matches!(self.load(ebreak.wrapping_sub(4), 32), Ok(inst) if inst as u32 == SEMIHOSTING_ENTRY)
+            && matches!(self.load(self.pc, 32), Ok(inst) if inst as u32 == SEMIHOSTING_EXIT)
+    }
+
+    /// Translates a virtual address with Sv39, or Sv32 on RV32, when paging
+    /// is active for the effective privilege mode of the access. In a guest,
+    /// or for a hypervisor load or store, the G-stage then translates the
+    /// guest physical address that gives.
+    pub fn translate(&mut self, addr: u64, access: AccessType) -> Result<u64, Exception> {
+        let (mode, virt) = self.effective_privilege(access);
+        self.virtual_access = virt;
+        if !virt {
+            if mode == MACHINE {
+                return Ok(addr);
+            }
+            return self.translate_stage(addr, addr, access, Stage::Supervisor(mode));
+        }
+        let guest_addr = self.translate_stage(addr, addr, access, Stage::VirtualSupervisor(mode))?;
+        self.translate_stage(addr, guest_addr, access, Stage::Guest)
+    }
+
+    /// The privilege mode, and whether in a guest, that an access is made
+    /// with: the hart's own for fetches, and for loads and stores the one
+    /// mstatus.MPRV or a hypervisor load or store picks.
+    fn effective_privilege(&self, access: AccessType) -> (u64, bool) {
+        let status = self.csrs[MSTATUS];
+        match access {
+            _ if self.guest_access.is_some() => ((self.csrs[HSTATUS] & HSTATUS_SPVP) >> 8, true),
+            AccessType::Instruction => (self.mode, self.virt),
+            _ if status & MSTATUS_MPRV != 0 => {
+                let mode = (status & MSTATUS_MPP) >> 11;
+                (mode, mode != MACHINE && status & MSTATUS_MPV != 0)
+            }
+            _ => (self.mode, self.virt),
+        }
+    }
+
+    /// Checks that PMP lets the hart make an access of `size` bits at
+    /// physical address `paddr`, which `vaddr` translated to.
+    fn check_pmp(&self, vaddr: u64, paddr: u64, size: u64, access: AccessType) -> Result<(), Exception> {
+        let (mode, _) = self.effective_privilege(access);
+        if self.pmp_allows(paddr, size, access, mode) {
+            Ok(())
+        } else {
+            Err(access.access_fault(vaddr))
+        }
+    }
+
+    /// Translates `addr` through one stage, with the page table of satp,
+    /// vsatp or hgatp. `vaddr` is the address the access was made to, for
+    /// the exceptions.
+    fn translate_stage(&mut self, vaddr: u64, addr: u64, access: AccessType, stage: Stage) -> Result<u64, Exception> {
+        let sv32 = self.xlen == 32;
+        let (atp, format) = match stage {
+            Stage::Supervisor(_) | Stage::VirtualSupervisor(_) => {
+                let atp = if matches!(stage, Stage::Supervisor(_)) {
+                    self.csrs[SATP]
+                } else {
+                    self.csrs[VSATP]
+                };
+                let format = match (sv32, if sv32 { atp >> 31 } else { atp >> 60 }) {
+                    (true, SATP_MODE_SV32) => SV32,
+                    (false, SATP_MODE_SV39) => SV39,
+                    _ => return Ok(addr),
+                };
+                (atp, format)
+            }
+            Stage::Guest | Stage::GuestPageTable => {
+                let atp = self.csrs[HGATP];
+                let format = match (sv32, if sv32 { atp >> 31 } else { atp >> 60 }) {
+                    (true, SATP_MODE_SV32) => SV32.widened(),
+                    (false, HGATP_MODE_SV39X4) => SV39.widened(),
+                    (false, HGATP_MODE_SV48X4) => SV48.widened(),
+                    _ => return Ok(addr),
+                };
+                (atp, format)
+            }
+        };
+
+        let page_fault = match stage {
+            Stage::Guest | Stage::GuestPageTable => access.guest_page_fault(vaddr, addr),
+            _ => access.page_fault(vaddr),
+        };
+        let access_fault = access.access_fault(vaddr);
+
+        let bits = format.address_bits();
+        let in_range = match stage {
+            // Guest physical addresses are zero-extended.
+            Stage::Guest | Stage::GuestPageTable => addr >> bits == 0,
+            // Virtual ones sign-extended, from bit 38 for Sv39.
+            _ => sv32 || ((addr as i64) << (64 - bits) >> (64 - bits)) as u64 == addr,
+        };
+        if !in_range {
+            return Err(page_fault);
+        }
+
+        // Sv32 walks two levels of 10-bit VPNs with 4-byte PTEs, Sv39 three
+        // levels of 9-bit VPNs with 8-byte PTEs; the G-stage root tables
+        // take two more bits.
+        let vpn = |level: u64| {
+            let width = if level == format.levels - 1 { format.vpn_bits + format.root_bits } else { format.vpn_bits };
+            (addr >> (12 + format.vpn_bits * level)) & ((1 << width) - 1)
+        };
+        let mut table = (atp & format.ppn_mask) * PAGE_SIZE;
+        let mut level = format.levels - 1;
+        // henvcfg turns on Svpbmt's memory types and Svadu's A and D updates
+        // for a guest's own page tables, menvcfg for the others.
+        let envcfg = match stage {
+            Stage::VirtualSupervisor(_) => self.csrs[HENVCFG],
+            _ => self.csrs[MENVCFG],
+        };
+        let (pte, pte_addr) = loop {
+            let mut pte_addr = table + vpn(level) * format.pte_size;
+            if matches!(stage, Stage::VirtualSupervisor(_)) {
+                // The guest's page tables are at guest physical addresses.
+                pte_addr = self.translate_stage(vaddr, pte_addr, access, Stage::GuestPageTable)?;
+            }
+            // Page tables are read with S-mode's PMP permissions.
+            if !self.pmp_allows(pte_addr, format.pte_size * 8, AccessType::Load, SUPERVISOR) {
+                return Err(access_fault);
+            }
+            let pte = self.bus.lock().unwrap().load(pte_addr, format.pte_size * 8).map_err(|_| access_fault)?;
+            let leaf = pte & (PTE_R | PTE_X) != 0;
+            if pte & PTE_V == 0 || (pte & PTE_R == 0 && pte & PTE_W != 0) || !self.pte_bits_valid(pte, leaf, envcfg) {
+                return Err(page_fault);
+            }
+            if leaf {
+                break (pte, pte_addr);
+            }
+            if level == 0 {
+                return Err(page_fault);
+            }
+            level -= 1;
+            table = ((pte >> 10) & format.ppn_mask) * PAGE_SIZE;
+        };
+
+        let status = self.csrs[MSTATUS];
+        let vsstatus = self.csrs[VSSTATUS];
+        let mxr = match stage {
+            // The guest's MXR only applies to its own page tables.
+            Stage::VirtualSupervisor(_) => (status | vsstatus) & MSTATUS_MXR != 0,
+            _ => status & MSTATUS_MXR != 0,
+        };
+        // HLVX needs execute permission instead of read permission.
+        let hlvx = self.guest_access == Some(true) && stage != Stage::GuestPageTable;
+        let allowed = match access {
+            _ if stage == Stage::GuestPageTable => pte & PTE_R != 0,
+            AccessType::Instruction => pte & PTE_X != 0,
+            AccessType::Load if hlvx => pte & PTE_X != 0,
+            AccessType::Load => pte & PTE_R != 0 || (pte & PTE_X != 0 && mxr),
+            AccessType::Store => pte & PTE_W != 0,
+        };
+        let user_ok = match stage {
+            Stage::Supervisor(USER) | Stage::VirtualSupervisor(USER) => pte & PTE_U != 0,
+            Stage::Supervisor(_) => pte & PTE_U == 0 || (access != AccessType::Instruction && status & MSTATUS_SUM != 0),
+            Stage::VirtualSupervisor(_) => {
+                pte & PTE_U == 0 || (access != AccessType::Instruction && vsstatus & MSTATUS_SUM != 0)
+            }
+            // Every G-stage access counts as a user one.
+            Stage::Guest | Stage::GuestPageTable => pte & PTE_U != 0,
+        };
+        if !allowed || !user_ok {
+            return Err(page_fault);
+        }
+
+        // Superpages must be aligned to their size.
+        let mut ppn = (pte >> 10) & format.ppn_mask;
+        if ppn & ((1 << (format.vpn_bits * level)) - 1) != 0 {
+            return Err(page_fault);
+        }
+        // A NAPOT PTE maps its page as part of a naturally aligned 64 KiB
+        // one, the only size Svnapot defines: a 4 KiB leaf with the low PPN
+        // bits 0b1000, standing for the page the address falls in.
+        if pte & PTE_N != 0 {
+            if level != 0 || ppn & 0xf != 0b1000 {
+                return Err(page_fault);
+            }
+            ppn = (ppn & !0xf) | ((addr >> 12) & 0xf);
+        }
+
+        // A has to be set, and D for a store. With menvcfg.ADUE, or for a
+        // guest's own page tables henvcfg.ADUE, the hart sets them itself
+        // (Svadu); otherwise it faults so that software can (Svade).
+        let store = access == AccessType::Store && stage != Stage::GuestPageTable;
+        let needed = if store { PTE_A | PTE_D } else { PTE_A };
+        if pte & needed != needed {
+            if envcfg & ENVCFG_ADUE == 0 {
+                return Err(page_fault);
+            }
+            // The PTE changed since it was read: walk the tables again. In a
+            // guest the G-stage only has to allow reading the PTE.
+            if !self.update_pte(pte_addr, format.pte_size * 8, pte, pte | needed, access_fault)? {
+                return self.translate_stage(vaddr, addr, access, stage);
+            }
+        }
+
+        let offset_mask = (1 << (12 + format.vpn_bits * level)) - 1;
+        Ok(((ppn * PAGE_SIZE) & !offset_mask) | (addr & offset_mask))
+    }
+
+    /// Whether the bits Svnapot and Svpbmt add to a PTE are valid: they are
+    /// only allowed in leaf PTEs, N with Svnapot and PBMT while `envcfg`
+    /// turns the memory types on, without the reserved type 3. The types
+    /// themselves make no difference, as memory has no caches.
+    fn pte_bits_valid(&self, pte: u64, leaf: bool, envcfg: u64) -> bool {
+        let pbmt = (pte & PTE_PBMT) >> 61;
+        pte & PTE_RESERVED == 0
+            && (pte & PTE_N == 0 || (leaf && self.isa.has(SVNAPOT)))
+            && (pbmt == 0 || (leaf && envcfg & ENVCFG_PBMTE != 0 && pbmt != 3))
+    }
+
+    /// Sets the A and D bits of the PTE at `addr` for Svadu, as an atomic
+    /// update that only writes `new` if the PTE still holds `old`. The write
+    /// is checked with S-mode's PMP permissions like the read.
+    fn update_pte(&mut self, addr: u64, size: u64, old: u64, new: u64, fault: Exception) -> Result<bool, Exception> {
+        if !self.pmp_allows(addr, size, AccessType::Store, SUPERVISOR) {
+            return Err(fault);
+        }
+        let mut bus = self.bus.lock().unwrap();
+        if bus.load(addr, size).map_err(|_| fault)? != old {
+            return Ok(false);
+        }
+        bus.store(addr, size, new).map_err(|_| fault)?;
+        Ok(true)
+    }
+
+    /// Returns the highest-priority interrupt that is pending, enabled and
+    /// not masked by the current privilege mode.
+    fn pending_interrupt(&self) -> Option<Interrupt> {
+        let pending = self.load_csr(MIP) & self.csrs[MIE];
+        if pending == 0 {
+            return None;
+        }
+
+        let status = self.csrs[MSTATUS];
+        let m_enabled = self.mode < MACHINE || status & MSTATUS_MIE != 0;
+        // A guest cannot mask the interrupts of its hypervisor, and only a
+        // guest takes the ones delegated to VS-mode.
+        let s_enabled =
+            self.virt || self.mode < SUPERVISOR || (self.mode == SUPERVISOR && status & MSTATUS_SIE != 0);
+        let vs_enabled = self.virt && (self.mode < SUPERVISOR || self.csrs[VSSTATUS] & MSTATUS_SIE != 0);
+        let delegated = self.csrs[MIDELEG];
+        let guest_delegated = self.csrs[HIDELEG];
+
+        Interrupt::ALL.into_iter().find(|i| {
+            let bit = 1 << i.code();
+            pending & bit != 0
+                && if delegated & bit == 0 {
+                    m_enabled
+                } else if guest_delegated & bit == 0 {
+                    s_enabled
+                } else {
+                    vs_enabled
+                }
+        })
+    }
+
+    /// Enters the trap handler for an exception raised by `step`.
+    pub fn handle_exception(&mut self, exception: Exception) {
+        let guest_virtual = self.virtual_access && exception.has_address();
+        self.trap(exception.code(), exception.value(), false, exception.guest_address(), guest_virtual);
+    }
+
+    pub fn handle_interrupt(&mut self, interrupt: Interrupt) {
+        self.trap(interrupt.code(), 0, true, 0, false);
+    }
+
+    /// Moves to the handler of a trap, in S-mode if it is delegated there and
+    /// the hart is not in M-mode, otherwise in M-mode. A trap from a guest
+    /// that the hypervisor delegates in turn goes to the guest's handler in
+    /// VS-mode. `self.pc` is the address to resume at; `guest_addr` is the
+    /// guest physical address of a guest-page fault, and `guest_virtual`
+    /// says whether `tval` is a guest virtual address.
+    fn trap(&mut self, cause: u64, tval: u64, interrupt: bool, guest_addr: u64, guest_virtual: bool) {
+        let (delegation, guest_delegation) = if interrupt {
+            (self.csrs[MIDELEG], self.csrs[HIDELEG])
+        } else {
+            (self.csrs[MEDELEG], self.csrs[HEDELEG])
+        };
+        let interrupt_bit = (interrupt as u64) << (self.xlen - 1);
+        let status = self.csrs[MSTATUS];
+
+        if self.virt && ((delegation & guest_delegation) >> cause) & 1 == 1 {
+            // The guest sees its interrupts as the supervisor ones.
+            let cause = if interrupt { cause - 1 } else { cause };
+            self.csrs[VSEPC] = self.pc;
+            self.csrs[VSCAUSE] = interrupt_bit | cause;
+            self.csrs[VSTVAL] = tval;
+            self.csrs[VSSTATUS] = supervisor_trap_status(self.csrs[VSSTATUS], self.mode);
+            self.mode = SUPERVISOR;
+            self.pc = trap_vector(self.csrs[VSTVEC], cause, interrupt);
+        } else if self.mode <= SUPERVISOR && (delegation >> cause) & 1 == 1 {
+            let vector = self.csrs[STVEC];
+            self.csrs[SEPC] = self.pc;
+            self.csrs[SCAUSE] = interrupt_bit | cause;
+            self.csrs[STVAL] = tval;
+            self.csrs[MSTATUS] = supervisor_trap_status(status, self.mode);
+            if self.isa.has(H) {
+                // SPV = V, SPVP = the guest's mode if it was in one
+                let mut hstatus = self.csrs[HSTATUS] & !(HSTATUS_SPV | HSTATUS_GVA);
+                if self.virt {
+                    hstatus = (hstatus & !HSTATUS_SPVP) | HSTATUS_SPV | (self.mode << 8);
+                }
+                if guest_virtual {
+                    hstatus |= HSTATUS_GVA;
+                }
+                self.csrs[HSTATUS] = hstatus;
+                self.csrs[HTVAL] = guest_addr >> 2;
+                self.csrs[HTINST] = 0;
+            }
+            self.mode = SUPERVISOR;
+            self.virt = false;
+            self.pc = trap_vector(vector, cause, interrupt);
+        } else {
+            let vector = self.csrs[MTVEC];
+            self.csrs[MEPC] = self.pc;
+            self.csrs[MCAUSE] = interrupt_bit | cause;
+            self.csrs[MTVAL] = tval;
+            // MPIE = MIE, MIE = 0, MPP = previous mode, MPV = V
+            let mut status = status & !(MSTATUS_MPIE | MSTATUS_MIE | MSTATUS_MPP | MSTATUS_MPV | MSTATUS_GVA);
+            if self.csrs[MSTATUS] & MSTATUS_MIE != 0 {
+                status |= MSTATUS_MPIE;
+            }
+            status |= self.mode << 11;
+            if self.isa.has(H) {
+                if self.virt {
+                    status |= MSTATUS_MPV;
+                }
+                if guest_virtual {
+                    status |= MSTATUS_GVA;
+                }
+                self.csrs[MTVAL2] = guest_addr >> 2;
+                self.csrs[MTINST] = 0;
+            }
+            self.csrs[MSTATUS] = status;
+            self.mode = MACHINE;
+            self.virt = false;
+            self.pc = trap_vector(vector, cause, interrupt);
+        }
+    }
+
+    /// The exception an instruction raises for accessing a CSR, and writing
+    /// it if `write` is set, if the current mode may not. In a guest, the
+    /// accesses HS-mode could make raise virtual instruction exceptions
+    /// instead, so that the hypervisor can emulate them.
+    fn csr_access_fault(&self, addr: usize, write: bool, instruction: u32) -> Option<Exception> {
+        let hypervisor_mode = if addr == SEED { self.mode } else { SUPERVISOR };
+        // hstatus.VTVM traps a guest's satp whatever mstatus.TVM says.
+        let vtvm = addr == SATP && self.csrs[HSTATUS] & HSTATUS_VTVM != 0;
+        if self.csr_accessible(addr, write, self.mode, self.virt) {
+            None
+        } else if self.virt && (vtvm || self.csr_accessible(addr, write, hypervisor_mode, false)) {
+            Some(Exception::VirtualInstruction(instruction as u64))
+        } else {
+            Some(Exception::IllegalInstruction(instruction as u64))
+        }
+    }
+
+    /// Whether `mode`, in a guest if `virt` is set, may access a CSR at all,
+    /// and write it if `write` is set.
+    fn csr_accessible(&self, addr: usize, write: bool, mode: u64, virt: bool) -> bool {
+        let known = matches!(
+            addr,
+            FFLAGS
+                | FRM
+                | FCSR
+                | SSTATUS
+                | SIE
+                | STVEC
+                | SCOUNTEREN
+                | SENVCFG
+                | SSCRATCH
+                | SEPC
+                | SCAUSE
+                | STVAL
+                | SIP
+                | SATP
+                | MSTATUS
+                | MISA
+                | MEDELEG
+                | MIDELEG
+                | MIE
+                | MTVEC
+                | MCOUNTEREN
+                | MENVCFG
+                | MSCRATCH
+                | MEPC
+                | MCAUSE
+                | MTVAL
+                | MIP
+                | MVENDORID
+                | MARCHID
+                | MIMPID
+                | MHARTID
+                | MCONFIGPTR
+                | CYCLE
+                | TIME
+                | INSTRET
+                | HPMCOUNTER3..=HPMCOUNTER31
+                | MCYCLE
+                | MINSTRET
+                | MHPMCOUNTER3..=MHPMCOUNTER31
+                | MCOUNTINHIBIT
+                | MHPMEVENT3..=MHPMEVENT31
+        ) || (matches!(addr, VSTART | VXSAT | VXRM | VCSR | VL | VTYPE | VLENB) && self.isa.has(V))
+            || (matches!(addr, PMPADDR0..=PMPADDR15))
+            || (matches!(addr, PMPCFG0..=PMPCFG3) && (self.xlen == 32 || addr & 1 == 0))
+            || (addr == SEED && self.isa.has(ZKR))
+            || (addr == MSECCFG && (self.isa.has(ZKR) || self.isa.has(SMEPMP)))
+            || (addr == MSECCFGH && (self.isa.has(ZKR) || self.isa.has(SMEPMP)) && self.xlen == 32)
+            || (matches!(addr, MSTATUSH | MENVCFGH) && self.xlen == 32)
+            || (matches!(
+                addr,
+                CYCLEH
+                    | TIMEH
+                    | INSTRETH
+                    | HPMCOUNTER3H..=HPMCOUNTER31H
+                    | MCYCLEH
+                    | MINSTRETH
+                    | MHPMCOUNTER3H..=MHPMCOUNTER31H
+            ) && self.xlen == 32)
+            || (addr == SCOUNTOVF && self.isa.has(SSCOFPMF))
+            || (matches!(addr, MHPMEVENT3H..=MHPMEVENT31H) && self.isa.has(SSCOFPMF) && self.xlen == 32)
+            || (addr == STIMECMP && self.isa.has(SSTC))
+            || (addr == STIMECMPH && self.isa.has(SSTC) && self.xlen == 32)
+            || (addr == VSTIMECMP && self.isa.has(SSTC) && self.isa.has(H))
+            || (addr == VSTIMECMPH && self.isa.has(SSTC) && self.isa.has(H) && self.xlen == 32)
+            || (matches!(
+                addr,
+                VSSTATUS
+                    | VSIE
+                    | VSTVEC
+                    | VSSCRATCH
+                    | VSEPC
+                    | VSCAUSE
+                    | VSTVAL
+                    | VSIP
+                    | VSATP
+                    | HSTATUS
+                    | HEDELEG
+                    | HIDELEG
+                    | HIE
+                    | HTIMEDELTA
+                    | HCOUNTEREN
+                    | HGEIE
+                    | HENVCFG
+                    | HTVAL
+                    | HIP
+                    | HVIP
+                    | HTINST
+                    | HGATP
+                    | HGEIP
+                    | MTINST
+                    | MTVAL2
+            ) && self.isa.has(H))
+            || (matches!(addr, HTIMEDELTAH | HENVCFGH) && self.isa.has(H) && self.xlen == 32)
+            || (matches!(addr, MISELECT | MIREG | MTOPI | MVIEN | MVIP) && self.isa.has(SMAIA))
+            || (matches!(addr, MIDELEGH | MIEH | MIPH | MVIENH | MVIPH) && self.isa.has(SMAIA) && self.xlen == 32)
+            || (addr == MTOPEI && self.isa.has(SMAIA) && self.imsic)
+            || (matches!(addr, SISELECT | SIREG | STOPI) && self.isa.has(SSAIA))
+            || (matches!(addr, SIEH | SIPH) && self.isa.has(SSAIA) && self.xlen == 32)
+            || (addr == STOPEI && self.isa.has(SSAIA) && self.imsic)
+            || (matches!(addr, VSISELECT | VSTOPI | HVIEN | HVICTL | HVIPRIO1 | HVIPRIO2)
+                && self.isa.has(SSAIA)
+                && self.isa.has(H))
+            || (matches!(addr, VSIEH | VSIPH | HIDELEGH | HVIENH | HVIPH | HVIPRIO1H | HVIPRIO2H)
+                && self.isa.has(SSAIA)
+                && self.isa.has(H)
+                && self.xlen == 32);
+        // The hypervisor and VS CSRs belong to HS-mode.
+        let hypervisor = (addr >> 8) & 0x3 == 0x2;
+        let privilege = if hypervisor { SUPERVISOR } else { ((addr >> 8) & 0x3) as u64 };
+        let read_only = (addr >> 10) & 0x3 == 0x3;
+        let trap_vm = if virt {
+            addr == SATP && self.csrs[HSTATUS] & HSTATUS_VTVM != 0
+        } else {
+            matches!(addr, SATP | HGATP) && mode == SUPERVISOR && self.csrs[MSTATUS] & MSTATUS_TVM != 0
+        };
+
+        known
+            && privilege <= mode
+            && !(hypervisor && virt)
+            && !(write && read_only)
+            && !trap_vm
+            && (addr > FCSR || self.fs_enabled())
+            && (!matches!(addr, VSTART..=VCSR | VL..=VLENB) || self.vs_enabled())
+            && (!matches!(addr, CYCLE..=HPMCOUNTER31 | CYCLEH..=HPMCOUNTER31H) || self.counter_enabled(addr, mode, virt))
+            && (!matches!(addr, STIMECMP | STIMECMPH | VSTIMECMP | VSTIMECMPH) || self.stimecmp_accessible(mode, virt))
+            && (addr != SEED || self.seed_accessible(write, mode, virt))
+            && (!matches!(addr, MIREG | SIREG) || self.virt || self.ireg_accessible(addr))
+            && !(virt && matches!(addr, SIREG | STOPEI))
+    }
+
+    /// Whether `mode` may read `seed`. Reading it takes entropy away, so that
+    /// is only done by instructions that also write it, and never by guests.
+    fn seed_accessible(&self, write: bool, mode: u64, virt: bool) -> bool {
+        write
+            && !virt
+            && match mode {
+                MACHINE => true,
+                SUPERVISOR => self.csrs[MSECCFG] & MSECCFG_SSEED != 0,
+                _ => self.csrs[MSECCFG] & MSECCFG_USEED != 0,
+            }
+    }
+
+    /// The value of `seed` for an instruction reading it: 16 fresh bits of
+    /// entropy, or WAIT if the source has none, as when a replay runs out.
+    fn read_seed(&mut self) -> u64 {
+        let mut bytes = [0; 2];
+        let mut bus = self.bus.lock().unwrap();
+        match bus.entropy.as_mut().map(|entropy| entropy.fill(&mut bytes)) {
+            Some(Ok(())) => SEED_ES16 | u16::from_le_bytes(bytes) as u64,
+            _ => SEED_WAIT,
+        }
+    }
+
+    /// Whether the counteren CSRs let `mode` read a counter, with hcounteren
+    /// also having a say in a guest.
+    fn counter_enabled(&self, addr: usize, mode: u64, virt: bool) -> bool {
+        let bit = 1 << (addr & 0x1f);
+        let hypervisor = !virt || self.csrs[HCOUNTEREN] & bit != 0;
+        match mode {
+            MACHINE => true,
+            SUPERVISOR => self.csrs[MCOUNTEREN] & bit != 0 && hypervisor,
+            _ => self.csrs[MCOUNTEREN] & self.csrs[SCOUNTEREN] & bit != 0 && hypervisor,
+        }
+    }
+
+    /// What a guest adds to `time`: htimedelta, in a guest.
+    fn time_delta(&self) -> u64 {
+        if self.virt {
+            self.htimedelta()
+        } else {
+            0
+        }
+    }
+
+    /// htimedelta, with htimedeltah on RV32.
+    pub fn htimedelta(&self) -> u64 {
+        if self.xlen == 32 {
+            (self.csrs[HTIMEDELTAH] << 32) | self.csrs[HTIMEDELTA]
+        } else {
+            self.csrs[HTIMEDELTA]
+        }
+    }
+
+    /// The interrupts S-mode handles, not counting the hypervisor's.
+    fn supervisor_interrupts(&self) -> u64 {
+        self.csrs[MIDELEG] & !HYPERVISOR_INTERRUPTS
+    }
+
+    pub fn load_csr(&self, addr: usize) -> u64 {
+        match addr {
+            FFLAGS => self.csrs[FCSR] & 0x1f,
+            FRM => (self.csrs[FCSR] >> 5) & 0x7,
+            FCSR => self.csrs[FCSR] & 0xff,
+            VCSR => (self.csrs[VXRM] << 1) | self.csrs[VXSAT],
+            SSTATUS => self.load_csr(MSTATUS) & (SSTATUS_MASK | 1 << (self.xlen - 1)),
+            SIE => self.csrs[MIE] & self.supervisor_interrupts(),
+            SIP => self.load_csr(MIP) & self.supervisor_interrupts(),
+            MSTATUS => self.with_sd(self.csrs[MSTATUS]),
+            VSSTATUS => {
+                // Guests run with the XLEN of the hypervisor.
+                let uxl = if self.xlen == 64 { 2 << 32 } else { 0 };
+                self.with_sd(self.csrs[VSSTATUS] | uxl)
+            }
+            // The upper half of mstatus on RV32: little-endian M and S modes,
+            // and the hypervisor's MPV and GVA
+            MSTATUSH => (self.csrs[MSTATUS] & (MSTATUS_MPV | MSTATUS_GVA)) >> 32,
+            PMPCFG0..=PMPCFG3 => self.load_pmpcfg(addr),
+            MIP => self.csrs[MIP] | self.mip_hw,
+            // A guest sees its VS-mode interrupts as the supervisor ones.
+            VSIE => (self.csrs[MIE] & self.csrs[HIDELEG] & VS_INTERRUPTS) >> 1,
+            VSIP => (self.load_csr(MIP) & self.csrs[HIDELEG] & VS_INTERRUPTS) >> 1,
+            HIE => self.csrs[MIE] & HYPERVISOR_INTERRUPTS,
+            HIP => self.load_csr(MIP) & HYPERVISOR_INTERRUPTS,
+            HVIP => self.csrs[MIP] & VS_INTERRUPTS,
+            // The bits of mip software can write, which mvien leaves alone
+            MVIP => self.csrs[MIP] & (SSIP_BIT | STIP_BIT | SEIP_BIT),
+            MIREG | SIREG => self.load_ireg(addr),
+            MTOPEI | STOPEI => self.load_topei(addr),
+            MTOPI | STOPI | VSTOPI => self.load_topi(addr),
+            TIME => self.truncate(self.time.wrapping_add(self.time_delta())),
+            TIMEH => self.time.wrapping_add(self.time_delta()) >> 32,
+            CYCLE..=HPMCOUNTER31 => self.truncate(self.csrs[addr - CYCLE + MCYCLE]),
+            CYCLEH..=HPMCOUNTER31H => self.csrs[addr - CYCLEH + MCYCLE] >> 32,
+            MCYCLEH..=MHPMCOUNTER31H => self.csrs[addr - MCYCLEH + MCYCLE] >> 32,
+            MHPMEVENT3H..=MHPMEVENT31H => self.csrs[addr - MHPMEVENT3H + MHPMEVENT3] >> 32,
+            // The upper halves of these are 0x10 above them on RV32.
+            STIMECMPH | VSTIMECMPH | MENVCFGH | HENVCFGH => self.csrs[addr - 0x10] >> 32,
+            MCYCLE..=MHPMCOUNTER31 | MHPMEVENT3..=MHPMEVENT31 | STIMECMP | VSTIMECMP | MENVCFG | HENVCFG => {
+                self.truncate(self.csrs[addr])
+            }
+            SCOUNTOVF => self.load_scountovf(),
+            _ => self.csrs[addr],
+        }
+    }
+
+    pub fn store_csr(&mut self, addr: usize, value: u64) {
+        let value = self.truncate(value);
+        match addr {
+            FFLAGS => {
+                self.csrs[FCSR] = (self.csrs[FCSR] & !0x1f) | (value & 0x1f);
+                self.set_fs_dirty();
+            }
+            FRM => {
+                self.csrs[FCSR] = (self.csrs[FCSR] & !0xe0) | ((value & 0x7) << 5);
+                self.set_fs_dirty();
+            }
+            FCSR => {
+                self.csrs[FCSR] = value & 0xff;
+                self.set_fs_dirty();
+            }
+            VSTART => {
+                // Wide enough for the largest element index, VLEN - 1
+                self.csrs[VSTART] = value & (self.vlen as u64 - 1);
+                self.set_vs_dirty();
+            }
+            VXSAT => {
+                self.csrs[VXSAT] = value & 1;
+                self.set_vs_dirty();
+            }
+            VXRM => {
+                self.csrs[VXRM] = value & 0x3;
+                self.set_vs_dirty();
+            }
+            VCSR => {
+                self.csrs[VXSAT] = value & 1;
+                self.csrs[VXRM] = (value >> 1) & 0x3;
+                self.set_vs_dirty();
+            }
+            SSTATUS | VSSTATUS => {
+                let mask = SSTATUS_MASK & self.mstatus_writable();
+                let status = if addr == SSTATUS { MSTATUS } else { VSSTATUS };
+                self.csrs[status] = (self.csrs[status] & !mask) | (value & mask);
+            }
+            SIE => {
+                let mask = self.supervisor_interrupts();
+                self.csrs[MIE] = (self.csrs[MIE] & !mask) | (value & mask);
+            }
+            SIP => {
+                let mask = (SSIP_BIT | LCOFIP_BIT) & self.csrs[MIDELEG];
+                self.csrs[MIP] = (self.csrs[MIP] & !mask) | (value & mask);
+            }
+            VSIE => {
+                let mask = self.csrs[HIDELEG] & VS_INTERRUPTS;
+                self.csrs[MIE] = (self.csrs[MIE] & !mask) | ((value << 1) & mask);
+            }
+            VSIP => {
+                let mask = self.csrs[HIDELEG] & VSSIP_BIT;
+                self.csrs[MIP] = (self.csrs[MIP] & !mask) | ((value << 1) & mask);
+            }
+            HIE => self.csrs[MIE] = (self.csrs[MIE] & !HYPERVISOR_INTERRUPTS) | (value & HYPERVISOR_INTERRUPTS),
+            // hip.VSSIP and the bits of hvip are the ones of mip that the
+            // hypervisor sets to interrupt its guest.
+            HIP => self.csrs[MIP] = (self.csrs[MIP] & !VSSIP_BIT) | (value & VSSIP_BIT),
+            HVIP => self.csrs[MIP] = (self.csrs[MIP] & !VS_INTERRUPTS) | (value & VS_INTERRUPTS),
+            MVIP => {
+                let mask = (SSIP_BIT | STIP_BIT | SEIP_BIT) & !self.sstc_stip();
+                self.csrs[MIP] = (self.csrs[MIP] & !mask) | (value & mask);
+            }
+            // Room for every register number the AIA defines
+            MISELECT | SISELECT | VSISELECT => self.csrs[addr] = value & 0xfff,
+            MIREG | SIREG => self.store_ireg(addr, value),
+            MTOPEI | STOPEI => self.claim_topei(addr),
+            // Kept for the hypervisor to read back; the interrupts it would
+            // inject into a guest are not modelled.
+            HVICTL => self.csrs[HVICTL] = value & 0x4fff_03ff,
+            SATP | VSATP => {
+                // Only Bare and Sv39, or Sv32 on RV32, are supported; other
+                // modes are ignored.
+                if self.xlen == 32 || matches!(value >> 60, 0 | SATP_MODE_SV39) {
+                    self.csrs[addr] = value;
+                }
+            }
+            HGATP => {
+                // Bare, Sv39x4 and Sv48x4, or Sv32x4 on RV32, with the root
+                // table aligned to its 16 KiB.
+                if self.xlen == 32 || matches!(value >> 60, 0 | HGATP_MODE_SV39X4 | HGATP_MODE_SV48X4) {
+                    self.csrs[HGATP] = value & !0b11;
+                }
+            }
+            HSTATUS => {
+                let mask = HSTATUS_GVA
+                    | HSTATUS_SPV
+                    | HSTATUS_SPVP
+                    | HSTATUS_HU
+                    | HSTATUS_VTVM
+                    | HSTATUS_VTW
+                    | HSTATUS_VTSR;
+                self.csrs[HSTATUS] = (self.csrs[HSTATUS] & !mask) | (value & mask);
+            }
+            // Environment calls and the hypervisor's own exceptions stay with
+            // it.
+            HEDELEG => self.csrs[HEDELEG] = value & 0xb1ff,
+            HIDELEG => self.csrs[HIDELEG] = value & VS_INTERRUPTS,
+            MSTATUS => {
+                let mut value = value;
+                // MPP cannot hold the reserved mode 2.
+                if value & MSTATUS_MPP == 2 << 11 {
+                    value &= !MSTATUS_MPP;
+                }
+                let mask = self.mstatus_writable();
+                self.csrs[MSTATUS] = (self.csrs[MSTATUS] & !mask) | (value & mask);
+            }
+            MSTATUSH if self.isa.has(H) => {
+                let mask = MSTATUS_MPV | MSTATUS_GVA;
+                self.csrs[MSTATUS] = (self.csrs[MSTATUS] & !mask) | ((value << 32) & mask);
+            }
+            MEDELEG => {
+                // Environment calls from M-mode cannot be delegated.
+                let mut mask = 0xb3ff;
+                if self.isa.has(H) {
+                    // Environment calls from VS-mode, guest-page faults and
+                    // virtual instructions
+                    mask |= (1 << 10) | (0xf << 20);
+                }
+                self.csrs[MEDELEG] = value & mask;
+            }
+            MIDELEG => {
+                // The hypervisor's interrupts are always delegated.
+                let fixed = if self.isa.has(H) { HYPERVISOR_INTERRUPTS } else { 0 };
+                let mut mask = SSIP_BIT | STIP_BIT | SEIP_BIT;
+                if self.isa.has(SSCOFPMF) {
+                    mask |= LCOFIP_BIT;
+                }
+                self.csrs[MIDELEG] = (value & mask) | fixed;
+            }
+            MSECCFG => self.store_mseccfg(value),
+            PMPCFG0..=PMPCFG3 => self.store_pmpcfg(addr, value),
+            PMPADDR0..=PMPADDR15 => self.store_pmpaddr(addr - PMPADDR0, value),
+            MENVCFG | SENVCFG | HENVCFG | MENVCFGH | HENVCFGH => {
+                let (addr, high) = if matches!(addr, MENVCFGH | HENVCFGH) { (addr - 0x10, true) } else { (addr, false) };
+                self.store_wide(addr, value, high);
+                let mut envcfg = self.csrs[addr] & self.envcfg_writable(addr);
+                // CBIE = 0b10 is reserved.
+                if envcfg & ENVCFG_CBIE == 0b10 << 4 {
+                    envcfg &= !ENVCFG_CBIE;
+                }
+                // With Sstc's timer on, only stimecmp raises STIP.
+                if addr == MENVCFG && envcfg & ENVCFG_STCE != 0 {
+                    self.csrs[MIP] &= !STIP_BIT;
+                }
+                self.csrs[addr] = envcfg;
+                if addr == MENVCFG {
+                    self.csrs[HENVCFG] &= self.envcfg_writable(HENVCFG);
+                }
+            }
+            MCYCLE..=MHPMCOUNTER31 => {
+                self.store_wide(addr, value, false);
+                self.counters_written |= 1 << (addr - MCYCLE);
+            }
+            MCYCLEH..=MHPMCOUNTER31H => {
+                self.store_wide(addr - MCYCLEH + MCYCLE, value, true);
+                self.counters_written |= 1 << (addr - MCYCLEH);
+            }
+            // time cannot be stopped.
+            MCOUNTINHIBIT => self.csrs[MCOUNTINHIBIT] = value & 0xffff_fffd,
+            MHPMEVENT3..=MHPMEVENT31 => self.store_hpmevent(addr, value, false),
+            MHPMEVENT3H..=MHPMEVENT31H => self.store_hpmevent(addr - MHPMEVENT3H + MHPMEVENT3, value, true),
+            STIMECMP | VSTIMECMP => self.store_wide(addr, value, false),
+            STIMECMPH | VSTIMECMPH => self.store_wide(addr - 0x10, value, true),
+            MIE => {
+                let mut mask = SSIP_BIT | MSIP_BIT | STIP_BIT | MTIP_BIT | SEIP_BIT | MEIP_BIT;
+                if self.isa.has(H) {
+                    mask |= HYPERVISOR_INTERRUPTS;
+                }
+                if self.isa.has(SSCOFPMF) {
+                    mask |= LCOFIP_BIT;
+                }
+                self.csrs[MIE] = value & mask;
+            }
+            MIP => {
+                let mut mask = (SSIP_BIT | STIP_BIT | SEIP_BIT) & !self.sstc_stip();
+                if self.isa.has(H) {
+                    mask |= VSSIP_BIT;
+                }
+                if self.isa.has(SSCOFPMF) {
+                    mask |= LCOFIP_BIT;
+                }
+                self.csrs[MIP] = (self.csrs[MIP] & !mask) | (value & mask);
+            }
+            // Only direct and vectored modes exist.
+            MTVEC | STVEC | VSTVEC => self.csrs[addr] = value & !0b10,
+            MEPC | SEPC | VSEPC => self.csrs[addr] = value & !1,
+            // Read-only, or like seed ignoring writes. There are no guest
+            // external interrupt lines.
+            MISA | MVENDORID | MARCHID | MIMPID | MHARTID | MCONFIGPTR | MSTATUSH | MSECCFGH | SEED | HGEIE | HGEIP => {}
+            // There are no interrupts above 15 and no priorities to set, and
+            // mvien and hvien do not virtualize any of the ones there are.
+            MVIEN | HVIEN | HVIPRIO1 | HVIPRIO2 | MIDELEGH | MIEH | MIPH | MVIENH | MVIPH | SIEH | SIPH | VSIEH
+            | VSIPH | HIDELEGH | HVIENH | HVIPH | HVIPRIO1H | HVIPRIO2H => {}
+            _ => self.csrs[addr] = value,
+        }
+    }
+
+    /// The mstatus fields software can change. VS only exists with the
+    /// vector extension, and MPV and GVA with the hypervisor extension, in
+    /// mstatush on RV32.
+    fn mstatus_writable(&self) -> u64 {
+        let mut writable = MSTATUS_WRITABLE;
+        if self.isa.has(V) {
+            writable |= MSTATUS_VS;
+        }
+        if self.isa.has(H) && self.xlen == 64 {
+            writable |= MSTATUS_MPV | MSTATUS_GVA;
+        }
+        writable
+    }
+
+    /// mstatus or vsstatus with SD, their top bit, showing whether FS or VS
+    /// is dirty.
+    fn with_sd(&self, status: u64) -> u64 {
+        let dirty = status & MSTATUS_FS == FS_DIRTY || status & MSTATUS_VS == VS_DIRTY;
+        self.truncate(status & !MSTATUS_SD) | ((dirty as u64) << (self.xlen - 1))
+    }
 
-    pub fn execute(&mut self, instruction: u32) -> Result<(), ()> {
+    /// The fields of menvcfg, senvcfg or henvcfg that exist for the
+    /// extensions the hart implements.
+    fn envcfg_writable(&self, addr: usize) -> u64 {
+        let mut writable = ENVCFG_FIOM;
+        if self.isa.has(ZICBOM) {
+            writable |= ENVCFG_CBIE | ENVCFG_CBCFE;
+        }
+        if self.isa.has(ZICBOZ) {
+            writable |= ENVCFG_CBZE;
+        }
+        if addr != SENVCFG {
+            if self.isa.has(SSTC) {
+                writable |= ENVCFG_STCE;
+            }
+            if self.isa.has(SVPBMT) {
+                writable |= ENVCFG_PBMTE;
+            }
+            if self.isa.has(SVADU) {
+                writable |= ENVCFG_ADUE;
+            }
+        }
+        // henvcfg's STCE, PBMTE and ADUE are read-only zero while menvcfg's
+        // are clear.
+        if addr == HENVCFG {
+            writable &= self.csrs[MENVCFG] | !(ENVCFG_STCE | ENVCFG_PBMTE | ENVCFG_ADUE);
+        }
+        writable
+    }
+
+    /// Writes a 64-bit CSR, or on RV32 its lower half, or its upper half if
+    /// `high` is set.
+    pub fn store_wide(&mut self, addr: usize, value: u64, high: bool) {
+        let old = self.csrs[addr];
+        self.csrs[addr] = match (self.xlen, high) {
+            (64, _) => value,
+            (_, false) => (old & !0xffff_ffff) | value,
+            (_, true) => (old & 0xffff_ffff) | (value << 32),
+        };
+    }
+
+    /// Checks that menvcfg, henvcfg in a guest, and senvcfg for U-mode let
+    /// the current mode run an instruction that a field of theirs controls.
+    /// What only the hypervisor's henvcfg or a guest's senvcfg forbids is a
+    /// virtual instruction exception.
+    fn envcfg_allows(&self, field: u64, instruction: u32) -> Result<(), Exception> {
+        if self.mode == MACHINE {
+            return Ok(());
+        }
+        if self.csrs[MENVCFG] & field == 0 {
+            return Err(Exception::IllegalInstruction(instruction as u64));
+        }
+        let henvcfg = !self.virt || self.csrs[HENVCFG] & field != 0;
+        let senvcfg = self.mode == SUPERVISOR || self.csrs[SENVCFG] & field != 0;
+        match (henvcfg && senvcfg, self.virt) {
+            (true, _) => Ok(()),
+            (false, true) => Err(Exception::VirtualInstruction(instruction as u64)),
+            (false, false) => Err(Exception::IllegalInstruction(instruction as u64)),
+        }
+    }
+
+    /// Whether the FPU is on: FS is not Off in mstatus, nor in a guest in
+    /// vsstatus.
+    pub fn fs_enabled(&self) -> bool {
+        self.csrs[MSTATUS] & MSTATUS_FS != 0 && (!self.virt || self.csrs[VSSTATUS] & MSTATUS_FS != 0)
+    }
+
+    /// Whether the vector unit is on, like `fs_enabled` with VS.
+    pub fn vs_enabled(&self) -> bool {
+        self.csrs[MSTATUS] & MSTATUS_VS != 0 && (!self.virt || self.csrs[VSSTATUS] & MSTATUS_VS != 0)
+    }
+
+    /// Marks the floating-point state as modified, for a guest too.
+    pub fn set_fs_dirty(&mut self) {
+        self.csrs[MSTATUS] |= FS_DIRTY;
+        if self.virt {
+            self.csrs[VSSTATUS] |= FS_DIRTY;
+        }
+    }
+
+    /// Marks the vector state as modified, for a guest too.
+    pub fn set_vs_dirty(&mut self) {
+        self.csrs[MSTATUS] |= VS_DIRTY;
+        if self.virt {
+            self.csrs[VSSTATUS] |= VS_DIRTY;
+        }
+    }
+
+    pub fn execute(&mut self, instruction: u32) -> Result<(), Exception> {
         let opcode = instruction & 0x7f;
         let rd = ((instruction >> 7) & 0x1f) as usize;
         let rs1 = ((instruction >> 15) & 0x1f) as usize;
         let rs2 = ((instruction >> 20) & 0x1f) as usize;
         let funct3 = (instruction >> 12) & 0x7;
         let funct7 = (instruction >> 25) & 0x7f;
+        let rv32 = self.xlen == 32;
 
         self.regs[0] = 0;
 
@@ -77,6 +1915,8 @@ impl Cpu {
                 let addr = self.regs[rs1].wrapping_add(imm);
 
                 match funct3 {
+                    // LD and LWU are RV64 only.
+                    0x3 | 0x6 if rv32 => return Err(Exception::IllegalInstruction(instruction as u64)),
                     0x0 => {
                         // LB
                         let val = self.load(addr, 8)?;
@@ -112,18 +1952,26 @@ impl Cpu {
                         let val = self.load(addr, 32)?;
                         self.regs[rd] = val;
                     }
-                    _ => {
-                        println!(
-                            "not implemented yet, opcode: {:#x} funct3 {:#x}",
-                            opcode, funct3
-                        )
-                    }
+                    _ => return Err(Exception::IllegalInstruction(instruction as u64)),
                 }
             }
+            0x0f => {
+                // cbo.inval, cbo.clean, cbo.flush, cbo.zero
+                if funct3 == 0x2 {
+                    self.cache_block_op(instruction)?;
+                }
+                // fence, fence.i, and pause, which is a fence hint
+                // Every access goes straight to the shared bus and there is no
+                // instruction cache, so they are no-ops.
+            }
             0x13 => {
                 let imm = ((instruction & 0xffff_0000) as i32 as i64 >> 20) as u64;
-                // Shift amount is in the lower 6 bits of the I-immediate field
+                // Shift amount is in the lower 6 bits of the I-immediate field,
+                // 5 on RV32 where setting the sixth is reserved.
                 let shift_amount = (imm & 0x3f) as u32;
+                if matches!(funct3, 0x1 | 0x5) && rv32 && shift_amount > 0x1f {
+                    return Err(Exception::IllegalInstruction(instruction as u64));
+                }
 
                 match funct3 {
                     0x0 => {
@@ -132,10 +1980,16 @@ impl Cpu {
                     }
                     0x1 => {
                         // Slli
+                        if funct7 >> 1 != 0 {
+                            return self.execute_bitmanip(instruction);
+                        }
                         self.regs[rd] = self.regs[rs1] << shift_amount;
                     }
                     0x2 => {
                         // Slti
+                        if instruction == SNAPSHOT_HINT {
+                            self.snapshot_requested = true;
+                        }
                         self.regs[rd] = if (self.regs[rs1] as i64) < (imm as i64) {
                             1
                         } else {
@@ -153,9 +2007,9 @@ impl Cpu {
                     0x5 => {
                         match funct7 >> 1 {
                             // Srli
-                            0x00 => self.regs[rd] = self.regs[rs1].wrapping_shr(shift_amount),
+                            0x00 => self.regs[rd] = self.truncate(self.regs[rs1]).wrapping_shr(shift_amount),
                             0x10 => self.regs[rd] = (self.regs[rs1] as i64).wrapping_shr(shift_amount) as u64,
-                            _ => {}
+                            _ => return self.execute_bitmanip(instruction),
                         }
                     }
                     0x6 => self.regs[rd] = self.regs[rs1] | imm, // Ori
@@ -166,8 +2020,9 @@ impl Cpu {
             0x17 => {
                 // Auipc
                 let imm = (instruction & 0xffff_f000) as i32 as i64 as u64;
-                self.regs[rd] = self.pc.wrapping_add(imm).wrapping_sub(4);
+                self.regs[rd] = self.pc.wrapping_add(imm).wrapping_sub(self.inst_len);
             }
+            0x1b | 0x3b if rv32 => return Err(Exception::IllegalInstruction(instruction as u64)),
             0x1b => {
                 let imm = ((instruction as i32 as i64) >> 20) as u64;
                 let shift_amount = (imm & 0x1f) as u32;
@@ -177,6 +2032,7 @@ impl Cpu {
                         // Addiw
                         self.regs[rd] = self.regs[rs1].wrapping_add(imm) as i32 as i64 as u64;
                     }
+                    0x1 if funct7 != 0 => return self.execute_bitmanip(instruction),
                     0x1 => {
                         // Slliw
                         self.regs[rd] = self.regs[rs1].wrapping_shl(shift_amount) as i32 as i64 as u64;
@@ -191,10 +2047,10 @@ impl Cpu {
                                 // Sraiw
                                 self.regs[rd] = (self.regs[rs1] as i32).wrapping_shr(shift_amount) as i64 as u64;
                             }
-                            _ => {}
+                            _ => return self.execute_bitmanip(instruction),
                         }
                     }
-                    _ => {}
+                    _ => return Err(Exception::IllegalInstruction(instruction as u64)),
                 }
             }
             0x23 => {
@@ -204,15 +2060,94 @@ impl Cpu {
                 let addr = self.regs[rs1].wrapping_add(imm);
 
                 match funct3 {
+                    0x3 if rv32 => return Err(Exception::IllegalInstruction(instruction as u64)),
                     0x0 => self.store(addr, 8, self.regs[rs2])?,    // SB
                     0x1 => self.store(addr, 16, self.regs[rs2])?,   // SH
                     0x2 => self.store(addr, 32, self.regs[rs2])?,   // SW
                     0x3 => self.store(addr, 64, self.regs[rs2])?,   // SD
-                    _ => {}
+                    _ => return Err(Exception::IllegalInstruction(instruction as u64)),
+                }
+            }
+            0x2f => {
+                // Atomic instructions
+                let funct5 = funct7 >> 2;
+                let size = match funct3 {
+                    0x2 => 32,
+                    0x3 if !rv32 => 64,
+                    _ => return Err(Exception::IllegalInstruction(instruction as u64)),
+                };
+                // Reserved encodings must trap before the read, which may
+                // have side effects on a device.
+                match funct5 {
+                    0x02 if rs2 != 0 => return Err(Exception::IllegalInstruction(instruction as u64)),
+                    0x00..=0x04 | 0x08 | 0x0c | 0x10 | 0x14 | 0x18 | 0x1c => {}
+                    _ => return Err(Exception::IllegalInstruction(instruction as u64)),
+                }
+                let addr = self.truncate(self.regs[rs1]);
+                if addr & (size / 8 - 1) != 0 {
+                    return Err(if funct5 == 0x02 {
+                        Exception::LoadAddressMisaligned(addr)
+                    } else {
+                        Exception::StoreAMOAddressMisaligned(addr)
+                    });
+                }
+                // LR needs only read permission; the others may write.
+                let access = if funct5 == 0x02 { AccessType::Load } else { AccessType::Store };
+                let paddr = self.translate(addr, access)?;
+                self.check_pmp(addr, paddr, size, access)?;
+                if !matches!(funct5, 0x02 | 0x03) {
+                    // AMOs read as well as write.
+                    self.check_pmp(addr, paddr, size, AccessType::Load)
+                        .map_err(|_| Exception::StoreAMOAccessFault(addr))?;
+                }
+                let (vaddr, addr) = (addr, paddr);
+                // Faults report the virtual address, and AMOs fault as stores.
+                let load_fault = |_| Exception::LoadAccessFault(vaddr);
+                let store_fault = |_| Exception::StoreAMOAccessFault(vaddr);
+                let sext = |v: u64| if size == 32 { v as i32 as i64 as u64 } else { v };
+                let mask = if size == 32 { 0xffff_ffff } else { u64::MAX };
+
+                // Hold the bus for the whole read-modify-write so that harts
+                // running on other host threads cannot interleave with it.
+                let mut bus = self.bus.lock().unwrap();
+                match funct5 {
+                    0x02 => {
+                        // lr
+                        let val = bus.load(addr, size).map_err(load_fault)?;
+                        bus.reserve(self.hartid, addr);
+                        self.regs[rd] = sext(val);
+                    }
+                    0x03 => {
+                        // sc
+                        if bus.take_reservation(self.hartid, addr) {
+                            bus.store(addr, size, self.regs[rs2]).map_err(store_fault)?;
+                            self.regs[rd] = 0;
+                        } else {
+                            self.regs[rd] = 1;
+                        }
+                    }
+                    _ => {
+                        let val = bus.load(addr, size).map_err(store_fault)?;
+                        let src = self.regs[rs2];
+                        let (signed_val, signed_src) = (sext(val) as i64, sext(src) as i64);
+                        let result = match funct5 {
+                            0x00 => val.wrapping_add(src), // amoadd
+                            0x01 => src,                   // amoswap
+                            0x04 => val ^ src,             // amoxor
+                            0x08 => val | src,             // amoor
+                            0x0c => val & src,             // amoand
+                            0x10 => if signed_val < signed_src { val } else { src }, // amomin
+                            0x14 => if signed_val > signed_src { val } else { src }, // amomax
+                            0x18 => if (val & mask) < (src & mask) { val } else { src }, // amominu
+                            _ => if (val & mask) > (src & mask) { val } else { src }, // amomaxu
+                        };
+                        bus.store(addr, size, result).map_err(store_fault)?;
+                        self.regs[rd] = sext(val);
+                    }
                 }
             }
             0x33 => {
-                let shift_amount = ((self.regs[rs2] & 0x3f) as u64) as u32;
+                let shift_amount = (self.regs[rs2] & (self.xlen as u64 - 1)) as u32;
 
                 match (funct3, funct7) {
                     (0x0, 0x00) => {
@@ -253,7 +2188,7 @@ impl Cpu {
                     }
                     (0x5, 0x00) => {
                         // srl
-                        self.regs[rd] = self.regs[rs1].wrapping_shr(shift_amount);
+                        self.regs[rd] = self.truncate(self.regs[rs1]).wrapping_shr(shift_amount);
                     }
                     (0x5, 0x20) => {
                         // sra
@@ -267,7 +2202,59 @@ impl Cpu {
                         // and
                         self.regs[rd] = self.regs[rs1] & self.regs[rs2];
                     }
-                    _ => {}
+                    (0x1, 0x01) => {
+                        // mulh
+                        let product = (self.regs[rs1] as i64 as i128) * (self.regs[rs2] as i64 as i128);
+                        self.regs[rd] = (product >> self.xlen) as u64;
+                    }
+                    (0x2, 0x01) => {
+                        // mulhsu
+                        let product = (self.regs[rs1] as i64 as i128) * (self.truncate(self.regs[rs2]) as i128);
+                        self.regs[rd] = (product >> self.xlen) as u64;
+                    }
+                    (0x3, 0x01) => {
+                        // mulhu
+                        let product = (self.truncate(self.regs[rs1]) as u128) * (self.truncate(self.regs[rs2]) as u128);
+                        self.regs[rd] = (product >> self.xlen) as u64;
+                    }
+                    (0x4, 0x01) => {
+                        // div
+                        let (dividend, divisor) = (self.regs[rs1] as i64, self.regs[rs2] as i64);
+                        self.regs[rd] = if divisor == 0 {
+                            u64::MAX
+                        } else {
+                            dividend.wrapping_div(divisor) as u64
+                        };
+                    }
+                    (0x5, 0x01) => {
+                        // divu
+                        self.regs[rd] = match self.truncate(self.regs[rs2]) {
+                            0 => u64::MAX,
+                            divisor => self.truncate(self.regs[rs1]) / divisor,
+                        };
+                    }
+                    (0x6, 0x01) => {
+                        // rem
+                        let (dividend, divisor) = (self.regs[rs1] as i64, self.regs[rs2] as i64);
+                        self.regs[rd] = if divisor == 0 {
+                            dividend as u64
+                        } else {
+                            dividend.wrapping_rem(divisor) as u64
+                        };
+                    }
+                    (0x7, 0x01) => {
+                        // remu
+                        self.regs[rd] = match self.truncate(self.regs[rs2]) {
+                            0 => self.regs[rs1],
+                            divisor => self.truncate(self.regs[rs1]) % divisor,
+                        };
+                    }
+                    (0x5 | 0x7, 0x07) if self.isa.has(ZICOND) => {
+                        // czero.eqz, czero.nez
+                        let zero = (self.regs[rs2] == 0) == (funct3 == 0x5);
+                        self.regs[rd] = if zero { 0 } else { self.regs[rs1] };
+                    }
+                    _ => return self.execute_bitmanip(instruction),
                 }
             }
             0x37 => {
@@ -297,7 +2284,43 @@ impl Cpu {
                         // sraw
                         self.regs[rd] = ((self.regs[rs1] as i32) >> (shift_amount as i32)) as u64;
                     }
-                    _ => {}
+                    (0x0, 0x01) => {
+                        // mulw
+                        self.regs[rd] = (self.regs[rs1] as i32).wrapping_mul(self.regs[rs2] as i32) as i64 as u64;
+                    }
+                    (0x4, 0x01) => {
+                        // divw
+                        let (dividend, divisor) = (self.regs[rs1] as i32, self.regs[rs2] as i32);
+                        self.regs[rd] = if divisor == 0 {
+                            u64::MAX
+                        } else {
+                            dividend.wrapping_div(divisor) as i64 as u64
+                        };
+                    }
+                    (0x5, 0x01) => {
+                        // divuw
+                        self.regs[rd] = match self.regs[rs2] as u32 {
+                            0 => u64::MAX,
+                            divisor => ((self.regs[rs1] as u32) / divisor) as i32 as i64 as u64,
+                        };
+                    }
+                    (0x6, 0x01) => {
+                        // remw
+                        let (dividend, divisor) = (self.regs[rs1] as i32, self.regs[rs2] as i32);
+                        self.regs[rd] = if divisor == 0 {
+                            dividend as i64 as u64
+                        } else {
+                            dividend.wrapping_rem(divisor) as i64 as u64
+                        };
+                    }
+                    (0x7, 0x01) => {
+                        // remuw
+                        self.regs[rd] = match self.regs[rs2] as u32 {
+                            0 => self.regs[rs1] as i32 as i64 as u64,
+                            divisor => ((self.regs[rs1] as u32) % divisor) as i32 as i64 as u64,
+                        };
+                    }
+                    _ => return self.execute_bitmanip(instruction),
                 }
             }
             0x63 => {
@@ -306,43 +2329,45 @@ impl Cpu {
                     | ((instruction >> 20) & 0x7e0) as u64
                     | ((instruction >> 7) & 0x1e) as u64;
 
+                #[allow(clippy::collapsible_match)]
                 match funct3 {
                     0x0 => {
                         // beq
                         if self.regs[rs1] == self.regs[rs2] {
-                            self.pc = self.pc.wrapping_add(imm).wrapping_sub(4);
+                            self.pc = self.pc.wrapping_add(imm).wrapping_sub(self.inst_len);
                         }
                     }
                     0x1 => {
                         // bne
                         if self.regs[rs1] != self.regs[rs2] {
-                            self.pc = self.pc.wrapping_add(imm).wrapping_sub(4);
+                            self.pc = self.pc.wrapping_add(imm).wrapping_sub(self.inst_len);
                         }
                     }
                     0x4 => {
                         // blt
                         if (self.regs[rs1] as i64) < (self.regs[rs2] as i64) {
-                            self.pc = self.pc.wrapping_add(imm).wrapping_sub(4);
+                            self.pc = self.pc.wrapping_add(imm).wrapping_sub(self.inst_len);
                         }
                     }
                     0x5 => {
                         // bge
                         if (self.regs[rs1] as i64) >= (self.regs[rs2] as i64) {
-                            self.pc = self.pc.wrapping_add(imm).wrapping_sub(4);
+                            self.pc = self.pc.wrapping_add(imm).wrapping_sub(self.inst_len);
                         }
                     }
                     0x6 => {
                         // bltu
                         if self.regs[rs1] < self.regs[rs2] {
-                            self.pc = self.pc.wrapping_add(imm).wrapping_sub(4);
+                            self.pc = self.pc.wrapping_add(imm).wrapping_sub(self.inst_len);
                         }
                     }
                     0x7 => {
                         // bgeu
                         if self.regs[rs1] >= self.regs[rs2] {
-                            self.pc = self.pc.wrapping_add(imm).wrapping_sub(4);
+                            self.pc = self.pc.wrapping_add(imm).wrapping_sub(self.inst_len);
                         }
                     }
+                    0x2 | 0x3 => return Err(Exception::IllegalInstruction(instruction as u64)),
                     _ => {}
                 }
             }
@@ -364,23 +2389,190 @@ impl Cpu {
                     | ((instruction >> 9) & 0x800) as u64
                     | ((instruction >> 20) & 0x7fe) as u64;
 
-                self.pc = self.pc.wrapping_add(imm).wrapping_sub(4);
+                self.pc = self.pc.wrapping_add(imm).wrapping_sub(self.inst_len);
             }
-            _ => {
-                dbg!(format!("Not implemented opcode: {:#x}", opcode));
+            0x73 => {
+                if funct3 == 0x4 || (funct3 == 0x0 && rd == 0 && matches!(funct7, 0x11 | 0x31)) {
+                    // hlv, hlvx, hsv, hfence.vvma, hfence.gvma
+                    return self.execute_hypervisor(instruction);
+                }
+                let mut csr = ((instruction >> 20) & 0xfff) as usize;
+                // The zero-extended 5-bit immediate of the *i variants
+                let zimm = rs1 as u64;
+                let virtual_instruction = Exception::VirtualInstruction(instruction as u64);
+                if funct3 != 0x0 {
+                    // csrrs/csrrc (and their immediate forms) with x0 do not write.
+                    let write = funct3 & 0x3 == 0x1 || rs1 != 0;
+                    if let Some(exception) = self.csr_access_fault(csr, write, instruction) {
+                        return Err(exception);
+                    }
+                    if self.virt {
+                        csr = guest_csr(csr);
+                    }
+                    if csr == SEED {
+                        self.regs[rd] = self.read_seed();
+                        return Ok(());
+                    }
+                }
+
+                match funct3 {
+                    0x0 => {
+                        match (rs2, funct7) {
+                            (0x0, 0x0) => {
+                                // ecall
+                                if self.mode == SUPERVISOR && !self.virt {
+                                    if let Some(sbi) = self.sbi.clone() {
+                                        sbi.lock().unwrap().call(self);
+                                        return Ok(());
+                                    }
+                                }
+                                return Err(match self.mode {
+                                    USER => Exception::EnvironmentCallFromUMode,
+                                    SUPERVISOR if self.virt => Exception::EnvironmentCallFromVSMode,
+                                    SUPERVISOR => Exception::EnvironmentCallFromSMode,
+                                    _ => Exception::EnvironmentCallFromMMode,
+                                });
+                            }
+                            (0x1, 0x0) => {
+                                // ebreak
+                                if let Some(host) = self.semihosting.clone() {
+                                    if self.is_semihosting_call() {
+                                        host.lock().unwrap().call(self);
+                                        return Ok(());
+                                    }
+                                }
+                                return Err(Exception::Breakpoint(self.pc.wrapping_sub(self.inst_len)));
+                            }
+                            (0x2, 0x8) if self.virt => {
+                                // sret in a guest, returning within it
+                                if self.mode == USER || self.csrs[HSTATUS] & HSTATUS_VTSR != 0 {
+                                    return Err(virtual_instruction);
+                                }
+                                let status = self.csrs[VSSTATUS];
+                                self.mode = (status & MSTATUS_SPP) >> 8;
+                                self.csrs[VSSTATUS] = supervisor_return_status(status);
+                                self.pc = self.csrs[VSEPC];
+                            }
+                            (0x2, 0x8) if self.mode >= SUPERVISOR => {
+                                // sret
+                                if self.mode == SUPERVISOR && self.csrs[MSTATUS] & MSTATUS_TSR != 0 {
+                                    return Err(Exception::IllegalInstruction(instruction as u64));
+                                }
+                                let status = self.csrs[MSTATUS];
+                                self.mode = (status & MSTATUS_SPP) >> 8;
+                                let mut status = supervisor_return_status(status);
+                                if self.mode != MACHINE {
+                                    status &= !MSTATUS_MPRV;
+                                }
+                                self.csrs[MSTATUS] = status;
+                                // V = SPV, SPV = 0
+                                self.virt = self.csrs[HSTATUS] & HSTATUS_SPV != 0;
+                                self.csrs[HSTATUS] &= !HSTATUS_SPV;
+                                self.pc = self.csrs[SEPC];
+                            }
+                            (0x2, 0x18) if self.mode == MACHINE => {
+                                // mret
+                                let status = self.csrs[MSTATUS];
+                                self.mode = (status & MSTATUS_MPP) >> 11;
+                                // MIE = MPIE, MPIE = 1, MPP = U, V = MPV, MPV = 0
+                                let mie = (status & MSTATUS_MPIE) >> 4;
+                                let mut status =
+                                    (status & !(MSTATUS_MIE | MSTATUS_MPP | MSTATUS_MPV)) | mie | MSTATUS_MPIE;
+                                if self.mode != MACHINE {
+                                    status &= !MSTATUS_MPRV;
+                                }
+                                self.virt = self.mode != MACHINE && self.csrs[MSTATUS] & MSTATUS_MPV != 0;
+                                self.csrs[MSTATUS] = status;
+                                self.pc = self.csrs[MEPC];
+                            }
+                            (0x5, 0x8) => {
+                                // wfi
+                                // Interrupts are checked before every instruction,
+                                // so waiting is the same as carrying on.
+                                if self.mode < MACHINE && self.csrs[MSTATUS] & MSTATUS_TW != 0 {
+                                    return Err(Exception::IllegalInstruction(instruction as u64));
+                                }
+                                if self.virt && (self.mode == USER || self.csrs[HSTATUS] & HSTATUS_VTW != 0) {
+                                    return Err(virtual_instruction);
+                                }
+                            }
+                            (_, 0x9) if rd == 0 && self.virt => {
+                                // sfence.vma in a guest
+                                if self.mode == USER || self.csrs[HSTATUS] & HSTATUS_VTVM != 0 {
+                                    return Err(virtual_instruction);
+                                }
+                            }
+                            (_, 0x9) if rd == 0 && self.mode >= SUPERVISOR => {
+                                // sfence.vma
+                                // There is no TLB to flush.
+                                if self.mode == SUPERVISOR && self.csrs[MSTATUS] & MSTATUS_TVM != 0 {
+                                    return Err(Exception::IllegalInstruction(instruction as u64));
+                                }
+                            }
+                            _ => return Err(Exception::IllegalInstruction(instruction as u64)),
+                        }
+                    }
+                    0x1 => {
+                        // csrrw
+                        let t = self.load_csr(csr);
+                        self.store_csr(csr, self.regs[rs1]);
+                        self.regs[rd] = t;
+                    }
+                    0x2 => {
+                        // csrrs
+                        let t = self.load_csr(csr);
+                        if rs1 != 0 {
+                            self.store_csr(csr, t | self.regs[rs1]);
+                        }
+                        self.regs[rd] = t;
+                    }
+                    0x3 => {
+                        // csrrc
+                        let t = self.load_csr(csr);
+                        if rs1 != 0 {
+                            self.store_csr(csr, t & !self.regs[rs1]);
+                        }
+                        self.regs[rd] = t;
+                    }
+                    0x5 => {
+                        // csrrwi
+                        self.regs[rd] = self.load_csr(csr);
+                        self.store_csr(csr, zimm);
+                    }
+                    0x6 => {
+                        // csrrsi
+                        let t = self.load_csr(csr);
+                        if zimm != 0 {
+                            self.store_csr(csr, t | zimm);
+                        }
+                        self.regs[rd] = t;
+                    }
+                    0x7 => {
+                        // csrrci
+                        let t = self.load_csr(csr);
+                        if zimm != 0 {
+                            self.store_csr(csr, t & !zimm);
+                        }
+                        self.regs[rd] = t;
+                    }
+                    _ => return Err(Exception::IllegalInstruction(instruction as u64)),
+                }
             }
+            // Vector loads and stores use the widths the scalar ones leave free.
+            0x07 | 0x27 if matches!(funct3, 0x0 | 0x5 | 0x6 | 0x7) => self.execute_vector(instruction)?,
+            0x57 => self.execute_vector(instruction)?,
+            0x07 | 0x27 | 0x43 | 0x47 | 0x4b | 0x4f | 0x53 => self.execute_fp(instruction)?,
+            _ => return Err(Exception::IllegalInstruction(instruction as u64)),
         }
 
         Ok(())
     }
 
+    #[allow(clippy::format_in_format_args)]
     pub fn dump_registers(&self) {
         let mut output = String::from("");
-        let abi = [
-            "zero", " ra ", " sp ", " gp ", " tp ", " t0 ", " t1 ", " t2 ", " s0 ", " s1 ", " a0 ",
-            " a1 ", " a2 ", " a3 ", " a4 ", " a5 ", " a6 ", " a7 ", " s2 ", " s3 ", " s4 ", " s5 ",
-            " s6 ", " s7 ", " s8 ", " s9 ", " s10", " s11", " t3 ", " t4 ", " t5 ", " t6 ",
-        ];
+        // Centred in four columns: " ra ", " s10".
+        let abi: Vec<String> = ABI_NAMES.iter().map(|name| format!("{:>4}", format!("{:<3}", name))).collect();
         for i in (0..32).step_by(4) {
             output = format!(
                 "{}\n{}",
@@ -389,20 +2581,619 @@ impl Cpu {
                     "x{:02}({})={:>#18x} x{:02}({})={:>#18x} x{:02}({})={:>#18x} x{:02}({})={:>#18x}",
                     i,
                     abi[i],
-                    self.regs[i],
+                    self.truncate(self.regs[i]),
                     i + 1,
                     abi[i + 1],
-                    self.regs[i + 1],
+                    self.truncate(self.regs[i + 1]),
                     i + 2,
                     abi[i + 2],
-                    self.regs[i + 2],
+                    self.truncate(self.regs[i + 2]),
                     i + 3,
                     abi[i + 3],
-                    self.regs[i + 3],
+                    self.truncate(self.regs[i + 3]),
                 )
             );
         }
         println!("{}", output);
     }
 
-}
\ No newline at end of file
+}
+
+/// Whether an access of `size` bits at `addr` spans two pages.
+fn crosses_page(addr: u64, size: u64) -> bool {
+    (addr % PAGE_SIZE) + size / 8 > PAGE_SIZE
+}
+
+/// sstatus, or vsstatus, on a trap into S-mode from `mode`: SPIE = SIE,
+/// SIE = 0, SPP = the previous mode.
+fn supervisor_trap_status(status: u64, mode: u64) -> u64 {
+    let mut new = status & !(MSTATUS_SPIE | MSTATUS_SIE | MSTATUS_SPP);
+    if status & MSTATUS_SIE != 0 {
+        new |= MSTATUS_SPIE;
+    }
+    if mode == SUPERVISOR {
+        new |= MSTATUS_SPP;
+    }
+    new
+}
+
+/// sstatus, or vsstatus, on an sret: SIE = SPIE, SPIE = 1, SPP = U.
+fn supervisor_return_status(status: u64) -> u64 {
+    let sie = (status & MSTATUS_SPIE) >> 4;
+    (status & !(MSTATUS_SIE | MSTATUS_SPP)) | sie | MSTATUS_SPIE
+}
+
+/// The VS CSR that a guest reaches through a supervisor CSR number.
+fn guest_csr(addr: usize) -> usize {
+    match addr {
+        SSTATUS => VSSTATUS,
+        SIE => VSIE,
+        STVEC => VSTVEC,
+        SSCRATCH => VSSCRATCH,
+        SEPC => VSEPC,
+        SCAUSE => VSCAUSE,
+        STVAL => VSTVAL,
+        SIP => VSIP,
+        SATP => VSATP,
+        SISELECT => VSISELECT,
+        SIREG => VSIREG,
+        STOPEI => VSTOPEI,
+        STOPI => VSTOPI,
+        SIEH => VSIEH,
+        SIPH => VSIPH,
+        STIMECMP => VSTIMECMP,
+        STIMECMPH => VSTIMECMPH,
+        _ => addr,
+    }
+}
+
+/// The handler address for a trap: the base of `tvec`, plus 4 times the
+/// cause for interrupts in vectored mode.
+fn trap_vector(tvec: u64, cause: u64, interrupt: bool) -> u64 {
+    let base = tvec & !0b11;
+    if interrupt && tvec & 0b11 == 1 {
+        base + 4 * cause
+    } else {
+        base
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    const ROOT: u64 = 0x8010_0000;
+    const LEAF: u64 = PTE_V | PTE_A | PTE_D;
+
+    /// A PTE pointing at physical address `addr`.
+    fn pte(addr: u64, flags: u64) -> u64 {
+        (addr >> 12) << 10 | flags
+    }
+
+    fn poke(cpu: &Cpu, addr: u64, size: u64, value: u64) {
+        cpu.bus.lock().unwrap().store(addr, size, value).unwrap();
+    }
+
+    /// An RV64 hart in S-mode with Sv39 paging on, the root table at `ROOT`
+    /// and the tables it points to in the pages after it.
+    fn sv39() -> Cpu {
+        let mut cpu = machine(&MachineConfig::default(), &[]).harts.remove(0);
+        cpu.mode = SUPERVISOR;
+        cpu.csrs[SATP] = SATP_MODE_SV39 << 60 | ROOT >> 12;
+        // 0x4000_1000 through a table at each level
+        poke(&cpu, ROOT + 8, 64, pte(ROOT + 0x1000, PTE_V));
+        poke(&cpu, ROOT + 0x1000, 64, pte(ROOT + 0x2000, PTE_V));
+        poke(&cpu, ROOT + 0x2000 + 8, 64, pte(0x8020_0000, LEAF | PTE_R | PTE_W));
+        cpu
+    }
+
+    #[test]
+    fn sv39_walks_the_page_tables() {
+        let mut cpu = sv39();
+        assert_eq!(cpu.translate(0x4000_1234, AccessType::Load), Ok(0x8020_0234));
+        assert_eq!(cpu.translate(0x4000_1ffc, AccessType::Store), Ok(0x8020_0ffc));
+        assert_eq!(cpu.translate(0x4000_0000, AccessType::Load), Err(Exception::LoadPageFault(0x4000_0000)));
+
+        // A gigapage and a megapage
+        poke(&cpu, ROOT + 2 * 8, 64, pte(0x8000_0000, LEAF | PTE_X));
+        poke(&cpu, ROOT + 0x1000 + 8, 64, pte(0x8040_0000, LEAF | PTE_R));
+        assert_eq!(cpu.translate(0x8765_4321, AccessType::Instruction), Ok(0x8765_4321));
+        assert_eq!(cpu.translate(0x4020_5678, AccessType::Load), Ok(0x8040_5678));
+
+        // M-mode and a bare satp do not translate.
+        cpu.mode = MACHINE;
+        assert_eq!(cpu.translate(0x4000_1234, AccessType::Load), Ok(0x4000_1234));
+        cpu.mode = SUPERVISOR;
+        cpu.csrs[SATP] = 0;
+        assert_eq!(cpu.translate(0x4000_1234, AccessType::Load), Ok(0x4000_1234));
+    }
+
+    #[test]
+    fn sv39_faults() {
+        let mut cpu = sv39();
+        // Not sign-extended from bit 38
+        let high = 0x40_0000_1000;
+        assert_eq!(cpu.translate(high, AccessType::Load), Err(Exception::LoadPageFault(high)));
+        // A megapage that is not aligned
+        poke(&cpu, ROOT + 0x1000 + 8, 64, pte(0x8040_1000, LEAF | PTE_R));
+        assert_eq!(cpu.translate(0x4020_0000, AccessType::Load), Err(Exception::LoadPageFault(0x4020_0000)));
+        // Write without read is reserved.
+        poke(&cpu, ROOT + 0x2000 + 8, 64, pte(0x8020_0000, LEAF | PTE_W));
+        assert_eq!(cpu.translate(0x4000_1000, AccessType::Store), Err(Exception::StoreAMOPageFault(0x4000_1000)));
+        // A pointer at the last level
+        poke(&cpu, ROOT + 0x2000 + 8, 64, pte(0x8020_0000, PTE_V));
+        assert_eq!(cpu.translate(0x4000_1000, AccessType::Load), Err(Exception::LoadPageFault(0x4000_1000)));
+    }
+
+    #[test]
+    fn sv39_checks_permissions() {
+        let mut cpu = sv39();
+        let page = 0x4000_1000;
+        poke(&cpu, ROOT + 0x2000 + 8, 64, pte(0x8020_0000, LEAF | PTE_R));
+        assert!(cpu.translate(page, AccessType::Store).is_err());
+        assert!(cpu.translate(page, AccessType::Instruction).is_err());
+
+        // Execute-only pages are readable with MXR.
+        poke(&cpu, ROOT + 0x2000 + 8, 64, pte(0x8020_0000, LEAF | PTE_X));
+        assert!(cpu.translate(page, AccessType::Load).is_err());
+        cpu.csrs[MSTATUS] |= MSTATUS_MXR;
+        assert!(cpu.translate(page, AccessType::Load).is_ok());
+
+        // S-mode reaches user pages only with SUM, and never runs code there.
+        poke(&cpu, ROOT + 0x2000 + 8, 64, pte(0x8020_0000, LEAF | PTE_R | PTE_X | PTE_U));
+        assert!(cpu.translate(page, AccessType::Load).is_err());
+        cpu.csrs[MSTATUS] |= MSTATUS_SUM;
+        assert!(cpu.translate(page, AccessType::Load).is_ok());
+        assert!(cpu.translate(page, AccessType::Instruction).is_err());
+        cpu.mode = USER;
+        assert!(cpu.translate(page, AccessType::Instruction).is_ok());
+        poke(&cpu, ROOT + 0x2000 + 8, 64, pte(0x8020_0000, LEAF | PTE_R));
+        assert!(cpu.translate(page, AccessType::Load).is_err());
+
+        // MPRV makes M-mode loads translate as MPP's.
+        cpu.mode = MACHINE;
+        cpu.csrs[MSTATUS] |= MSTATUS_MPRV | SUPERVISOR << 11;
+        assert_eq!(cpu.translate(page, AccessType::Load), Ok(0x8020_0000));
+        assert_eq!(cpu.translate(page, AccessType::Instruction), Ok(page));
+    }
+
+    #[test]
+    fn atomics_fault_at_the_virtual_address() {
+        let mut cpu = sv39();
+        let page = 0x4000_1000;
+        cpu.regs[A0 as usize] = page;
+        let amo = |funct5: u32, rs2| r_type(0x2f, T0, 3, A0, rs2, funct5 << 2);
+        let (lr, sc, amoadd) = (amo(0x02, ZERO), amo(0x03, T1), amo(0x00, T1));
+        cpu.execute(amoadd).unwrap();
+        cpu.execute(lr).unwrap();
+        cpu.execute(sc).unwrap();
+        assert_eq!(cpu.regs[T0 as usize], 0);
+
+        // A page of nothing: the faults name the page, and only LR faults as
+        // a load.
+        poke(&cpu, ROOT + 0x2000 + 8, 64, pte(0x7000_0000, LEAF | PTE_R | PTE_W));
+        assert_eq!(cpu.execute(lr), Err(Exception::LoadAccessFault(page)));
+        assert_eq!(cpu.execute(amoadd), Err(Exception::StoreAMOAccessFault(page)));
+        cpu.bus.lock().unwrap().reserve(cpu.hartid, 0x7000_0000);
+        assert_eq!(cpu.execute(sc), Err(Exception::StoreAMOAccessFault(page)));
+
+        // Reserved encodings trap before anything is read.
+        for inst in [amo(0x02, T1), amo(0x05, T1), amo(0x1f, T1)] {
+            assert_eq!(cpu.execute(inst), Err(Exception::IllegalInstruction(inst as u64)));
+        }
+    }
+
+    #[test]
+    fn svnapot_maps_64_kib_pages() {
+        let mut cpu = sv39();
+        // 0x4001_0000 to 0x4002_0000 onto 0x8021_0000, one PTE for each
+        // page of it
+        let napot = pte(0x8021_8000, LEAF | PTE_R | PTE_N);
+        for i in 0x10..0x20 {
+            poke(&cpu, ROOT + 0x2000 + i * 8, 64, napot);
+        }
+        assert_eq!(cpu.translate(0x4001_5678, AccessType::Load), Ok(0x8021_5678));
+        assert_eq!(cpu.translate(0x4001_f000, AccessType::Load), Ok(0x8021_f000));
+
+        // Other sizes are reserved, as is N on pointers and superpages.
+        poke(&cpu, ROOT + 0x2000 + 0x10 * 8, 64, pte(0x8021_4000, LEAF | PTE_R | PTE_N));
+        assert_eq!(cpu.translate(0x4001_0000, AccessType::Load), Err(Exception::LoadPageFault(0x4001_0000)));
+        poke(&cpu, ROOT + 0x1000 + 8, 64, pte(0x8040_0000, LEAF | PTE_R | PTE_N));
+        assert_eq!(cpu.translate(0x4020_0000, AccessType::Load), Err(Exception::LoadPageFault(0x4020_0000)));
+        poke(&cpu, ROOT + 0x1000, 64, pte(ROOT + 0x2000, PTE_V | PTE_N));
+        assert_eq!(cpu.translate(0x4001_f000, AccessType::Load), Err(Exception::LoadPageFault(0x4001_f000)));
+
+        // Without Svnapot, N is reserved everywhere.
+        poke(&cpu, ROOT + 0x1000, 64, pte(ROOT + 0x2000, PTE_V));
+        cpu.isa = Isa::parse("rv64gc").unwrap().1;
+        assert_eq!(cpu.translate(0x4001_f000, AccessType::Load), Err(Exception::LoadPageFault(0x4001_f000)));
+    }
+
+    #[test]
+    fn svpbmt_types_need_menvcfg() {
+        let mut cpu = sv39();
+        let page = 0x4000_1000;
+        let fault = Err(Exception::LoadPageFault(page));
+        // Non-cacheable memory
+        poke(&cpu, ROOT + 0x2000 + 8, 64, pte(0x8020_0000, LEAF | PTE_R | 1 << 61));
+        assert_eq!(cpu.translate(page, AccessType::Load), fault);
+        cpu.store_csr(MENVCFG, ENVCFG_PBMTE);
+        assert_eq!(cpu.translate(page, AccessType::Load), Ok(0x8020_0000));
+
+        // Type 3 is reserved, and so are types on pointers and the bits
+        // above PPN.
+        poke(&cpu, ROOT + 0x2000 + 8, 64, pte(0x8020_0000, LEAF | PTE_R | PTE_PBMT));
+        assert_eq!(cpu.translate(page, AccessType::Load), fault);
+        poke(&cpu, ROOT + 0x2000 + 8, 64, pte(0x8020_0000, LEAF | PTE_R | 1 << 54));
+        assert_eq!(cpu.translate(page, AccessType::Load), fault);
+        poke(&cpu, ROOT + 0x2000 + 8, 64, pte(0x8020_0000, LEAF | PTE_R));
+        poke(&cpu, ROOT + 0x1000, 64, pte(ROOT + 0x2000, PTE_V | 2 << 61));
+        assert_eq!(cpu.translate(page, AccessType::Load), fault);
+    }
+
+    #[test]
+    fn a_and_d_fault_or_are_set_with_adue() {
+        let mut cpu = sv39();
+        let page = 0x4000_1000;
+        let leaf = ROOT + 0x2000 + 8;
+        let load = |cpu: &Cpu| cpu.bus.lock().unwrap().load(leaf, 64).unwrap();
+        // Svade: software sets A, and D before a store.
+        poke(&cpu, leaf, 64, pte(0x8020_0000, PTE_V | PTE_R | PTE_W));
+        assert_eq!(cpu.translate(page, AccessType::Load), Err(Exception::LoadPageFault(page)));
+        poke(&cpu, leaf, 64, pte(0x8020_0000, PTE_V | PTE_A | PTE_R | PTE_W));
+        assert_eq!(cpu.translate(page, AccessType::Load), Ok(0x8020_0000));
+        assert_eq!(cpu.translate(page, AccessType::Store), Err(Exception::StoreAMOPageFault(page)));
+        assert_eq!(load(&cpu), pte(0x8020_0000, PTE_V | PTE_A | PTE_R | PTE_W));
+
+        // Svadu: the hart sets them.
+        cpu.store_csr(MENVCFG, ENVCFG_ADUE);
+        poke(&cpu, leaf, 64, pte(0x8020_0000, PTE_V | PTE_R | PTE_W));
+        assert_eq!(cpu.translate(page, AccessType::Load), Ok(0x8020_0000));
+        assert_eq!(load(&cpu), pte(0x8020_0000, PTE_V | PTE_A | PTE_R | PTE_W));
+        assert_eq!(cpu.translate(page, AccessType::Store), Ok(0x8020_0000));
+        assert_eq!(load(&cpu), pte(0x8020_0000, LEAF | PTE_R | PTE_W));
+
+        // Not if the access fails anyway
+        poke(&cpu, leaf, 64, pte(0x8020_0000, PTE_V | PTE_R));
+        assert_eq!(cpu.translate(page, AccessType::Store), Err(Exception::StoreAMOPageFault(page)));
+        assert_eq!(load(&cpu), pte(0x8020_0000, PTE_V | PTE_R));
+    }
+
+    /// An RV32 hart in S-mode with Sv32 paging on, mapping 0x4000_1000 like
+    /// `sv39`.
+    fn sv32() -> Cpu {
+        let config = MachineConfig { xlen: Some(32), ..Default::default() };
+        let mut cpu = machine(&config, &[]).harts.remove(0);
+        cpu.mode = SUPERVISOR;
+        cpu.csrs[SATP] = SATP_MODE_SV32 << 31 | ROOT >> 12;
+        poke(&cpu, ROOT + 0x100 * 4, 32, pte(ROOT + 0x1000, PTE_V));
+        poke(&cpu, ROOT + 0x1000 + 4, 32, pte(0x8020_0000, LEAF | PTE_R | PTE_W));
+        cpu
+    }
+
+    #[test]
+    fn sv32_walks_the_page_tables() {
+        let mut cpu = sv32();
+        assert_eq!(cpu.translate(0x4000_1234, AccessType::Load), Ok(0x8020_0234));
+        assert_eq!(cpu.translate(0x4000_2000, AccessType::Load), Err(Exception::LoadPageFault(0x4000_2000)));
+        assert!(cpu.translate(0x4000_1000, AccessType::Instruction).is_err());
+
+        // A 4 MiB megapage, and one that is not aligned
+        poke(&cpu, ROOT + 0x200 * 4, 32, pte(0x8000_0000, LEAF | PTE_X));
+        assert_eq!(cpu.translate(0x803f_fffc, AccessType::Instruction), Ok(0x803f_fffc));
+        poke(&cpu, ROOT + 0x200 * 4, 32, pte(0x8020_0000, LEAF | PTE_X));
+        let fault = Exception::InstructionPageFault(0x8000_0000);
+        assert_eq!(cpu.translate(0x8000_0000, AccessType::Instruction), Err(fault));
+
+        // Physical addresses have 34 bits.
+        poke(&cpu, ROOT + 0x1000 + 4, 32, pte(0x3_0000_0000, LEAF | PTE_R));
+        assert_eq!(cpu.translate(0x4000_1234, AccessType::Load), Ok(0x3_0000_0234));
+
+        // The Sv39 mode value is not a mode on RV32.
+        cpu.csrs[SATP] = ROOT >> 12;
+        assert_eq!(cpu.translate(0x4000_1234, AccessType::Load), Ok(0x4000_1234));
+    }
+
+    #[test]
+    fn rv32_computes_with_32_bits() {
+        let mut program = li(A0, 0x7fff_ffff).to_vec();
+        program.extend([
+            addi(A0, A0, 1),
+            r_type(0x33, T0, 1, A0, A0, 1), // mulh t0, a0, a0
+            i_type(0x13, T1, 5, A0, 31),    // srli t1, a0, 31
+            i_type(0x13, T2, 5, A0, 0x400), // srai t2, a0, 0
+        ]);
+        let config = MachineConfig { xlen: Some(32), ..Default::default() };
+        let mut cpu = machine(&config, &program).harts.remove(0);
+        for _ in 0..program.len() {
+            cpu.step().unwrap();
+        }
+        assert_eq!(cpu.truncate(cpu.regs[A0 as usize]), 0x8000_0000);
+        assert_eq!(cpu.truncate(cpu.regs[T0 as usize]), 0x4000_0000);
+        assert_eq!(cpu.truncate(cpu.regs[T1 as usize]), 1);
+        assert_eq!(cpu.truncate(cpu.regs[T2 as usize]), 0x8000_0000);
+
+        // Shifts past bit 31 and the RV64 instructions are illegal.
+        for inst in [i_type(0x13, A0, 1, A0, 32), i_type(0x03, A0, 3, A0, 0), addi(A0, A0, 0) | 0x8] {
+            assert_eq!(cpu.execute(inst), Err(Exception::IllegalInstruction(inst as u64)), "{:#010x}", inst);
+        }
+    }
+
+    /// The guest's own page tables, at a guest physical address that the
+    /// G-stage maps to itself.
+    const GUEST_ROOT: u64 = 0x8030_0000;
+
+    /// A hart in VS-mode whose G-stage, with the root table at `ROOT`, maps
+    /// the guest physical page at 0x4000_1000 to 0x8020_0000 and the
+    /// gigapage at 0x8000_0000 to itself. The guest's satp is bare.
+    fn guest() -> Cpu {
+        let mut cpu = machine(&MachineConfig::default(), &[]).harts.remove(0);
+        cpu.mode = SUPERVISOR;
+        cpu.virt = true;
+        cpu.csrs[HGATP] = HGATP_MODE_SV39X4 << 60 | ROOT >> 12;
+        // The Sv39x4 root table takes 16 KiB.
+        poke(&cpu, ROOT + 8, 64, pte(ROOT + 0x4000, PTE_V));
+        poke(&cpu, ROOT + 0x4000, 64, pte(ROOT + 0x5000, PTE_V));
+        poke(&cpu, ROOT + 0x5000 + 8, 64, pte(0x8020_0000, LEAF | PTE_R | PTE_W | PTE_U));
+        poke(&cpu, ROOT + 2 * 8, 64, pte(0x8000_0000, LEAF | PTE_R | PTE_W | PTE_X | PTE_U));
+        cpu
+    }
+
+    /// Turns on the guest's Sv39 paging, mapping its virtual page at 0x1000
+    /// to the guest physical page at 0x4000_1000.
+    fn guest_paging(cpu: &mut Cpu) {
+        cpu.csrs[VSATP] = SATP_MODE_SV39 << 60 | GUEST_ROOT >> 12;
+        poke(cpu, GUEST_ROOT, 64, pte(GUEST_ROOT + 0x1000, PTE_V));
+        poke(cpu, GUEST_ROOT + 0x1000, 64, pte(GUEST_ROOT + 0x2000, PTE_V));
+        poke(cpu, GUEST_ROOT + 0x2000 + 8, 64, pte(0x4000_1000, LEAF | PTE_R | PTE_W));
+    }
+
+    #[test]
+    fn g_stage_translates_guest_physical_addresses() {
+        let mut cpu = guest();
+        assert_eq!(cpu.translate(0x4000_1234, AccessType::Load), Ok(0x8020_0234));
+        assert_eq!(cpu.translate(0x4000_1234, AccessType::Store), Ok(0x8020_0234));
+        assert_eq!(cpu.translate(0x8765_4321, AccessType::Instruction), Ok(0x8765_4321));
+        let fault = Exception::InstructionGuestPageFault(0x4000_1000, 0x4000_1000);
+        assert_eq!(cpu.translate(0x4000_1000, AccessType::Instruction), Err(fault));
+        let fault = Exception::LoadGuestPageFault(0x4000_2000, 0x4000_2000);
+        assert_eq!(cpu.translate(0x4000_2000, AccessType::Load), Err(fault));
+        // Guest physical addresses have 41 bits.
+        assert!(cpu.translate(1 << 41, AccessType::Load).is_err());
+        // Every G-stage leaf is a user page.
+        poke(&cpu, ROOT + 0x5000 + 8, 64, pte(0x8020_0000, LEAF | PTE_R | PTE_W));
+        let fault = Exception::StoreAMOGuestPageFault(0x4000_1000, 0x4000_1000);
+        assert_eq!(cpu.translate(0x4000_1000, AccessType::Store), Err(fault));
+    }
+
+    #[test]
+    fn guests_translate_in_two_stages() {
+        let mut cpu = guest();
+        guest_paging(&mut cpu);
+        assert_eq!(cpu.translate(0x1234, AccessType::Load), Ok(0x8020_0234));
+        assert_eq!(cpu.translate(0x2000, AccessType::Load), Err(Exception::LoadPageFault(0x2000)));
+
+        // The guest's page tables go through the G-stage too.
+        poke(&cpu, ROOT + 2 * 8, 64, pte(0x8000_0000, LEAF | PTE_X | PTE_U));
+        let fault = Exception::LoadGuestPageFault(0x1234, GUEST_ROOT);
+        assert_eq!(cpu.translate(0x1234, AccessType::Load), Err(fault));
+
+        // Satp stays for HS-mode, which translates with it alone.
+        cpu.virt = false;
+        assert_eq!(cpu.translate(0x1234, AccessType::Load), Ok(0x1234));
+    }
+
+    #[test]
+    fn henvcfg_governs_the_guests_page_tables() {
+        let mut cpu = guest();
+        guest_paging(&mut cpu);
+        let guest_leaf = GUEST_ROOT + 0x2000 + 8;
+        let g_leaf = ROOT + 0x5000 + 8;
+        poke(&cpu, guest_leaf, 64, pte(0x4000_1000, PTE_V | PTE_R | PTE_W));
+        poke(&cpu, g_leaf, 64, pte(0x8020_0000, PTE_V | PTE_R | PTE_W | PTE_U));
+        assert_eq!(cpu.translate(0x1234, AccessType::Store), Err(Exception::StoreAMOPageFault(0x1234)));
+        // menvcfg.ADUE only covers the G-stage.
+        cpu.store_csr(MENVCFG, ENVCFG_ADUE | ENVCFG_PBMTE);
+        assert_eq!(cpu.translate(0x1234, AccessType::Store), Err(Exception::StoreAMOPageFault(0x1234)));
+        cpu.store_csr(HENVCFG, ENVCFG_ADUE);
+        assert_eq!(cpu.translate(0x1234, AccessType::Store), Ok(0x8020_0234));
+        let load = |addr| cpu.bus.lock().unwrap().load(addr, 64).unwrap();
+        assert_eq!(load(guest_leaf), pte(0x4000_1000, LEAF | PTE_R | PTE_W));
+        assert_eq!(load(g_leaf), pte(0x8020_0000, LEAF | PTE_R | PTE_W | PTE_U));
+
+        // Likewise henvcfg.PBMTE turns on the guest's memory types.
+        poke(&cpu, guest_leaf, 64, pte(0x4000_1000, LEAF | PTE_R | 2 << 61));
+        assert_eq!(cpu.translate(0x1234, AccessType::Load), Err(Exception::LoadPageFault(0x1234)));
+        cpu.store_csr(HENVCFG, ENVCFG_ADUE | ENVCFG_PBMTE);
+        assert_eq!(cpu.translate(0x1234, AccessType::Load), Ok(0x8020_0234));
+    }
+
+    #[test]
+    fn hypervisor_loads_and_stores_as_the_guest() {
+        let mut cpu = guest();
+        guest_paging(&mut cpu);
+        cpu.virt = false;
+        cpu.csrs[HSTATUS] |= HSTATUS_SPVP;
+        poke(&cpu, 0x8020_0230, 64, 0x8877_6655_4433_2211);
+        cpu.regs[T0 as usize] = 0x1230;
+        cpu.regs[T1 as usize] = 0xabcd;
+        let hlv_d = r_type(0x73, A0, 4, T0, 0, 0x36);
+        let hsv_h = r_type(0x73, 0, 4, T0, T1, 0x33);
+        let hlv_hu = r_type(0x73, A0, 4, T0, 1, 0x32);
+        let hlvx_wu = r_type(0x73, A0, 4, T0, 3, 0x34);
+        cpu.execute(hlv_d).unwrap();
+        assert_eq!(cpu.regs[A0 as usize], 0x8877_6655_4433_2211);
+        cpu.execute(hsv_h).unwrap();
+        cpu.execute(hlv_hu).unwrap();
+        assert_eq!(cpu.regs[A0 as usize], 0xabcd);
+        // hlvx needs execute permission instead of read.
+        assert_eq!(cpu.execute(hlvx_wu), Err(Exception::LoadPageFault(0x1230)));
+
+        // U-mode only with hstatus.HU, and never in a guest
+        cpu.mode = USER;
+        assert_eq!(cpu.execute(hlv_d), Err(Exception::IllegalInstruction(hlv_d as u64)));
+        cpu.csrs[HSTATUS] |= HSTATUS_HU;
+        cpu.execute(hlv_d).unwrap();
+        cpu.virt = true;
+        assert_eq!(cpu.execute(hlv_d), Err(Exception::VirtualInstruction(hlv_d as u64)));
+    }
+
+    #[test]
+    fn guests_reach_the_hypervisor_csrs_through_traps() {
+        let mut cpu = machine(&MachineConfig::default(), &[]).harts.remove(0);
+        let read = |cpu: &mut Cpu, csr: usize| {
+            let inst = csrrs(A0, csr as u16, ZERO);
+            cpu.execute(inst).map(|()| cpu.regs[A0 as usize]).map_err(|e| (e, inst as u64))
+        };
+        cpu.mode = SUPERVISOR;
+        cpu.csrs[HSTATUS] = HSTATUS_HU;
+        assert_eq!(read(&mut cpu, HSTATUS), Ok(HSTATUS_HU));
+
+        // A guest's S CSRs are the VS ones.
+        cpu.virt = true;
+        cpu.csrs[VSSCRATCH] = 7;
+        assert_eq!(read(&mut cpu, SSCRATCH), Ok(7));
+        assert!(matches!(read(&mut cpu, HSTATUS), Err((Exception::VirtualInstruction(i), j)) if i == j));
+        assert!(matches!(read(&mut cpu, VSSCRATCH), Err((Exception::VirtualInstruction(_), _))));
+        assert!(matches!(read(&mut cpu, MSTATUS), Err((Exception::IllegalInstruction(_), _))));
+        cpu.mode = USER;
+        assert!(matches!(read(&mut cpu, SSCRATCH), Err((Exception::VirtualInstruction(_), _))));
+        cpu.virt = false;
+        assert!(matches!(read(&mut cpu, HSTATUS), Err((Exception::IllegalInstruction(_), _))));
+
+        // TVM traps satp and hgatp in HS-mode, VTVM a guest's satp.
+        cpu.mode = SUPERVISOR;
+        cpu.csrs[MSTATUS] |= MSTATUS_TVM;
+        assert!(matches!(read(&mut cpu, HGATP), Err((Exception::IllegalInstruction(_), _))));
+        assert!(matches!(read(&mut cpu, SATP), Err((Exception::IllegalInstruction(_), _))));
+        cpu.virt = true;
+        assert_eq!(read(&mut cpu, SATP), Ok(0));
+        cpu.csrs[HSTATUS] |= HSTATUS_VTVM;
+        assert!(matches!(read(&mut cpu, SATP), Err((Exception::VirtualInstruction(_), _))));
+
+        let (xlen, isa) = Isa::parse("rv64gc").unwrap();
+        let config = MachineConfig { xlen: Some(xlen), isa, ..Default::default() };
+        let mut cpu = machine(&config, &[]).harts.remove(0);
+        assert!(matches!(read(&mut cpu, HSTATUS), Err((Exception::IllegalInstruction(_), _))));
+    }
+
+    #[test]
+    fn guest_traps_go_where_delegated() {
+        let mut cpu = guest();
+        cpu.pc = 0x4000;
+        cpu.csrs[STVEC] = 0x8000;
+        cpu.csrs[VSTVEC] = 0x9000;
+        cpu.csrs[MTVEC] = 0xa000;
+        cpu.csrs[MEDELEG] = 1 << 3 | 1 << 21;
+        cpu.csrs[HEDELEG] = 1 << 3;
+
+        // Delegated by both, to the guest's handler
+        cpu.mode = USER;
+        cpu.handle_exception(Exception::Breakpoint(0x4000));
+        assert_eq!((cpu.pc, cpu.mode, cpu.virt), (0x9000, SUPERVISOR, true));
+        assert_eq!((cpu.csrs[VSCAUSE], cpu.csrs[VSEPC], cpu.csrs[VSTVAL]), (3, 0x4000, 0x4000));
+
+        // Guest-page faults go to the hypervisor, with the address.
+        let fault = cpu.translate(0x4000_2008, AccessType::Load).unwrap_err();
+        cpu.handle_exception(fault);
+        assert_eq!((cpu.pc, cpu.mode, cpu.virt), (0x8000, SUPERVISOR, false));
+        assert_eq!((cpu.csrs[SCAUSE], cpu.csrs[SEPC], cpu.csrs[STVAL]), (21, 0x9000, 0x4000_2008));
+        assert_eq!(cpu.csrs[HTVAL], 0x4000_2008 >> 2);
+        let hstatus = cpu.csrs[HSTATUS];
+        assert_eq!(hstatus & (HSTATUS_SPV | HSTATUS_SPVP | HSTATUS_GVA), HSTATUS_SPV | HSTATUS_SPVP | HSTATUS_GVA);
+
+        // And the rest to M-mode, which records that the hart was in a guest.
+        cpu.virt = true;
+        cpu.handle_exception(Exception::IllegalInstruction(0));
+        assert_eq!((cpu.pc, cpu.mode, cpu.virt), (0xa000, MACHINE, false));
+        assert_eq!(cpu.csrs[MSTATUS] & (MSTATUS_MPV | MSTATUS_MPP), MSTATUS_MPV | SUPERVISOR << 11);
+    }
+
+    #[test]
+    fn czero_tests_rs2() {
+        let mut cpu = machine(&MachineConfig::default(), &[]).harts.remove(0);
+        cpu.regs[A0 as usize] = 5;
+        for (rs2, funct3, result) in [(0, 5, 0), (1, 5, 5), (0, 7, 5), (1, 7, 0)] {
+            cpu.regs[T0 as usize] = rs2;
+            cpu.execute(r_type(0x33, T1, funct3, A0, T0, 0x07)).unwrap();
+            assert_eq!(cpu.regs[T1 as usize], result, "funct3 {} rs2 {}", funct3, rs2);
+        }
+
+        let (xlen, isa) = Isa::parse("rv64gc").unwrap();
+        let config = MachineConfig { xlen: Some(xlen), isa, ..Default::default() };
+        let mut cpu = machine(&config, &[]).harts.remove(0);
+        let inst = r_type(0x33, T1, 5, A0, T0, 0x07);
+        assert_eq!(cpu.execute(inst), Err(Exception::IllegalInstruction(inst as u64)));
+        // pause is a fence, and the prefetches are ori to x0.
+        cpu.execute(0x0100_000f).unwrap();
+        cpu.execute(i_type(0x13, ZERO, 6, A0, 0x43)).unwrap();
+    }
+
+    #[test]
+    fn cbo_zero_clears_the_block() {
+        let mut cpu = machine(&MachineConfig::default(), &[]).harts.remove(0);
+        let block = ROOT + 0x1000;
+        for offset in (0..0x80).step_by(8) {
+            poke(&cpu, block + offset, 64, u64::MAX);
+        }
+        cpu.regs[T0 as usize] = block + 0x13;
+        let cbo = |funct12: i32| i_type(0x0f, ZERO, 2, T0, funct12);
+        cpu.execute(cbo(4)).unwrap();
+        assert_eq!(cpu.load(block, 64), Ok(0));
+        assert_eq!(cpu.load(block + 0x38, 64), Ok(0));
+        assert_eq!(cpu.load(block + 0x40, 64), Ok(u64::MAX));
+        assert_eq!(cpu.load(block - 8, 64), Ok(0));
+
+        // The management operations change nothing, but need memory.
+        for funct12 in [0, 1, 2] {
+            cpu.execute(cbo(funct12)).unwrap();
+        }
+        assert_eq!(cpu.load(block + 0x40, 64), Ok(u64::MAX));
+        cpu.regs[T0 as usize] = 0x1000;
+        assert_eq!(cpu.execute(cbo(2)), Err(Exception::StoreAMOAccessFault(0x1000)));
+        assert!(cpu.execute(cbo(3)).is_err());
+    }
+
+    #[test]
+    fn envcfg_enables_cbo() {
+        let mut cpu = machine(&MachineConfig::default(), &[]).harts.remove(0);
+        cpu.regs[T0 as usize] = ROOT;
+        let zero = i_type(0x0f, ZERO, 2, T0, 4);
+        let illegal = Err(Exception::IllegalInstruction(zero as u64));
+        cpu.mode = SUPERVISOR;
+        cpu.csrs[MENVCFG] = 0;
+        assert_eq!(cpu.execute(zero), illegal);
+        cpu.csrs[MENVCFG] = ENVCFG_CBZE;
+        assert_eq!(cpu.execute(zero), Ok(()));
+
+        // U-mode needs senvcfg too, and a guest henvcfg.
+        cpu.mode = USER;
+        cpu.csrs[SENVCFG] = 0;
+        assert_eq!(cpu.execute(zero), illegal);
+        cpu.virt = true;
+        assert_eq!(cpu.execute(zero), Err(Exception::VirtualInstruction(zero as u64)));
+        cpu.csrs[SENVCFG] = ENVCFG_CBZE;
+        cpu.csrs[HENVCFG] = ENVCFG_CBZE;
+        cpu.csrs[MENVCFG] = 0;
+        assert_eq!(cpu.execute(zero), illegal);
+    }
+
+    #[test]
+    fn runs_compressed_instructions() {
+        let program = [
+            0x050d_4515,                    // c.li a0, 5; c.addi a0, 3
+            addi(T1, T0, 1) << 16 | 0x82aa, // c.mv t0, a0, and a 32-bit instruction
+            addi(T1, T0, 1) >> 16,          // at a 2-byte boundary; then c.unimp
+        ];
+        let mut cpu = machine(&MachineConfig::default(), &program).harts.remove(0);
+        let base = cpu.pc;
+        for _ in 0..4 {
+            cpu.step().unwrap();
+        }
+        assert_eq!((cpu.regs[A0 as usize], cpu.regs[T0 as usize], cpu.regs[T1 as usize]), (8, 8, 9));
+        assert_eq!(cpu.pc, base + 10);
+        assert_eq!(cpu.step(), Err(Exception::IllegalInstruction(0)));
+        assert_eq!(cpu.pc, base + 10);
+    }
+}
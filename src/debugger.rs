@@ -0,0 +1,406 @@
+use std::collections::BTreeSet;
+
+use crate::cpu::*;
+use crate::machine::*;
+use crate::snapshot::*;
+
+/// Checkpoints kept before every other one is dropped.
+const MAX_CHECKPOINTS: usize = 64;
+
+/// How often, in instructions, a long run looks for a request to stop.
+const INTERRUPT_CHECK: u64 = 0x10000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WatchKind {
+    Write,
+    Read,
+    Access,
+}
+
+impl WatchKind {
+    /// Whether a read, or a write if `write` is set, triggers the watchpoint.
+    pub fn covers(self, write: bool) -> bool {
+        match self {
+            WatchKind::Write => write,
+            WatchKind::Read => !write,
+            WatchKind::Access => true,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub addr: u64,
+    pub len: u64,
+    pub kind: WatchKind,
+}
+
+/// Why the machine stopped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stop {
+    /// The hart being stepped executed an instruction.
+    Step,
+    /// A hart is about to execute an instruction with a breakpoint on it.
+    Breakpoint,
+    /// An instruction accessed watched memory. Running forwards, the hart
+    /// has just executed it; running backwards, it is about to.
+    Watchpoint(Watchpoint),
+    /// The front end asked the machine to stop.
+    Interrupted,
+    /// Reverse execution reached the start of the recorded history.
+    HistoryStart,
+    /// The guest exited with this status.
+    Exited(i32),
+    /// Every hart has stopped.
+    Halted,
+}
+
+/// The state of the whole machine at one point of the run.
+struct Checkpoint {
+    steps: u64,
+    turn: usize,
+    slice: u64,
+    state: Vec<u8>,
+}
+
+/// Runs a machine under the control of a debugger, forwards and backwards.
+///
+/// Checkpoints of the machine are kept in memory every `interval`
+/// instructions. The round-robin schedule is deterministic, so going back to
+/// an earlier instruction restores the last checkpoint before it and runs
+/// forward again from there, with device output muted. When there are too
+/// many checkpoints every other one is dropped and the interval doubles, so
+/// the history always reaches back to the start of the session.
+pub struct Debugger {
+    pub machine: Machine,
+    quantum: u64,
+    pub breakpoints: BTreeSet<u64>,
+    watchpoints: Vec<Watchpoint>,
+    checkpoints: Vec<Checkpoint>,
+    interval: u64,
+    initial_interval: u64,
+    /// The hart that caused the last stop, shown by default.
+    pub hart: usize,
+}
+
+impl Debugger {
+    pub fn new(machine: Machine, quantum: u64, interval: u64) -> Self {
+        let mut debugger = Self {
+            machine,
+            quantum,
+            breakpoints: BTreeSet::new(),
+            watchpoints: Vec::new(),
+            checkpoints: Vec::new(),
+            interval,
+            initial_interval: interval,
+            hart: 0,
+        };
+        debugger.checkpoint();
+        debugger.hart = debugger.next().unwrap_or(0);
+        debugger
+    }
+
+    pub fn add_watchpoint(&mut self, watch: Watchpoint) {
+        self.watchpoints.push(watch);
+        self.update_watchpoints();
+    }
+
+    /// Removes a watchpoint, returning whether there was one.
+    pub fn remove_watchpoint(&mut self, watch: Watchpoint) -> bool {
+        let Some(index) = self.watchpoints.iter().position(|&w| w == watch) else {
+            return false;
+        };
+        self.watchpoints.remove(index);
+        self.update_watchpoints();
+        true
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    fn update_watchpoints(&mut self) {
+        for hart in self.machine.harts.iter_mut() {
+            hart.watchpoints = self.watchpoints.clone();
+        }
+    }
+
+    /// Starts the history afresh, after the state was changed by hand: runs
+    /// from an earlier checkpoint would not lead to the current state.
+    pub fn modified(&mut self) {
+        self.checkpoints.clear();
+        self.interval = self.initial_interval;
+        self.checkpoint();
+    }
+
+    fn checkpoint(&mut self) {
+        let mut out = StateWriter::default();
+        self.machine.save_state(&mut out);
+        self.checkpoints.push(Checkpoint {
+            steps: self.machine.steps,
+            turn: self.machine.turn,
+            slice: self.machine.slice,
+            state: out.data,
+        });
+        if self.checkpoints.len() > MAX_CHECKPOINTS {
+            let mut index = 0;
+            self.checkpoints.retain(|_| {
+                index += 1;
+                index & 1 == 1
+            });
+            self.interval *= 2;
+        }
+    }
+
+    fn restore(&mut self, index: usize) {
+        let checkpoint = &self.checkpoints[index];
+        let mut input = StateReader::new(&checkpoint.state);
+        self.machine
+            .restore_state(&mut input)
+            .expect("a checkpoint restores on the machine it was taken from");
+        self.machine.turn = checkpoint.turn;
+        self.machine.slice = checkpoint.slice;
+        for hart in self.machine.harts.iter_mut() {
+            hart.watch_hit = None;
+        }
+    }
+
+    /// Takes a checkpoint if one is due, then returns the hart that runs
+    /// next, like `Machine::next_hart`.
+    fn next(&mut self) -> Option<usize> {
+        let last = self.checkpoints.last().map_or(0, |checkpoint| checkpoint.steps);
+        if self.machine.steps >= last + self.interval {
+            self.checkpoint();
+        }
+        self.machine.next_hart(self.quantum)
+    }
+
+    /// Why the machine cannot go on.
+    fn end(&self) -> Stop {
+        self.machine.exit_status.map_or(Stop::Halted, Stop::Exited)
+    }
+
+    /// Runs until a breakpoint or watchpoint is hit, or until `hart` has
+    /// executed an instruction if given. `interrupted` is polled now and
+    /// then and stops the machine when it returns true.
+    pub fn resume(&mut self, hart: Option<usize>, interrupted: &mut dyn FnMut() -> bool) -> Stop {
+        let mut first = true;
+        loop {
+            let Some(next) = self.next() else {
+                return self.end();
+            };
+            // The breakpoint the hart stopped at does not stop it again.
+            if !first && self.breakpoints.contains(&self.machine.harts[next].pc) {
+                self.hart = next;
+                return Stop::Breakpoint;
+            }
+            first = false;
+
+            self.machine.step(self.quantum);
+            if let Some(watch) = self.machine.harts[next].watch_hit.take() {
+                self.hart = next;
+                self.next();
+                return Stop::Watchpoint(watch);
+            }
+            if hart == Some(next) {
+                self.hart = next;
+                self.next();
+                return Stop::Step;
+            }
+            if self.machine.steps & (INTERRUPT_CHECK - 1) == 0 && interrupted() {
+                self.hart = self.next().unwrap_or(next);
+                return Stop::Interrupted;
+            }
+        }
+    }
+
+    /// Goes back to just before the last instruction `hart` executed if
+    /// given, or else to the last breakpoint or watchpoint hit.
+    pub fn reverse(&mut self, hart: Option<usize>) -> Stop {
+        self.machine.mute(true);
+        let stop = self.search_back(hart);
+        self.machine.mute(false);
+        stop
+    }
+
+    fn search_back(&mut self, hart: Option<usize>) -> Stop {
+        let end = self.machine.steps;
+        for index in (0..self.checkpoints.len()).rev() {
+            if self.checkpoints[index].steps >= end {
+                continue;
+            }
+            // Run forward to the next checkpoint, which later rounds of the
+            // search have covered, remembering the last place to stop at.
+            let limit = self.checkpoints.get(index + 1).map_or(end, |next| next.steps.min(end));
+            self.restore(index);
+            let mut found = None;
+            while self.machine.steps < limit {
+                let Some(next) = self.next() else {
+                    break;
+                };
+                let steps = self.machine.steps;
+                match hart {
+                    Some(hart) if hart == next => found = Some((steps, next, Stop::Step)),
+                    None if self.breakpoints.contains(&self.machine.harts[next].pc) => {
+                        found = Some((steps, next, Stop::Breakpoint))
+                    }
+                    _ => {}
+                }
+                self.machine.step(self.quantum);
+                if let Some(watch) = self.machine.harts[next].watch_hit.take() {
+                    if hart.is_none() {
+                        found = Some((steps, next, Stop::Watchpoint(watch)));
+                    }
+                }
+            }
+
+            if let Some((steps, next, stop)) = found {
+                self.restore(index);
+                self.run_to(steps);
+                self.hart = next;
+                return stop;
+            }
+        }
+
+        self.restore(0);
+        self.hart = self.next().unwrap_or(self.hart);
+        Stop::HistoryStart
+    }
+
+    /// Runs forward until `steps` instructions have been executed.
+    fn run_to(&mut self, steps: u64) {
+        while self.machine.steps < steps {
+            let Some(next) = self.next() else {
+                break;
+            };
+            self.machine.step(self.quantum);
+            self.machine.harts[next].watch_hit = None;
+        }
+        self.next();
+    }
+
+    /// Reads guest memory at a virtual address, as `hart` sees it.
+    pub fn read_memory(&mut self, hart: usize, addr: u64, len: u64) -> Option<Vec<u8>> {
+        (0..len)
+            .map(|i| {
+                let paddr = self.physical(hart, addr.wrapping_add(i))?;
+                let byte = self.machine.bus.lock().unwrap().load(paddr, 8).ok()?;
+                Some(byte as u8)
+            })
+            .collect()
+    }
+
+    /// Writes guest memory at a virtual address, as `hart` sees it.
+    pub fn write_memory(&mut self, hart: usize, addr: u64, data: &[u8]) -> Option<()> {
+        for (i, &byte) in data.iter().enumerate() {
+            let paddr = self.physical(hart, addr.wrapping_add(i as u64))?;
+            self.machine.bus.lock().unwrap().store(paddr, 8, byte as u64).ok()?;
+        }
+        self.modified();
+        Some(())
+    }
+
+    /// Translates an address the debugger is given: anything the hart can
+    /// read or execute.
+    fn physical(&mut self, hart: usize, addr: u64) -> Option<u64> {
+        let hart = &mut self.machine.harts[hart];
+        hart.translate(addr, AccessType::Load)
+            .or_else(|_| hart.translate(addr, AccessType::Instruction))
+            .ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::DRAM_BASE;
+    use crate::config::*;
+    use crate::testing::*;
+
+    const COUNTER: u64 = DRAM_BASE + 0x1000;
+
+    /// Counts t0 up to 5, storing each value to `COUNTER`, then stops.
+    fn debugger() -> Debugger {
+        let program = [
+            // auipc t1, 1
+            0x1000 | (T1 << 7) | 0x17,
+            addi(T2, ZERO, 5),
+            addi(T0, T0, 1),
+            sd(T0, T1, 0),
+            bne(T0, T2, -8),
+            jalr(ZERO, ZERO, 0),
+        ];
+        Debugger::new(machine(&MachineConfig::default(), &program), 1, 3)
+    }
+
+    fn never() -> bool {
+        false
+    }
+
+    #[test]
+    fn breakpoints_and_watchpoints_stop_the_run() {
+        let mut debugger = debugger();
+        debugger.breakpoints.insert(DRAM_BASE + 8);
+        assert_eq!(debugger.resume(None, &mut never), Stop::Breakpoint);
+        assert_eq!((debugger.machine.harts[0].pc, debugger.machine.harts[0].regs[T0 as usize]), (DRAM_BASE + 8, 0));
+        assert_eq!(debugger.resume(None, &mut never), Stop::Breakpoint);
+        assert_eq!(debugger.machine.harts[0].regs[T0 as usize], 1);
+        assert_eq!(debugger.resume(Some(0), &mut never), Stop::Step);
+        assert_eq!(debugger.machine.harts[0].pc, DRAM_BASE + 12);
+
+        debugger.breakpoints.clear();
+        let watch = Watchpoint { addr: COUNTER, len: 8, kind: WatchKind::Write };
+        debugger.add_watchpoint(watch);
+        assert_eq!(debugger.resume(None, &mut never), Stop::Watchpoint(watch));
+        assert_eq!(debugger.machine.harts[0].pc, DRAM_BASE + 16);
+        assert_eq!(debugger.read_memory(0, COUNTER, 2), Some(vec![2, 0]));
+        assert_eq!(debugger.resume(None, &mut never), Stop::Watchpoint(watch));
+        assert_eq!(debugger.read_memory(0, COUNTER, 1), Some(vec![3]));
+
+        // Memory written by hand starts the history afresh.
+        assert_eq!(debugger.write_memory(0, COUNTER, &[9]), Some(()));
+        assert_eq!(debugger.read_memory(0, COUNTER, 1), Some(vec![9]));
+        let steps = debugger.machine.steps;
+        assert_eq!(debugger.reverse(None), Stop::HistoryStart);
+        assert_eq!(debugger.machine.steps, steps);
+
+        assert!(debugger.remove_watchpoint(watch));
+        assert!(!debugger.remove_watchpoint(watch));
+        assert_eq!(debugger.resume(None, &mut never), Stop::Halted);
+        assert_eq!(debugger.machine.harts[0].regs[T0 as usize], 5);
+    }
+
+    #[test]
+    fn reverse_steps_and_continues() {
+        let mut debugger = debugger();
+        assert_eq!(debugger.resume(None, &mut never), Stop::Halted);
+        assert_eq!(debugger.machine.steps, 18);
+
+        assert_eq!(debugger.reverse(Some(0)), Stop::Step);
+        assert_eq!((debugger.machine.steps, debugger.machine.harts[0].pc), (17, DRAM_BASE + 20));
+
+        debugger.breakpoints.insert(DRAM_BASE + 8);
+        assert_eq!(debugger.reverse(None), Stop::Breakpoint);
+        assert_eq!(debugger.machine.harts[0].regs[T0 as usize], 4);
+        assert_eq!(debugger.reverse(None), Stop::Breakpoint);
+        assert_eq!(debugger.machine.harts[0].regs[T0 as usize], 3);
+
+        // Going backwards, the hart stops before the store that hit.
+        debugger.breakpoints.clear();
+        let watch = Watchpoint { addr: COUNTER, len: 8, kind: WatchKind::Write };
+        debugger.add_watchpoint(watch);
+        assert_eq!(debugger.reverse(None), Stop::Watchpoint(watch));
+        assert_eq!(debugger.machine.harts[0].pc, DRAM_BASE + 12);
+        assert_eq!(debugger.machine.harts[0].regs[T0 as usize], 3);
+        assert_eq!(debugger.read_memory(0, COUNTER, 1), Some(vec![2]));
+
+        debugger.remove_watchpoint(watch);
+        assert_eq!(debugger.reverse(None), Stop::HistoryStart);
+        assert_eq!((debugger.machine.steps, debugger.machine.harts[0].pc), (0, DRAM_BASE));
+
+        // Running forward again gets to the same end.
+        assert_eq!(debugger.resume(None, &mut never), Stop::Halted);
+        assert_eq!(debugger.machine.steps, 18);
+        assert_eq!(debugger.read_memory(0, COUNTER, 1), Some(vec![5]));
+    }
+}
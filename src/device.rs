@@ -0,0 +1,85 @@
+use std::io;
+
+use crate::dram::*;
+use crate::exception::*;
+use crate::fdt::*;
+use crate::snapshot::*;
+
+/// A memory-mapped peripheral on the bus.
+///
+/// Accesses are passed the offset from `base()`, already checked to lie
+/// within `size()` bytes.
+pub trait Device: Send {
+    fn base(&self) -> u64;
+    fn size(&self) -> u64;
+    fn load(&mut self, offset: u64, size: u64) -> Result<u64, Exception>;
+    fn store(&mut self, offset: u64, size: u64, value: u64) -> Result<(), Exception>;
+
+    /// The PLIC interrupt source the device is wired to, if any.
+    fn irq(&self) -> Option<u32> {
+        None
+    }
+
+    /// Whether the device's interrupt line is currently raised.
+    fn interrupting(&mut self) -> bool {
+        false
+    }
+
+    /// Reads and writes guest memory for work the guest asked for through
+    /// the device's registers, such as requests queued in DRAM, or to hand
+    /// it data from the host. Called after every store to a device and as
+    /// the machine timer advances.
+    fn dma(&mut self, _dram: &mut Dram) {}
+
+    /// Exit status requested by the guest through the device, if any.
+    fn exit_status(&self) -> Option<i32> {
+        None
+    }
+
+    /// Adds the device's nodes under `/soc`.
+    fn device_tree(&self, _fdt: &mut Fdt, _harts: usize) {}
+
+    /// Writes the state the guest can observe to a snapshot. Host-side
+    /// connections, such as sockets and open image files, are not part of it.
+    fn save(&self, _out: &mut StateWriter) {}
+
+    /// Restores what `save` wrote on a device created from the same
+    /// configuration.
+    fn restore(&mut self, _input: &mut StateReader) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Stops or resumes the output the device sends to the host, while the
+    /// debugger runs again through instructions whose output was already
+    /// sent.
+    fn mute(&mut self, _muted: bool) {}
+
+    /// A summary of the device's registers, shown by the monitor.
+    fn dump_state(&self) -> Option<String> {
+        None
+    }
+
+    /// The device tree path of the device if it is a console for the
+    /// kernel's early messages.
+    fn stdout_path(&self) -> Option<String> {
+        None
+    }
+}
+
+/// Reads `size` bits at byte `offset` out of a little-endian register value.
+pub fn read_reg(reg: u64, offset: u64, size: u64) -> u64 {
+    let value = reg >> ((offset % 8) * 8);
+    if size == 64 {
+        value
+    } else {
+        value & ((1 << size) - 1)
+    }
+}
+
+/// Writes `size` bits of `value` at byte `offset` of a little-endian register
+/// value and returns the new register value.
+pub fn write_reg(reg: u64, offset: u64, size: u64, value: u64) -> u64 {
+    let shift = (offset % 8) * 8;
+    let mask = if size == 64 { u64::MAX } else { (1 << size) - 1 } << shift;
+    (reg & !mask) | ((value << shift) & mask)
+}
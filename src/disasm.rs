@@ -0,0 +1,722 @@
+use crate::cpu::*;
+
+/// Disassembles an RV64GC or RV32GC instruction, with the bit-manipulation,
+/// scalar cryptography and vector extensions, at `pc`, with ABI register
+/// names and branch targets as addresses. Compressed instructions are shown
+/// as the instruction they expand to.
+pub fn disassemble(inst: u32, pc: u64) -> String {
+    decode(inst, pc).unwrap_or_else(|| format!(".word {:#010x}", inst))
+}
+
+/// The one-operand SHA-2 and SM3 instructions in OP-IMM, from immediate
+/// 0x100.
+const CRYPTO_UNARY: [&str; 10] = [
+    "sha256sum0",
+    "sha256sum1",
+    "sha256sig0",
+    "sha256sig1",
+    "sha512sum0",
+    "sha512sum1",
+    "sha512sig0",
+    "sha512sig1",
+    "sm3p0",
+    "sm3p1",
+];
+
+fn x(reg: u32) -> &'static str {
+    ABI_NAMES[reg as usize & 0x1f]
+}
+
+fn f(reg: u32) -> &'static str {
+    FP_ABI_NAMES[reg as usize & 0x1f]
+}
+
+fn op(mnemonic: &str, operands: String) -> Option<String> {
+    match operands.is_empty() {
+        true => Some(mnemonic.to_string()),
+        false => Some(format!("{:<7} {}", mnemonic, operands)),
+    }
+}
+
+fn csr_name(csr: u32) -> String {
+    CSR_NAMES
+        .iter()
+        .find(|(_, addr)| *addr == csr as usize)
+        .map_or_else(|| format!("{:#x}", csr), |(name, _)| name.to_string())
+}
+
+fn decode(inst: u32, pc: u64) -> Option<String> {
+    let opcode = inst & 0x7f;
+    let rd = (inst >> 7) & 0x1f;
+    let rs1 = (inst >> 15) & 0x1f;
+    let rs2 = (inst >> 20) & 0x1f;
+    let rs3 = inst >> 27;
+    let funct3 = (inst >> 12) & 0x7;
+    let funct7 = inst >> 25;
+    let i_imm = (inst as i32) >> 20;
+    let s_imm = ((inst as i32) >> 25 << 5) | rd as i32;
+    let b_imm = ((inst as i32) >> 31 << 12)
+        | (((inst >> 7) & 1) << 11) as i32
+        | (((inst >> 25) & 0x3f) << 5) as i32
+        | (((inst >> 8) & 0xf) << 1) as i32;
+    let j_imm = ((inst as i32) >> 31 << 20)
+        | (inst & 0xff000) as i32
+        | (((inst >> 20) & 1) << 11) as i32
+        | (((inst >> 21) & 0x3ff) << 1) as i32;
+    let target = |imm: i32| pc.wrapping_add(imm as i64 as u64);
+
+    match opcode {
+        0x03 => {
+            let mnemonic = ["lb", "lh", "lw", "ld", "lbu", "lhu", "lwu"].get(funct3 as usize)?;
+            op(mnemonic, format!("{}, {}({})", x(rd), i_imm, x(rs1)))
+        }
+        0x07 => {
+            let mnemonic = match funct3 {
+                2 => "flw",
+                3 => "fld",
+                0 | 5..=7 => return decode_vector_memory(inst, false),
+                _ => return None,
+            };
+            op(mnemonic, format!("{}, {}({})", f(rd), i_imm, x(rs1)))
+        }
+        0x0f => match funct3 {
+            0 if inst == 0x0100000f => op("pause", String::new()),
+            0 => op("fence", String::new()),
+            1 => op("fence.i", String::new()),
+            2 if rd == 0 => {
+                let mnemonic = ["cbo.inval", "cbo.clean", "cbo.flush", "", "cbo.zero"].get(i_imm as usize)?;
+                op(mnemonic, format!("({})", x(rs1)))
+            }
+            _ => None,
+        },
+        0x13 => {
+            let shamt = (inst >> 20) & 0x3f;
+            let shift = |mnemonic| op(mnemonic, format!("{}, {}, {}", x(rd), x(rs1), shamt));
+            let unary = |mnemonic| op(mnemonic, format!("{}, {}", x(rd), x(rs1)));
+            match funct3 {
+                0 if inst == 0x13 => op("nop", String::new()),
+                0 if rs1 == 0 => op("li", format!("{}, {}", x(rd), i_imm)),
+                0 if i_imm == 0 => op("mv", format!("{}, {}", x(rd), x(rs1))),
+                1 if funct7 >> 1 == 0 => shift("slli"),
+                5 if funct7 >> 1 == 0 => shift("srli"),
+                5 if funct7 >> 1 == 0x10 => shift("srai"),
+                1 if funct7 == 0x30 => unary(["clz", "ctz", "cpop", "", "sext.b", "sext.h"].get(rs2 as usize).filter(|m| !m.is_empty())?),
+                1 if funct7 >> 1 == 0x12 => shift("bclri"),
+                1 if funct7 >> 1 == 0x1a => shift("binvi"),
+                1 if funct7 >> 1 == 0x0a => shift("bseti"),
+                5 if funct7 >> 1 == 0x12 => shift("bexti"),
+                5 if funct7 >> 1 == 0x18 => shift("rori"),
+                5 if i_imm == 0x287 => unary("orc.b"),
+                5 if i_imm == 0x6b8 || i_imm == 0x698 => unary("rev8"),
+                1 if (0x100..=0x109).contains(&i_imm) => unary(CRYPTO_UNARY[i_imm as usize - 0x100]),
+                1 if i_imm == 0x300 => unary("aes64im"),
+                1 if i_imm >> 4 == 0x31 => op("aes64ks1i", format!("{}, {}, {}", x(rd), x(rs1), i_imm & 0xf)),
+                1 | 5 => None,
+                6 if rd == 0 && matches!(rs2, 0 | 1 | 3) => {
+                    let mnemonic = ["prefetch.i", "prefetch.r", "", "prefetch.w"][rs2 as usize];
+                    op(mnemonic, format!("{}({})", i_imm & !0x1f, x(rs1)))
+                }
+                _ => {
+                    let mnemonic = ["addi", "", "slti", "sltiu", "xori", "", "ori", "andi"][funct3 as usize];
+                    op(mnemonic, format!("{}, {}, {}", x(rd), x(rs1), i_imm))
+                }
+            }
+        }
+        0x17 => op("auipc", format!("{}, {:#x}", x(rd), inst >> 12)),
+        0x1b => {
+            let shamt = (inst >> 20) & 0x1f;
+            match (funct3, funct7) {
+                (0, _) if i_imm == 0 => op("sext.w", format!("{}, {}", x(rd), x(rs1))),
+                (0, _) => op("addiw", format!("{}, {}, {}", x(rd), x(rs1), i_imm)),
+                (1, 0x00) => op("slliw", format!("{}, {}, {}", x(rd), x(rs1), shamt)),
+                (5, 0x00) => op("srliw", format!("{}, {}, {}", x(rd), x(rs1), shamt)),
+                (5, 0x20) => op("sraiw", format!("{}, {}, {}", x(rd), x(rs1), shamt)),
+                (1, 0x04 | 0x05) => op("slli.uw", format!("{}, {}, {}", x(rd), x(rs1), (inst >> 20) & 0x3f)),
+                (1, 0x30) => op(["clzw", "ctzw", "cpopw"].get(rs2 as usize)?, format!("{}, {}", x(rd), x(rs1))),
+                (5, 0x30) => op("roriw", format!("{}, {}, {}", x(rd), x(rs1), shamt)),
+                _ => None,
+            }
+        }
+        0x23 => {
+            let mnemonic = ["sb", "sh", "sw", "sd"].get(funct3 as usize)?;
+            op(mnemonic, format!("{}, {}({})", x(rs2), s_imm, x(rs1)))
+        }
+        0x27 => {
+            let mnemonic = match funct3 {
+                2 => "fsw",
+                3 => "fsd",
+                0 | 5..=7 => return decode_vector_memory(inst, true),
+                _ => return None,
+            };
+            op(mnemonic, format!("{}, {}({})", f(rs2), s_imm, x(rs1)))
+        }
+        0x2f => {
+            let width = match funct3 {
+                2 => "w",
+                3 => "d",
+                _ => return None,
+            };
+            let name = match funct7 >> 2 {
+                0x00 => "amoadd",
+                0x01 => "amoswap",
+                0x02 => "lr",
+                0x03 => "sc",
+                0x04 => "amoxor",
+                0x08 => "amoor",
+                0x0c => "amoand",
+                0x10 => "amomin",
+                0x14 => "amomax",
+                0x18 => "amominu",
+                0x1c => "amomaxu",
+                _ => return None,
+            };
+            let ordering = match funct7 & 3 {
+                0 => "",
+                1 => ".rl",
+                2 => ".aq",
+                _ => ".aqrl",
+            };
+            let mnemonic = format!("{}.{}{}", name, width, ordering);
+            if name == "lr" {
+                op(&mnemonic, format!("{}, ({})", x(rd), x(rs1)))
+            } else {
+                op(&mnemonic, format!("{}, {}, ({})", x(rd), x(rs2), x(rs1)))
+            }
+        }
+        0x33 => {
+            let mnemonic = match (funct7, funct3) {
+                (0x00, _) => ["add", "sll", "slt", "sltu", "xor", "srl", "or", "and"][funct3 as usize],
+                (0x20, 0) => "sub",
+                (0x20, 5) => "sra",
+                (0x01, _) => ["mul", "mulh", "mulhsu", "mulhu", "div", "divu", "rem", "remu"][funct3 as usize],
+                (0x10, 2) => "sh1add",
+                (0x10, 4) => "sh2add",
+                (0x10, 6) => "sh3add",
+                (0x20, 4) => "xnor",
+                (0x20, 6) => "orn",
+                (0x20, 7) => "andn",
+                (0x05, 1) => "clmul",
+                (0x05, 2) => "clmulr",
+                (0x05, 3) => "clmulh",
+                (0x05, 4) => "min",
+                (0x05, 5) => "minu",
+                (0x05, 6) => "max",
+                (0x05, 7) => "maxu",
+                (0x07, 5) => "czero.eqz",
+                (0x07, 7) => "czero.nez",
+                (0x04, 4) if rs2 == 0 => return op("zext.h", format!("{}, {}", x(rd), x(rs1))),
+                (0x30, 1) => "rol",
+                (0x30, 5) => "ror",
+                (0x24, 1) => "bclr",
+                (0x24, 5) => "bext",
+                (0x34, 1) => "binv",
+                (0x14, 1) => "bset",
+                (0x19, 0) => "aes64es",
+                (0x1b, 0) => "aes64esm",
+                (0x1d, 0) => "aes64ds",
+                (0x1f, 0) => "aes64dsm",
+                (0x3f, 0) => "aes64ks2",
+                (0x28, 0) => "sha512sum0r",
+                (0x29, 0) => "sha512sum1r",
+                (0x2a, 0) => "sha512sig0l",
+                (0x2b, 0) => "sha512sig1l",
+                (0x2e, 0) => "sha512sig0h",
+                (0x2f, 0) => "sha512sig1h",
+                (_, 0) => {
+                    // The byte-select forms of RV32 AES and of SM4
+                    let mnemonic = match funct7 & 0x1f {
+                        0x11 => "aes32esi",
+                        0x13 => "aes32esmi",
+                        0x15 => "aes32dsi",
+                        0x17 => "aes32dsmi",
+                        0x18 => "sm4ed",
+                        0x1a => "sm4ks",
+                        _ => return None,
+                    };
+                    return op(mnemonic, format!("{}, {}, {}, {}", x(rd), x(rs1), x(rs2), funct7 >> 5));
+                }
+                _ => return None,
+            };
+            op(mnemonic, format!("{}, {}, {}", x(rd), x(rs1), x(rs2)))
+        }
+        0x37 => op("lui", format!("{}, {:#x}", x(rd), inst >> 12)),
+        0x3b => {
+            let mnemonic = match (funct7, funct3) {
+                (0x00, 0) => "addw",
+                (0x20, 0) => "subw",
+                (0x00, 1) => "sllw",
+                (0x00, 5) => "srlw",
+                (0x20, 5) => "sraw",
+                (0x01, 0) => "mulw",
+                (0x01, 4) => "divw",
+                (0x01, 5) => "divuw",
+                (0x01, 6) => "remw",
+                (0x01, 7) => "remuw",
+                (0x04, 0) if rs2 == 0 => return op("zext.w", format!("{}, {}", x(rd), x(rs1))),
+                (0x04, 0) => "add.uw",
+                (0x10, 2) => "sh1add.uw",
+                (0x10, 4) => "sh2add.uw",
+                (0x10, 6) => "sh3add.uw",
+                (0x04, 4) if rs2 == 0 => return op("zext.h", format!("{}, {}", x(rd), x(rs1))),
+                (0x30, 1) => "rolw",
+                (0x30, 5) => "rorw",
+                _ => return None,
+            };
+            op(mnemonic, format!("{}, {}, {}", x(rd), x(rs1), x(rs2)))
+        }
+        0x43 | 0x47 | 0x4b | 0x4f => {
+            let name = ["fmadd", "fmsub", "fnmsub", "fnmadd"][((opcode >> 2) & 3) as usize];
+            let precision = match funct7 & 3 {
+                0 => "s",
+                1 => "d",
+                _ => return None,
+            };
+            op(
+                &format!("{}.{}", name, precision),
+                format!("{}, {}, {}, {}", f(rd), f(rs1), f(rs2), f(rs3)),
+            )
+        }
+        0x53 => decode_fp(funct7, funct3, rd, rs1, rs2),
+        0x57 => decode_vector(inst),
+        0x63 => {
+            let mnemonic = match funct3 {
+                0 => "beq",
+                1 => "bne",
+                4 => "blt",
+                5 => "bge",
+                6 => "bltu",
+                7 => "bgeu",
+                _ => return None,
+            };
+            op(mnemonic, format!("{}, {}, {:#x}", x(rs1), x(rs2), target(b_imm)))
+        }
+        0x67 if funct3 == 0 => match (rd, i_imm) {
+            (0, 0) if rs1 == 1 => op("ret", String::new()),
+            (0, 0) => op("jr", x(rs1).to_string()),
+            _ => op("jalr", format!("{}, {}({})", x(rd), i_imm, x(rs1))),
+        },
+        0x6f => match rd {
+            0 => op("j", format!("{:#x}", target(j_imm))),
+            _ => op("jal", format!("{}, {:#x}", x(rd), target(j_imm))),
+        },
+        0x73 => decode_system(inst, funct3, rd, rs1, rs2),
+        _ => None,
+    }
+}
+
+/// How LLVM prints the `fli` constants.
+const FLI_NAMES: [&str; 32] = [
+    "-1.0", "min", "1.52587890625e-05", "3.0517578125e-05", "0.00390625", "0.0078125", "0.0625", "0.125", "0.25",
+    "0.3125", "0.375", "0.4375", "0.5", "0.625", "0.75", "0.875", "1.0", "1.25", "1.5", "1.75", "2.0", "2.5", "3.0",
+    "4.0", "8.0", "16.0", "128.0", "256.0", "32768.0", "65536.0", "inf", "nan",
+];
+
+fn decode_fp(funct7: u32, funct3: u32, rd: u32, rs1: u32, rs2: u32) -> Option<String> {
+    let precision = if funct7 & 1 == 0 { "s" } else { "d" };
+    let integer = || ["w", "wu", "l", "lu"].get(rs2 as usize).copied();
+    match funct7 >> 2 {
+        0x00..=0x03 => {
+            let name = ["fadd", "fsub", "fmul", "fdiv"][(funct7 >> 2) as usize];
+            op(&format!("{}.{}", name, precision), format!("{}, {}, {}", f(rd), f(rs1), f(rs2)))
+        }
+        0x0b if rs2 == 0 => op(&format!("fsqrt.{}", precision), format!("{}, {}", f(rd), f(rs1))),
+        0x04 => {
+            let name = match funct3 {
+                0 if rs1 == rs2 => return op(&format!("fmv.{}", precision), format!("{}, {}", f(rd), f(rs1))),
+                0 => "fsgnj",
+                1 => "fsgnjn",
+                2 => "fsgnjx",
+                _ => return None,
+            };
+            op(&format!("{}.{}", name, precision), format!("{}, {}, {}", f(rd), f(rs1), f(rs2)))
+        }
+        0x05 => {
+            let name = ["fmin", "fmax", "fminm", "fmaxm"].get(funct3 as usize)?;
+            op(&format!("{}.{}", name, precision), format!("{}, {}, {}", f(rd), f(rs1), f(rs2)))
+        }
+        0x08 => match (funct7, rs2) {
+            (0x20, 1) => op("fcvt.s.d", format!("{}, {}", f(rd), f(rs1))),
+            (0x21, 0) => op("fcvt.d.s", format!("{}, {}", f(rd), f(rs1))),
+            (_, 4 | 5) => {
+                let name = if rs2 == 4 { "fround" } else { "froundnx" };
+                op(&format!("{}.{}", name, precision), format!("{}, {}", f(rd), f(rs1)))
+            }
+            _ => None,
+        },
+        0x14 => {
+            let name = ["fle", "flt", "feq", "", "fleq", "fltq"].get(funct3 as usize).filter(|n| !n.is_empty())?;
+            op(&format!("{}.{}", name, precision), format!("{}, {}, {}", x(rd), f(rs1), f(rs2)))
+        }
+        0x18 if funct7 == 0x61 && rs2 == 8 && funct3 == 1 => op("fcvtmod.w.d", format!("{}, {}, rtz", x(rd), f(rs1))),
+        0x18 => op(&format!("fcvt.{}.{}", integer()?, precision), format!("{}, {}", x(rd), f(rs1))),
+        0x1a => op(&format!("fcvt.{}.{}", precision, integer()?), format!("{}, {}", f(rd), x(rs1))),
+        0x1c if rs2 == 0 => match funct3 {
+            0 => op(if precision == "s" { "fmv.x.w" } else { "fmv.x.d" }, format!("{}, {}", x(rd), f(rs1))),
+            1 => op(&format!("fclass.{}", precision), format!("{}, {}", x(rd), f(rs1))),
+            _ => None,
+        },
+        0x1c if funct7 == 0x71 && rs2 == 1 && funct3 == 0 => op("fmvh.x.d", format!("{}, {}", x(rd), f(rs1))),
+        0x16 if funct7 == 0x59 && funct3 == 0 => op("fmvp.d.x", format!("{}, {}, {}", f(rd), x(rs1), x(rs2))),
+        0x1e if rs2 == 1 && funct3 == 0 => op(&format!("fli.{}", precision), format!("{}, {}", f(rd), FLI_NAMES[rs1 as usize])),
+        0x1e if rs2 == 0 && funct3 == 0 => {
+            op(if precision == "s" { "fmv.w.x" } else { "fmv.d.x" }, format!("{}, {}", f(rd), x(rs1)))
+        }
+        _ => None,
+    }
+}
+
+fn decode_system(inst: u32, funct3: u32, rd: u32, rs1: u32, rs2: u32) -> Option<String> {
+    let csr = csr_name(inst >> 20);
+    match funct3 {
+        0 if rd == 0 => match (inst >> 25, rs2, rs1) {
+            (0x00, 0, 0) => op("ecall", String::new()),
+            (0x00, 1, 0) => op("ebreak", String::new()),
+            (0x08, 2, 0) => op("sret", String::new()),
+            (0x18, 2, 0) => op("mret", String::new()),
+            (0x08, 5, 0) => op("wfi", String::new()),
+            (0x09, _, _) => op("sfence.vma", format!("{}, {}", x(rs1), x(rs2))),
+            (0x11, _, _) => op("hfence.vvma", format!("{}, {}", x(rs1), x(rs2))),
+            (0x31, _, _) => op("hfence.gvma", format!("{}, {}", x(rs1), x(rs2))),
+            _ => None,
+        },
+        4 => {
+            let funct7 = inst >> 25;
+            let size = ["b", "h", "w", "d"][((funct7 >> 1) & 3) as usize];
+            match (funct7, rs2) {
+                (0x31 | 0x33 | 0x35 | 0x37, _) if rd == 0 => op(&format!("hsv.{}", size), format!("{}, ({})", x(rs2), x(rs1))),
+                (0x30 | 0x32 | 0x34 | 0x36, 0) => op(&format!("hlv.{}", size), format!("{}, ({})", x(rd), x(rs1))),
+                (0x30 | 0x32 | 0x34, 1) => op(&format!("hlv.{}u", size), format!("{}, ({})", x(rd), x(rs1))),
+                (0x32 | 0x34, 3) => op(&format!("hlvx.{}u", size), format!("{}, ({})", x(rd), x(rs1))),
+                _ => None,
+            }
+        }
+        1..=3 => {
+            let name = ["", "csrrw", "csrrs", "csrrc"][funct3 as usize];
+            match (funct3, rd, rs1) {
+                (2, _, 0) => op("csrr", format!("{}, {}", x(rd), csr)),
+                (1, 0, _) => op("csrw", format!("{}, {}", csr, x(rs1))),
+                (2, 0, _) => op("csrs", format!("{}, {}", csr, x(rs1))),
+                (3, 0, _) => op("csrc", format!("{}, {}", csr, x(rs1))),
+                _ => op(name, format!("{}, {}, {}", x(rd), csr, x(rs1))),
+            }
+        }
+        5..=7 => {
+            let name = ["csrrwi", "csrrsi", "csrrci"][funct3 as usize - 5];
+            op(name, format!("{}, {}, {}", x(rd), csr, rs1))
+        }
+        _ => None,
+    }
+}
+
+/// OPIVV, OPIVX and OPIVI mnemonics by funct6.
+const OPI_NAMES: [&str; 50] = [
+    "vadd", "", "vsub", "vrsub", "vminu", "vmin", "vmaxu", "vmax", "", "vand", "vor", "vxor", "vrgather", "",
+    "vslideup", "vslidedown", "vadc", "vmadc", "vsbc", "vmsbc", "", "", "", "vmerge", "vmseq", "vmsne", "vmsltu",
+    "vmslt", "vmsleu", "vmsle", "vmsgtu", "vmsgt", "vsaddu", "vsadd", "vssubu", "vssub", "", "vsll", "", "vsmul",
+    "vsrl", "vsra", "vssrl", "vssra", "vnsrl", "vnsra", "vnclipu", "vnclip", "vwredsumu", "vwredsum",
+];
+
+/// OPMVV and OPMVX mnemonics by funct6.
+const OPM_NAMES: [&str; 64] = [
+    "vredsum", "vredand", "vredor", "vredxor", "vredminu", "vredmin", "vredmaxu", "vredmax", "vaaddu", "vaadd",
+    "vasubu", "vasub", "", "", "vslide1up", "vslide1down", "", "", "", "", "", "", "", "vcompress", "vmandn", "vmand",
+    "vmor", "vmxor", "vmorn", "vmnand", "vmnor", "vmxnor", "vdivu", "vdiv", "vremu", "vrem", "vmulhu", "vmul",
+    "vmulhsu", "vmulh", "", "vmadd", "", "vnmsub", "", "vmacc", "", "vnmsac", "vwaddu", "vwadd", "vwsubu", "vwsub",
+    "vwaddu.w", "vwadd.w", "vwsubu.w", "vwsub.w", "vwmulu", "", "vwmulsu", "vwmul", "vwmaccu", "vwmacc", "vwmaccus",
+    "vwmaccsu",
+];
+
+/// OPFVV and OPFVF mnemonics by funct6.
+const OPF_NAMES: [&str; 64] = [
+    "vfadd", "vfredusum", "vfsub", "vfredosum", "vfmin", "vfredmin", "vfmax", "vfredmax", "vfsgnj", "vfsgnjn",
+    "vfsgnjx", "", "", "", "vfslide1up", "vfslide1down", "", "", "", "", "", "", "", "vfmerge", "vmfeq", "vmfle", "",
+    "vmflt", "vmfne", "vmfgt", "", "vmfge", "vfdiv", "vfrdiv", "", "", "vfmul", "", "", "vfrsub", "vfmadd", "vfnmadd",
+    "vfmsub", "vfnmsub", "vfmacc", "vfnmacc", "vfmsac", "vfnmsac", "vfwadd", "vfwredusum", "vfwsub", "vfwredosum",
+    "vfwadd.w", "", "vfwsub.w", "", "vfwmul", "", "", "", "vfwmacc", "vfwnmacc", "vfwmsac", "vfwnmsac",
+];
+
+/// A `vtype` value as the assembler writes it, such as `e32, m1, ta, ma`.
+fn vtype_name(vtype: u32) -> String {
+    let vsew = (vtype >> 3) & 0x7;
+    let lmul = ["m1", "m2", "m4", "m8", "", "mf8", "mf4", "mf2"][(vtype & 0x7) as usize];
+    if vtype >> 8 != 0 || vsew > 3 || lmul.is_empty() {
+        return format!("{:#x}", vtype);
+    }
+    let ta = if vtype & (1 << 6) != 0 { "ta" } else { "tu" };
+    let ma = if vtype & (1 << 7) != 0 { "ma" } else { "mu" };
+    format!("e{}, {}, {}, {}", 8 << vsew, lmul, ta, ma)
+}
+
+fn decode_vector_memory(inst: u32, store: bool) -> Option<String> {
+    let vd = (inst >> 7) & 0x1f;
+    let rs1 = (inst >> 15) & 0x1f;
+    let rs2 = (inst >> 20) & 0x1f;
+    let vm = (inst >> 25) & 1 == 1;
+    let nf = (inst >> 29) + 1;
+    let eew = match (inst >> 12) & 0x7 {
+        0 => 8,
+        5 => 16,
+        6 => 32,
+        _ => 64,
+    };
+    if (inst >> 28) & 1 != 0 {
+        return None;
+    }
+    let prefix = if store { "vs" } else { "vl" };
+    let segment = |kind: &str| match nf {
+        1 => format!("{}{}e", prefix, kind),
+        _ => format!("{}{}seg{}e", prefix, kind, nf),
+    };
+    let mask = if vm { "" } else { ", v0.t" };
+    let (name, operands) = match (inst >> 26) & 0x3 {
+        0 => match rs2 {
+            0x00 => (format!("{}{}.v", segment(""), eew), String::new()),
+            0x08 if store => (format!("vs{}r.v", nf), String::new()),
+            0x08 => (format!("vl{}re{}.v", nf, eew), String::new()),
+            0x0b => (format!("{}m.v", prefix), String::new()),
+            0x10 if !store => (format!("{}{}ff.v", segment(""), eew), String::new()),
+            _ => return None,
+        },
+        2 => (format!("{}{}.v", segment("s"), eew), format!(", {}", x(rs2))),
+        mop => {
+            let kind = if mop == 1 { "ux" } else { "ox" };
+            (format!("{}i{}.v", segment(kind), eew), format!(", v{}", rs2))
+        }
+    };
+    op(&name, format!("v{}, ({}){}{}", vd, x(rs1), operands, mask))
+}
+
+fn decode_vector(inst: u32) -> Option<String> {
+    let funct3 = (inst >> 12) & 0x7;
+    let funct6 = inst >> 26;
+    let vd = (inst >> 7) & 0x1f;
+    let rs1 = (inst >> 15) & 0x1f;
+    let rs2 = (inst >> 20) & 0x1f;
+    let vm = (inst >> 25) & 1 == 1;
+    let mask = if vm { "" } else { ", v0.t" };
+
+    // The forms other than the usual vd, vs2, operand
+    match (funct3, funct6) {
+        (7, _) if inst >> 31 == 0 => {
+            return op("vsetvli", format!("{}, {}, {}", x(vd), x(rs1), vtype_name((inst >> 20) & 0x7ff)))
+        }
+        (7, _) if inst >> 30 == 0b11 => {
+            return op("vsetivli", format!("{}, {}, {}", x(vd), rs1, vtype_name((inst >> 20) & 0x3ff)))
+        }
+        (7, _) if inst >> 25 == 0x40 => return op("vsetvl", format!("{}, {}, {}", x(vd), x(rs1), x(rs2))),
+        (7, _) => return None,
+        (3, 0x27) => return op(&format!("vmv{}r.v", rs1 + 1), format!("v{}, v{}", vd, rs2)),
+        (0 | 3 | 4, 0x17) | (5, 0x17) if vm => {
+            let (name, operand) = match funct3 {
+                0 => ("vmv.v.v", format!("v{}", rs1)),
+                3 => ("vmv.v.i", format!("{}", (rs1 as i32) << 27 >> 27)),
+                4 => ("vmv.v.x", x(rs1).to_string()),
+                _ => ("vfmv.v.f", f(rs1).to_string()),
+            };
+            return op(name, format!("v{}, {}", vd, operand));
+        }
+        (2, 0x10) => {
+            let name = match rs1 {
+                0x00 if vm => return op("vmv.x.s", format!("{}, v{}", x(vd), rs2)),
+                0x10 => "vcpop.m",
+                0x11 => "vfirst.m",
+                _ => return None,
+            };
+            return op(name, format!("{}, v{}{}", x(vd), rs2, mask));
+        }
+        (6, 0x10) => return op("vmv.s.x", format!("v{}, {}", vd, x(rs1))),
+        (1, 0x10) => return op("vfmv.f.s", format!("{}, v{}", f(vd), rs2)),
+        (5, 0x10) => return op("vfmv.s.f", format!("v{}, {}", vd, f(rs1))),
+        (2, 0x12) => {
+            let name = ["", "", "vzext.vf8", "vsext.vf8", "vzext.vf4", "vsext.vf4", "vzext.vf2", "vsext.vf2"]
+                .get(rs1 as usize)
+                .filter(|name| !name.is_empty())?;
+            return op(name, format!("v{}, v{}{}", vd, rs2, mask));
+        }
+        (2, 0x14) => {
+            let name = match rs1 {
+                0x01 => "vmsbf.m",
+                0x02 => "vmsof.m",
+                0x03 => "vmsif.m",
+                0x10 => "viota.m",
+                0x11 => return op("vid.v", format!("v{}{}", vd, mask)),
+                _ => return None,
+            };
+            return op(name, format!("v{}, v{}{}", vd, rs2, mask));
+        }
+        (1, 0x12) => {
+            let kind = ["xu.f", "x.f", "f.xu", "f.x", "f.f", "rod.f.f", "rtz.xu.f", "rtz.x.f"][(rs1 & 0x7) as usize];
+            let name = match rs1 >> 3 {
+                0 if !matches!(rs1, 4 | 5) => format!("vfcvt.{}.v", kind),
+                1 if rs1 != 0xd => format!("vfwcvt.{}.v", kind),
+                2 => format!("vfncvt.{}.w", kind),
+                _ => return None,
+            };
+            return op(&name, format!("v{}, v{}{}", vd, rs2, mask));
+        }
+        (1, 0x13) => {
+            let name = match rs1 {
+                0x00 => "vfsqrt.v",
+                0x04 => "vfrsqrt7.v",
+                0x05 => "vfrec7.v",
+                0x10 => "vfclass.v",
+                _ => return None,
+            };
+            return op(name, format!("v{}, v{}{}", vd, rs2, mask));
+        }
+        _ => {}
+    }
+
+    let name = match funct3 {
+        0 if funct6 == 0x0e => &"vrgatherei16",
+        0 | 3 | 4 => OPI_NAMES.get(funct6 as usize)?,
+        2 | 6 => &OPM_NAMES[funct6 as usize],
+        _ => &OPF_NAMES[funct6 as usize],
+    };
+    if name.is_empty() {
+        return None;
+    }
+    let operand = match funct3 {
+        0..=2 => format!("v{}", rs1),
+        3 if matches!(funct6, 0x0c | 0x0e | 0x0f | 0x25 | 0x28..=0x2f) => format!("{}", rs1),
+        3 => format!("{}", (rs1 as i32) << 27 >> 27),
+        5 => f(rs1).to_string(),
+        _ => x(rs1).to_string(),
+    };
+    let form = ["vv", "vv", "vv", "vi", "vx", "vf", "vx"][funct3 as usize];
+    let reduction = name.contains("red");
+    let narrowing = matches!(funct3, 0 | 3 | 4) && (0x2c..=0x2f).contains(&funct6);
+    let mask_logical = funct3 == 2 && (0x18..=0x1f).contains(&funct6);
+    let suffix = match name.strip_suffix(".w") {
+        Some(_) => form.replacen('v', "w", 1),
+        None if reduction => "vs".to_string(),
+        None if narrowing => form.replacen('v', "w", 1),
+        None if mask_logical => "mm".to_string(),
+        None if *name == "vcompress" => "vm".to_string(),
+        None if !vm && matches!(funct6, 0x10..=0x13 | 0x17) && funct3 != 2 => format!("{}m", form),
+        None => form.to_string(),
+    };
+    let name = format!("{}.{}", name.trim_end_matches(".w"), suffix);
+    let mask = if matches!(funct6, 0x10..=0x13 | 0x17) && funct3 != 2 {
+        if vm { "" } else { ", v0" }
+    } else {
+        mask
+    };
+    // The multiply-adds take the multiplier first.
+    let multiply_add = (matches!(funct3, 2 | 6) && matches!(funct6, 0x29 | 0x2b | 0x2d | 0x2f | 0x3c..=0x3f))
+        || (matches!(funct3, 1 | 5) && matches!(funct6, 0x28..=0x2f | 0x3c..=0x3f));
+    let operands = match multiply_add {
+        true => format!("v{}, {}, v{}{}", vd, operand, rs2, mask),
+        false => format!("v{}, v{}, {}{}", vd, rs2, operand, mask),
+    };
+    op(&name, operands)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encodings and their disassembly at 0x1000. The text is LLVM's, with
+    /// our spacing and addresses in hex.
+    const CASES: [(u32, &str); 59] = [
+        (0xffc12503, "lw      a0, -4(sp)"),
+        (0xff813407, "fld     fs0, -8(sp)"),
+        (0x12345537, "lui     a0, 0x12345"),
+        (0x00001297, "auipc   t0, 0x1"),
+        (0xf9c58513, "addi    a0, a1, -100"),
+        (0x02a00513, "li      a0, 42"),
+        (0x00058513, "mv      a0, a1"),
+        (0x00000013, "nop"),
+        (0x03f59513, "slli    a0, a1, 63"),
+        (0x40335293, "srai    t0, t1, 3"),
+        (0x0005851b, "sext.w  a0, a1"),
+        (0xfe113823, "sd      ra, -16(sp)"),
+        (0x06b6252f, "amoadd.w.aqrl a0, a1, (a2)"),
+        (0x100332af, "lr.d    t0, (t1)"),
+        (0x1ab6252f, "sc.w.rl a0, a1, (a2)"),
+        (0x407302b3, "sub     t0, t1, t2"),
+        (0x02c5d533, "divu    a0, a1, a2"),
+        (0x20c5c533, "sh2add  a0, a1, a2"),
+        (0x60559513, "sext.h  a0, a1"),
+        (0x6b85d513, "rev8    a0, a1"),
+        (0x2a859513, "bseti   a0, a1, 40"),
+        (0x10259513, "sha256sig0 a0, a1"),
+        (0x31a59513, "aes64ks1i a0, a1, 10"),
+        (0x0805853b, "zext.w  a0, a1"),
+        (0x0835951b, "slli.uw a0, a1, 3"),
+        (0x6ac5f543, "fmadd.d fa0, fa1, fa2, fa3"),
+        (0x22b58553, "fmv.d   fa0, fa1"),
+        (0xd2357553, "fcvt.d.lu fa0, a0"),
+        (0xe2050553, "fmv.x.d a0, fa0"),
+        (0x008500e7, "jalr    ra, 8(a0)"),
+        (0x00008067, "ret"),
+        (0x00050067, "jr      a0"),
+        (0x30200073, "mret"),
+        (0x12b50073, "sfence.vma a0, a1"),
+        (0x30002573, "csrr    a0, mstatus"),
+        (0x18051073, "csrw    satp, a0"),
+        (0x34059573, "csrrw   a0, mscratch, a1"),
+        (0x30446573, "csrrsi  a0, mie, 8"),
+        (0x7c002573, "csrr    a0, 0x7c0"),
+        (0x0515f557, "vsetvli a0, a1, e32, m2, ta, mu"),
+        (0xc8727557, "vsetivli a0, 4, e8, mf2, tu, ma"),
+        (0x00058127, "vse8.v  v2, (a1), v0.t"),
+        (0x0ab57187, "vlse64.v v3, (a0), a1"),
+        (0x06855207, "vluxei16.v v4, (a0), v8"),
+        (0x42055207, "vlseg3e16.v v4, (a0)"),
+        (0x22856107, "vl2re32.v v2, (a0)"),
+        (0x022eb0d7, "vadd.vi v1, v2, -3"),
+        (0x082540d7, "vsub.vx v1, v2, a0, v0.t"),
+        (0x5e02b0d7, "vmv.v.i v1, 5"),
+        (0x0221a0d7, "vredsum.vs v1, v2, v3"),
+        (0x6020b057, "vmseq.vi v0, v2, 1, v0.t"),
+        (0xb22540d7, "vnsrl.wx v1, v2, a0"),
+        (0xd6432157, "vwadd.wv v2, v4, v6"),
+        (0x6621a0d7, "vmand.mm v1, v2, v3"),
+        (0x9e303157, "vmv1r.v v2, v3"),
+        (0x4a2090d7, "vfcvt.x.f.v v1, v2"),
+        (0x402180d7, "vadc.vvm v1, v2, v3, v0"),
+        (0x3e2fb0d7, "vslidedown.vi v1, v2, 31"),
+        (0x5e21a0d7, "vcompress.vm v1, v2, v3"),
+    ];
+
+    /// Extensions LLVM 14 cannot assemble, encoded from the specifications.
+    const NEWER: [(u32, &str); 14] = [
+        (0x0ec5d533, "czero.eqz a0, a1, a2"),
+        (0x0ec5f533, "czero.nez a0, a1, a2"),
+        (0xf2168553, "fli.d   fa0, 0.625"),
+        (0x28c5a553, "fminm.s fa0, fa1, fa2"),
+        (0xa2b54553, "fleq.d  a0, fa0, fa1"),
+        (0x4245f553, "fround.d fa0, fa1"),
+        (0xc2851553, "fcvtmod.w.d a0, fa0, rtz"),
+        (0x62000073, "hfence.gvma zero, zero"),
+        (0x6805c573, "hlv.w   a0, (a1)"),
+        (0x6eb54073, "hsv.d   a1, (a0)"),
+        (0x6835c573, "hlvx.wu a0, (a1)"),
+        (0x0045200f, "cbo.zero (a0)"),
+        (0x04356013, "prefetch.w 64(a0)"),
+        (0x0100000f, "pause"),
+    ];
+
+    #[test]
+    fn matches_the_assembler() {
+        for (inst, text) in CASES.into_iter().chain(NEWER) {
+            assert_eq!(disassemble(inst, 0x1000), text, "{:#010x}", inst);
+        }
+    }
+
+    #[test]
+    fn shows_targets_as_addresses() {
+        assert_eq!(disassemble(0x00b50863, 0x1000), "beq     a0, a1, 0x1010");
+        assert_eq!(disassemble(0xfe029ce3, 0x1000), "bne     t0, zero, 0xff8");
+        assert_eq!(disassemble(0x001000ef, 0x1000), "jal     ra, 0x1800");
+        assert_eq!(disassemble(0xffdff06f, 0), "j       0xfffffffffffffffc");
+    }
+
+    #[test]
+    fn unknown_encodings_are_words() {
+        for inst in [0x0000_0000, 0xffff_ffff, 0x0000_7003, 0x2800_302f, 0x8200_7057] {
+            assert_eq!(disassemble(inst, 0), format!(".word {:#010x}", inst));
+        }
+    }
+}
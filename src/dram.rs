@@ -1,64 +1,88 @@
-use crate::bus::*;
+use crate::exception::*;
 
 pub const DRAM_SIZE: u64 = 1024 * 1024 * 128;
 
 pub struct Dram {
+    pub base: u64,
     pub dram: Vec<u8>,
 }
 
+#[allow(clippy::needless_return, clippy::unit_arg)]
 impl Dram {
-    pub fn new(code: Vec<u8>) -> Self {
-        let mut dram = vec![0; DRAM_SIZE as usize];
+    pub fn new(base: u64, size: u64, code: Vec<u8>) -> Self {
+        let mut dram = vec![0; size as usize];
         dram.splice(..code.len(), code.iter().cloned());
 
         Self {
+            base,
             dram,
         }
     }
 
-    pub fn load(&self, addr: u64, size: u64) -> Result<u64, ()> {
+    pub fn size(&self) -> u64 {
+        self.dram.len() as u64
+    }
+
+    pub fn contains(&self, addr: u64) -> bool {
+        self.base <= addr && addr - self.base < self.size()
+    }
+
+    /// The `len` bytes at `addr`, if they are all in memory.
+    pub fn slice(&self, addr: u64, len: u64) -> Option<&[u8]> {
+        let start = addr.checked_sub(self.base)?;
+        let end = start.checked_add(len).filter(|&end| end <= self.size())?;
+        Some(&self.dram[start as usize..end as usize])
+    }
+
+    pub fn slice_mut(&mut self, addr: u64, len: u64) -> Option<&mut [u8]> {
+        let start = addr.checked_sub(self.base)?;
+        let end = start.checked_add(len).filter(|&end| end <= self.size())?;
+        Some(&mut self.dram[start as usize..end as usize])
+    }
+
+    pub fn load(&self, addr: u64, size: u64) -> Result<u64, Exception> {
         match size {
             8 => Ok(self.load8(addr)),
             16 => Ok(self.load16(addr)),
             32 => Ok(self.load32(addr)),
             64 => Ok(self.load64(addr)),
-            _ => Err(()),
+            _ => Err(Exception::LoadAccessFault(addr)),
         }
     }
 
-    pub fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), ()> {
+    pub fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
         match size {
             8 => Ok(self.store8(addr, value)),
             16 => Ok(self.store16(addr, value)),
             32 => Ok(self.store32(addr, value)),
             64 => Ok(self.store64(addr, value)),
-            _ => Err(()),
+            _ => Err(Exception::StoreAMOAccessFault(addr)),
         }
     }
 
     fn load8(&self, addr: u64) -> u64 {
-        let index = (addr - DRAM_BASE) as usize;
+        let index = (addr - self.base) as usize;
         return self.dram[index] as u64;
     }
 
     fn store8(&mut self, addr: u64, value: u64) {
-        let index = (addr - DRAM_BASE) as usize;
+        let index = (addr - self.base) as usize;
         self.dram[index] = (value & 0xff) as u8;
     }
 
     fn load16(&self, addr: u64) -> u64 {
-        let index = (addr - DRAM_BASE) as usize;
+        let index = (addr - self.base) as usize;
         return (self.dram[index] as u64) | ((self.dram[index + 1] as u64) << 8);
     }
 
     fn store16(&mut self, addr: u64, value: u64) {
-        let index = (addr - DRAM_BASE) as usize;
+        let index = (addr - self.base) as usize;
         self.dram[index] = (value & 0xff) as u8;
         self.dram[index + 1] = ((value >> 8) & 0xff) as u8;
     }
 
     fn load32(&self, addr: u64) -> u64 {
-        let index = (addr - DRAM_BASE) as usize;
+        let index = (addr - self.base) as usize;
         return (self.dram[index] as u64)
             | ((self.dram[index + 1] as u64) << 8)
             | ((self.dram[index + 2] as u64) << 16)
@@ -66,7 +90,7 @@ impl Dram {
     }
 
     fn store32(&mut self, addr: u64, value: u64) {
-        let index = (addr - DRAM_BASE) as usize;
+        let index = (addr - self.base) as usize;
         self.dram[index] = (value & 0xff) as u8;
         self.dram[index + 1] = ((value >> 8) & 0xff) as u8;
         self.dram[index + 2] = ((value >> 16) & 0xff) as u8;
@@ -74,7 +98,7 @@ impl Dram {
     }
 
     fn load64(&self, addr: u64) -> u64 {
-        let index = (addr - DRAM_BASE) as usize;
+        let index = (addr - self.base) as usize;
         return (self.dram[index] as u64)
             | ((self.dram[index + 1] as u64) << 8)
             | ((self.dram[index + 2] as u64) << 16)
@@ -86,7 +110,7 @@ impl Dram {
     }
 
     fn store64(&mut self, addr: u64, value: u64) {
-        let index = (addr - DRAM_BASE) as usize;
+        let index = (addr - self.base) as usize;
         self.dram[index] = (value & 0xff) as u8;
         self.dram[index + 1] = ((value >> 8) & 0xff) as u8;
         self.dram[index + 2] = ((value >> 16) & 0xff) as u8;
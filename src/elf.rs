@@ -0,0 +1,263 @@
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+const ELFCLASS32: u8 = 1;
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EM_RISCV: u16 = 243;
+
+pub const PT_LOAD: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+
+/// A loadable program segment.
+#[derive(Clone, Debug)]
+pub struct Segment {
+    pub vaddr: u64,
+    pub paddr: u64,
+    pub offset: u64,
+    pub filesz: u64,
+    pub memsz: u64,
+}
+
+/// The parts of a little-endian RISC-V ELF file needed to load and start it.
+#[derive(Clone, Debug)]
+pub struct Elf {
+    /// 32 for ELF32 (RV32) files, 64 for ELF64 ones.
+    pub xlen: u32,
+    pub entry: u64,
+    pub phoff: u64,
+    pub phentsize: u16,
+    pub phnum: u16,
+    pub segments: Vec<Segment>,
+    /// Named entries of the symbol table, if the file has one.
+    pub symbols: Vec<(String, u64)>,
+}
+
+pub fn is_elf(data: &[u8]) -> bool {
+    data.starts_with(ELF_MAGIC)
+}
+
+impl Elf {
+    pub fn parse(data: &[u8]) -> Result<Self, String> {
+        if data.len() < 52 || !is_elf(data) {
+            return Err(String::from("not an ELF file"));
+        }
+        let xlen = match data[4] {
+            ELFCLASS32 => 32,
+            ELFCLASS64 => 64,
+            _ => return Err(String::from("unknown ELF class")),
+        };
+        let word = |offset| read_word(data, offset, xlen);
+        if data[5] != ELFDATA2LSB {
+            return Err(String::from("only little-endian ELF files are supported"));
+        }
+        if read16(data, 18)? != EM_RISCV {
+            return Err(String::from("not a RISC-V ELF file"));
+        }
+
+        // Header fields are words of the class's size, which moves the ones
+        // after them.
+        let (phoff, phentsize, phnum) = if xlen == 32 { (28, 42, 44) } else { (32, 54, 56) };
+        let entry = word(24)?;
+        let phoff = word(phoff)?;
+        let phentsize = read16(data, phentsize)?;
+        let phnum = read16(data, phnum)?;
+
+        let mut segments = Vec::new();
+        for i in 0..phnum as u64 {
+            let ph = i
+                .checked_mul(phentsize as u64)
+                .and_then(|offset| offset.checked_add(phoff))
+                .and_then(|ph| usize::try_from(ph).ok())
+                .ok_or_else(|| String::from("truncated ELF file"))?;
+            if read32(data, ph)? != PT_LOAD {
+                continue;
+            }
+            let segment = if xlen == 32 {
+                Segment {
+                    offset: word(ph + 4)?,
+                    vaddr: word(ph + 8)?,
+                    paddr: word(ph + 12)?,
+                    filesz: word(ph + 16)?,
+                    memsz: word(ph + 20)?,
+                }
+            } else {
+                Segment {
+                    offset: word(ph + 8)?,
+                    vaddr: word(ph + 16)?,
+                    paddr: word(ph + 24)?,
+                    filesz: word(ph + 32)?,
+                    memsz: word(ph + 40)?,
+                }
+            };
+            if segment.offset.saturating_add(segment.filesz) > data.len() as u64 {
+                return Err(String::from("segment extends past the end of the file"));
+            }
+            if segment.memsz < segment.filesz {
+                return Err(String::from("segment is smaller in memory than in the file"));
+            }
+            segments.push(segment);
+        }
+
+        Ok(Self {
+            xlen,
+            entry,
+            phoff,
+            phentsize,
+            phnum,
+            segments,
+            symbols: parse_symbols(data, xlen)?,
+        })
+    }
+
+    pub fn symbol(&self, name: &str) -> Option<u64> {
+        self.symbols.iter().find(|(n, _)| n == name).map(|(_, addr)| *addr)
+    }
+
+    /// Virtual address of the program headers, if a segment maps them.
+    pub fn phdr_addr(&self) -> Option<u64> {
+        self.segments
+            .iter()
+            .find(|s| s.offset <= self.phoff && self.phoff < s.offset + s.filesz)
+            .map(|s| s.vaddr + (self.phoff - s.offset))
+    }
+}
+
+/// Reads `.symtab` and the string table it links to.
+fn parse_symbols(data: &[u8], xlen: u32) -> Result<Vec<(String, u64)>, String> {
+    let word = |offset| read_word(data, offset, xlen);
+    let (shoff, shentsize, shnum) = if xlen == 32 { (32, 46, 48) } else { (40, 58, 60) };
+    let shoff = word(shoff)? as usize;
+    let shentsize = read16(data, shentsize)? as usize;
+    let shnum = read16(data, shnum)? as usize;
+
+    let section = |i: usize| -> Result<(u32, usize, usize, usize, usize), String> {
+        let sh = i
+            .checked_mul(shentsize)
+            .and_then(|offset| offset.checked_add(shoff))
+            .filter(|&sh| sh < data.len())
+            .ok_or_else(|| String::from("truncated ELF file"))?;
+        if xlen == 32 {
+            Ok((
+                read32(data, sh + 4)?,
+                word(sh + 16)? as usize,
+                word(sh + 20)? as usize,
+                read32(data, sh + 24)? as usize,
+                word(sh + 36)? as usize,
+            ))
+        } else {
+            Ok((
+                read32(data, sh + 4)?,
+                word(sh + 24)? as usize,
+                word(sh + 32)? as usize,
+                read32(data, sh + 40)? as usize,
+                word(sh + 56)? as usize,
+            ))
+        }
+    };
+
+    let mut symbols = Vec::new();
+    for i in 0..shnum {
+        let (kind, offset, size, link, entsize) = section(i)?;
+        if kind != SHT_SYMTAB || entsize == 0 {
+            continue;
+        }
+        let (_, strtab, _, _, _) = section(link)?;
+        let end = offset.checked_add(size).ok_or_else(|| String::from("truncated ELF file"))?;
+        for sym in (offset..end).step_by(entsize) {
+            let Some(name) = strtab.checked_add(read32(data, sym)? as usize) else {
+                continue;
+            };
+            // st_value follows st_name directly in ELF32 symbols.
+            let value = word(if xlen == 32 { sym + 4 } else { sym + 8 })?;
+            let end = data
+                .get(name..)
+                .and_then(|rest| rest.iter().position(|&b| b == 0))
+                .map(|n| name + n);
+            if let Some(end) = end.filter(|&end| end > name) {
+                symbols.push((String::from_utf8_lossy(&data[name..end]).into_owned(), value));
+            }
+        }
+    }
+
+    Ok(symbols)
+}
+
+fn bytes<const N: usize>(data: &[u8], offset: usize) -> Result<[u8; N], String> {
+    offset
+        .checked_add(N)
+        .and_then(|end| data.get(offset..end))
+        .and_then(|b| b.try_into().ok())
+        .ok_or_else(|| String::from("truncated ELF file"))
+}
+
+fn read16(data: &[u8], offset: usize) -> Result<u16, String> {
+    Ok(u16::from_le_bytes(bytes(data, offset)?))
+}
+
+fn read32(data: &[u8], offset: usize) -> Result<u32, String> {
+    Ok(u32::from_le_bytes(bytes(data, offset)?))
+}
+
+fn read64(data: &[u8], offset: usize) -> Result<u64, String> {
+    Ok(u64::from_le_bytes(bytes(data, offset)?))
+}
+
+/// Reads an address or size, 4 bytes in ELF32 files and 8 in ELF64 ones.
+fn read_word(data: &[u8], offset: usize, xlen: u32) -> Result<u64, String> {
+    if xlen == 32 {
+        read32(data, offset).map(u64::from)
+    } else {
+        read64(data, offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An ELF64 file with one PT_LOAD segment covering the whole file.
+    fn elf64(filesz: u64, memsz: u64) -> Vec<u8> {
+        let mut data = vec![0; 64 + 56];
+        data[..4].copy_from_slice(ELF_MAGIC);
+        data[4] = ELFCLASS64;
+        data[5] = ELFDATA2LSB;
+        data[18..20].copy_from_slice(&EM_RISCV.to_le_bytes());
+        data[24..32].copy_from_slice(&0x8000_0000u64.to_le_bytes());
+        data[32..40].copy_from_slice(&64u64.to_le_bytes());
+        data[54..56].copy_from_slice(&56u16.to_le_bytes());
+        data[56..58].copy_from_slice(&1u16.to_le_bytes());
+        data[64..68].copy_from_slice(&PT_LOAD.to_le_bytes());
+        data[80..88].copy_from_slice(&0x8000_0000u64.to_le_bytes());
+        data[88..96].copy_from_slice(&0x8000_0000u64.to_le_bytes());
+        data[96..104].copy_from_slice(&filesz.to_le_bytes());
+        data[104..112].copy_from_slice(&memsz.to_le_bytes());
+        data
+    }
+
+    #[test]
+    fn parses_segments() {
+        let elf = Elf::parse(&elf64(120, 0x1000)).unwrap();
+        assert_eq!((elf.xlen, elf.entry, elf.phnum), (64, 0x8000_0000, 1));
+        let segment = &elf.segments[0];
+        assert_eq!((segment.offset, segment.filesz, segment.memsz), (0, 120, 0x1000));
+        assert_eq!(elf.phdr_addr(), Some(0x8000_0040));
+    }
+
+    #[test]
+    fn malformed_files_are_errors() {
+        assert!(Elf::parse(&elf64(120, 16)).unwrap_err().contains("smaller in memory"));
+        assert!(Elf::parse(&elf64(121, 0x1000)).unwrap_err().contains("past the end"));
+
+        // Program and section headers far past the end of the file.
+        for (offset, field) in [(32, u64::MAX), (32, u64::MAX - 8), (40, u64::MAX - 2)] {
+            let mut data = elf64(120, 0x1000);
+            data[offset..offset + 8].copy_from_slice(&field.to_le_bytes());
+            data[58..60].copy_from_slice(&64u16.to_le_bytes());
+            data[60..62].copy_from_slice(&1u16.to_le_bytes());
+            assert!(Elf::parse(&data).is_err());
+        }
+        let mut data = elf64(120, 0x1000);
+        data[54..56].copy_from_slice(&u16::MAX.to_le_bytes());
+        data[56..58].copy_from_slice(&u16::MAX.to_le_bytes());
+        assert!(Elf::parse(&data).is_err());
+    }
+}
@@ -0,0 +1,80 @@
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+use crate::config::*;
+use crate::replay::*;
+use crate::snapshot::*;
+use crate::virtio::*;
+
+const HOST_SOURCE: &str = "/dev/urandom";
+
+enum Source {
+    Host(File, HostInput),
+    /// splitmix64 state.
+    Seeded(u64),
+}
+
+/// Random bytes from the host or from a seed, for the virtio entropy device
+/// and the `seed` CSR.
+pub struct Entropy {
+    source: Source,
+}
+
+impl Entropy {
+    /// Host reads are recorded and replayed under `name`.
+    pub fn new(source: RngSource, name: &str, log: Option<&SharedLog>) -> io::Result<Self> {
+        let source = match source {
+            RngSource::Host => {
+                let path = Path::new(HOST_SOURCE);
+                let file = File::open(path).map_err(|e| with_path(path, e))?;
+                Source::Host(file, HostInput::new(name, log))
+            }
+            RngSource::Seed(seed) => Source::Seeded(seed),
+        };
+        Ok(Self { source })
+    }
+
+    pub fn fill(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        match &mut self.source {
+            Source::Host(file, host) => {
+                let len = buf.len();
+                let data = host.read(|| {
+                    let mut data = vec![0; len];
+                    file.read_exact(&mut data).map_or(Vec::new(), |_| data)
+                });
+                if data.len() != len {
+                    return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+                }
+                buf.copy_from_slice(&data);
+                Ok(())
+            }
+            Source::Seeded(state) => {
+                for chunk in buf.chunks_mut(8) {
+                    *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+                    let mut z = *state;
+                    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+                    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+                    z ^= z >> 31;
+                    chunk.copy_from_slice(&z.to_le_bytes()[..chunk.len()]);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Where a seeded sequence has got to, so a resumed run goes on with the
+    /// same bytes.
+    pub fn save(&self, out: &mut StateWriter) {
+        if let Source::Seeded(state) = self.source {
+            out.u64(state);
+        }
+    }
+
+    pub fn restore(&mut self, input: &mut StateReader) -> io::Result<()> {
+        if let Source::Seeded(state) = &mut self.source {
+            *state = input.u64()?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,100 @@
+/// Synchronous exceptions raised while executing an instruction. The value is
+/// the faulting address or instruction, as it would appear in `mtval`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exception {
+    InstructionAccessFault(u64),
+    IllegalInstruction(u64),
+    Breakpoint(u64),
+    LoadAddressMisaligned(u64),
+    LoadAccessFault(u64),
+    StoreAMOAddressMisaligned(u64),
+    StoreAMOAccessFault(u64),
+    EnvironmentCallFromUMode,
+    EnvironmentCallFromSMode,
+    EnvironmentCallFromVSMode,
+    EnvironmentCallFromMMode,
+    InstructionPageFault(u64),
+    LoadPageFault(u64),
+    StoreAMOPageFault(u64),
+    /// Guest-page faults also carry the guest physical address that the
+    /// G-stage could not translate.
+    InstructionGuestPageFault(u64, u64),
+    LoadGuestPageFault(u64, u64),
+    VirtualInstruction(u64),
+    StoreAMOGuestPageFault(u64, u64),
+}
+
+impl Exception {
+    /// The exception code written to `mcause`/`scause`.
+    pub fn code(&self) -> u64 {
+        match self {
+            Exception::InstructionAccessFault(_) => 1,
+            Exception::IllegalInstruction(_) => 2,
+            Exception::Breakpoint(_) => 3,
+            Exception::LoadAddressMisaligned(_) => 4,
+            Exception::LoadAccessFault(_) => 5,
+            Exception::StoreAMOAddressMisaligned(_) => 6,
+            Exception::StoreAMOAccessFault(_) => 7,
+            Exception::EnvironmentCallFromUMode => 8,
+            Exception::EnvironmentCallFromSMode => 9,
+            Exception::EnvironmentCallFromVSMode => 10,
+            Exception::EnvironmentCallFromMMode => 11,
+            Exception::InstructionPageFault(_) => 12,
+            Exception::LoadPageFault(_) => 13,
+            Exception::StoreAMOPageFault(_) => 15,
+            Exception::InstructionGuestPageFault(..) => 20,
+            Exception::LoadGuestPageFault(..) => 21,
+            Exception::VirtualInstruction(_) => 22,
+            Exception::StoreAMOGuestPageFault(..) => 23,
+        }
+    }
+
+    /// The value written to `mtval`/`stval`.
+    pub fn value(&self) -> u64 {
+        match *self {
+            Exception::InstructionAccessFault(v)
+            | Exception::IllegalInstruction(v)
+            | Exception::Breakpoint(v)
+            | Exception::LoadAddressMisaligned(v)
+            | Exception::LoadAccessFault(v)
+            | Exception::StoreAMOAddressMisaligned(v)
+            | Exception::StoreAMOAccessFault(v)
+            | Exception::InstructionPageFault(v)
+            | Exception::LoadPageFault(v)
+            | Exception::StoreAMOPageFault(v)
+            | Exception::InstructionGuestPageFault(v, _)
+            | Exception::LoadGuestPageFault(v, _)
+            | Exception::VirtualInstruction(v)
+            | Exception::StoreAMOGuestPageFault(v, _) => v,
+            Exception::EnvironmentCallFromUMode
+            | Exception::EnvironmentCallFromSMode
+            | Exception::EnvironmentCallFromVSMode
+            | Exception::EnvironmentCallFromMMode => 0,
+        }
+    }
+
+    /// The guest physical address of a guest-page fault, or 0, from which
+    /// `mtval2`/`htval` are written.
+    pub fn guest_address(&self) -> u64 {
+        match *self {
+            Exception::InstructionGuestPageFault(_, gpa)
+            | Exception::LoadGuestPageFault(_, gpa)
+            | Exception::StoreAMOGuestPageFault(_, gpa) => gpa,
+            _ => 0,
+        }
+    }
+
+    /// Whether the value is an address, rather than an instruction or
+    /// nothing.
+    pub fn has_address(&self) -> bool {
+        !matches!(
+            self,
+            Exception::IllegalInstruction(_)
+                | Exception::VirtualInstruction(_)
+                | Exception::EnvironmentCallFromUMode
+                | Exception::EnvironmentCallFromSMode
+                | Exception::EnvironmentCallFromVSMode
+                | Exception::EnvironmentCallFromMMode
+        )
+    }
+}
@@ -0,0 +1,422 @@
+use std::collections::BTreeMap;
+
+use crate::bus::*;
+use crate::config::*;
+use crate::isa::*;
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_VERSION: u32 = 17;
+const FDT_LAST_COMP_VERSION: u32 = 16;
+const HEADER_SIZE: usize = 40;
+
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_END: u32 = 9;
+
+/// Ticks of `mtime` per second reported to the guest, the same as QEMU's.
+pub const TIMEBASE_FREQUENCY: u32 = 10_000_000;
+
+/// Phandle of the parent of every device interrupt: the PLIC, or the
+/// S-level domain of the APLIC.
+pub const IRQ_PHANDLE: u32 = 1;
+/// Phandle of the test finisher, which the poweroff and reboot nodes refer to.
+pub const SYSCON_PHANDLE: u32 = 2;
+/// Phandles of the M-level APLIC domain and of the M-level and S-level
+/// IMSICs.
+pub const APLIC_M_PHANDLE: u32 = 3;
+pub const IMSIC_M_PHANDLE: u32 = 4;
+pub const IMSIC_S_PHANDLE: u32 = 5;
+
+/// Level-triggered, active high, in the second cell of an APLIC interrupt.
+const IRQ_TYPE_LEVEL_HIGH: u32 = 4;
+
+/// Phandle of the interrupt controller node of a hart.
+pub fn cpu_intc_phandle(hartid: usize) -> u32 {
+    6 + hartid as u32
+}
+
+/// Builds a flattened device tree blob, one node and property at a time.
+pub struct Fdt {
+    structure: Vec<u8>,
+    strings: Vec<u8>,
+    /// Offsets of the property names already in `strings`.
+    names: BTreeMap<String, u32>,
+    depth: usize,
+    /// Cells of a device interrupt: the source, and its type with an APLIC.
+    pub interrupt_cells: u32,
+}
+
+impl Fdt {
+    pub fn new() -> Self {
+        Self {
+            structure: Vec::new(),
+            strings: Vec::new(),
+            names: BTreeMap::new(),
+            depth: 0,
+            interrupt_cells: 1,
+        }
+    }
+
+    fn token(&mut self, token: u32) {
+        self.structure.extend_from_slice(&token.to_be_bytes());
+    }
+
+    fn align(&mut self) {
+        while self.structure.len() & 3 != 0 {
+            self.structure.push(0);
+        }
+    }
+
+    pub fn begin_node(&mut self, name: &str) {
+        self.token(FDT_BEGIN_NODE);
+        self.structure.extend_from_slice(name.as_bytes());
+        self.structure.push(0);
+        self.align();
+        self.depth += 1;
+    }
+
+    pub fn end_node(&mut self) {
+        self.token(FDT_END_NODE);
+        self.depth -= 1;
+    }
+
+    pub fn property(&mut self, name: &str, value: &[u8]) {
+        let offset = match self.names.get(name) {
+            Some(&offset) => offset,
+            None => {
+                let offset = self.strings.len() as u32;
+                self.strings.extend_from_slice(name.as_bytes());
+                self.strings.push(0);
+                self.names.insert(name.to_string(), offset);
+                offset
+            }
+        };
+        self.token(FDT_PROP);
+        self.token(value.len() as u32);
+        self.token(offset);
+        self.structure.extend_from_slice(value);
+        self.align();
+    }
+
+    /// A property with no value, such as `interrupt-controller`.
+    pub fn property_null(&mut self, name: &str) {
+        self.property(name, &[]);
+    }
+
+    pub fn property_u32(&mut self, name: &str, value: u32) {
+        self.property(name, &value.to_be_bytes());
+    }
+
+    pub fn property_u64(&mut self, name: &str, value: u64) {
+        self.property(name, &value.to_be_bytes());
+    }
+
+    pub fn property_cells(&mut self, name: &str, cells: &[u32]) {
+        let bytes: Vec<u8> = cells.iter().flat_map(|cell| cell.to_be_bytes()).collect();
+        self.property(name, &bytes);
+    }
+
+    pub fn property_string(&mut self, name: &str, value: &str) {
+        self.property_strings(name, &[value]);
+    }
+
+    pub fn property_strings(&mut self, name: &str, values: &[&str]) {
+        let mut bytes = Vec::new();
+        for value in values {
+            bytes.extend_from_slice(value.as_bytes());
+            bytes.push(0);
+        }
+        self.property(name, &bytes);
+    }
+
+    /// `interrupts` and `interrupt-parent` for a device wired to source
+    /// `irq` of the interrupt controller.
+    pub fn property_interrupt(&mut self, irq: u32) {
+        if self.interrupt_cells == 2 {
+            self.property_cells("interrupts", &[irq, IRQ_TYPE_LEVEL_HIGH]);
+        } else {
+            self.property_u32("interrupts", irq);
+        }
+        self.property_u32("interrupt-parent", IRQ_PHANDLE);
+    }
+
+    /// `reg` for a parent with two address and two size cells.
+    pub fn property_reg(&mut self, base: u64, size: u64) {
+        self.property_cells(
+            "reg",
+            &[(base >> 32) as u32, base as u32, (size >> 32) as u32, size as u32],
+        );
+    }
+
+    /// Returns the blob, with an empty memory reservation map.
+    pub fn finish(mut self) -> Vec<u8> {
+        assert_eq!(self.depth, 0, "unbalanced device tree nodes");
+        self.token(FDT_END);
+
+        let off_mem_rsvmap = HEADER_SIZE;
+        let off_dt_struct = off_mem_rsvmap + 16;
+        let off_dt_strings = off_dt_struct + self.structure.len();
+        let total_size = off_dt_strings + self.strings.len();
+
+        let header = [
+            FDT_MAGIC,
+            total_size as u32,
+            off_dt_struct as u32,
+            off_dt_strings as u32,
+            off_mem_rsvmap as u32,
+            FDT_VERSION,
+            FDT_LAST_COMP_VERSION,
+            0, // boot_cpuid_phys
+            self.strings.len() as u32,
+            self.structure.len() as u32,
+        ];
+        let mut blob: Vec<u8> = header.iter().flat_map(|word| word.to_be_bytes()).collect();
+        blob.extend_from_slice(&[0; 16]);
+        blob.append(&mut self.structure);
+        blob.append(&mut self.strings);
+        blob
+    }
+}
+
+/// Describes the machine: memory, harts and their ISA, and every device on
+/// the bus. `initrd` is the address range of the initial ramdisk, if any.
+pub fn generate(config: &MachineConfig, bus: &Bus, initrd: Option<(u64, u64)>) -> Vec<u8> {
+    let mut fdt = Fdt::new();
+    if config.interrupt_controller != InterruptController::Plic {
+        fdt.interrupt_cells = 2;
+    }
+    fdt.begin_node("");
+    fdt.property_u32("#address-cells", 2);
+    fdt.property_u32("#size-cells", 2);
+    fdt.property_string("compatible", "rvemu,virt");
+    fdt.property_string("model", "rvemu virt");
+
+    fdt.begin_node("chosen");
+    if let Some(bootargs) = &config.bootargs {
+        fdt.property_string("bootargs", bootargs);
+    }
+    if let Some(path) = bus.console_path() {
+        fdt.property_string("stdout-path", &path);
+    }
+    if let Some((start, end)) = initrd {
+        fdt.property_u64("linux,initrd-start", start);
+        fdt.property_u64("linux,initrd-end", end);
+    }
+    fdt.end_node();
+
+    fdt.begin_node(&format!("memory@{:x}", config.dram_base));
+    fdt.property_string("device_type", "memory");
+    fdt.property_reg(config.dram_base, config.dram_size);
+    fdt.end_node();
+
+    let extensions = config.isa.extensions();
+    let xlen = config.xlen();
+    let isa = isa_string(xlen, &extensions);
+    fdt.begin_node("cpus");
+    fdt.property_u32("#address-cells", 1);
+    fdt.property_u32("#size-cells", 0);
+    fdt.property_u32("timebase-frequency", TIMEBASE_FREQUENCY);
+    for hartid in 0..config.harts {
+        fdt.begin_node(&format!("cpu@{}", hartid));
+        fdt.property_string("device_type", "cpu");
+        fdt.property_u32("reg", hartid as u32);
+        fdt.property_string("status", "okay");
+        fdt.property_string("compatible", "riscv");
+        fdt.property_string("riscv,isa", &isa);
+        fdt.property_string("riscv,isa-base", &format!("rv{}i", xlen));
+        fdt.property_strings("riscv,isa-extensions", &extensions);
+        fdt.property_string("mmu-type", if xlen == 32 { "riscv,sv32" } else { "riscv,sv39" });
+        let block_size = config.cache_block_size as u32;
+        if config.isa.has(ZICBOM) {
+            fdt.property_u32("riscv,cbom-block-size", block_size);
+        }
+        if config.isa.has(ZICBOP) {
+            fdt.property_u32("riscv,cbop-block-size", block_size);
+        }
+        if config.isa.has(ZICBOZ) {
+            fdt.property_u32("riscv,cboz-block-size", block_size);
+        }
+
+        fdt.begin_node("interrupt-controller");
+        fdt.property_u32("#interrupt-cells", 1);
+        fdt.property_null("interrupt-controller");
+        fdt.property_string("compatible", "riscv,cpu-intc");
+        fdt.property_u32("phandle", cpu_intc_phandle(hartid));
+        fdt.end_node();
+
+        fdt.end_node();
+    }
+    fdt.end_node();
+
+    fdt.begin_node("soc");
+    fdt.property_u32("#address-cells", 2);
+    fdt.property_u32("#size-cells", 2);
+    fdt.property_string("compatible", "simple-bus");
+    fdt.property_null("ranges");
+    bus.describe(&mut fdt, config.harts);
+    fdt.end_node();
+
+    fdt.end_node();
+    fdt.finish()
+}
+
+/// The `riscv,isa` string, e.g. `rv64imafdc_zicsr_zifencei`.
+fn isa_string(xlen: u32, extensions: &[&str]) -> String {
+    let mut isa = format!("rv{}", xlen);
+    for extension in extensions.iter().filter(|e| e.len() == 1) {
+        isa.push_str(extension);
+    }
+    for extension in extensions.iter().filter(|e| e.len() > 1) {
+        isa.push('_');
+        isa.push_str(extension);
+    }
+    isa
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    fn word(blob: &[u8], offset: usize) -> u32 {
+        u32::from_be_bytes(blob[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn c_string(bytes: &[u8]) -> &str {
+        let end = bytes.iter().position(|&b| b == 0).unwrap();
+        std::str::from_utf8(&bytes[..end]).unwrap()
+    }
+
+    /// Reads a blob back, checking its header, as the properties by their
+    /// paths, e.g. `/cpus/cpu@0/reg`.
+    fn parse(blob: &[u8]) -> BTreeMap<String, Vec<u8>> {
+        assert_eq!(word(blob, 0), FDT_MAGIC);
+        assert_eq!(word(blob, 4) as usize, blob.len());
+        let (structure, strings) = (word(blob, 8) as usize, word(blob, 12) as usize);
+        assert_eq!(word(blob, 32) as usize, blob.len() - strings);
+        assert_eq!(word(blob, 36) as usize, strings - structure);
+        let mut properties = BTreeMap::new();
+        let mut path = vec![];
+        let mut offset = structure;
+        loop {
+            let token = word(blob, offset);
+            offset += 4;
+            match token {
+                FDT_BEGIN_NODE => {
+                    let name = c_string(&blob[offset..]);
+                    path.push(name.to_string());
+                    offset += (name.len() + 4) & !3;
+                }
+                FDT_END_NODE => {
+                    path.pop().unwrap();
+                }
+                FDT_PROP => {
+                    let (len, name) = (word(blob, offset) as usize, word(blob, offset + 4) as usize);
+                    let value = blob[offset + 8..offset + 8 + len].to_vec();
+                    let name = c_string(&blob[strings + name..]);
+                    properties.insert(format!("{}/{}", path.join("/"), name), value);
+                    offset += (8 + len + 3) & !3;
+                }
+                FDT_END => break,
+                _ => panic!("token {} at {:#x}", token, offset - 4),
+            }
+        }
+        assert!(path.is_empty());
+        assert_eq!(offset, strings);
+        properties
+    }
+
+    fn cells(value: &[u8]) -> Vec<u32> {
+        value.chunks(4).map(|cell| u32::from_be_bytes(cell.try_into().unwrap())).collect()
+    }
+
+    fn strings(value: &[u8]) -> Vec<&str> {
+        value.split(|&b| b == 0).filter(|s| !s.is_empty()).map(|s| std::str::from_utf8(s).unwrap()).collect()
+    }
+
+    #[test]
+    fn writes_nodes_and_properties() {
+        let mut fdt = Fdt::new();
+        fdt.begin_node("");
+        fdt.property_u32("#size-cells", 2);
+        fdt.begin_node("node@1000");
+        fdt.property_reg(0x1_0000_1000, 0x100);
+        fdt.property_strings("compatible", &["a,b", "c"]);
+        fdt.property_null("flag");
+        fdt.property_u64("big", 0x0102_0304_0506_0708);
+        fdt.end_node();
+        fdt.begin_node("other");
+        fdt.property_u32("#size-cells", 1);
+        fdt.end_node();
+        fdt.end_node();
+        let blob = fdt.finish();
+        assert_eq!((word(&blob, 20), word(&blob, 24)), (17, 16));
+
+        let properties = parse(&blob);
+        let keys: Vec<&str> = properties.keys().map(String::as_str).collect();
+        let expected = [
+            "/#size-cells",
+            "/node@1000/big",
+            "/node@1000/compatible",
+            "/node@1000/flag",
+            "/node@1000/reg",
+            "/other/#size-cells",
+        ];
+        assert_eq!(keys, expected);
+        assert_eq!(cells(&properties["/node@1000/reg"]), [1, 0x1000, 0, 0x100]);
+        assert_eq!(properties["/node@1000/compatible"], b"a,b\0c\0");
+        assert_eq!(properties["/node@1000/flag"], b"");
+        assert_eq!(properties["/node@1000/big"], [1, 2, 3, 4, 5, 6, 7, 8]);
+        // Each name is stored once.
+        assert_eq!(&blob[word(&blob, 12) as usize..], b"#size-cells\0reg\0compatible\0flag\0big\0");
+    }
+
+    #[test]
+    fn describes_the_virt_machine() {
+        let config = MachineConfig { harts: 2, bootargs: Some(String::from("console=ttyS0")), ..virt() };
+        let dtb = machine(&config, &[]).device_tree(&config, Some((0x8400_0000, 0x8410_0000)));
+        let properties = parse(&dtb);
+        let get = |path: &str| &properties[path][..];
+
+        assert_eq!(get("/chosen/bootargs"), b"console=ttyS0\0");
+        assert_eq!(get("/chosen/stdout-path"), b"/soc/serial@10000000\0");
+        assert_eq!(get("/chosen/linux,initrd-end"), 0x8410_0000u64.to_be_bytes());
+        assert_eq!(cells(get("/memory@80000000/reg")), [0, 0x8000_0000, 0, 0x800_0000]);
+        assert_eq!(cells(get("/cpus/timebase-frequency")), [TIMEBASE_FREQUENCY]);
+        assert_eq!(cells(get("/cpus/cpu@1/reg")), [1]);
+        assert_eq!(cells(get("/cpus/cpu@1/interrupt-controller/phandle")), [cpu_intc_phandle(1)]);
+        let isa = c_string(get("/cpus/cpu@0/riscv,isa"));
+        assert!(isa.starts_with("rv64imafdc") && isa.contains("_zicsr_zifencei"), "{}", isa);
+        assert_eq!(strings(get("/cpus/cpu@0/riscv,isa-extensions")), config.isa.extensions());
+        assert_eq!(get("/cpus/cpu@0/mmu-type"), b"riscv,sv39\0");
+        assert_eq!(cells(get("/cpus/cpu@0/riscv,cboz-block-size")), [64]);
+
+        // The devices, wired to the PLIC
+        assert_eq!(cells(get("/soc/clint@2000000/reg")), [0, 0x200_0000, 0, 0x1_0000]);
+        assert_eq!(cells(get("/soc/plic@c000000/interrupts-extended")), [6, 11, 6, 9, 7, 11, 7, 9]);
+        assert_eq!(cells(get("/soc/serial@10000000/interrupts")), [10]);
+        assert_eq!(cells(get("/soc/serial@10000000/interrupt-parent")), [IRQ_PHANDLE]);
+        assert_eq!(cells(get("/soc/poweroff/regmap")), [SYSCON_PHANDLE]);
+    }
+
+    #[test]
+    fn describes_the_aia() {
+        let config = MachineConfig { interrupt_controller: InterruptController::AplicImsic, xlen: Some(32), ..virt() };
+        let dtb = machine(&config, &[]).device_tree(&config, None);
+        let properties = parse(&dtb);
+        let get = |path: &str| &properties[path][..];
+        assert!(!properties.contains_key("/chosen/bootargs"));
+        assert_eq!(get("/cpus/cpu@0/mmu-type"), b"riscv,sv32\0");
+        assert_eq!(cells(get("/soc/serial@10000000/interrupts")), [10, 4]);
+        assert_eq!(cells(get("/soc/interrupt-controller@d000000/phandle")), [IRQ_PHANDLE]);
+        assert_eq!(cells(get("/soc/interrupt-controller@d000000/msi-parent")), [IMSIC_S_PHANDLE]);
+        assert_eq!(cells(get("/soc/interrupt-controller@c000000/riscv,children")), [IRQ_PHANDLE]);
+        assert_eq!(cells(get("/soc/imsics@28000000/interrupts-extended")), [6, 9]);
+    }
+
+    #[test]
+    fn isa_strings_put_single_letters_first() {
+        assert_eq!(isa_string(32, &["i", "m", "zicsr", "a", "sstc"]), "rv32ima_zicsr_sstc");
+    }
+}
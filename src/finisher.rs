@@ -0,0 +1,71 @@
+use crate::device::*;
+use crate::exception::*;
+use crate::fdt::*;
+
+pub const FINISHER_BASE: u64 = 0x10_0000;
+pub const FINISHER_SIZE: u64 = 0x1000;
+
+pub const FINISHER_FAIL: u64 = 0x3333;
+pub const FINISHER_PASS: u64 = 0x5555;
+pub const FINISHER_RESET: u64 = 0x7777;
+
+/// SiFive test finisher, which firmware uses to power off or reset the board.
+///
+/// Writing 0x5555 powers off with status 0 and `code << 16 | 0x3333` with
+/// status `code`. A reset request also stops the emulator.
+pub struct Finisher {
+    exit_status: Option<i32>,
+}
+
+impl Finisher {
+    pub fn new() -> Self {
+        Self { exit_status: None }
+    }
+}
+
+impl Device for Finisher {
+    fn base(&self) -> u64 {
+        FINISHER_BASE
+    }
+
+    fn size(&self) -> u64 {
+        FINISHER_SIZE
+    }
+
+    fn load(&mut self, _offset: u64, _size: u64) -> Result<u64, Exception> {
+        Ok(0)
+    }
+
+    fn store(&mut self, offset: u64, _size: u64, value: u64) -> Result<(), Exception> {
+        if offset != 0 {
+            return Ok(());
+        }
+        self.exit_status = match value & 0xffff {
+            FINISHER_PASS | FINISHER_RESET => Some(0),
+            FINISHER_FAIL => Some((value >> 16) as i32),
+            _ => None,
+        };
+        Ok(())
+    }
+
+    fn exit_status(&self) -> Option<i32> {
+        self.exit_status
+    }
+
+    fn device_tree(&self, fdt: &mut Fdt, _harts: usize) {
+        fdt.begin_node(&format!("test@{:x}", FINISHER_BASE));
+        fdt.property_strings("compatible", &["sifive,test1", "sifive,test0", "syscon"]);
+        fdt.property_reg(FINISHER_BASE, FINISHER_SIZE);
+        fdt.property_u32("phandle", SYSCON_PHANDLE);
+        fdt.end_node();
+
+        for (name, value) in [("poweroff", FINISHER_PASS), ("reboot", FINISHER_RESET)] {
+            fdt.begin_node(name);
+            fdt.property_string("compatible", &format!("syscon-{}", name));
+            fdt.property_u32("regmap", SYSCON_PHANDLE);
+            fdt.property_u32("offset", 0);
+            fdt.property_u32("value", value as u32);
+            fdt.end_node();
+        }
+    }
+}
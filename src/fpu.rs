@@ -0,0 +1,1085 @@
+use std::ops::{Add, Div, Mul, Neg, Sub};
+
+use crate::cpu::*;
+use crate::exception::*;
+use crate::isa::*;
+
+// fflags bits
+pub const NX: u64 = 1 << 0;
+pub const UF: u64 = 1 << 1;
+pub const OF: u64 = 1 << 2;
+pub const DZ: u64 = 1 << 3;
+pub const NV: u64 = 1 << 4;
+
+// Rounding modes
+pub const RNE: u64 = 0b000;
+pub const RTZ: u64 = 0b001;
+pub const RDN: u64 = 0b010;
+pub const RUP: u64 = 0b011;
+pub const RMM: u64 = 0b100;
+const DYN: u64 = 0b111;
+
+/// The constants `fli` loads, by rs1. Entry 1 stands for the smallest normal
+/// number of the format and entry 31 for the canonical NaN.
+const FLI_CONSTANTS: [f64; 32] = [
+    -1.0,
+    0.0,
+    1.0 / 65536.0,
+    1.0 / 32768.0,
+    1.0 / 256.0,
+    1.0 / 128.0,
+    0.0625,
+    0.125,
+    0.25,
+    0.3125,
+    0.375,
+    0.4375,
+    0.5,
+    0.625,
+    0.75,
+    0.875,
+    1.0,
+    1.25,
+    1.5,
+    1.75,
+    2.0,
+    2.5,
+    3.0,
+    4.0,
+    8.0,
+    16.0,
+    128.0,
+    256.0,
+    32768.0,
+    65536.0,
+    f64::INFINITY,
+    0.0,
+];
+
+/// What the F, D and V instructions need from `f32` and `f64`.
+pub trait Float:
+    Copy
+    + PartialEq
+    + PartialOrd
+    + Add<Output = Self>
+    + Sub<Output = Self>
+    + Mul<Output = Self>
+    + Div<Output = Self>
+    + Neg<Output = Self>
+{
+    const BITS: u32;
+    /// The width of the fraction field.
+    const MANTISSA: u32;
+    const BIAS: i32;
+    const ZERO: Self;
+    const MIN_POSITIVE: Self;
+
+    /// Reads the value from a floating-point register, unboxing it.
+    fn from_reg(reg: u64) -> Self;
+    /// The register contents for the value, NaN-boxed if needed.
+    fn to_reg(self) -> u64;
+    fn to_raw(self) -> u64;
+    fn from_raw(raw: u64) -> Self;
+    fn canonical_nan() -> Self;
+    fn is_nan(self) -> bool;
+    fn is_snan(self) -> bool;
+    fn is_infinite(self) -> bool;
+    fn is_finite(self) -> bool;
+    fn is_sign_negative(self) -> bool;
+    fn abs(self) -> Self;
+    fn sqrt(self) -> Self;
+    fn mul_add(self, a: Self, b: Self) -> Self;
+    fn to_f64(self) -> f64;
+    /// Rounds a double to the format, to nearest.
+    fn from_f64(value: f64) -> Self;
+}
+
+impl Float for f32 {
+    const BITS: u32 = 32;
+    const MANTISSA: u32 = 23;
+    const BIAS: i32 = 127;
+    const ZERO: Self = 0.0;
+    const MIN_POSITIVE: Self = f32::MIN_POSITIVE;
+
+    fn from_reg(reg: u64) -> Self {
+        if reg >> 32 == 0xffff_ffff {
+            f32::from_bits(reg as u32)
+        } else {
+            Self::canonical_nan()
+        }
+    }
+    fn to_reg(self) -> u64 {
+        0xffff_ffff_0000_0000 | self.to_bits() as u64
+    }
+    fn to_raw(self) -> u64 {
+        self.to_bits() as u64
+    }
+    fn from_raw(raw: u64) -> Self {
+        f32::from_bits(raw as u32)
+    }
+    fn canonical_nan() -> Self {
+        f32::from_bits(0x7fc0_0000)
+    }
+    fn is_nan(self) -> bool {
+        f32::is_nan(self)
+    }
+    fn is_snan(self) -> bool {
+        f32::is_nan(self) && self.to_bits() & 0x0040_0000 == 0
+    }
+    fn is_infinite(self) -> bool {
+        f32::is_infinite(self)
+    }
+    fn is_finite(self) -> bool {
+        f32::is_finite(self)
+    }
+    fn is_sign_negative(self) -> bool {
+        f32::is_sign_negative(self)
+    }
+    fn abs(self) -> Self {
+        f32::abs(self)
+    }
+    fn sqrt(self) -> Self {
+        f32::sqrt(self)
+    }
+    fn mul_add(self, a: Self, b: Self) -> Self {
+        f32::mul_add(self, a, b)
+    }
+    fn to_f64(self) -> f64 {
+        self as f64
+    }
+    fn from_f64(value: f64) -> Self {
+        value as f32
+    }
+}
+
+impl Float for f64 {
+    const BITS: u32 = 64;
+    const MANTISSA: u32 = 52;
+    const BIAS: i32 = 1023;
+    const ZERO: Self = 0.0;
+    const MIN_POSITIVE: Self = f64::MIN_POSITIVE;
+
+    fn from_reg(reg: u64) -> Self {
+        f64::from_bits(reg)
+    }
+    fn to_reg(self) -> u64 {
+        self.to_bits()
+    }
+    fn to_raw(self) -> u64 {
+        self.to_bits()
+    }
+    fn from_raw(raw: u64) -> Self {
+        f64::from_bits(raw)
+    }
+    fn canonical_nan() -> Self {
+        f64::from_bits(0x7ff8_0000_0000_0000)
+    }
+    fn is_nan(self) -> bool {
+        f64::is_nan(self)
+    }
+    fn is_snan(self) -> bool {
+        f64::is_nan(self) && self.to_bits() & 0x0008_0000_0000_0000 == 0
+    }
+    fn is_infinite(self) -> bool {
+        f64::is_infinite(self)
+    }
+    fn is_finite(self) -> bool {
+        f64::is_finite(self)
+    }
+    fn is_sign_negative(self) -> bool {
+        f64::is_sign_negative(self)
+    }
+    fn abs(self) -> Self {
+        f64::abs(self)
+    }
+    fn sqrt(self) -> Self {
+        f64::sqrt(self)
+    }
+    fn mul_add(self, a: Self, b: Self) -> Self {
+        f64::mul_add(self, a, b)
+    }
+    fn to_f64(self) -> f64 {
+        self
+    }
+    fn from_f64(value: f64) -> Self {
+        value
+    }
+}
+
+/// The flags of an operation whose result the host gets exactly, as with
+/// infinite, NaN or zero operands, canonicalizing a NaN result.
+fn finish<F: Float>(result: F, operands: &[F]) -> (F, u64) {
+    let mut flags = 0;
+    if operands.iter().any(|x| x.is_snan()) {
+        flags |= NV;
+    }
+    if result.is_nan() {
+        if !operands.iter().any(|x| x.is_nan()) {
+            flags |= NV;
+        }
+        return (F::canonical_nan(), flags);
+    }
+    (result, flags)
+}
+
+/// A finite value, `mant * 2^exp`. Results that need more bits than `mant`
+/// has keep a sticky lowest bit, set if any bit below it would be.
+#[derive(Clone, Copy, Debug)]
+struct Exact {
+    negative: bool,
+    mant: u128,
+    exp: i32,
+}
+
+impl Exact {
+    fn of<F: Float>(x: F) -> Exact {
+        let raw = x.to_raw();
+        let fraction = raw & ((1 << F::MANTISSA) - 1);
+        let biased = (raw >> F::MANTISSA) as i32 & (2 * F::BIAS + 1);
+        let negative = (raw >> (F::BITS - 1)) & 1 == 1;
+        // Subnormals have the exponent of the smallest normals.
+        let (mant, biased) = if biased == 0 { (fraction, 1) } else { (fraction | 1 << F::MANTISSA, biased) };
+        Exact { negative, mant: mant as u128, exp: biased - F::BIAS - F::MANTISSA as i32 }
+    }
+
+    /// The exponent of the highest set bit.
+    fn top(self) -> i32 {
+        self.exp + 127 - self.mant.leading_zeros() as i32
+    }
+
+    fn mul(self, other: Exact) -> Exact {
+        Exact { negative: self.negative != other.negative, mant: self.mant * other.mant, exp: self.exp + other.exp }
+    }
+
+    /// The sum of two values of at most 106 bits. An exact zero sum of
+    /// addends of opposite signs is negative only when rounding down.
+    fn add(self, other: Exact, rm: u64) -> Exact {
+        if self.mant == 0 && other.mant == 0 {
+            let negative = if self.negative == other.negative { self.negative } else { rm == RDN };
+            return Exact { negative, ..self };
+        } else if other.mant == 0 {
+            return self;
+        } else if self.mant == 0 {
+            return other;
+        }
+        // The larger one goes up to bit 125, which leaves room for the carry.
+        // Bits of the other are only lost far below where the sum is rounded.
+        let (big, small) = if self.top() >= other.top() { (self, other) } else { (other, self) };
+        let exp = big.top() - 125;
+        let align = |x: Exact| match x.exp - exp {
+            shift if shift >= 0 => x.mant << shift,
+            shift if shift > -128 => (x.mant >> -shift) | (x.mant & ((1 << -shift) - 1) != 0) as u128,
+            _ => 1,
+        };
+        let (a, b) = (align(big), align(small));
+        let (negative, mant) = if big.negative == small.negative {
+            (big.negative, a + b)
+        } else if a >= b {
+            (big.negative, a - b)
+        } else {
+            (small.negative, b - a)
+        };
+        if mant == 0 {
+            return Exact { negative: rm == RDN, mant, exp };
+        }
+        Exact { negative, mant, exp }
+    }
+
+    /// The quotient of nonzero values.
+    fn div(self, other: Exact) -> Exact {
+        // With both normalized to 64 bits, the quotient has 64 or 65.
+        let normalize = |x: Exact| {
+            let shift = x.mant.leading_zeros() as i32 - 64;
+            (x.mant << shift, x.exp - shift)
+        };
+        let ((a, ea), (b, eb)) = (normalize(self), normalize(other));
+        let sticky = (a << 64) % b != 0;
+        Exact { negative: self.negative != other.negative, mant: ((a << 64) / b) | sticky as u128, exp: ea - eb - 64 }
+    }
+
+    /// The square root of a positive value.
+    fn sqrt(self) -> Exact {
+        // Normalized to 127 or 128 bits with an even exponent, whose root
+        // has 64 bits
+        let shift = self.mant.leading_zeros() as i32;
+        let (mut mant, mut exp) = (self.mant << shift, self.exp - shift);
+        if exp & 1 != 0 {
+            mant >>= 1;
+            exp += 1;
+        }
+        let root = mant.isqrt();
+        Exact { negative: false, mant: root | (root * root != mant) as u128, exp: exp / 2 }
+    }
+
+    /// Rounds to the format in mode `rm`, with the exception flags that
+    /// raises. Tininess is detected after rounding, as RISC-V requires.
+    fn round<F: Float>(self, rm: u64) -> (F, u64) {
+        let sign = (self.negative as u64) << (F::BITS - 1);
+        if self.mant == 0 {
+            return (F::from_raw(sign), 0);
+        }
+        let precision = F::MANTISSA as i32 + 1;
+        let emin = 1 - F::BIAS;
+        let top = self.top();
+        // The exponent of the result's lowest bit, fixed for subnormals
+        let mut lsb = (top + 1 - precision).max(emin + 1 - precision);
+        let (mut mant, inexact) = self.round_at(lsb, rm);
+        if mant >> precision != 0 {
+            // Rounded up to the next power of two
+            mant >>= 1;
+            lsb += 1;
+        }
+        let biased = if mant >> F::MANTISSA != 0 { lsb + F::MANTISSA as i32 + F::BIAS } else { 0 };
+        let infinity = ((2 * F::BIAS + 1) as u64) << F::MANTISSA;
+        if biased > 2 * F::BIAS {
+            // Overflow gives the largest finite number when rounding toward it
+            let to_infinity = match rm {
+                RTZ => false,
+                RDN => self.negative,
+                RUP => !self.negative,
+                _ => true,
+            };
+            let r = if to_infinity { infinity } else { infinity - 1 };
+            return (F::from_raw(sign | r), OF | NX);
+        }
+        let r = F::from_raw(sign | (biased as u64) << F::MANTISSA | (mant as u64 & ((1 << F::MANTISSA) - 1)));
+        if !inexact {
+            return (r, 0);
+        }
+        // Tiny unless rounding with an unbounded exponent reaches the
+        // smallest normal number
+        let tiny = top < emin && !(top + 1 == emin && self.round_at(top + 1 - precision, rm).0 >> precision != 0);
+        (r, if tiny { NX | UF } else { NX })
+    }
+
+    /// The value in units of 2^`lsb`, rounded to an integer in mode `rm`, and
+    /// whether that is inexact.
+    fn round_at(self, lsb: i32, rm: u64) -> (u128, bool) {
+        let shift = lsb - self.exp;
+        if shift <= 0 {
+            return (self.mant << -shift, false);
+        }
+        // A value this far below the unit only counts as less than half.
+        let (mant, shift) = if shift > 127 { (1, 2) } else { (self.mant, shift) };
+        let kept = mant >> shift;
+        let rest = mant & ((1 << shift) - 1);
+        let half = 1 << (shift - 1);
+        let up = match rm {
+            RTZ => false,
+            RDN => self.negative && rest != 0,
+            RUP => !self.negative && rest != 0,
+            RMM => rest >= half,
+            _ => rest > half || (rest == half && kept & 1 == 1),
+        };
+        (kept + up as u128, rest != 0)
+    }
+}
+
+pub fn add<F: Float>(a: F, b: F, rm: u64) -> (F, u64) {
+    if !a.is_finite() || !b.is_finite() {
+        return finish(a + b, &[a, b]);
+    }
+    Exact::of(a).add(Exact::of(b), rm).round(rm)
+}
+
+pub fn mul<F: Float>(a: F, b: F, rm: u64) -> (F, u64) {
+    if !a.is_finite() || !b.is_finite() {
+        return finish(a * b, &[a, b]);
+    }
+    Exact::of(a).mul(Exact::of(b)).round(rm)
+}
+
+pub fn div<F: Float>(a: F, b: F, rm: u64) -> (F, u64) {
+    if b == F::ZERO && a.is_finite() && a != F::ZERO {
+        return (a / b, DZ);
+    }
+    if !a.is_finite() || !b.is_finite() || a == F::ZERO || b == F::ZERO {
+        return finish(a / b, &[a, b]);
+    }
+    Exact::of(a).div(Exact::of(b)).round(rm)
+}
+
+pub fn sqrt<F: Float>(a: F, rm: u64) -> (F, u64) {
+    if !a.is_finite() || a <= F::ZERO {
+        return finish(a.sqrt(), &[a]);
+    }
+    Exact::of(a).sqrt().round(rm)
+}
+
+/// Fused multiply-add, rounded once.
+pub fn fma<F: Float>(a: F, b: F, c: F, rm: u64) -> (F, u64) {
+    if !a.is_finite() || !b.is_finite() || !c.is_finite() {
+        let (r, mut flags) = finish(a.mul_add(b, c), &[a, b, c]);
+        // inf * 0 is invalid even when adding a quiet NaN.
+        if (a.is_infinite() && b == F::ZERO) || (a == F::ZERO && b.is_infinite()) {
+            flags |= NV;
+        }
+        return (r, flags);
+    }
+    Exact::of(a).mul(Exact::of(b)).add(Exact::of(c), rm).round(rm)
+}
+
+/// fmin/fmax: a NaN operand is ignored unless both are NaN, and -0 < +0.
+pub fn min_max<F: Float>(a: F, b: F, max: bool) -> (F, u64) {
+    let flags = if a.is_snan() || b.is_snan() { NV } else { 0 };
+    let r = match (a.is_nan(), b.is_nan()) {
+        (true, true) => F::canonical_nan(),
+        (true, false) => b,
+        (false, true) => a,
+        _ if a == b => {
+            if a.is_sign_negative() != max {
+                a
+            } else {
+                b
+            }
+        }
+        _ if (a < b) != max => a,
+        _ => b,
+    };
+    (r, flags)
+}
+
+/// fminm/fmaxm: like fmin and fmax, except that a NaN operand makes the
+/// result NaN.
+pub fn min_max_propagating<F: Float>(a: F, b: F, max: bool) -> (F, u64) {
+    if a.is_nan() || b.is_nan() {
+        let flags = if a.is_snan() || b.is_snan() { NV } else { 0 };
+        return (F::canonical_nan(), flags);
+    }
+    min_max(a, b, max)
+}
+
+pub fn classify<F: Float>(a: F) -> u64 {
+    let negative = a.is_sign_negative();
+    let bit = if a.is_nan() {
+        if a.is_snan() {
+            8
+        } else {
+            9
+        }
+    } else if a.is_infinite() {
+        if negative {
+            0
+        } else {
+            7
+        }
+    } else if a == F::ZERO {
+        if negative {
+            3
+        } else {
+            4
+        }
+    } else if a.abs() < F::MIN_POSITIVE {
+        if negative {
+            2
+        } else {
+            5
+        }
+    } else if negative {
+        1
+    } else {
+        6
+    };
+    1 << bit
+}
+
+fn round(value: f64, rm: u64) -> f64 {
+    match rm {
+        RTZ => value.trunc(),
+        RDN => value.floor(),
+        RUP => value.ceil(),
+        RMM => value.round(),
+        _ => value.round_ties_even(),
+    }
+}
+
+/// Converts to an integer in `[min, max]`, saturating out-of-range values and
+/// NaNs as the spec requires.
+pub fn to_int<F: Float>(a: F, rm: u64, min: i128, max: i128) -> (i128, u64) {
+    if a.is_nan() {
+        return (max, NV);
+    }
+    let value = a.to_f64();
+    let rounded = round(value, rm);
+    // The bounds are powers of two, or one less, so max + 1 is exact.
+    if rounded < min as f64 {
+        (min, NV)
+    } else if rounded >= (max + 1) as f64 {
+        (max, NV)
+    } else {
+        let flags = if rounded != value { NX } else { 0 };
+        (rounded as i128, flags)
+    }
+}
+
+/// fround/froundnx: rounds to an integer in the same format. Only froundnx,
+/// with `exact` set, raises NX when that changes the value.
+pub fn round_to_integer<F: Float>(a: F, rm: u64, exact: bool) -> (F, u64) {
+    if a.is_nan() {
+        return (F::canonical_nan(), if a.is_snan() { NV } else { 0 });
+    }
+    if !a.is_finite() {
+        return (a, 0);
+    }
+    let r = F::from_f64(round(a.to_f64(), rm));
+    (r, if exact && r != a { NX } else { 0 })
+}
+
+/// fcvtmod.w.d: the low 32 bits of the integer part. Out-of-range values,
+/// infinities and NaNs raise NV, the last two giving zero.
+pub fn to_int_modular(a: f64) -> (i32, u64) {
+    if !a.is_finite() {
+        return (0, NV);
+    }
+    let integer = a.trunc();
+    let low = if integer.abs() < 9.0e18 {
+        integer as i64 as u64
+    } else {
+        // Whole and at least 2^63: the significand shifted by the exponent
+        let bits = a.to_bits();
+        let significand = (bits & ((1 << 52) - 1)) | (1 << 52);
+        let shift = ((bits >> 52) & 0x7ff) - 1075;
+        let magnitude = if shift < 64 { significand << shift } else { 0 };
+        if a < 0.0 {
+            magnitude.wrapping_neg()
+        } else {
+            magnitude
+        }
+    };
+    let flags = if integer < i32::MIN as f64 || integer > i32::MAX as f64 {
+        NV
+    } else if integer != a {
+        NX
+    } else {
+        0
+    };
+    (low as i32, flags)
+}
+
+/// Rounds a double to single precision.
+pub fn narrow(a: f64, rm: u64) -> (f32, u64) {
+    if a.is_nan() {
+        (f32::canonical_nan(), if a.is_snan() { NV } else { 0 })
+    } else if a.is_finite() {
+        Exact::of(a).round(rm)
+    } else {
+        (a as f32, 0)
+    }
+}
+
+pub fn from_int<F: Float>(value: i128, rm: u64) -> (F, u64) {
+    Exact { negative: value < 0, mant: value.unsigned_abs(), exp: 0 }.round(rm)
+}
+
+impl Cpu {
+    /// Executes the F and D extension instructions.
+    pub fn execute_fp(&mut self, instruction: u32) -> Result<(), Exception> {
+        let illegal = Exception::IllegalInstruction(instruction as u64);
+        if !self.fs_enabled() {
+            return Err(illegal);
+        }
+
+        let opcode = instruction & 0x7f;
+        let rd = ((instruction >> 7) & 0x1f) as usize;
+        let rs1 = ((instruction >> 15) & 0x1f) as usize;
+        let rs2 = ((instruction >> 20) & 0x1f) as usize;
+        let rs3 = ((instruction >> 27) & 0x1f) as usize;
+        let funct3 = ((instruction >> 12) & 0x7) as u64;
+        let funct7 = (instruction >> 25) & 0x7f;
+        let double = match funct7 & 0x3 {
+            0 => false,
+            1 => true,
+            _ if opcode == 0x07 || opcode == 0x27 => false,
+            _ => return Err(illegal),
+        };
+        let rm = match funct3 {
+            DYN => self.load_csr(FRM),
+            rm => rm,
+        };
+        let rm_valid = rm <= RMM;
+
+        match opcode {
+            0x07 => {
+                // flw, fld
+                let imm = ((instruction as i32 as i64) >> 20) as u64;
+                let addr = self.regs[rs1].wrapping_add(imm);
+                self.fregs[rd] = match funct3 {
+                    0x2 => self.load(addr, 32)? | 0xffff_ffff_0000_0000,
+                    0x3 => self.load(addr, 64)?,
+                    _ => return Err(illegal),
+                };
+            }
+            0x27 => {
+                // fsw, fsd
+                let imm = (((instruction & 0xfe00_0000) as i32 as i64 >> 20) as u64) | ((instruction >> 7) & 0x1f) as u64;
+                let addr = self.regs[rs1].wrapping_add(imm);
+                match funct3 {
+                    0x2 => self.store(addr, 32, self.fregs[rs2])?,
+                    0x3 => self.store(addr, 64, self.fregs[rs2])?,
+                    _ => return Err(illegal),
+                }
+                return Ok(());
+            }
+            0x43 | 0x47 | 0x4b | 0x4f => {
+                // fmadd, fmsub, fnmsub, fnmadd
+                if !rm_valid {
+                    return Err(illegal);
+                }
+                if double {
+                    self.fused::<f64>(opcode, rd, rs1, rs2, rs3, rm);
+                } else {
+                    self.fused::<f32>(opcode, rd, rs1, rs2, rs3, rm);
+                }
+            }
+            0x53 => {
+                if !rm_valid && rounds(funct7 >> 2) {
+                    return Err(illegal);
+                }
+                match funct7 {
+                    0x20 if rs2 == 1 => {
+                        // fcvt.s.d
+                        let (r, flags) = narrow(f64::from_reg(self.fregs[rs1]), rm);
+                        self.fregs[rd] = r.to_reg();
+                        self.csrs[FCSR] |= flags;
+                    }
+                    0x21 if rs2 == 0 => {
+                        // fcvt.d.s
+                        let a = f32::from_reg(self.fregs[rs1]);
+                        let r = if a.is_nan() { f64::canonical_nan() } else { a as f64 };
+                        self.fregs[rd] = r.to_reg();
+                        self.csrs[FCSR] |= if a.is_snan() { NV } else { 0 };
+                    }
+                    _ => {
+                        let done = if double {
+                            self.op_fp::<f64>(funct7 >> 2, funct3, rm, rd, rs1, rs2)
+                        } else {
+                            self.op_fp::<f32>(funct7 >> 2, funct3, rm, rd, rs1, rs2)
+                        };
+                        if !done {
+                            return Err(illegal);
+                        }
+                    }
+                }
+            }
+            _ => return Err(illegal),
+        }
+
+        self.set_fs_dirty();
+        Ok(())
+    }
+
+    fn fused<F: Float>(&mut self, opcode: u32, rd: usize, rs1: usize, rs2: usize, rs3: usize, rm: u64) {
+        let negate_product = opcode == 0x4b || opcode == 0x4f;
+        let negate_addend = opcode == 0x47 || opcode == 0x4f;
+        let mut a = F::from_reg(self.fregs[rs1]);
+        let b = F::from_reg(self.fregs[rs2]);
+        let mut c = F::from_reg(self.fregs[rs3]);
+        if negate_product {
+            a = -a;
+        }
+        if negate_addend {
+            c = -c;
+        }
+        let (r, flags) = fma(a, b, c, rm);
+        self.fregs[rd] = r.to_reg();
+        self.csrs[FCSR] |= flags;
+    }
+
+    /// The OP-FP instructions that exist for both precisions. Returns false
+    /// if the encoding is not one of them.
+    fn op_fp<F: Float>(&mut self, funct5: u32, funct3: u64, rm: u64, rd: usize, rs1: usize, rs2: usize) -> bool {
+        let a = F::from_reg(self.fregs[rs1]);
+        let b = F::from_reg(self.fregs[rs2]);
+        let (mut result, mut flags) = (None, 0);
+        let set_x = |cpu: &mut Cpu, value: u64| cpu.regs[rd] = value;
+        // The conversions to and from 64-bit integers, and moves of doubles
+        // to and from the integer registers, are RV64 only.
+        let max_int = if self.xlen == 32 { 1 } else { 3 };
+        let move_bits = if self.xlen == 32 { 32 } else { 64 };
+        let zfa = self.isa.has(ZFA);
+        let rv32_double = F::BITS == 64 && self.xlen == 32;
+
+        match (funct5, funct3) {
+            (0x00, _) => (result, flags) = some(add(a, b, rm)),
+            (0x01, _) => (result, flags) = some(add(a, -b, rm)),
+            (0x02, _) => (result, flags) = some(mul(a, b, rm)),
+            (0x03, _) => (result, flags) = some(div(a, b, rm)),
+            (0x0b, _) if rs2 == 0 => (result, flags) = some(sqrt(a, rm)),
+            (0x04, 0..=2) => {
+                // fsgnj, fsgnjn, fsgnjx work on the raw bits.
+                let sign_bit = F::BITS - 1;
+                let (x, y) = (a.to_reg(), b.to_reg());
+                let sign = match funct3 {
+                    0 => y,
+                    1 => !y,
+                    _ => x ^ y,
+                } & (1 << sign_bit);
+                self.fregs[rd] = (x & !(1 << sign_bit)) | sign;
+            }
+            (0x05, 0..=1) => (result, flags) = some(min_max(a, b, funct3 == 1)),
+            (0x05, 2..=3) if zfa => (result, flags) = some(min_max_propagating(a, b, funct3 == 3)), // fminm, fmaxm
+            (0x08, _) if zfa && (rs2 == 4 || rs2 == 5) => {
+                // fround, froundnx
+                (result, flags) = some(round_to_integer(a, rm, rs2 == 5));
+            }
+            (0x14, 0..=2) => {
+                // fle, flt, feq
+                let any_nan = a.is_nan() || b.is_nan();
+                let signaling = a.is_snan() || b.is_snan();
+                let value = match funct3 {
+                    0 => a <= b,
+                    1 => a < b,
+                    _ => a == b,
+                };
+                flags = if signaling || (any_nan && funct3 != 2) { NV } else { 0 };
+                set_x(self, value as u64);
+            }
+            (0x14, 4..=5) if zfa => {
+                // fleq, fltq: quiet, so only signaling NaNs raise NV
+                let value = if funct3 == 4 { a <= b } else { a < b };
+                flags = if a.is_snan() || b.is_snan() { NV } else { 0 };
+                set_x(self, value as u64);
+            }
+            (0x18, _) if rs2 <= max_int => {
+                // fcvt.w, fcvt.wu, fcvt.l, fcvt.lu
+                let (min, max) = match rs2 {
+                    0 => (i32::MIN as i128, i32::MAX as i128),
+                    1 => (0, u32::MAX as i128),
+                    2 => (i64::MIN as i128, i64::MAX as i128),
+                    _ => (0, u64::MAX as i128),
+                };
+                let (value, f) = to_int(a, rm, min, max);
+                flags = f;
+                // 32-bit results are sign-extended, even unsigned ones.
+                let value = if rs2 < 2 { value as i32 as i64 as u64 } else { value as u64 };
+                set_x(self, value);
+            }
+            (0x18, 1) if zfa && rs2 == 8 && F::BITS == 64 => {
+                // fcvtmod.w.d, with the rounding mode fixed to RTZ
+                let (value, f) = to_int_modular(a.to_f64());
+                flags = f;
+                set_x(self, value as i64 as u64);
+            }
+            (0x1a, _) if rs2 <= max_int => {
+                // fcvt.s.w, fcvt.s.wu, fcvt.s.l, fcvt.s.lu (and .d)
+                let x = self.regs[rs1];
+                let value = match rs2 {
+                    0 => x as i32 as i128,
+                    1 => x as u32 as i128,
+                    2 => x as i64 as i128,
+                    _ => x as i128,
+                };
+                (result, flags) = some(from_int::<F>(value, rm));
+            }
+            (0x1c, 0) if rs2 == 0 && F::BITS <= move_bits => {
+                // fmv.x.w, fmv.x.d
+                let raw = self.fregs[rs1];
+                let value = if F::BITS == 64 { raw } else { raw as i32 as i64 as u64 };
+                set_x(self, value);
+            }
+            (0x1c, 1) if rs2 == 0 => set_x(self, classify(a)),
+            // fmvh.x.d and fmvp.d.x move the halves of a double on RV32.
+            (0x1c, 0) if zfa && rs2 == 1 && rv32_double => set_x(self, (self.fregs[rs1] >> 32) as i32 as i64 as u64),
+            (0x16, 0) if zfa && rv32_double => {
+                self.fregs[rd] = ((self.regs[rs2] as u32 as u64) << 32) | self.regs[rs1] as u32 as u64;
+            }
+            (0x1e, 0) if zfa && rs2 == 1 => {
+                // fli
+                result = Some(match rs1 {
+                    1 => F::MIN_POSITIVE,
+                    31 => F::canonical_nan(),
+                    _ => F::from_f64(FLI_CONSTANTS[rs1]),
+                });
+            }
+            (0x1e, 0) if rs2 == 0 && F::BITS <= move_bits => {
+                // fmv.w.x, fmv.d.x
+                let x = self.regs[rs1];
+                self.fregs[rd] = if F::BITS == 64 { x } else { x | 0xffff_ffff_0000_0000 };
+            }
+            _ => return false,
+        }
+
+        if let Some(r) = result {
+            self.fregs[rd] = r.to_reg();
+        }
+        self.csrs[FCSR] |= flags;
+        true
+    }
+}
+
+fn some<F>((value, flags): (F, u64)) -> (Option<F>, u64) {
+    (Some(value), flags)
+}
+
+/// Whether an OP-FP funct5 takes a rounding mode in funct3.
+fn rounds(funct5: u32) -> bool {
+    matches!(funct5, 0x00..=0x03 | 0x08 | 0x0b | 0x18 | 0x1a)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::*;
+    use crate::testing::*;
+
+    const MODES: [u64; 5] = [RNE, RTZ, RDN, RUP, RMM];
+
+    /// A xorshift generator, for operands all over the range of a format.
+    struct Random(u64);
+
+    impl Random {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        /// Random bits, or a number of magnitude 1 to 2^16 half the time so
+        /// that sums cancel
+        fn f64(&mut self) -> f64 {
+            let bits = self.next();
+            if bits & 1 == 0 {
+                f64::from_bits(bits.rotate_right(1))
+            } else {
+                f64::from_bits((bits & 0x800f_ffff_ffff_ffff) | (1023 + (bits >> 52 & 0xf)) << 52)
+            }
+        }
+    }
+
+    /// Checks the results of an operation in every rounding mode against
+    /// its result rounded to nearest by the host.
+    fn check(host: f64, result: impl Fn(u64) -> (f64, u64)) {
+        if !host.is_finite() {
+            return;
+        }
+        let [nearest, zero, down, up, away] = MODES.map(&result);
+        assert_eq!(nearest.0.to_bits(), host.to_bits());
+        let inexact = nearest.1 & NX != 0;
+        assert!(MODES.iter().all(|&rm| (result(rm).1 & NX != 0) == inexact));
+        if !inexact {
+            assert!([zero, down, up, away].iter().all(|r| r.0 == host));
+        } else if down.0.is_finite() && up.0.is_finite() {
+            assert_eq!(down.0.next_up(), up.0, "{host:e}");
+            assert!(host == down.0 || host == up.0);
+            let toward_zero = if host.is_sign_negative() { up } else { down };
+            assert_eq!(zero.0, toward_zero.0);
+            assert!(away.0 == down.0 || away.0 == up.0);
+        }
+    }
+
+    #[test]
+    fn directed_rounding_brackets_the_nearest_result() {
+        let mut random = Random(0x2545_f491_4f6c_dd1d);
+        for _ in 0..20_000 {
+            let (a, b, c) = (random.f64(), random.f64(), random.f64());
+            check(a + b, |rm| add(a, b, rm));
+            check(a * b, |rm| mul(a, b, rm));
+            check(a / b, |rm| div(a, b, rm));
+            check(a.abs().sqrt(), |rm| sqrt(a.abs(), rm));
+            check(a.mul_add(b, c), |rm| fma(a, b, c, rm));
+            let (x, y, z) = (a as f32, b as f32, c as f32);
+            let same = |(r, _): (f32, u64), host: f32| r.to_bits() == host.to_bits() || host.is_nan();
+            assert!(same(add(x, y, RNE), x + y) && same(mul(x, y, RNE), x * y) && same(div(x, y, RNE), x / y));
+            assert!(same(sqrt(x.abs(), RNE), x.abs().sqrt()) && same(fma(x, y, z, RNE), x.mul_add(y, z)));
+            // Single-precision products are exact in double precision.
+            let exact = x as f64 * y as f64;
+            for rm in MODES {
+                let (r, flags) = mul(x, y, rm);
+                if r.is_finite() {
+                    assert_eq!(flags & NX != 0, r as f64 != exact);
+                    assert!(rm != RDN || r as f64 <= exact);
+                    assert!(rm != RUP || r as f64 >= exact);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn rounding_modes() {
+        let tiny = 2f64.powi(-60);
+        assert_eq!(add(1.0, tiny, RUP), (1f64.next_up(), NX));
+        assert_eq!(add(1.0, tiny, RNE), (1.0, NX));
+        assert_eq!(add(-1.0, -tiny, RDN), (-1f64.next_up(), NX));
+        assert_eq!(add(-1.0, -tiny, RTZ), (-1.0, NX));
+        assert_eq!(div(1f32, 3.0, RDN).0.next_up(), div(1f32, 3.0, RUP).0);
+        assert_eq!(sqrt(2f64, RUP).0, sqrt(2f64, RDN).0.next_up());
+        // 1 + 2^-24 is halfway between two floats.
+        let tie = 1.0 + 2f64.powi(-24);
+        assert_eq!(narrow(tie, RNE), (1.0, NX));
+        assert_eq!(narrow(tie, RMM), (1f32.next_up(), NX));
+        assert_eq!(narrow(-tie, RMM), (-1f32.next_up(), NX));
+        assert_eq!(from_int::<f64>((1 << 53) + 1, RUP), ((1u64 << 53) as f64 + 2.0, NX));
+        assert_eq!(from_int::<f64>(-(1 << 53) - 1, RTZ), (-((1u64 << 53) as f64), NX));
+        assert_eq!(from_int::<f32>(u64::MAX as i128, RTZ), (f32::from_bits(0x5f7f_ffff), NX));
+    }
+
+    #[test]
+    fn exact_zero_sums_are_negative_when_rounding_down() {
+        for rm in MODES {
+            let negative = rm == RDN;
+            assert_eq!(add(1.0, -1.0, rm).0.is_sign_negative(), negative);
+            assert_eq!(add(0.0, -0.0, rm).0.is_sign_negative(), negative);
+            assert_eq!(fma(2.0, 3.0, -6.0, rm).0.is_sign_negative(), negative);
+            assert!(add(-0.0, -0.0, rm).0.is_sign_negative());
+            assert!(!add(0.0, 0.0, rm).0.is_sign_negative());
+        }
+    }
+
+    #[test]
+    fn overflow_rounds_to_the_largest_number_toward_zero() {
+        assert_eq!(mul(f64::MAX, 2.0, RNE), (f64::INFINITY, OF | NX));
+        assert_eq!(mul(f64::MAX, 2.0, RTZ), (f64::MAX, OF | NX));
+        assert_eq!(mul(f64::MAX, 2.0, RDN), (f64::MAX, OF | NX));
+        assert_eq!(mul(f64::MAX, -2.0, RDN), (f64::NEG_INFINITY, OF | NX));
+        assert_eq!(mul(f64::MAX, -2.0, RUP), (-f64::MAX, OF | NX));
+        assert_eq!(add(f32::MAX, f32::MAX, RMM), (f32::INFINITY, OF | NX));
+        // Just above the largest number, which only rounding up overflows
+        assert_eq!(add(f64::MAX, 1.0, RUP), (f64::INFINITY, OF | NX));
+        assert_eq!(add(f64::MAX, 1.0, RNE), (f64::MAX, NX));
+    }
+
+    #[test]
+    fn tininess_is_detected_after_rounding() {
+        let min = f64::MIN_POSITIVE;
+        // 2^-1022 - 2^-1075 rounds up to the smallest normal number, but
+        // would not with an unbounded exponent.
+        assert_eq!(mul(min, 1f64.next_down(), RNE), (min, UF | NX));
+        // 2^-1022 - 2^-1076 would, so it is not tiny unless rounded down.
+        let (r, flags) = fma(min, -(2f64.powi(-54)), min, RNE);
+        assert_eq!((r, flags), (min, NX));
+        assert_eq!(fma(min, -(2f64.powi(-54)), min, RTZ), (min.next_down(), UF | NX));
+        // Exact subnormal results are not underflows.
+        assert_eq!(mul(min, 0.5, RNE), (min / 2.0, 0));
+    }
+
+    #[test]
+    fn double_fma_raises_inexact() {
+        assert_eq!(fma(0.1, 0.1, 1.0, RNE).1, NX);
+        // 0.1 is a little over a tenth, by exactly 2^-54 / 10.
+        assert_eq!(fma(0.1, 10.0, -1.0, RNE), (2f64.powi(-54), 0));
+        let x = 1f64.next_up();
+        assert_eq!(fma(x, x, -1.0, RNE).1, NX);
+        assert_eq!(fma(x, x, -1.0, RUP).0, fma(x, x, -1.0, RDN).0.next_up());
+        assert_eq!(fma(2.0, 3.0, 1.0, RUP), (7.0, 0));
+    }
+
+    #[test]
+    fn conversions_saturate_at_the_bounds() {
+        let (i32_min, i32_max) = (i32::MIN as i128, i32::MAX as i128);
+        let (i64_min, i64_max) = (i64::MIN as i128, i64::MAX as i128);
+        assert_eq!(to_int(2f64.powi(63), RTZ, i64_min, i64_max), (i64_max, NV));
+        assert_eq!(to_int(-(2f64.powi(63)), RTZ, i64_min, i64_max), (i64_min, 0));
+        assert_eq!(to_int(2f64.powi(63).next_down(), RTZ, i64_min, i64_max), ((1 << 63) - 1024, 0));
+        assert_eq!(to_int(2f64.powi(64), RTZ, 0, u64::MAX as i128), (u64::MAX as i128, NV));
+        assert_eq!(to_int(2f32.powi(64), RNE, 0, u64::MAX as i128), (u64::MAX as i128, NV));
+        assert_eq!(to_int(2f64.powi(31), RTZ, i32_min, i32_max), (i32_max, NV));
+        assert_eq!(to_int(2f64.powi(31) - 0.5, RTZ, i32_min, i32_max), (i32_max, NX));
+        assert_eq!(to_int(2f64.powi(31) - 0.5, RUP, i32_min, i32_max), (i32_max, NV));
+        assert_eq!(to_int(2f64.powi(32), RTZ, 0, u32::MAX as i128), (u32::MAX as i128, NV));
+        assert_eq!(to_int(2f32.powi(31), RTZ, i32_min, i32_max), (i32_max, NV));
+        assert_eq!(to_int(-1.0f64, RTZ, 0, u32::MAX as i128), (0, NV));
+        assert_eq!(to_int(-0.5f64, RTZ, 0, u32::MAX as i128), (0, NX));
+        assert_eq!(to_int(f64::NAN, RTZ, i32_min, i32_max), (i32_max, NV));
+    }
+
+    #[test]
+    fn conversions_round_in_every_mode() {
+        let expected = [(RNE, 2, -2), (RTZ, 2, -2), (RDN, 2, -3), (RUP, 3, -2), (RMM, 3, -3)];
+        for (rm, up, down) in expected {
+            assert_eq!(to_int(2.5f64, rm, i64::MIN as i128, i64::MAX as i128), (up, NX));
+            assert_eq!(to_int(-2.5f32, rm, i64::MIN as i128, i64::MAX as i128), (down, NX));
+        }
+    }
+
+    #[test]
+    fn instructions_use_their_rounding_mode_or_frm() {
+        let mut machine = machine(&MachineConfig::default(), &[]);
+        let cpu = &mut machine.harts[0];
+        cpu.csrs[MSTATUS] |= MSTATUS_FS;
+        cpu.fregs[1] = 1f64.to_reg();
+        cpu.fregs[2] = 3f64.to_reg();
+        // fdiv.d f3, f1, f2 with rm in funct3
+        let fdiv = |rm: u32| 0x0d << 25 | 2 << 20 | 1 << 15 | rm << 12 | 3 << 7 | 0x53;
+        cpu.execute(fdiv(RUP as u32)).unwrap();
+        let up = f64::from_reg(cpu.fregs[3]);
+        cpu.store_csr(FRM, RDN);
+        cpu.execute(fdiv(DYN as u32)).unwrap();
+        assert_eq!(f64::from_reg(cpu.fregs[3]).next_up(), up);
+        cpu.store_csr(FRM, 5);
+        assert!(cpu.execute(fdiv(DYN as u32)).is_err());
+    }
+
+    #[test]
+    fn zfa_rounds_and_converts_modulo() {
+        assert_eq!(round_to_integer(2.5f64, RNE, false), (2.0, 0));
+        assert_eq!(round_to_integer(2.5f64, RNE, true), (2.0, NX));
+        assert_eq!(round_to_integer(-2.5f32, RMM, true), (-3.0, NX));
+        assert_eq!(round_to_integer(2.0f64, RUP, true), (2.0, 0));
+        assert_eq!(round_to_integer(f64::NEG_INFINITY, RNE, true), (f64::NEG_INFINITY, 0));
+        let snan = f64::from_bits(0x7ff0_0000_0000_0001);
+        assert_eq!(round_to_integer(snan, RNE, false).1, NV);
+        assert!(round_to_integer(snan, RNE, false).0.to_bits() == f64::canonical_nan().to_bits());
+
+        assert_eq!(to_int_modular(-1.5), (-1, NX));
+        assert_eq!(to_int_modular(2f64.powi(32) + 5.5), (5, NV));
+        assert_eq!(to_int_modular(2f64.powi(70) + 2f64.powi(20)), (1 << 20, NV));
+        assert_eq!(to_int_modular(-(2f64.powi(70) + 2f64.powi(20))), (-(1 << 20), NV));
+        assert_eq!(to_int_modular(2f64.powi(80)), (0, NV));
+        assert_eq!(to_int_modular(f64::NAN), (0, NV));
+        assert_eq!(to_int_modular(f64::INFINITY), (0, NV));
+
+        let (r, flags) = min_max_propagating(1.0f32, f32::NAN, false);
+        assert_eq!((r.to_bits(), flags), (f32::canonical_nan().to_bits(), 0));
+        assert_eq!(min_max_propagating(snan, 1.0, true).1, NV);
+        assert!(min_max_propagating(-0.0f64, 0.0, false).0.is_sign_negative());
+        assert_eq!(min_max_propagating(-0.0f64, 0.0, true), (0.0, 0));
+    }
+
+    #[test]
+    fn zfa_instructions() {
+        let mut rv64 = machine(&MachineConfig::default(), &[]);
+        let cpu = &mut rv64.harts[0];
+        cpu.csrs[MSTATUS] |= MSTATUS_FS;
+        let fp = |funct7: u32, rd: u32, rs1: u32, rs2: u32, funct3: u32| r_type(0x53, rd, funct3, rs1, rs2, funct7);
+        // fli.d and fli.s
+        for (rs1, value) in [(0, -1.0), (2, 2f64.powi(-16)), (13, 0.625), (29, 65536.0), (30, f64::INFINITY)] {
+            cpu.execute(fp(0x79, 1, rs1, 1, 0)).unwrap();
+            assert_eq!(f64::from_reg(cpu.fregs[1]), value);
+            cpu.execute(fp(0x78, 1, rs1, 1, 0)).unwrap();
+            assert_eq!(f32::from_reg(cpu.fregs[1]), value as f32);
+        }
+        cpu.execute(fp(0x79, 1, 1, 1, 0)).unwrap();
+        assert_eq!(f64::from_reg(cpu.fregs[1]), f64::MIN_POSITIVE);
+        cpu.execute(fp(0x78, 1, 31, 1, 0)).unwrap();
+        assert_eq!(cpu.fregs[1], f32::canonical_nan().to_reg());
+
+        // fleq.d and fltq.d raise nothing for quiet NaNs, unlike fle.d.
+        cpu.fregs[2] = 1f64.to_reg();
+        cpu.store_csr(FFLAGS, 0);
+        cpu.execute(fp(0x51, A0, 1, 2, 4)).unwrap();
+        cpu.execute(fp(0x51, A0, 1, 2, 5)).unwrap();
+        assert_eq!((cpu.regs[A0 as usize], cpu.load_csr(FFLAGS)), (0, 0));
+        cpu.execute(fp(0x51, A0, 1, 2, 0)).unwrap();
+        assert_eq!(cpu.load_csr(FFLAGS), NV);
+
+        // fcvtmod.w.d
+        cpu.fregs[1] = (-(2f64.powi(33)) - 7.0).to_reg();
+        cpu.execute(fp(0x61, A0, 1, 8, 1)).unwrap();
+        assert_eq!(cpu.regs[A0 as usize], -7i64 as u64);
+
+        // fmvh.x.d and fmvp.d.x are RV32 only.
+        let inst = fp(0x71, A0, 1, 1, 0);
+        assert_eq!(cpu.execute(inst), Err(Exception::IllegalInstruction(inst as u64)));
+        let config = MachineConfig { xlen: Some(32), ..Default::default() };
+        let mut rv32 = machine(&config, &[]);
+        let cpu = &mut rv32.harts[0];
+        cpu.csrs[MSTATUS] |= MSTATUS_FS;
+        cpu.regs[T0 as usize] = 0x5555_4444;
+        cpu.regs[T1 as usize] = 0xffff_ffff_9999_8888;
+        cpu.execute(fp(0x59, 3, T0, T1, 0)).unwrap();
+        assert_eq!(cpu.fregs[3], 0x9999_8888_5555_4444);
+        cpu.execute(fp(0x71, A0, 3, 1, 0)).unwrap();
+        assert_eq!(cpu.truncate(cpu.regs[A0 as usize]), 0x9999_8888);
+    }
+}
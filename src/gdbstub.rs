@@ -0,0 +1,505 @@
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use crate::cpu::*;
+use crate::debugger::*;
+
+/// Largest packet we accept, as advertised to GDB.
+const PACKET_SIZE: usize = 0x4000;
+
+/// GDB numbers the CSRs from here, in address order.
+const FIRST_CSR: usize = 65;
+/// GDB's pseudo-register for the privilege mode.
+const PRIV: usize = FIRST_CSR + 4096;
+
+/// How the session ended.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Detach {
+    /// The guest exited or every hart stopped.
+    Stopped,
+    /// GDB killed the machine or went away.
+    Killed,
+    /// GDB detached; the machine runs on without it.
+    Running,
+}
+
+/// A GDB remote serial protocol server. Every hart is a thread, numbered
+/// from 1, and the machine stops as a whole.
+pub struct GdbStub {
+    stream: TcpStream,
+    debugger: Debugger,
+    /// The hart register accesses go to.
+    hart: usize,
+    acks: bool,
+}
+
+impl GdbStub {
+    /// Waits for GDB to connect on `address`.
+    pub fn listen(address: &str, debugger: Debugger) -> io::Result<Self> {
+        let listener = TcpListener::bind(address)?;
+        eprintln!("rvemu: waiting for GDB on {}", listener.local_addr()?);
+        let (stream, _) = listener.accept()?;
+        stream.set_nodelay(true)?;
+        let hart = debugger.hart;
+        Ok(Self {
+            stream,
+            debugger,
+            hart,
+            acks: true,
+        })
+    }
+
+    pub fn into_debugger(self) -> Debugger {
+        self.debugger
+    }
+
+    /// Serves GDB until it detaches, kills the machine or the guest exits.
+    pub fn serve(&mut self) -> io::Result<Detach> {
+        loop {
+            let Some(packet) = self.receive()? else {
+                return Ok(Detach::Killed);
+            };
+            let (reply, detach) = self.handle(&packet);
+            self.send(&reply)?;
+            if let Some(detach) = detach {
+                return Ok(detach);
+            }
+        }
+    }
+
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        let mut byte = [0];
+        match self.stream.read(&mut byte)? {
+            0 => Ok(None),
+            _ => Ok(Some(byte[0])),
+        }
+    }
+
+    /// Reads the next packet, or `None` once GDB has gone.
+    fn receive(&mut self) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            // Acks and stray interrupts between packets are skipped.
+            match self.read_byte()? {
+                None => return Ok(None),
+                Some(b'$') => {}
+                Some(_) => continue,
+            }
+            let mut packet = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    None => return Ok(None),
+                    Some(b'#') => break,
+                    Some(byte) => packet.push(byte),
+                }
+            }
+            let mut checksum = [0; 2];
+            self.stream.read_exact(&mut checksum)?;
+            let valid = std::str::from_utf8(&checksum)
+                .ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                == Some(packet.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)));
+            if self.acks {
+                self.stream.write_all(if valid { b"+" } else { b"-" })?;
+            }
+            if valid {
+                return Ok(Some(unescape(&packet)));
+            }
+        }
+    }
+
+    fn send(&mut self, reply: &str) -> io::Result<()> {
+        let checksum = reply.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+        let packet = format!("${}#{:02x}", reply, checksum);
+        loop {
+            self.stream.write_all(packet.as_bytes())?;
+            if !self.acks || self.read_byte()? != Some(b'-') {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Whether GDB sent an interrupt (Ctrl-C) while the machine runs.
+    fn interrupted(stream: &mut TcpStream) -> bool {
+        let mut byte = [0];
+        if stream.set_nonblocking(true).is_err() {
+            return false;
+        }
+        let interrupted = matches!(stream.read(&mut byte), Ok(1) if byte[0] == 0x03);
+        let _ = stream.set_nonblocking(false);
+        interrupted
+    }
+
+    /// Answers a packet. Unsupported requests get an empty reply.
+    fn handle(&mut self, packet: &[u8]) -> (String, Option<Detach>) {
+        let text = String::from_utf8_lossy(packet);
+        let (command, args) = text.split_at(text.len().min(1));
+        let reply = match command {
+            "?" => self.stop_reply(Stop::Interrupted),
+            "g" => (0..33).map(|reg| self.hex_register(reg, self.read_register(reg).unwrap())).collect(),
+            "G" => {
+                let values = decode_hex(args).unwrap_or_default();
+                let size = self.register_size(0);
+                for (reg, value) in values.chunks_exact(size).take(33).enumerate() {
+                    let mut bytes = value.to_vec();
+                    bytes.resize(8, 0);
+                    self.write_register(reg, u64::from_le_bytes(bytes.try_into().unwrap()));
+                }
+                self.debugger.modified();
+                String::from("OK")
+            }
+            "p" => {
+                let reg = usize::from_str_radix(args, 16).ok();
+                match reg.and_then(|reg| Some((reg, self.read_register(reg)?))) {
+                    Some((reg, value)) => self.hex_register(reg, value),
+                    None => String::from("E01"),
+                }
+            }
+            "P" => {
+                let written = args.split_once('=').and_then(|(reg, value)| {
+                    let reg = usize::from_str_radix(reg, 16).ok()?;
+                    let mut bytes = decode_hex(value)?;
+                    bytes.resize(8, 0);
+                    self.write_register(reg, u64::from_le_bytes(bytes.try_into().unwrap()))
+                });
+                self.debugger.modified();
+                ok_or_error(written)
+            }
+            "m" => {
+                let read = parse_range(args)
+                    .and_then(|(addr, len)| self.debugger.read_memory(self.hart, addr, len.min(PACKET_SIZE as u64 / 2)));
+                read.map_or(String::from("E14"), |bytes| hex_bytes(&bytes))
+            }
+            "M" => {
+                let written = args.split_once(':').and_then(|(range, data)| {
+                    let (addr, _) = parse_range(range)?;
+                    self.debugger.write_memory(self.hart, addr, &decode_hex(data)?)
+                });
+                ok_or_error(written)
+            }
+            "Z" | "z" => self.breakpoint(command == "Z", args),
+            "H" => {
+                // Hg and Hc: 0 and -1 mean any hart.
+                match args.get(1..).and_then(parse_thread) {
+                    Some(hart) if hart >= self.debugger.machine.harts.len() => String::from("E01"),
+                    Some(hart) => {
+                        self.hart = hart;
+                        String::from("OK")
+                    }
+                    None => String::from("OK"),
+                }
+            }
+            "T" => ok_or_error(parse_thread(args).filter(|&hart| hart < self.debugger.machine.harts.len())),
+            "s" | "c" => {
+                let hart = (command == "s").then_some(self.hart);
+                let stream = &mut self.stream;
+                let stop = self.debugger.resume(hart, &mut || Self::interrupted(stream));
+                return self.stopped(stop);
+            }
+            "b" => {
+                let stop = match args {
+                    "s" => self.debugger.reverse(Some(self.hart)),
+                    "c" => self.debugger.reverse(None),
+                    _ => return (String::new(), None),
+                };
+                return self.stopped(stop);
+            }
+            "D" => return (String::from("OK"), Some(Detach::Running)),
+            "k" => return (String::from("OK"), Some(Detach::Killed)),
+            "q" | "Q" | "v" => self.query(&text),
+            _ => String::new(),
+        };
+        (reply, None)
+    }
+
+    fn query(&mut self, text: &str) -> String {
+        if text.starts_with("qSupported") {
+            return format!(
+                "PacketSize={:x};qXfer:features:read+;swbreak+;hwbreak+;ReverseStep+;ReverseContinue+;QStartNoAckMode+",
+                PACKET_SIZE
+            );
+        }
+        if let Some(request) = text.strip_prefix("qXfer:features:read:target.xml:") {
+            let Some((offset, len)) = parse_range(request) else {
+                return String::from("E00");
+            };
+            let xml = target_description(self.debugger.machine.harts[self.hart].xlen);
+            let start = usize::try_from(offset).map_or(xml.len(), |offset| offset.min(xml.len()));
+            let end = usize::try_from(len).map_or(xml.len(), |len| start.saturating_add(len).min(xml.len()));
+            let marker = if end == xml.len() { 'l' } else { 'm' };
+            return format!("{}{}", marker, &xml[start..end]);
+        }
+        match text {
+            "QStartNoAckMode" => {
+                self.acks = false;
+                String::from("OK")
+            }
+            "qAttached" => String::from("1"),
+            "qC" => format!("QC{:x}", self.debugger.hart + 1),
+            "qfThreadInfo" => {
+                let threads: Vec<String> = (1..=self.debugger.machine.harts.len()).map(|id| format!("{:x}", id)).collect();
+                format!("m{}", threads.join(","))
+            }
+            "qsThreadInfo" => String::from("l"),
+            _ => String::new(),
+        }
+    }
+
+    /// Inserts or removes a breakpoint or watchpoint: `<type>,<addr>,<kind>`.
+    fn breakpoint(&mut self, insert: bool, args: &str) -> String {
+        let mut fields = args.splitn(3, ',');
+        let (Some(kind), Some(addr), Some(len)) = (fields.next(), fields.next(), fields.next()) else {
+            return String::from("E01");
+        };
+        let (Ok(addr), Ok(len)) = (u64::from_str_radix(addr, 16), u64::from_str_radix(len, 16)) else {
+            return String::from("E01");
+        };
+        let kind = match kind {
+            // Software and hardware breakpoints are the same here: nothing
+            // is written to memory.
+            "0" | "1" => {
+                if insert {
+                    self.debugger.breakpoints.insert(addr);
+                } else {
+                    self.debugger.breakpoints.remove(&addr);
+                }
+                return String::from("OK");
+            }
+            "2" => WatchKind::Write,
+            "3" => WatchKind::Read,
+            "4" => WatchKind::Access,
+            _ => return String::new(),
+        };
+        let watch = Watchpoint { addr, len, kind };
+        if insert {
+            self.debugger.add_watchpoint(watch);
+        } else {
+            self.debugger.remove_watchpoint(watch);
+        }
+        String::from("OK")
+    }
+
+    fn stopped(&mut self, stop: Stop) -> (String, Option<Detach>) {
+        match stop {
+            Stop::Exited(status) => (format!("W{:02x}", status as u8), Some(Detach::Stopped)),
+            Stop::Halted => (String::from("W00"), Some(Detach::Stopped)),
+            _ => {
+                self.hart = self.debugger.hart;
+                (self.stop_reply(stop), None)
+            }
+        }
+    }
+
+    fn stop_reply(&self, stop: Stop) -> String {
+        let signal = if stop == Stop::Interrupted { 2 } else { 5 };
+        let reason = match stop {
+            Stop::Breakpoint => String::from("swbreak:;"),
+            Stop::Watchpoint(watch) => {
+                let kind = match watch.kind {
+                    WatchKind::Write => "watch",
+                    WatchKind::Read => "rwatch",
+                    WatchKind::Access => "awatch",
+                };
+                format!("{}:{:x};", kind, watch.addr)
+            }
+            Stop::HistoryStart => String::from("replaylog:begin;"),
+            _ => String::new(),
+        };
+        format!("T{:02x}thread:{:x};{}", signal, self.debugger.hart + 1, reason)
+    }
+
+    fn read_register(&self, reg: usize) -> Option<u64> {
+        let hart = &self.debugger.machine.harts[self.hart];
+        match reg {
+            0..=31 => Some(hart.truncate(hart.regs[reg])),
+            32 => Some(hart.pc),
+            33..=64 => Some(hart.fregs[reg - 33]),
+            PRIV => Some(hart.mode),
+            FIRST_CSR..PRIV => Some(hart.load_csr(reg - FIRST_CSR)),
+            _ => None,
+        }
+    }
+
+    fn write_register(&mut self, reg: usize, value: u64) -> Option<()> {
+        let hart = &mut self.debugger.machine.harts[self.hart];
+        match reg {
+            0 => {}
+            1..=31 => hart.regs[reg] = hart.sign_extend(value),
+            32 => hart.pc = hart.truncate(value),
+            33..=64 => hart.fregs[reg - 33] = value,
+            // Mode 2 is reserved.
+            PRIV if value & 3 == 2 => return None,
+            PRIV => hart.mode = value & 3,
+            FIRST_CSR..PRIV => hart.store_csr(reg - FIRST_CSR, value),
+            _ => return None,
+        }
+        Some(())
+    }
+
+    /// Bytes of a register in the target description: the floating-point
+    /// registers are 64 bits wide and their CSRs 32, the rest XLEN.
+    fn register_size(&self, reg: usize) -> usize {
+        match reg.checked_sub(FIRST_CSR) {
+            Some(FFLAGS | FRM | FCSR) => 4,
+            _ if (33..=64).contains(&reg) => 8,
+            _ => self.debugger.machine.harts[self.hart].xlen as usize / 8,
+        }
+    }
+
+    /// Registers go over the wire in target byte order.
+    fn hex_register(&self, reg: usize, value: u64) -> String {
+        hex_bytes(&value.to_le_bytes()[..self.register_size(reg)])
+    }
+}
+
+/// Describes the registers to GDB in its numbering.
+fn target_description(xlen: u32) -> String {
+    let mut xml = format!(
+        "<?xml version=\"1.0\"?><!DOCTYPE target SYSTEM \"gdb-target.dtd\"><target version=\"1.0\">\
+         <architecture>riscv:rv{}</architecture><feature name=\"org.gnu.gdb.riscv.cpu\">",
+        xlen
+    );
+    for reg in 0..32 {
+        xml += &format!("<reg name=\"x{}\" bitsize=\"{}\" type=\"int\" regnum=\"{}\"/>", reg, xlen, reg);
+    }
+    xml += &format!("<reg name=\"pc\" bitsize=\"{}\" type=\"code_ptr\" regnum=\"32\"/></feature>", xlen);
+    xml += "<feature name=\"org.gnu.gdb.riscv.fpu\">";
+    for reg in 0..32 {
+        xml += &format!("<reg name=\"f{}\" bitsize=\"64\" type=\"ieee_double\" regnum=\"{}\"/>", reg, 33 + reg);
+    }
+    for (name, csr) in [("fflags", FFLAGS), ("frm", FRM), ("fcsr", FCSR)] {
+        xml += &format!("<reg name=\"{}\" bitsize=\"32\" type=\"int\" regnum=\"{}\"/>", name, FIRST_CSR + csr);
+    }
+    xml += "</feature><feature name=\"org.gnu.gdb.riscv.csr\">";
+    for (name, csr) in CSR_NAMES.iter().filter(|(_, csr)| !matches!(*csr, FFLAGS | FRM | FCSR)) {
+        xml += &format!("<reg name=\"{}\" bitsize=\"{}\" type=\"int\" regnum=\"{}\"/>", name, xlen, FIRST_CSR + csr);
+    }
+    xml += "</feature><feature name=\"org.gnu.gdb.riscv.virtual\">";
+    xml += &format!("<reg name=\"priv\" bitsize=\"{}\" type=\"int\" regnum=\"{}\"/>", xlen, PRIV);
+    xml += "</feature></target>";
+    xml
+}
+
+/// Undoes the `}` escaping of binary data.
+fn unescape(packet: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(packet.len());
+    let mut bytes = packet.iter();
+    while let Some(&byte) = bytes.next() {
+        match byte {
+            b'}' => out.extend(bytes.next().map(|byte| byte ^ 0x20)),
+            _ => out.push(byte),
+        }
+    }
+    out
+}
+
+/// A thread id, hart + 1. `0` and `-1` (any and all) give `None`.
+fn parse_thread(text: &str) -> Option<usize> {
+    usize::from_str_radix(text, 16).ok().filter(|&id| id > 0).map(|id| id - 1)
+}
+
+/// `<addr>,<len>` in hex.
+fn parse_range(text: &str) -> Option<(u64, u64)> {
+    let (addr, len) = text.split_once(',')?;
+    Some((u64::from_str_radix(addr, 16).ok()?, u64::from_str_radix(len, 16).ok()?))
+}
+
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    (0..text.len() / 2)
+        .map(|i| u8::from_str_radix(text.get(2 * i..2 * i + 2)?, 16).ok())
+        .collect()
+}
+
+fn hex_bytes(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn ok_or_error<T>(result: Option<T>) -> String {
+    match result {
+        Some(_) => String::from("OK"),
+        None => String::from("E01"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::*;
+    use crate::testing::*;
+
+    /// A stub for a machine running `program`, and GDB's end of its connection.
+    fn connect(program: &[u32]) -> (GdbStub, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let gdb = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let debugger = Debugger::new(machine(&MachineConfig::default(), program), 1, 16);
+        let stub = GdbStub { stream, debugger, hart: 0, acks: true };
+        (stub, gdb)
+    }
+
+    fn packet(text: &str) -> String {
+        format!("${}#{:02x}", text, text.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte)))
+    }
+
+    fn reply(stub: &mut GdbStub, text: &str) -> String {
+        stub.handle(text.as_bytes()).0
+    }
+
+    #[test]
+    fn packets_are_checked_and_acknowledged() {
+        let (mut stub, mut gdb) = connect(&[]);
+        let requests = format!("$qC#00{}+{}+", packet("qAttached"), packet("k"));
+        gdb.write_all(requests.as_bytes()).unwrap();
+        assert_eq!(stub.serve().unwrap(), Detach::Killed);
+        drop(stub);
+        let mut replies = String::new();
+        gdb.read_to_string(&mut replies).unwrap();
+        assert_eq!(replies, format!("-+{}+{}", packet("1"), packet("OK")));
+    }
+
+    #[test]
+    fn registers_memory_and_breakpoints() {
+        let program = [addi(T0, ZERO, 7), addi(T0, T0, 1), addi(T0, T0, 1), jalr(ZERO, ZERO, 0)];
+        let (mut stub, _gdb) = connect(&program);
+        assert!(reply(&mut stub, "qSupported:swbreak+").contains(";ReverseStep+;ReverseContinue+;"));
+        assert_eq!(reply(&mut stub, "qfThreadInfo"), "m1");
+        assert!(reply(&mut stub, "qXfer:features:read:target.xml:0,40").starts_with("m<?xml"));
+        assert_eq!(reply(&mut stub, "p20"), "0000008000000000");
+        assert_eq!(reply(&mut stub, "m80000000,4"), hex_bytes(&program[0].to_le_bytes()));
+        assert_eq!(reply(&mut stub, "m0,4"), "E14");
+
+        assert_eq!(reply(&mut stub, "Z0,80000008,4"), "OK");
+        assert_eq!(reply(&mut stub, "c"), "T05thread:1;swbreak:;");
+        assert_eq!(reply(&mut stub, "p5"), "0800000000000000");
+        assert_eq!(reply(&mut stub, "bs"), "T05thread:1;");
+        assert_eq!(reply(&mut stub, "p5"), "0700000000000000");
+        assert_eq!(reply(&mut stub, "P5=2a00000000000000"), "OK");
+        assert_eq!(reply(&mut stub, "s"), "T05thread:1;");
+        assert_eq!(reply(&mut stub, "p5"), "2b00000000000000");
+        // x0 stays zero, and unknown registers are refused.
+        assert_eq!(reply(&mut stub, "P0=0100000000000000"), "OK");
+        assert_eq!(reply(&mut stub, "p0"), "0000000000000000");
+        assert_eq!(reply(&mut stub, "p2000"), "E01");
+
+        assert_eq!(reply(&mut stub, "z0,80000008,4"), "OK");
+        assert_eq!(stub.handle(b"c"), (String::from("W00"), Some(Detach::Stopped)));
+        assert_eq!(reply(&mut stub, "p5"), "2c00000000000000");
+    }
+    #[test]
+    fn threads_and_ranges_are_checked() {
+        let (mut stub, _gdb) = connect(&[SPIN]);
+        assert_eq!(reply(&mut stub, "Hg5"), "E01");
+        assert_eq!(reply(&mut stub, "Hc-1"), "OK");
+        assert_eq!(reply(&mut stub, "Hg1"), "OK");
+        assert_eq!(reply(&mut stub, "p20"), "0000008000000000");
+        assert_eq!(reply(&mut stub, "T2"), "E01");
+        assert_eq!(reply(&mut stub, &format!("P{:x}=0200000000000000", PRIV)), "E01");
+        assert_eq!(reply(&mut stub, &format!("P{:x}=0100000000000000", PRIV)), "OK");
+        assert_eq!(reply(&mut stub, &format!("p{:x}", PRIV)), "0100000000000000");
+
+        let whole = reply(&mut stub, "qXfer:features:read:target.xml:0,100000");
+        assert!(whole.starts_with("l<?xml") && whole.ends_with("</target>"));
+        let tail = reply(&mut stub, "qXfer:features:read:target.xml:10,ffffffffffffffff");
+        assert_eq!(tail, format!("l{}", &whole[0x11..]));
+        assert_eq!(reply(&mut stub, "qXfer:features:read:target.xml:ffffffffffffffff,10"), "l");
+    }
+}
@@ -0,0 +1,271 @@
+use std::io::{self, Read, Write};
+
+use crate::cpu::*;
+use crate::replay::*;
+use crate::syscall::*;
+
+// HTIF devices
+const DEVICE_SYSCALL: u64 = 0;
+const DEVICE_CONSOLE: u64 = 1;
+
+/// Host-target interface of Spike's frontend server.
+///
+/// The guest writes a command to `tohost` and polls `fromhost` for the answer.
+/// A command packs a device in bits 63:56, a command in bits 55:48 and a
+/// payload in bits 47:0. Device 0 proxies riscv-pk style system calls (the
+/// payload points to eight words holding the number and arguments, or is odd
+/// to exit), device 1 is a character console.
+pub struct Htif {
+    pub tohost: u64,
+    pub fromhost: Option<u64>,
+    /// `argv` as reported to `getmainvars`.
+    args: Vec<String>,
+    linux: Linux,
+    /// The console's input.
+    stdin: HostInput,
+    /// Console output is dropped while set.
+    muted: bool,
+}
+
+impl Htif {
+    pub fn new(tohost: u64, fromhost: Option<u64>, args: Vec<String>, strace: bool, log: Option<&SharedLog>) -> Self {
+        Self {
+            tohost,
+            fromhost,
+            args,
+            linux: Linux::new(strace, HostInput::new("htif-stdin", log)),
+            stdin: HostInput::new("htif", log),
+            muted: false,
+        }
+    }
+
+    /// Stops or resumes console output and writes to stdout and stderr, like
+    /// `Device::mute`.
+    pub fn mute(&mut self, muted: bool) {
+        self.muted = muted;
+        self.linux.mute(muted);
+    }
+
+    /// Services a pending command, if any. Returns the exit status once the
+    /// guest has asked to exit.
+    pub fn poll(&mut self, cpu: &mut Cpu) -> Option<i32> {
+        let command = match cpu.load(self.tohost, 64) {
+            Ok(0) | Err(_) => return None,
+            Ok(command) => command,
+        };
+        let _ = cpu.store(self.tohost, 64, 0);
+
+        let device = command >> 56;
+        let cmd = (command >> 48) & 0xff;
+        let payload = command & 0xffff_ffff_ffff;
+
+        let response = match (device, cmd) {
+            (DEVICE_SYSCALL, 0) if payload & 1 == 1 => return Some((payload >> 1) as i32),
+            (DEVICE_SYSCALL, 0) => {
+                if let Some(status) = self.syscall(cpu, payload) {
+                    return Some(status);
+                }
+                1
+            }
+            (DEVICE_CONSOLE, 0) => {
+                let byte = self.stdin.read(|| {
+                    let mut byte = vec![0];
+                    match io::stdin().read(&mut byte) {
+                        Ok(1) => byte,
+                        _ => Vec::new(),
+                    }
+                });
+                byte.first().map_or(0xffff_ffff_ffff, |&byte| byte as u64)
+            }
+            (DEVICE_CONSOLE, 1) if self.muted => 0,
+            (DEVICE_CONSOLE, 1) => {
+                let mut stdout = io::stdout();
+                let _ = stdout.write_all(&[payload as u8]).and(stdout.flush());
+                0
+            }
+            _ => {
+                eprintln!("htif: unknown command {:#x}", command);
+                return None;
+            }
+        };
+
+        if let Some(fromhost) = self.fromhost {
+            let _ = cpu.store(fromhost, 64, (device << 56) | (cmd << 48) | response);
+        }
+        None
+    }
+
+    /// Runs the system call described by the eight words at `magic_mem` and
+    /// writes the result back to the first word.
+    fn syscall(&mut self, cpu: &mut Cpu, magic_mem: u64) -> Option<i32> {
+        let mut words = [0; 8];
+        for (i, word) in words.iter_mut().enumerate() {
+            *word = cpu.load(magic_mem + i as u64 * 8, 64).unwrap_or(0);
+        }
+        let (number, a) = (words[0], &words[1..]);
+
+        // The frontend server passes paths as (pointer, length) pairs. The
+        // strings are NUL-terminated as well, so drop the lengths and reuse
+        // the Linux system calls.
+        let (number, args) = match number {
+            93 => return Some(a[0] as i32), // exit
+            34 | 35 | 48 => (number, [a[0], a[1], a[3], 0, 0, 0]),
+            56 | 79 => (number, [a[0], a[1], a[3], a[4], 0, 0]),
+            1024 => (56, [AT_FDCWD as u64, a[0], a[2], a[3], 0, 0]), // open
+            1039 => (79, [AT_FDCWD as u64, a[0], a[2], 0, 0, 0]),    // lstat
+            _ => (number, [a[0], a[1], a[2], a[3], a[4], a[5]]),
+        };
+        let ret = match number {
+            17 | 25 | 34 | 35 | 48 | 56 | 57 | 62 | 63 | 64 | 67 | 68 | 79 | 80 => {
+                self.linux.dispatch(cpu, number, &args)
+            }
+            2011 => self.getmainvars(cpu, args[0], args[1]),
+            _ => -ENOSYS,
+        };
+        self.linux.trace(number, &args, ret);
+
+        let _ = cpu.store(magic_mem, 64, ret as u64);
+        None
+    }
+
+    /// Writes argc, argv and an empty envp followed by the strings into the
+    /// guest buffer, the way riscv-pk expects them.
+    fn getmainvars(&self, cpu: &mut Cpu, buf: u64, limit: u64) -> i64 {
+        let mut words = vec![self.args.len() as u64];
+        let mut strings = Vec::new();
+        let mut offset = (self.args.len() as u64 + 3) * 8;
+        for arg in &self.args {
+            words.push(buf + offset);
+            strings.extend(arg.as_bytes());
+            strings.push(0);
+            offset += arg.len() as u64 + 1;
+        }
+        words.push(0); // argv[argc]
+        words.push(0); // envp[0]
+
+        let mut bytes: Vec<u8> = words.iter().flat_map(|w| w.to_le_bytes()).collect();
+        bytes.extend(strings);
+        if bytes.len() as u64 > limit {
+            return -ENOMEM;
+        }
+
+        match cpu.bus.lock().unwrap().write_bytes(buf, &bytes) {
+            Ok(_) => 0,
+            Err(_) => -EFAULT,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::bus::DRAM_BASE;
+    use crate::config::*;
+    use crate::testing::*;
+
+    const TOHOST: u64 = DRAM_BASE + 0x1000;
+    const FROMHOST: u64 = DRAM_BASE + 0x1008;
+    const MAGIC: u64 = DRAM_BASE + 0x2000;
+    const BUF: u64 = DRAM_BASE + 0x3000;
+
+    fn htif(args: &[&str]) -> (Htif, Cpu) {
+        let cpu = machine(&MachineConfig::default(), &[SPIN]).harts.remove(0);
+        let mut htif = Htif::new(TOHOST, Some(FROMHOST), args.iter().map(|arg| arg.to_string()).collect(), false, None);
+        htif.mute(true);
+        (htif, cpu)
+    }
+
+    /// Makes the system call `number` through the frontend server, returning
+    /// what it wrote back.
+    fn syscall(htif: &mut Htif, cpu: &mut Cpu, number: u64, args: &[u64]) -> i64 {
+        for (i, &word) in std::iter::once(&number).chain(args).enumerate() {
+            cpu.store(MAGIC + i as u64 * 8, 64, word).unwrap();
+        }
+        cpu.store(TOHOST, 64, MAGIC).unwrap();
+        assert_eq!(htif.poll(cpu), None);
+        assert_eq!(cpu.load(TOHOST, 64), Ok(0));
+        assert_eq!(cpu.load(FROMHOST, 64), Ok(1));
+        cpu.store(FROMHOST, 64, 0).unwrap();
+        cpu.load(MAGIC, 64).unwrap() as i64
+    }
+
+    #[test]
+    fn exits_with_the_status() {
+        let (mut htif, mut cpu) = htif(&[]);
+        assert_eq!(htif.poll(&mut cpu), None);
+        cpu.store(TOHOST, 64, 3 << 1 | 1).unwrap();
+        assert_eq!(htif.poll(&mut cpu), Some(3));
+
+        // As does the exit system call
+        cpu.store(MAGIC, 64, 93).unwrap();
+        cpu.store(MAGIC + 8, 64, 7).unwrap();
+        cpu.store(TOHOST, 64, MAGIC).unwrap();
+        assert_eq!(htif.poll(&mut cpu), Some(7));
+    }
+
+    #[test]
+    fn proxies_file_system_calls() {
+        let (mut htif, mut cpu) = htif(&[]);
+        let path = temp_path("htif.txt");
+        fs::write(&path, "hello, htif").unwrap();
+        let name = path.to_str().unwrap();
+        cpu.bus.lock().unwrap().write_bytes(BUF, format!("{}\0", name).as_bytes()).unwrap();
+        let len = name.len() as u64 + 1;
+
+        // Paths come with their lengths, which are dropped.
+        let fd = syscall(&mut htif, &mut cpu, 1024, &[BUF, len, 0, 0]);
+        assert!(fd >= 3, "{}", fd);
+        assert_eq!(syscall(&mut htif, &mut cpu, 63, &[fd as u64, BUF + 0x100, 5]), 5);
+        assert_eq!(cpu.bus.lock().unwrap().read_bytes(BUF + 0x100, 5), Ok(b"hello".to_vec()));
+        assert_eq!(syscall(&mut htif, &mut cpu, 57, &[fd as u64]), 0);
+        assert_eq!(syscall(&mut htif, &mut cpu, 1039, &[BUF, len, BUF + 0x200]), 0);
+        // st_size
+        assert_eq!(cpu.load(BUF + 0x200 + 48, 64), Ok(11));
+
+        fs::remove_file(&path).unwrap();
+        assert_eq!(syscall(&mut htif, &mut cpu, 1024, &[BUF, len, 0, 0]), -ENOENT);
+        assert_eq!(syscall(&mut htif, &mut cpu, 12345, &[]), -ENOSYS);
+    }
+
+    #[test]
+    fn reports_the_arguments() {
+        let (mut htif, mut cpu) = htif(&["prog", "-v"]);
+        assert_eq!(syscall(&mut htif, &mut cpu, 2011, &[BUF, 0x100]), 0);
+        // argc, argv with its NULL, an empty envp, then the strings
+        assert_eq!(cpu.load(BUF, 64), Ok(2));
+        assert_eq!(cpu.load(BUF + 8, 64), Ok(BUF + 40));
+        assert_eq!(cpu.load(BUF + 16, 64), Ok(BUF + 45));
+        assert_eq!((cpu.load(BUF + 24, 64), cpu.load(BUF + 32, 64)), (Ok(0), Ok(0)));
+        assert_eq!(cpu.bus.lock().unwrap().read_bytes(BUF + 40, 8), Ok(b"prog\0-v\0".to_vec()));
+        assert_eq!(syscall(&mut htif, &mut cpu, 2011, &[BUF, 47]), -ENOMEM);
+    }
+
+    #[test]
+    fn stdin_is_recorded_and_replayed() {
+        let path = temp_path("htif-stdin.log");
+        let log = Arc::new(Mutex::new(InputLog::create(&path).unwrap()));
+        HostInput::new("htif-stdin", Some(&log)).read(|| b"typed".to_vec());
+        drop(log);
+
+        let log = Arc::new(Mutex::new(InputLog::open(&path).unwrap()));
+        let (_, mut cpu) = htif(&[]);
+        let mut htif = Htif::new(TOHOST, Some(FROMHOST), Vec::new(), false, Some(&log));
+        htif.mute(true);
+        assert_eq!(syscall(&mut htif, &mut cpu, 63, &[0, BUF, 16]), 5);
+        assert_eq!(cpu.bus.lock().unwrap().read_bytes(BUF, 5), Ok(b"typed".to_vec()));
+        // The log has nothing more, so stdin is at its end.
+        assert_eq!(syscall(&mut htif, &mut cpu, 63, &[0, BUF, 16]), 0);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn console_output_is_acknowledged() {
+        let (mut htif, mut cpu) = htif(&[]);
+        cpu.store(TOHOST, 64, 1 << 56 | 1 << 48 | b'x' as u64).unwrap();
+        assert_eq!(htif.poll(&mut cpu), None);
+        assert_eq!(cpu.load(FROMHOST, 64), Ok(1 << 56 | 1 << 48));
+    }
+}
@@ -0,0 +1,76 @@
+//! The hypervisor instructions of the H extension: the guest loads and
+//! stores (hlv, hlvx, hsv) and the guest address-translation fences. The
+//! CSRs, two-stage translation and the guest traps live in the CPU.
+
+use crate::cpu::*;
+use crate::exception::*;
+use crate::isa::*;
+
+impl Cpu {
+    /// Executes hlv.*, hlvx.*, hsv.*, hfence.vvma and hfence.gvma. They are
+    /// illegal without the H extension, virtual instructions in a guest,
+    /// and U-mode may only use the loads and stores when hstatus.HU is set.
+    pub fn execute_hypervisor(&mut self, instruction: u32) -> Result<(), Exception> {
+        let illegal = Exception::IllegalInstruction(instruction as u64);
+        let rd = ((instruction >> 7) & 0x1f) as usize;
+        let rs1 = ((instruction >> 15) & 0x1f) as usize;
+        let rs2 = ((instruction >> 20) & 0x1f) as usize;
+        let funct3 = (instruction >> 12) & 0x7;
+        let funct7 = (instruction >> 25) & 0x7f;
+
+        if !self.isa.has(H) {
+            return Err(illegal);
+        }
+        if funct3 == 0x0 {
+            // hfence.vvma, hfence.gvma
+            // There is no TLB to flush.
+            if self.virt {
+                return Err(Exception::VirtualInstruction(instruction as u64));
+            }
+            if self.mode == USER || (funct7 == 0x31 && self.mode == SUPERVISOR && self.csrs[MSTATUS] & MSTATUS_TVM != 0)
+            {
+                return Err(illegal);
+            }
+            return Ok(());
+        }
+
+        // hlv.b[u], hlv.h[u], hlv.w[u], hlv.d, hlvx.hu, hlvx.wu and hsv.*
+        let size = 8 << ((funct7 >> 1) & 0x3);
+        let store = funct7 & 1 == 1;
+        let rv64 = self.xlen == 64;
+        let valid = match (funct7, rs2) {
+            (0x31 | 0x33 | 0x35 | 0x37, _) => rd == 0 && (size < 64 || rv64),
+            // The signed loads, of which hlv.d is RV64 only
+            (0x30 | 0x32 | 0x34 | 0x36, 0) => size < 64 || rv64,
+            // The unsigned loads, of which hlv.wu is RV64 only
+            (0x30 | 0x32 | 0x34, 1) => size < 32 || rv64,
+            // hlvx.hu, hlvx.wu
+            (0x32 | 0x34, 3) => true,
+            _ => false,
+        };
+        if !valid {
+            return Err(illegal);
+        }
+        if self.virt {
+            return Err(Exception::VirtualInstruction(instruction as u64));
+        }
+        if self.mode == USER && self.csrs[HSTATUS] & HSTATUS_HU == 0 {
+            return Err(illegal);
+        }
+
+        self.guest_access = Some(rs2 == 3 && !store);
+        let addr = self.regs[rs1];
+        let result = if store {
+            self.store(addr, size, self.regs[rs2])
+        } else {
+            self.load(addr, size).map(|value| {
+                let signed = rs2 == 0 && size < 64;
+                let shift = 64 - size;
+                let value = if signed { ((value << shift) as i64 >> shift) as u64 } else { value };
+                self.regs[rd] = self.sign_extend(value);
+            })
+        };
+        self.guest_access = None;
+        result
+    }
+}
@@ -0,0 +1,360 @@
+use std::io;
+
+use crate::cpu::*;
+use crate::device::*;
+use crate::exception::*;
+use crate::fdt::*;
+use crate::snapshot::*;
+
+/// The M-level interrupt files, one 4 KiB page per hart, then the S-level
+/// ones, at the same addresses as on QEMU's `virt` board.
+pub const IMSIC_BASE: u64 = 0x2400_0000;
+pub const IMSIC_S_BASE: u64 = 0x2800_0000;
+pub const IMSIC_SIZE: u64 = 0x800_0000;
+const FILE_SIZE: u64 = 0x1000;
+
+/// Interrupt identities 1 to 255; identity 0 means "no interrupt".
+pub const IMSIC_IDS: u32 = 256;
+
+// Registers of an interrupt file's page
+const SETEIPNUM_LE: u64 = 0x0;
+const SETEIPNUM_BE: u64 = 0x4;
+
+// Interrupt file registers reached through miselect and siselect
+pub const ISELECT_EIDELIVERY: u64 = 0x70;
+pub const ISELECT_EITHRESHOLD: u64 = 0x72;
+pub const ISELECT_EIP0: u64 = 0x80;
+pub const ISELECT_EIE0: u64 = 0xc0;
+pub const ISELECT_EIE63: u64 = 0xff;
+
+/// The interrupt file of one privilege level of a hart: a pending and an
+/// enable bit for each identity, which MSIs set.
+#[derive(Clone, Default)]
+pub struct InterruptFile {
+    delivery: bool,
+    threshold: u32,
+    pending: [u64; 4],
+    enabled: [u64; 4],
+}
+
+impl InterruptFile {
+    /// The pending and enabled identity with the highest priority, which is
+    /// the lowest number, or 0. Only identities below a non-zero threshold
+    /// count.
+    pub fn top(&self) -> u32 {
+        if self.pending.iter().zip(&self.enabled).all(|(&pending, &enabled)| pending & enabled == 0) {
+            return 0;
+        }
+        (1..IMSIC_IDS)
+            .take_while(|&id| self.threshold == 0 || id < self.threshold)
+            .find(|&id| (self.pending[id as usize / 64] & self.enabled[id as usize / 64]) >> (id % 64) & 1 == 1)
+            .unwrap_or(0)
+    }
+
+    /// Whether the file raises the external interrupt of its level.
+    pub fn interrupting(&self) -> bool {
+        self.delivery && self.top() != 0
+    }
+
+    /// Receives an MSI. Identities the file does not have are ignored.
+    pub fn set_pending(&mut self, id: u32) {
+        if id != 0 && id < IMSIC_IDS {
+            self.pending[id as usize / 64] |= 1 << (id % 64);
+        }
+    }
+
+    /// The value of mtopei or stopei: the top identity, as the number and
+    /// the priority.
+    pub fn topei(&self) -> u64 {
+        let id = self.top() as u64;
+        (id << 16) | id
+    }
+
+    /// Claims the top identity, for a write to mtopei or stopei.
+    pub fn claim(&mut self) {
+        let id = self.top();
+        self.pending[id as usize / 64] &= !(1 << (id % 64));
+    }
+
+    /// The bits an eip or eie register covers, and its number. The
+    /// registers are 32 bits each; on RV64 an even one also holds its odd
+    /// neighbour, in the upper half.
+    fn bits(&self, select: u64) -> (&[u64; 4], usize) {
+        if select < ISELECT_EIE0 {
+            (&self.pending, (select - ISELECT_EIP0) as usize)
+        } else {
+            (&self.enabled, (select - ISELECT_EIE0) as usize)
+        }
+    }
+
+    fn bits_mut(&mut self, select: u64) -> (&mut [u64; 4], usize) {
+        if select < ISELECT_EIE0 {
+            (&mut self.pending, (select - ISELECT_EIP0) as usize)
+        } else {
+            (&mut self.enabled, (select - ISELECT_EIE0) as usize)
+        }
+    }
+
+    /// Reads the register `select` names through mireg or sireg.
+    pub fn load(&self, select: u64, xlen: u32) -> u64 {
+        match select {
+            ISELECT_EIDELIVERY => self.delivery as u64,
+            ISELECT_EITHRESHOLD => self.threshold as u64,
+            _ => {
+                let (bits, reg) = self.bits(select);
+                // Registers past the last identity read as zero.
+                let Some(&word) = bits.get(reg / 2) else {
+                    return 0;
+                };
+                if xlen == 64 {
+                    word
+                } else {
+                    (word >> (32 * (reg % 2))) & 0xffff_ffff
+                }
+            }
+        }
+    }
+
+    /// Writes the register `select` names. Only direct delivery of MSIs is
+    /// supported, so eidelivery is 0 or 1.
+    pub fn store(&mut self, select: u64, value: u64, xlen: u32) {
+        match select {
+            ISELECT_EIDELIVERY => self.delivery = value & 1 == 1,
+            ISELECT_EITHRESHOLD => self.threshold = (value as u32) & (IMSIC_IDS - 1),
+            _ => {
+                let (bits, reg) = self.bits_mut(select);
+                let Some(word) = bits.get_mut(reg / 2) else {
+                    return;
+                };
+                if xlen == 64 {
+                    *word = value;
+                } else {
+                    let shift = 32 * (reg % 2);
+                    *word = (*word & !(0xffff_ffff << shift)) | ((value & 0xffff_ffff) << shift);
+                }
+                // Identity 0 does not exist.
+                self.pending[0] &= !1;
+                self.enabled[0] &= !1;
+            }
+        }
+    }
+
+    fn save(&self, out: &mut StateWriter) {
+        out.bool(self.delivery);
+        out.u32(self.threshold);
+        for (&pending, &enabled) in self.pending.iter().zip(&self.enabled) {
+            out.u64(pending);
+            out.u64(enabled);
+        }
+    }
+
+    fn restore(&mut self, input: &mut StateReader) -> io::Result<()> {
+        self.delivery = input.bool()?;
+        self.threshold = input.u32()?;
+        for (pending, enabled) in self.pending.iter_mut().zip(self.enabled.iter_mut()) {
+            *pending = input.u64()?;
+            *enabled = input.u64()?;
+        }
+        Ok(())
+    }
+}
+
+/// Incoming MSI controllers of the AIA: an M-level and an S-level interrupt
+/// file for every hart. Devices and harts signal an interrupt by writing its
+/// identity to a file's page; the hart reads and claims it through its
+/// mtopei and stopei CSRs.
+///
+/// There are no guest interrupt files.
+pub struct Imsic {
+    /// The files of each hart, M-level first.
+    pub files: Vec<[InterruptFile; 2]>,
+}
+
+impl Imsic {
+    pub fn new(harts: usize) -> Self {
+        Self {
+            files: vec![Default::default(); harts],
+        }
+    }
+
+    /// The interrupt file of `mode`, M or S, of a hart.
+    pub fn file(&mut self, hartid: usize, mode: u64) -> &mut InterruptFile {
+        &mut self.files[hartid][(mode != MACHINE) as usize]
+    }
+
+    /// The file whose page holds byte `offset`.
+    fn file_at(&mut self, offset: u64) -> Option<&mut InterruptFile> {
+        let (level, page) = if offset >= IMSIC_S_BASE - IMSIC_BASE {
+            (1, (offset - (IMSIC_S_BASE - IMSIC_BASE)) / FILE_SIZE)
+        } else {
+            (0, offset / FILE_SIZE)
+        };
+        self.files.get_mut(page as usize).map(|files| &mut files[level])
+    }
+
+    /// Delivers an MSI written to `addr`, if that is the seteipnum_le
+    /// register of a file.
+    pub fn message(&mut self, addr: u64, data: u32) {
+        if addr.wrapping_sub(IMSIC_BASE) < IMSIC_SIZE && addr % FILE_SIZE == SETEIPNUM_LE {
+            if let Some(file) = self.file_at(addr - IMSIC_BASE) {
+                file.set_pending(data);
+            }
+        }
+    }
+
+    fn describe_level(&self, fdt: &mut Fdt, base: u64, phandle: u32, irq: u32) {
+        let harts = self.files.len();
+        fdt.begin_node(&format!("imsics@{:x}", base));
+        fdt.property_string("compatible", "riscv,imsics");
+        fdt.property_reg(base, harts as u64 * FILE_SIZE);
+        fdt.property_u32("#address-cells", 0);
+        fdt.property_u32("#interrupt-cells", 0);
+        fdt.property_null("interrupt-controller");
+        fdt.property_null("msi-controller");
+        fdt.property_u32("riscv,num-ids", IMSIC_IDS - 1);
+        fdt.property_u32("phandle", phandle);
+        let cells: Vec<u32> = (0..harts).flat_map(|hart| [cpu_intc_phandle(hart), irq]).collect();
+        fdt.property_cells("interrupts-extended", &cells);
+        fdt.end_node();
+    }
+}
+
+impl Device for Imsic {
+    fn base(&self) -> u64 {
+        IMSIC_BASE
+    }
+
+    fn size(&self) -> u64 {
+        IMSIC_SIZE
+    }
+
+    fn device_tree(&self, fdt: &mut Fdt, _harts: usize) {
+        // The M-level files take machine external interrupts, the S-level
+        // ones supervisor external interrupts.
+        self.describe_level(fdt, IMSIC_BASE, IMSIC_M_PHANDLE, 11);
+        self.describe_level(fdt, IMSIC_S_BASE, IMSIC_S_PHANDLE, 9);
+    }
+
+    /// The registers of a page are write-only and read as zero.
+    fn load(&mut self, offset: u64, _size: u64) -> Result<u64, Exception> {
+        self.file_at(offset).ok_or(Exception::LoadAccessFault(IMSIC_BASE + offset))?;
+        Ok(0)
+    }
+
+    fn store(&mut self, offset: u64, size: u64, value: u64) -> Result<(), Exception> {
+        let file = self.file_at(offset).ok_or(Exception::StoreAMOAccessFault(IMSIC_BASE + offset))?;
+        // Anything but a whole seteipnum register is ignored.
+        match (offset % FILE_SIZE, size) {
+            (SETEIPNUM_LE, 32) => file.set_pending(value as u32),
+            (SETEIPNUM_BE, 32) => file.set_pending((value as u32).swap_bytes()),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn dump_state(&self) -> Option<String> {
+        let mut state = String::from("imsic:");
+        for (hart, files) in self.files.iter().enumerate() {
+            for (file, level) in files.iter().zip(['M', 'S']) {
+                let pending: Vec<String> = (1..IMSIC_IDS)
+                    .filter(|&id| file.pending[id as usize / 64] >> (id % 64) & 1 == 1)
+                    .map(|id| id.to_string())
+                    .collect();
+                state += &format!(
+                    "\n  hart {} {}-level: eidelivery={} eithreshold={} top={} pending [{}]",
+                    hart,
+                    level,
+                    file.delivery as u8,
+                    file.threshold,
+                    file.top(),
+                    pending.join(" ")
+                );
+            }
+        }
+        Some(state)
+    }
+
+    fn save(&self, out: &mut StateWriter) {
+        out.u64(self.files.len() as u64);
+        for file in self.files.iter().flatten() {
+            file.save(out);
+        }
+    }
+
+    fn restore(&mut self, input: &mut StateReader) -> io::Result<()> {
+        input.count(self.files.len(), "harts")?;
+        for file in self.files.iter_mut().flatten() {
+            file.restore(input)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages_reach_the_file_of_their_page() {
+        let mut imsic = Imsic::new(2);
+        let s_page = |hart: u64| IMSIC_S_BASE - IMSIC_BASE + hart * FILE_SIZE;
+        imsic.store(FILE_SIZE + SETEIPNUM_LE, 32, 7).unwrap();
+        imsic.store(s_page(0) + SETEIPNUM_BE, 32, 9u32.swap_bytes() as u64).unwrap();
+        // Identities past the last are dropped.
+        imsic.message(IMSIC_S_BASE + FILE_SIZE, 300);
+        imsic.message(IMSIC_S_BASE + FILE_SIZE, 11);
+        // Only whole seteipnum registers count.
+        imsic.store(s_page(1) + SETEIPNUM_LE, 16, 12).unwrap();
+        imsic.store(s_page(1) + 8, 32, 13).unwrap();
+        assert_eq!(imsic.file(1, MACHINE).pending, [1 << 7, 0, 0, 0]);
+        assert_eq!(imsic.file(0, SUPERVISOR).pending, [1 << 9, 0, 0, 0]);
+        assert_eq!(imsic.file(1, SUPERVISOR).pending, [1 << 11, 0, 0, 0]);
+        assert_eq!(imsic.file(0, MACHINE).pending, [0; 4]);
+
+        // The pages read as zero, and there are none past the last hart.
+        assert_eq!(imsic.load(FILE_SIZE, 32), Ok(0));
+        assert!(imsic.load(2 * FILE_SIZE, 32).is_err());
+        assert!(imsic.store(s_page(2), 32, 1).is_err());
+    }
+
+    #[test]
+    fn the_lowest_enabled_identity_is_on_top() {
+        let mut file = InterruptFile::default();
+        for id in [70, 40, 0] {
+            file.set_pending(id);
+        }
+        assert_eq!(file.top(), 0);
+        file.store(ISELECT_EIE0, 1 << 40, 64);
+        file.store(ISELECT_EIE0 + 2, 1 << 6, 64);
+        assert_eq!(file.topei(), 40 << 16 | 40);
+        // Nothing is delivered until eidelivery is set.
+        assert!(!file.interrupting());
+        file.store(ISELECT_EIDELIVERY, 1, 64);
+        assert!(file.interrupting());
+
+        // Only identities below the threshold count.
+        file.store(ISELECT_EITHRESHOLD, 40, 64);
+        assert_eq!(file.top(), 0);
+        file.store(ISELECT_EITHRESHOLD, 0, 64);
+        file.claim();
+        assert_eq!(file.top(), 70);
+        file.claim();
+        assert_eq!(file.top(), 0);
+        assert!(!file.interrupting());
+    }
+
+    #[test]
+    fn rv32_splits_the_registers() {
+        let mut file = InterruptFile::default();
+        file.store(ISELECT_EIP0 + 1, 0x1_8000_0000, 32);
+        file.store(ISELECT_EIP0, 0xffff_ffff, 32);
+        // Identity 0 does not exist.
+        assert_eq!(file.load(ISELECT_EIP0, 32), 0xffff_fffe);
+        assert_eq!(file.load(ISELECT_EIP0 + 1, 32), 0x8000_0000);
+        assert_eq!(file.load(ISELECT_EIP0, 64), 0x8000_0000_ffff_fffe);
+        // Past identity 255
+        file.store(ISELECT_EIE0 + 8, 1, 32);
+        assert_eq!(file.load(ISELECT_EIE0 + 8, 32), 0);
+        assert_eq!(file.enabled, [0; 4]);
+    }
+}
@@ -0,0 +1,50 @@
+/// Asynchronous interrupts, in decreasing order of priority.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interrupt {
+    MachineExternal,
+    MachineSoftware,
+    MachineTimer,
+    SupervisorExternal,
+    SupervisorSoftware,
+    SupervisorTimer,
+    SupervisorGuestExternal,
+    VirtualSupervisorExternal,
+    VirtualSupervisorSoftware,
+    VirtualSupervisorTimer,
+    LocalCounterOverflow,
+}
+
+impl Interrupt {
+    /// Every interrupt, highest priority first.
+    pub const ALL: [Interrupt; 11] = [
+        Interrupt::MachineExternal,
+        Interrupt::MachineSoftware,
+        Interrupt::MachineTimer,
+        Interrupt::SupervisorExternal,
+        Interrupt::SupervisorSoftware,
+        Interrupt::SupervisorTimer,
+        Interrupt::SupervisorGuestExternal,
+        Interrupt::VirtualSupervisorExternal,
+        Interrupt::VirtualSupervisorSoftware,
+        Interrupt::VirtualSupervisorTimer,
+        Interrupt::LocalCounterOverflow,
+    ];
+
+    /// The interrupt code written to `mcause`/`scause`, which is also its bit
+    /// in `mip`/`mie`.
+    pub fn code(&self) -> u64 {
+        match self {
+            Interrupt::SupervisorSoftware => 1,
+            Interrupt::VirtualSupervisorSoftware => 2,
+            Interrupt::MachineSoftware => 3,
+            Interrupt::SupervisorTimer => 5,
+            Interrupt::VirtualSupervisorTimer => 6,
+            Interrupt::MachineTimer => 7,
+            Interrupt::SupervisorExternal => 9,
+            Interrupt::VirtualSupervisorExternal => 10,
+            Interrupt::MachineExternal => 11,
+            Interrupt::SupervisorGuestExternal => 12,
+            Interrupt::LocalCounterOverflow => 13,
+        }
+    }
+}
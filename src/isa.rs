@@ -0,0 +1,119 @@
+//! The ISA string, which picks the optional extensions the harts implement.
+
+/// Extensions the harts always implement, as named in the device tree.
+const BASE_EXTENSIONS: &[&str] = &["i", "m", "a", "f", "d", "c", "zicntr", "zicsr", "zifencei", "zihpm"];
+
+// Optional extensions
+pub const ZBA: u64 = 1 << 0;
+pub const ZBB: u64 = 1 << 1;
+pub const ZBC: u64 = 1 << 2;
+pub const ZBS: u64 = 1 << 3;
+pub const V: u64 = 1 << 4;
+pub const ZKND: u64 = 1 << 5;
+pub const ZKNE: u64 = 1 << 6;
+pub const ZKNH: u64 = 1 << 7;
+pub const ZKR: u64 = 1 << 8;
+pub const ZKSED: u64 = 1 << 9;
+pub const ZKSH: u64 = 1 << 10;
+pub const ZICBOM: u64 = 1 << 11;
+pub const ZICBOP: u64 = 1 << 12;
+pub const ZICBOZ: u64 = 1 << 13;
+pub const ZICOND: u64 = 1 << 14;
+pub const ZIHINTPAUSE: u64 = 1 << 15;
+pub const ZFA: u64 = 1 << 16;
+pub const H: u64 = 1 << 17;
+pub const SMEPMP: u64 = 1 << 18;
+pub const SMAIA: u64 = 1 << 19;
+pub const SSAIA: u64 = 1 << 20;
+pub const SSCOFPMF: u64 = 1 << 21;
+pub const SSTC: u64 = 1 << 22;
+pub const SVADE: u64 = 1 << 23;
+pub const SVADU: u64 = 1 << 24;
+pub const SVNAPOT: u64 = 1 << 25;
+pub const SVPBMT: u64 = 1 << 26;
+
+/// The optional extensions by name, in canonical order.
+const OPTIONAL_EXTENSIONS: &[(&str, u64)] = &[
+    ("v", V),
+    ("h", H),
+    ("zicbom", ZICBOM),
+    ("zicbop", ZICBOP),
+    ("zicboz", ZICBOZ),
+    ("zicond", ZICOND),
+    ("zihintpause", ZIHINTPAUSE),
+    ("zfa", ZFA),
+    ("zba", ZBA),
+    ("zbb", ZBB),
+    ("zbc", ZBC),
+    ("zbs", ZBS),
+    ("zknd", ZKND),
+    ("zkne", ZKNE),
+    ("zknh", ZKNH),
+    ("zkr", ZKR),
+    ("zksed", ZKSED),
+    ("zksh", ZKSH),
+    ("smaia", SMAIA),
+    ("smepmp", SMEPMP),
+    ("ssaia", SSAIA),
+    ("sscofpmf", SSCOFPMF),
+    ("sstc", SSTC),
+    ("svade", SVADE),
+    ("svadu", SVADU),
+    ("svnapot", SVNAPOT),
+    ("svpbmt", SVPBMT),
+];
+
+/// The optional extensions that are enabled. All of them are unless an ISA
+/// string leaves some out.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Isa(u64);
+
+impl Default for Isa {
+    fn default() -> Self {
+        Isa(OPTIONAL_EXTENSIONS.iter().fold(0, |bits, (_, bit)| bits | bit))
+    }
+}
+
+impl Isa {
+    pub fn has(self, extension: u64) -> bool {
+        self.0 & extension != 0
+    }
+
+    /// Parses an ISA string such as `rv64gc_zba_zbb`, returning its XLEN
+    /// along with the extensions.
+    pub fn parse(isa: &str) -> Result<(u32, Isa), String> {
+        let lower = isa.to_ascii_lowercase();
+        let mut parts = lower.split('_');
+        let first = parts.next().unwrap_or_default();
+        let (xlen, letters) = if let Some(letters) = first.strip_prefix("rv32") {
+            (32, letters)
+        } else if let Some(letters) = first.strip_prefix("rv64") {
+            (64, letters)
+        } else {
+            return Err(format!("ISA string `{}` does not start with rv32 or rv64", isa));
+        };
+
+        // G stands for IMAFD with Zicsr and Zifencei.
+        let letters = letters.replacen('g', "imafd", 1);
+        if !"imafdc".chars().all(|c| letters.contains(c)) {
+            return Err(String::from("the I, M, A, F, D and C extensions cannot be left out"));
+        }
+
+        let mut bits = 0;
+        let single = letters.chars().filter(|c| !"imafdc".contains(*c)).map(String::from);
+        for name in single.chain(parts.filter(|name| !name.is_empty()).map(String::from)) {
+            match OPTIONAL_EXTENSIONS.iter().find(|(n, _)| *n == name) {
+                Some((_, bit)) => bits |= bit,
+                None if BASE_EXTENSIONS.contains(&name.as_str()) => {}
+                None => return Err(format!("unsupported extension `{}`", name)),
+            }
+        }
+        Ok((xlen, Isa(bits)))
+    }
+
+    /// The names of the implemented extensions, for the device tree.
+    pub fn extensions(self) -> Vec<&'static str> {
+        let optional = OPTIONAL_EXTENSIONS.iter().filter(|(_, bit)| self.has(*bit)).map(|(name, _)| *name);
+        BASE_EXTENSIONS.iter().copied().chain(optional).collect()
+    }
+}
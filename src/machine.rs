@@ -0,0 +1,541 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crate::bus::*;
+use crate::config::*;
+use crate::cpu::*;
+use crate::elf::*;
+use crate::fdt;
+use crate::htif::*;
+use crate::isa::*;
+use crate::sbi::*;
+use crate::semihosting::*;
+use crate::snapshot::*;
+
+/// A set of harts sharing one bus.
+pub struct Machine {
+    pub harts: Vec<Cpu>,
+    pub bus: Arc<Mutex<Bus>>,
+    /// Frontend server for programs that talk to the host through `tohost`.
+    pub htif: Option<Htif>,
+    pub semihosting: Option<Arc<Mutex<Semihosting>>>,
+    /// Exit status the guest reported, if it asked to exit.
+    pub exit_status: Option<i32>,
+    /// Instructions stepped through so far, over all harts.
+    pub steps: u64,
+    /// Where `run` saves a snapshot, after `snapshot_at` steps or when the
+    /// guest executes `SNAPSHOT_HINT`.
+    pub snapshot: Option<PathBuf>,
+    pub snapshot_at: Option<u64>,
+    /// Where the round-robin schedule has got to: the hart whose turn it is,
+    /// or the number of harts between rounds, and how many instructions it
+    /// has run this round.
+    pub turn: usize,
+    pub slice: u64,
+    /// The ranges of memory the program or firmware was loaded into, which
+    /// the boot images must stay clear of.
+    firmware: Vec<(u64, u64)>,
+}
+
+impl Machine {
+    pub fn new(config: &MachineConfig, code: Vec<u8>) -> io::Result<Self> {
+        let firmware = match code.len() {
+            0 => Vec::new(),
+            len => vec![(config.dram_base, config.dram_base + len as u64)],
+        };
+        let bus = Arc::new(Mutex::new(Bus::new(config, code)?));
+        let harts = (0..config.harts)
+            .map(|hartid| Cpu::new(Arc::clone(&bus), config, hartid))
+            .collect();
+
+        Ok(Self {
+            harts,
+            bus,
+            htif: None,
+            semihosting: None,
+            exit_status: None,
+            steps: 0,
+            snapshot: None,
+            snapshot_at: None,
+            turn: config.harts,
+            slice: 0,
+            firmware,
+        })
+    }
+
+    /// Copies the segments of an ELF file to their physical addresses and
+    /// starts every hart at its entry point.
+    pub fn load_elf(&mut self, elf: &Elf, data: &[u8]) -> Result<(), String> {
+        let mut bus = self.bus.lock().unwrap();
+        for segment in &elf.segments {
+            if !bus.is_memory_range(segment.paddr, segment.memsz) {
+                return Err(format!("segment at {:#x} does not fit in the memory map", segment.paddr));
+            }
+            let start = segment.offset as usize;
+            let bytes = &data[start..start + segment.filesz as usize];
+            let bss = vec![0; (segment.memsz - segment.filesz) as usize];
+            bus.write_bytes(segment.paddr, bytes)
+                .and_then(|_| bus.write_bytes(segment.paddr + segment.filesz, &bss))
+                .map_err(|_| format!("segment at {:#x} does not fit in the memory map", segment.paddr))?;
+            self.firmware.push((segment.paddr, segment.paddr + segment.memsz));
+        }
+        for hart in self.harts.iter_mut() {
+            hart.pc = elf.entry;
+        }
+
+        Ok(())
+    }
+
+    /// Loads the kernel, initrd and device tree named in the configuration
+    /// and passes the device tree address to every hart in a1. Under the
+    /// `virt` profile, a device tree is generated if none is given.
+    ///
+    /// The device tree goes at the highest 2 MiB boundary that leaves room
+    /// for it below the end of DRAM. Images may not overlap each other or
+    /// the program or firmware already loaded. Returns the device tree, if
+    /// one was loaded.
+    pub fn load_boot_images(&mut self, config: &MachineConfig) -> Result<Option<Vec<u8>>, String> {
+        let read = |path: &std::path::PathBuf| fs::read(path).map_err(|e| format!("{}: {}", path.display(), e));
+        let mut images = Vec::new();
+        if let Some(path) = &config.kernel {
+            images.push(("kernel", config.dram_base + KERNEL_OFFSET, read(path)?));
+        }
+        let mut initrd = None;
+        if let Some(path) = &config.initrd {
+            let data = read(path)?;
+            let addr = config.initrd_addr();
+            initrd = Some((addr, addr + data.len() as u64));
+            images.push(("initrd", addr, data));
+        }
+        let dtb = match &config.dtb {
+            Some(path) => Some(read(path)?),
+            None if config.profile == Profile::Virt => Some(self.device_tree(config, initrd)),
+            None => None,
+        };
+        if let Some(dtb) = &dtb {
+            let addr = config.dram_end().saturating_sub(dtb.len() as u64) & !0x1f_ffff;
+            for hart in self.harts.iter_mut() {
+                hart.regs[11] = hart.sign_extend(addr);
+            }
+            images.push(("device tree", addr, dtb.clone()));
+        }
+
+        for (i, (name, addr, data)) in images.iter().enumerate() {
+            let end = addr + data.len() as u64;
+            if *addr < config.dram_base || end > config.dram_end() {
+                return Err(format!("{} at {:#x} does not fit in DRAM", name, addr));
+            }
+            if let Some((other, _, _)) = images[..i]
+                .iter()
+                .find(|(_, a, d)| *a < end && *addr < a + d.len() as u64)
+            {
+                return Err(format!("{} at {:#x} overlaps the {}", name, addr, other));
+            }
+            if self.firmware.iter().any(|&(start, firmware_end)| start < end && *addr < firmware_end) {
+                return Err(format!("{} at {:#x} overlaps the firmware", name, addr));
+            }
+            self.bus.lock().unwrap().write_bytes(*addr, data).unwrap();
+        }
+
+        Ok(dtb)
+    }
+
+    /// Generates a device tree describing the machine.
+    pub fn device_tree(&self, config: &MachineConfig, initrd: Option<(u64, u64)>) -> Vec<u8> {
+        fdt::generate(config, &self.bus.lock().unwrap(), initrd)
+    }
+
+    /// Mutes or unmutes everything the machine sends to the host's
+    /// terminal.
+    pub fn mute(&mut self, muted: bool) {
+        self.bus.lock().unwrap().mute(muted);
+        if let Some(htif) = self.htif.as_mut() {
+            htif.mute(muted);
+        }
+        if let Some(semihosting) = &self.semihosting {
+            semihosting.lock().unwrap().mute(muted);
+        }
+    }
+
+    /// Services semihosting calls from every hart. `args` is the command line
+    /// reported to the guest.
+    pub fn enable_semihosting(&mut self, args: &[String]) {
+        let log = self.bus.lock().unwrap().input_log.clone();
+        let host = Arc::new(Mutex::new(Semihosting::new(args, log.as_ref())));
+        for hart in self.harts.iter_mut() {
+            hart.semihosting = Some(Arc::clone(&host));
+        }
+        self.semihosting = Some(host);
+    }
+
+    /// Services SBI calls in the emulator instead of firmware. Hart 0 starts
+    /// at the kernel in S-mode, with the traps and interrupts a supervisor
+    /// handles delegated to it, the counters readable, and the cache-block
+    /// instructions and the Sstc timer allowed; the others wait for
+    /// `sbi_hart_start`. The interrupt sources of an APLIC are handed to its
+    /// S-level domain.
+    pub fn enable_sbi(&mut self, config: &MachineConfig) {
+        if let Some(aplic) = self.bus.lock().unwrap().aplic.as_mut() {
+            aplic.delegate_all();
+        }
+        let sbi = Arc::new(Mutex::new(Sbi::new(self.harts.len())));
+        for hart in self.harts.iter_mut() {
+            hart.mode = SUPERVISOR;
+            hart.pc = config.dram_base + KERNEL_OFFSET;
+            // Everything but environment calls from S- and M-mode, with the
+            // guest traps of the H extension when the hart has it.
+            hart.store_csr(MEDELEG, 0xb1ff | (1 << 10) | (0xf << 20));
+            hart.store_csr(MIDELEG, SSIP_BIT | STIP_BIT | SEIP_BIT);
+            hart.csrs[MCOUNTEREN] = 0xffff_ffff;
+            // Hardware A/D updating is only turned on when the ISA string
+            // leaves Svade out, as kernels otherwise expect to set A and D.
+            let mut envcfg = ENVCFG_CBIE | ENVCFG_CBCFE | ENVCFG_CBZE | ENVCFG_STCE | ENVCFG_PBMTE;
+            if !hart.isa.has(SVADE) {
+                envcfg |= ENVCFG_ADUE;
+            }
+            hart.store_csr(MENVCFG, envcfg);
+            hart.sbi = Some(Arc::clone(&sbi));
+        }
+    }
+
+    /// Writes the complete state of the machine to `path`: the harts, memory
+    /// and every device. Disk images, shared directories and host
+    /// connections are not included.
+    pub fn save_snapshot(&self, path: &Path) -> io::Result<()> {
+        let mut out = StateWriter::default();
+        out.data.extend_from_slice(SNAPSHOT_MAGIC);
+        out.u32(SNAPSHOT_VERSION);
+        self.save_state(&mut out);
+        fs::write(path, &out.data)
+    }
+
+    /// Writes the state of the harts, memory and devices, and the number
+    /// of steps so far.
+    pub fn save_state(&self, out: &mut StateWriter) {
+        out.u64(self.steps);
+        out.u64(self.harts.len() as u64);
+        for hart in &self.harts {
+            hart.save(out);
+        }
+        self.bus.lock().unwrap().save(out);
+        let sbi = self.harts[0].sbi.as_ref();
+        out.bool(sbi.is_some());
+        if let Some(sbi) = sbi {
+            sbi.lock().unwrap().save(out);
+        }
+    }
+
+    /// Puts the machine back in the state saved in `path`. The machine must
+    /// have been built from the same configuration as the one saved.
+    pub fn load_snapshot(&mut self, path: &Path) -> io::Result<()> {
+        let data = fs::read(path)?;
+        if data.get(..SNAPSHOT_MAGIC.len()) != Some(&SNAPSHOT_MAGIC[..]) {
+            return Err(invalid_snapshot("not a snapshot file"));
+        }
+        let mut input = StateReader::new(&data[SNAPSHOT_MAGIC.len()..]);
+        if input.u32()? != SNAPSHOT_VERSION {
+            return Err(invalid_snapshot("saved by an incompatible version"));
+        }
+        self.restore_state(&mut input)?;
+        // Snapshots are taken between rounds.
+        self.turn = self.harts.len();
+        self.slice = 0;
+        // A snapshot point the saved run has gone past is not taken again.
+        if self.snapshot_at.is_some_and(|at| at <= self.steps) {
+            self.snapshot_at = None;
+        }
+        Ok(())
+    }
+
+    /// Restores what `save_state` wrote. The schedule is left as it is.
+    pub fn restore_state(&mut self, input: &mut StateReader) -> io::Result<()> {
+        self.steps = input.u64()?;
+        input.count(self.harts.len(), "harts")?;
+        for hart in self.harts.iter_mut() {
+            hart.restore(input)?;
+        }
+        self.bus.lock().unwrap().restore(input)?;
+        let sbi = self.harts[0].sbi.clone();
+        if input.bool()? != sbi.is_some() {
+            return Err(invalid_snapshot("the built-in SBI must be enabled in both runs or neither"));
+        }
+        if let Some(sbi) = sbi {
+            sbi.lock().unwrap().restore(input)?;
+        }
+        self.exit_status = None;
+        Ok(())
+    }
+
+    /// Saves a snapshot if one is due. Called between scheduling rounds, so
+    /// a resumed run carries on exactly as the original one did.
+    fn take_snapshot(&mut self) {
+        let Some(path) = self.snapshot.clone() else {
+            return;
+        };
+        let requested = self.harts.iter().any(|hart| hart.snapshot_requested);
+        let reached = self.snapshot_at.is_some_and(|at| self.steps >= at);
+        if !requested && !reached {
+            return;
+        }
+        for hart in self.harts.iter_mut() {
+            hart.snapshot_requested = false;
+        }
+        if reached {
+            self.snapshot_at = None;
+        }
+        match self.save_snapshot(&path) {
+            Ok(()) => eprintln!("rvemu: saved a snapshot to {} after {} steps", path.display(), self.steps),
+            Err(e) => eprintln!("rvemu: cannot save a snapshot to {}: {}", path.display(), e),
+        }
+    }
+
+    /// Runs the harts round-robin on the current thread, `quantum`
+    /// instructions at a time, until every hart has stopped.
+    ///
+    /// The interleaving only depends on the program and the quantum, so runs
+    /// are reproducible. The machine timer advances by `quantum` ticks after
+    /// every round.
+    pub fn run(&mut self, quantum: u64) {
+        while self.step(quantum).is_some() {}
+    }
+
+    /// Returns the hart that runs next. Between rounds, this first takes a
+    /// snapshot if one is due and advances the devices by `quantum` ticks.
+    /// Calling it again before `step` changes nothing. `None` means the
+    /// guest has exited or every hart has stopped.
+    pub fn next_hart(&mut self, quantum: u64) -> Option<usize> {
+        loop {
+            if self.exit_status.is_some() {
+                return None;
+            }
+            if self.turn == self.harts.len() {
+                // A hart stops for good when it reaches address 0.
+                if self.harts.iter().all(|hart| hart.pc == 0) {
+                    return None;
+                }
+                self.take_snapshot();
+                let mut bus = self.bus.lock().unwrap();
+                bus.tick(quantum);
+                self.exit_status = bus.exit_status();
+                drop(bus);
+                self.turn = 0;
+                self.slice = 0;
+                continue;
+            }
+            if self.slice < quantum && self.harts[self.turn].pc != 0 {
+                return Some(self.turn);
+            }
+            self.turn += 1;
+            self.slice = 0;
+        }
+    }
+
+    /// Runs one instruction on the hart whose turn it is and returns that
+    /// hart, or `None` if the machine has stopped.
+    pub fn step(&mut self, quantum: u64) -> Option<usize> {
+        let id = self.next_hart(quantum)?;
+        let hart = &mut self.harts[id];
+        run_one(hart);
+        self.steps += 1;
+        self.slice += 1;
+
+        if let Some(htif) = self.htif.as_mut() {
+            self.exit_status = htif.poll(hart);
+        }
+        if self.exit_status.is_none() {
+            self.exit_status = semihosting_exit(&self.semihosting);
+        }
+        Some(id)
+    }
+
+    /// Runs every hart on its own host thread until all of them have stopped.
+    pub fn run_parallel(&mut self) {
+        let stop = AtomicBool::new(false);
+        let stop = &stop;
+        let mut htif = self.htif.take();
+        // The timer follows whichever hart has run furthest, so it keeps
+        // going while any hart does.
+        let time = &AtomicU64::new(0);
+        let device_exit = &Mutex::new(None);
+        let mut exit_status = None;
+
+        let semihosting = &self.semihosting;
+        let bus = &self.bus;
+
+        thread::scope(|scope| {
+            let (first, rest) = self.harts.split_first_mut().unwrap();
+            for hart in rest {
+                scope.spawn(move || {
+                    let mut steps = 0;
+                    while !stop.load(Ordering::Relaxed) && run_one(hart) {
+                        steps += 1;
+                        if let Some(status) = keep_time(bus, time, steps) {
+                            *device_exit.lock().unwrap() = Some(status);
+                            stop.store(true, Ordering::Relaxed);
+                        }
+                        if semihosting_exit(semihosting).is_some() {
+                            stop.store(true, Ordering::Relaxed);
+                        }
+                    }
+                });
+            }
+
+            // The first hart's thread also serves the frontend.
+            let mut steps = 0;
+            while !stop.load(Ordering::Relaxed) && run_one(first) {
+                steps += 1;
+                if let Some(status) = keep_time(bus, time, steps) {
+                    exit_status = Some(status);
+                    stop.store(true, Ordering::Relaxed);
+                }
+                if let Some(htif) = htif.as_mut() {
+                    if let Some(status) = htif.poll(first) {
+                        exit_status = Some(status);
+                        stop.store(true, Ordering::Relaxed);
+                    }
+                }
+                if semihosting_exit(semihosting).is_some() {
+                    stop.store(true, Ordering::Relaxed);
+                }
+            }
+        });
+
+        self.htif = htif;
+        self.exit_status = exit_status.or(*device_exit.lock().unwrap()).or(semihosting_exit(&self.semihosting));
+    }
+}
+
+/// Advances the devices to `steps`, how far a hart has run, if no hart has
+/// run further yet. Returns the exit status a device reports.
+fn keep_time(bus: &Mutex<Bus>, time: &AtomicU64, steps: u64) -> Option<i32> {
+    let previous = time.fetch_max(steps, Ordering::Relaxed);
+    if previous >= steps {
+        return None;
+    }
+    let mut bus = bus.lock().unwrap();
+    bus.tick(steps - previous);
+    bus.exit_status()
+}
+
+fn semihosting_exit(semihosting: &Option<Arc<Mutex<Semihosting>>>) -> Option<i32> {
+    semihosting.as_ref().and_then(|host| host.lock().unwrap().exit_status)
+}
+
+/// Steps a hart once, taking any trap, and returns whether it is still
+/// running. A hart stops when it reaches address 0, which is where traps go
+/// until the program sets up `mtvec`.
+fn run_one(hart: &mut Cpu) -> bool {
+    if let Err(exception) = hart.step() {
+        hart.handle_exception(exception);
+    }
+
+    hart.pc != 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    #[test]
+    fn parallel_timer_keeps_going_after_hart_0_stops() {
+        let mut program = vec![
+            // Hart 0 stops straight away.
+            bne(A0, ZERO, 8),
+            jalr(ZERO, ZERO, 0),
+        ];
+        // Hart 1 sets its mtimecmp and waits for the timer interrupt, then
+        // tells the finisher to exit.
+        program.extend(li(T0, 0x200_4008));
+        program.extend([addi(T1, ZERO, 1000), sd(T1, T0, 0)]);
+        program.extend([csrrs(T2, 0x344, ZERO), andi(T2, T2, 0x80), beq(T2, ZERO, -8)]);
+        program.extend(li(T0, 0x10_0000));
+        program.extend(li(T1, 0x5555));
+        program.extend([sw(T1, T0, 0), SPIN]);
+        let config = MachineConfig { harts: 2, ..virt() };
+        let mut machine = machine(&config, &program);
+        machine.run_parallel();
+        assert_eq!(machine.exit_status, Some(0));
+    }
+
+    #[test]
+    fn harts_get_stacks_of_their_own() {
+        let config = MachineConfig { harts: 3, ..virt() };
+        let machine = machine(&config, &[]);
+        let top = config.dram_end();
+        let stacks: Vec<u64> = machine.harts.iter().map(|hart| hart.regs[2]).collect();
+        assert_eq!(stacks, [top, top - HART_STACK_SIZE, top - 2 * HART_STACK_SIZE]);
+    }
+
+    #[test]
+    fn resumed_runs_end_like_the_original() {
+        // Each hart sums 1 to 1000, with a delay that depends on its id, and
+        // reads the timer.
+        let program = [
+            addi(T0, A0, 1000),
+            addi(T1, ZERO, 0),
+            r_type(0x33, T1, 0, T1, T0, 0),
+            addi(T0, T0, -1),
+            bne(T0, ZERO, -8),
+            csrrs(T2, 0xc01, ZERO),
+            jalr(ZERO, ZERO, 0),
+        ];
+        let config = MachineConfig { harts: 2, ..virt() };
+        let path = temp_path("snapshot");
+        let mut original = machine(&config, &program);
+        original.snapshot = Some(path.clone());
+        original.snapshot_at = Some(1000);
+        original.run(7);
+
+        let mut resumed = machine(&config, &program);
+        resumed.load_snapshot(&path).unwrap();
+        assert!(resumed.steps >= 1000 && resumed.steps < original.steps);
+        resumed.run(7);
+        assert_eq!(resumed.steps, original.steps);
+        for (a, b) in original.harts.iter().zip(&resumed.harts) {
+            assert_eq!(a.regs, b.regs);
+        }
+        assert_eq!(original.harts[1].regs[T1 as usize], 1001 * 1002 / 2);
+
+        // A snapshot of another machine is refused.
+        let mut other = machine(&MachineConfig { harts: 1, ..virt() }, &program);
+        assert!(other.load_snapshot(&path).is_err());
+        fs::write(&path, b"RVEMUSNP\x00\x00\x00\x00").unwrap();
+        assert!(resumed.load_snapshot(&path).is_err());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn segments_must_fit_in_memory() {
+        let mut rv64 = machine(&MachineConfig::default(), &[SPIN]);
+        let data = [0; 16];
+        let segment = |paddr, memsz| Segment { vaddr: paddr, paddr, offset: 0, filesz: 16, memsz };
+        let elf = |segment| Elf {
+            xlen: 64,
+            entry: 0,
+            phoff: 0,
+            phentsize: 0,
+            phnum: 0,
+            segments: vec![segment],
+            symbols: vec![],
+        };
+        assert!(rv64.load_elf(&elf(segment(DRAM_BASE, 0x1000)), &data).is_ok());
+        assert!(rv64.load_elf(&elf(segment(DRAM_BASE, 1 << 62)), &data).is_err());
+        assert!(rv64.load_elf(&elf(segment(u64::MAX - 8, 16)), &data).is_err());
+    }
+    #[test]
+    fn boot_images_stay_clear_of_the_firmware() {
+        let path = temp_path("kernel.bin");
+        fs::write(&path, [0x13, 0, 0, 0]).unwrap();
+        let config = MachineConfig { kernel: Some(path.clone()), ..Default::default() };
+        let mut small = Machine::new(&config, vec![0; 0x10_0000]).unwrap();
+        assert_eq!(small.load_boot_images(&config), Ok(None));
+        let mut large = Machine::new(&config, vec![0; 0x30_0000]).unwrap();
+        assert!(large.load_boot_images(&config).unwrap_err().contains("kernel at 0x80200000 overlaps the firmware"));
+        fs::remove_file(path).unwrap();
+    }
+}
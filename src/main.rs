@@ -1,50 +1,360 @@
 mod cpu;
+mod aia;
+mod aplic;
+mod bitmanip;
 mod bus;
+mod clint;
+mod config;
+mod counters;
+mod crypto;
+mod debugger;
+mod device;
+mod disasm;
 mod dram;
+mod elf;
+mod entropy;
+mod exception;
+mod fdt;
+mod finisher;
+mod fpu;
+mod gdbstub;
+mod htif;
+mod hypervisor;
+mod imsic;
+mod interrupt;
+mod isa;
+mod machine;
+mod monitor;
+mod pcap;
+mod plic;
+mod pmp;
+mod replay;
+mod rvc;
+mod sbi;
+mod semihosting;
+mod snapshot;
+mod syscall;
+#[cfg(test)]
+mod testing;
+mod uart;
+mod vector;
+mod virtio;
+mod virtio_9p;
+mod virtio_blk;
+mod virtio_console;
+mod virtio_net;
+mod virtio_rng;
 
-use std::{io, env};
-use std::fs::File;
+use std::{io, env, process};
+use std::fs::{self, File};
+use std::path::Path;
 use std::io::prelude::*;
 
-// Default DRAM size (128 MiB)
+use config::*;
+use debugger::*;
+use elf::*;
+use gdbstub::*;
+use htif::*;
+use machine::*;
+use monitor::*;
+use replay::*;
+use syscall::*;
+use uart::*;
 
-use cpu::*;
+const USAGE: &str = "Usage: rvemu [options] [<filename> [args...]]
+
+Options:
+    --machine <file>                 read a machine description file
+    --dram-base <addr>               DRAM base address (default 0x80000000)
+    --dram-size <size>               DRAM size, e.g. 64M (default 128M)
+    --rom <name@base:size[:file]>    add a read-only memory region
+    --sram <name@base:size[:file]>   add a read-write memory region
+    --sp <addr>                      initial stack pointer of hart 0 (default: top of DRAM)
+    --harts <n>                      number of harts (default 1)
+    --xlen <32|64>                   register width (default: the ELF class, or 64)
+    --isa <string>                   ISA string picking the optional extensions, e.g.
+                                     rv64gcv_zba_zbb (default: all of them)
+    --vlen <bits>                    vector register width (default 128)
+    --elen <32|64>                   widest vector element (default 64)
+    --cache-block-size <bytes>       cache block size of the Zicbom, Zicboz and
+                                     Zicbop instructions (default 64)
+    --entropy <host|seed:<n>>        where the Zkr `seed` CSR gets its entropy:
+                                     the host or a fixed seed (default host)
+    --quantum <n>                    instructions per hart per turn (default 1000)
+    --parallel                       run each hart on its own host thread
+    --user                           run a static Linux ELF, passing it [args...]
+    --strace                         log emulated system calls
+    --semihosting                    service semihosting calls, passing [args...]
+    --profile <bare|virt>            peripherals to emulate (default bare)
+    --interrupt-controller <plic|aplic|aplic-imsic>
+                                     interrupt controller of the virt profile: a
+                                     PLIC, or an AIA APLIC on its own or with
+                                     per-hart IMSICs (default plic)
+    --bios <file>                    firmware to boot when <filename> is omitted
+    --sbi                            boot the kernel in S-mode with a built-in SBI
+                                     instead of firmware (virt)
+    --kernel <file>                  kernel image, loaded 2 MiB into DRAM (virt)
+    --initrd <file>                  initial ramdisk, loaded mid-DRAM (virt)
+    --dtb <file>                     device tree blob, passed in a1 (virt; default:
+                                     generated from the machine)
+    --bootargs <string>              kernel command line in the generated device tree
+    --drive <file[:overlay]>         attach a raw disk image as a virtio block device
+                                     (virt; writes go to the overlay file if given)
+    --net <listen|connect|replay>:<path>
+                                     add a virtio network card connected to another
+                                     emulator through a Unix socket, or receiving
+                                     the frames of a pcap file (virt)
+    --mac <addr>                     MAC address of the card (default 52:54:00:12:34:56)
+    --net-capture <file>             record the card's traffic to a pcap file
+    --console-port <name>:<listen|file>:<path>
+                                     add a port to a virtio console, on a Unix socket
+                                     or appending to a file; the first one is the
+                                     guest's console (virt)
+    --rng <host|seed:<n>>            add a virtio entropy device fed by the host or
+                                     by a fixed seed (virt)
+    --share <tag>:<dir>              share a host directory over virtio 9P (virt)
+    --snapshot <file>                where to save a snapshot of the machine, when
+                                     the guest executes `slti x0, x0, 0x5a` or after
+                                     --snapshot-at instructions
+    --snapshot-at <n>                save the snapshot after <n> instructions
+    --resume <file>                  start from a snapshot of the same machine
+                                     (<filename> is then optional)
+    --record <file>                  log every input from the host, such as stdin,
+                                     sockets and host entropy, for --replay
+    --replay <file>                  feed a run the inputs logged by --record
+                                     instead of the host's, reproducing it exactly
+    --gdb <[host:]port>              wait for GDB to connect and run under its
+                                     control, with reverse execution
+    --monitor                        run under a built-in monitor, reading commands
+                                     from the terminal, with reverse execution
+    --checkpoint-interval <n>        instructions between the checkpoints kept for
+                                     reverse execution (default: 10M)
+    --dump-dtb <file>                write the device tree to <file> and exit";
+
+/// Flags that do not take a value.
+const SWITCHES: &[&str] = &["parallel", "user", "strace", "semihosting", "sbi", "monitor"];
+
+/// Builds the machine configuration from the command line and returns it
+/// together with the program to run and its arguments.
+fn parse_args(args: &[String]) -> Result<(MachineConfig, String, Vec<String>), String> {
+    let mut config = MachineConfig::default();
+    let mut settings = Vec::new();
+    let mut filename = None;
+    let mut guest_args = Vec::new();
+
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        if filename.is_some() {
+            guest_args.push(arg.clone());
+        } else if let Some(flag) = arg.strip_prefix("--") {
+            let (key, value) = match flag.split_once('=') {
+                Some((key, value)) => (key.to_string(), value.to_string()),
+                None if SWITCHES.contains(&flag) => (flag.to_string(), String::from("true")),
+                None => {
+                    let value = iter
+                        .next()
+                        .ok_or_else(|| format!("missing value for `{}`", arg))?;
+                    (flag.to_string(), value.clone())
+                }
+            };
+            if key == "machine" {
+                config.load_file(&value)?;
+            } else {
+                settings.push((key, value));
+            }
+        } else {
+            filename = Some(arg.clone());
+        }
+    }
+
+    // Flags override whatever the machine file says, regardless of order.
+    // `--user` goes first since it changes the default memory map.
+    settings.sort_by_key(|(key, _)| key != "user");
+    for (key, value) in settings {
+        config.set(&key, &value)?;
+    }
+    config.validate()?;
+
+    if config.sbi && filename.is_some() {
+        return Err(String::from("the built-in SBI boots the kernel directly; drop <filename>"));
+    }
+
+    // Dumping the device tree, booting with the built-in SBI and resuming a
+    // snapshot do not need a program.
+    let filename = filename
+        .or_else(|| config.bios.as_ref().map(|path| path.display().to_string()))
+        .or_else(|| (config.dump_dtb.is_some() || config.sbi || config.resume.is_some()).then(String::new))
+        .ok_or_else(|| String::from("missing <filename>"))?;
+    Ok((config, filename, guest_args))
+}
+
+/// Runs a Linux user-mode program on a single hart and exits with its status.
+fn run_user(config: &MachineConfig, filename: &str, data: Vec<u8>, args: Vec<String>) -> io::Result<()> {
+    let invalid = |e: String| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", filename, e));
+
+    let elf = Elf::parse(&data).map_err(invalid)?;
+    if elf.xlen != 64 {
+        return Err(invalid(String::from("only RV64 programs can run in user mode")));
+    }
+    let mut machine = Machine::new(config, Vec::new())?;
+    let log = machine.bus.lock().unwrap().input_log.clone();
+    let cpu = &mut machine.harts[0];
+    let mut linux = Linux::new(config.strace, HostInput::new("stdin", log.as_ref()));
+
+    let mut argv = vec![filename.to_string()];
+    argv.extend(args);
+    linux.load(cpu, &elf, &data, &argv).map_err(invalid)?;
+
+    match linux.run(cpu, config.quantum) {
+        Ok(status) => process::exit(status),
+        Err(e) => {
+            eprintln!("rvemu: {:?} at pc {:#x}", e, cpu.pc);
+            cpu.dump_registers();
+            process::exit(128 + 11); // as if killed by SIGSEGV
+        }
+    }
+}
+
+/// Writes the device tree the machine would boot with to `path`.
+fn dump_dtb(config: &MachineConfig, path: &Path) -> io::Result<()> {
+    let mut machine = Machine::new(config, Vec::new())?;
+    let dtb = match machine.load_boot_images(config) {
+        Ok(dtb) => dtb.unwrap_or_else(|| machine.device_tree(config, None)),
+        Err(e) => {
+            eprintln!("error: {}", e);
+            process::exit(1);
+        }
+    };
+    fs::write(path, dtb)
+}
 
 fn main() -> io::Result<()> {
     let args: Vec<String> = env::args().collect();
 
-    if args.len() != 2 {
-        panic!("Usage: cargo run <filename>");
+    let (mut config, filename, mut guest_args) = match parse_args(&args[1..]) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            eprintln!("error: {}\n\n{}", e, USAGE);
+            process::exit(2);
+        }
+    };
+    if let Some(path) = &config.dump_dtb {
+        return dump_dtb(&config, path);
     }
-    let mut file = File::open(&args[1])?;
-    let mut code = Vec::new();
-    file.read_to_end(&mut code)?;
 
-    let mut cpu = Cpu::new(code);
+    let mut code = Vec::new();
+    if !filename.is_empty() {
+        File::open(&filename)?.read_to_end(&mut code)?;
+    }
 
-    loop {
-        // 1. Fetch
-        let instruction = match cpu.fetch() {
-            Ok(inst) => inst,
-            Err(_) => break,
-        };
+    if config.user {
+        return run_user(&config, &filename, code, guest_args);
+    }
 
-        // 2. Add 4 to the program counter
-        cpu.pc += 4;
+    let mut machine;
+    let mut symbols = Vec::new();
+    if is_elf(&code) {
+        let invalid = |e: String| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", filename, e));
+        let elf = Elf::parse(&code).map_err(invalid)?;
+        // The ELF class picks the register width unless `xlen` is given.
+        match config.xlen {
+            Some(xlen) if xlen != elf.xlen => {
+                return Err(invalid(format!("an RV{} program cannot run with `xlen = {}`", elf.xlen, xlen)));
+            }
+            _ => config.xlen = Some(elf.xlen),
+        }
+        if let Err(e) = config.validate() {
+            eprintln!("error: {}", e);
+            process::exit(2);
+        }
+        machine = Machine::new(&config, Vec::new())?;
+        machine.load_elf(&elf, &code).map_err(invalid)?;
+        symbols = elf.symbols.clone();
 
-        // 3. Decode
-        // 4. Execute
-        match cpu.execute(instruction as u32) {
-            Ok(_) => (),
-            Err(_) => break,
+        // Programs linked against riscv-pk or newlib's HTIF support talk to
+        // the host through these two symbols.
+        if let Some(tohost) = elf.symbol("tohost") {
+            let mut argv = vec![filename.clone()];
+            argv.append(&mut guest_args);
+            let log = machine.bus.lock().unwrap().input_log.clone();
+            machine.htif = Some(Htif::new(tohost, elf.symbol("fromhost"), argv, config.strace, log.as_ref()));
         }
+    } else {
+        machine = Machine::new(&config, code)?;
+    }
+    if config.semihosting {
+        let mut argv = vec![filename.clone()];
+        argv.append(&mut guest_args);
+        machine.enable_semihosting(&argv);
+    }
+    if let Some(arg) = guest_args.first() {
+        eprintln!("error: unexpected argument `{}`\n\n{}", arg, USAGE);
+        process::exit(2);
+    }
+    if let Err(e) = machine.load_boot_images(&config) {
+        eprintln!("error: {}", e);
+        process::exit(1);
+    }
+    if config.sbi {
+        machine.enable_sbi(&config);
+    }
+    machine.snapshot = config.snapshot.clone();
+    machine.snapshot_at = config.snapshot_at;
+    if let Some(path) = &config.resume {
+        if let Err(e) = machine.load_snapshot(path) {
+            eprintln!("error: {}: {}", path.display(), e);
+            process::exit(1);
+        }
+    }
 
-        if cpu.pc == 0 {
-            break;
+    // The monitor needs the terminal's line editing.
+    let terminal = match config.profile {
+        Profile::Virt if !config.monitor => raw_terminal(),
+        _ => None,
+    };
+    let mut run = true;
+    if let Some(address) = &config.gdb {
+        let debugger = Debugger::new(machine, config.quantum, config.checkpoint_interval);
+        let mut stub = GdbStub::listen(address, debugger)?;
+        let detach = stub.serve();
+        machine = stub.into_debugger().machine;
+        match detach {
+            Ok(Detach::Running) => {}
+            Ok(Detach::Stopped) => run = false,
+            Ok(Detach::Killed) => {
+                restore_terminal(&terminal);
+                process::exit(0);
+            }
+            Err(e) => {
+                restore_terminal(&terminal);
+                return Err(e);
+            }
         }
     }
+    if config.monitor {
+        let debugger = Debugger::new(machine, config.quantum, config.checkpoint_interval);
+        let mut monitor = Monitor::new(debugger, &symbols);
+        monitor.run();
+        machine = monitor.into_debugger().machine;
+        run = false;
+    }
+    if run && config.parallel {
+        machine.run_parallel();
+    } else if run {
+        machine.run(config.quantum);
+    }
+    restore_terminal(&terminal);
+
+    if let Some(status) = machine.exit_status {
+        process::exit(status);
+    }
 
-    cpu.dump_registers();
+    for hart in &machine.harts {
+        if config.harts > 1 {
+            println!("hart {}:", hart.hartid);
+        }
+        hart.dump_registers();
+    }
 
     Ok(())
 }
@@ -0,0 +1,541 @@
+use std::io::{self, BufRead, Write};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+
+use crate::cpu::*;
+use crate::debugger::*;
+use crate::disasm::*;
+use crate::rvc::*;
+
+const HELP: &str = "Commands (values are numbers, registers or symbols, with an optional +/- offset):
+    step [n]                   s   execute n instructions of the current hart (default 1)
+    continue                   c   run until a breakpoint or watchpoint; Enter stops the machine
+    rstep [n]                  rs  go back n instructions of the current hart
+    rcontinue                  rc  run backwards to the previous breakpoint or watchpoint
+    regs                       r   show the integer registers
+    fregs                          show the floating-point registers
+    print <value>              p   show a value, such as a register, CSR or symbol
+    set <reg> <value>              set a register, CSR, pc or priv
+    x <addr> [len]                 examine memory (default 64 bytes)
+    write <addr> <value> [size]    write a value of 1, 2, 4 or 8 bytes (default 8)
+    dis [addr] [n]                 disassemble n instructions (default: around pc)
+    break [addr]               b   set a breakpoint, or list them with the watchpoints
+    delete <addr>              d   remove a breakpoint
+    watch <addr> [len] [r|w|rw]    stop on accesses to memory (default 8 bytes written)
+    unwatch <addr> [len] [r|w|rw]  remove a watchpoint
+    csr [name]                     show the CSRs, or one of them
+    dev                            show the state of the devices
+    hart [n]                       show or select the hart commands apply to
+    quit                       q   leave the monitor";
+
+/// Instructions `dis` shows by default.
+const DISASSEMBLE_COUNT: u64 = 10;
+
+/// A register the monitor can read and write.
+#[derive(Clone, Copy)]
+enum Register {
+    X(usize),
+    F(usize),
+    Pc,
+    Priv,
+    Csr(usize),
+}
+
+impl Register {
+    fn parse(name: &str) -> Option<Register> {
+        let index = |prefix: &str| name.strip_prefix(prefix)?.parse::<usize>().ok().filter(|&i| i < 32);
+        if let Some(reg) = index("x") {
+            return Some(Register::X(reg));
+        }
+        if let Some(reg) = index("f") {
+            return Some(Register::F(reg));
+        }
+        let position = |names: &[&str]| names.iter().position(|&n| n == name);
+        if let Some(reg) = position(&ABI_NAMES) {
+            return Some(Register::X(reg));
+        }
+        if let Some(reg) = position(&FP_ABI_NAMES) {
+            return Some(Register::F(reg));
+        }
+        match name {
+            "fp" => Some(Register::X(8)),
+            "pc" => Some(Register::Pc),
+            "priv" => Some(Register::Priv),
+            _ => CSR_NAMES.iter().find(|(n, _)| *n == name).map(|&(_, csr)| Register::Csr(csr)),
+        }
+    }
+
+    fn read(self, hart: &Cpu) -> u64 {
+        match self {
+            Register::X(reg) => hart.truncate(hart.regs[reg]),
+            Register::F(reg) => hart.fregs[reg],
+            Register::Pc => hart.pc,
+            Register::Priv => hart.mode,
+            Register::Csr(csr) => hart.load_csr(csr),
+        }
+    }
+
+    fn write(self, hart: &mut Cpu, value: u64) {
+        match self {
+            Register::X(0) => {}
+            Register::X(reg) => hart.regs[reg] = hart.sign_extend(value),
+            Register::F(reg) => hart.fregs[reg] = value,
+            Register::Pc => hart.pc = hart.truncate(value),
+            // Mode 2 is reserved.
+            Register::Priv if value & 3 == 2 => {}
+            Register::Priv => hart.mode = value & 3,
+            Register::Csr(csr) => hart.store_csr(csr, value),
+        }
+    }
+}
+
+/// A command line on the terminal for running and examining the machine,
+/// with the same history as the GDB stub for going backwards.
+pub struct Monitor {
+    debugger: Debugger,
+    /// Named addresses from the ELF file, sorted by address.
+    symbols: Vec<(String, u64)>,
+    /// Lines typed on stdin, read on their own thread so that a running
+    /// machine can be stopped.
+    lines: Receiver<String>,
+    /// The hart commands apply to.
+    hart: usize,
+}
+
+impl Monitor {
+    pub fn new(debugger: Debugger, symbols: &[(String, u64)]) -> Self {
+        let (sender, lines) = mpsc::channel();
+        thread::spawn(move || {
+            for line in io::stdin().lock().lines() {
+                let Ok(line) = line else {
+                    break;
+                };
+                if sender.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let mut symbols: Vec<(String, u64)> =
+            symbols.iter().filter(|(name, _)| !name.is_empty()).cloned().collect();
+        symbols.sort_by_key(|&(_, addr)| addr);
+        let hart = debugger.hart;
+        Self {
+            debugger,
+            symbols,
+            lines,
+            hart,
+        }
+    }
+
+    pub fn into_debugger(self) -> Debugger {
+        self.debugger
+    }
+
+    /// Reads commands until `quit` or the end of input.
+    pub fn run(&mut self) {
+        println!("rvemu monitor; type `help` for the commands");
+        self.show_location();
+        loop {
+            print!("(rvemu) ");
+            let _ = io::stdout().flush();
+            let Ok(line) = self.lines.recv() else {
+                println!();
+                return;
+            };
+            let words: Vec<&str> = line.split_whitespace().collect();
+            match words.first() {
+                None => {}
+                Some(&"quit" | &"q") => return,
+                Some(_) => {
+                    if let Err(e) = self.command(&words) {
+                        println!("{}", e);
+                    }
+                }
+            }
+        }
+    }
+
+    fn command(&mut self, words: &[&str]) -> Result<(), String> {
+        let args = &words[1..];
+        let arg = |i: usize| args.get(i).copied();
+        match words[0] {
+            "step" | "s" => {
+                let count = arg(0).map_or(Ok(1), |count| self.value(count))?;
+                let mut stop = Stop::Step;
+                for _ in 0..count {
+                    stop = self.debugger.resume(Some(self.hart), &mut || false);
+                    if stop != Stop::Step {
+                        break;
+                    }
+                }
+                self.stopped(stop);
+            }
+            "continue" | "c" => {
+                println!("running; press Enter to stop");
+                let lines = &self.lines;
+                let stop = self.debugger.resume(None, &mut || lines.try_recv().is_ok());
+                self.stopped(stop);
+            }
+            "rstep" | "rs" => {
+                let count = arg(0).map_or(Ok(1), |count| self.value(count))?;
+                let mut stop = Stop::Step;
+                for _ in 0..count {
+                    stop = self.debugger.reverse(Some(self.hart));
+                    if stop != Stop::Step {
+                        break;
+                    }
+                }
+                self.stopped(stop);
+            }
+            "rcontinue" | "rc" => {
+                let stop = self.debugger.reverse(None);
+                self.stopped(stop);
+            }
+            "regs" | "r" => {
+                let hart = &self.debugger.machine.harts[self.hart];
+                println!("pc={:#x} priv={} virt={}", hart.pc, hart.mode, hart.virt as u8);
+                hart.dump_registers();
+            }
+            "fregs" => {
+                let hart = &self.debugger.machine.harts[self.hart];
+                for (i, value) in hart.fregs.iter().enumerate() {
+                    let end = if i % 4 == 3 { "\n" } else { " " };
+                    print!("f{:02}({:>4})={:>#18x}{}", i, FP_ABI_NAMES[i], value, end);
+                }
+            }
+            "print" | "p" => {
+                let text = arg(0).ok_or("usage: print <value>")?;
+                let value = self.value(text)?;
+                match Register::parse(text) {
+                    Some(Register::F(_)) => println!("{:#x} ({})", value, f64::from_bits(value)),
+                    _ => println!("{:#x} ({}){}", value, value as i64, self.describe(value)),
+                }
+            }
+            "set" => {
+                let (Some(name), Some(value)) = (arg(0), arg(1)) else {
+                    return Err(String::from("usage: set <reg> <value>"));
+                };
+                let reg = Register::parse(name).ok_or_else(|| format!("unknown register `{}`", name))?;
+                let value = self.value(value)?;
+                reg.write(&mut self.debugger.machine.harts[self.hart], value);
+                self.debugger.modified();
+            }
+            "x" => {
+                let addr = self.value(arg(0).ok_or("usage: x <addr> [len]")?)?;
+                let len = arg(1).map_or(Ok(64), |len| self.value(len))?;
+                for line in (0..len).step_by(16) {
+                    let start = addr.wrapping_add(line);
+                    let bytes = self
+                        .debugger
+                        .read_memory(self.hart, start, (len - line).min(16))
+                        .ok_or_else(|| format!("cannot read memory at {:#x}", start))?;
+                    let hex: Vec<String> = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+                    let text: String = bytes
+                        .iter()
+                        .map(|&byte| if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' })
+                        .collect();
+                    println!("{:#018x}: {:<48}{}", start, hex.join(" "), text);
+                }
+            }
+            "write" | "w" => {
+                let (Some(addr), Some(value)) = (arg(0), arg(1)) else {
+                    return Err(String::from("usage: write <addr> <value> [size]"));
+                };
+                let (addr, value) = (self.value(addr)?, self.value(value)?);
+                let size = arg(2).map_or(Ok(8), |size| self.value(size))?;
+                if !matches!(size, 1 | 2 | 4 | 8) {
+                    return Err(String::from("the size is 1, 2, 4 or 8 bytes"));
+                }
+                self.debugger
+                    .write_memory(self.hart, addr, &value.to_le_bytes()[..size as usize])
+                    .ok_or_else(|| format!("cannot write memory at {:#x}", addr))?;
+            }
+            "dis" => {
+                let pc = self.debugger.machine.harts[self.hart].pc;
+                let start = match arg(0) {
+                    Some(addr) => self.value(addr)?,
+                    None => self.start_before(pc),
+                };
+                let count = arg(1).map_or(Ok(DISASSEMBLE_COUNT), |count| self.value(count))?;
+                let mut addr = start;
+                for _ in 0..count {
+                    if let Some(label) = self.label(addr) {
+                        println!("{}:", label);
+                    }
+                    let marker = if addr == pc { "=>" } else { "  " };
+                    match self.instruction(addr) {
+                        Some((len, text)) => {
+                            println!("{} {:#x}: {}", marker, addr, text);
+                            addr = addr.wrapping_add(len);
+                        }
+                        None => {
+                            println!("{} {:#x}: cannot read memory", marker, addr);
+                            break;
+                        }
+                    }
+                }
+            }
+            "break" | "b" => match arg(0) {
+                Some(addr) => {
+                    let addr = self.value(addr)?;
+                    self.debugger.breakpoints.insert(addr);
+                    println!("breakpoint at {:#x}{}", addr, self.describe(addr));
+                }
+                None => {
+                    for &addr in &self.debugger.breakpoints {
+                        println!("breakpoint at {:#x}{}", addr, self.describe(addr));
+                    }
+                    for watch in self.debugger.watchpoints() {
+                        println!("watchpoint on {:#x}, {} bytes, {:?}", watch.addr, watch.len, watch.kind);
+                    }
+                }
+            },
+            "delete" | "d" => {
+                let addr = self.value(arg(0).ok_or("usage: delete <addr>")?)?;
+                if !self.debugger.breakpoints.remove(&addr) {
+                    return Err(format!("no breakpoint at {:#x}", addr));
+                }
+            }
+            "watch" | "unwatch" => {
+                let addr = self.value(arg(0).ok_or_else(|| format!("usage: {} <addr> [len] [r|w|rw]", words[0]))?)?;
+                let len = arg(1).map_or(Ok(8), |len| self.value(len))?;
+                let kind = match arg(2).unwrap_or("w") {
+                    "w" => WatchKind::Write,
+                    "r" => WatchKind::Read,
+                    "rw" => WatchKind::Access,
+                    kind => return Err(format!("unknown kind of watchpoint `{}`", kind)),
+                };
+                let watch = Watchpoint { addr, len, kind };
+                if words[0] == "watch" {
+                    self.debugger.add_watchpoint(watch);
+                } else if !self.debugger.remove_watchpoint(watch) {
+                    return Err(String::from("no such watchpoint"));
+                }
+            }
+            "csr" => {
+                let hart = &self.debugger.machine.harts[self.hart];
+                match arg(0) {
+                    Some(name) => {
+                        let &(_, csr) = CSR_NAMES
+                            .iter()
+                            .find(|(n, _)| *n == name)
+                            .ok_or_else(|| format!("unknown CSR `{}`", name))?;
+                        println!("{:<12}{:#x}", name, hart.load_csr(csr));
+                    }
+                    None => {
+                        for &(name, csr) in CSR_NAMES {
+                            println!("{:<12}{:#x}", name, hart.load_csr(csr));
+                        }
+                    }
+                }
+            }
+            "dev" => {
+                let states = self.debugger.machine.bus.lock().unwrap().dump_state();
+                if states.is_empty() {
+                    println!("no devices");
+                }
+                for state in states {
+                    println!("{}", state);
+                }
+            }
+            "hart" => match arg(0) {
+                Some(hart) => {
+                    let hart = self.value(hart)? as usize;
+                    if hart >= self.debugger.machine.harts.len() {
+                        return Err(format!("there is no hart {}", hart));
+                    }
+                    self.hart = hart;
+                    self.show_location();
+                }
+                None => println!("hart {} of {}", self.hart, self.debugger.machine.harts.len()),
+            },
+            "help" | "h" => println!("{}", HELP),
+            command => return Err(format!("unknown command `{}`; type `help` for the commands", command)),
+        }
+        Ok(())
+    }
+
+    /// Reports why the machine stopped and where.
+    fn stopped(&mut self, stop: Stop) {
+        match stop {
+            Stop::Step => {}
+            Stop::Breakpoint => println!("breakpoint on hart {}", self.debugger.hart),
+            Stop::Watchpoint(watch) => println!("watchpoint on {:#x} hit by hart {}", watch.addr, self.debugger.hart),
+            Stop::Interrupted => println!("stopped"),
+            Stop::HistoryStart => println!("reached the start of the history"),
+            Stop::Exited(status) => {
+                println!("the guest exited with status {}", status);
+                return;
+            }
+            Stop::Halted => {
+                println!("every hart has stopped");
+                return;
+            }
+        }
+        self.hart = self.debugger.hart;
+        self.show_location();
+    }
+
+    fn show_location(&mut self) {
+        let pc = self.debugger.machine.harts[self.hart].pc;
+        let text = self.instruction(pc).map_or_else(|| String::from("cannot read memory"), |(_, text)| text);
+        println!("hart {} => {:#x}{}: {}", self.hart, pc, self.describe(pc), text);
+    }
+
+    /// The raw encoding and disassembly of the instruction at `addr`, and its
+    /// length.
+    fn instruction(&mut self, addr: u64) -> Option<(u64, String)> {
+        let low = self.debugger.read_memory(self.hart, addr, 2)?;
+        let low = u16::from_le_bytes([low[0], low[1]]);
+        if low & 3 != 3 {
+            let xlen = self.debugger.machine.harts[self.hart].xlen;
+            let text = expand_compressed(low, xlen).map_or_else(|| String::from(".short"), |inst| disassemble(inst, addr));
+            return Some((2, format!("{:04x}      {}", low, text)));
+        }
+        let bytes = self.debugger.read_memory(self.hart, addr, 4)?;
+        let inst = u32::from_le_bytes(bytes.try_into().unwrap());
+        Some((4, format!("{:08x}  {}", inst, disassemble(inst, addr))))
+    }
+
+    /// Where to start disassembling so that a few instructions before `pc`
+    /// show. Compressed instructions make this a guess: the furthest start
+    /// that decodes to an instruction boundary at pc wins.
+    fn start_before(&mut self, pc: u64) -> u64 {
+        for back in (2..=16).rev().step_by(2) {
+            let mut addr = pc.wrapping_sub(back);
+            while addr < pc {
+                match self.instruction(addr) {
+                    Some((len, _)) => addr += len,
+                    None => break,
+                }
+            }
+            if addr == pc {
+                return pc.wrapping_sub(back);
+            }
+        }
+        pc
+    }
+
+    /// The symbol at `addr`, if any.
+    fn label(&self, addr: u64) -> Option<&str> {
+        self.symbols.iter().find(|&&(_, a)| a == addr).map(|(name, _)| name.as_str())
+    }
+
+    /// `<symbol+offset>` for the closest symbol at or before `addr`.
+    fn describe(&self, addr: u64) -> String {
+        let index = self.symbols.partition_point(|&(_, a)| a <= addr);
+        match index.checked_sub(1).map(|i| &self.symbols[i]) {
+            Some((name, a)) if *a == addr => format!(" <{}>", name),
+            Some((name, a)) if addr - a < 0x10000 => format!(" <{}+{:#x}>", name, addr - a),
+            _ => String::new(),
+        }
+    }
+
+    /// Evaluates a number, register or symbol, plus or minus another value.
+    fn value(&self, text: &str) -> Result<u64, String> {
+        if let Some((left, right)) = text.rsplit_once('+').filter(|(left, _)| !left.is_empty()) {
+            return Ok(self.value(left)?.wrapping_add(self.value(right)?));
+        }
+        if let Some((left, right)) = text.rsplit_once('-').filter(|(left, _)| !left.is_empty()) {
+            return Ok(self.value(left)?.wrapping_sub(self.value(right)?));
+        }
+        if let Some(negated) = text.strip_prefix('-') {
+            return Ok(self.value(negated)?.wrapping_neg());
+        }
+        if let Some(hex) = text.strip_prefix("0x") {
+            return u64::from_str_radix(hex, 16).map_err(|_| format!("invalid number `{}`", text));
+        }
+        if text.starts_with(|c: char| c.is_ascii_digit()) {
+            return text.parse().map_err(|_| format!("invalid number `{}`", text));
+        }
+        if let Some(reg) = Register::parse(text) {
+            return Ok(reg.read(&self.debugger.machine.harts[self.hart]));
+        }
+        self.symbols
+            .iter()
+            .find(|(name, _)| name == text)
+            .map(|&(_, addr)| addr)
+            .ok_or_else(|| format!("unknown register or symbol `{}`", text))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::DRAM_BASE;
+    use crate::config::*;
+    use crate::testing::*;
+
+    /// A monitor with no terminal, for a machine running `program`.
+    fn monitor(program: &[u32]) -> Monitor {
+        let debugger = Debugger::new(machine(&MachineConfig::default(), program), 1, 16);
+        // Sorted by address, as `new` leaves them
+        let symbols = vec![(String::from("start"), DRAM_BASE), (String::from("loop"), DRAM_BASE + 8)];
+        let (_, lines) = mpsc::channel();
+        Monitor { debugger, symbols, lines, hart: 0 }
+    }
+
+    #[test]
+    fn evaluates_values() {
+        let mut monitor = monitor(&[]);
+        monitor.debugger.machine.harts[0].regs[10] = 0x40;
+        assert_eq!(monitor.value("16"), Ok(16));
+        assert_eq!(monitor.value("0x10"), Ok(16));
+        assert_eq!(monitor.value("-1"), Ok(u64::MAX));
+        assert_eq!(monitor.value("a0"), Ok(0x40));
+        assert_eq!(monitor.value("x10+8-2"), Ok(0x46));
+        assert_eq!(monitor.value("pc"), Ok(DRAM_BASE));
+        assert_eq!(monitor.value("loop+4"), Ok(DRAM_BASE + 12));
+        assert_eq!(monitor.value("misa"), Ok(monitor.debugger.machine.harts[0].load_csr(MISA)));
+        assert!(monitor.value("0xg").is_err());
+        assert!(monitor.value("nowhere").is_err());
+
+        assert_eq!(monitor.describe(DRAM_BASE + 8), " <loop>");
+        assert_eq!(monitor.describe(DRAM_BASE + 10), " <loop+0x2>");
+        assert_eq!(monitor.describe(DRAM_BASE - 2), "");
+        assert_eq!(monitor.label(DRAM_BASE), Some("start"));
+    }
+
+    #[test]
+    fn commands_change_the_machine() {
+        let mut monitor = monitor(&[addi(A0, A0, 1), addi(A0, A0, 1), addi(A0, A0, 1), jalr(ZERO, ZERO, 0)]);
+        monitor.command(&["set", "a0", "5"]).unwrap();
+        monitor.command(&["set", "priv", "2"]).unwrap();
+        assert_eq!(monitor.debugger.machine.harts[0].mode, 3);
+        monitor.command(&["s", "2"]).unwrap();
+        assert_eq!(monitor.value("a0"), Ok(7));
+        monitor.command(&["rs"]).unwrap();
+        assert_eq!(monitor.value("a0"), Ok(6));
+
+        monitor.command(&["write", "start+0x100", "0x1234", "2"]).unwrap();
+        assert_eq!(monitor.debugger.read_memory(0, DRAM_BASE + 0x100, 3), Some(vec![0x34, 0x12, 0]));
+        assert!(monitor.command(&["write", "start", "1", "3"]).is_err());
+        assert!(monitor.command(&["x", "0"]).is_err());
+
+        monitor.command(&["b", "loop+4"]).unwrap();
+        monitor.command(&["c"]).unwrap();
+        assert_eq!(monitor.value("pc"), Ok(DRAM_BASE + 12));
+        monitor.command(&["d", "loop+4"]).unwrap();
+        assert!(monitor.command(&["d", "loop+4"]).is_err());
+        monitor.command(&["watch", "start+0x100", "2", "rw"]).unwrap();
+        assert!(monitor.command(&["unwatch", "start+0x100"]).is_err());
+        monitor.command(&["unwatch", "start+0x100", "2", "rw"]).unwrap();
+        assert!(monitor.command(&["watch", "0", "1", "x"]).is_err());
+
+        assert!(monitor.command(&["hart", "1"]).is_err());
+        assert!(monitor.command(&["set", "q9", "1"]).is_err());
+        assert!(monitor.command(&["frobnicate"]).is_err());
+    }
+
+    #[test]
+    fn disassembles_compressed_instructions() {
+        // c.addi a0, 1 and c.nop, then addi a0, a0, 1
+        let mut monitor = monitor(&[0x0001_0505, addi(A0, A0, 1)]);
+        assert_eq!(monitor.instruction(DRAM_BASE), Some((2, String::from("0505      addi    a0, a0, 1"))));
+        assert_eq!(monitor.instruction(DRAM_BASE + 2), Some((2, String::from("0001      nop"))));
+        assert_eq!(monitor.instruction(DRAM_BASE + 4), Some((4, String::from("00150513  addi    a0, a0, 1"))));
+        assert_eq!(monitor.instruction(0), None);
+        assert_eq!(monitor.start_before(DRAM_BASE + 4), DRAM_BASE);
+    }
+}
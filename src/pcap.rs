@@ -0,0 +1,93 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const MAGIC_MICROS: u32 = 0xa1b2_c3d4;
+const MAGIC_NANOS: u32 = 0xa1b2_3c4d;
+const VERSION_MAJOR: u16 = 2;
+const VERSION_MINOR: u16 = 4;
+const SNAPLEN: u32 = 65535;
+const LINKTYPE_ETHERNET: u32 = 1;
+
+const HEADER_SIZE: usize = 24;
+const RECORD_HEADER_SIZE: usize = 16;
+
+/// Writes Ethernet frames to a capture file that Wireshark and tcpdump can
+/// read, timestamped with the host clock.
+pub struct PcapWriter {
+    file: File,
+}
+
+impl PcapWriter {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let mut file = File::create(path)?;
+        let mut header = Vec::with_capacity(HEADER_SIZE);
+        header.extend_from_slice(&MAGIC_MICROS.to_le_bytes());
+        header.extend_from_slice(&VERSION_MAJOR.to_le_bytes());
+        header.extend_from_slice(&VERSION_MINOR.to_le_bytes());
+        header.extend_from_slice(&0i32.to_le_bytes()); // thiszone
+        header.extend_from_slice(&0u32.to_le_bytes()); // sigfigs
+        header.extend_from_slice(&SNAPLEN.to_le_bytes());
+        header.extend_from_slice(&LINKTYPE_ETHERNET.to_le_bytes());
+        file.write_all(&header)?;
+        Ok(Self { file })
+    }
+
+    pub fn write(&mut self, frame: &[u8]) -> io::Result<()> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let len = frame.len().min(SNAPLEN as usize);
+        let mut record = Vec::with_capacity(RECORD_HEADER_SIZE + len);
+        record.extend_from_slice(&(now.as_secs() as u32).to_le_bytes());
+        record.extend_from_slice(&now.subsec_micros().to_le_bytes());
+        record.extend_from_slice(&(len as u32).to_le_bytes());
+        record.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        record.extend_from_slice(&frame[..len]);
+        // One write per frame, so the file is usable while the guest runs.
+        self.file.write_all(&record)
+    }
+}
+
+/// Reads every frame of an Ethernet capture file, in either byte order and
+/// with either timestamp resolution.
+pub fn read_pcap(path: &Path) -> io::Result<Vec<Vec<u8>>> {
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message);
+    let data = fs::read(path)?;
+    if data.len() < HEADER_SIZE {
+        return Err(invalid("truncated pcap header"));
+    }
+
+    let magic = u32::from_le_bytes(data[0..4].try_into().unwrap());
+    let little_endian = match magic {
+        MAGIC_MICROS | MAGIC_NANOS => true,
+        _ if magic.swap_bytes() == MAGIC_MICROS || magic.swap_bytes() == MAGIC_NANOS => false,
+        _ => return Err(invalid("not a pcap file")),
+    };
+    let word = |offset: usize| {
+        let bytes = data[offset..offset + 4].try_into().unwrap();
+        if little_endian {
+            u32::from_le_bytes(bytes)
+        } else {
+            u32::from_be_bytes(bytes)
+        }
+    };
+    if word(20) != LINKTYPE_ETHERNET {
+        return Err(invalid("only Ethernet captures can be replayed"));
+    }
+
+    let mut frames = Vec::new();
+    let mut offset = HEADER_SIZE;
+    while offset < data.len() {
+        if offset + RECORD_HEADER_SIZE > data.len() {
+            return Err(invalid("truncated record header"));
+        }
+        let len = word(offset + 8) as usize;
+        let start = offset + RECORD_HEADER_SIZE;
+        let frame = data
+            .get(start..start + len)
+            .ok_or_else(|| invalid("truncated record"))?;
+        frames.push(frame.to_vec());
+        offset = start + len;
+    }
+    Ok(frames)
+}
@@ -0,0 +1,297 @@
+use std::io;
+
+use crate::device::*;
+use crate::exception::*;
+use crate::fdt::*;
+use crate::snapshot::*;
+
+pub const PLIC_BASE: u64 = 0x0c00_0000;
+pub const PLIC_SIZE: u64 = 0x400_0000;
+/// Interrupt sources 1 to 95; source 0 means "no interrupt".
+pub const PLIC_SOURCES: u32 = 96;
+
+const PRIORITY: u64 = 0x0;
+const PENDING: u64 = 0x1000;
+const ENABLE: u64 = 0x2000;
+const ENABLE_STRIDE: u64 = 0x80;
+const CONTEXT: u64 = 0x20_0000;
+const CONTEXT_STRIDE: u64 = 0x1000;
+
+/// Platform-level interrupt controller with two contexts per hart: 2h for
+/// M-mode and 2h + 1 for S-mode.
+///
+/// Sources are level-triggered: one is pending while its device holds the
+/// line up and it has not been claimed.
+pub struct Plic {
+    priority: Vec<u32>,
+    enable: Vec<u128>,
+    threshold: Vec<u32>,
+    /// Sources claimed and not yet completed.
+    claimed: u128,
+    /// Interrupt lines raised by the devices, updated by `Bus`.
+    pub lines: u128,
+}
+
+impl Plic {
+    pub fn new(harts: usize) -> Self {
+        Self {
+            priority: vec![0; PLIC_SOURCES as usize],
+            enable: vec![0; 2 * harts],
+            threshold: vec![0; 2 * harts],
+            claimed: 0,
+            lines: 0,
+        }
+    }
+
+    fn pending(&self) -> u128 {
+        self.lines & !self.claimed & !1
+    }
+
+    /// The highest-priority source that can interrupt a context; the lowest
+    /// id wins a tie.
+    fn best(&self, context: usize) -> Option<u32> {
+        let candidates = self.pending() & self.enable[context];
+        (1..PLIC_SOURCES)
+            .filter(|&src| candidates >> src & 1 == 1 && self.priority[src as usize] > self.threshold[context])
+            .max_by_key(|&src| (self.priority[src as usize], std::cmp::Reverse(src)))
+    }
+
+    /// Whether the external interrupt line of a context is raised.
+    pub fn interrupting(&self, context: usize) -> bool {
+        self.best(context).is_some()
+    }
+
+    fn read32(&mut self, offset: u64) -> Option<u32> {
+        let contexts = self.enable.len() as u64;
+        match offset {
+            PRIORITY..PENDING => self.priority.get((offset / 4) as usize).copied(),
+            PENDING..ENABLE => (offset - PENDING < 12).then(|| (self.pending() >> ((offset - PENDING) * 8)) as u32),
+            ENABLE..CONTEXT => {
+                let (context, word) = ((offset - ENABLE) / ENABLE_STRIDE, (offset - ENABLE) % ENABLE_STRIDE / 4);
+                (context < contexts && word < 3).then(|| (self.enable[context as usize] >> (word * 32)) as u32)
+            }
+            _ => {
+                let (context, reg) = ((offset - CONTEXT) / CONTEXT_STRIDE, (offset - CONTEXT) % CONTEXT_STRIDE);
+                if context >= contexts {
+                    return None;
+                }
+                let context = context as usize;
+                match reg {
+                    0 => Some(self.threshold[context]),
+                    4 => {
+                        // Claim
+                        let source = self.best(context).unwrap_or(0);
+                        if source != 0 {
+                            self.claimed |= 1 << source;
+                        }
+                        Some(source)
+                    }
+                    _ => None,
+                }
+            }
+        }
+    }
+
+    fn write32(&mut self, offset: u64, value: u32) -> Option<()> {
+        let contexts = self.enable.len() as u64;
+        match offset {
+            PRIORITY..PENDING => *self.priority.get_mut((offset / 4) as usize)? = value & 0x7,
+            // Pending bits are read-only.
+            PENDING..ENABLE => {}
+            ENABLE..CONTEXT => {
+                let (context, word) = ((offset - ENABLE) / ENABLE_STRIDE, (offset - ENABLE) % ENABLE_STRIDE / 4);
+                if context >= contexts || word >= 3 {
+                    return None;
+                }
+                let enable = &mut self.enable[context as usize];
+                *enable = (*enable & !(0xffff_ffff << (word * 32))) | ((value as u128) << (word * 32));
+                // Source 0 does not exist.
+                *enable &= !1;
+            }
+            _ => {
+                let (context, reg) = ((offset - CONTEXT) / CONTEXT_STRIDE, (offset - CONTEXT) % CONTEXT_STRIDE);
+                if context >= contexts {
+                    return None;
+                }
+                let context = context as usize;
+                match reg {
+                    0 => self.threshold[context] = value & 0x7,
+                    4 => {
+                        // Complete
+                        if value < PLIC_SOURCES && self.enable[context] >> value & 1 == 1 {
+                            self.claimed &= !(1 << value);
+                        }
+                    }
+                    _ => return None,
+                }
+            }
+        }
+        Some(())
+    }
+}
+
+impl Device for Plic {
+    fn base(&self) -> u64 {
+        PLIC_BASE
+    }
+
+    fn size(&self) -> u64 {
+        PLIC_SIZE
+    }
+
+    fn device_tree(&self, fdt: &mut Fdt, harts: usize) {
+        fdt.begin_node(&format!("plic@{:x}", PLIC_BASE));
+        fdt.property_strings("compatible", &["sifive,plic-1.0.0", "riscv,plic0"]);
+        fdt.property_reg(PLIC_BASE, PLIC_SIZE);
+        fdt.property_u32("#address-cells", 0);
+        fdt.property_u32("#interrupt-cells", 1);
+        fdt.property_null("interrupt-controller");
+        fdt.property_u32("riscv,ndev", PLIC_SOURCES - 1);
+        fdt.property_u32("phandle", IRQ_PHANDLE);
+        // The M-mode and S-mode external interrupts of every hart, in
+        // context order
+        let cells: Vec<u32> = (0..harts)
+            .flat_map(|hart| [cpu_intc_phandle(hart), 11, cpu_intc_phandle(hart), 9])
+            .collect();
+        fdt.property_cells("interrupts-extended", &cells);
+        fdt.end_node();
+    }
+
+    fn load(&mut self, offset: u64, size: u64) -> Result<u64, Exception> {
+        let word = offset & !3;
+        let value = self.read32(word).ok_or(Exception::LoadAccessFault(PLIC_BASE + offset))?;
+        Ok(read_reg(value as u64, offset - word, size.min(32)))
+    }
+
+    fn store(&mut self, offset: u64, size: u64, value: u64) -> Result<(), Exception> {
+        let word = offset & !3;
+        let fault = Exception::StoreAMOAccessFault(PLIC_BASE + offset);
+        // Only whole registers can be written.
+        if word != offset || size < 32 {
+            return Err(fault);
+        }
+        self.write32(word, value as u32).ok_or(fault)
+    }
+
+    fn dump_state(&self) -> Option<String> {
+        let mut state = format!("plic: pending={:#x} claimed={:#x}", self.pending(), self.claimed);
+        for (context, (&enable, &threshold)) in self.enable.iter().zip(&self.threshold).enumerate() {
+            let mode = if context % 2 == 0 { 'M' } else { 'S' };
+            state += &format!(
+                "\n  hart {} {}-mode: enable={:#x} threshold={}",
+                context / 2,
+                mode,
+                enable,
+                threshold
+            );
+        }
+        let priorities: Vec<String> = (1..PLIC_SOURCES as usize)
+            .filter(|&src| self.priority[src] != 0)
+            .map(|src| format!("{}:{}", src, self.priority[src]))
+            .collect();
+        if !priorities.is_empty() {
+            state += &format!("\n  priorities {}", priorities.join(" "));
+        }
+        Some(state)
+    }
+
+    /// The lines are left out; they are sampled from the devices again.
+    fn save(&self, out: &mut StateWriter) {
+        for &priority in &self.priority {
+            out.u32(priority);
+        }
+        out.u64(self.enable.len() as u64);
+        for (&enable, &threshold) in self.enable.iter().zip(&self.threshold) {
+            out.u128(enable);
+            out.u32(threshold);
+        }
+        out.u128(self.claimed);
+    }
+
+    fn restore(&mut self, input: &mut StateReader) -> io::Result<()> {
+        for priority in self.priority.iter_mut() {
+            *priority = input.u32()?;
+        }
+        input.count(self.enable.len(), "interrupt contexts")?;
+        for (enable, threshold) in self.enable.iter_mut().zip(self.threshold.iter_mut()) {
+            *enable = input.u128()?;
+            *threshold = input.u32()?;
+        }
+        self.claimed = input.u128()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(plic: &mut Plic, offset: u64, value: u32) {
+        plic.store(offset, 32, value as u64).unwrap();
+    }
+
+    fn read(plic: &mut Plic, offset: u64) -> u32 {
+        plic.load(offset, 32).unwrap() as u32
+    }
+
+    /// The offset of a register of context 1, hart 0's S-mode.
+    fn s_mode(offset: u64) -> u64 {
+        CONTEXT + CONTEXT_STRIDE + offset
+    }
+
+    #[test]
+    fn claims_the_highest_priority_source() {
+        let mut plic = Plic::new(1);
+        write(&mut plic, 4 * 3, 1);
+        write(&mut plic, 4 * 10, 5);
+        write(&mut plic, 4 * 40, 5);
+        write(&mut plic, ENABLE + ENABLE_STRIDE, 1 << 3 | 1 << 10);
+        write(&mut plic, ENABLE + ENABLE_STRIDE + 4, 1 << (40 - 32));
+        plic.lines = 1 << 3 | 1 << 10 | 1 << 40;
+        assert_eq!(read(&mut plic, PENDING), 1 << 3 | 1 << 10);
+        assert_eq!(read(&mut plic, PENDING + 4), 1 << 8);
+
+        // Hart 0's M-mode enabled nothing.
+        assert!(!plic.interrupting(0));
+        assert!(plic.interrupting(1));
+        // Sources 10 and 40 tie and the lower id wins.
+        assert_eq!(read(&mut plic, s_mode(4)), 10);
+        assert_eq!(read(&mut plic, s_mode(4)), 40);
+        assert_eq!(read(&mut plic, s_mode(4)), 3);
+        assert_eq!(read(&mut plic, s_mode(4)), 0);
+        assert!(!plic.interrupting(1));
+
+        // Completing lets a source still held up interrupt again.
+        write(&mut plic, s_mode(4), 10);
+        assert_eq!(read(&mut plic, s_mode(4)), 10);
+    }
+
+    #[test]
+    fn threshold_masks_low_priorities() {
+        let mut plic = Plic::new(1);
+        write(&mut plic, 4 * 7, 3);
+        write(&mut plic, ENABLE, 1 << 7);
+        plic.lines = 1 << 7;
+        write(&mut plic, CONTEXT, 3);
+        assert!(!plic.interrupting(0));
+        write(&mut plic, CONTEXT, 2);
+        assert!(plic.interrupting(0));
+        // Priorities and thresholds have three bits.
+        write(&mut plic, CONTEXT, 0xff);
+        assert_eq!(read(&mut plic, CONTEXT), 7);
+    }
+
+    #[test]
+    fn rejects_partial_and_out_of_range_accesses() {
+        let mut plic = Plic::new(1);
+        assert!(plic.store(4, 8, 1).is_err());
+        assert!(plic.store(6, 32, 1).is_err());
+        // Source 0 cannot be enabled.
+        write(&mut plic, ENABLE, 0xffff_ffff);
+        assert_eq!(read(&mut plic, ENABLE), 0xffff_fffe);
+        // Hart 1's contexts do not exist.
+        assert!(plic.load(ENABLE + 2 * ENABLE_STRIDE, 32).is_err());
+        assert!(plic.load(CONTEXT + 2 * CONTEXT_STRIDE, 32).is_err());
+        assert!(plic.load(4 * PLIC_SOURCES as u64, 32).is_err());
+    }
+}
@@ -0,0 +1,355 @@
+//! Physical memory protection: the pmpcfg and pmpaddr CSRs, and the checks
+//! they make on every physical access, with the Smepmp rules for M-mode when
+//! `mseccfg.MML` is set.
+
+use crate::cpu::*;
+use crate::isa::*;
+
+/// The number of PMP entries.
+pub const PMP_ENTRIES: usize = 16;
+
+// pmpcfg fields of an entry
+const PMP_R: u8 = 1 << 0;
+const PMP_W: u8 = 1 << 1;
+const PMP_X: u8 = 1 << 2;
+const PMP_A: u8 = 0b11 << 3;
+const PMP_L: u8 = 1 << 7;
+
+// Address-matching modes, in the A field
+const PMP_OFF: u8 = 0;
+const PMP_TOR: u8 = 1 << 3;
+const PMP_NA4: u8 = 2 << 3;
+
+impl Cpu {
+    /// The configuration byte of entry `i`. It is kept in the RV32 layout,
+    /// four entries to each of pmpcfg0 to pmpcfg3, on both XLENs.
+    fn pmp_config(&self, i: usize) -> u8 {
+        (self.csrs[PMPCFG0 + i / 4] >> (8 * (i % 4))) as u8
+    }
+
+    /// Whether entry `i` is locked and cannot be changed. mseccfg.RLB lifts
+    /// the locks.
+    fn pmp_locked(&self, i: usize) -> bool {
+        self.pmp_config(i) & PMP_L != 0 && self.csrs[MSECCFG] & MSECCFG_RLB == 0
+    }
+
+    /// Reads pmpcfg0 to pmpcfg3. On RV64 only the even ones exist, each
+    /// holding eight entries.
+    pub fn load_pmpcfg(&self, addr: usize) -> u64 {
+        if self.xlen == 32 {
+            self.csrs[addr]
+        } else {
+            self.csrs[addr] | (self.csrs[addr + 1] << 32)
+        }
+    }
+
+    /// Writes pmpcfg0 to pmpcfg3, leaving locked entries alone.
+    pub fn store_pmpcfg(&mut self, addr: usize, value: u64) {
+        let first = (addr - PMPCFG0) * 4;
+        let count = self.xlen as usize / 8;
+        for i in first..first + count {
+            self.store_pmp_config(i, (value >> (8 * (i - first))) as u8);
+        }
+    }
+
+    fn store_pmp_config(&mut self, i: usize, cfg: u8) {
+        if self.pmp_locked(i) {
+            return;
+        }
+        // Bits 5 and 6 are reserved.
+        let mut cfg = cfg & (PMP_L | PMP_A | PMP_X | PMP_W | PMP_R);
+        let status = self.csrs[MSECCFG];
+        if status & MSECCFG_MML == 0 {
+            // Writable but not readable is reserved without Smepmp's rules.
+            if cfg & (PMP_R | PMP_W) == PMP_W {
+                cfg &= !PMP_W;
+            }
+        } else if status & MSECCFG_RLB == 0 && cfg & PMP_L != 0 && pmp_permissions(cfg, MACHINE, true) & PMP_X != 0 {
+            // No new rules executable by M-mode once MML is set.
+            return;
+        }
+        let shift = 8 * (i % 4);
+        let word = &mut self.csrs[PMPCFG0 + i / 4];
+        *word = (*word & !(0xff << shift)) | ((cfg as u64) << shift);
+    }
+
+    /// Writes pmpaddr`i`, which holds bits 55:2 of an address (33:2 on
+    /// RV32). It is locked along with its entry, and with the next one if
+    /// that uses it as the bottom of a TOR range.
+    pub fn store_pmpaddr(&mut self, i: usize, value: u64) {
+        let next_tor = i + 1 < PMP_ENTRIES && self.pmp_config(i + 1) & PMP_A == PMP_TOR;
+        if self.pmp_locked(i) || (next_tor && self.pmp_locked(i + 1)) {
+            return;
+        }
+        let mask = if self.xlen == 32 { 0xffff_ffff } else { (1 << 54) - 1 };
+        self.csrs[PMPADDR0 + i] = value & mask;
+    }
+
+    /// Writes mseccfg. MML and MMWP stay set until reset, and RLB cannot be
+    /// set once an entry is locked without it.
+    pub fn store_mseccfg(&mut self, value: u64) {
+        let old = self.csrs[MSECCFG];
+        let mut new = 0;
+        if self.isa.has(ZKR) {
+            new |= value & (MSECCFG_USEED | MSECCFG_SSEED);
+        }
+        if self.isa.has(SMEPMP) {
+            new |= (old | value) & (MSECCFG_MML | MSECCFG_MMWP);
+            let any_locked = (0..PMP_ENTRIES).any(|i| self.pmp_config(i) & PMP_L != 0);
+            if old & MSECCFG_RLB != 0 || !any_locked {
+                new |= value & MSECCFG_RLB;
+            }
+        }
+        self.csrs[MSECCFG] = new;
+    }
+
+    /// The byte range entry `i` covers, or None when it is off.
+    fn pmp_range(&self, i: usize) -> Option<(u64, u64)> {
+        let addr = self.csrs[PMPADDR0 + i];
+        match self.pmp_config(i) & PMP_A {
+            PMP_OFF => None,
+            PMP_TOR => {
+                let bottom = if i == 0 { 0 } else { self.csrs[PMPADDR0 + i - 1] };
+                Some((bottom << 2, addr << 2))
+            }
+            PMP_NA4 => Some((addr << 2, (addr << 2) + 4)),
+            _ => {
+                // NAPOT: the trailing ones give the size, 8 bytes and up.
+                let ones = addr.trailing_ones();
+                let base = (addr & !((1 << ones) - 1)) << 2;
+                Some((base, base + (8 << ones)))
+            }
+        }
+    }
+
+    /// Whether PMP lets `mode` access the `size` bits at physical address
+    /// `addr` the way `access` does. The lowest-numbered entry that matches
+    /// any of the bytes decides, and it must match all of them.
+    pub fn pmp_allows(&self, addr: u64, size: u64, access: AccessType, mode: u64) -> bool {
+        let status = self.csrs[MSECCFG];
+        // The usual case, with every entry off and no Smepmp rules
+        if self.csrs[PMPCFG0..=PMPCFG3].iter().all(|&cfg| cfg == 0) && status & (MSECCFG_MML | MSECCFG_MMWP) == 0 {
+            return true;
+        }
+        let mml = status & MSECCFG_MML != 0;
+        let needed = match access {
+            AccessType::Instruction => PMP_X,
+            AccessType::Load => PMP_R,
+            AccessType::Store => PMP_W,
+        };
+        let end = addr.saturating_add(size / 8);
+        let mut active = false;
+        for i in 0..PMP_ENTRIES {
+            let Some((bottom, top)) = self.pmp_range(i) else {
+                continue;
+            };
+            active = true;
+            if addr >= top || end <= bottom || bottom >= top {
+                continue;
+            }
+            if addr < bottom || end > top {
+                return false;
+            }
+            return pmp_permissions(self.pmp_config(i), mode, mml) & needed != 0;
+        }
+
+        // No entry matches. M-mode may go anywhere unless MMWP is set,
+        // though with MML it only executes from its own rules. As a
+        // convenience for software that never sets PMP up, S-mode and U-mode
+        // are only refused once some entry is in use.
+        if mode == MACHINE {
+            status & MSECCFG_MMWP == 0 && !(mml && access == AccessType::Instruction)
+        } else {
+            !active
+        }
+    }
+}
+
+/// The permissions an entry with configuration `cfg` gives `mode`. Without
+/// MML, M-mode is only held to locked entries; with it, locked entries are
+/// for M-mode and the others for S-mode and U-mode, except the shared
+/// regions encoded with W but not R.
+fn pmp_permissions(cfg: u8, mode: u64, mml: bool) -> u8 {
+    let locked = cfg & PMP_L != 0;
+    let rwx = cfg & (PMP_R | PMP_W | PMP_X);
+    let machine = mode == MACHINE;
+    if !mml {
+        return if machine && !locked { PMP_R | PMP_W | PMP_X } else { rwx };
+    }
+    match (locked, rwx) {
+        // Shared data: read and write for M-mode, read or read and write
+        // for the others
+        (false, 0b010) => {
+            if machine {
+                PMP_R | PMP_W
+            } else {
+                PMP_R
+            }
+        }
+        (false, 0b110) => PMP_R | PMP_W,
+        // Shared code: execute, or read and execute for M-mode
+        (true, 0b010) => PMP_X,
+        (true, 0b110) => {
+            if machine {
+                PMP_R | PMP_X
+            } else {
+                PMP_X
+            }
+        }
+        // Shared read-only data
+        (true, 0b111) => PMP_R,
+        (true, _) if machine => rwx,
+        (false, _) if !machine => rwx,
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::*;
+    use crate::testing::*;
+
+    fn hart() -> Cpu {
+        machine(&MachineConfig::default(), &[SPIN]).harts.remove(0)
+    }
+
+    #[test]
+    fn tor_na4_and_napot_ranges() {
+        let mut cpu = hart();
+        cpu.store_csr(PMPADDR0, 0x8000_1000 >> 2);
+        cpu.store_csr(PMPADDR0 + 1, 0x8000_2000 >> 2);
+        cpu.store_csr(PMPADDR0 + 2, 0x8000_3000 >> 2);
+        // 4 KiB at 0x8000_4000
+        cpu.store_csr(PMPADDR0 + 3, (0x8000_4000 >> 2) | 0x1ff);
+        // Entry 1 is TOR and readable, 2 is NA4 and read-write, 3 is NAPOT
+        // and executable. Entry 0 is off and only gives entry 1 its bottom.
+        let cfg = ((PMP_TOR | PMP_R) as u64) << 8
+            | ((PMP_NA4 | PMP_R | PMP_W) as u64) << 16
+            | ((PMP_A | PMP_X) as u64) << 24;
+        cpu.store_csr(PMPCFG0, cfg);
+        assert_eq!(cpu.load_csr(PMPCFG0), cfg);
+
+        let allows = |addr, size, access| cpu.pmp_allows(addr, size, access, SUPERVISOR);
+        assert!(allows(0x8000_1000, 32, AccessType::Load));
+        assert!(allows(0x8000_1ff8, 64, AccessType::Load));
+        assert!(!allows(0x8000_1000, 32, AccessType::Store));
+        // Accesses must lie wholly inside the entry that matches.
+        assert!(!allows(0x8000_1ffc, 64, AccessType::Load));
+        assert!(!allows(0x8000_0ffc, 64, AccessType::Load));
+        assert!(allows(0x8000_3000, 32, AccessType::Store));
+        assert!(!allows(0x8000_3000, 64, AccessType::Store));
+        assert!(!allows(0x8000_3004, 8, AccessType::Load));
+        assert!(allows(0x8000_4ffe, 16, AccessType::Instruction));
+        assert!(!allows(0x8000_4000, 32, AccessType::Load));
+        assert!(!allows(0x8000_5000, 16, AccessType::Instruction));
+
+        // Nothing matches: S-mode is refused, M-mode goes anywhere, and
+        // M-mode ignores the permissions of entries that are not locked.
+        assert!(!allows(0x9000_0000, 8, AccessType::Load));
+        assert!(cpu.pmp_allows(0x9000_0000, 8, AccessType::Load, MACHINE));
+        assert!(cpu.pmp_allows(0x8000_1000, 32, AccessType::Store, MACHINE));
+    }
+
+    #[test]
+    fn writable_but_not_readable_is_reserved() {
+        let mut cpu = hart();
+        cpu.store_csr(PMPCFG0, (PMP_NA4 | PMP_W | 1 << 5) as u64);
+        assert_eq!(cpu.load_csr(PMPCFG0), PMP_NA4 as u64);
+        // With every entry off, nothing is checked.
+        cpu.store_csr(PMPCFG0, 0);
+        assert!(cpu.pmp_allows(0x9000_0000, 64, AccessType::Store, USER));
+    }
+
+    #[test]
+    fn locked_entries_bind_machine_mode() {
+        let mut cpu = hart();
+        cpu.store_csr(PMPADDR0, 0x8000_1000 >> 2);
+        cpu.store_csr(PMPADDR0 + 1, 0x8000_2000 >> 2);
+        let cfg = ((PMP_L | PMP_TOR | PMP_R) as u64) << 8;
+        cpu.store_csr(PMPCFG0, cfg);
+        assert!(cpu.pmp_allows(0x8000_1000, 64, AccessType::Load, MACHINE));
+        assert!(!cpu.pmp_allows(0x8000_1000, 64, AccessType::Store, MACHINE));
+        assert!(!cpu.pmp_allows(0x8000_1000, 16, AccessType::Instruction, MACHINE));
+
+        // The entry, its address and the address below it stay as they are.
+        cpu.store_csr(PMPCFG0, 0);
+        cpu.store_csr(PMPADDR0 + 1, 0);
+        cpu.store_csr(PMPADDR0, 0);
+        assert_eq!(cpu.load_csr(PMPCFG0), cfg);
+        assert_eq!(cpu.csrs[PMPADDR0 + 1], 0x8000_2000 >> 2);
+        assert_eq!(cpu.csrs[PMPADDR0], 0x8000_1000 >> 2);
+        // Entries around it can still change.
+        cpu.store_csr(PMPADDR0 + 2, 0x1234);
+        assert_eq!(cpu.csrs[PMPADDR0 + 2], 0x1234);
+
+        // RLB cannot be set once an entry is locked without it.
+        cpu.store_csr(MSECCFG, MSECCFG_RLB);
+        assert_eq!(cpu.csrs[MSECCFG], 0);
+    }
+
+    #[test]
+    fn rlb_lifts_the_locks() {
+        let mut cpu = hart();
+        cpu.store_csr(MSECCFG, MSECCFG_RLB);
+        cpu.store_csr(PMPCFG0, (PMP_L | PMP_NA4 | PMP_R) as u64);
+        cpu.store_csr(PMPCFG0, 0);
+        assert_eq!(cpu.load_csr(PMPCFG0), 0);
+    }
+
+    #[test]
+    fn rv32_keeps_four_entries_to_a_register() {
+        let rv32 = MachineConfig { xlen: Some(32), ..Default::default() };
+        let mut cpu = machine(&rv32, &[SPIN]).harts.remove(0);
+        cpu.store_csr(PMPCFG0 + 1, (PMP_NA4 | PMP_R) as u64);
+        cpu.store_csr(PMPADDR0 + 4, 0xffff_ffff_ffff);
+        assert_eq!(cpu.load_csr(PMPCFG0 + 1), (PMP_NA4 | PMP_R) as u64);
+        assert_eq!(cpu.csrs[PMPADDR0 + 4], 0xffff_ffff);
+        assert_eq!(cpu.pmp_range(4), Some((0x3_ffff_fffc, 0x4_0000_0000)));
+    }
+
+    #[test]
+    fn machine_mode_lockdown() {
+        let mut cpu = hart();
+        cpu.store_csr(MSECCFG, MSECCFG_MML);
+        // M-mode no longer executes where no entry matches.
+        assert!(cpu.pmp_allows(0x9000_0000, 64, AccessType::Load, MACHINE));
+        assert!(!cpu.pmp_allows(0x9000_0000, 16, AccessType::Instruction, MACHINE));
+
+        // Locked rules M-mode could execute from cannot be added, other
+        // rules can.
+        cpu.store_csr(PMPCFG0, (PMP_L | PMP_NA4 | PMP_X) as u64);
+        assert_eq!(cpu.load_csr(PMPCFG0), 0);
+        cpu.store_csr(PMPCFG0, (PMP_L | PMP_NA4 | PMP_R) as u64);
+        assert_eq!(cpu.load_csr(PMPCFG0), (PMP_L | PMP_NA4 | PMP_R) as u64);
+
+        // MML and MMWP stay set, and with MMWP M-mode goes nowhere unmatched.
+        cpu.store_csr(MSECCFG, MSECCFG_MMWP);
+        cpu.store_csr(MSECCFG, 0);
+        assert_eq!(cpu.csrs[MSECCFG], MSECCFG_MML | MSECCFG_MMWP);
+        assert!(!cpu.pmp_allows(0x9000_0000, 64, AccessType::Load, MACHINE));
+    }
+
+    #[test]
+    fn lockdown_permissions() {
+        let (rwx, r, w, x) = (PMP_R | PMP_W | PMP_X, PMP_R, PMP_W, PMP_X);
+        let cases = [
+            // cfg                   M-mode     S-mode
+            (rwx,                    0,         rwx),
+            (PMP_L | r | x,          r | x,     0),
+            (w,                      r | w,     r),
+            (w | x,                  r | w,     r | w),
+            (PMP_L | w,              x,         x),
+            (PMP_L | w | x,          r | x,     x),
+            (PMP_L | rwx,            r,         r),
+        ];
+        for (cfg, m, s) in cases {
+            assert_eq!(pmp_permissions(cfg, MACHINE, true), m, "{cfg:#x}");
+            assert_eq!(pmp_permissions(cfg, SUPERVISOR, true), s, "{cfg:#x}");
+        }
+        // Without MML, M-mode is only held to locked entries.
+        assert_eq!(pmp_permissions(r, MACHINE, false), rwx);
+        assert_eq!(pmp_permissions(PMP_L | r, MACHINE, false), r);
+        assert_eq!(pmp_permissions(PMP_L | r, USER, false), r);
+    }
+}
@@ -0,0 +1,205 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use crate::snapshot::*;
+
+pub const LOG_MAGIC: &[u8; 8] = b"RVEMULOG";
+pub const LOG_VERSION: u32 = 1;
+
+/// Host data one source received at one poll.
+type Entries = VecDeque<(u64, Vec<u8>)>;
+
+/// The inputs a run received from the host, written as they arrive or read
+/// back to feed a replay.
+///
+/// Every entry is keyed by a source name and by how many times that source
+/// had polled the host before. Execution is deterministic given its inputs:
+/// `mtime` counts instructions and interrupts follow from device state. So a
+/// replay polls the same sources in the same order, and handing each poll
+/// the data it got in the recorded run reproduces that run exactly.
+pub enum InputLog {
+    Record(File),
+    Replay(HashMap<String, Entries>),
+}
+
+pub type SharedLog = Arc<Mutex<InputLog>>;
+
+fn invalid_log(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("invalid input log: {}", message))
+}
+
+impl InputLog {
+    /// Starts a new log at `path`.
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let mut file = File::create(path)?;
+        let mut header = StateWriter::default();
+        header.data.extend_from_slice(LOG_MAGIC);
+        header.u32(LOG_VERSION);
+        file.write_all(&header.data)?;
+        Ok(InputLog::Record(file))
+    }
+
+    /// Reads a log to replay.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let data = fs::read(path)?;
+        let body = data
+            .strip_prefix(LOG_MAGIC)
+            .ok_or_else(|| invalid_log("not an rvemu input log"))?;
+        let mut input = StateReader::new(body);
+        let truncated = |_| invalid_log("truncated");
+        if input.u32().map_err(truncated)? != LOG_VERSION {
+            return Err(invalid_log("unsupported version"));
+        }
+
+        let mut sources: HashMap<String, Entries> = HashMap::new();
+        while !input.is_empty() {
+            let name = String::from_utf8_lossy(input.bytes().map_err(truncated)?).into_owned();
+            let poll = input.u64().map_err(truncated)?;
+            let data = input.bytes().map_err(truncated)?.to_vec();
+            sources.entry(name).or_default().push_back((poll, data));
+        }
+        Ok(InputLog::Replay(sources))
+    }
+}
+
+/// One source of host input: a device's connection to the outside world,
+/// the host clock or stdin. Without a log it passes the host's data
+/// through; otherwise that data is recorded, or replaced by what was.
+pub struct HostInput {
+    name: String,
+    log: Option<SharedLog>,
+    /// The entries still to be replayed for this source.
+    replay: Option<Entries>,
+    polls: u64,
+}
+
+impl HostInput {
+    pub fn new(name: &str, log: Option<&SharedLog>) -> Self {
+        let mut replay = None;
+        let log = log.and_then(|log| match &mut *log.lock().unwrap() {
+            InputLog::Record(_) => Some(Arc::clone(log)),
+            InputLog::Replay(sources) => {
+                replay = Some(sources.remove(name).unwrap_or_default());
+                None
+            }
+        });
+        Self {
+            name: name.to_string(),
+            log,
+            replay,
+            polls: 0,
+        }
+    }
+
+    /// What arrived from the host since the last poll, as `live` returns
+    /// it. Empty chunks are not recorded.
+    pub fn poll(&mut self, live: impl FnOnce() -> Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+        let poll = self.polls;
+        self.polls += 1;
+
+        if let Some(entries) = self.replay.as_mut() {
+            let mut chunks = Vec::new();
+            while entries.front().is_some_and(|(at, _)| *at == poll) {
+                chunks.push(entries.pop_front().unwrap().1);
+            }
+            return chunks;
+        }
+
+        let chunks = live();
+        if let Some(log) = &self.log {
+            let mut record = StateWriter::default();
+            for chunk in chunks.iter().filter(|chunk| !chunk.is_empty()) {
+                record.bytes(self.name.as_bytes());
+                record.u64(poll);
+                record.bytes(chunk);
+            }
+            let failed = match &mut *log.lock().unwrap() {
+                InputLog::Record(file) if !record.data.is_empty() => file.write_all(&record.data).is_err(),
+                _ => false,
+            };
+            if failed {
+                eprintln!("rvemu: cannot write the input log; stopping it");
+                self.log = None;
+            }
+        }
+        chunks
+    }
+
+    /// A single read from the host, such as a clock or a buffer of random
+    /// bytes. An empty result stands for nothing being available.
+    pub fn read(&mut self, live: impl FnOnce() -> Vec<u8>) -> Vec<u8> {
+        self.poll(|| vec![live()]).concat()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::*;
+    use crate::entropy::*;
+    use crate::testing::*;
+
+    fn shared(log: InputLog) -> SharedLog {
+        Arc::new(Mutex::new(log))
+    }
+
+    #[test]
+    fn replays_what_each_poll_received() {
+        let path = temp_path("inputs.log");
+        let log = shared(InputLog::create(&path).unwrap());
+        let mut uart = HostInput::new("uart", Some(&log));
+        let mut net = HostInput::new("net", Some(&log));
+        assert_eq!(uart.poll(Vec::new), Vec::<Vec<u8>>::new());
+        assert_eq!(uart.poll(|| vec![b"ab".to_vec(), vec![], b"c".to_vec()]).len(), 3);
+        assert_eq!(net.poll(|| vec![b"frame".to_vec()]), [b"frame"]);
+        uart.poll(|| vec![b"d".to_vec()]);
+        drop(log);
+
+        let log = shared(InputLog::open(&path).unwrap());
+        let mut uart = HostInput::new("uart", Some(&log));
+        let mut net = HostInput::new("net", Some(&log));
+        let mut clock = HostInput::new("clock", Some(&log));
+        // The host is not asked again, and empty chunks were left out.
+        let host = || -> Vec<Vec<u8>> { panic!("the host was polled during a replay") };
+        assert_eq!(uart.poll(host), Vec::<Vec<u8>>::new());
+        assert_eq!(uart.poll(host), [b"ab".to_vec(), b"c".to_vec()]);
+        assert_eq!(uart.poll(host), [b"d"]);
+        assert_eq!(net.poll(host), [b"frame"]);
+        assert_eq!(net.poll(host), Vec::<Vec<u8>>::new());
+        assert_eq!(clock.read(|| panic!("the host was read during a replay")), b"");
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn host_entropy_is_replayed() {
+        let path = temp_path("entropy.log");
+        let log = shared(InputLog::create(&path).unwrap());
+        let mut recorded = [0; 32];
+        Entropy::new(RngSource::Host, "rng", Some(&log)).unwrap().fill(&mut recorded).unwrap();
+        drop(log);
+
+        let log = shared(InputLog::open(&path).unwrap());
+        let mut replayed = [0; 32];
+        Entropy::new(RngSource::Host, "rng", Some(&log)).unwrap().fill(&mut replayed).unwrap();
+        assert_eq!(replayed, recorded);
+        // Past the end of the log there is nothing more to read.
+        let mut entropy = Entropy::new(RngSource::Host, "rng", Some(&log)).unwrap();
+        assert!(entropy.fill(&mut replayed).is_err());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn refuses_other_files() {
+        let path = temp_path("not.log");
+        fs::write(&path, b"RVEMULOG\x02\x00\x00\x00").unwrap();
+        assert!(InputLog::open(&path).is_err());
+        fs::write(&path, b"RVEMULOG\x01\x00\x00\x00\x03\x00").unwrap();
+        assert!(InputLog::open(&path).is_err());
+        fs::write(&path, b"RVEMUSNP\x01\x00\x00\x00").unwrap();
+        assert!(InputLog::open(&path).is_err());
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,367 @@
+//! Expansion of RV64C and RV32C compressed instructions into their 32-bit
+//! equivalents.
+
+fn r(funct7: u32, rs2: u32, rs1: u32, funct3: u32, rd: u32, opcode: u32) -> u32 {
+    (funct7 << 25) | (rs2 << 20) | (rs1 << 15) | (funct3 << 12) | (rd << 7) | opcode
+}
+
+fn i(imm: u32, rs1: u32, funct3: u32, rd: u32, opcode: u32) -> u32 {
+    ((imm & 0xfff) << 20) | (rs1 << 15) | (funct3 << 12) | (rd << 7) | opcode
+}
+
+fn s(imm: u32, rs2: u32, rs1: u32, funct3: u32, opcode: u32) -> u32 {
+    (((imm >> 5) & 0x7f) << 25) | (rs2 << 20) | (rs1 << 15) | (funct3 << 12) | ((imm & 0x1f) << 7) | opcode
+}
+
+fn b(imm: u32, rs2: u32, rs1: u32, funct3: u32) -> u32 {
+    (((imm >> 12) & 1) << 31)
+        | (((imm >> 5) & 0x3f) << 25)
+        | (rs2 << 20)
+        | (rs1 << 15)
+        | (funct3 << 12)
+        | (((imm >> 1) & 0xf) << 8)
+        | (((imm >> 11) & 1) << 7)
+        | 0x63
+}
+
+fn j(imm: u32, rd: u32) -> u32 {
+    (((imm >> 20) & 1) << 31)
+        | (((imm >> 1) & 0x3ff) << 21)
+        | (((imm >> 11) & 1) << 20)
+        | (((imm >> 12) & 0xff) << 12)
+        | (rd << 7)
+        | 0x6f
+}
+
+/// Extracts `inst[hi:lo]` and places it at bit `to` of the result.
+fn bits(inst: u32, hi: u32, lo: u32, to: u32) -> u32 {
+    ((inst >> lo) & ((1 << (hi - lo + 1)) - 1)) << to
+}
+
+/// Sign-extends the low `width` bits of `value`.
+fn sext(value: u32, width: u32) -> u32 {
+    (((value << (32 - width)) as i32) >> (32 - width)) as u32
+}
+
+/// Returns the 32-bit instruction a 16-bit one stands for, or `None` if the
+/// encoding is reserved or illegal.
+///
+/// A few encodings mean different things on RV32; the RV64-only forms they
+/// replace elsewhere (`c.addiw`, `c.subw`, shifts by 32 or more) expand to
+/// instructions that an RV32 hart rejects itself.
+pub fn expand_compressed(inst: u16, xlen: u32) -> Option<u32> {
+    let inst = inst as u32;
+    let rv32 = xlen == 32;
+    let funct3 = inst >> 13;
+    // Full register numbers, and the x8-x15 ones of the 3-bit fields
+    let rd = bits(inst, 11, 7, 0);
+    let rs2 = bits(inst, 6, 2, 0);
+    let rd_ = bits(inst, 4, 2, 0) + 8;
+    let rs1_ = bits(inst, 9, 7, 0) + 8;
+
+    let expanded = match (inst & 0x3, funct3) {
+        (0b00, 0b000) => {
+            // c.addi4spn
+            let imm = bits(inst, 12, 11, 4) | bits(inst, 10, 7, 6) | bits(inst, 6, 6, 2) | bits(inst, 5, 5, 3);
+            if imm == 0 {
+                return None;
+            }
+            i(imm, 2, 0b000, rd_, 0x13)
+        }
+        (0b00, 0b001) => {
+            // c.fld
+            let imm = bits(inst, 12, 10, 3) | bits(inst, 6, 5, 6);
+            i(imm, rs1_, 0b011, rd_, 0x07)
+        }
+        (0b00, 0b010) => {
+            // c.lw
+            let imm = bits(inst, 12, 10, 3) | bits(inst, 6, 6, 2) | bits(inst, 5, 5, 6);
+            i(imm, rs1_, 0b010, rd_, 0x03)
+        }
+        (0b00, 0b011) if rv32 => {
+            // c.flw
+            let imm = bits(inst, 12, 10, 3) | bits(inst, 6, 6, 2) | bits(inst, 5, 5, 6);
+            i(imm, rs1_, 0b010, rd_, 0x07)
+        }
+        (0b00, 0b011) => {
+            // c.ld
+            let imm = bits(inst, 12, 10, 3) | bits(inst, 6, 5, 6);
+            i(imm, rs1_, 0b011, rd_, 0x03)
+        }
+        (0b00, 0b101) => {
+            // c.fsd
+            let imm = bits(inst, 12, 10, 3) | bits(inst, 6, 5, 6);
+            s(imm, rd_, rs1_, 0b011, 0x27)
+        }
+        (0b00, 0b110) => {
+            // c.sw
+            let imm = bits(inst, 12, 10, 3) | bits(inst, 6, 6, 2) | bits(inst, 5, 5, 6);
+            s(imm, rd_, rs1_, 0b010, 0x23)
+        }
+        (0b00, 0b111) if rv32 => {
+            // c.fsw
+            let imm = bits(inst, 12, 10, 3) | bits(inst, 6, 6, 2) | bits(inst, 5, 5, 6);
+            s(imm, rd_, rs1_, 0b010, 0x27)
+        }
+        (0b00, 0b111) => {
+            // c.sd
+            let imm = bits(inst, 12, 10, 3) | bits(inst, 6, 5, 6);
+            s(imm, rd_, rs1_, 0b011, 0x23)
+        }
+        (0b01, 0b000) => {
+            // c.addi, c.nop
+            let imm = sext(bits(inst, 12, 12, 5) | bits(inst, 6, 2, 0), 6);
+            i(imm, rd, 0b000, rd, 0x13)
+        }
+        (0b01, 0b001) if !rv32 => {
+            // c.addiw
+            if rd == 0 {
+                return None;
+            }
+            let imm = sext(bits(inst, 12, 12, 5) | bits(inst, 6, 2, 0), 6);
+            i(imm, rd, 0b000, rd, 0x1b)
+        }
+        (0b01, 0b010) => {
+            // c.li
+            let imm = sext(bits(inst, 12, 12, 5) | bits(inst, 6, 2, 0), 6);
+            i(imm, 0, 0b000, rd, 0x13)
+        }
+        (0b01, 0b011) if rd == 2 => {
+            // c.addi16sp
+            let imm = bits(inst, 12, 12, 9)
+                | bits(inst, 6, 6, 4)
+                | bits(inst, 5, 5, 6)
+                | bits(inst, 4, 3, 7)
+                | bits(inst, 2, 2, 5);
+            if imm == 0 {
+                return None;
+            }
+            i(sext(imm, 10), 2, 0b000, 2, 0x13)
+        }
+        (0b01, 0b011) => {
+            // c.lui
+            let imm = sext(bits(inst, 12, 12, 17) | bits(inst, 6, 2, 12), 18);
+            if imm == 0 {
+                return None;
+            }
+            (imm & 0xffff_f000) | (rd << 7) | 0x37
+        }
+        (0b01, 0b100) => {
+            let shamt = bits(inst, 12, 12, 5) | bits(inst, 6, 2, 0);
+            match bits(inst, 11, 10, 0) {
+                // c.srli, c.srai
+                0b00 => i(shamt, rs1_, 0b101, rs1_, 0x13),
+                0b01 => i(shamt | 0x400, rs1_, 0b101, rs1_, 0x13),
+                0b10 => {
+                    // c.andi
+                    let imm = sext(shamt, 6);
+                    i(imm, rs1_, 0b111, rs1_, 0x13)
+                }
+                _ => {
+                    let rs2_ = rd_;
+                    match (bits(inst, 12, 12, 0), bits(inst, 6, 5, 0)) {
+                        (0, 0b00) => r(0x20, rs2_, rs1_, 0b000, rs1_, 0x33), // c.sub
+                        (0, 0b01) => r(0x00, rs2_, rs1_, 0b100, rs1_, 0x33), // c.xor
+                        (0, 0b10) => r(0x00, rs2_, rs1_, 0b110, rs1_, 0x33), // c.or
+                        (0, 0b11) => r(0x00, rs2_, rs1_, 0b111, rs1_, 0x33), // c.and
+                        (1, 0b00) => r(0x20, rs2_, rs1_, 0b000, rs1_, 0x3b), // c.subw
+                        (1, 0b01) => r(0x00, rs2_, rs1_, 0b000, rs1_, 0x3b), // c.addw
+                        _ => return None,
+                    }
+                }
+            }
+        }
+        (0b01, 0b001 | 0b101) => {
+            // c.jal (RV32 only), c.j
+            let imm = bits(inst, 12, 12, 11)
+                | bits(inst, 11, 11, 4)
+                | bits(inst, 10, 9, 8)
+                | bits(inst, 8, 8, 10)
+                | bits(inst, 7, 7, 6)
+                | bits(inst, 6, 6, 7)
+                | bits(inst, 5, 3, 1)
+                | bits(inst, 2, 2, 5);
+            j(sext(imm, 12), (funct3 == 0b001) as u32)
+        }
+        (0b01, 0b110 | 0b111) => {
+            // c.beqz, c.bnez
+            let imm = bits(inst, 12, 12, 8)
+                | bits(inst, 11, 10, 3)
+                | bits(inst, 6, 5, 6)
+                | bits(inst, 4, 3, 1)
+                | bits(inst, 2, 2, 5);
+            b(sext(imm, 9), 0, rs1_, funct3 & 1)
+        }
+        (0b10, 0b000) => {
+            // c.slli
+            let shamt = bits(inst, 12, 12, 5) | bits(inst, 6, 2, 0);
+            i(shamt, rd, 0b001, rd, 0x13)
+        }
+        (0b10, 0b001) => {
+            // c.fldsp
+            let imm = bits(inst, 12, 12, 5) | bits(inst, 6, 5, 3) | bits(inst, 4, 2, 6);
+            i(imm, 2, 0b011, rd, 0x07)
+        }
+        (0b10, 0b010) => {
+            // c.lwsp
+            if rd == 0 {
+                return None;
+            }
+            let imm = bits(inst, 12, 12, 5) | bits(inst, 6, 4, 2) | bits(inst, 3, 2, 6);
+            i(imm, 2, 0b010, rd, 0x03)
+        }
+        (0b10, 0b011) if rv32 => {
+            // c.flwsp
+            let imm = bits(inst, 12, 12, 5) | bits(inst, 6, 4, 2) | bits(inst, 3, 2, 6);
+            i(imm, 2, 0b010, rd, 0x07)
+        }
+        (0b10, 0b011) => {
+            // c.ldsp
+            if rd == 0 {
+                return None;
+            }
+            let imm = bits(inst, 12, 12, 5) | bits(inst, 6, 5, 3) | bits(inst, 4, 2, 6);
+            i(imm, 2, 0b011, rd, 0x03)
+        }
+        (0b10, 0b100) => match (bits(inst, 12, 12, 0), rd, rs2) {
+            (0, 0, 0) => return None,
+            (0, _, 0) => i(0, rd, 0b000, 0, 0x67),                // c.jr
+            // c.mv and c.add to x0 are hints.
+            (0, _, _) => r(0x00, rs2, 0, 0b000, rd, 0x33),        // c.mv
+            (_, 0, 0) => 0x0010_0073,                             // c.ebreak
+            (_, _, 0) => i(0, rd, 0b000, 1, 0x67),                // c.jalr
+            (_, _, _) => r(0x00, rs2, rd, 0b000, rd, 0x33),       // c.add
+        },
+        (0b10, 0b101) => {
+            // c.fsdsp
+            let imm = bits(inst, 12, 10, 3) | bits(inst, 9, 7, 6);
+            s(imm, rs2, 2, 0b011, 0x27)
+        }
+        (0b10, 0b110) => {
+            // c.swsp
+            let imm = bits(inst, 12, 9, 2) | bits(inst, 8, 7, 6);
+            s(imm, rs2, 2, 0b010, 0x23)
+        }
+        (0b10, 0b111) if rv32 => {
+            // c.fswsp
+            let imm = bits(inst, 12, 9, 2) | bits(inst, 8, 7, 6);
+            s(imm, rs2, 2, 0b010, 0x27)
+        }
+        (0b10, 0b111) => {
+            // c.sdsp
+            let imm = bits(inst, 12, 10, 3) | bits(inst, 9, 7, 6);
+            s(imm, rs2, 2, 0b011, 0x23)
+        }
+        _ => return None,
+    };
+
+    Some(expanded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Each compressed form with the expansion an assembler gives for it
+    const RV64: [(u16, u32); 37] = [
+        (0x0808, 0x01010513), // c.addi4spn a0, sp, 16
+        (0x2588, 0x0085b507), // c.fld fa0, 8(a1)
+        (0x42d0, 0x0046a603), // c.lw a2, 4(a3)
+        (0x7ff8, 0x0f87b703), // c.ld a4, 248(a5)
+        (0xa880, 0x0084b827), // c.fsd fs0, 16(s1)
+        (0xdde8, 0x06a5ae23), // c.sw a0, 124(a1)
+        (0xe480, 0x0084b423), // c.sd s0, 8(s1)
+        (0x0001, 0x00000013), // c.nop
+        (0x1281, 0xfe028293), // c.addi t0, -32
+        (0x257d, 0x01f5051b), // c.addiw a0, 31
+        (0x537d, 0xfff00313), // c.li t1, -1
+        (0x7101, 0xe0010113), // c.addi16sp sp, -512
+        (0x7505, 0xfffe1537), // c.lui a0, 0xfffe1
+        (0x917d, 0x03f55513), // c.srli a0, 63
+        (0x8485, 0x4014d493), // c.srai s1, 1
+        (0x9bfd, 0xfff7f793), // c.andi a5, -1
+        (0x8c05, 0x40940433), // c.sub s0, s1
+        (0x8d2d, 0x00b54533), // c.xor a0, a1
+        (0x8e55, 0x00d66633), // c.or a2, a3
+        (0x8f7d, 0x00f77733), // c.and a4, a5
+        (0x9d0d, 0x40b5053b), // c.subw a0, a1
+        (0x9e35, 0x00d6063b), // c.addw a2, a3
+        (0xb001, 0x801ff06f), // c.j -2048
+        (0xd101, 0xf00500e3), // c.beqz a0, -256
+        (0xecfd, 0x0e049f63), // c.bnez s1, 254
+        (0x1382, 0x02039393), // c.slli t2, 32
+        (0x307e, 0x1f813007), // c.fldsp ft0, 504(sp)
+        (0x50fe, 0x0fc12083), // c.lwsp ra, 252(sp)
+        (0x6fa2, 0x00813f83), // c.ldsp t6, 8(sp)
+        (0x8082, 0x00008067), // c.jr ra
+        (0x852e, 0x00b00533), // c.mv a0, a1
+        (0x9002, 0x00100073), // c.ebreak
+        (0x9282, 0x000280e7), // c.jalr t0
+        (0x994e, 0x01390933), // c.add s2, s3
+        (0xa046, 0x01113027), // c.fsdsp fa7, 0(sp)
+        (0xdfee, 0x0fb12e23), // c.swsp s11, 252(sp)
+        (0xff8e, 0x1e313c23), // c.sdsp gp, 504(sp)
+    ];
+
+    // The RV32 forms that take the place of RV64 ones
+    const RV32: [(u16, u32); 5] = [
+        (0x2ffd, 0x7fe000ef), // c.jal 2046
+        (0x7de8, 0x07c5a507), // c.flw fa0, 124(a1)
+        (0xe1c8, 0x00a5a227), // c.fsw fa0, 4(a1)
+        (0x70fe, 0x0fc12087), // c.flwsp ft1, 252(sp)
+        (0xe40a, 0x00212427), // c.fswsp ft2, 8(sp)
+    ];
+
+    #[test]
+    fn expands_every_format() {
+        for (inst, expanded) in RV64 {
+            assert_eq!(expand_compressed(inst, 64), Some(expanded), "{inst:#06x}");
+        }
+        for (inst, expanded) in RV32 {
+            assert_eq!(expand_compressed(inst, 32), Some(expanded), "{inst:#06x}");
+        }
+    }
+
+    #[test]
+    fn rv32_keeps_the_forms_it_shares() {
+        // Everything but the slots RV32 gives to c.jal, c.flw, c.fsw, c.flwsp and c.fswsp
+        for (inst, expanded) in RV64 {
+            let funct3 = inst >> 13;
+            let shared = match inst & 3 {
+                0b00 => funct3 != 0b011 && funct3 != 0b111,
+                0b01 => funct3 != 0b001,
+                _ => funct3 != 0b011 && funct3 != 0b111,
+            };
+            if shared {
+                assert_eq!(expand_compressed(inst, 32), Some(expanded), "{inst:#06x}");
+            }
+        }
+        // And gives the RV64 meaning of those slots on RV64
+        assert_eq!(expand_compressed(0x7de8, 64), Some(0x0f85b503)); // c.ld a0, 248(a1)
+        assert_eq!(expand_compressed(0x2ffd, 64), Some(0x01ff8f9b)); // c.addiw t6, 31
+    }
+
+    #[test]
+    fn hints_expand_to_writes_to_x0() {
+        assert_eq!(expand_compressed(0x802e, 64), Some(0x00b00033)); // c.mv x0, a1
+        assert_eq!(expand_compressed(0x902e, 64), Some(0x00b00033)); // c.add x0, a1
+    }
+
+    #[test]
+    fn reserved_encodings_do_not_expand() {
+        for inst in [
+            0x0000, // all zeros
+            0x0008, // c.addi4spn with a zero immediate
+            0x6101, // c.addi16sp with a zero immediate
+            0x6501, // c.lui with a zero immediate
+            0x2001, // c.addiw to x0
+            0x9c41, // c.subw's reserved neighbour
+            0x4002, // c.lwsp to x0
+            0x6002, // c.ldsp to x0
+            0x8002, // c.jr x0
+            0xffff, // not a compressed instruction at all
+        ] {
+            assert_eq!(expand_compressed(inst, 64), None, "{inst:#06x}");
+        }
+    }
+}
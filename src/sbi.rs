@@ -0,0 +1,422 @@
+use std::io;
+
+use crate::cpu::*;
+use crate::finisher::*;
+use crate::snapshot::*;
+use crate::uart::*;
+
+// Extension IDs
+const EXT_SET_TIMER: u64 = 0x00;
+const EXT_CONSOLE_PUTCHAR: u64 = 0x01;
+const EXT_CONSOLE_GETCHAR: u64 = 0x02;
+const EXT_CLEAR_IPI: u64 = 0x03;
+const EXT_SEND_IPI: u64 = 0x04;
+const EXT_REMOTE_FENCE_I: u64 = 0x05;
+const EXT_REMOTE_SFENCE_VMA: u64 = 0x06;
+const EXT_REMOTE_SFENCE_VMA_ASID: u64 = 0x07;
+const EXT_SHUTDOWN: u64 = 0x08;
+const EXT_BASE: u64 = 0x10;
+const EXT_TIME: u64 = 0x5449_4d45;
+const EXT_IPI: u64 = 0x0073_5049;
+const EXT_RFENCE: u64 = 0x5246_4e43;
+const EXT_HSM: u64 = 0x0048_534d;
+const EXT_SRST: u64 = 0x5352_5354;
+
+/// Extensions reported by `sbi_probe_extension`.
+const EXTENSIONS: &[u64] = &[
+    EXT_SET_TIMER,
+    EXT_CONSOLE_PUTCHAR,
+    EXT_CONSOLE_GETCHAR,
+    EXT_CLEAR_IPI,
+    EXT_SEND_IPI,
+    EXT_REMOTE_FENCE_I,
+    EXT_REMOTE_SFENCE_VMA,
+    EXT_REMOTE_SFENCE_VMA_ASID,
+    EXT_SHUTDOWN,
+    EXT_BASE,
+    EXT_TIME,
+    EXT_IPI,
+    EXT_RFENCE,
+    EXT_HSM,
+    EXT_SRST,
+];
+
+/// SBI 2.0
+const SPEC_VERSION: u64 = 2 << 24;
+/// Not a registered implementation ID; "rvem".
+const IMPL_ID: u64 = 0x7276_656d;
+const IMPL_VERSION: u64 = 1;
+
+// Error codes
+const SUCCESS: i64 = 0;
+const ERR_NOT_SUPPORTED: i64 = -2;
+const ERR_INVALID_PARAM: i64 = -3;
+const ERR_ALREADY_AVAILABLE: i64 = -6;
+
+// HSM states and suspend types
+const HSM_STARTED: u64 = 0;
+const HSM_STOPPED: u64 = 1;
+const HSM_START_PENDING: u64 = 2;
+const SUSPEND_RETENTIVE: u64 = 0;
+
+// System reset types and reasons
+const RESET_SHUTDOWN: u64 = 0;
+const RESET_WARM_REBOOT: u64 = 2;
+const REASON_NONE: u64 = 0;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum HartState {
+    Started,
+    Stopped,
+    /// Started by another hart through HSM, at `addr` with `opaque` in a1.
+    StartPending { addr: u64, opaque: u64 },
+}
+
+/// Supervisor binary interface implemented in the emulator, standing in for
+/// M-mode firmware such as OpenSBI.
+///
+/// `ecall`s from S-mode are serviced here. The CLINT does the firmware's
+/// bookkeeping: `sbi_set_timer` programs the hart's `mtimecmp`, whose
+/// interrupt reaches the hart as STIP, and IPIs are sent through `msip` and
+/// delivered as SSIP.
+pub struct Sbi {
+    harts: Vec<HartState>,
+}
+
+impl Sbi {
+    /// Hart 0 boots; the others wait for `sbi_hart_start`.
+    pub fn new(harts: usize) -> Self {
+        let mut states = vec![HartState::Stopped; harts];
+        states[0] = HartState::Started;
+        Self { harts: states }
+    }
+
+    pub fn save(&self, out: &mut StateWriter) {
+        for state in &self.harts {
+            match *state {
+                HartState::Started => out.u8(0),
+                HartState::Stopped => out.u8(1),
+                HartState::StartPending { addr, opaque } => {
+                    out.u8(2);
+                    out.u64(addr);
+                    out.u64(opaque);
+                }
+            }
+        }
+    }
+
+    pub fn restore(&mut self, input: &mut StateReader) -> io::Result<()> {
+        for state in self.harts.iter_mut() {
+            *state = match input.u8()? {
+                0 => HartState::Started,
+                1 => HartState::Stopped,
+                2 => HartState::StartPending {
+                    addr: input.u64()?,
+                    opaque: input.u64()?,
+                },
+                _ => return Err(invalid_snapshot("bad SBI hart state")),
+            };
+        }
+        Ok(())
+    }
+
+    /// Whether the hart should run, starting it if another hart asked for it.
+    pub fn running(&mut self, cpu: &mut Cpu) -> bool {
+        match self.harts[cpu.hartid] {
+            HartState::Started => true,
+            HartState::Stopped => false,
+            HartState::StartPending { addr, opaque } => {
+                cpu.mode = SUPERVISOR;
+                cpu.pc = addr;
+                cpu.regs[10] = cpu.hartid as u64;
+                cpu.regs[11] = opaque;
+                cpu.csrs[SATP] = 0;
+                cpu.csrs[MSTATUS] &= !MSTATUS_SIE;
+                self.harts[cpu.hartid] = HartState::Started;
+                true
+            }
+        }
+    }
+
+    /// Services an `ecall` from S-mode: a7 is the extension, a6 the function
+    /// and a0-a5 the arguments. The error goes back in a0 and the value in
+    /// a1, except for the legacy extensions which only return a0.
+    pub fn call(&mut self, cpu: &mut Cpu) {
+        let (eid, fid) = (cpu.regs[17], cpu.regs[16]);
+        let args = [cpu.regs[10], cpu.regs[11], cpu.regs[12]];
+
+        if eid < EXT_BASE {
+            cpu.regs[10] = self.legacy(cpu, eid, args[0]) as u64;
+            return;
+        }
+
+        let (error, value) = match (eid, fid) {
+            (EXT_BASE, 0) => (SUCCESS, SPEC_VERSION),
+            (EXT_BASE, 1) => (SUCCESS, IMPL_ID),
+            (EXT_BASE, 2) => (SUCCESS, IMPL_VERSION),
+            (EXT_BASE, 3) => (SUCCESS, EXTENSIONS.contains(&args[0]) as u64),
+            (EXT_BASE, 4) => (SUCCESS, cpu.csrs[MVENDORID]),
+            (EXT_BASE, 5) => (SUCCESS, cpu.csrs[MARCHID]),
+            (EXT_BASE, 6) => (SUCCESS, cpu.csrs[MIMPID]),
+            (EXT_TIME, 0) => (set_timer(cpu, args[0]), 0),
+            (EXT_IPI, 0) => (self.send_ipi(cpu, args[0], args[1]), 0),
+            // There are no TLBs or instruction caches to flush, only the
+            // hart mask to check.
+            (EXT_RFENCE, 0..=6) => match self.hart_mask(args[0], args[1]) {
+                Ok(_) => (SUCCESS, 0),
+                Err(error) => (error, 0),
+            },
+            (EXT_HSM, 0) => (self.hart_start(args[0], args[1], args[2]), 0),
+            (EXT_HSM, 1) => {
+                self.harts[cpu.hartid] = HartState::Stopped;
+                (SUCCESS, 0)
+            }
+            (EXT_HSM, 2) => match self.harts.get(args[0] as usize) {
+                Some(HartState::Started) => (SUCCESS, HSM_STARTED),
+                Some(HartState::Stopped) => (SUCCESS, HSM_STOPPED),
+                Some(HartState::StartPending { .. }) => (SUCCESS, HSM_START_PENDING),
+                None => (ERR_INVALID_PARAM, 0),
+            },
+            // A retentive suspend is a `wfi`, which returns straight away.
+            (EXT_HSM, 3) if args[0] as u32 as u64 == SUSPEND_RETENTIVE => (SUCCESS, 0),
+            (EXT_HSM, 3) => (ERR_NOT_SUPPORTED, 0),
+            (EXT_SRST, 0) => match args[0] {
+                RESET_SHUTDOWN..=RESET_WARM_REBOOT => {
+                    self.system_reset(cpu, args[0], args[1]);
+                    (SUCCESS, 0)
+                }
+                _ => (ERR_INVALID_PARAM, 0),
+            },
+            _ => (ERR_NOT_SUPPORTED, 0),
+        };
+
+        cpu.regs[10] = error as u64;
+        cpu.regs[11] = value;
+    }
+
+    /// The SBI v0.1 calls, whose hart masks are passed by address.
+    fn legacy(&mut self, cpu: &mut Cpu, eid: u64, arg: u64) -> i64 {
+        match eid {
+            EXT_SET_TIMER => return set_timer(cpu, arg),
+            EXT_CONSOLE_PUTCHAR => {
+                let mut bus = cpu.bus.lock().unwrap();
+                let _ = bus.store(UART_BASE + RBR, 8, arg & 0xff);
+            }
+            EXT_CONSOLE_GETCHAR => {
+                let mut bus = cpu.bus.lock().unwrap();
+                return match bus.load(UART_BASE + LSR, 8) {
+                    Ok(lsr) if lsr as u8 & LSR_DR != 0 => bus.load(UART_BASE + RBR, 8).unwrap() as i64,
+                    _ => -1,
+                };
+            }
+            EXT_CLEAR_IPI => cpu.csrs[MIP] &= !SSIP_BIT,
+            EXT_SEND_IPI => {
+                let mask = cpu.load(arg, 64).unwrap_or(0);
+                return self.send_ipi(cpu, mask, 0);
+            }
+            EXT_REMOTE_FENCE_I | EXT_REMOTE_SFENCE_VMA | EXT_REMOTE_SFENCE_VMA_ASID => {}
+            _ => self.system_reset(cpu, RESET_SHUTDOWN, REASON_NONE),
+        }
+        SUCCESS
+    }
+
+    /// The harts selected by a mask and its base, or every hart if the base
+    /// is -1.
+    fn hart_mask(&self, mask: u64, base: u64) -> Result<Vec<usize>, i64> {
+        if base == u64::MAX {
+            return Ok((0..self.harts.len()).collect());
+        }
+        let harts: Vec<usize> = (0..64)
+            .filter(|bit| mask & (1 << bit) != 0)
+            .map(|bit| base.saturating_add(bit) as usize)
+            .collect();
+        if harts.iter().any(|&hartid| hartid >= self.harts.len()) {
+            return Err(ERR_INVALID_PARAM);
+        }
+        Ok(harts)
+    }
+
+    fn send_ipi(&self, cpu: &mut Cpu, mask: u64, base: u64) -> i64 {
+        let harts = match self.hart_mask(mask, base) {
+            Ok(harts) => harts,
+            Err(error) => return error,
+        };
+        let mut bus = cpu.bus.lock().unwrap();
+        let Some(clint) = bus.clint.as_mut() else {
+            return ERR_NOT_SUPPORTED;
+        };
+        for hartid in harts {
+            clint.msip[hartid] = true;
+        }
+        SUCCESS
+    }
+
+    fn hart_start(&mut self, hartid: u64, addr: u64, opaque: u64) -> i64 {
+        match self.harts.get_mut(hartid as usize) {
+            Some(state @ HartState::Stopped) => {
+                *state = HartState::StartPending { addr, opaque };
+                SUCCESS
+            }
+            Some(_) => ERR_ALREADY_AVAILABLE,
+            None => ERR_INVALID_PARAM,
+        }
+    }
+
+    /// Powers the board off through the test finisher, which ends the run;
+    /// reboots end it too. The hart does not return from the call.
+    fn system_reset(&mut self, cpu: &mut Cpu, kind: u64, reason: u64) {
+        let value = match (kind, reason) {
+            (RESET_SHUTDOWN, REASON_NONE) => FINISHER_PASS,
+            (RESET_SHUTDOWN, _) => (1 << 16) | FINISHER_FAIL,
+            _ => FINISHER_RESET,
+        };
+        let _ = cpu.bus.lock().unwrap().store(FINISHER_BASE, 32, value);
+        self.harts[cpu.hartid] = HartState::Stopped;
+    }
+}
+
+/// Programs the next timer interrupt. STIP follows the machine timer, so a
+/// pending one clears once `mtimecmp` is past `mtime`.
+fn set_timer(cpu: &mut Cpu, time: u64) -> i64 {
+    let mut bus = cpu.bus.lock().unwrap();
+    match bus.clint.as_mut() {
+        Some(clint) => {
+            clint.mtimecmp[cpu.hartid] = time;
+            SUCCESS
+        }
+        None => ERR_NOT_SUPPORTED,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::DRAM_BASE;
+    use crate::config::*;
+    use crate::testing::*;
+
+    const A1: u32 = 11;
+    const A2: u32 = 12;
+    const A7: u32 = 17;
+
+    fn harts() -> Vec<Cpu> {
+        let config = MachineConfig { harts: 2, ..virt() };
+        machine(&config, &[]).harts
+    }
+
+    /// Calls function `fid` of extension `eid`, returning the error and value.
+    fn call(sbi: &mut Sbi, cpu: &mut Cpu, eid: u64, fid: u64, args: &[u64]) -> (i64, u64) {
+        cpu.regs[10..10 + args.len()].copy_from_slice(args);
+        cpu.regs[16] = fid;
+        cpu.regs[17] = eid;
+        sbi.call(cpu);
+        (cpu.regs[10] as i64, cpu.regs[11])
+    }
+
+    #[test]
+    fn base_extension_describes_the_implementation() {
+        let mut cpu = harts().remove(0);
+        let mut sbi = Sbi::new(2);
+        assert_eq!(call(&mut sbi, &mut cpu, EXT_BASE, 0, &[]), (SUCCESS, 2 << 24));
+        assert_eq!(call(&mut sbi, &mut cpu, EXT_BASE, 1, &[]), (SUCCESS, IMPL_ID));
+        assert_eq!(call(&mut sbi, &mut cpu, EXT_BASE, 3, &[EXT_HSM]), (SUCCESS, 1));
+        assert_eq!(call(&mut sbi, &mut cpu, EXT_BASE, 3, &[0x4442_434e]), (SUCCESS, 0));
+        assert_eq!(call(&mut sbi, &mut cpu, EXT_BASE, 4, &[]), (SUCCESS, cpu.csrs[MVENDORID]));
+        assert_eq!(call(&mut sbi, &mut cpu, EXT_BASE, 7, &[]).0, ERR_NOT_SUPPORTED);
+        assert_eq!(call(&mut sbi, &mut cpu, 0x4442_434e, 0, &[]).0, ERR_NOT_SUPPORTED);
+    }
+
+    #[test]
+    fn hsm_starts_and_stops_harts() {
+        let mut harts = harts();
+        let mut sbi = Sbi::new(2);
+        let (cpu, other) = harts.split_at_mut(1);
+        let (cpu, other) = (&mut cpu[0], &mut other[0]);
+        assert!(!sbi.running(other));
+        assert_eq!(call(&mut sbi, cpu, EXT_HSM, 2, &[1]), (SUCCESS, HSM_STOPPED));
+        assert_eq!(call(&mut sbi, cpu, EXT_HSM, 0, &[1, 0x8040_0000, 0x1234]), (SUCCESS, 0));
+        assert_eq!(call(&mut sbi, cpu, EXT_HSM, 2, &[1]), (SUCCESS, HSM_START_PENDING));
+        assert_eq!(call(&mut sbi, cpu, EXT_HSM, 0, &[1, 0, 0]).0, ERR_ALREADY_AVAILABLE);
+        assert_eq!(call(&mut sbi, cpu, EXT_HSM, 0, &[2, 0, 0]).0, ERR_INVALID_PARAM);
+        assert_eq!(call(&mut sbi, cpu, EXT_HSM, 2, &[2]).0, ERR_INVALID_PARAM);
+
+        // The hart starts in S-mode with its ID and the opaque value.
+        other.csrs[SATP] = 1 << 63;
+        assert!(sbi.running(other));
+        assert_eq!((other.mode, other.pc, other.csrs[SATP]), (SUPERVISOR, 0x8040_0000, 0));
+        assert_eq!((other.regs[10], other.regs[11]), (1, 0x1234));
+        assert_eq!(call(&mut sbi, cpu, EXT_HSM, 2, &[1]), (SUCCESS, HSM_STARTED));
+
+        // Only retentive suspends are supported.
+        assert_eq!(call(&mut sbi, other, EXT_HSM, 3, &[SUSPEND_RETENTIVE]).0, SUCCESS);
+        assert_eq!(call(&mut sbi, other, EXT_HSM, 3, &[0x8000_0000]).0, ERR_NOT_SUPPORTED);
+        assert_eq!(call(&mut sbi, other, EXT_HSM, 1, &[]).0, SUCCESS);
+        assert!(!sbi.running(other));
+    }
+
+    #[test]
+    fn timers_and_ipis_go_through_the_clint() {
+        let mut cpu = harts().remove(0);
+        let mut sbi = Sbi::new(2);
+        let clint = |cpu: &Cpu| {
+            let bus = cpu.bus.lock().unwrap();
+            let clint = bus.clint.as_ref().unwrap();
+            (clint.mtimecmp[0], clint.msip.clone())
+        };
+        assert_eq!(call(&mut sbi, &mut cpu, EXT_TIME, 0, &[5000]), (SUCCESS, 0));
+        assert_eq!(clint(&cpu), (5000, vec![false, false]));
+        // The legacy calls only return an error.
+        cpu.regs[11] = 99;
+        assert_eq!(call(&mut sbi, &mut cpu, EXT_SET_TIMER, 0, &[6000]), (SUCCESS, 99));
+        assert_eq!(clint(&cpu).0, 6000);
+
+        // Hart masks start at their base, or take every hart for a base of
+        // -1, and must only name harts that exist.
+        assert_eq!(call(&mut sbi, &mut cpu, EXT_IPI, 0, &[0b1, 1]).0, SUCCESS);
+        assert_eq!(clint(&cpu).1, [false, true]);
+        assert_eq!(call(&mut sbi, &mut cpu, EXT_IPI, 0, &[0b11, 1]).0, ERR_INVALID_PARAM);
+        assert_eq!(call(&mut sbi, &mut cpu, EXT_RFENCE, 1, &[0b100, 0]).0, ERR_INVALID_PARAM);
+        assert_eq!(call(&mut sbi, &mut cpu, EXT_RFENCE, 1, &[0, u64::MAX]).0, SUCCESS);
+        assert_eq!(call(&mut sbi, &mut cpu, EXT_IPI, 0, &[0, u64::MAX]).0, SUCCESS);
+        assert_eq!(clint(&cpu).1, [true, true]);
+
+        // The legacy IPI takes the mask from memory.
+        cpu.bus.lock().unwrap().clint.as_mut().unwrap().msip = vec![false, false];
+        cpu.store(DRAM_BASE, 64, 0b10).unwrap();
+        assert_eq!(call(&mut sbi, &mut cpu, EXT_SEND_IPI, 0, &[DRAM_BASE]).0, SUCCESS);
+        assert_eq!(clint(&cpu).1, [false, true]);
+        cpu.csrs[MIP] |= SSIP_BIT;
+        call(&mut sbi, &mut cpu, EXT_CLEAR_IPI, 0, &[]);
+        assert_eq!(cpu.csrs[MIP] & SSIP_BIT, 0);
+    }
+
+    #[test]
+    fn timers_and_ipis_need_a_clint() {
+        let mut cpu = machine(&MachineConfig::default(), &[]).harts.remove(0);
+        assert!(cpu.bus.lock().unwrap().clint.is_none());
+        let mut sbi = Sbi::new(1);
+        assert_eq!(call(&mut sbi, &mut cpu, EXT_TIME, 0, &[5000]).0, ERR_NOT_SUPPORTED);
+        assert_eq!(call(&mut sbi, &mut cpu, EXT_SET_TIMER, 0, &[5000]).0, ERR_NOT_SUPPORTED);
+        assert_eq!(call(&mut sbi, &mut cpu, EXT_IPI, 0, &[0b1, 0]).0, ERR_NOT_SUPPORTED);
+    }
+
+    #[test]
+    fn kernels_start_harts_and_power_off() {
+        let config = MachineConfig { harts: 2, ..virt() };
+        let mut rv64 = machine(&config, &[]);
+        rv64.enable_sbi(&config);
+        let mut program = vec![];
+        // Hart 0 starts hart 1 at the code below with 3 as the opaque value.
+        program.extend(li(A7, EXT_HSM as i32));
+        let (auipc_a1, ecall) = (0x17 | A1 << 7, 0x73);
+        program.extend([addi(A0, ZERO, 1), auipc_a1, addi(A1, A1, 20), addi(A2, ZERO, 3), ecall, SPIN]);
+        // Hart 1 shuts down for no reason, if it got its ID and the value.
+        program.extend(li(A7, EXT_SRST as i32));
+        program.extend([addi(A0, A0, -1), addi(A1, A1, -3), ecall, SPIN]);
+        let code: Vec<u8> = program.iter().flat_map(|word| word.to_le_bytes()).collect();
+        rv64.bus.lock().unwrap().write_bytes(config.dram_base + KERNEL_OFFSET, &code).unwrap();
+        rv64.run(10);
+        assert_eq!(rv64.exit_status, Some(0));
+        assert_eq!(rv64.harts[0].regs[10], SUCCESS as u64);
+    }
+}
@@ -0,0 +1,385 @@
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, IsTerminal, Read, Seek, SeekFrom, Write};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::cpu::*;
+use crate::replay::*;
+use crate::syscall::{EFAULT, EIO};
+
+// The instructions around the `ebreak` that mark a semihosting call.
+pub const SEMIHOSTING_ENTRY: u32 = 0x01f0_1013; // slli x0, x0, 0x1f
+pub const SEMIHOSTING_EXIT: u32 = 0x4070_5013; // srai x0, x0, 7
+
+// Operation numbers, shared with the Arm semihosting specification
+const SYS_OPEN: u64 = 0x01;
+const SYS_CLOSE: u64 = 0x02;
+const SYS_WRITEC: u64 = 0x03;
+const SYS_WRITE0: u64 = 0x04;
+const SYS_WRITE: u64 = 0x05;
+const SYS_READ: u64 = 0x06;
+const SYS_ISTTY: u64 = 0x09;
+const SYS_SEEK: u64 = 0x0a;
+const SYS_FLEN: u64 = 0x0c;
+const SYS_CLOCK: u64 = 0x10;
+const SYS_TIME: u64 = 0x11;
+const SYS_ERRNO: u64 = 0x13;
+const SYS_GET_CMDLINE: u64 = 0x15;
+const SYS_EXIT: u64 = 0x18;
+
+const ADP_STOPPED_APPLICATION_EXIT: u64 = 0x20026;
+
+/// -1, returned by most operations on failure
+const FAILURE: u64 = u64::MAX;
+
+enum Handle {
+    Stdin,
+    Stdout,
+    Stderr,
+    File(File),
+}
+
+/// Host side of RISC-V semihosting.
+///
+/// A call is `a0` = operation and `a1` = parameter or pointer to a block of
+/// 64-bit parameters; the result goes back to `a0`.
+pub struct Semihosting {
+    handles: BTreeMap<u64, Handle>,
+    cmdline: String,
+    errno: i64,
+    start: Instant,
+    /// Stdin and the host clock.
+    host: HostInput,
+    /// Set once the guest calls SYS_EXIT.
+    pub exit_status: Option<i32>,
+    /// Writes to stdout and stderr are dropped while set.
+    muted: bool,
+}
+
+impl Semihosting {
+    pub fn new(args: &[String], log: Option<&SharedLog>) -> Self {
+        Self {
+            handles: BTreeMap::new(),
+            cmdline: args.join(" "),
+            errno: 0,
+            start: Instant::now(),
+            host: HostInput::new("semihosting", log),
+            exit_status: None,
+            muted: false,
+        }
+    }
+
+    /// Stops or resumes output to stdout and stderr, like `Device::mute`.
+    pub fn mute(&mut self, muted: bool) {
+        self.muted = muted;
+    }
+
+    pub fn call(&mut self, cpu: &mut Cpu) {
+        let op = cpu.regs[10];
+        let param = cpu.truncate(cpu.regs[11]);
+        // Parameter blocks hold XLEN-sized fields.
+        let xlen = cpu.xlen as u64;
+        let arg = |cpu: &mut Cpu, i: u64| cpu.load(param + i * xlen / 8, xlen).unwrap_or(0);
+
+        let ret = match op {
+            SYS_OPEN => {
+                let (name, mode, len) = (arg(cpu, 0), arg(cpu, 1), arg(cpu, 2));
+                let name = read_string(cpu, name, Some(len));
+                self.open(&name, mode)
+            }
+            SYS_CLOSE => match self.handles.remove(&arg(cpu, 0)) {
+                Some(_) => 0,
+                None => FAILURE,
+            },
+            SYS_WRITEC => {
+                let c = cpu.load(param, 8).unwrap_or(0) as u8;
+                if !self.muted {
+                    let _ = write_stdout(&[c]);
+                }
+                0
+            }
+            SYS_WRITE0 => {
+                let s = read_string(cpu, param, None);
+                if !self.muted {
+                    let _ = write_stdout(s.as_bytes());
+                }
+                0
+            }
+            SYS_WRITE => {
+                let (handle, buf, len) = (arg(cpu, 0), arg(cpu, 1), arg(cpu, 2));
+                let data = cpu.bus.lock().unwrap().read_bytes(buf, len).unwrap_or_default();
+                let written = match self.handles.get_mut(&handle) {
+                    Some(Handle::Stdout | Handle::Stderr) if self.muted => Ok(data.len()),
+                    Some(Handle::Stdout) => write_stdout(&data).map(|_| data.len()),
+                    Some(Handle::Stderr) => io::stderr().write_all(&data).map(|_| data.len()),
+                    Some(Handle::File(file)) => file.write(&data),
+                    _ => Ok(0),
+                };
+                // The number of bytes that were *not* written
+                len - self.check(written).unwrap_or(0) as u64
+            }
+            SYS_READ => {
+                let (handle, buf, len) = (arg(cpu, 0), arg(cpu, 1), arg(cpu, 2));
+                self.read(cpu, handle, buf, len)
+            }
+            SYS_ISTTY => match self.handles.get(&arg(cpu, 0)) {
+                Some(Handle::Stdin) => io::stdin().is_terminal() as u64,
+                Some(Handle::Stdout) => io::stdout().is_terminal() as u64,
+                Some(Handle::Stderr) => io::stderr().is_terminal() as u64,
+                _ => 0,
+            },
+            SYS_SEEK => {
+                let (handle, pos) = (arg(cpu, 0), arg(cpu, 1));
+                let result = match self.handles.get_mut(&handle) {
+                    Some(Handle::File(file)) => file.seek(SeekFrom::Start(pos)),
+                    _ => Err(io::Error::from(io::ErrorKind::Unsupported)),
+                };
+                self.check(result).map_or(FAILURE, |_| 0)
+            }
+            SYS_FLEN => {
+                let len = match self.handles.get(&arg(cpu, 0)) {
+                    Some(Handle::File(file)) => file.metadata().map(|m| m.len()),
+                    _ => Err(io::Error::from(io::ErrorKind::Unsupported)),
+                };
+                self.check(len).unwrap_or(FAILURE)
+            }
+            // Centiseconds since the program started
+            SYS_CLOCK => {
+                let start = self.start;
+                self.clock(|| (start.elapsed().as_millis() / 10) as u64)
+            }
+            SYS_TIME => self.clock(|| SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()),
+            SYS_ERRNO => self.errno as u64,
+            SYS_GET_CMDLINE => {
+                let (buf, len) = (arg(cpu, 0), arg(cpu, 1));
+                if self.cmdline.len() as u64 + 1 > len {
+                    FAILURE
+                } else {
+                    let mut bus = cpu.bus.lock().unwrap();
+                    let _ = bus.write_bytes(buf, self.cmdline.as_bytes());
+                    let _ = bus.store(buf + self.cmdline.len() as u64, 8, 0);
+                    let _ = bus.store(param + xlen / 8, xlen, self.cmdline.len() as u64);
+                    0
+                }
+            }
+            SYS_EXIT => {
+                // On RV64 the parameter points to a (reason, subcode) pair.
+                // On RV32 it is the reason itself and there is no status.
+                self.exit_status = Some(if xlen == 32 {
+                    (param != ADP_STOPPED_APPLICATION_EXIT) as i32
+                } else if arg(cpu, 0) == ADP_STOPPED_APPLICATION_EXIT {
+                    arg(cpu, 1) as i32
+                } else {
+                    1
+                });
+                0
+            }
+            _ => {
+                eprintln!("semihosting: unsupported operation {:#x}", op);
+                FAILURE
+            }
+        };
+
+        cpu.regs[10] = ret;
+    }
+
+    /// SYS_READ: reads up to `len` bytes into `buf` and returns the number
+    /// of bytes that were *not* read.
+    fn read(&mut self, cpu: &mut Cpu, handle: u64, buf: u64, len: u64) -> u64 {
+        // Nothing is read into a buffer that is not all memory.
+        if !cpu.bus.lock().unwrap().is_memory_range(buf, len) {
+            self.errno = EFAULT;
+            return len;
+        }
+        let mut data = vec![0; len as usize];
+        let read = match self.handles.get_mut(&handle) {
+            Some(Handle::Stdin) => {
+                let input = self.host.read(|| {
+                    let mut input = vec![0; data.len()];
+                    let n = io::stdin().read(&mut input).unwrap_or(0);
+                    input.truncate(n);
+                    input
+                });
+                let n = input.len().min(data.len());
+                data[..n].copy_from_slice(&input[..n]);
+                Ok(n)
+            }
+            Some(Handle::File(file)) => file.read(&mut data),
+            _ => Ok(0),
+        };
+        let n = self.check(read).unwrap_or(0);
+        let _ = cpu.bus.lock().unwrap().write_bytes(buf, &data[..n]);
+        len - n as u64
+    }
+
+    /// Reads a host clock, or what it read in the recorded run.
+    fn clock(&mut self, live: impl FnOnce() -> u64) -> u64 {
+        let value = self.host.read(|| live().to_le_bytes().to_vec());
+        value.try_into().map_or(0, u64::from_le_bytes)
+    }
+
+    /// Records the errno of a failed host call.
+    fn check<T>(&mut self, result: io::Result<T>) -> Option<T> {
+        match result {
+            Ok(value) => Some(value),
+            Err(e) => {
+                self.errno = e.raw_os_error().map_or(EIO, |n| n as i64);
+                None
+            }
+        }
+    }
+
+    /// Opens a file with an `fopen`-style mode index. `:tt` is the console.
+    fn open(&mut self, name: &str, mode: u64) -> u64 {
+        let handle = if name == ":tt" {
+            match mode {
+                0..=3 => Handle::Stdin,
+                4..=7 => Handle::Stdout,
+                _ => Handle::Stderr,
+            }
+        } else {
+            // r, rb, r+, r+b, w, wb, w+, w+b, a, ab, a+, a+b
+            let mut options = OpenOptions::new();
+            match mode / 4 {
+                0 => options.read(true).write(mode & 2 != 0),
+                1 => options.write(true).create(true).truncate(true).read(mode & 2 != 0),
+                _ => options.append(true).create(true).read(mode & 2 != 0),
+            };
+            match self.check(options.open(name)) {
+                Some(file) => Handle::File(file),
+                None => return FAILURE,
+            }
+        };
+
+        let fd = (1..).find(|fd| !self.handles.contains_key(fd)).unwrap();
+        self.handles.insert(fd, handle);
+        fd
+    }
+}
+
+fn write_stdout(data: &[u8]) -> io::Result<()> {
+    let mut stdout = io::stdout();
+    stdout.write_all(data)?;
+    stdout.flush()
+}
+
+/// Reads `len` bytes, or up to the terminating NUL when `len` is `None`.
+fn read_string(cpu: &mut Cpu, addr: u64, len: Option<u64>) -> String {
+    let mut bytes = Vec::new();
+    while len.is_none_or(|len| (bytes.len() as u64) < len) {
+        match cpu.load(addr + bytes.len() as u64, 8) {
+            Ok(0) if len.is_none() => break,
+            Ok(byte) => bytes.push(byte as u8),
+            Err(_) => break,
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::DRAM_BASE;
+    use crate::config::*;
+    use crate::exception::*;
+    use crate::testing::*;
+
+    /// Makes a call with a parameter block at the start of DRAM.
+    fn call(host: &mut Semihosting, cpu: &mut Cpu, op: u64, params: &[u64]) -> u64 {
+        let block = cpu.pc;
+        for (i, &param) in params.iter().enumerate() {
+            cpu.bus.lock().unwrap().store(block + i as u64 * 8, 64, param).unwrap();
+        }
+        cpu.regs[10] = op;
+        cpu.regs[11] = block;
+        host.call(cpu);
+        cpu.regs[10]
+    }
+
+    #[test]
+    fn read_into_a_buffer_outside_memory_fails() {
+        let mut machine = machine(&MachineConfig::default(), &[]);
+        let cpu = &mut machine.harts[0];
+        let mut host = Semihosting::new(&[], None);
+        cpu.bus.lock().unwrap().write_bytes(cpu.pc + 0x100, b":tt\0").unwrap();
+        let tt = call(&mut host, cpu, SYS_OPEN, &[cpu.pc + 0x100, 0, 3]);
+        assert_ne!(tt, FAILURE);
+        let buf = cpu.pc + 0x200;
+        let huge = 1 << 62;
+        assert_eq!(call(&mut host, cpu, SYS_READ, &[tt, buf, huge]), huge);
+        assert_eq!(call(&mut host, cpu, SYS_ERRNO, &[]), EFAULT as u64);
+    }
+    #[test]
+    fn ebreak_between_the_markers_calls_the_host() {
+        let program = [
+            addi(A0, ZERO, SYS_ERRNO as i32),
+            SEMIHOSTING_ENTRY,
+            0x0010_0073, // ebreak
+            SEMIHOSTING_EXIT,
+            0x0010_0073,
+        ];
+        let mut rv64 = machine(&MachineConfig::default(), &program);
+        rv64.enable_semihosting(&[]);
+        let cpu = &mut rv64.harts[0];
+        for _ in 0..4 {
+            cpu.step().unwrap();
+        }
+        assert_eq!((cpu.regs[A0 as usize], cpu.pc), (0, DRAM_BASE + 16));
+        // Without the entry marker before it, an ebreak still traps.
+        assert_eq!(cpu.step(), Err(Exception::Breakpoint(DRAM_BASE + 16)));
+
+        // As does a c.ebreak between the markers.
+        let program = [addi(A0, ZERO, SYS_ERRNO as i32), SEMIHOSTING_ENTRY, 0x0001_9002, SEMIHOSTING_EXIT];
+        let mut rv64 = machine(&MachineConfig::default(), &program);
+        rv64.enable_semihosting(&[]);
+        let cpu = &mut rv64.harts[0];
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.step(), Err(Exception::Breakpoint(DRAM_BASE + 8)));
+        assert_eq!(cpu.regs[A0 as usize], SYS_ERRNO);
+    }
+
+    #[test]
+    fn writes_to_the_console_and_reports_the_command_line() {
+        let mut machine = machine(&MachineConfig::default(), &[]);
+        let cpu = &mut machine.harts[0];
+        let mut host = Semihosting::new(&[String::from("prog"), String::from("-v")], None);
+        host.mute(true);
+        let buf = cpu.pc + 0x100;
+        cpu.bus.lock().unwrap().write_bytes(buf, b"hi\0").unwrap();
+        cpu.regs[11] = buf;
+        cpu.regs[10] = SYS_WRITEC;
+        host.call(cpu);
+        assert_eq!(cpu.regs[10], 0);
+        cpu.regs[10] = SYS_WRITE0;
+        host.call(cpu);
+        assert_eq!(cpu.regs[10], 0);
+
+        assert_eq!(call(&mut host, cpu, SYS_GET_CMDLINE, &[buf, 8]), 0);
+        assert_eq!(cpu.bus.lock().unwrap().read_bytes(buf, 8), Ok(b"prog -v\0".to_vec()));
+        // The length in the block is updated to that of the string.
+        assert_eq!(cpu.load(cpu.pc + 8, 64), Ok(7));
+        assert_eq!(call(&mut host, cpu, SYS_GET_CMDLINE, &[buf, 7]), FAILURE);
+    }
+
+    #[test]
+    fn exit_takes_a_block_on_rv64_and_a_reason_on_rv32() {
+        let mut rv64 = machine(&MachineConfig::default(), &[]);
+        let cpu = &mut rv64.harts[0];
+        let mut host = Semihosting::new(&[], None);
+        call(&mut host, cpu, SYS_EXIT, &[ADP_STOPPED_APPLICATION_EXIT, 3]);
+        assert_eq!(host.exit_status, Some(3));
+        call(&mut host, cpu, SYS_EXIT, &[0x20023, 0]);
+        assert_eq!(host.exit_status, Some(1));
+
+        let mut rv32 = machine(&MachineConfig { xlen: Some(32), ..Default::default() }, &[]);
+        let cpu = &mut rv32.harts[0];
+        let mut host = Semihosting::new(&[], None);
+        for (reason, status) in [(ADP_STOPPED_APPLICATION_EXIT, 0), (0x20023, 1)] {
+            cpu.regs[10] = SYS_EXIT;
+            cpu.regs[11] = reason;
+            host.call(cpu);
+            assert_eq!(host.exit_status, Some(status));
+        }
+    }
+}
@@ -0,0 +1,318 @@
+use std::collections::HashMap;
+use std::io;
+
+pub const SNAPSHOT_MAGIC: &[u8; 8] = b"RVEMUSNP";
+/// Bumped whenever the layout of any saved state changes; older snapshots
+/// are refused rather than misread.
+pub const SNAPSHOT_VERSION: u32 = 5;
+
+/// `slti x0, x0, 0x5a`, a hint that does nothing on hardware. When a
+/// snapshot file is configured, a guest executing it asks for a snapshot.
+pub const SNAPSHOT_HINT: u32 = 0x05a0_2013;
+
+/// Memory is deduplicated and compressed in pages of this size.
+const PAGE: usize = 4096;
+/// Stands for a page of zeros in the page index.
+const ZERO_PAGE: u32 = u32::MAX;
+
+const MIN_MATCH: usize = 4;
+const MAX_MATCH: usize = MIN_MATCH + 0x7f;
+const MAX_LITERALS: usize = 0x80;
+
+/// Serialises machine state, little-endian.
+#[derive(Default)]
+pub struct StateWriter {
+    pub data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u128(&mut self, value: u128) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    /// A length-prefixed byte string.
+    pub fn bytes(&mut self, value: &[u8]) {
+        self.u64(value.len() as u64);
+        self.data.extend_from_slice(value);
+    }
+
+    /// The contents of a memory, with repeated pages stored once and every
+    /// page compressed.
+    pub fn memory(&mut self, memory: &[u8]) {
+        let mut unique: HashMap<&[u8], u32> = HashMap::new();
+        let mut pages = Vec::new();
+        let mut index = Vec::with_capacity(memory.len().div_ceil(PAGE));
+        let zeros = [0; PAGE];
+        for page in memory.chunks(PAGE) {
+            if page == &zeros[..page.len()] {
+                index.push(ZERO_PAGE);
+                continue;
+            }
+            let next = pages.len() as u32;
+            let id = *unique.entry(page).or_insert_with(|| {
+                pages.push(page);
+                next
+            });
+            index.push(id);
+        }
+
+        self.u64(memory.len() as u64);
+        let index: Vec<u8> = index.iter().flat_map(|id| id.to_le_bytes()).collect();
+        self.bytes(&compress(&index));
+        self.u32(pages.len() as u32);
+        for page in pages {
+            self.bytes(&compress(page));
+        }
+    }
+}
+
+/// Reads back what a `StateWriter` wrote.
+pub struct StateReader<'a> {
+    data: &'a [u8],
+}
+
+pub fn invalid_snapshot(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("invalid snapshot: {}", message))
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    /// Whether everything has been read.
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if self.data.len() < len {
+            return Err(invalid_snapshot("truncated"));
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    pub fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> io::Result<bool> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub fn u16(&mut self) -> io::Result<u16> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    pub fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub fn u128(&mut self) -> io::Result<u128> {
+        Ok(u128::from_le_bytes(self.take(16)?.try_into().unwrap()))
+    }
+
+    pub fn bytes(&mut self) -> io::Result<&'a [u8]> {
+        let len = self.u64()?;
+        self.take(usize::try_from(len).map_err(|_| invalid_snapshot("truncated"))?)
+    }
+
+    /// Reads a collection length, checked against what a machine of this
+    /// configuration has.
+    pub fn count(&mut self, expected: usize, what: &str) -> io::Result<()> {
+        if self.u64()? != expected as u64 {
+            return Err(invalid_snapshot(&format!("the machine has a different number of {}", what)));
+        }
+        Ok(())
+    }
+
+    /// Fills `memory` with what `StateWriter::memory` wrote. The sizes must
+    /// match.
+    pub fn memory(&mut self, memory: &mut [u8]) -> io::Result<()> {
+        if self.u64()? != memory.len() as u64 {
+            return Err(invalid_snapshot("memory size differs from the machine's"));
+        }
+        let mut index = vec![0; memory.len().div_ceil(PAGE) * 4];
+        decompress(self.bytes()?, &mut index).ok_or_else(|| invalid_snapshot("corrupt page index"))?;
+        let index = index.chunks(4).map(|id| u32::from_le_bytes(id.try_into().unwrap()));
+        let pages = (0..self.u32()?)
+            .map(|_| self.bytes())
+            .collect::<io::Result<Vec<&[u8]>>>()?;
+
+        for (page, id) in memory.chunks_mut(PAGE).zip(index) {
+            if id == ZERO_PAGE {
+                page.fill(0);
+                continue;
+            }
+            let compressed = pages.get(id as usize).ok_or_else(|| invalid_snapshot("bad page index"))?;
+            decompress(compressed, page).ok_or_else(|| invalid_snapshot("corrupt page"))?;
+        }
+        Ok(())
+    }
+}
+
+/// LZ77 compression of a page or the page index. The output is a sequence
+/// of literal runs, a byte `n - 1` followed by `n` bytes, and matches, a
+/// byte `0x80 | (len - 4)` followed by the 16-bit distance back to copy
+/// from.
+fn compress(input: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut table = [usize::MAX; 1 << 12];
+    let mut literals = 0;
+    let mut i = 0;
+
+    let flush = |out: &mut Vec<u8>, literals: &[u8]| {
+        for run in literals.chunks(MAX_LITERALS) {
+            out.push((run.len() - 1) as u8);
+            out.extend_from_slice(run);
+        }
+    };
+
+    while i + MIN_MATCH <= input.len() {
+        let key = u32::from_le_bytes(input[i..i + 4].try_into().unwrap());
+        let hash = (key.wrapping_mul(2_654_435_761) >> 20) as usize;
+        let candidate = table[hash];
+        table[hash] = i;
+        if candidate == usize::MAX
+            || i - candidate > u16::MAX as usize
+            || input[candidate..candidate + MIN_MATCH] != input[i..i + MIN_MATCH]
+        {
+            i += 1;
+            continue;
+        }
+        let len = input[i..]
+            .iter()
+            .zip(&input[candidate..])
+            .take(MAX_MATCH)
+            .take_while(|(a, b)| a == b)
+            .count();
+        flush(&mut out, &input[literals..i]);
+        out.push(0x80 | (len - MIN_MATCH) as u8);
+        out.extend_from_slice(&((i - candidate) as u16).to_le_bytes());
+        i += len;
+        literals = i;
+    }
+    flush(&mut out, &input[literals..]);
+    out
+}
+
+/// Undoes `compress`, which must produce exactly `out.len()` bytes.
+fn decompress(mut input: &[u8], out: &mut [u8]) -> Option<()> {
+    let mut pos = 0;
+    while let Some((&token, rest)) = input.split_first() {
+        if token & 0x80 == 0 {
+            let len = token as usize + 1;
+            out.get_mut(pos..pos + len)?.copy_from_slice(rest.get(..len)?);
+            pos += len;
+            input = &rest[len..];
+        } else {
+            let len = (token & 0x7f) as usize + MIN_MATCH;
+            let distance = u16::from_le_bytes(rest.get(..2)?.try_into().unwrap()) as usize;
+            let from = pos.checked_sub(distance).filter(|_| distance != 0)?;
+            if pos + len > out.len() {
+                return None;
+            }
+            // Byte by byte, as the source may overlap what is being written.
+            for k in 0..len {
+                out[pos + k] = out[from + k];
+            }
+            pos += len;
+            input = &rest[2..];
+        }
+    }
+    (pos == out.len()).then_some(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Pages of zeros, of repeated and overlapping runs, and of noise
+    fn pages() -> Vec<u8> {
+        let mut memory = vec![0; 3 * PAGE];
+        memory.extend((0..PAGE).map(|i| (i % 7) as u8));
+        memory.extend((0..PAGE).map(|i| (i / 300) as u8));
+        let mut x = 1u32;
+        memory.extend((0..PAGE).map(|_| {
+            x ^= x << 13;
+            x ^= x >> 17;
+            x ^= x << 5;
+            x as u8
+        }));
+        // The same page again, and a partial one
+        memory.extend_from_within(3 * PAGE..4 * PAGE);
+        memory.extend_from_slice(b"tail");
+        memory
+    }
+
+    #[test]
+    fn compression_round_trips() {
+        let memory = pages();
+        for page in memory.chunks(PAGE) {
+            let compressed = compress(page);
+            let mut out = vec![0xaa; page.len()];
+            assert_eq!(decompress(&compressed, &mut out), Some(()));
+            assert_eq!(out, page);
+        }
+        // Runs compress to a few bytes.
+        assert!(compress(&memory[3 * PAGE..4 * PAGE]).len() < 200);
+    }
+
+    #[test]
+    fn memory_round_trips_with_pages_stored_once() {
+        let memory = pages();
+        let mut out = StateWriter::default();
+        out.memory(&memory);
+        let noise = compress(&memory[5 * PAGE..6 * PAGE]).len();
+        assert!(out.data.len() < noise + 1000);
+
+        let mut restored = vec![0xaa; memory.len()];
+        let mut input = StateReader::new(&out.data);
+        input.memory(&mut restored).unwrap();
+        assert!(input.is_empty());
+        assert_eq!(restored, memory);
+        // The size has to match.
+        assert!(StateReader::new(&out.data).memory(&mut restored[1..]).is_err());
+    }
+
+    #[test]
+    fn corrupt_data_is_refused() {
+        let mut out = [0; 8];
+        // A match reaching back before the start, or past the end
+        assert_eq!(decompress(&[0x80, 1, 0], &mut out), None);
+        assert_eq!(decompress(&[0, 1, 0x84, 1, 0], &mut out), None);
+        // Too little or too much data
+        assert_eq!(decompress(&[3, 1, 2, 3, 4], &mut out), None);
+        assert_eq!(decompress(&[8, 0, 0, 0, 0, 0, 0, 0, 0, 0], &mut out), None);
+        assert_eq!(decompress(&[0, 1, 0x83, 1, 0, 0, 2], &mut out), None);
+        assert_eq!(decompress(&[0, 1, 0x83, 1, 0], &mut out), Some(()));
+        assert!(StateReader::new(&[1, 2, 3]).u64().is_err());
+        assert!(StateReader::new(&[9, 0, 0, 0, 0, 0, 0, 0, 1]).bytes().is_err());
+    }
+}
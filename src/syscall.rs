@@ -0,0 +1,978 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, IsTerminal, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::{FileExt, MetadataExt, OpenOptionsExt};
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::bus::*;
+use crate::cpu::*;
+use crate::elf::*;
+use crate::exception::*;
+use crate::replay::*;
+
+const PAGE_SIZE: u64 = 4096;
+/// Space kept free below the initial stack pointer before `mmap` regions.
+const STACK_SIZE: u64 = 8 * 1024 * 1024;
+
+// Linux errno values
+pub const ENOENT: i64 = 2;
+pub const EIO: i64 = 5;
+pub const EBADF: i64 = 9;
+pub const EAGAIN: i64 = 11;
+pub const ENOMEM: i64 = 12;
+pub const EFAULT: i64 = 14;
+pub const EINVAL: i64 = 22;
+pub const ENOTTY: i64 = 25;
+pub const ESPIPE: i64 = 29;
+pub const ERANGE: i64 = 34;
+pub const ENOSYS: i64 = 38;
+
+pub const AT_FDCWD: i64 = -100;
+const AT_EMPTY_PATH: u64 = 0x1000;
+const AT_REMOVEDIR: u64 = 0x200;
+
+const MAP_FIXED: u64 = 0x10;
+const MAP_ANONYMOUS: u64 = 0x20;
+
+// Auxiliary vector entries
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_BASE: u64 = 7;
+const AT_ENTRY: u64 = 9;
+const AT_UID: u64 = 11;
+const AT_EUID: u64 = 12;
+const AT_GID: u64 = 13;
+const AT_EGID: u64 = 14;
+const AT_HWCAP: u64 = 16;
+const AT_CLKTCK: u64 = 17;
+const AT_RANDOM: u64 = 25;
+const AT_EXECFN: u64 = 31;
+
+/// Names and argument counts of the syscalls, used for `--strace`.
+fn syscall_info(number: u64) -> Option<(&'static str, usize)> {
+    Some(match number {
+        17 => ("getcwd", 2),
+        23 => ("dup", 1),
+        25 => ("fcntl", 3),
+        29 => ("ioctl", 3),
+        34 => ("mkdirat", 3),
+        35 => ("unlinkat", 3),
+        48 => ("faccessat", 3),
+        56 => ("openat", 4),
+        57 => ("close", 1),
+        62 => ("lseek", 3),
+        63 => ("read", 3),
+        64 => ("write", 3),
+        65 => ("readv", 3),
+        66 => ("writev", 3),
+        67 => ("pread64", 4),
+        68 => ("pwrite64", 4),
+        78 => ("readlinkat", 4),
+        79 => ("newfstatat", 4),
+        80 => ("fstat", 2),
+        93 => ("exit", 1),
+        94 => ("exit_group", 1),
+        96 => ("set_tid_address", 1),
+        98 => ("futex", 6),
+        99 => ("set_robust_list", 2),
+        101 => ("nanosleep", 2),
+        113 => ("clock_gettime", 2),
+        115 => ("clock_nanosleep", 4),
+        124 => ("sched_yield", 0),
+        134 => ("rt_sigaction", 4),
+        135 => ("rt_sigprocmask", 4),
+        160 => ("uname", 1),
+        169 => ("gettimeofday", 2),
+        172 => ("getpid", 0),
+        173 => ("getppid", 0),
+        174 => ("getuid", 0),
+        175 => ("geteuid", 0),
+        176 => ("getgid", 0),
+        177 => ("getegid", 0),
+        178 => ("gettid", 0),
+        214 => ("brk", 1),
+        215 => ("munmap", 2),
+        222 => ("mmap", 6),
+        226 => ("mprotect", 3),
+        233 => ("madvise", 3),
+        261 => ("prlimit64", 4),
+        278 => ("getrandom", 3),
+        _ => return None,
+    })
+}
+
+enum Fd {
+    Stdin,
+    Stdout,
+    Stderr,
+    File(File),
+}
+
+/// Emulates enough of the Linux system call interface to run statically
+/// linked RV64 user programs, in the spirit of `qemu-riscv64`.
+pub struct Linux {
+    fds: BTreeMap<u64, Fd>,
+    brk_start: u64,
+    brk: u64,
+    /// `mmap` regions are handed out downwards from here.
+    mmap_top: u64,
+    start: Instant,
+    random: u64,
+    strace: bool,
+    /// Reads from stdin, which are recorded or replayed with the rest of
+    /// the host's input.
+    stdin: HostInput,
+    /// Writes to stdout and stderr are dropped while set.
+    muted: bool,
+}
+
+impl Linux {
+    pub fn new(strace: bool, stdin: HostInput) -> Self {
+        let mut fds = BTreeMap::new();
+        fds.insert(0, Fd::Stdin);
+        fds.insert(1, Fd::Stdout);
+        fds.insert(2, Fd::Stderr);
+
+        Self {
+            fds,
+            brk_start: 0,
+            brk: 0,
+            mmap_top: 0,
+            start: Instant::now(),
+            // Fixed seed so that AT_RANDOM and getrandom are reproducible.
+            random: 0x2545_f491_4f6c_dd1d,
+            strace,
+            stdin,
+            muted: false,
+        }
+    }
+
+    /// Stops or resumes output to stdout and stderr, like `Device::mute`.
+    pub fn mute(&mut self, muted: bool) {
+        self.muted = muted;
+    }
+
+    /// Loads the ELF segments, builds the initial stack with `args`, the
+    /// environment and the auxiliary vector, and points the hart at the entry.
+    pub fn load(&mut self, cpu: &mut Cpu, elf: &Elf, data: &[u8], args: &[String]) -> Result<(), String> {
+        let mut end = 0;
+        for segment in &elf.segments {
+            let start = segment.offset as usize;
+            let bytes = &data[start..start + segment.filesz as usize];
+            let mut bus = cpu.bus.lock().unwrap();
+            if !bus.is_memory_range(segment.vaddr, segment.memsz) {
+                return Err(format!("segment at {:#x} does not fit in the memory map", segment.vaddr));
+            }
+            bus.write_bytes(segment.vaddr, bytes)
+                .and_then(|_| {
+                    let bss = vec![0; (segment.memsz - segment.filesz) as usize];
+                    bus.write_bytes(segment.vaddr + segment.filesz, &bss)
+                })
+                .map_err(|_| format!("segment at {:#x} does not fit in the memory map", segment.vaddr))?;
+            end = end.max(segment.vaddr + segment.memsz);
+        }
+        self.brk_start = round_up(end);
+        self.brk = self.brk_start;
+
+        let top = cpu.regs[2];
+        self.mmap_top = match top.checked_sub(STACK_SIZE) {
+            Some(bottom) if bottom & !(PAGE_SIZE - 1) > self.brk => bottom & !(PAGE_SIZE - 1),
+            _ => return Err(String::from("not enough memory for the stack")),
+        };
+
+        self.build_stack(cpu, elf, args, top)
+            .map_err(|e| format!("failed to build the initial stack: {:?}", e))?;
+
+        cpu.mode = USER;
+        // Like Linux, let the program read cycle, time and instret.
+        cpu.csrs[MCOUNTEREN] = 0b111;
+        cpu.csrs[SCOUNTEREN] = 0b111;
+        cpu.pc = elf.entry;
+        // a0 holds the rtld_fini pointer, which is unused for static binaries.
+        cpu.regs[10] = 0;
+
+        Ok(())
+    }
+
+    fn build_stack(&mut self, cpu: &mut Cpu, elf: &Elf, args: &[String], top: u64) -> Result<(), Exception> {
+        let envs: Vec<String> = env::vars().map(|(k, v)| format!("{}={}", k, v)).collect();
+        let mut bus = cpu.bus.lock().unwrap();
+
+        // Strings and the AT_RANDOM bytes go at the very top.
+        let mut sp = top;
+        let mut push_str = |bus: &mut Bus, s: &str| -> Result<u64, Exception> {
+            sp -= s.len() as u64 + 1;
+            bus.write_bytes(sp, s.as_bytes())?;
+            bus.store(sp + s.len() as u64, 8, 0)?;
+            Ok(sp)
+        };
+        let arg_ptrs = args.iter().map(|a| push_str(&mut bus, a)).collect::<Result<Vec<_>, _>>()?;
+        let env_ptrs = envs.iter().map(|e| push_str(&mut bus, e)).collect::<Result<Vec<_>, _>>()?;
+        let execfn = arg_ptrs.first().copied().unwrap_or(0);
+
+        let mut random = [0u8; 16];
+        for chunk in random.chunks_mut(8) {
+            chunk.copy_from_slice(&self.next_random().to_le_bytes());
+        }
+        sp = (sp - 16) & !0xf;
+        let random_ptr = sp;
+        bus.write_bytes(random_ptr, &random)?;
+
+        let auxv = [
+            (AT_PHDR, elf.phdr_addr().unwrap_or(0)),
+            (AT_PHENT, elf.phentsize as u64),
+            (AT_PHNUM, elf.phnum as u64),
+            (AT_PAGESZ, PAGE_SIZE),
+            (AT_BASE, 0),
+            (AT_ENTRY, elf.entry),
+            (AT_UID, 0),
+            (AT_EUID, 0),
+            (AT_GID, 0),
+            (AT_EGID, 0),
+            (AT_HWCAP, b"imafdc".iter().map(|&e| isa_bit(e)).fold(0, |a, b| a | b)),
+            (AT_CLKTCK, 100),
+            (AT_RANDOM, random_ptr),
+            (AT_EXECFN, execfn),
+            (AT_NULL, 0),
+        ];
+
+        // argc, argv, NULL, envp, NULL, auxv, with sp 16-byte aligned.
+        let mut words = vec![arg_ptrs.len() as u64];
+        words.extend(&arg_ptrs);
+        words.push(0);
+        words.extend(&env_ptrs);
+        words.push(0);
+        for (key, value) in auxv {
+            words.push(key);
+            words.push(value);
+        }
+        sp = (sp - words.len() as u64 * 8) & !0xf;
+        for (i, word) in words.iter().enumerate() {
+            bus.store(sp + i as u64 * 8, 64, *word)?;
+        }
+
+        cpu.regs[2] = sp;
+        Ok(())
+    }
+
+    /// Runs the program until it exits and returns its status, or the
+    /// exception that stopped it. As on a machine, the timer advances by
+    /// `quantum` ticks every `quantum` instructions.
+    pub fn run(&mut self, cpu: &mut Cpu, quantum: u64) -> Result<i32, Exception> {
+        let mut slice = 0;
+        loop {
+            match cpu.step() {
+                Ok(_) => {}
+                Err(Exception::EnvironmentCallFromUMode) => {
+                    if let Some(status) = self.syscall(cpu) {
+                        return Ok(status);
+                    }
+                    cpu.pc += 4;
+                }
+                Err(e) => return Err(e),
+            }
+            slice += 1;
+            if slice == quantum {
+                cpu.bus.lock().unwrap().tick(quantum);
+                slice = 0;
+            }
+        }
+    }
+
+    /// Services the `ecall` the hart just executed. Returns the exit status
+    /// once the program has exited.
+    pub fn syscall(&mut self, cpu: &mut Cpu) -> Option<i32> {
+        let number = cpu.regs[17];
+        let args = [
+            cpu.regs[10],
+            cpu.regs[11],
+            cpu.regs[12],
+            cpu.regs[13],
+            cpu.regs[14],
+            cpu.regs[15],
+        ];
+
+        if number == 93 || number == 94 {
+            // exit, exit_group
+            if self.strace {
+                eprintln!("{}({}) = ?", syscall_info(number).unwrap().0, args[0] as i32);
+            }
+            return Some(args[0] as i32);
+        }
+
+        let ret = self.dispatch(cpu, number, &args);
+        self.trace(number, &args, ret);
+
+        cpu.regs[10] = ret as u64;
+        None
+    }
+
+    /// Logs a finished system call when `--strace` is on.
+    pub fn trace(&self, number: u64, args: &[u64; 6], ret: i64) {
+        if !self.strace {
+            return;
+        }
+        match syscall_info(number) {
+            Some((name, nargs)) => {
+                let formatted: Vec<String> = args[..nargs].iter().map(|a| format!("{:#x}", a)).collect();
+                if (0..0x1000).contains(&ret) || ret < 0 {
+                    eprintln!("{}({}) = {}", name, formatted.join(", "), ret);
+                } else {
+                    eprintln!("{}({}) = {:#x}", name, formatted.join(", "), ret);
+                }
+            }
+            None => eprintln!("syscall_{}(...) = {} (unsupported)", number, ret),
+        }
+    }
+
+    /// Runs one system call other than `exit`/`exit_group` and returns its
+    /// result, a negative errno on failure.
+    pub fn dispatch(&mut self, cpu: &mut Cpu, number: u64, args: &[u64; 6]) -> i64 {
+        match number {
+            17 => self.getcwd(cpu, args[0], args[1]),
+            23 => self.dup(args[0]),
+            25 => match args[1] {
+                // F_DUPFD, F_DUPFD_CLOEXEC
+                0 | 1030 => self.dup(args[0]),
+                _ if self.fds.contains_key(&args[0]) => 0,
+                _ => -EBADF,
+            },
+            29 => self.ioctl(cpu, args[0], args[1], args[2]),
+            34 => match self.path_at(cpu, args[0], args[1]) {
+                Ok(path) => result(fs::create_dir(path).map(|_| 0)),
+                Err(e) => e,
+            },
+            35 => match self.path_at(cpu, args[0], args[1]) {
+                Ok(path) if args[2] & AT_REMOVEDIR != 0 => result(fs::remove_dir(path).map(|_| 0)),
+                Ok(path) => result(fs::remove_file(path).map(|_| 0)),
+                Err(e) => e,
+            },
+            48 => match self.path_at(cpu, args[0], args[1]) {
+                Ok(path) if path.exists() => 0,
+                Ok(_) => -ENOENT,
+                Err(e) => e,
+            },
+            56 => self.openat(cpu, args[0], args[1], args[2], args[3]),
+            57 => match self.fds.remove(&args[0]) {
+                Some(_) => 0,
+                None => -EBADF,
+            },
+            62 => self.lseek(args[0], args[1] as i64, args[2]),
+            63 => self.read(cpu, args[0], args[1], args[2], None),
+            64 => self.write(cpu, args[0], args[1], args[2], None),
+            65 | 66 => {
+                // readv, writev
+                let mut total = 0;
+                for i in 0..args[2] {
+                    let iov = args[1] + i * 16;
+                    let (base, len) = match (cpu.load(iov, 64), cpu.load(iov + 8, 64)) {
+                        (Ok(base), Ok(len)) => (base, len),
+                        _ => return -EFAULT,
+                    };
+                    let n = if number == 65 {
+                        self.read(cpu, args[0], base, len, None)
+                    } else {
+                        self.write(cpu, args[0], base, len, None)
+                    };
+                    if n < 0 {
+                        return if total > 0 { total } else { n };
+                    }
+                    total += n;
+                    if (n as u64) < len {
+                        break;
+                    }
+                }
+                total
+            }
+            67 => self.read(cpu, args[0], args[1], args[2], Some(args[3])),
+            68 => self.write(cpu, args[0], args[1], args[2], Some(args[3])),
+            78 => match self.path_at(cpu, args[0], args[1]) {
+                Ok(path) => match fs::read_link(path) {
+                    Ok(target) => {
+                        let target = target.to_string_lossy().into_owned();
+                        let bytes = &target.as_bytes()[..target.len().min(args[3] as usize)];
+                        match cpu.bus.lock().unwrap().write_bytes(args[2], bytes) {
+                            Ok(_) => bytes.len() as i64,
+                            Err(_) => -EFAULT,
+                        }
+                    }
+                    Err(e) => errno(e),
+                },
+                Err(e) => e,
+            },
+            79 => {
+                // newfstatat
+                if args[3] & AT_EMPTY_PATH != 0 && matches!(self.read_cstring(cpu, args[1]).as_deref(), Ok("")) {
+                    return self.fstat(cpu, args[0], args[2]);
+                }
+                match self.path_at(cpu, args[0], args[1]) {
+                    Ok(path) => match fs::metadata(path) {
+                        Ok(meta) => write_stat(cpu, args[2], &meta),
+                        Err(e) => errno(e),
+                    },
+                    Err(e) => e,
+                }
+            }
+            80 => self.fstat(cpu, args[0], args[1]),
+            96 | 172 | 178 => std::process::id() as i64, // set_tid_address, getpid, gettid
+            173 => 1,                                     // getppid
+            174..=177 => 0,                               // getuid, geteuid, getgid, getegid
+            98 => match args[1] & 0x7f {
+                // futex: with a single thread nobody else can change the
+                // word, so waiting would block forever.
+                0 => -EAGAIN,
+                _ => 0,
+            },
+            99 | 124 | 134 | 135 | 226 | 233 => 0, // set_robust_list, sched_yield, signals, mprotect, madvise
+            101 | 115 => {
+                // nanosleep, clock_nanosleep
+                let req = if number == 101 { args[0] } else { args[2] };
+                match (cpu.load(req, 64), cpu.load(req.wrapping_add(8), 64)) {
+                    (Ok(sec), Ok(nsec)) if (sec as i64) < 0 || nsec >= 1_000_000_000 => -EINVAL,
+                    (Ok(sec), Ok(nsec)) => {
+                        thread::sleep(Duration::new(sec, nsec as u32));
+                        0
+                    }
+                    _ => -EFAULT,
+                }
+            }
+            113 => {
+                // clock_gettime
+                let now = match args[0] {
+                    // CLOCK_REALTIME and CLOCK_REALTIME_COARSE
+                    0 | 5 => SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default(),
+                    _ => self.start.elapsed(),
+                };
+                write_timespec(cpu, args[1], now.as_secs(), now.subsec_nanos() as u64)
+            }
+            160 => {
+                // uname
+                let mut utsname = vec![0u8; 65 * 6];
+                for (i, field) in ["Linux", "rvemu", "6.1.0", "#1", "riscv64", ""].iter().enumerate() {
+                    utsname[i * 65..i * 65 + field.len()].copy_from_slice(field.as_bytes());
+                }
+                match cpu.bus.lock().unwrap().write_bytes(args[0], &utsname) {
+                    Ok(_) => 0,
+                    Err(_) => -EFAULT,
+                }
+            }
+            169 => {
+                // gettimeofday
+                let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+                if args[0] == 0 {
+                    return 0;
+                }
+                write_timespec(cpu, args[0], now.as_secs(), now.subsec_micros() as u64)
+            }
+            214 => self.brk(cpu, args[0]),
+            215 => 0, // munmap: regions are never reused
+            222 => self.mmap(cpu, args),
+            261 => {
+                // prlimit64: report the stack size for every resource
+                if args[3] != 0 {
+                    let mut bus = cpu.bus.lock().unwrap();
+                    if bus.store(args[3], 64, STACK_SIZE).and(bus.store(args[3] + 8, 64, u64::MAX)).is_err() {
+                        return -EFAULT;
+                    }
+                }
+                0
+            }
+            278 => {
+                // getrandom
+                if !cpu.bus.lock().unwrap().is_memory_range(args[0], args[1]) {
+                    return -EFAULT;
+                }
+                let bytes: Vec<u8> = (0..args[1]).map(|_| self.next_random() as u8).collect();
+                match cpu.bus.lock().unwrap().write_bytes(args[0], &bytes) {
+                    Ok(_) => args[1] as i64,
+                    Err(_) => -EFAULT,
+                }
+            }
+            _ => -ENOSYS,
+        }
+    }
+
+    fn next_random(&mut self) -> u64 {
+        // xorshift64
+        self.random ^= self.random << 13;
+        self.random ^= self.random >> 7;
+        self.random ^= self.random << 17;
+        self.random
+    }
+
+    fn read_cstring(&self, cpu: &mut Cpu, addr: u64) -> Result<String, i64> {
+        let mut bytes = Vec::new();
+        loop {
+            match cpu.load(addr + bytes.len() as u64, 8) {
+                Ok(0) => break,
+                Ok(byte) => bytes.push(byte as u8),
+                Err(_) => return Err(-EFAULT),
+            }
+        }
+        Ok(String::from_utf8_lossy(&bytes).into_owned())
+    }
+
+    /// Resolves a path argument relative to a directory fd. Only `AT_FDCWD`
+    /// and absolute paths are supported.
+    fn path_at(&self, cpu: &mut Cpu, dirfd: u64, path: u64) -> Result<PathBuf, i64> {
+        let path = PathBuf::from(self.read_cstring(cpu, path)?);
+        if path.is_absolute() || dirfd as i64 == AT_FDCWD {
+            Ok(path)
+        } else {
+            Err(-EBADF)
+        }
+    }
+
+    fn insert_fd(&mut self, fd: Fd) -> i64 {
+        let mut n = 0;
+        while self.fds.contains_key(&n) {
+            n += 1;
+        }
+        self.fds.insert(n, fd);
+        n as i64
+    }
+
+    fn getcwd(&self, cpu: &mut Cpu, buf: u64, size: u64) -> i64 {
+        let cwd = match env::current_dir() {
+            Ok(cwd) => cwd.to_string_lossy().into_owned(),
+            Err(e) => return errno(e),
+        };
+        if cwd.len() as u64 + 1 > size {
+            return -ERANGE;
+        }
+        let mut bus = cpu.bus.lock().unwrap();
+        match bus.write_bytes(buf, cwd.as_bytes()).and(bus.store(buf + cwd.len() as u64, 8, 0)) {
+            Ok(_) => cwd.len() as i64 + 1,
+            Err(_) => -EFAULT,
+        }
+    }
+
+    fn dup(&mut self, fd: u64) -> i64 {
+        let new = match self.fds.get(&fd) {
+            Some(Fd::Stdin) => Fd::Stdin,
+            Some(Fd::Stdout) => Fd::Stdout,
+            Some(Fd::Stderr) => Fd::Stderr,
+            Some(Fd::File(file)) => match file.try_clone() {
+                Ok(file) => Fd::File(file),
+                Err(e) => return errno(e),
+            },
+            None => return -EBADF,
+        };
+        self.insert_fd(new)
+    }
+
+    fn ioctl(&self, cpu: &mut Cpu, fd: u64, request: u64, arg: u64) -> i64 {
+        const TCGETS: u64 = 0x5401;
+        let is_tty = match self.fds.get(&fd) {
+            Some(Fd::Stdin) => io::stdin().is_terminal(),
+            Some(Fd::Stdout) => io::stdout().is_terminal(),
+            Some(Fd::Stderr) => io::stderr().is_terminal(),
+            Some(Fd::File(_)) => false,
+            None => return -EBADF,
+        };
+        if request != TCGETS || !is_tty {
+            return -ENOTTY;
+        }
+        // A zeroed termios is enough for libc to treat the fd as a terminal.
+        match cpu.bus.lock().unwrap().write_bytes(arg, &[0; 60]) {
+            Ok(_) => 0,
+            Err(_) => -EFAULT,
+        }
+    }
+
+    fn openat(&mut self, cpu: &mut Cpu, dirfd: u64, path: u64, flags: u64, mode: u64) -> i64 {
+        let path = match self.path_at(cpu, dirfd, path) {
+            Ok(path) => path,
+            Err(e) => return e,
+        };
+
+        let mut options = OpenOptions::new();
+        match flags & 0o3 {
+            0 => options.read(true),
+            1 => options.write(true),
+            _ => options.read(true).write(true),
+        };
+        options
+            .append(flags & 0o2000 != 0)
+            .truncate(flags & 0o1000 != 0)
+            .mode(mode as u32);
+        match (flags & 0o100 != 0, flags & 0o200 != 0) {
+            (true, true) => options.create_new(true),
+            (true, false) => options.create(true),
+            _ => &mut options,
+        };
+
+        match options.open(path) {
+            Ok(file) => self.insert_fd(Fd::File(file)),
+            Err(e) => errno(e),
+        }
+    }
+
+    fn lseek(&mut self, fd: u64, offset: i64, whence: u64) -> i64 {
+        let pos = match whence {
+            0 => SeekFrom::Start(offset as u64),
+            1 => SeekFrom::Current(offset),
+            2 => SeekFrom::End(offset),
+            _ => return -EINVAL,
+        };
+        match self.fds.get_mut(&fd) {
+            Some(Fd::File(file)) => result(file.seek(pos).map(|n| n as i64)),
+            Some(_) => -ESPIPE,
+            None => -EBADF,
+        }
+    }
+
+    fn read(&mut self, cpu: &mut Cpu, fd: u64, buf: u64, count: u64, offset: Option<u64>) -> i64 {
+        if !cpu.bus.lock().unwrap().is_memory_range(buf, count) {
+            return -EFAULT;
+        }
+        let mut data = vec![0; count as usize];
+        let n = match (self.fds.get_mut(&fd), offset) {
+            (Some(Fd::Stdin), None) => {
+                let input = self.stdin.read(|| {
+                    let n = io::stdin().read(&mut data).unwrap_or(0);
+                    data[..n].to_vec()
+                });
+                let n = input.len().min(data.len());
+                data[..n].copy_from_slice(&input[..n]);
+                Ok(n)
+            }
+            (Some(Fd::File(file)), None) => file.read(&mut data),
+            (Some(Fd::File(file)), Some(offset)) => file.read_at(&mut data, offset),
+            (Some(_), _) => return -EBADF,
+            (None, _) => return -EBADF,
+        };
+        match n {
+            Ok(n) => match cpu.bus.lock().unwrap().write_bytes(buf, &data[..n]) {
+                Ok(_) => n as i64,
+                Err(_) => -EFAULT,
+            },
+            Err(e) => errno(e),
+        }
+    }
+
+    fn write(&mut self, cpu: &mut Cpu, fd: u64, buf: u64, count: u64, offset: Option<u64>) -> i64 {
+        let mut bus = cpu.bus.lock().unwrap();
+        if !bus.is_memory_range(buf, count) {
+            return -EFAULT;
+        }
+        let data = match bus.read_bytes(buf, count) {
+            Ok(data) => data,
+            Err(_) => return -EFAULT,
+        };
+        drop(bus);
+        let n = match (self.fds.get_mut(&fd), offset) {
+            (Some(Fd::Stdout | Fd::Stderr), None) if self.muted => Ok(data.len()),
+            (Some(Fd::Stdout), None) => {
+                let mut stdout = io::stdout();
+                stdout.write_all(&data).and(stdout.flush()).map(|_| data.len())
+            }
+            (Some(Fd::Stderr), None) => io::stderr().write_all(&data).map(|_| data.len()),
+            (Some(Fd::File(file)), None) => file.write(&data),
+            (Some(Fd::File(file)), Some(offset)) => file.write_at(&data, offset),
+            (Some(_), _) => return -EBADF,
+            (None, _) => return -EBADF,
+        };
+        result(n.map(|n| n as i64))
+    }
+
+    fn fstat(&self, cpu: &mut Cpu, fd: u64, statbuf: u64) -> i64 {
+        let meta = match self.fds.get(&fd) {
+            Some(Fd::File(file)) => file.metadata(),
+            Some(Fd::Stdin) => fs::metadata("/dev/stdin"),
+            Some(Fd::Stdout) => fs::metadata("/dev/stdout"),
+            Some(Fd::Stderr) => fs::metadata("/dev/stderr"),
+            None => return -EBADF,
+        };
+        match meta {
+            Ok(meta) => write_stat(cpu, statbuf, &meta),
+            Err(e) => errno(e),
+        }
+    }
+
+    fn brk(&mut self, cpu: &mut Cpu, addr: u64) -> i64 {
+        if addr >= self.brk_start && addr < self.mmap_top {
+            if addr > self.brk {
+                // Memory given back and requested again must read as zero.
+                let zeros = vec![0; (addr - self.brk) as usize];
+                if cpu.bus.lock().unwrap().write_bytes(self.brk, &zeros).is_err() {
+                    return self.brk as i64;
+                }
+            }
+            self.brk = addr;
+        }
+        self.brk as i64
+    }
+
+    fn mmap(&mut self, cpu: &mut Cpu, args: &[u64; 6]) -> i64 {
+        let (addr, len, flags, fd, offset) = (args[0], args[1], args[3], args[4], args[5]);
+        if len == 0 {
+            return -EINVAL;
+        }
+        // The mapping has to fit in guest memory before a host buffer for
+        // it is allocated.
+        let Some(len) = len.checked_next_multiple_of(PAGE_SIZE) else {
+            return -ENOMEM;
+        };
+        let start = if flags & MAP_FIXED != 0 {
+            if !cpu.bus.lock().unwrap().is_memory_range(addr, len) {
+                return -ENOMEM;
+            }
+            addr
+        } else {
+            match self.mmap_top.checked_sub(len) {
+                Some(start) if start >= self.brk => {
+                    self.mmap_top = start;
+                    start
+                }
+                _ => return -ENOMEM,
+            }
+        };
+
+        let mut data = vec![0; len as usize];
+        if flags & MAP_ANONYMOUS == 0 {
+            match self.fds.get(&fd) {
+                Some(Fd::File(file)) => {
+                    // Short reads past the end of the file leave zeros.
+                    let mut filled = 0;
+                    while filled < data.len() {
+                        match file.read_at(&mut data[filled..], offset + filled as u64) {
+                            Ok(0) => break,
+                            Ok(n) => filled += n,
+                            Err(e) => return errno(e),
+                        }
+                    }
+                }
+                _ => return -EBADF,
+            }
+        }
+
+        match cpu.bus.lock().unwrap().write_bytes(start, &data) {
+            Ok(_) => start as i64,
+            Err(_) => -ENOMEM,
+        }
+    }
+}
+
+/// The AT_HWCAP bit of a single-letter extension.
+fn isa_bit(extension: u8) -> u64 {
+    1 << (extension - b'a')
+}
+
+fn round_up(addr: u64) -> u64 {
+    (addr + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
+}
+
+fn errno(e: io::Error) -> i64 {
+    e.raw_os_error().map_or(-EIO, |n| -(n as i64))
+}
+
+fn result(r: io::Result<i64>) -> i64 {
+    r.unwrap_or_else(errno)
+}
+
+fn write_timespec(cpu: &mut Cpu, addr: u64, sec: u64, frac: u64) -> i64 {
+    let mut bus = cpu.bus.lock().unwrap();
+    match bus.store(addr, 64, sec).and(bus.store(addr + 8, 64, frac)) {
+        Ok(_) => 0,
+        Err(_) => -EFAULT,
+    }
+}
+
+/// Writes a `struct stat` in the riscv64 layout.
+fn write_stat(cpu: &mut Cpu, addr: u64, meta: &fs::Metadata) -> i64 {
+    let mut stat = Vec::with_capacity(128);
+    stat.extend(meta.dev().to_le_bytes());
+    stat.extend(meta.ino().to_le_bytes());
+    stat.extend(meta.mode().to_le_bytes());
+    stat.extend((meta.nlink() as u32).to_le_bytes());
+    stat.extend(meta.uid().to_le_bytes());
+    stat.extend(meta.gid().to_le_bytes());
+    stat.extend(meta.rdev().to_le_bytes());
+    stat.extend(0u64.to_le_bytes());
+    stat.extend(meta.size().to_le_bytes());
+    stat.extend((meta.blksize() as u32).to_le_bytes());
+    stat.extend(0u32.to_le_bytes());
+    stat.extend(meta.blocks().to_le_bytes());
+    for (sec, nsec) in [
+        (meta.atime(), meta.atime_nsec()),
+        (meta.mtime(), meta.mtime_nsec()),
+        (meta.ctime(), meta.ctime_nsec()),
+    ] {
+        stat.extend(sec.to_le_bytes());
+        stat.extend(nsec.to_le_bytes());
+    }
+    stat.extend([0u8; 8]);
+
+    match cpu.bus.lock().unwrap().write_bytes(addr, &stat) {
+        Ok(_) => 0,
+        Err(_) => -EFAULT,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::*;
+    use crate::machine::*;
+    use crate::testing::*;
+
+    #[test]
+    fn huge_lengths_fail_instead_of_allocating() {
+        let mut machine = machine(&MachineConfig::default(), &[]);
+        let cpu = &mut machine.harts[0];
+        let mut linux = Linux::new(false, HostInput::new("stdin", None));
+        let buf = cpu.pc;
+        let huge = 1 << 62;
+        assert_eq!(linux.dispatch(cpu, 63, &[0, buf, huge, 0, 0, 0]), -EFAULT);
+        assert_eq!(linux.dispatch(cpu, 64, &[1, buf, huge, 0, 0, 0]), -EFAULT);
+        assert_eq!(linux.dispatch(cpu, 278, &[buf, huge, 0, 0, 0, 0]), -EFAULT);
+        assert_eq!(linux.dispatch(cpu, 222, &[0, u64::MAX, 3, MAP_ANONYMOUS, u64::MAX, 0]), -ENOMEM);
+        assert_eq!(linux.dispatch(cpu, 222, &[buf, huge, 3, MAP_ANONYMOUS | MAP_FIXED, u64::MAX, 0]), -ENOMEM);
+    }
+
+    #[test]
+    fn buffers_in_memory_are_used() {
+        let mut machine = machine(&MachineConfig::default(), &[]);
+        let cpu = &mut machine.harts[0];
+        let mut linux = Linux::new(false, HostInput::new("stdin", None));
+        let buf = cpu.pc;
+        assert_eq!(linux.dispatch(cpu, 278, &[buf, 16, 0, 0, 0, 0]), 16);
+        let fixed = buf + 0x10_0000;
+        let flags = MAP_ANONYMOUS | MAP_FIXED;
+        assert_eq!(linux.dispatch(cpu, 222, &[fixed, 0x2000, 3, flags, u64::MAX, 0]), fixed as i64);
+    }
+
+    /// A machine with `program` loaded as a user-mode program run with `args`.
+    fn load(program: &[u32], args: &[&str]) -> (Machine, Linux) {
+        let config = MachineConfig { user: true, ..Default::default() };
+        let mut machine = machine(&config, program);
+        let (base, size) = (config.dram_base, program.len() as u64 * 4);
+        let segment = Segment { vaddr: base, paddr: base, offset: 0, filesz: size, memsz: size };
+        let elf =
+            Elf { xlen: 64, entry: base, phoff: 0, phentsize: 0, phnum: 0, segments: vec![segment], symbols: vec![] };
+        let code: Vec<u8> = program.iter().flat_map(|word| word.to_le_bytes()).collect();
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        let mut linux = Linux::new(false, HostInput::new("stdin", None));
+        linux.load(&mut machine.harts[0], &elf, &code, &args).unwrap();
+        (machine, linux)
+    }
+
+    #[test]
+    fn programs_read_time_without_a_clint() {
+        // Reads time, counts down from 100, reads it again and exits.
+        let program = [
+            csrrs(T0, TIME as u16, ZERO),
+            addi(T2, ZERO, 100),
+            addi(T2, T2, -1),
+            bne(T2, ZERO, -4),
+            csrrs(T1, TIME as u16, ZERO),
+            // exit(3)
+            addi(A0, ZERO, 3),
+            addi(17, ZERO, 93),
+            ECALL,
+        ];
+        let (mut machine, mut linux) = load(&program, &["time"]);
+        let hart = &mut machine.harts[0];
+        assert_eq!(linux.run(hart, 10), Ok(3));
+        assert_eq!(hart.mode, USER);
+        assert!(hart.regs[T1 as usize] >= hart.regs[T0 as usize] + 200);
+    }
+
+    #[test]
+    fn the_stack_holds_arguments_environment_and_auxv() {
+        let (mut machine, _) = load(&[SPIN], &["prog", "-v"]);
+        let cpu = &mut machine.harts[0];
+        let sp = cpu.regs[2];
+        assert_eq!(sp % 16, 0);
+        let word = |cpu: &mut Cpu, i: u64| cpu.load(sp + i * 8, 64).unwrap();
+        let string = |cpu: &mut Cpu, addr: u64| {
+            let bytes = (addr..).map(|addr| cpu.load(addr, 8).unwrap() as u8).take_while(|&b| b != 0);
+            String::from_utf8(bytes.collect()).unwrap()
+        };
+
+        assert_eq!(word(cpu, 0), 2);
+        let (arg0, arg1) = (word(cpu, 1), word(cpu, 2));
+        assert_eq!((string(cpu, arg0), string(cpu, arg1)), (String::from("prog"), String::from("-v")));
+        assert_eq!(word(cpu, 3), 0);
+        let mut i = 4;
+        let mut envs = 0;
+        while word(cpu, i) != 0 {
+            let env = word(cpu, i);
+            assert!(string(cpu, env).contains('='));
+            envs += 1;
+            i += 1;
+        }
+        assert_eq!(envs, env::vars().count());
+
+        let mut auxv = BTreeMap::new();
+        loop {
+            let (key, value) = (word(cpu, i + 1), word(cpu, i + 2));
+            i += 2;
+            if key == AT_NULL {
+                break;
+            }
+            auxv.insert(key, value);
+        }
+        assert_eq!(auxv[&AT_PAGESZ], PAGE_SIZE);
+        assert_eq!(auxv[&AT_ENTRY], DRAM_BASE);
+        assert_eq!(auxv[&AT_PHDR], DRAM_BASE);
+        assert_eq!(auxv[&AT_EXECFN], arg0);
+        // The random bytes sit between the pointers and the strings.
+        assert!(auxv[&AT_RANDOM] > sp + i * 8 && auxv[&AT_RANDOM] + 16 <= arg0);
+        assert_eq!(cpu.pc, DRAM_BASE);
+    }
+
+    #[test]
+    fn brk_grows_and_shrinks_the_heap() {
+        let (mut machine, mut linux) = load(&[SPIN], &["brk"]);
+        let cpu = &mut machine.harts[0];
+        let start = linux.dispatch(cpu, 214, &[0; 6]) as u64;
+        assert_eq!(start % PAGE_SIZE, 0);
+        assert!(start > DRAM_BASE);
+
+        assert_eq!(linux.dispatch(cpu, 214, &[start + 0x2000, 0, 0, 0, 0, 0]), (start + 0x2000) as i64);
+        cpu.store(start + 0x1000, 64, 0x55).unwrap();
+        assert_eq!(linux.dispatch(cpu, 214, &[start, 0, 0, 0, 0, 0]), start as i64);
+        // Memory given back reads as zero when it is asked for again.
+        assert_eq!(linux.dispatch(cpu, 214, &[start + 0x2000, 0, 0, 0, 0, 0]), (start + 0x2000) as i64);
+        assert_eq!(cpu.load(start + 0x1000, 64), Ok(0));
+        // Below the start or into the stack, the break stays where it is.
+        assert_eq!(linux.dispatch(cpu, 214, &[start - 8, 0, 0, 0, 0, 0]), (start + 0x2000) as i64);
+        assert_eq!(linux.dispatch(cpu, 214, &[cpu.regs[2], 0, 0, 0, 0, 0]), (start + 0x2000) as i64);
+    }
+
+    #[test]
+    fn nanosleep_checks_its_request() {
+        let (mut machine, mut linux) = load(&[SPIN], &["sleep"]);
+        let cpu = &mut machine.harts[0];
+        let req = cpu.regs[2] - 0x100;
+        let mut sleep = |cpu: &mut Cpu, sec: u64, nsec: u64| {
+            cpu.store(req, 64, sec).unwrap();
+            cpu.store(req + 8, 64, nsec).unwrap();
+            let nanosleep = linux.dispatch(cpu, 101, &[req, 0, 0, 0, 0, 0]);
+            let clock_nanosleep = linux.dispatch(cpu, 115, &[1, 0, req, 0, 0, 0]);
+            (nanosleep, clock_nanosleep)
+        };
+        assert_eq!(sleep(cpu, 0, 1000), (0, 0));
+        assert_eq!(sleep(cpu, 0, 1_000_000_000), (-EINVAL, -EINVAL));
+        assert_eq!(sleep(cpu, u64::MAX, 0), (-EINVAL, -EINVAL));
+        assert_eq!(sleep(cpu, 0, u64::MAX), (-EINVAL, -EINVAL));
+        assert_eq!(linux.dispatch(cpu, 101, &[u64::MAX - 4, 0, 0, 0, 0, 0]), -EFAULT);
+    }
+
+    #[test]
+    fn exit_group_stops_the_program() {
+        let program = [addi(A0, ZERO, -1), addi(17, ZERO, 94), ECALL, SPIN];
+        let (mut machine, mut linux) = load(&program, &["exit"]);
+        let hart = &mut machine.harts[0];
+        assert_eq!(linux.run(hart, 1000), Ok(-1));
+        assert_eq!(hart.pc, DRAM_BASE + 8);
+    }
+}
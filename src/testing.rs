@@ -0,0 +1,267 @@
+//! Helpers for the unit tests: machines running programs given as
+//! instruction words, encoders for the instructions they use, and a virtio
+//! driver for the devices.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use crate::bus::DRAM_BASE;
+use crate::config::*;
+use crate::device::*;
+use crate::dram::*;
+use crate::machine::*;
+use crate::virtio::*;
+
+// Registers by ABI name
+pub const ZERO: u32 = 0;
+pub const T0: u32 = 5;
+pub const T1: u32 = 6;
+pub const T2: u32 = 7;
+pub const A0: u32 = 10;
+
+/// A machine with `program` at the start of DRAM.
+pub fn machine(config: &MachineConfig, program: &[u32]) -> Machine {
+    let code = program.iter().flat_map(|word| word.to_le_bytes()).collect();
+    Machine::new(config, code).unwrap()
+}
+
+/// The configuration of the `virt` profile, with its devices.
+pub fn virt() -> MachineConfig {
+    MachineConfig { profile: Profile::Virt, ..Default::default() }
+}
+
+pub fn r_type(opcode: u32, rd: u32, funct3: u32, rs1: u32, rs2: u32, funct7: u32) -> u32 {
+    (funct7 << 25) | (rs2 << 20) | (rs1 << 15) | (funct3 << 12) | (rd << 7) | opcode
+}
+
+pub fn i_type(opcode: u32, rd: u32, funct3: u32, rs1: u32, imm: i32) -> u32 {
+    ((imm as u32 & 0xfff) << 20) | (rs1 << 15) | (funct3 << 12) | (rd << 7) | opcode
+}
+
+pub fn s_type(opcode: u32, funct3: u32, rs1: u32, rs2: u32, imm: i32) -> u32 {
+    let imm = imm as u32;
+    ((imm >> 5 & 0x7f) << 25) | (rs2 << 20) | (rs1 << 15) | (funct3 << 12) | ((imm & 0x1f) << 7) | opcode
+}
+
+pub fn b_type(funct3: u32, rs1: u32, rs2: u32, offset: i32) -> u32 {
+    let imm = offset as u32;
+    ((imm >> 12 & 1) << 31)
+        | ((imm >> 5 & 0x3f) << 25)
+        | (rs2 << 20)
+        | (rs1 << 15)
+        | (funct3 << 12)
+        | ((imm >> 1 & 0xf) << 8)
+        | ((imm >> 11 & 1) << 7)
+        | 0x63
+}
+
+pub fn addi(rd: u32, rs1: u32, imm: i32) -> u32 {
+    i_type(0x13, rd, 0, rs1, imm)
+}
+
+pub fn andi(rd: u32, rs1: u32, imm: i32) -> u32 {
+    i_type(0x13, rd, 7, rs1, imm)
+}
+
+/// `lui` with the value the register ends up holding, whose low 12 bits
+/// must be clear.
+pub fn lui(rd: u32, value: i32) -> u32 {
+    (value as u32 & 0xffff_f000) | (rd << 7) | 0x37
+}
+
+/// `lui` and `addi` loading a 32-bit value, sign-extended.
+pub fn li(rd: u32, value: i32) -> [u32; 2] {
+    let low = (value << 20) >> 20;
+    [lui(rd, value.wrapping_sub(low)), addi(rd, rd, low)]
+}
+
+pub fn sw(rs2: u32, rs1: u32, offset: i32) -> u32 {
+    s_type(0x23, 2, rs1, rs2, offset)
+}
+
+pub fn sd(rs2: u32, rs1: u32, offset: i32) -> u32 {
+    s_type(0x23, 3, rs1, rs2, offset)
+}
+
+pub fn beq(rs1: u32, rs2: u32, offset: i32) -> u32 {
+    b_type(0, rs1, rs2, offset)
+}
+
+pub fn bne(rs1: u32, rs2: u32, offset: i32) -> u32 {
+    b_type(1, rs1, rs2, offset)
+}
+
+pub fn jalr(rd: u32, rs1: u32, offset: i32) -> u32 {
+    i_type(0x67, rd, 0, rs1, offset)
+}
+
+/// `jal x0, 0`: spins in place.
+pub const SPIN: u32 = 0x6f;
+
+pub const ECALL: u32 = 0x73;
+
+pub fn csrrs(rd: u32, csr: u16, rs1: u32) -> u32 {
+    i_type(0x73, rd, 2, rs1, csr as i32)
+}
+
+/// A path in the host's temporary directory, unique to this test run.
+pub fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("rvemu-test-{}-{}", std::process::id(), name))
+}
+
+// virtio-mmio registers the driver uses, and the status bits it sets
+const DEVICE_FEATURES: u64 = 0x010;
+const DEVICE_FEATURES_SEL: u64 = 0x014;
+const DRIVER_FEATURES: u64 = 0x020;
+const DRIVER_FEATURES_SEL: u64 = 0x024;
+const QUEUE_SEL: u64 = 0x030;
+const QUEUE_NUM_MAX: u64 = 0x034;
+const QUEUE_NUM: u64 = 0x038;
+const QUEUE_READY: u64 = 0x044;
+const QUEUE_NOTIFY: u64 = 0x050;
+const STATUS: u64 = 0x070;
+const QUEUE_DESC_LOW: u64 = 0x080;
+const QUEUE_DRIVER_LOW: u64 = 0x090;
+const QUEUE_DEVICE_LOW: u64 = 0x0a0;
+const ACKNOWLEDGE: u32 = 1;
+const DRIVER: u32 = 2;
+const DRIVER_OK: u32 = 4;
+const FEATURES_OK: u32 = 8;
+
+const QUEUE_SIZE: u64 = 16;
+/// Queue `i` takes the page at `DRAM_BASE + i * 0x1000`, and buffers come
+/// after the queues.
+const QUEUE_AREA: u64 = 0x1000;
+const BUFFERS: u64 = 0x4_0000;
+
+/// The driver side of a virtio device on a transport, with its own memory.
+/// It accepts every feature the device offers and sets up every queue.
+pub struct Driver {
+    pub transport: VirtioMmio,
+    pub dram: Dram,
+    /// The next free byte for buffers
+    next: u64,
+    /// For every queue, the next descriptor and available entry, the next
+    /// used entry to look at, and the writable buffers of its chains
+    queues: Vec<DriverQueue>,
+}
+
+#[derive(Default)]
+struct DriverQueue {
+    next_desc: u64,
+    avail_idx: u16,
+    used_idx: u16,
+    writable: HashMap<u16, Vec<(u64, u32)>>,
+}
+
+impl Driver {
+    pub fn new(device: Box<dyn VirtioDevice>) -> Self {
+        let mut driver = Self {
+            transport: VirtioMmio::new(0, device),
+            dram: Dram::new(DRAM_BASE, 0x10_0000, Vec::new()),
+            next: DRAM_BASE + BUFFERS,
+            queues: Vec::new(),
+        };
+        for sel in 0..2 {
+            driver.write(DEVICE_FEATURES_SEL, sel);
+            let features = driver.read(DEVICE_FEATURES);
+            driver.write(DRIVER_FEATURES_SEL, sel);
+            driver.write(DRIVER_FEATURES, features);
+        }
+        driver.write(STATUS, ACKNOWLEDGE | DRIVER | FEATURES_OK);
+        let mut index = 0;
+        loop {
+            driver.write(QUEUE_SEL, index);
+            if driver.read(QUEUE_NUM_MAX) == 0 {
+                break;
+            }
+            let area = DRAM_BASE + index as u64 * QUEUE_AREA;
+            driver.write(QUEUE_NUM, QUEUE_SIZE as u32);
+            driver.write(QUEUE_DESC_LOW, area as u32);
+            driver.write(QUEUE_DRIVER_LOW, (area + 0x400) as u32);
+            driver.write(QUEUE_DEVICE_LOW, (area + 0x800) as u32);
+            driver.write(QUEUE_READY, 1);
+            driver.queues.push(DriverQueue::default());
+            index += 1;
+        }
+        driver.write(STATUS, ACKNOWLEDGE | DRIVER | FEATURES_OK | DRIVER_OK);
+        driver
+    }
+
+    pub fn read(&mut self, offset: u64) -> u32 {
+        self.transport.load(offset, 32).unwrap() as u32
+    }
+
+    pub fn write(&mut self, offset: u64, value: u32) {
+        self.transport.store(offset, 32, value as u64).unwrap();
+    }
+
+    fn allocate(&mut self, len: u32) -> u64 {
+        let addr = self.next;
+        self.next += (len as u64).next_multiple_of(8);
+        addr
+    }
+
+    /// Makes a chain available on `queue`: buffers holding `readable`, then
+    /// empty ones of the `writable` sizes.
+    pub fn submit(&mut self, queue: usize, readable: &[&[u8]], writable: &[u32]) {
+        let mut buffers = Vec::new();
+        for data in readable {
+            let addr = self.allocate(data.len() as u32);
+            self.dram.slice_mut(addr, data.len() as u64).unwrap().copy_from_slice(data);
+            buffers.push((addr, data.len() as u32, 0));
+        }
+        for &len in writable {
+            buffers.push((self.allocate(len), len, 2));
+        }
+
+        let area = DRAM_BASE + queue as u64 * QUEUE_AREA;
+        let q = &mut self.queues[queue];
+        let head = q.next_desc as u16;
+        for (i, &(addr, len, flags)) in buffers.iter().enumerate() {
+            let index = q.next_desc;
+            q.next_desc = (q.next_desc + 1) % QUEUE_SIZE;
+            let last = i + 1 == buffers.len();
+            let flags: u16 = flags | if last { 0 } else { 1 };
+            let desc = [
+                &addr.to_le_bytes()[..],
+                &len.to_le_bytes(),
+                &flags.to_le_bytes(),
+                &(q.next_desc as u16).to_le_bytes(),
+            ]
+            .concat();
+            self.dram.slice_mut(area + 16 * index, 16).unwrap().copy_from_slice(&desc);
+        }
+        let writable = buffers.iter().filter(|b| b.2 != 0).map(|&(addr, len, _)| (addr, len)).collect();
+        q.writable.insert(head, writable);
+        let slot = q.avail_idx as u64 % QUEUE_SIZE;
+        self.dram.store(area + 0x404 + 2 * slot, 16, head as u64).unwrap();
+        q.avail_idx = q.avail_idx.wrapping_add(1);
+        self.dram.store(area + 0x402, 16, q.avail_idx as u64).unwrap();
+    }
+
+    /// Notifies the device about `queue` and lets it work on the memory.
+    pub fn notify(&mut self, queue: usize) {
+        self.write(QUEUE_NOTIFY, queue as u32);
+        self.transport.dma(&mut self.dram);
+    }
+
+    /// Takes the next chain the device returned on `queue`: the length it
+    /// reported writing, and everything in its writable buffers.
+    pub fn used(&mut self, queue: usize) -> Option<(u32, Vec<u8>)> {
+        let used = DRAM_BASE + queue as u64 * QUEUE_AREA + 0x800;
+        let q = &mut self.queues[queue];
+        if self.dram.load(used + 2, 16).unwrap() as u16 == q.used_idx {
+            return None;
+        }
+        let slot = q.used_idx as u64 % QUEUE_SIZE;
+        q.used_idx = q.used_idx.wrapping_add(1);
+        let head = self.dram.load(used + 4 + 8 * slot, 32).unwrap() as u16;
+        let len = self.dram.load(used + 8 + 8 * slot, 32).unwrap() as u32;
+        let mut data = Vec::new();
+        for (addr, size) in q.writable.remove(&head).unwrap() {
+            data.extend_from_slice(self.dram.slice(addr, size as u64).unwrap());
+        }
+        Some((len, data))
+    }
+}
@@ -0,0 +1,333 @@
+use std::collections::VecDeque;
+use std::io::{self, IsTerminal, Read, Write};
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+
+use crate::device::*;
+use crate::exception::*;
+use crate::fdt::*;
+use crate::replay::*;
+use crate::snapshot::*;
+
+pub const UART_BASE: u64 = 0x1000_0000;
+pub const UART_SIZE: u64 = 0x100;
+pub const UART_IRQ: u32 = 10;
+/// Input clock reported to the guest; only used to compute divisors.
+const UART_CLOCK: u32 = 3_686_400;
+
+// Register offsets
+pub const RBR: u64 = 0; // receive buffer (read), transmit holding (write)
+const IER: u64 = 1;
+const IIR: u64 = 2; // interrupt identification (read), FIFO control (write)
+const LCR: u64 = 3;
+const MCR: u64 = 4;
+pub const LSR: u64 = 5;
+const MSR: u64 = 6;
+const SCR: u64 = 7;
+
+const IER_RDI: u8 = 1 << 0;
+const IER_THRI: u8 = 1 << 1;
+
+const IIR_NO_INT: u8 = 0x01;
+const IIR_THRI: u8 = 0x02;
+const IIR_RDI: u8 = 0x04;
+const IIR_FIFO_ENABLED: u8 = 0xc0;
+
+const LCR_DLAB: u8 = 1 << 7;
+
+pub const LSR_DR: u8 = 1 << 0;
+const LSR_THRE: u8 = 1 << 5;
+const LSR_TEMT: u8 = 1 << 6;
+
+/// A 16550-compatible UART connected to the host's stdin and stdout.
+///
+/// Transmission is instantaneous, so the transmitter is always empty.
+pub struct Uart {
+    input: Receiver<u8>,
+    host: HostInput,
+    rx: VecDeque<u8>,
+    ier: u8,
+    lcr: u8,
+    mcr: u8,
+    scr: u8,
+    fifo_enabled: bool,
+    divisor: u16,
+    /// The "transmitter empty" interrupt, cleared by reading IIR.
+    thre_pending: bool,
+    /// Output is dropped while set.
+    muted: bool,
+}
+
+impl Uart {
+    /// Creates the UART and, if `stdin` is set, starts a thread that
+    /// forwards stdin to it. Otherwise the UART never receives anything.
+    pub fn new(stdin: bool, log: Option<&SharedLog>) -> Self {
+        let (sender, input) = mpsc::channel();
+        if stdin {
+            thread::spawn(move || {
+                let mut stdin = io::stdin();
+                let mut byte = [0];
+                while let Ok(1) = stdin.read(&mut byte) {
+                    if sender.send(byte[0]).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+
+        Self {
+            input,
+            host: HostInput::new("uart", log),
+            rx: VecDeque::new(),
+            ier: 0,
+            lcr: 0,
+            mcr: 0,
+            scr: 0,
+            fifo_enabled: false,
+            divisor: 0,
+            thre_pending: false,
+            muted: false,
+        }
+    }
+
+    fn poll_input(&mut self) {
+        let input = &self.input;
+        for chunk in self.host.poll(|| vec![input.try_iter().collect()]) {
+            self.rx.extend(chunk);
+        }
+    }
+
+    fn iir(&self) -> u8 {
+        let id = if self.ier & IER_RDI != 0 && !self.rx.is_empty() {
+            IIR_RDI
+        } else if self.ier & IER_THRI != 0 && self.thre_pending {
+            IIR_THRI
+        } else {
+            IIR_NO_INT
+        };
+        if self.fifo_enabled {
+            id | IIR_FIFO_ENABLED
+        } else {
+            id
+        }
+    }
+}
+
+impl Device for Uart {
+    fn base(&self) -> u64 {
+        UART_BASE
+    }
+
+    fn size(&self) -> u64 {
+        UART_SIZE
+    }
+
+    fn load(&mut self, offset: u64, _size: u64) -> Result<u64, Exception> {
+        self.poll_input();
+        let dlab = self.lcr & LCR_DLAB != 0;
+        let value = match offset {
+            RBR if dlab => self.divisor as u8,
+            RBR => self.rx.pop_front().unwrap_or(0),
+            IER if dlab => (self.divisor >> 8) as u8,
+            IER => self.ier,
+            IIR => {
+                let iir = self.iir();
+                if iir & 0x0f == IIR_THRI {
+                    self.thre_pending = false;
+                }
+                iir
+            }
+            LCR => self.lcr,
+            MCR => self.mcr,
+            LSR => LSR_THRE | LSR_TEMT | if self.rx.is_empty() { 0 } else { LSR_DR },
+            // Carrier detect, data set ready and clear to send
+            MSR => 0xb0,
+            SCR => self.scr,
+            _ => 0,
+        };
+        Ok(value as u64)
+    }
+
+    fn store(&mut self, offset: u64, _size: u64, value: u64) -> Result<(), Exception> {
+        let value = value as u8;
+        let dlab = self.lcr & LCR_DLAB != 0;
+        match offset {
+            RBR if dlab => self.divisor = (self.divisor & 0xff00) | value as u16,
+            RBR => {
+                if !self.muted {
+                    let mut stdout = io::stdout();
+                    let _ = stdout.write_all(&[value]);
+                    let _ = stdout.flush();
+                }
+                self.thre_pending = true;
+            }
+            IER if dlab => self.divisor = (self.divisor & 0x00ff) | ((value as u16) << 8),
+            IER => {
+                // Enabling the interrupt with an empty transmitter raises it.
+                if value & IER_THRI != 0 && self.ier & IER_THRI == 0 {
+                    self.thre_pending = true;
+                }
+                self.ier = value & 0x0f;
+            }
+            IIR => {
+                self.fifo_enabled = value & 1 != 0;
+                // Clear the receive FIFO
+                if value & 0x02 != 0 {
+                    self.rx.clear();
+                }
+            }
+            LCR => self.lcr = value,
+            MCR => self.mcr = value & 0x1f,
+            SCR => self.scr = value,
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn irq(&self) -> Option<u32> {
+        Some(UART_IRQ)
+    }
+
+    fn device_tree(&self, fdt: &mut Fdt, _harts: usize) {
+        fdt.begin_node(&format!("serial@{:x}", UART_BASE));
+        fdt.property_string("compatible", "ns16550a");
+        fdt.property_reg(UART_BASE, UART_SIZE);
+        fdt.property_u32("clock-frequency", UART_CLOCK);
+        fdt.property_interrupt(UART_IRQ);
+        fdt.end_node();
+    }
+
+    fn mute(&mut self, muted: bool) {
+        self.muted = muted;
+    }
+
+    fn dump_state(&self) -> Option<String> {
+        Some(format!(
+            "uart: ier={:#04x} iir={:#04x} lcr={:#04x} mcr={:#04x} divisor={} rx={} bytes",
+            self.ier,
+            self.iir(),
+            self.lcr,
+            self.mcr,
+            self.divisor,
+            self.rx.len()
+        ))
+    }
+
+    fn stdout_path(&self) -> Option<String> {
+        Some(format!("/soc/serial@{:x}", UART_BASE))
+    }
+
+    fn interrupting(&mut self) -> bool {
+        self.poll_input();
+        self.iir() & IIR_NO_INT == 0
+    }
+
+    /// Input typed before the snapshot and not yet read by the guest is
+    /// kept.
+    fn save(&self, out: &mut StateWriter) {
+        out.bytes(&self.rx.iter().copied().collect::<Vec<u8>>());
+        out.u8(self.ier);
+        out.u8(self.lcr);
+        out.u8(self.mcr);
+        out.u8(self.scr);
+        out.bool(self.fifo_enabled);
+        out.u16(self.divisor);
+        out.bool(self.thre_pending);
+    }
+
+    fn restore(&mut self, input: &mut StateReader) -> io::Result<()> {
+        self.rx = input.bytes()?.iter().copied().collect();
+        self.ier = input.u8()?;
+        self.lcr = input.u8()?;
+        self.mcr = input.u8()?;
+        self.scr = input.u8()?;
+        self.fifo_enabled = input.bool()?;
+        self.divisor = input.u16()?;
+        self.thre_pending = input.bool()?;
+        Ok(())
+    }
+}
+
+/// Switches a terminal on stdin to non-canonical mode without echo, so that
+/// keys reach the guest as they are typed and are echoed only by it. Returns
+/// the previous settings for `restore_terminal`.
+///
+/// Signals are left enabled so that Ctrl-C still stops the emulator.
+pub fn raw_terminal() -> Option<String> {
+    if !io::stdin().is_terminal() {
+        return None;
+    }
+    let saved = Command::new("stty").arg("-g").stdin(Stdio::inherit()).output().ok()?;
+    let saved = String::from_utf8(saved.stdout).ok()?.trim().to_string();
+    Command::new("stty")
+        .args(["-icanon", "-echo", "min", "1"])
+        .stdin(Stdio::inherit())
+        .status()
+        .ok()?;
+    Some(saved)
+}
+
+pub fn restore_terminal(saved: &Option<String>) {
+    if let Some(settings) = saved {
+        let _ = Command::new("stty").arg(settings).stdin(Stdio::inherit()).status();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn uart() -> Uart {
+        let mut uart = Uart::new(false, None);
+        uart.mute(true);
+        uart
+    }
+
+    #[test]
+    fn divisor_latch_shadows_the_data_registers() {
+        let mut uart = uart();
+        uart.store(LCR, 8, LCR_DLAB as u64 | 0x03).unwrap();
+        uart.store(RBR, 8, 0x0c).unwrap();
+        uart.store(IER, 8, 0x01).unwrap();
+        assert_eq!(uart.divisor, 0x010c);
+        assert_eq!(uart.load(IER, 8), Ok(0x01));
+        uart.store(LCR, 8, 0x03).unwrap();
+        assert_eq!(uart.load(IER, 8), Ok(0));
+        assert_eq!(uart.load(LCR, 8), Ok(0x03));
+    }
+
+    #[test]
+    fn transmitter_interrupt_clears_on_reading_iir() {
+        let mut uart = uart();
+        assert!(!uart.interrupting());
+        uart.store(IER, 8, IER_THRI as u64).unwrap();
+        assert!(uart.interrupting());
+        assert_eq!(uart.load(IIR, 8), Ok(IIR_THRI as u64));
+        assert!(!uart.interrupting());
+        // Each byte sent empties the transmitter again.
+        uart.store(RBR, 8, b'x' as u64).unwrap();
+        assert!(uart.interrupting());
+        uart.store(IIR, 8, 1).unwrap();
+        assert_eq!(uart.load(IIR, 8), Ok((IIR_THRI | IIR_FIFO_ENABLED) as u64));
+    }
+
+    #[test]
+    fn received_bytes_are_read_in_order() {
+        let mut uart = uart();
+        uart.store(IER, 8, IER_RDI as u64).unwrap();
+        assert_eq!(uart.load(LSR, 8), Ok((LSR_THRE | LSR_TEMT) as u64));
+        uart.rx.extend(b"ok");
+        assert!(uart.interrupting());
+        assert_eq!(uart.load(LSR, 8), Ok((LSR_THRE | LSR_TEMT | LSR_DR) as u64));
+        assert_eq!(uart.load(IIR, 8), Ok(IIR_RDI as u64));
+        assert_eq!(uart.load(RBR, 8), Ok(b'o' as u64));
+        assert_eq!(uart.load(RBR, 8), Ok(b'k' as u64));
+        assert!(!uart.interrupting());
+
+        // Resetting the receive FIFO drops what was not read.
+        uart.rx.extend(b"lost");
+        uart.store(IIR, 8, 0x03).unwrap();
+        assert_eq!(uart.load(LSR, 8).unwrap() & LSR_DR as u64, 0);
+    }
+}
@@ -0,0 +1,1668 @@
+//! The vector extension, RVV 1.0.
+
+use crate::cpu::*;
+use crate::exception::*;
+use crate::fpu::*;
+use crate::isa::*;
+
+// OP-V categories, in funct3
+const OPIVV: u32 = 0b000;
+const OPFVV: u32 = 0b001;
+const OPMVV: u32 = 0b010;
+const OPIVI: u32 = 0b011;
+const OPIVX: u32 = 0b100;
+const OPFVF: u32 = 0b101;
+const OPMVX: u32 = 0b110;
+const OPCFG: u32 = 0b111;
+
+// Fixed-point rounding modes in vxrm, besides round-to-odd (3)
+const VXRM_RNU: u64 = 0b00;
+const VXRM_RNE: u64 = 0b01;
+const VXRM_RDN: u64 = 0b10;
+
+/// vfrec7's estimates of 1/x, by the 7 bits below the point of the
+/// normalized significand of x, as the 7 bits below the point of the
+/// result's.
+const RECIPROCAL_ESTIMATES: [u8; 128] = [
+    127, 125, 123, 121, 119, 117, 116, 114, 112, 110, 109, 107, 105, 104, 102, 100, 99, 97, 96, 94, 93, 91, 90, 88, 87,
+    85, 84, 83, 81, 80, 79, 77, 76, 75, 74, 72, 71, 70, 69, 68, 66, 65, 64, 63, 62, 61, 60, 59, 58, 57, 56, 55, 54, 53,
+    52, 51, 50, 49, 48, 47, 46, 45, 44, 43, 42, 41, 40, 40, 39, 38, 37, 36, 35, 35, 34, 33, 32, 31, 31, 30, 29, 28, 28,
+    27, 26, 25, 25, 24, 23, 23, 22, 21, 21, 20, 19, 19, 18, 17, 17, 16, 15, 15, 14, 14, 13, 12, 12, 11, 11, 10, 9, 9,
+    8, 8, 7, 7, 6, 5, 5, 4, 4, 3, 3, 2, 2, 1, 1, 0,
+];
+
+/// vfrsqrt7's estimates of 1/sqrt(x), by the lowest bit of the exponent
+/// and the top 6 bits of the significand of x.
+const RSQRT_ESTIMATES: [u8; 128] = [
+    52, 51, 50, 48, 47, 46, 44, 43, 42, 41, 40, 39, 38, 36, 35, 34, 33, 32, 31, 30, 30, 29, 28, 27, 26, 25, 24, 23, 23,
+    22, 21, 20, 19, 19, 18, 17, 16, 16, 15, 14, 14, 13, 12, 12, 11, 10, 10, 9, 9, 8, 7, 7, 6, 6, 5, 4, 4, 3, 3, 2, 2,
+    1, 1, 0, 127, 125, 123, 121, 119, 118, 116, 114, 113, 111, 109, 108, 106, 105, 103, 102, 100, 99, 97, 96, 95, 93,
+    92, 91, 90, 88, 87, 86, 85, 84, 83, 82, 80, 79, 78, 77, 76, 75, 74, 73, 72, 71, 70, 70, 69, 68, 67, 66, 65, 64, 63,
+    63, 62, 61, 60, 59, 59, 58, 57, 56, 56, 55, 54, 53,
+];
+
+/// The fields of a legal `vtype`.
+#[derive(Clone, Copy)]
+struct VType {
+    sew: u32,
+    /// LMUL as a power of two, -3 to 3.
+    lmul: i32,
+    /// Tail and mask agnostic
+    ta: bool,
+    ma: bool,
+}
+
+impl VType {
+    /// Decodes `vtype`, or returns None if vill or a reserved bit is set,
+    /// SEW is wider than ELEN or a fractional LMUL cannot hold an element.
+    fn decode(vtype: u64, elen: u32) -> Option<VType> {
+        let vsew = (vtype >> 3) & 0x7;
+        let vlmul = (vtype & 0x7) as i32;
+        if vtype >> 8 != 0 || vsew > 3 || vlmul == 4 {
+            return None;
+        }
+        let sew = 8 << vsew;
+        let lmul = if vlmul > 4 { vlmul - 8 } else { vlmul };
+        if sew > elen || (lmul < 0 && sew << -lmul > elen) {
+            return None;
+        }
+        Some(VType {
+            sew,
+            lmul,
+            ta: vtype & (1 << 6) != 0,
+            ma: vtype & (1 << 7) != 0,
+        })
+    }
+}
+
+/// A vector register group: its first register, element width and EMUL as
+/// a power of two.
+#[derive(Clone, Copy)]
+struct Group {
+    reg: usize,
+    eew: u32,
+    emul: i32,
+}
+
+impl Group {
+    /// A mask register, with a bit per element.
+    fn mask(reg: usize) -> Group {
+        Group { reg, eew: 1, emul: 0 }
+    }
+
+    fn regs(self) -> usize {
+        1 << self.emul.max(0)
+    }
+
+    /// Whether EMUL is at most 8 and at least 1/8, and the group starts at
+    /// a multiple of its size.
+    fn valid(self) -> bool {
+        (-3..=3).contains(&self.emul) && self.reg.is_multiple_of(self.regs())
+    }
+
+    fn overlaps(self, other: Group) -> bool {
+        self.reg < other.reg + other.regs() && other.reg < self.reg + self.regs()
+    }
+
+    /// Whether an instruction reading `src` may write this group: if they
+    /// do not overlap, have the same element width, or overlap only in the
+    /// lowest-numbered part of a wider source or the highest-numbered part
+    /// of a wider destination.
+    fn may_overwrite(self, src: Group) -> bool {
+        !self.overlaps(src)
+            || self.eew == src.eew
+            || (self.eew < src.eew && self.reg == src.reg)
+            || (self.eew > src.eew && src.emul >= 0 && src.reg + src.regs() == self.reg + self.regs())
+    }
+}
+
+/// The element width and fixed-point state of the integer operations.
+struct Env {
+    sew: u32,
+    vxrm: u64,
+    /// Set when a fixed-point result saturates.
+    sat: bool,
+}
+
+impl Env {
+    fn sext(&self, value: u64) -> i64 {
+        sext(value, self.sew)
+    }
+
+    /// The shift amount in `b`: its low log2(SEW) bits.
+    fn shamt(&self, b: u64) -> u32 {
+        (b & (self.sew as u64 - 1)) as u32
+    }
+
+    /// Shifts right by `d` bits, rounding as vxrm says.
+    fn round(&self, value: i128, d: u32) -> i128 {
+        if d == 0 {
+            return value;
+        }
+        let bits = value as u128;
+        let bit = |n: u32| ((bits >> n) & 1) as i128;
+        let any_below = |n: u32| bits & ((1 << n) - 1) != 0;
+        let increment = match self.vxrm {
+            VXRM_RNU => bit(d - 1),
+            VXRM_RNE => bit(d - 1) & (any_below(d - 1) as i128 | bit(d)),
+            VXRM_RDN => 0,
+            _ => (bit(d) == 0 && any_below(d)) as i128,
+        };
+        (value >> d) + increment
+    }
+
+    fn clamp_unsigned(&mut self, value: i128) -> u64 {
+        let max = ones(self.sew) as i128;
+        if value < 0 || value > max {
+            self.sat = true;
+        }
+        value.clamp(0, max) as u64
+    }
+
+    fn clamp_signed(&mut self, value: i128) -> u64 {
+        let max = (ones(self.sew) >> 1) as i128;
+        if value < -max - 1 || value > max {
+            self.sat = true;
+        }
+        value.clamp(-max - 1, max) as u64
+    }
+}
+
+/// A single-width integer operation on an element `a` of vs2, `b` of vs1 or
+/// the scalar operand, and `d` of the destination.
+type IntOp = fn(a: u64, b: u64, d: u64, env: &mut Env) -> u64;
+
+/// An integer comparison of an element of vs2 with one of vs1 or the
+/// scalar operand.
+type Compare = fn(a: u64, b: u64, env: &Env) -> bool;
+
+/// A single-width floating-point operation, like `IntOp`, returning the
+/// exception flags with the result.
+type FpOp<F> = fn(a: F, b: F, d: F, rm: u64) -> (F, u64);
+
+impl Cpu {
+    /// Executes the vector instructions: OP-V, and the loads and stores,
+    /// which share LOAD-FP and STORE-FP with the scalar ones.
+    pub fn execute_vector(&mut self, instruction: u32) -> Result<(), Exception> {
+        if !self.isa.has(V) || !self.vs_enabled() {
+            return Err(Exception::IllegalInstruction(instruction as u64));
+        }
+        let opcode = instruction & 0x7f;
+        let funct3 = (instruction >> 12) & 0x7;
+        let result = match opcode {
+            0x07 | 0x27 => self.vector_memory(instruction, opcode == 0x27),
+            _ if funct3 == OPCFG => self.vsetvl(instruction),
+            _ if funct3 == OPFVV || funct3 == OPFVF => self.vector_fp(instruction),
+            _ => self.vector_integer(instruction),
+        };
+        match result {
+            Err(Exception::IllegalInstruction(_)) => {}
+            Ok(()) => {
+                self.csrs[VSTART] = 0;
+                self.set_vs_dirty();
+            }
+            // A load or store that faults part way keeps what it did so far.
+            Err(_) => self.set_vs_dirty(),
+        }
+        result
+    }
+
+    fn vlenb(&self) -> usize {
+        self.vlen as usize / 8
+    }
+
+    /// The number of elements in a group of LMUL registers.
+    fn vlmax(&self, sew: u32, lmul: i32) -> usize {
+        let per_register = (self.vlen / sew) as usize;
+        if lmul >= 0 {
+            per_register << lmul
+        } else {
+            per_register >> -lmul
+        }
+    }
+
+    fn vtype(&self, instruction: u32) -> Result<VType, Exception> {
+        VType::decode(self.csrs[VTYPE], self.elen).ok_or(Exception::IllegalInstruction(instruction as u64))
+    }
+
+    /// Element `index` of the group starting at `reg`, `eew` bits wide.
+    pub fn velem(&self, reg: usize, index: usize, eew: u32) -> u64 {
+        let size = eew as usize / 8;
+        let offset = reg * self.vlenb() + index * size;
+        let mut bytes = [0; 8];
+        bytes[..size].copy_from_slice(&self.vregs[offset..offset + size]);
+        u64::from_le_bytes(bytes)
+    }
+
+    pub fn set_velem(&mut self, reg: usize, index: usize, eew: u32, value: u64) {
+        let size = eew as usize / 8;
+        let offset = reg * self.vlenb() + index * size;
+        self.vregs[offset..offset + size].copy_from_slice(&value.to_le_bytes()[..size]);
+    }
+
+    fn mask_bit(&self, reg: usize, index: usize) -> bool {
+        (self.vregs[reg * self.vlenb() + index / 8] >> (index % 8)) & 1 != 0
+    }
+
+    fn set_mask_bit(&mut self, reg: usize, index: usize, value: bool) {
+        let byte = &mut self.vregs[reg * self.vlen as usize / 8 + index / 8];
+        *byte = (*byte & !(1 << (index % 8))) | ((value as u8) << (index % 8));
+    }
+
+    /// Whether element `index` is active: always for unmasked instructions,
+    /// otherwise if its bit in v0 is set.
+    fn active(&self, vm: bool, index: usize) -> bool {
+        vm || self.mask_bit(0, index)
+    }
+
+    /// The tail and mask agnostic bits of vtype.
+    fn policy(&self) -> (bool, bool) {
+        VType::decode(self.csrs[VTYPE], self.elen).map_or((false, false), |vtype| (vtype.ta, vtype.ma))
+    }
+
+    /// Sets the elements of `dst` from `from` to the end of its last
+    /// register to all ones, which is what agnostic elements get.
+    fn fill_tail(&mut self, dst: Group, from: usize) {
+        let end = dst.regs() * self.vlen as usize / dst.eew as usize;
+        for i in from..end {
+            self.set_velem(dst.reg, i, dst.eew, u64::MAX);
+        }
+    }
+
+    /// Writes `op`'s result for every active body element to `dst`.
+    /// Masked-off elements and the tail are left alone, or set to all ones
+    /// when vtype makes them agnostic. Nothing is written when vstart is
+    /// not below vl.
+    fn vloop(&mut self, dst: Group, vm: bool, op: impl FnMut(&mut Cpu, usize) -> u64) {
+        self.vloop_from(0, dst, vm, op);
+    }
+
+    /// Like `vloop`, leaving the body elements below `start` alone.
+    fn vloop_from(&mut self, start: usize, dst: Group, vm: bool, mut op: impl FnMut(&mut Cpu, usize) -> u64) {
+        let (vstart, vl) = (self.csrs[VSTART] as usize, self.csrs[VL] as usize);
+        if vstart >= vl {
+            return;
+        }
+        let (ta, ma) = self.policy();
+        for i in start.max(vstart)..vl {
+            if self.active(vm, i) {
+                let value = op(self, i);
+                self.set_velem(dst.reg, i, dst.eew, value);
+            } else if ma {
+                self.set_velem(dst.reg, i, dst.eew, u64::MAX);
+            }
+        }
+        if ta {
+            self.fill_tail(dst, vl);
+        }
+    }
+
+    /// Like `vloop` for instructions writing a mask register, whose tail is
+    /// always agnostic.
+    fn vloop_mask(&mut self, vd: usize, vm: bool, mut op: impl FnMut(&mut Cpu, usize) -> bool) {
+        let (vstart, vl) = (self.csrs[VSTART] as usize, self.csrs[VL] as usize);
+        if vstart >= vl {
+            return;
+        }
+        let (_, ma) = self.policy();
+        for i in vstart..vl {
+            if self.active(vm, i) {
+                let bit = op(self, i);
+                self.set_mask_bit(vd, i, bit);
+            } else if ma {
+                self.set_mask_bit(vd, i, true);
+            }
+        }
+        for i in vl..self.vlen as usize {
+            self.set_mask_bit(vd, i, true);
+        }
+    }
+
+    /// Folds the active elements into element 0 of vs1 with `op`, and
+    /// writes the result to element 0 of vd.
+    fn vreduce(&mut self, vd: usize, vs1: usize, eew: u32, vm: bool, mut op: impl FnMut(&mut Cpu, u64, usize) -> u64) {
+        let vl = self.csrs[VL] as usize;
+        if vl == 0 {
+            return;
+        }
+        let mut acc = self.velem(vs1, 0, eew);
+        for i in 0..vl {
+            if self.active(vm, i) {
+                acc = op(self, acc, i);
+            }
+        }
+        self.set_velem(vd, 0, eew, acc);
+        if self.policy().0 {
+            self.fill_tail(Group { reg: vd, eew, emul: 0 }, 1);
+        }
+    }
+
+    /// vsetvli, vsetivli and vsetvl: set vtype, and vl from the application
+    /// vector length (AVL) and VLMAX.
+    fn vsetvl(&mut self, instruction: u32) -> Result<(), Exception> {
+        let rd = ((instruction >> 7) & 0x1f) as usize;
+        let rs1 = ((instruction >> 15) & 0x1f) as usize;
+        let rs2 = ((instruction >> 20) & 0x1f) as usize;
+        let (vtype, avl) = if instruction >> 31 == 0 {
+            // vsetvli
+            (((instruction >> 20) & 0x7ff) as u64, None)
+        } else if instruction >> 30 == 0b11 {
+            // vsetivli, with AVL in the rs1 field
+            (((instruction >> 20) & 0x3ff) as u64, Some(rs1 as u64))
+        } else if instruction >> 25 == 0x40 {
+            // vsetvl
+            (self.truncate(self.regs[rs2]), None)
+        } else {
+            return Err(Exception::IllegalInstruction(instruction as u64));
+        };
+        // x0 as rs1 asks for VLMAX, or keeps vl if rd is x0 as well.
+        let avl = avl.unwrap_or(match (rs1, rd) {
+            (0, 0) => self.csrs[VL],
+            (0, _) => u64::MAX,
+            _ => self.truncate(self.regs[rs1]),
+        });
+        match VType::decode(vtype, self.elen) {
+            Some(decoded) => {
+                self.csrs[VTYPE] = vtype;
+                self.csrs[VL] = avl.min(self.vlmax(decoded.sew, decoded.lmul) as u64);
+            }
+            None => {
+                self.csrs[VTYPE] = 1 << (self.xlen - 1);
+                self.csrs[VL] = 0;
+            }
+        }
+        self.regs[rd] = self.csrs[VL];
+        Ok(())
+    }
+
+    /// Loads element `index` of the group at `reg` from `addr`, or stores
+    /// it there. A fault leaves vstart at the element.
+    fn transfer(&mut self, addr: u64, reg: usize, index: usize, eew: u32, store: bool) -> Result<(), Exception> {
+        let result = if store {
+            let value = self.velem(reg, index, eew);
+            self.store(addr, eew as u64, value)
+        } else {
+            self.load(addr, eew as u64).map(|value| self.set_velem(reg, index, eew, value))
+        };
+        if result.is_err() {
+            self.csrs[VSTART] = index as u64;
+        }
+        result
+    }
+
+    /// The vector loads and stores: unit-stride, strided and indexed, their
+    /// segment forms, fault-only-first loads, and whole register and mask
+    /// transfers.
+    fn vector_memory(&mut self, instruction: u32, store: bool) -> Result<(), Exception> {
+        let vd = ((instruction >> 7) & 0x1f) as usize;
+        let rs1 = ((instruction >> 15) & 0x1f) as usize;
+        let rs2 = ((instruction >> 20) & 0x1f) as usize;
+        let vm = (instruction >> 25) & 1 == 1;
+        let mop = (instruction >> 26) & 0x3;
+        let nf = ((instruction >> 29) & 0x7) as usize + 1;
+        let eew = match (instruction >> 12) & 0x7 {
+            0x0 => 8,
+            0x5 => 16,
+            0x6 => 32,
+            _ => 64,
+        };
+        let indexed = mop & 1 == 1;
+        // mew selects element widths above 64 bits.
+        legal((instruction >> 28) & 1 == 0 && eew <= self.elen, instruction)?;
+        let base = self.regs[rs1];
+        let vstart = self.csrs[VSTART] as usize;
+
+        if mop == 0 && rs2 == 0b01000 {
+            // Whole registers, whatever vtype and vl hold
+            legal(vm && nf.is_power_of_two() && vd.is_multiple_of(nf) && (!store || eew == 8), instruction)?;
+            let evl = nf * self.vlen as usize / eew as usize;
+            for i in vstart..evl {
+                let addr = base.wrapping_add((i * eew as usize / 8) as u64);
+                self.transfer(addr, vd, i, eew, store)?;
+            }
+            return Ok(());
+        }
+
+        let vtype = self.vtype(instruction)?;
+        let vl = self.csrs[VL] as usize;
+        if mop == 0 && rs2 == 0b01011 {
+            // vlm.v and vsm.v, a byte for every eight elements
+            legal(vm && nf == 1 && eew == 8, instruction)?;
+            let evl = vl.div_ceil(8);
+            for i in vstart..evl {
+                self.transfer(base.wrapping_add(i as u64), vd, i, 8, store)?;
+            }
+            // The tail of a mask is always agnostic.
+            if !store && vstart < evl {
+                self.fill_tail(Group { reg: vd, eew: 8, emul: 0 }, evl);
+            }
+            return Ok(());
+        }
+
+        let fault_only_first = !store && mop == 0 && rs2 == 0b10000;
+        legal(mop != 0 || rs2 == 0 || fault_only_first, instruction)?;
+        let log2 = |eew: u32| eew.trailing_zeros() as i32;
+        let emul = log2(eew) - log2(vtype.sew) + vtype.lmul;
+        // Indexed accesses take the data width from vtype, and EEW is that of
+        // the indexes.
+        let data = if indexed {
+            Group { reg: vd, eew: vtype.sew, emul: vtype.lmul }
+        } else {
+            Group { reg: vd, eew, emul }
+        };
+        let index = Group { reg: rs2, eew, emul };
+        let span = nf * data.regs();
+        legal(data.valid() && (!indexed || index.valid()) && span <= 8 && vd + span <= 32, instruction)?;
+        if !store {
+            legal(vm || vd != 0, instruction)?;
+            if indexed && vd < index.reg + index.regs() && index.reg < vd + span {
+                legal(nf == 1 && data.may_overwrite(index), instruction)?;
+            }
+        }
+
+        let size = data.eew as u64 / 8;
+        let stride = if mop == 2 { self.regs[rs2] } else { nf as u64 * size };
+        for i in vstart..vl {
+            if !self.active(vm, i) {
+                if !store && vtype.ma {
+                    for field in 0..nf {
+                        self.set_velem(vd + field * data.regs(), i, data.eew, u64::MAX);
+                    }
+                }
+                continue;
+            }
+            let addr = if indexed {
+                base.wrapping_add(self.velem(rs2, i, eew))
+            } else {
+                base.wrapping_add((i as u64).wrapping_mul(stride))
+            };
+            for field in 0..nf {
+                let addr = addr.wrapping_add(field as u64 * size);
+                match self.transfer(addr, vd + field * data.regs(), i, data.eew, store) {
+                    // Only the first element of a fault-only-first load traps;
+                    // a later one ends the load there instead.
+                    Err(_) if fault_only_first && i > 0 => {
+                        self.csrs[VL] = i as u64;
+                        return Ok(());
+                    }
+                    result => result?,
+                }
+            }
+        }
+        if !store && vtype.ta && vstart < vl {
+            for field in 0..nf {
+                self.fill_tail(Group { reg: vd + field * data.regs(), ..data }, vl);
+            }
+        }
+        Ok(())
+    }
+
+    /// vmv1r.v, vmv2r.v, vmv4r.v and vmv8r.v, which copy whole registers
+    /// whatever vtype holds.
+    fn vmv_whole(&mut self, instruction: u32) -> Result<(), Exception> {
+        let vd = ((instruction >> 7) & 0x1f) as usize;
+        let vs2 = ((instruction >> 20) & 0x1f) as usize;
+        let vm = (instruction >> 25) & 1 == 1;
+        let regs = ((instruction >> 15) & 0x1f) as usize + 1;
+        legal(vm && matches!(regs, 1 | 2 | 4 | 8) && vd.is_multiple_of(regs) && vs2.is_multiple_of(regs), instruction)?;
+        let eew = self.vtype(instruction).map_or(8, |vtype| vtype.sew);
+        let evl = regs * self.vlen as usize / eew as usize;
+        for i in self.csrs[VSTART] as usize..evl {
+            let value = self.velem(vs2, i, eew);
+            self.set_velem(vd, i, eew, value);
+        }
+        Ok(())
+    }
+
+    /// vmv.s.x and vfmv.s.f, which write element 0 of vd.
+    fn vmv_s(&mut self, vd: usize, eew: u32, value: u64) {
+        if self.csrs[VSTART] < self.csrs[VL] {
+            self.set_velem(vd, 0, eew, value);
+            if self.policy().0 {
+                self.fill_tail(Group { reg: vd, eew, emul: 0 }, 1);
+            }
+        }
+    }
+
+    /// vmerge, vfmerge and, when unmasked, vmv.v and vfmv.v.f.
+    fn vmerge(&mut self, instruction: u32, vtype: VType, operand: impl Fn(&Cpu, usize) -> u64) -> Result<(), Exception> {
+        let vd = ((instruction >> 7) & 0x1f) as usize;
+        let rs1 = ((instruction >> 15) & 0x1f) as usize;
+        let vs2 = ((instruction >> 20) & 0x1f) as usize;
+        let vm = (instruction >> 25) & 1 == 1;
+        let vector = instruction & (0x7 << 12) == 0;
+        let group = |reg| Group { reg, eew: vtype.sew, emul: vtype.lmul };
+        legal(
+            if vm { vs2 == 0 } else { vd != 0 && group(vs2).valid() }
+                && group(vd).valid()
+                && (!vector || group(rs1).valid()),
+            instruction,
+        )?;
+        self.vloop(group(vd), true, |cpu, i| {
+            if cpu.active(vm, i) {
+                operand(cpu, i)
+            } else {
+                cpu.velem(vs2, i, vtype.sew)
+            }
+        });
+        Ok(())
+    }
+
+    /// vslide1up and vslide1down, and their floating-point forms, which
+    /// shift `scalar` in at one end.
+    fn vslide1(&mut self, instruction: u32, vtype: VType, scalar: u64) -> Result<(), Exception> {
+        let vd = ((instruction >> 7) & 0x1f) as usize;
+        let vs2 = ((instruction >> 20) & 0x1f) as usize;
+        let vm = (instruction >> 25) & 1 == 1;
+        let up = (instruction >> 26) & 1 == 0;
+        let sew = vtype.sew;
+        let dst = Group { reg: vd, eew: sew, emul: vtype.lmul };
+        let src = Group { reg: vs2, ..dst };
+        legal(dst.valid() && src.valid() && (vm || vd != 0) && !(up && dst.overlaps(src)), instruction)?;
+        let vl = self.csrs[VL] as usize;
+        self.vloop(dst, vm, |cpu, i| match up {
+            true if i == 0 => scalar,
+            true => cpu.velem(vs2, i - 1, sew),
+            false if i + 1 == vl => scalar,
+            false => cpu.velem(vs2, i + 1, sew),
+        });
+        Ok(())
+    }
+
+    /// The integer, fixed-point, mask and permutation instructions of OP-V.
+    fn vector_integer(&mut self, instruction: u32) -> Result<(), Exception> {
+        let funct3 = (instruction >> 12) & 0x7;
+        let funct6 = instruction >> 26;
+        let vm = (instruction >> 25) & 1 == 1;
+        let vd = ((instruction >> 7) & 0x1f) as usize;
+        let rs1 = ((instruction >> 15) & 0x1f) as usize;
+        let vs2 = ((instruction >> 20) & 0x1f) as usize;
+        let vector = matches!(funct3, OPIVV | OPMVV);
+
+        if funct3 == OPIVI && funct6 == 0x27 {
+            return self.vmv_whole(instruction);
+        }
+        let vtype = self.vtype(instruction)?;
+        let (sew, lmul) = (vtype.sew, vtype.lmul);
+        // Shifts, slides and gathers take an unsigned immediate, the others a
+        // signed one.
+        let scalar = match funct3 {
+            OPIVI if matches!(funct6, 0x0c | 0x0e | 0x0f | 0x25 | 0x28..=0x2f) => rs1 as u64,
+            OPIVI => ((rs1 as i64) << 59 >> 59) as u64,
+            _ => self.regs[rs1],
+        };
+        // Slide amounts and gather indexes are XLEN-bit unsigned values.
+        let offset = if funct3 == OPIVI { scalar } else { self.truncate(scalar) };
+        let b = scalar & ones(sew);
+        let operand = move |cpu: &Cpu, i: usize| if vector { cpu.velem(rs1, i, sew) } else { b };
+        let group = |reg| Group { reg, eew: sew, emul: lmul };
+        let masked_v0 = |dst: Group| vm || !dst.overlaps(Group::mask(0));
+        let mut env = Env {
+            sew,
+            vxrm: self.csrs[VXRM],
+            sat: false,
+        };
+
+        if let Some(op) = int_op(funct3, funct6) {
+            let dst = group(vd);
+            legal(
+                dst.valid() && group(vs2).valid() && (!vector || group(rs1).valid()) && masked_v0(dst),
+                instruction,
+            )?;
+            self.vloop(dst, vm, |cpu, i| {
+                op(cpu.velem(vs2, i, sew), operand(cpu, i), cpu.velem(vd, i, sew), &mut env)
+            });
+            if env.sat {
+                self.csrs[VXSAT] = 1;
+            }
+            return Ok(());
+        }
+        if let Some(compare) = int_compare(funct3, funct6) {
+            let dst = Group::mask(vd);
+            legal(
+                group(vs2).valid()
+                    && dst.may_overwrite(group(vs2))
+                    && (!vector || (group(rs1).valid() && dst.may_overwrite(group(rs1)))),
+                instruction,
+            )?;
+            self.vloop_mask(vd, vm, |cpu, i| compare(cpu.velem(vs2, i, sew), operand(cpu, i), &env));
+            return Ok(());
+        }
+
+        match (funct3, funct6) {
+            (OPIVV | OPIVX | OPIVI, 0x10) | (OPIVV | OPIVX, 0x12) => {
+                // vadc, vsbc, with the carry or borrow in v0
+                legal(
+                    !vm && vd != 0 && group(vd).valid() && group(vs2).valid() && (!vector || group(rs1).valid()),
+                    instruction,
+                )?;
+                let subtract = funct6 == 0x12;
+                self.vloop(group(vd), true, |cpu, i| {
+                    let (a, b, carry) = (cpu.velem(vs2, i, sew), operand(cpu, i), cpu.mask_bit(0, i) as u64);
+                    if subtract {
+                        a.wrapping_sub(b).wrapping_sub(carry)
+                    } else {
+                        a.wrapping_add(b).wrapping_add(carry)
+                    }
+                });
+            }
+            (OPIVV | OPIVX | OPIVI, 0x11) | (OPIVV | OPIVX, 0x13) => {
+                // vmadc, vmsbc: the carry or borrow out, taking one in from v0
+                // unless unmasked
+                let dst = Group::mask(vd);
+                legal(
+                    group(vs2).valid()
+                        && dst.may_overwrite(group(vs2))
+                        && (!vector || (group(rs1).valid() && dst.may_overwrite(group(rs1)))),
+                    instruction,
+                )?;
+                let subtract = funct6 == 0x13;
+                self.vloop_mask(vd, true, |cpu, i| {
+                    let (a, b) = (cpu.velem(vs2, i, sew) as i128, operand(cpu, i) as i128);
+                    let carry = (!vm && cpu.mask_bit(0, i)) as i128;
+                    if subtract {
+                        a - b - carry < 0
+                    } else {
+                        (a + b + carry) >> sew != 0
+                    }
+                });
+            }
+            (OPIVV | OPIVX | OPIVI, 0x17) => self.vmerge(instruction, vtype, operand)?,
+            (OPIVV | OPIVX | OPIVI, 0x2c..=0x2f) => {
+                // vnsrl, vnsra, vnclipu, vnclip, from 2 * SEW bits
+                let wide = Group { reg: vs2, eew: 2 * sew, emul: lmul + 1 };
+                let dst = group(vd);
+                legal(
+                    2 * sew <= self.elen
+                        && wide.valid()
+                        && dst.valid()
+                        && dst.may_overwrite(wide)
+                        && (!vector || group(rs1).valid())
+                        && masked_v0(dst),
+                    instruction,
+                )?;
+                self.vloop(dst, vm, |cpu, i| {
+                    let a = cpu.velem(vs2, i, 2 * sew);
+                    let shift = (operand(cpu, i) & (2 * sew as u64 - 1)) as u32;
+                    match funct6 {
+                        0x2c => a >> shift,
+                        0x2d => (sext(a, 2 * sew) >> shift) as u64,
+                        0x2e => env.clamp_unsigned(env.round(a as i128, shift)),
+                        _ => env.clamp_signed(env.round(sext(a, 2 * sew) as i128, shift)),
+                    }
+                });
+                if env.sat {
+                    self.csrs[VXSAT] = 1;
+                }
+            }
+            (OPMVV | OPMVX, 0x30..=0x38 | 0x3a..=0x3d | 0x3f) | (OPMVX, 0x3e) => {
+                // Widening adds, subtracts, multiplies and multiply-adds, into
+                // 2 * SEW bits. The .w forms take vs2 at that width too.
+                let wide_a = (0x34..=0x37).contains(&funct6);
+                let (signed_a, signed_b) = match funct6 {
+                    // vwmulsu, vwmaccus: vs2 is signed
+                    0x3a | 0x3e => (true, false),
+                    // vwmaccsu: vs1 or rs1 is signed
+                    0x3f => (false, true),
+                    _ => (funct6 & 1 == 1, funct6 & 1 == 1),
+                };
+                let dst = Group { reg: vd, eew: 2 * sew, emul: lmul + 1 };
+                let a_group = if wide_a { Group { reg: vs2, ..dst } } else { group(vs2) };
+                legal(
+                    2 * sew <= self.elen
+                        && dst.valid()
+                        && a_group.valid()
+                        && dst.may_overwrite(a_group)
+                        && (!vector || (group(rs1).valid() && dst.may_overwrite(group(rs1))))
+                        && masked_v0(dst),
+                    instruction,
+                )?;
+                let extend = move |value: u64, signed: bool| if signed { sext(value, sew) as u64 } else { value };
+                self.vloop(dst, vm, |cpu, i| {
+                    let a = cpu.velem(vs2, i, a_group.eew);
+                    let a = if wide_a { a } else { extend(a, signed_a) };
+                    let b = extend(operand(cpu, i), signed_b);
+                    match funct6 {
+                        0x30 | 0x31 | 0x34 | 0x35 => a.wrapping_add(b),
+                        0x32 | 0x33 | 0x36 | 0x37 => a.wrapping_sub(b),
+                        0x38..=0x3b => a.wrapping_mul(b),
+                        _ => b.wrapping_mul(a).wrapping_add(cpu.velem(vd, i, 2 * sew)),
+                    }
+                });
+            }
+            (OPMVV, 0x00..=0x07) => {
+                // vredsum, vredand, vredor, vredxor, vredminu, vredmin,
+                // vredmaxu, vredmax
+                legal(self.csrs[VSTART] == 0 && group(vs2).valid(), instruction)?;
+                self.vreduce(vd, rs1, sew, vm, |cpu, acc, i| {
+                    let x = cpu.velem(vs2, i, sew);
+                    let (signed_acc, signed_x) = (sext(acc, sew), sext(x, sew));
+                    match funct6 {
+                        0x00 => acc.wrapping_add(x),
+                        0x01 => acc & x,
+                        0x02 => acc | x,
+                        0x03 => acc ^ x,
+                        0x04 => acc.min(x),
+                        0x05 => signed_acc.min(signed_x) as u64,
+                        0x06 => acc.max(x),
+                        _ => signed_acc.max(signed_x) as u64,
+                    }
+                });
+            }
+            (OPIVV, 0x30 | 0x31) => {
+                // vwredsumu, vwredsum
+                legal(self.csrs[VSTART] == 0 && 2 * sew <= self.elen && group(vs2).valid(), instruction)?;
+                let signed = funct6 == 0x31;
+                self.vreduce(vd, rs1, 2 * sew, vm, |cpu, acc, i| {
+                    let x = cpu.velem(vs2, i, sew);
+                    acc.wrapping_add(if signed { sext(x, sew) as u64 } else { x })
+                });
+            }
+            (OPIVV | OPIVX | OPIVI, 0x0c) | (OPIVV, 0x0e) => {
+                // vrgather, vrgatherei16
+                let index = if funct6 == 0x0e {
+                    Group { reg: rs1, eew: 16, emul: 4 - sew.trailing_zeros() as i32 + lmul }
+                } else {
+                    group(rs1)
+                };
+                let dst = group(vd);
+                legal(
+                    dst.valid()
+                        && group(vs2).valid()
+                        && !dst.overlaps(group(vs2))
+                        && (!vector || (index.valid() && !dst.overlaps(index)))
+                        && masked_v0(dst),
+                    instruction,
+                )?;
+                let vlmax = self.vlmax(sew, lmul) as u64;
+                self.vloop(dst, vm, |cpu, i| {
+                    let at = if vector { cpu.velem(rs1, i, index.eew) } else { offset };
+                    if at < vlmax {
+                        cpu.velem(vs2, at as usize, sew)
+                    } else {
+                        0
+                    }
+                });
+            }
+            (OPIVX | OPIVI, 0x0e) => {
+                // vslideup
+                let dst = group(vd);
+                legal(
+                    dst.valid() && group(vs2).valid() && !dst.overlaps(group(vs2)) && masked_v0(dst),
+                    instruction,
+                )?;
+                let start = offset.min(self.csrs[VL]) as usize;
+                self.vloop_from(start, dst, vm, |cpu, i| cpu.velem(vs2, i - start, sew));
+            }
+            (OPIVX | OPIVI, 0x0f) => {
+                // vslidedown
+                let dst = group(vd);
+                legal(dst.valid() && group(vs2).valid() && masked_v0(dst), instruction)?;
+                let vlmax = self.vlmax(sew, lmul) as u64;
+                self.vloop(dst, vm, |cpu, i| match (i as u64).checked_add(offset) {
+                    Some(at) if at < vlmax => cpu.velem(vs2, at as usize, sew),
+                    _ => 0,
+                });
+            }
+            (OPMVX, 0x0e | 0x0f) => self.vslide1(instruction, vtype, b)?,
+            (OPMVV, 0x10) => match rs1 {
+                0x00 if vm => {
+                    // vmv.x.s
+                    self.regs[vd] = sext(self.velem(vs2, 0, sew), sew) as u64;
+                }
+                0x10 | 0x11 => {
+                    // vcpop.m, vfirst.m
+                    legal(self.csrs[VSTART] == 0, instruction)?;
+                    let vl = self.csrs[VL] as usize;
+                    let mut set = (0..vl).filter(|&i| self.active(vm, i) && self.mask_bit(vs2, i));
+                    self.regs[vd] = if rs1 == 0x10 {
+                        set.count() as u64
+                    } else {
+                        set.next().map_or(u64::MAX, |i| i as u64)
+                    };
+                }
+                _ => return Err(Exception::IllegalInstruction(instruction as u64)),
+            },
+            (OPMVX, 0x10) if vs2 == 0 && vm => self.vmv_s(vd, sew, b), // vmv.s.x
+            (OPMVV, 0x12) if (0x02..=0x07).contains(&rs1) => {
+                // vzext and vsext, from SEW / 8, SEW / 4 or SEW / 2 bits
+                let factor = 4 - (rs1 as i32 >> 1);
+                let src = Group { reg: vs2, eew: sew >> factor, emul: lmul - factor };
+                let dst = group(vd);
+                legal(
+                    src.eew >= 8 && src.valid() && dst.valid() && dst.may_overwrite(src) && masked_v0(dst),
+                    instruction,
+                )?;
+                let signed = rs1 & 1 == 1;
+                self.vloop(dst, vm, |cpu, i| {
+                    let x = cpu.velem(vs2, i, src.eew);
+                    if signed {
+                        sext(x, src.eew) as u64
+                    } else {
+                        x
+                    }
+                });
+            }
+            (OPMVV, 0x14) => match rs1 {
+                0x01..=0x03 => {
+                    // vmsbf, vmsof, vmsif: set before, only or including the
+                    // first set bit
+                    legal(self.csrs[VSTART] == 0 && vd != vs2 && (vm || vd != 0), instruction)?;
+                    let mut seen = false;
+                    self.vloop_mask(vd, vm, |cpu, i| {
+                        let set = cpu.mask_bit(vs2, i);
+                        let bit = match rs1 {
+                            0x01 => !seen && !set,
+                            0x02 => !seen && set,
+                            _ => !seen,
+                        };
+                        seen |= set;
+                        bit
+                    });
+                }
+                0x10 => {
+                    // viota: the number of set bits below each element
+                    let dst = group(vd);
+                    legal(
+                        self.csrs[VSTART] == 0 && dst.valid() && !dst.overlaps(Group::mask(vs2)) && masked_v0(dst),
+                        instruction,
+                    )?;
+                    let mut count = 0;
+                    self.vloop(dst, vm, |cpu, i| {
+                        let value = count;
+                        count += cpu.mask_bit(vs2, i) as u64;
+                        value
+                    });
+                }
+                0x11 if vs2 == 0 => {
+                    // vid
+                    let dst = group(vd);
+                    legal(dst.valid() && masked_v0(dst), instruction)?;
+                    self.vloop(dst, vm, |_, i| i as u64);
+                }
+                _ => return Err(Exception::IllegalInstruction(instruction as u64)),
+            },
+            (OPMVV, 0x17) => {
+                // vcompress: packs the elements selected by vs1, leaving a tail
+                let dst = group(vd);
+                legal(
+                    vm && self.csrs[VSTART] == 0
+                        && dst.valid()
+                        && group(vs2).valid()
+                        && !dst.overlaps(group(vs2))
+                        && !dst.overlaps(Group::mask(rs1)),
+                    instruction,
+                )?;
+                let vl = self.csrs[VL] as usize;
+                if vl > 0 {
+                    let mut packed = 0;
+                    for i in 0..vl {
+                        if self.mask_bit(rs1, i) {
+                            let value = self.velem(vs2, i, sew);
+                            self.set_velem(vd, packed, sew, value);
+                            packed += 1;
+                        }
+                    }
+                    if vtype.ta {
+                        self.fill_tail(dst, packed);
+                    }
+                }
+            }
+            (OPMVV, 0x18..=0x1f) if vm => {
+                // vmandn, vmand, vmor, vmxor, vmorn, vmnand, vmnor, vmxnor
+                self.vloop_mask(vd, true, |cpu, i| {
+                    let (a, b) = (cpu.mask_bit(vs2, i), cpu.mask_bit(rs1, i));
+                    match funct6 {
+                        0x18 => a && !b,
+                        0x19 => a && b,
+                        0x1a => a || b,
+                        0x1b => a != b,
+                        0x1c => a || !b,
+                        0x1d => !(a && b),
+                        0x1e => !(a || b),
+                        _ => a == b,
+                    }
+                });
+            }
+            _ => return Err(Exception::IllegalInstruction(instruction as u64)),
+        }
+        Ok(())
+    }
+
+    /// The floating-point instructions of OP-V, on single-precision elements
+    /// when SEW is 32 and double-precision ones when it is 64.
+    fn vector_fp(&mut self, instruction: u32) -> Result<(), Exception> {
+        let rm = self.load_csr(FRM);
+        legal(self.fs_enabled() && rm <= RMM, instruction)?;
+        let vtype = self.vtype(instruction)?;
+        let funct6 = instruction >> 26;
+        let flags = match (funct6, vtype.sew) {
+            (0x12, _) => self.vector_fp_convert(instruction, vtype, rm)?,
+            (0x30..=0x3f, _) => self.vector_fp_widening(instruction, vtype, rm)?,
+            (_, 32) => self.vector_float::<f32>(instruction, vtype, rm)?,
+            (_, 64) => self.vector_float::<f64>(instruction, vtype, rm)?,
+            _ => return Err(Exception::IllegalInstruction(instruction as u64)),
+        };
+        self.csrs[FCSR] |= flags;
+        self.set_fs_dirty();
+        Ok(())
+    }
+
+    /// The single-width floating-point instructions. Returns the exception
+    /// flags raised.
+    fn vector_float<F: Float>(&mut self, instruction: u32, vtype: VType, rm: u64) -> Result<u64, Exception> {
+        let funct3 = (instruction >> 12) & 0x7;
+        let funct6 = instruction >> 26;
+        let vm = (instruction >> 25) & 1 == 1;
+        let vd = ((instruction >> 7) & 0x1f) as usize;
+        let rs1 = ((instruction >> 15) & 0x1f) as usize;
+        let vs2 = ((instruction >> 20) & 0x1f) as usize;
+        let vector = funct3 == OPFVV;
+        let sew = F::BITS;
+        let scalar = to_element(F::from_reg(self.fregs[rs1]));
+        let operand = move |cpu: &Cpu, i: usize| if vector { cpu.velem(rs1, i, sew) } else { scalar };
+        let group = |reg| Group { reg, eew: sew, emul: vtype.lmul };
+        let masked_v0 = |dst: Group| vm || !dst.overlaps(Group::mask(0));
+        let mut flags = 0;
+
+        if let Some(op) = fp_op::<F>(funct3, funct6) {
+            let dst = group(vd);
+            legal(
+                dst.valid() && group(vs2).valid() && (!vector || group(rs1).valid()) && masked_v0(dst),
+                instruction,
+            )?;
+            self.vloop(dst, vm, |cpu, i| {
+                let a = from_element::<F>(cpu.velem(vs2, i, sew));
+                let b = from_element::<F>(operand(cpu, i));
+                let d = from_element::<F>(cpu.velem(vd, i, sew));
+                let (r, f) = op(a, b, d, rm);
+                flags |= f;
+                to_element(r)
+            });
+            return Ok(flags);
+        }
+
+        match (funct3, funct6) {
+            (_, 0x18 | 0x19 | 0x1b | 0x1c) | (OPFVF, 0x1d | 0x1f) => {
+                // vmfeq, vmfle, vmflt, vmfne, vmfgt, vmfge. Only equality
+                // comparisons are quiet.
+                let dst = Group::mask(vd);
+                legal(
+                    group(vs2).valid()
+                        && dst.may_overwrite(group(vs2))
+                        && (!vector || (group(rs1).valid() && dst.may_overwrite(group(rs1)))),
+                    instruction,
+                )?;
+                self.vloop_mask(vd, vm, |cpu, i| {
+                    let a = from_element::<F>(cpu.velem(vs2, i, sew));
+                    let b = from_element::<F>(operand(cpu, i));
+                    let quiet = matches!(funct6, 0x18 | 0x1c);
+                    if a.is_snan() || b.is_snan() || (!quiet && (a.is_nan() || b.is_nan())) {
+                        flags |= NV;
+                    }
+                    match funct6 {
+                        0x18 => a == b,
+                        0x19 => a <= b,
+                        0x1b => a < b,
+                        0x1c => a != b,
+                        0x1d => a > b,
+                        _ => a >= b,
+                    }
+                });
+            }
+            (OPFVF, 0x17) => self.vmerge(instruction, vtype, |_, _| scalar)?,
+            (OPFVV, 0x10) if rs1 == 0 && vm => {
+                // vfmv.f.s
+                self.fregs[vd] = from_element::<F>(self.velem(vs2, 0, sew)).to_reg();
+            }
+            (OPFVF, 0x10) if vs2 == 0 && vm => self.vmv_s(vd, sew, scalar), // vfmv.s.f
+            (OPFVF, 0x0e | 0x0f) => self.vslide1(instruction, vtype, scalar)?,
+            (OPFVV, 0x13) if matches!(rs1, 0x00 | 0x04 | 0x05 | 0x10) => {
+                // vfsqrt, vfrsqrt7, vfrec7, vfclass
+                let dst = group(vd);
+                legal(dst.valid() && group(vs2).valid() && masked_v0(dst), instruction)?;
+                self.vloop(dst, vm, |cpu, i| {
+                    let a = from_element::<F>(cpu.velem(vs2, i, sew));
+                    let (r, f) = match rs1 {
+                        0x00 => sqrt(a, rm),
+                        0x04 => rsqrt_estimate(a),
+                        0x05 => reciprocal_estimate(a, rm),
+                        _ => return classify(a),
+                    };
+                    flags |= f;
+                    to_element(r)
+                });
+            }
+            (OPFVV, 0x01 | 0x03 | 0x05 | 0x07) => {
+                // vfredusum, vfredosum, vfredmin, vfredmax. Unordered sums
+                // are done in order as well.
+                legal(self.csrs[VSTART] == 0 && group(vs2).valid(), instruction)?;
+                self.vreduce(vd, rs1, sew, vm, |cpu, acc, i| {
+                    let (acc, x) = (from_element::<F>(acc), from_element::<F>(cpu.velem(vs2, i, sew)));
+                    let (r, f) = match funct6 {
+                        0x01 | 0x03 => add(acc, x, rm),
+                        0x05 => min_max(acc, x, false),
+                        _ => min_max(acc, x, true),
+                    };
+                    flags |= f;
+                    to_element(r)
+                });
+            }
+            _ => return Err(Exception::IllegalInstruction(instruction as u64)),
+        }
+        Ok(flags)
+    }
+
+    /// The widening floating-point instructions, from single to double
+    /// precision. Returns the exception flags raised.
+    fn vector_fp_widening(&mut self, instruction: u32, vtype: VType, rm: u64) -> Result<u64, Exception> {
+        let funct3 = (instruction >> 12) & 0x7;
+        let funct6 = instruction >> 26;
+        let vm = (instruction >> 25) & 1 == 1;
+        let vd = ((instruction >> 7) & 0x1f) as usize;
+        let rs1 = ((instruction >> 15) & 0x1f) as usize;
+        let vs2 = ((instruction >> 20) & 0x1f) as usize;
+        let vector = funct3 == OPFVV;
+        let narrow = |reg| Group { reg, eew: 32, emul: vtype.lmul };
+        let wide = |reg| Group { reg, eew: 64, emul: vtype.lmul + 1 };
+        legal(vtype.sew == 32 && self.elen == 64, instruction)?;
+        let scalar = to_element(f32::from_reg(self.fregs[rs1]));
+        let operand = move |cpu: &Cpu, i: usize| if vector { cpu.velem(rs1, i, 32) } else { scalar };
+        let mut flags = 0;
+
+        match funct6 {
+            0x30 | 0x32 | 0x34 | 0x36 | 0x38 | 0x3c..=0x3f => {
+                // vfwadd, vfwsub, their .w forms taking vs2 in double
+                // precision, vfwmul, vfwmacc, vfwnmacc, vfwmsac, vfwnmsac
+                let wide_a = matches!(funct6, 0x34 | 0x36);
+                let dst = wide(vd);
+                let a_group = if wide_a { wide(vs2) } else { narrow(vs2) };
+                legal(
+                    dst.valid()
+                        && a_group.valid()
+                        && dst.may_overwrite(a_group)
+                        && (!vector || (narrow(rs1).valid() && dst.may_overwrite(narrow(rs1))))
+                        && (vm || !dst.overlaps(Group::mask(0))),
+                    instruction,
+                )?;
+                self.vloop(dst, vm, |cpu, i| {
+                    let (a, fa) = if wide_a {
+                        (f64::from_bits(cpu.velem(vs2, i, 64)), 0)
+                    } else {
+                        widen(from_element(cpu.velem(vs2, i, 32)))
+                    };
+                    let (b, fb) = widen(from_element(operand(cpu, i)));
+                    let d = f64::from_bits(cpu.velem(vd, i, 64));
+                    let (r, f) = match funct6 {
+                        0x30 | 0x34 => add(a, b, rm),
+                        0x32 | 0x36 => add(a, -b, rm),
+                        0x38 => mul(a, b, rm),
+                        0x3c => fma(b, a, d, rm),
+                        0x3d => fma(-b, a, -d, rm),
+                        0x3e => fma(b, a, -d, rm),
+                        _ => fma(-b, a, d, rm),
+                    };
+                    flags |= fa | fb | f;
+                    r.to_bits()
+                });
+            }
+            0x31 | 0x33 if vector => {
+                // vfwredusum, vfwredosum
+                legal(self.csrs[VSTART] == 0 && narrow(vs2).valid(), instruction)?;
+                self.vreduce(vd, rs1, 64, vm, |cpu, acc, i| {
+                    let (x, fx) = widen(from_element(cpu.velem(vs2, i, 32)));
+                    let (r, f) = add(f64::from_bits(acc), x, rm);
+                    flags |= fx | f;
+                    r.to_bits()
+                });
+            }
+            _ => return Err(Exception::IllegalInstruction(instruction as u64)),
+        }
+        Ok(flags)
+    }
+
+    /// VFUNARY0: conversions between integers and floating-point values,
+    /// and between precisions, at single width, widening or narrowing.
+    /// Returns the exception flags raised.
+    fn vector_fp_convert(&mut self, instruction: u32, vtype: VType, rm: u64) -> Result<u64, Exception> {
+        let funct3 = (instruction >> 12) & 0x7;
+        let vm = (instruction >> 25) & 1 == 1;
+        let vd = ((instruction >> 7) & 0x1f) as usize;
+        let code = (instruction >> 15) & 0x1f;
+        let vs2 = ((instruction >> 20) & 0x1f) as usize;
+        let (sew, lmul) = (vtype.sew, vtype.lmul);
+        let (src, dst) = match code >> 3 {
+            0 => (Group { reg: vs2, eew: sew, emul: lmul }, Group { reg: vd, eew: sew, emul: lmul }),
+            1 => (Group { reg: vs2, eew: sew, emul: lmul }, Group { reg: vd, eew: 2 * sew, emul: lmul + 1 }),
+            _ => (Group { reg: vs2, eew: 2 * sew, emul: lmul + 1 }, Group { reg: vd, eew: sew, emul: lmul }),
+        };
+        // xu.f, x.f, f.xu, f.x, f.f, rod.f.f, rtz.xu.f, rtz.x.f
+        let kind = code & 0x7;
+        let float = |eew: u32| eew == 32 || eew == 64;
+        let legal_kind = match (code >> 3, kind) {
+            (0, 4 | 5) | (1, 5) | (3.., _) => false,
+            (_, 0 | 1 | 6 | 7) => float(src.eew),
+            (_, 2 | 3) => float(dst.eew),
+            _ => float(src.eew) && float(dst.eew),
+        };
+        legal(
+            funct3 == OPFVV
+                && legal_kind
+                && src.eew.max(dst.eew) <= self.elen
+                && src.valid()
+                && dst.valid()
+                && dst.may_overwrite(src)
+                && (vm || !dst.overlaps(Group::mask(0))),
+            instruction,
+        )?;
+        let mut flags = 0;
+        self.vloop(dst, vm, |cpu, i| {
+            let x = cpu.velem(vs2, i, src.eew);
+            let (value, f) = match kind {
+                0 | 1 | 6 | 7 => {
+                    let rm = if kind >= 6 { RTZ } else { rm };
+                    let (min, max) = if kind & 1 == 1 {
+                        (-(1 << (dst.eew - 1)), (1 << (dst.eew - 1)) - 1)
+                    } else {
+                        (0, (1 << dst.eew) - 1)
+                    };
+                    let (value, f) = if src.eew == 32 {
+                        to_int(from_element::<f32>(x), rm, min, max)
+                    } else {
+                        to_int(from_element::<f64>(x), rm, min, max)
+                    };
+                    (value as u64, f)
+                }
+                2 | 3 => {
+                    let value = if kind == 3 { sext(x, src.eew) as i128 } else { x as i128 };
+                    if dst.eew == 32 {
+                        let (r, f) = from_int::<f32>(value, rm);
+                        (to_element(r), f)
+                    } else {
+                        let (r, f) = from_int::<f64>(value, rm);
+                        (to_element(r), f)
+                    }
+                }
+                4 if dst.eew == 64 => {
+                    let (r, f) = widen(from_element(x));
+                    (r.to_bits(), f)
+                }
+                4 => {
+                    let (r, f) = narrow(f64::from_bits(x), rm);
+                    (to_element(r), f)
+                }
+                _ => {
+                    let (r, f) = narrow_odd(f64::from_bits(x));
+                    (to_element(r), f)
+                }
+            };
+            flags |= f;
+            value
+        });
+        Ok(flags)
+    }
+}
+
+fn legal(ok: bool, instruction: u32) -> Result<(), Exception> {
+    match ok {
+        true => Ok(()),
+        false => Err(Exception::IllegalInstruction(instruction as u64)),
+    }
+}
+
+/// All ones in the low `bits` bits.
+fn ones(bits: u32) -> u64 {
+    if bits >= 64 {
+        u64::MAX
+    } else {
+        (1 << bits) - 1
+    }
+}
+
+/// Sign-extends the low `bits` bits of `value`.
+fn sext(value: u64, bits: u32) -> i64 {
+    ((value << (64 - bits)) as i64) >> (64 - bits)
+}
+
+/// The single-width integer operations, by category and funct6.
+fn int_op(funct3: u32, funct6: u32) -> Option<IntOp> {
+    let (vv, vi) = (funct3 == OPIVV, funct3 == OPIVI);
+    let op: IntOp = match funct3 {
+        OPIVV | OPIVX | OPIVI => match funct6 {
+            0x00 => |a, b, _, _| a.wrapping_add(b),
+            0x02 if !vi => |a, b, _, _| a.wrapping_sub(b),
+            0x03 if !vv => |a, b, _, _| b.wrapping_sub(a),
+            0x04 if !vi => |a, b, _, _| a.min(b),
+            0x05 if !vi => |a, b, _, e| if e.sext(a) < e.sext(b) { a } else { b },
+            0x06 if !vi => |a, b, _, _| a.max(b),
+            0x07 if !vi => |a, b, _, e| if e.sext(a) > e.sext(b) { a } else { b },
+            0x09 => |a, b, _, _| a & b,
+            0x0a => |a, b, _, _| a | b,
+            0x0b => |a, b, _, _| a ^ b,
+            // vsaddu, vsadd, vssubu, vssub
+            0x20 => |a, b, _, e| e.clamp_unsigned(a as i128 + b as i128),
+            0x21 => |a, b, _, e| e.clamp_signed(e.sext(a) as i128 + e.sext(b) as i128),
+            0x22 if !vi => |a, b, _, e| e.clamp_unsigned(a as i128 - b as i128),
+            0x23 if !vi => |a, b, _, e| e.clamp_signed(e.sext(a) as i128 - e.sext(b) as i128),
+            0x25 => |a, b, _, e| a << e.shamt(b),
+            // vsmul: a fractional multiply, rounded and saturated
+            0x27 if !vi => |a, b, _, e| {
+                let product = e.sext(a) as i128 * e.sext(b) as i128;
+                e.clamp_signed(e.round(product, e.sew - 1))
+            },
+            0x28 => |a, b, _, e| a >> e.shamt(b),
+            0x29 => |a, b, _, e| (e.sext(a) >> e.shamt(b)) as u64,
+            // vssrl, vssra: shifts with rounding
+            0x2a => |a, b, _, e| e.round(a as i128, e.shamt(b)) as u64,
+            0x2b => |a, b, _, e| e.round(e.sext(a) as i128, e.shamt(b)) as u64,
+            _ => return None,
+        },
+        OPMVV | OPMVX => match funct6 {
+            // vaaddu, vaadd, vasubu, vasub: halved, with rounding
+            0x08 => |a, b, _, e| e.round(a as i128 + b as i128, 1) as u64,
+            0x09 => |a, b, _, e| e.round(e.sext(a) as i128 + e.sext(b) as i128, 1) as u64,
+            0x0a => |a, b, _, e| e.round(a as i128 - b as i128, 1) as u64,
+            0x0b => |a, b, _, e| e.round(e.sext(a) as i128 - e.sext(b) as i128, 1) as u64,
+            0x20 => |a, b, _, _| a.checked_div(b).unwrap_or(u64::MAX),
+            0x21 => |a, b, _, e| match e.sext(b) {
+                0 => u64::MAX,
+                divisor => e.sext(a).wrapping_div(divisor) as u64,
+            },
+            0x22 => |a, b, _, _| a.checked_rem(b).unwrap_or(a),
+            0x23 => |a, b, _, e| match e.sext(b) {
+                0 => a,
+                divisor => e.sext(a).wrapping_rem(divisor) as u64,
+            },
+            0x24 => |a, b, _, e| ((a as u128 * b as u128) >> e.sew) as u64,
+            0x25 => |a, b, _, _| a.wrapping_mul(b),
+            0x26 => |a, b, _, e| ((e.sext(a) as i128 * b as i128) >> e.sew) as u64,
+            0x27 => |a, b, _, e| ((e.sext(a) as i128 * e.sext(b) as i128) >> e.sew) as u64,
+            // vmadd, vnmsub, vmacc, vnmsac
+            0x29 => |a, b, d, _| b.wrapping_mul(d).wrapping_add(a),
+            0x2b => |a, b, d, _| a.wrapping_sub(b.wrapping_mul(d)),
+            0x2d => |a, b, d, _| b.wrapping_mul(a).wrapping_add(d),
+            0x2f => |a, b, d, _| d.wrapping_sub(b.wrapping_mul(a)),
+            _ => return None,
+        },
+        _ => return None,
+    };
+    Some(op)
+}
+
+/// The integer comparisons, writing a mask, by category and funct6.
+fn int_compare(funct3: u32, funct6: u32) -> Option<Compare> {
+    let (vv, vi) = (funct3 == OPIVV, funct3 == OPIVI);
+    if !matches!(funct3, OPIVV | OPIVX | OPIVI) {
+        return None;
+    }
+    let compare: Compare = match funct6 {
+        0x18 => |a, b, _| a == b,
+        0x19 => |a, b, _| a != b,
+        0x1a if !vi => |a, b, _| a < b,
+        0x1b if !vi => |a, b, e| e.sext(a) < e.sext(b),
+        0x1c => |a, b, _| a <= b,
+        0x1d => |a, b, e| e.sext(a) <= e.sext(b),
+        0x1e if !vv => |a, b, _| a > b,
+        0x1f if !vv => |a, b, e| e.sext(a) > e.sext(b),
+        _ => return None,
+    };
+    Some(compare)
+}
+
+/// The single-width floating-point arithmetic, by category and funct6.
+fn fp_op<F: Float>(funct3: u32, funct6: u32) -> Option<FpOp<F>> {
+    let vf = funct3 == OPFVF;
+    let op: FpOp<F> = match funct6 {
+        0x00 => |a, b, _, rm| add(a, b, rm),
+        0x02 => |a, b, _, rm| add(a, -b, rm),
+        0x27 if vf => |a, b, _, rm| add(b, -a, rm),
+        0x24 => |a, b, _, rm| mul(a, b, rm),
+        0x20 => |a, b, _, rm| div(a, b, rm),
+        0x21 if vf => |a, b, _, rm| div(b, a, rm),
+        0x04 => |a, b, _, _| min_max(a, b, false),
+        0x06 => |a, b, _, _| min_max(a, b, true),
+        // vfsgnj, vfsgnjn, vfsgnjx
+        0x08 => |a, b, _, _| (with_sign(a, b.is_sign_negative()), 0),
+        0x09 => |a, b, _, _| (with_sign(a, !b.is_sign_negative()), 0),
+        0x0a => |a, b, _, _| (with_sign(a, a.is_sign_negative() != b.is_sign_negative()), 0),
+        // vfmadd, vfnmadd, vfmsub, vfnmsub overwrite the multiplicand,
+        // vfmacc, vfnmacc, vfmsac, vfnmsac the addend.
+        0x28 => |a, b, d, rm| fma(b, d, a, rm),
+        0x29 => |a, b, d, rm| fma(-b, d, -a, rm),
+        0x2a => |a, b, d, rm| fma(b, d, -a, rm),
+        0x2b => |a, b, d, rm| fma(-b, d, a, rm),
+        0x2c => |a, b, d, rm| fma(b, a, d, rm),
+        0x2d => |a, b, d, rm| fma(-b, a, -d, rm),
+        0x2e => |a, b, d, rm| fma(b, a, -d, rm),
+        0x2f => |a, b, d, rm| fma(-b, a, d, rm),
+        _ => return None,
+    };
+    Some(op)
+}
+
+/// Reads a floating-point element.
+fn from_element<F: Float>(raw: u64) -> F {
+    // NaN-boxed, so that the full register value is taken as it is
+    F::from_reg(raw | !ones(F::BITS))
+}
+
+fn to_element<F: Float>(value: F) -> u64 {
+    value.to_reg() & ones(F::BITS)
+}
+
+/// `a` with its sign bit set to `negative`, even for NaNs.
+fn with_sign<F: Float>(a: F, negative: bool) -> F {
+    if a.is_sign_negative() == negative {
+        a
+    } else {
+        -a
+    }
+}
+
+/// Converts a single-precision value to double precision, which is exact
+/// apart from NaNs.
+fn widen(a: f32) -> (f64, u64) {
+    if a.is_nan() {
+        (f64::canonical_nan(), if a.is_snan() { NV } else { 0 })
+    } else {
+        (a as f64, 0)
+    }
+}
+
+/// Rounds a double to single precision by truncating it and setting the
+/// lowest bit of an inexact result, for vfncvt.rod.f.f.w.
+fn narrow_odd(a: f64) -> (f32, u64) {
+    if !a.is_finite() {
+        return narrow(a, RNE);
+    }
+    let mut r = a as f32;
+    if r as f64 == a {
+        return (r, 0);
+    }
+    if (r as f64).abs() > a.abs() {
+        r = f32::from_bits(r.to_bits() - 1);
+    }
+    let r = f32::from_bits(r.to_bits() | 1);
+    let flags = if a.abs() > f32::MAX as f64 {
+        OF | NX
+    } else if r.abs() < f32::MIN_POSITIVE {
+        UF | NX
+    } else {
+        NX
+    };
+    (r, flags)
+}
+
+/// The exponent and significand widths of single and double precision.
+fn float_format(bits: u32) -> (u32, u32) {
+    if bits == 32 {
+        (8, 23)
+    } else {
+        (11, 52)
+    }
+}
+
+/// The biased exponent and the significand without its leading one of a
+/// finite non-zero value, shifting subnormals up so that their exponent
+/// goes below 1.
+fn normalize(raw: u64, bits: u32) -> (i64, u64) {
+    let (exp_bits, sig_bits) = float_format(bits);
+    let mut exp = ((raw >> sig_bits) & ones(exp_bits)) as i64;
+    let mut sig = raw & ones(sig_bits);
+    if exp == 0 {
+        while sig & (1 << (sig_bits - 1)) == 0 {
+            sig <<= 1;
+            exp -= 1;
+        }
+        sig = (sig << 1) & ones(sig_bits);
+    }
+    (exp, sig)
+}
+
+/// vfrec7: an estimate of 1/a to 7 bits.
+fn reciprocal_estimate<F: Float>(a: F, rm: u64) -> (F, u64) {
+    let (exp_bits, sig_bits) = float_format(F::BITS);
+    let raw = to_element(a);
+    let sign = raw & (1 << (F::BITS - 1));
+    let infinity = ones(exp_bits) << sig_bits;
+    if a.is_nan() {
+        return (F::canonical_nan(), if a.is_snan() { NV } else { 0 });
+    }
+    if a.is_infinite() {
+        return (from_element(sign), 0);
+    }
+    if a == F::ZERO {
+        return (from_element(sign | infinity), DZ);
+    }
+
+    let (exp, sig) = normalize(raw, F::BITS);
+    let bias = ones(exp_bits - 1) as i64;
+    let mut out_exp = 2 * bias - 1 - exp;
+    if out_exp > 2 * bias {
+        // The reciprocal of a tiny subnormal overflows, to infinity or the
+        // largest finite value depending on the rounding direction.
+        let to_infinity = match rm {
+            RTZ => false,
+            RDN => sign != 0,
+            RUP => sign == 0,
+            _ => true,
+        };
+        let r = if to_infinity { infinity } else { infinity - 1 };
+        return (from_element(sign | r), OF | NX);
+    }
+    let mut out_sig = (RECIPROCAL_ESTIMATES[(sig >> (sig_bits - 7)) as usize] as u64) << (sig_bits - 7);
+    if out_exp <= 0 {
+        // A subnormal result
+        out_sig = (out_sig | (1 << sig_bits)) >> (1 - out_exp);
+        out_exp = 0;
+    }
+    (from_element(sign | ((out_exp as u64) << sig_bits) | out_sig), 0)
+}
+
+/// vfrsqrt7: an estimate of 1/sqrt(a) to 7 bits.
+fn rsqrt_estimate<F: Float>(a: F) -> (F, u64) {
+    let (exp_bits, sig_bits) = float_format(F::BITS);
+    let raw = to_element(a);
+    let infinity = ones(exp_bits) << sig_bits;
+    if a.is_nan() {
+        return (F::canonical_nan(), if a.is_snan() { NV } else { 0 });
+    }
+    if a == F::ZERO {
+        return (from_element((raw & (1 << (F::BITS - 1))) | infinity), DZ);
+    }
+    if a.is_sign_negative() {
+        return (F::canonical_nan(), NV);
+    }
+    if a.is_infinite() {
+        return (F::ZERO, 0);
+    }
+
+    let (exp, sig) = normalize(raw, F::BITS);
+    let bias = ones(exp_bits - 1) as i64;
+    let index = (((exp & 1) as u64) << 6) | (sig >> (sig_bits - 6));
+    let out_sig = (RSQRT_ESTIMATES[index as usize] as u64) << (sig_bits - 7);
+    let out_exp = (3 * bias - 1 - exp) / 2;
+    (from_element(((out_exp as u64) << sig_bits) | out_sig), 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::*;
+    use crate::testing::*;
+
+    const TA: u32 = 1 << 6;
+    const MA: u32 = 1 << 7;
+    const E8: u32 = 0 << 3;
+    const E32: u32 = 2 << 3;
+    const E64: u32 = 3 << 3;
+    const M2: u32 = 1;
+    const MF8: u32 = 5;
+
+    fn hart(config: &MachineConfig) -> Cpu {
+        let mut cpu = machine(config, &[]).harts.remove(0);
+        cpu.csrs[MSTATUS] |= MSTATUS_VS;
+        cpu
+    }
+
+    fn vsetvli(rd: u32, rs1: u32, vtype: u32) -> u32 {
+        i_type(0x57, rd, OPCFG, rs1, vtype as i32)
+    }
+
+    fn vsetivli(rd: u32, avl: u32, vtype: u32) -> u32 {
+        0b11 << 30 | i_type(0x57, rd, OPCFG, avl, vtype as i32)
+    }
+
+    /// An OP-V instruction, unmasked unless `vm` is false.
+    fn op_v(funct6: u32, funct3: u32, vd: u32, vs2: u32, rs1: u32, vm: bool) -> u32 {
+        r_type(0x57, vd, funct3, rs1, vs2, funct6 << 1 | vm as u32)
+    }
+
+    /// Sets vtype and vl, checking the vl it gets.
+    fn configure(cpu: &mut Cpu, avl: u64, vtype: u32, vl: u64) {
+        cpu.regs[T0 as usize] = avl;
+        cpu.execute(vsetvli(A0, T0, vtype)).unwrap();
+        assert_eq!((cpu.regs[A0 as usize], cpu.csrs[VL]), (vl, vl), "vtype {:#x}", vtype);
+    }
+
+    fn set(cpu: &mut Cpu, reg: usize, eew: u32, values: &[u64]) {
+        for (i, &value) in values.iter().enumerate() {
+            cpu.set_velem(reg, i, eew, value);
+        }
+    }
+
+    fn get(cpu: &Cpu, reg: usize, eew: u32, count: usize) -> Vec<u64> {
+        (0..count).map(|i| cpu.velem(reg, i, eew)).collect()
+    }
+
+    #[test]
+    fn vsetvl_picks_vl_from_vlmax() {
+        let cpu = &mut hart(&MachineConfig::default());
+        assert_eq!(cpu.load_csr(VLENB), 16);
+        configure(cpu, 10, E32, 4);
+        configure(cpu, 10, E8 | M2, 10);
+        configure(cpu, 100, E8 | M2, 32);
+        configure(cpu, 3, E64 | TA | MA, 2);
+        assert_eq!(cpu.csrs[VTYPE], (E64 | TA | MA) as u64);
+
+        // x0 as AVL asks for VLMAX, and keeps vl when rd is x0 too.
+        cpu.execute(vsetvli(A0, ZERO, E32)).unwrap();
+        assert_eq!(cpu.csrs[VL], 4);
+        cpu.execute(vsetvli(ZERO, ZERO, E8)).unwrap();
+        assert_eq!(cpu.csrs[VL], 4);
+        cpu.execute(vsetivli(A0, 7, E8)).unwrap();
+        assert_eq!(cpu.regs[A0 as usize], 7);
+
+        // SEW wider than ELEN, or than a fractional LMUL holds, sets vill.
+        for vtype in [4 << 3, E64 | MF8, 4, 1 << 8] {
+            configure(cpu, 10, vtype, 0);
+            assert_eq!(cpu.csrs[VTYPE], 1 << 63, "vtype {:#x}", vtype);
+            assert!(cpu.execute(op_v(0x00, OPIVV, 1, 2, 3, true)).is_err());
+        }
+
+        let cpu = &mut hart(&MachineConfig { vlen: 256, elen: 32, ..Default::default() });
+        configure(cpu, 100, E32, 8);
+        configure(cpu, 100, E64, 0);
+    }
+
+    #[test]
+    fn masks_and_tails_follow_the_policy() {
+        let cpu = &mut hart(&MachineConfig::default());
+        set(cpu, 0, 8, &[0b0101]);
+        set(cpu, 2, 32, &[1, 2, 3, 4]);
+        set(cpu, 3, 32, &[10, 20, 30, 40]);
+
+        // Undisturbed: masked-off and tail elements are kept.
+        configure(cpu, 3, E32, 3);
+        set(cpu, 1, 32, &[7, 7, 7, 7]);
+        cpu.execute(op_v(0x00, OPIVV, 1, 2, 3, false)).unwrap(); // vadd.vv v1, v2, v3, v0.t
+        assert_eq!(get(cpu, 1, 32, 4), [11, 7, 33, 7]);
+
+        // Agnostic: here they become all ones.
+        configure(cpu, 3, E32 | TA | MA, 3);
+        cpu.execute(op_v(0x00, OPIVV, 1, 2, 3, false)).unwrap();
+        assert_eq!(get(cpu, 1, 32, 4), [11, 0xffff_ffff, 33, 0xffff_ffff]);
+
+        configure(cpu, 4, E32, 4);
+        cpu.execute(op_v(0x00, OPIVI, 1, 2, 0x1d, true)).unwrap(); // vadd.vi v1, v2, -3
+        assert_eq!(get(cpu, 1, 32, 4), [0xffff_fffe, 0xffff_ffff, 0, 1]);
+        cpu.regs[T0 as usize] = 3;
+        cpu.execute(op_v(0x25, OPMVX, 1, 3, T0, true)).unwrap(); // vmul.vx v1, v3, t0
+        assert_eq!(get(cpu, 1, 32, 4), [30, 60, 90, 120]);
+        set(cpu, 4, 32, &[1000]);
+        cpu.execute(op_v(0x00, OPMVV, 5, 3, 4, true)).unwrap(); // vredsum.vs v5, v3, v4
+        assert_eq!(get(cpu, 5, 32, 1), [1100]);
+        cpu.execute(op_v(0x18, OPIVI, 6, 2, 3, true)).unwrap(); // vmseq.vi v6, v2, 3
+        assert_eq!(get(cpu, 6, 8, 1), [0b1111_0100]);
+    }
+
+    #[test]
+    fn saturating_adds_set_vxsat() {
+        let cpu = &mut hart(&MachineConfig::default());
+        configure(cpu, 2, E8, 2);
+        set(cpu, 2, 8, &[250, 1]);
+        set(cpu, 3, 8, &[10, 2]);
+        cpu.execute(op_v(0x20, OPIVV, 1, 2, 3, true)).unwrap(); // vsaddu.vv v1, v2, v3
+        assert_eq!(get(cpu, 1, 8, 2), [255, 3]);
+        assert_eq!(cpu.load_csr(VXSAT), 1);
+        assert_eq!(cpu.load_csr(VCSR), 1);
+    }
+
+    #[test]
+    fn register_groups_must_fit() {
+        let cpu = &mut hart(&MachineConfig::default());
+        configure(cpu, 8, E32 | M2, 8);
+        // An odd register cannot start a group of two.
+        let inst = op_v(0x00, OPIVV, 1, 2, 4, true);
+        assert_eq!(cpu.execute(inst), Err(Exception::IllegalInstruction(inst as u64)));
+        cpu.execute(op_v(0x00, OPIVV, 6, 2, 4, true)).unwrap();
+
+        // vwaddu.vv writes a group of four, which may not overlap the low
+        // part of a source.
+        let inst = op_v(0x30, OPMVV, 4, 4, 2, true);
+        assert_eq!(cpu.execute(inst), Err(Exception::IllegalInstruction(inst as u64)));
+        cpu.execute(op_v(0x30, OPMVV, 8, 4, 2, true)).unwrap();
+    }
+
+    #[test]
+    fn loads_and_stores_elements() {
+        let config = MachineConfig::default();
+        let cpu = &mut hart(&config);
+        let data = config.dram_base + 0x1000;
+        for i in 0..8 {
+            cpu.store(data + 4 * i, 32, 0x100 + i).unwrap();
+        }
+        configure(cpu, 4, E32, 4);
+        cpu.regs[T0 as usize] = data;
+        cpu.regs[T1 as usize] = 8;
+        let vle32 = |vd: u32| 1 << 25 | T0 << 15 | 6 << 12 | vd << 7 | 0x07;
+        cpu.execute(vle32(1)).unwrap();
+        assert_eq!(get(cpu, 1, 32, 4), [0x100, 0x101, 0x102, 0x103]);
+        cpu.execute(2 << 26 | T1 << 20 | vle32(2)).unwrap(); // vlse32.v v2, (t0), t1
+        assert_eq!(get(cpu, 2, 32, 4), [0x100, 0x102, 0x104, 0x106]);
+        cpu.regs[T0 as usize] = data + 0x100;
+        cpu.execute(vle32(2) | 0x20).unwrap(); // vse32.v v2, (t0)
+        assert_eq!(cpu.load(data + 0x10c, 32), Ok(0x106));
+
+        // A fault part way leaves vstart at the element, and what came
+        // before it loaded.
+        let end = config.dram_end();
+        cpu.regs[T0 as usize] = end - 8;
+        set(cpu, 3, 32, &[0; 4]);
+        cpu.store(end - 4, 32, 0x55).unwrap();
+        assert_eq!(cpu.execute(vle32(3)), Err(Exception::LoadAccessFault(end)));
+        assert_eq!(cpu.csrs[VSTART], 2);
+        assert_eq!(get(cpu, 3, 32, 4), [0, 0x55, 0, 0]);
+    }
+
+    #[test]
+    fn off_in_mstatus_is_illegal() {
+        let cpu = &mut hart(&MachineConfig::default());
+        cpu.csrs[MSTATUS] &= !MSTATUS_VS;
+        let inst = vsetvli(A0, ZERO, E32);
+        assert_eq!(cpu.execute(inst), Err(Exception::IllegalInstruction(inst as u64)));
+        cpu.csrs[MSTATUS] |= MSTATUS_VS & (MSTATUS_VS >> 1);
+        cpu.execute(inst).unwrap();
+        assert_eq!(cpu.csrs[MSTATUS] & MSTATUS_VS, MSTATUS_VS);
+    }
+}
@@ -0,0 +1,687 @@
+use std::io;
+use std::path::Path;
+
+use crate::device::*;
+use crate::dram::*;
+use crate::exception::*;
+use crate::fdt::*;
+use crate::snapshot::*;
+
+/// The virtio-mmio transports sit one per 4 KiB page from here, as on QEMU's
+/// `virt` board.
+pub const VIRTIO_BASE: u64 = 0x1000_1000;
+pub const VIRTIO_SIZE: u64 = 0x1000;
+pub const VIRTIO_SLOTS: usize = 8;
+/// PLIC source of the first transport; the others follow.
+pub const VIRTIO_IRQ: u32 = 1;
+
+/// Offered by every device: the driver follows virtio 1.0 or later.
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+// Register offsets
+const MAGIC_VALUE: u64 = 0x000;
+const VERSION: u64 = 0x004;
+const DEVICE_ID: u64 = 0x008;
+const VENDOR_ID: u64 = 0x00c;
+const DEVICE_FEATURES: u64 = 0x010;
+const DEVICE_FEATURES_SEL: u64 = 0x014;
+const DRIVER_FEATURES: u64 = 0x020;
+const DRIVER_FEATURES_SEL: u64 = 0x024;
+const QUEUE_SEL: u64 = 0x030;
+const QUEUE_NUM_MAX: u64 = 0x034;
+const QUEUE_NUM: u64 = 0x038;
+const QUEUE_READY: u64 = 0x044;
+const QUEUE_NOTIFY: u64 = 0x050;
+const INTERRUPT_STATUS: u64 = 0x060;
+const INTERRUPT_ACK: u64 = 0x064;
+const STATUS: u64 = 0x070;
+const QUEUE_DESC_LOW: u64 = 0x080;
+const QUEUE_DESC_HIGH: u64 = 0x084;
+const QUEUE_DRIVER_LOW: u64 = 0x090;
+const QUEUE_DRIVER_HIGH: u64 = 0x094;
+const QUEUE_DEVICE_LOW: u64 = 0x0a0;
+const QUEUE_DEVICE_HIGH: u64 = 0x0a4;
+const CONFIG: u64 = 0x100;
+
+const MAGIC: u32 = 0x7472_6976; // "virt"
+const TRANSPORT_VERSION: u32 = 2;
+const VENDOR: u32 = 0x6d65_7672; // "rvem"
+
+const STATUS_DRIVER_OK: u32 = 4;
+const STATUS_FEATURES_OK: u32 = 8;
+const STATUS_DEVICE_NEEDS_RESET: u32 = 64;
+
+const INTERRUPT_USED_BUFFER: u32 = 1 << 0;
+const INTERRUPT_CONFIG_CHANGE: u32 = 1 << 1;
+
+const QUEUE_SIZE_MAX: u32 = 256;
+
+const VIRTQ_DESC_F_NEXT: u16 = 1;
+const VIRTQ_DESC_F_WRITE: u16 = 2;
+
+/// The device side of a virtio device, behind a virtio-mmio transport.
+pub trait VirtioDevice: Send {
+    fn device_id(&self) -> u32;
+
+    /// A short name for the device, as in `virtio-blk`.
+    fn name(&self) -> &'static str;
+
+    /// Device-specific feature bits. `VIRTIO_F_VERSION_1` is added by the
+    /// transport.
+    fn features(&self) -> u64;
+
+    fn queues(&self) -> usize;
+
+    /// The device configuration space.
+    fn config(&self) -> Vec<u8>;
+
+    /// Handles a notification from the driver: takes the chains it made
+    /// available on `queue` and returns them used.
+    fn notify(&mut self, queue: usize, vq: &mut Virtqueue, dram: &mut Dram);
+
+    /// Moves data that arrived on the host side into the queues. Called
+    /// regularly once the driver is ready.
+    fn poll(&mut self, _queues: &mut [Virtqueue], _dram: &mut Dram) {}
+
+    /// Returns the device to its initial state after the driver resets it.
+    fn reset(&mut self) {}
+
+    /// Stops or resumes output to the host, like `Device::mute`.
+    fn mute(&mut self, _muted: bool) {}
+
+    /// Writes the device-specific state to a snapshot.
+    fn save(&self, _out: &mut StateWriter) {}
+
+    fn restore(&mut self, _input: &mut StateReader) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A split virtqueue, as set up by the driver.
+#[derive(Clone, Default)]
+pub struct Virtqueue {
+    num: u32,
+    ready: bool,
+    desc: u64,
+    avail: u64,
+    used: u64,
+    /// The next entry of the available ring to take.
+    last_avail: u16,
+    /// The next entry of the used ring to fill.
+    used_idx: u16,
+    /// Set when buffers were returned since the transport last checked.
+    returned: bool,
+    /// Set when the driver handed over a descriptor outside memory, or a
+    /// chain that loops or is longer than memory.
+    broken: bool,
+}
+
+/// A chain of descriptors: buffers the device reads, then buffers it writes.
+/// Every buffer has been checked to lie in DRAM.
+pub struct Chain {
+    head: u16,
+    readable: Vec<(u64, u32)>,
+    writable: Vec<(u64, u32)>,
+}
+
+impl Virtqueue {
+    fn save(&self, out: &mut StateWriter) {
+        out.u32(self.num);
+        out.bool(self.ready);
+        out.u64(self.desc);
+        out.u64(self.avail);
+        out.u64(self.used);
+        out.u16(self.last_avail);
+        out.u16(self.used_idx);
+        out.bool(self.returned);
+        out.bool(self.broken);
+    }
+
+    fn restore(&mut self, input: &mut StateReader) -> io::Result<()> {
+        self.num = input.u32()?;
+        self.ready = input.bool()?;
+        self.desc = input.u64()?;
+        self.avail = input.u64()?;
+        self.used = input.u64()?;
+        self.last_avail = input.u16()?;
+        self.used_idx = input.u16()?;
+        self.returned = input.bool()?;
+        self.broken = input.bool()?;
+        Ok(())
+    }
+
+    fn read16(dram: &Dram, addr: u64) -> Option<u16> {
+        dram.slice(addr, 2).map(|b| u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    /// Takes the next chain the driver made available, if any.
+    pub fn pop(&mut self, dram: &Dram) -> Option<Chain> {
+        if !self.ready || self.broken || self.num == 0 {
+            return None;
+        }
+        let slot = self.last_avail as u64 % self.num as u64;
+        let (Some(avail_idx), Some(head)) = (
+            Self::read16(dram, self.avail + 2),
+            Self::read16(dram, self.avail + 4 + 2 * slot),
+        ) else {
+            self.broken = true;
+            return None;
+        };
+        if avail_idx == self.last_avail {
+            return None;
+        }
+        self.last_avail = self.last_avail.wrapping_add(1);
+
+        let mut chain = Chain {
+            head,
+            readable: Vec::new(),
+            writable: Vec::new(),
+        };
+        let mut index = head;
+        let mut total = 0;
+        // A well-formed chain visits every descriptor at most once.
+        for _ in 0..self.num {
+            if index as u32 >= self.num {
+                break;
+            }
+            let Some(desc) = dram.slice(self.desc + 16 * index as u64, 16) else {
+                break;
+            };
+            let addr = u64::from_le_bytes(desc[0..8].try_into().unwrap());
+            let len = u32::from_le_bytes(desc[8..12].try_into().unwrap());
+            let flags = u16::from_le_bytes([desc[12], desc[13]]);
+            let next = u16::from_le_bytes([desc[14], desc[15]]);
+            if dram.slice(addr, len as u64).is_none() {
+                break;
+            }
+            // The buffers may overlap, but a chain that adds up to more than
+            // memory can only be meant to make the device allocate.
+            total += len as u64;
+            if total > dram.size() {
+                break;
+            }
+            if flags & VIRTQ_DESC_F_WRITE != 0 {
+                chain.writable.push((addr, len));
+            } else {
+                chain.readable.push((addr, len));
+            }
+            if flags & VIRTQ_DESC_F_NEXT == 0 {
+                return Some(chain);
+            }
+            index = next;
+        }
+
+        self.broken = true;
+        None
+    }
+
+    /// Returns a chain to the driver, with `len` bytes written to it.
+    pub fn push(&mut self, dram: &mut Dram, chain: Chain, len: u32) {
+        let slot = self.used_idx as u64 % self.num as u64;
+        let elem = [(chain.head as u32).to_le_bytes(), len.to_le_bytes()].concat();
+        self.used_idx = self.used_idx.wrapping_add(1);
+        self.returned = true;
+        match dram.slice_mut(self.used + 4 + 8 * slot, 8) {
+            Some(bytes) => bytes.copy_from_slice(&elem),
+            None => self.broken = true,
+        }
+        match dram.slice_mut(self.used + 2, 2) {
+            Some(bytes) => bytes.copy_from_slice(&self.used_idx.to_le_bytes()),
+            None => self.broken = true,
+        }
+    }
+}
+
+impl Chain {
+    pub fn readable_len(&self) -> u64 {
+        self.readable.iter().map(|&(_, len)| len as u64).sum()
+    }
+
+    pub fn writable_len(&self) -> u64 {
+        self.writable.iter().map(|&(_, len)| len as u64).sum()
+    }
+
+    /// Everything the driver gave the device to read, in order.
+    pub fn read(&self, dram: &Dram) -> Vec<u8> {
+        let mut data = Vec::with_capacity(self.readable_len() as usize);
+        for &(addr, len) in &self.readable {
+            data.extend_from_slice(dram.slice(addr, len as u64).unwrap());
+        }
+        data
+    }
+
+    /// Copies `data` into the writable buffers, starting `offset` bytes in.
+    /// Returns how many bytes fitted.
+    pub fn write(&self, dram: &mut Dram, mut offset: u64, mut data: &[u8]) -> u64 {
+        let mut written = 0;
+        for &(addr, len) in &self.writable {
+            let len = len as u64;
+            if offset >= len {
+                offset -= len;
+                continue;
+            }
+            let count = (len - offset).min(data.len() as u64);
+            dram.slice_mut(addr + offset, count)
+                .unwrap()
+                .copy_from_slice(&data[..count as usize]);
+            data = &data[count as usize..];
+            written += count;
+            offset = 0;
+        }
+        written
+    }
+}
+
+/// A virtio-mmio transport, version 2, in one of the slots.
+pub struct VirtioMmio {
+    slot: usize,
+    device: Box<dyn VirtioDevice>,
+    device_features_sel: u32,
+    driver_features: u64,
+    driver_features_sel: u32,
+    queue_sel: u32,
+    queues: Vec<Virtqueue>,
+    /// Queues notified since the last call to `dma`.
+    notified: u64,
+    interrupt_status: u32,
+    status: u32,
+}
+
+impl VirtioMmio {
+    pub fn new(slot: usize, device: Box<dyn VirtioDevice>) -> Self {
+        let queues = vec![Virtqueue::default(); device.queues()];
+        Self {
+            slot,
+            device,
+            device_features_sel: 0,
+            driver_features: 0,
+            driver_features_sel: 0,
+            queue_sel: 0,
+            queues,
+            notified: 0,
+            interrupt_status: 0,
+            status: 0,
+        }
+    }
+
+    fn features(&self) -> u64 {
+        self.device.features() | VIRTIO_F_VERSION_1
+    }
+
+    fn queue(&mut self) -> Option<&mut Virtqueue> {
+        self.queues.get_mut(self.queue_sel as usize)
+    }
+
+    fn reset(&mut self) {
+        self.device_features_sel = 0;
+        self.driver_features = 0;
+        self.driver_features_sel = 0;
+        self.queue_sel = 0;
+        self.queues = vec![Virtqueue::default(); self.device.queues()];
+        self.notified = 0;
+        self.interrupt_status = 0;
+        self.status = 0;
+        self.device.reset();
+    }
+}
+
+/// Prefixes an error opening or using a host file with its path.
+pub fn with_path(path: &Path, e: io::Error) -> io::Error {
+    io::Error::new(e.kind(), format!("{}: {}", path.display(), e))
+}
+
+/// Sets the low or high half of a 64-bit address.
+fn set_half(reg: &mut u64, high: bool, value: u64) {
+    let shift = if high { 32 } else { 0 };
+    *reg = (*reg & !(0xffff_ffff << shift)) | ((value & 0xffff_ffff) << shift);
+}
+
+impl Device for VirtioMmio {
+    fn base(&self) -> u64 {
+        VIRTIO_BASE + self.slot as u64 * VIRTIO_SIZE
+    }
+
+    fn size(&self) -> u64 {
+        VIRTIO_SIZE
+    }
+
+    fn load(&mut self, offset: u64, size: u64) -> Result<u64, Exception> {
+        if offset >= CONFIG {
+            let config = self.device.config();
+            let value = (0..size / 8)
+                .map(|i| *config.get((offset - CONFIG + i) as usize).unwrap_or(&0) as u64)
+                .rev()
+                .fold(0, |value, byte| (value << 8) | byte);
+            return Ok(value);
+        }
+        // The other registers are 32 bits wide and only accessed whole.
+        if size != 32 || offset & 3 != 0 {
+            return Ok(0);
+        }
+
+        let queue = self.queues.get(self.queue_sel as usize);
+        let value = match offset {
+            MAGIC_VALUE => MAGIC,
+            VERSION => TRANSPORT_VERSION,
+            DEVICE_ID => self.device.device_id(),
+            VENDOR_ID => VENDOR,
+            DEVICE_FEATURES => match self.device_features_sel {
+                0 => self.features() as u32,
+                1 => (self.features() >> 32) as u32,
+                _ => 0,
+            },
+            QUEUE_NUM_MAX => queue.map_or(0, |_| QUEUE_SIZE_MAX),
+            QUEUE_READY => queue.map_or(0, |q| q.ready as u32),
+            INTERRUPT_STATUS => self.interrupt_status,
+            STATUS => self.status,
+            _ => 0,
+        };
+        Ok(value as u64)
+    }
+
+    fn store(&mut self, offset: u64, size: u64, value: u64) -> Result<(), Exception> {
+        if offset >= CONFIG || size != 32 || offset & 3 != 0 {
+            // No device lets the driver write its configuration.
+            return Ok(());
+        }
+
+        let value32 = value as u32;
+        match offset {
+            DEVICE_FEATURES_SEL => self.device_features_sel = value32,
+            DRIVER_FEATURES => match self.driver_features_sel {
+                0 => set_half(&mut self.driver_features, false, value),
+                1 => set_half(&mut self.driver_features, true, value),
+                _ => {}
+            },
+            DRIVER_FEATURES_SEL => self.driver_features_sel = value32,
+            QUEUE_SEL => self.queue_sel = value32,
+            QUEUE_NUM => {
+                if let Some(queue) = self.queue() {
+                    // Queue sizes are powers of two no larger than the maximum.
+                    if value32.is_power_of_two() && value32 <= QUEUE_SIZE_MAX {
+                        queue.num = value32;
+                    }
+                }
+            }
+            QUEUE_READY => {
+                if let Some(queue) = self.queue() {
+                    queue.ready = value32 & 1 != 0;
+                }
+            }
+            QUEUE_NOTIFY if (value32 as usize) < self.queues.len() => self.notified |= 1 << value32,
+            INTERRUPT_ACK => self.interrupt_status &= !value32,
+            STATUS => {
+                if value32 == 0 {
+                    self.reset();
+                } else if self.driver_features & !self.features() != 0 {
+                    // Features the device does not offer cannot be accepted.
+                    self.status = value32 & !STATUS_FEATURES_OK;
+                } else {
+                    self.status = value32;
+                }
+            }
+            QUEUE_DESC_LOW | QUEUE_DESC_HIGH => {
+                if let Some(queue) = self.queue() {
+                    set_half(&mut queue.desc, offset == QUEUE_DESC_HIGH, value);
+                }
+            }
+            QUEUE_DRIVER_LOW | QUEUE_DRIVER_HIGH => {
+                if let Some(queue) = self.queue() {
+                    set_half(&mut queue.avail, offset == QUEUE_DRIVER_HIGH, value);
+                }
+            }
+            QUEUE_DEVICE_LOW | QUEUE_DEVICE_HIGH => {
+                if let Some(queue) = self.queue() {
+                    set_half(&mut queue.used, offset == QUEUE_DEVICE_HIGH, value);
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn dma(&mut self, dram: &mut Dram) {
+        while self.notified != 0 {
+            let index = self.notified.trailing_zeros() as usize;
+            self.notified &= !(1 << index);
+
+            self.device.notify(index, &mut self.queues[index], dram);
+        }
+        if self.status & STATUS_DRIVER_OK != 0 {
+            self.device.poll(&mut self.queues, dram);
+        }
+
+        for queue in self.queues.iter_mut() {
+            if std::mem::take(&mut queue.returned) {
+                self.interrupt_status |= INTERRUPT_USED_BUFFER;
+            }
+            if queue.broken && self.status & STATUS_DEVICE_NEEDS_RESET == 0 {
+                self.status |= STATUS_DEVICE_NEEDS_RESET;
+                self.interrupt_status |= INTERRUPT_CONFIG_CHANGE;
+            }
+        }
+    }
+
+    fn irq(&self) -> Option<u32> {
+        Some(VIRTIO_IRQ + self.slot as u32)
+    }
+
+    fn mute(&mut self, muted: bool) {
+        self.device.mute(muted);
+    }
+
+    fn save(&self, out: &mut StateWriter) {
+        out.u32(self.device.device_id());
+        out.u32(self.device_features_sel);
+        out.u64(self.driver_features);
+        out.u32(self.driver_features_sel);
+        out.u32(self.queue_sel);
+        out.u64(self.queues.len() as u64);
+        for queue in &self.queues {
+            queue.save(out);
+        }
+        out.u64(self.notified);
+        out.u32(self.interrupt_status);
+        out.u32(self.status);
+        self.device.save(out);
+    }
+
+    fn restore(&mut self, input: &mut StateReader) -> io::Result<()> {
+        if input.u32()? != self.device.device_id() {
+            return Err(invalid_snapshot(&format!("different virtio device in slot {}", self.slot)));
+        }
+        self.device_features_sel = input.u32()?;
+        self.driver_features = input.u64()?;
+        self.driver_features_sel = input.u32()?;
+        self.queue_sel = input.u32()?;
+        input.count(self.queues.len(), "virtqueues")?;
+        for queue in self.queues.iter_mut() {
+            queue.restore(input)?;
+        }
+        self.notified = input.u64()?;
+        self.interrupt_status = input.u32()?;
+        self.status = input.u32()?;
+        self.device.restore(input)
+    }
+
+    fn interrupting(&mut self) -> bool {
+        self.interrupt_status != 0
+    }
+
+    fn dump_state(&self) -> Option<String> {
+        let mut state = format!(
+            "virtio-{} (slot {}): status={:#x} features={:#x} interrupt={:#x}",
+            self.device.name(),
+            self.slot, self.status, self.driver_features, self.interrupt_status
+        );
+        for (index, queue) in self.queues.iter().enumerate() {
+            state += &format!(
+                "\n  queue {}: num={} ready={} desc={:#x} avail={:#x} used={:#x} last_avail={} used_idx={}{}",
+                index,
+                queue.num,
+                queue.ready as u8,
+                queue.desc,
+                queue.avail,
+                queue.used,
+                queue.last_avail,
+                queue.used_idx,
+                if queue.broken { " broken" } else { "" }
+            );
+        }
+        Some(state)
+    }
+
+    fn device_tree(&self, fdt: &mut Fdt, _harts: usize) {
+        fdt.begin_node(&format!("virtio_mmio@{:x}", self.base()));
+        fdt.property_string("compatible", "virtio,mmio");
+        fdt.property_reg(self.base(), VIRTIO_SIZE);
+        fdt.property_interrupt(VIRTIO_IRQ + self.slot as u32);
+        fdt.end_node();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    /// Returns what it is given to read in the buffers it writes, on either
+    /// of its two queues.
+    struct Echo;
+
+    impl VirtioDevice for Echo {
+        fn device_id(&self) -> u32 {
+            42
+        }
+
+        fn name(&self) -> &'static str {
+            "echo"
+        }
+
+        fn features(&self) -> u64 {
+            1 << 3
+        }
+
+        fn queues(&self) -> usize {
+            2
+        }
+
+        fn config(&self) -> Vec<u8> {
+            vec![1, 2, 3, 4, 5]
+        }
+
+        fn notify(&mut self, _queue: usize, vq: &mut Virtqueue, dram: &mut Dram) {
+            while let Some(chain) = vq.pop(dram) {
+                let data = chain.read(dram);
+                let written = chain.write(dram, 0, &data);
+                vq.push(dram, chain, written as u32);
+            }
+        }
+    }
+
+    fn echo() -> VirtioMmio {
+        VirtioMmio::new(1, Box::new(Echo))
+    }
+
+    fn read(transport: &mut VirtioMmio, offset: u64) -> u32 {
+        transport.load(offset, 32).unwrap() as u32
+    }
+
+    #[test]
+    fn identifies_the_device() {
+        let mut transport = echo();
+        assert_eq!(transport.base(), VIRTIO_BASE + VIRTIO_SIZE);
+        assert_eq!(read(&mut transport, MAGIC_VALUE), MAGIC);
+        assert_eq!(read(&mut transport, VERSION), 2);
+        assert_eq!(read(&mut transport, DEVICE_ID), 42);
+        assert_eq!(read(&mut transport, VENDOR_ID), VENDOR);
+        // Registers are only read whole.
+        assert_eq!(transport.load(MAGIC_VALUE, 8), Ok(0));
+        // Configuration space reads may be any size, with zeros past its end.
+        assert_eq!(transport.load(CONFIG + 1, 16), Ok(0x0302));
+        assert_eq!(transport.load(CONFIG + 4, 32), Ok(5));
+    }
+
+    #[test]
+    fn negotiates_features() {
+        let mut transport = echo();
+        assert_eq!(read(&mut transport, DEVICE_FEATURES), 1 << 3);
+        transport.store(DEVICE_FEATURES_SEL, 32, 1).unwrap();
+        assert_eq!(read(&mut transport, DEVICE_FEATURES), 1);
+
+        // A feature the device does not offer is refused.
+        transport.store(DRIVER_FEATURES, 32, 1 << 4).unwrap();
+        transport.store(STATUS, 32, STATUS_FEATURES_OK as u64 | 3).unwrap();
+        assert_eq!(read(&mut transport, STATUS), 3);
+        transport.store(DRIVER_FEATURES, 32, 1 << 3).unwrap();
+        transport.store(STATUS, 32, STATUS_FEATURES_OK as u64 | 3).unwrap();
+        assert_eq!(read(&mut transport, STATUS), STATUS_FEATURES_OK | 3);
+    }
+
+    #[test]
+    fn sets_up_the_queues_it_has() {
+        let mut transport = echo();
+        transport.store(QUEUE_SEL, 32, 1).unwrap();
+        assert_eq!(read(&mut transport, QUEUE_NUM_MAX), QUEUE_SIZE_MAX);
+        transport.store(QUEUE_READY, 32, 1).unwrap();
+        assert_eq!(read(&mut transport, QUEUE_READY), 1);
+        transport.store(QUEUE_SEL, 32, 2).unwrap();
+        assert_eq!(read(&mut transport, QUEUE_NUM_MAX), 0);
+        transport.store(QUEUE_READY, 32, 1).unwrap();
+        assert_eq!(read(&mut transport, QUEUE_READY), 0);
+
+        // Writing 0 to the status resets everything.
+        transport.store(STATUS, 32, 0).unwrap();
+        transport.store(QUEUE_SEL, 32, 1).unwrap();
+        assert_eq!(read(&mut transport, QUEUE_READY), 0);
+    }
+
+    #[test]
+    fn returns_chains_and_interrupts() {
+        let mut driver = Driver::new(Box::new(Echo));
+        driver.submit(1, &[b"hello, ", b"world"], &[4, 20]);
+        assert_eq!(driver.used(1), None);
+        driver.notify(1);
+        let (len, data) = driver.used(1).unwrap();
+        assert_eq!(&data[..len as usize], b"hello, world");
+        assert!(driver.transport.interrupting());
+        assert_eq!(driver.read(INTERRUPT_STATUS), INTERRUPT_USED_BUFFER);
+        driver.write(INTERRUPT_ACK, INTERRUPT_USED_BUFFER);
+        assert!(!driver.transport.interrupting());
+
+        // Queue 0 is separate.
+        driver.submit(0, &[b"ping"], &[4]);
+        driver.notify(1);
+        assert_eq!(driver.used(0), None);
+        driver.notify(0);
+        assert_eq!(driver.used(0), Some((4, b"ping".to_vec())));
+    }
+
+    #[test]
+    fn needs_a_reset_after_a_bad_descriptor() {
+        let mut driver = Driver::new(Box::new(Echo));
+        driver.submit(0, &[b"ping"], &[4]);
+        // Point the buffer outside memory.
+        let desc = driver.dram.base;
+        driver.dram.store(desc, 64, 0x1000).unwrap();
+        driver.notify(0);
+        assert_eq!(driver.used(0), None);
+        assert_ne!(driver.read(STATUS) & STATUS_DEVICE_NEEDS_RESET, 0);
+        assert_eq!(driver.read(INTERRUPT_STATUS), INTERRUPT_CONFIG_CHANGE);
+    }
+    #[test]
+    fn needs_a_reset_after_a_chain_longer_than_memory() {
+        let mut driver = Driver::new(Box::new(Echo));
+        let size = driver.dram.size();
+        driver.submit(0, &[&b"ping"[..]; 16], &[]);
+        // Every descriptor covers all of memory.
+        for i in 0..16 {
+            let desc = driver.dram.base + 16 * i;
+            driver.dram.store(desc, 64, driver.dram.base).unwrap();
+            driver.dram.store(desc + 8, 32, size).unwrap();
+        }
+        driver.notify(0);
+        assert_eq!(driver.used(0), None);
+        assert_ne!(driver.read(STATUS) & STATUS_DEVICE_NEEDS_RESET, 0);
+    }
+}
@@ -0,0 +1,1016 @@
+use std::collections::HashMap;
+use std::fs::{self, DirBuilder, File, FileTimes, Metadata, OpenOptions};
+use std::io;
+use std::os::unix::fs::{DirBuilderExt, FileExt, MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::config::*;
+use crate::dram::*;
+use crate::snapshot::*;
+use crate::virtio::*;
+
+const VIRTIO_ID_9P: u32 = 9;
+
+const VIRTIO_9P_MOUNT_TAG: u64 = 1 << 0;
+
+const VERSION: &str = "9P2000.L";
+
+/// Largest message size offered to the driver.
+const MAX_MSIZE: u32 = 512 * 1024;
+
+/// Size, type and tag that start every message.
+const HEADER_SIZE: usize = 7;
+/// Header and count of an Rread.
+const RREAD_HEADER_SIZE: u32 = 11;
+
+// Message types. The reply to each T-message is the type after it.
+const TSTATFS: u8 = 8;
+const TLOPEN: u8 = 12;
+const TLCREATE: u8 = 14;
+const TSYMLINK: u8 = 16;
+const TRENAME: u8 = 20;
+const TREADLINK: u8 = 22;
+const TGETATTR: u8 = 24;
+const TSETATTR: u8 = 26;
+const TREADDIR: u8 = 40;
+const TFSYNC: u8 = 50;
+const TLOCK: u8 = 52;
+const TGETLOCK: u8 = 54;
+const TLINK: u8 = 70;
+const TMKDIR: u8 = 72;
+const TRENAMEAT: u8 = 74;
+const TUNLINKAT: u8 = 76;
+const TVERSION: u8 = 100;
+const TATTACH: u8 = 104;
+const TFLUSH: u8 = 108;
+const TWALK: u8 = 110;
+const TREAD: u8 = 116;
+const TWRITE: u8 = 118;
+const TCLUNK: u8 = 120;
+const TREMOVE: u8 = 122;
+const RLERROR: u8 = 7;
+
+// Error numbers, as on Linux
+const ENOENT: u32 = 2;
+const EIO: u32 = 5;
+const EBADF: u32 = 9;
+const EACCES: u32 = 13;
+const ENOTDIR: u32 = 20;
+const EINVAL: u32 = 22;
+const EOPNOTSUPP: u32 = 95;
+
+// Qid types
+const QTDIR: u8 = 0x80;
+const QTSYMLINK: u8 = 0x02;
+const QTFILE: u8 = 0x00;
+
+// Directory entry types
+const DT_DIR: u8 = 4;
+const DT_REG: u8 = 8;
+const DT_LNK: u8 = 10;
+
+// Open flags
+const O_ACCMODE: u32 = 3;
+const O_WRONLY: u32 = 1;
+const O_RDWR: u32 = 2;
+const O_EXCL: u32 = 0o200;
+const O_TRUNC: u32 = 0o1000;
+const O_APPEND: u32 = 0o2000;
+
+/// The host's O_NOFOLLOW, which moved on some architectures.
+#[cfg(any(target_arch = "aarch64", target_arch = "arm", target_arch = "powerpc64"))]
+const HOST_O_NOFOLLOW: i32 = 0o100000;
+#[cfg(not(any(target_arch = "aarch64", target_arch = "arm", target_arch = "powerpc64")))]
+const HOST_O_NOFOLLOW: i32 = 0o400000;
+
+const AT_REMOVEDIR: u32 = 0x200;
+
+// Tsetattr valid bits
+const SETATTR_MODE: u32 = 1 << 0;
+const SETATTR_UID: u32 = 1 << 1;
+const SETATTR_GID: u32 = 1 << 2;
+const SETATTR_SIZE: u32 = 1 << 3;
+const SETATTR_ATIME: u32 = 1 << 4;
+const SETATTR_MTIME: u32 = 1 << 5;
+const SETATTR_ATIME_SET: u32 = 1 << 7;
+const SETATTR_MTIME_SET: u32 = 1 << 8;
+
+/// The fields of Rgetattr that are filled in: everything up to the block
+/// count.
+const GETATTR_BASIC: u64 = 0x7ff;
+
+const V9FS_MAGIC: u32 = 0x0102_1997;
+const LOCK_SUCCESS: u8 = 0;
+const F_UNLCK: u8 = 2;
+
+type Result<T> = std::result::Result<T, u32>;
+
+fn errno(e: io::Error) -> u32 {
+    e.raw_os_error().map_or(EIO, |code| code as u32)
+}
+
+/// Reads the fields of a T-message in order.
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl Reader<'_> {
+    fn bytes(&mut self, len: usize) -> Result<&[u8]> {
+        if self.data.len() < len {
+            return Err(EINVAL);
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let len = self.u16()? as usize;
+        String::from_utf8(self.bytes(len)?.to_vec()).map_err(|_| EINVAL)
+    }
+
+    /// A single path component to look up or create in a directory.
+    fn name(&mut self) -> Result<String> {
+        let name = self.string()?;
+        if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+            return Err(EINVAL);
+        }
+        Ok(name)
+    }
+}
+
+/// Builds the body of an R-message.
+#[derive(Default)]
+struct Writer {
+    data: Vec<u8>,
+}
+
+impl Writer {
+    fn u8(&mut self, value: u8) -> &mut Self {
+        self.data.push(value);
+        self
+    }
+
+    fn u16(&mut self, value: u16) -> &mut Self {
+        self.data.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn u32(&mut self, value: u32) -> &mut Self {
+        self.data.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn u64(&mut self, value: u64) -> &mut Self {
+        self.data.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn string(&mut self, value: &str) -> &mut Self {
+        self.u16(value.len() as u16);
+        self.data.extend_from_slice(value.as_bytes());
+        self
+    }
+
+    fn qid(&mut self, metadata: &Metadata) -> &mut Self {
+        let kind = if metadata.is_dir() {
+            QTDIR
+        } else if metadata.is_symlink() {
+            QTSYMLINK
+        } else {
+            QTFILE
+        };
+        self.u8(kind).u32(0).u64(metadata.ino())
+    }
+}
+
+/// A file the guest refers to by number.
+struct Fid {
+    /// Relative to the shared directory, with only normal components.
+    path: PathBuf,
+    /// Set once opened, unless it is a directory.
+    file: Option<File>,
+    /// The flags it was opened with, to open it again on restore.
+    flags: Option<u32>,
+    /// Names and types of a directory's entries, listed when the guest
+    /// starts reading it.
+    entries: Vec<(String, Metadata)>,
+}
+
+impl Fid {
+    fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            file: None,
+            flags: None,
+            entries: Vec::new(),
+        }
+    }
+}
+
+/// A host directory shared with the guest over 9P2000.L, as QEMU's
+/// `virtio-9p` does. Linux mounts it with
+/// `mount -t 9p -o trans=virtio,version=9p2000.L <tag> <dir>`.
+///
+/// Files keep the host's owner and permissions, and the guest acts with
+/// those of the user running the emulator. The host never follows a
+/// symbolic link that is the last name of a path, and paths whose directory
+/// resolves outside the share are refused, so links the guest makes cannot
+/// lead out of it.
+pub struct Share9p {
+    root: PathBuf,
+    tag: String,
+    msize: u32,
+    fids: HashMap<u32, Fid>,
+}
+
+impl Share9p {
+    pub fn new(share: &Share) -> io::Result<Self> {
+        let root = fs::canonicalize(&share.path).map_err(|e| with_path(&share.path, e))?;
+        if !root.is_dir() {
+            return Err(with_path(
+                &share.path,
+                io::Error::new(io::ErrorKind::InvalidInput, "not a directory"),
+            ));
+        }
+        Ok(Self {
+            root,
+            tag: share.tag.clone(),
+            msize: MAX_MSIZE,
+            fids: HashMap::new(),
+        })
+    }
+
+    fn fid(&mut self, fid: u32) -> Result<&mut Fid> {
+        self.fids.get_mut(&fid).ok_or(EBADF)
+    }
+
+    /// The host path of a fid, or of `name` in the directory it refers to.
+    fn host_path(&self, fid: u32, name: Option<&str>) -> Result<PathBuf> {
+        let mut path = self.fids.get(&fid).ok_or(EBADF)?.path.clone();
+        if let Some(name) = name {
+            path.push(name);
+        }
+        self.resolve(&path)
+    }
+
+    /// The host path of a path relative to the share. The directory it is
+    /// in is resolved, and must still be inside the share; the last name is
+    /// left alone, so a symbolic link there is not followed.
+    fn resolve(&self, relative: &Path) -> Result<PathBuf> {
+        let (Some(dir), Some(name)) = (relative.parent(), relative.file_name()) else {
+            return Ok(self.root.clone());
+        };
+        let dir = fs::canonicalize(self.root.join(dir)).map_err(errno)?;
+        if !dir.starts_with(&self.root) {
+            return Err(EACCES);
+        }
+        Ok(dir.join(name))
+    }
+
+    fn metadata(path: &Path) -> Result<Metadata> {
+        fs::symlink_metadata(path).map_err(errno)
+    }
+
+    /// Handles one T-message and returns the R-message.
+    fn handle(&mut self, request: &[u8]) -> Vec<u8> {
+        if request.len() < HEADER_SIZE {
+            return Vec::new();
+        }
+        let kind = request[4];
+        let tag = u16::from_le_bytes([request[5], request[6]]);
+        let mut body = Writer::default();
+        let reply = match self.dispatch(
+            kind,
+            &mut Reader {
+                data: &request[HEADER_SIZE..],
+            },
+            &mut body,
+        ) {
+            Ok(()) => kind + 1,
+            Err(code) => {
+                body = Writer::default();
+                body.u32(code);
+                RLERROR
+            }
+        };
+
+        let mut message = Vec::with_capacity(HEADER_SIZE + body.data.len());
+        message.extend_from_slice(&((HEADER_SIZE + body.data.len()) as u32).to_le_bytes());
+        message.push(reply);
+        message.extend_from_slice(&tag.to_le_bytes());
+        message.extend_from_slice(&body.data);
+        message
+    }
+
+    fn dispatch(&mut self, kind: u8, r: &mut Reader, w: &mut Writer) -> Result<()> {
+        match kind {
+            TVERSION => {
+                let msize = r.u32()?;
+                let version = r.string()?;
+                self.fids.clear();
+                self.msize = msize.min(MAX_MSIZE);
+                let version = if version.starts_with(VERSION) { VERSION } else { "unknown" };
+                w.u32(self.msize).string(version);
+            }
+            TATTACH => {
+                let fid = r.u32()?;
+                let metadata = Self::metadata(&self.root)?;
+                self.fids.insert(fid, Fid::new(PathBuf::new()));
+                w.qid(&metadata);
+            }
+            TWALK => self.walk(r, w)?,
+            TGETATTR => {
+                let path = self.host_path(r.u32()?, None)?;
+                let m = Self::metadata(&path)?;
+                w.u64(GETATTR_BASIC).qid(&m);
+                w.u32(m.mode()).u32(m.uid()).u32(m.gid()).u64(m.nlink());
+                w.u64(m.rdev()).u64(m.size()).u64(m.blksize()).u64(m.blocks());
+                w.u64(m.atime() as u64).u64(m.atime_nsec() as u64);
+                w.u64(m.mtime() as u64).u64(m.mtime_nsec() as u64);
+                w.u64(m.ctime() as u64).u64(m.ctime_nsec() as u64);
+                // Birth time, generation and data version
+                w.u64(0).u64(0).u64(0).u64(0);
+            }
+            TSETATTR => self.setattr(r)?,
+            TLOPEN => {
+                let fid = r.u32()?;
+                let flags = r.u32()?;
+                let path = self.host_path(fid, None)?;
+                let metadata = Self::metadata(&path)?;
+                if !metadata.is_dir() {
+                    let file = open_options(flags).open(&path).map_err(errno)?;
+                    let fid = self.fid(fid)?;
+                    fid.file = Some(file);
+                    fid.flags = Some(flags);
+                }
+                w.qid(&metadata).u32(0);
+            }
+            TLCREATE => {
+                let fid = r.u32()?;
+                let name = r.name()?;
+                let flags = r.u32()?;
+                let mode = r.u32()?;
+                let path = self.host_path(fid, Some(&name))?;
+                let mut options = open_options(flags);
+                // Creating needs write access, even for a file the guest
+                // only reads from.
+                options.write(true);
+                if flags & O_EXCL != 0 {
+                    options.create_new(true);
+                } else {
+                    options.create(true);
+                }
+                let file = options.mode(mode & 0o7777).open(&path).map_err(errno)?;
+                let metadata = file.metadata().map_err(errno)?;
+                // The fid now refers to the new file.
+                let fid = self.fid(fid)?;
+                fid.path.push(&name);
+                fid.file = Some(file);
+                fid.flags = Some(flags);
+                w.qid(&metadata).u32(0);
+            }
+            TSYMLINK => {
+                let fid = r.u32()?;
+                let name = r.name()?;
+                let target = r.string()?;
+                let path = self.host_path(fid, Some(&name))?;
+                std::os::unix::fs::symlink(target, &path).map_err(errno)?;
+                w.qid(&Self::metadata(&path)?);
+            }
+            TMKDIR => {
+                let fid = r.u32()?;
+                let name = r.name()?;
+                let mode = r.u32()?;
+                let path = self.host_path(fid, Some(&name))?;
+                DirBuilder::new().mode(mode & 0o7777).create(&path).map_err(errno)?;
+                w.qid(&Self::metadata(&path)?);
+            }
+            TLINK => {
+                let dir = r.u32()?;
+                let fid = r.u32()?;
+                let name = r.name()?;
+                let target = self.host_path(fid, None)?;
+                let path = self.host_path(dir, Some(&name))?;
+                fs::hard_link(target, path).map_err(errno)?;
+            }
+            TREADLINK => {
+                let path = self.host_path(r.u32()?, None)?;
+                let target = fs::read_link(path).map_err(errno)?;
+                w.string(&target.to_string_lossy());
+            }
+            TRENAME => {
+                let fid = r.u32()?;
+                let dir = r.u32()?;
+                let name = r.name()?;
+                let from = self.host_path(fid, None)?;
+                let to = self.host_path(dir, Some(&name))?;
+                fs::rename(from, to).map_err(errno)?;
+                let new = self.fid(dir)?.path.join(&name);
+                self.fid(fid)?.path = new;
+            }
+            TRENAMEAT => {
+                let old_dir = r.u32()?;
+                let old_name = r.name()?;
+                let new_dir = r.u32()?;
+                let new_name = r.name()?;
+                let from = self.host_path(old_dir, Some(&old_name))?;
+                let to = self.host_path(new_dir, Some(&new_name))?;
+                fs::rename(from, to).map_err(errno)?;
+            }
+            TUNLINKAT => {
+                let dir = r.u32()?;
+                let name = r.name()?;
+                let flags = r.u32()?;
+                let path = self.host_path(dir, Some(&name))?;
+                if flags & AT_REMOVEDIR != 0 {
+                    fs::remove_dir(path).map_err(errno)?;
+                } else {
+                    fs::remove_file(path).map_err(errno)?;
+                }
+            }
+            TREMOVE => {
+                let fid = r.u32()?;
+                let path = self.host_path(fid, None)?;
+                self.fids.remove(&fid);
+                if Self::metadata(&path)?.is_dir() {
+                    fs::remove_dir(path).map_err(errno)?;
+                } else {
+                    fs::remove_file(path).map_err(errno)?;
+                }
+            }
+            TREADDIR => self.readdir(r, w)?,
+            TREAD => {
+                let fid = r.u32()?;
+                let offset = r.u64()?;
+                let count = r.u32()?.min(self.msize.saturating_sub(RREAD_HEADER_SIZE));
+                let file = self.fid(fid)?.file.as_ref().ok_or(EBADF)?;
+                let mut data = vec![0; count as usize];
+                let mut len = 0;
+                while len < data.len() {
+                    match file.read_at(&mut data[len..], offset + len as u64) {
+                        Ok(0) => break,
+                        Ok(n) => len += n,
+                        Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                        Err(e) => return Err(errno(e)),
+                    }
+                }
+                w.u32(len as u32);
+                w.data.extend_from_slice(&data[..len]);
+            }
+            TWRITE => {
+                let fid = r.u32()?;
+                let offset = r.u64()?;
+                let count = r.u32()?;
+                let data = r.bytes(count as usize)?;
+                let file = self.fid(fid)?.file.as_ref().ok_or(EBADF)?;
+                file.write_all_at(data, offset).map_err(errno)?;
+                w.u32(count);
+            }
+            TFSYNC => {
+                let fid = r.u32()?;
+                if let Some(file) = &self.fid(fid)?.file {
+                    file.sync_all().map_err(errno)?;
+                }
+            }
+            TCLUNK => {
+                self.fids.remove(&r.u32()?).ok_or(EBADF)?;
+            }
+            TSTATFS => {
+                self.fid(r.u32()?)?;
+                // The host's figures are not available without libc; report
+                // a large, mostly empty file system.
+                w.u32(V9FS_MAGIC).u32(4096);
+                w.u64(1 << 28).u64(1 << 28).u64(1 << 28);
+                w.u64(1 << 24).u64(1 << 24);
+                w.u64(0).u32(255);
+            }
+            // Locks are advisory, and the guest is the only user it knows
+            // about.
+            TLOCK => {
+                w.u8(LOCK_SUCCESS);
+            }
+            TGETLOCK => {
+                r.u32()?;
+                r.u8()?;
+                let start = r.u64()?;
+                let length = r.u64()?;
+                let proc_id = r.u32()?;
+                let client_id = r.string()?;
+                w.u8(F_UNLCK).u64(start).u64(length).u32(proc_id).string(&client_id);
+            }
+            TFLUSH => {}
+            // Tauth, Txattrwalk, Tmknod and the like
+            _ => return Err(EOPNOTSUPP),
+        }
+        Ok(())
+    }
+
+    /// Walks from a fid through a list of names and returns a qid for each
+    /// one found. The new fid is only made if all of them were.
+    fn walk(&mut self, r: &mut Reader, w: &mut Writer) -> Result<()> {
+        let fid = r.u32()?;
+        let new_fid = r.u32()?;
+        let names = (0..r.u16()?).map(|_| r.string()).collect::<Result<Vec<String>>>()?;
+        let mut path = self.fid(fid)?.path.clone();
+        let mut qids = Writer::default();
+        let mut found = 0;
+        for name in &names {
+            let mut next = path.clone();
+            match name.as_str() {
+                "." => {}
+                // Never above the root of the share
+                ".." => {
+                    next.pop();
+                }
+                _ if name.is_empty() || name.contains('/') => break,
+                _ => next.push(name),
+            }
+            let Ok(metadata) = self.resolve(&next).and_then(|path| Self::metadata(&path)) else {
+                break;
+            };
+            qids.qid(&metadata);
+            path = next;
+            found += 1;
+        }
+
+        if found == 0 && !names.is_empty() {
+            return Err(ENOENT);
+        }
+        if found == names.len() {
+            self.fids.insert(new_fid, Fid::new(path));
+        }
+        w.u16(found as u16);
+        w.data.extend_from_slice(&qids.data);
+        Ok(())
+    }
+
+    fn setattr(&mut self, r: &mut Reader) -> Result<()> {
+        let fid = r.u32()?;
+        let valid = r.u32()?;
+        let mode = r.u32()?;
+        let uid = r.u32()?;
+        let gid = r.u32()?;
+        let size = r.u64()?;
+        let atime = Duration::new(r.u64()?, r.u64()? as u32);
+        let mtime = Duration::new(r.u64()?, r.u64()? as u32);
+        let path = self.host_path(fid, None)?;
+
+        if valid & SETATTR_MODE != 0 {
+            // Changing the mode of a link would change that of its target.
+            if Self::metadata(&path)?.is_symlink() {
+                return Err(EOPNOTSUPP);
+            }
+            fs::set_permissions(&path, fs::Permissions::from_mode(mode & 0o7777)).map_err(errno)?;
+        }
+        if valid & (SETATTR_UID | SETATTR_GID) != 0 {
+            let uid = (valid & SETATTR_UID != 0).then_some(uid);
+            let gid = (valid & SETATTR_GID != 0).then_some(gid);
+            std::os::unix::fs::lchown(&path, uid, gid).map_err(errno)?;
+        }
+        if valid & SETATTR_SIZE != 0 {
+            let file = open_options(O_WRONLY).open(&path).map_err(errno)?;
+            file.set_len(size).map_err(errno)?;
+        }
+        if valid & (SETATTR_ATIME | SETATTR_MTIME) != 0 {
+            let now = SystemTime::now();
+            let mut times = FileTimes::new();
+            if valid & SETATTR_ATIME != 0 {
+                times = times.set_accessed(match valid & SETATTR_ATIME_SET {
+                    0 => now,
+                    _ => UNIX_EPOCH + atime,
+                });
+            }
+            if valid & SETATTR_MTIME != 0 {
+                times = times.set_modified(match valid & SETATTR_MTIME_SET {
+                    0 => now,
+                    _ => UNIX_EPOCH + mtime,
+                });
+            }
+            open_options(0).open(&path).and_then(|file| file.set_times(times)).map_err(errno)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the directory entries after `offset`, the index of the last
+    /// one the guest has seen, that fit in `count` bytes.
+    fn readdir(&mut self, r: &mut Reader, w: &mut Writer) -> Result<()> {
+        let fid = r.u32()?;
+        let offset = r.u64()?;
+        let count = r.u32()?.min(self.msize.saturating_sub(RREAD_HEADER_SIZE)) as usize;
+        let path = self.host_path(fid, None)?;
+        let parent = match path == self.root {
+            true => path.clone(),
+            false => path.parent().unwrap_or(&path).to_path_buf(),
+        };
+        if offset == 0 {
+            let metadata = Self::metadata(&path)?;
+            if !metadata.is_dir() {
+                return Err(ENOTDIR);
+            }
+            let mut entries = vec![
+                (String::from("."), metadata),
+                (String::from(".."), Self::metadata(&parent)?),
+            ];
+            for entry in fs::read_dir(&path).map_err(errno)? {
+                let entry = entry.map_err(errno)?;
+                if let Ok(metadata) = entry.path().symlink_metadata() {
+                    entries.push((entry.file_name().to_string_lossy().into_owned(), metadata));
+                }
+            }
+            self.fid(fid)?.entries = entries;
+        }
+
+        let mut data = Writer::default();
+        for (i, (name, metadata)) in self.fid(fid)?.entries.iter().enumerate().skip(offset as usize) {
+            let kind = if metadata.is_dir() {
+                DT_DIR
+            } else if metadata.is_symlink() {
+                DT_LNK
+            } else {
+                DT_REG
+            };
+            let mut entry = Writer::default();
+            entry.qid(metadata).u64(i as u64 + 1).u8(kind).string(name);
+            if data.data.len() + entry.data.len() > count {
+                break;
+            }
+            data.data.extend_from_slice(&entry.data);
+        }
+        w.u32(data.data.len() as u32);
+        w.data.extend_from_slice(&data.data);
+        Ok(())
+    }
+}
+
+/// Options to open a file with the guest's flags, never through a symbolic
+/// link.
+fn open_options(flags: u32) -> OpenOptions {
+    let mut options = OpenOptions::new();
+    options.custom_flags(HOST_O_NOFOLLOW);
+    match flags & O_ACCMODE {
+        O_WRONLY => options.write(true),
+        O_RDWR => options.read(true).write(true),
+        _ => options.read(true),
+    };
+    if flags & O_TRUNC != 0 {
+        options.truncate(true);
+    }
+    if flags & O_APPEND != 0 {
+        options.append(true);
+    }
+    options
+}
+
+impl VirtioDevice for Share9p {
+    fn device_id(&self) -> u32 {
+        VIRTIO_ID_9P
+    }
+
+    fn name(&self) -> &'static str {
+        "9p"
+    }
+
+    fn features(&self) -> u64 {
+        VIRTIO_9P_MOUNT_TAG
+    }
+
+    fn queues(&self) -> usize {
+        1
+    }
+
+    /// The length of the mount tag, then the tag.
+    fn config(&self) -> Vec<u8> {
+        let mut config = (self.tag.len() as u16).to_le_bytes().to_vec();
+        config.extend_from_slice(self.tag.as_bytes());
+        config
+    }
+
+    fn notify(&mut self, _queue: usize, vq: &mut Virtqueue, dram: &mut Dram) {
+        while let Some(chain) = vq.pop(dram) {
+            let reply = self.handle(&chain.read(dram));
+            let written = chain.write(dram, 0, &reply);
+            vq.push(dram, chain, written as u32);
+        }
+    }
+
+    fn reset(&mut self) {
+        self.fids.clear();
+    }
+
+    /// The fids by path. Open files are opened again on restore, without
+    /// truncating them; one that has gone in the meantime fails to read.
+    fn save(&self, out: &mut StateWriter) {
+        out.u32(self.msize);
+        out.u64(self.fids.len() as u64);
+        let mut numbers: Vec<&u32> = self.fids.keys().collect();
+        numbers.sort();
+        for number in numbers {
+            let fid = &self.fids[number];
+            out.u32(*number);
+            out.bytes(fid.path.as_os_str().as_encoded_bytes());
+            out.bool(fid.flags.is_some());
+            out.u32(fid.flags.unwrap_or(0));
+        }
+    }
+
+    fn restore(&mut self, input: &mut StateReader) -> io::Result<()> {
+        self.msize = input.u32()?;
+        self.fids.clear();
+        for _ in 0..input.u64()? {
+            let number = input.u32()?;
+            let path = String::from_utf8_lossy(input.bytes()?).into_owned();
+            let opened = input.bool()?;
+            let flags = input.u32()?;
+            let mut fid = Fid::new(PathBuf::from(path));
+            if opened {
+                let path = self.resolve(&fid.path).ok();
+                fid.file = path.and_then(|path| open_options(flags & !O_TRUNC).open(path).ok());
+                fid.flags = Some(flags);
+            }
+            self.fids.insert(number, fid);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::*;
+    use crate::testing::*;
+
+    /// A share of a new directory holding `hello.txt`.
+    fn new_share(name: &str) -> (Share9p, PathBuf) {
+        let dir = temp_path(name);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("hello.txt"), "hello").unwrap();
+        let share = Share9p::new(&Share { tag: "host".into(), path: dir.clone() }).unwrap();
+        (share, dir)
+    }
+
+    /// Sends a T-message whose body `build` writes, and returns the body of
+    /// the reply or the error number.
+    fn call(share: &mut Share9p, kind: u8, build: impl FnOnce(&mut Writer)) -> Result<Vec<u8>> {
+        let mut body = Writer::default();
+        build(&mut body);
+        let mut message = ((HEADER_SIZE + body.data.len()) as u32).to_le_bytes().to_vec();
+        message.push(kind);
+        message.extend_from_slice(&7u16.to_le_bytes());
+        message.extend_from_slice(&body.data);
+
+        let reply = share.handle(&message);
+        assert_eq!(reply[..4], (reply.len() as u32).to_le_bytes());
+        assert_eq!(reply[5..7], 7u16.to_le_bytes());
+        let body = reply[HEADER_SIZE..].to_vec();
+        match reply[4] {
+            RLERROR => Err(u32::from_le_bytes(body.try_into().unwrap())),
+            reply => {
+                assert_eq!(reply, kind + 1);
+                Ok(body)
+            }
+        }
+    }
+
+    /// Attaches fid 0 to the root and walks fid `new` to `names` from it.
+    fn walk(share: &mut Share9p, new: u32, names: &[&str]) -> Result<Vec<u8>> {
+        call(share, TWALK, |w| {
+            w.u32(0).u32(new).u16(names.len() as u16);
+            for name in names {
+                w.string(name);
+            }
+        })
+    }
+
+    /// The names in an Rreaddir, in order.
+    fn names(mut body: &[u8]) -> Vec<String> {
+        let mut names = Vec::new();
+        body = &body[4..];
+        while !body.is_empty() {
+            let len = u16::from_le_bytes([body[22], body[23]]) as usize;
+            names.push(String::from_utf8(body[24..24 + len].to_vec()).unwrap());
+            body = &body[24 + len..];
+        }
+        names
+    }
+
+    #[test]
+    fn negotiates_over_the_queue() {
+        let (share, dir) = new_share("9p-queue");
+        let mut driver = Driver::new(Box::new(share));
+        // The mount tag
+        assert_eq!(driver.transport.load(0x100, 16), Ok(4));
+        assert_eq!(driver.transport.load(0x102, 32), Ok(u32::from_le_bytes(*b"host") as u64));
+
+        let mut version = Writer::default();
+        version.u32(1 << 30).string("9P2000.L");
+        let header = [&(21u32.to_le_bytes())[..], &[TVERSION], &0xffffu16.to_le_bytes()].concat();
+        driver.submit(0, &[&header, &version.data], &[64]);
+        driver.notify(0);
+        let (len, reply) = driver.used(0).unwrap();
+        let mut expected = Writer::default();
+        expected.u32(21).u8(TVERSION + 1).u16(0xffff).u32(MAX_MSIZE).string("9P2000.L");
+        assert_eq!(reply[..len as usize], expected.data);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn walks_opens_and_reads() {
+        let (mut share, dir) = new_share("9p-read");
+        call(&mut share, TVERSION, |w| {
+            w.u32(8192).string("9P2000.u");
+        })
+        .unwrap();
+        assert_eq!(share.msize, 8192);
+        call(&mut share, TATTACH, |w| {
+            w.u32(0).u32(!0).string("user").string("").u32(0);
+        })
+        .unwrap();
+
+        let walked = walk(&mut share, 1, &["hello.txt"]).unwrap();
+        // One qid, for a file
+        assert_eq!(walked[..3], [1, 0, QTFILE]);
+        call(&mut share, TLOPEN, |w| {
+            w.u32(1).u32(0);
+        })
+        .unwrap();
+        let read = call(&mut share, TREAD, |w| {
+            w.u32(1).u64(1).u32(100);
+        });
+        assert_eq!(read.unwrap(), [&4u32.to_le_bytes()[..], b"ello"].concat());
+
+        // Walks stop at the first missing name, and never leave the share.
+        assert_eq!(walk(&mut share, 2, &["missing"]), Err(ENOENT));
+        assert_eq!(walk(&mut share, 2, &["hello.txt", "missing"]).unwrap()[..2], [1, 0]);
+        assert_eq!(share.fid(2).err(), Some(EBADF));
+        walk(&mut share, 2, &["..", ".."]).unwrap();
+        assert_eq!(share.fids[&2].path, PathBuf::new());
+        assert_eq!(walk(&mut share, 3, &["a/b"]), Err(ENOENT));
+
+        // Unopened and clunked fids cannot be read.
+        let read = |share: &mut Share9p, fid| {
+            call(share, TREAD, |w| {
+                w.u32(fid).u64(0).u32(1);
+            })
+        };
+        assert_eq!(read(&mut share, 2), Err(EBADF));
+        call(&mut share, TCLUNK, |w| {
+            w.u32(1);
+        })
+        .unwrap();
+        assert_eq!(read(&mut share, 1), Err(EBADF));
+        assert_eq!(call(&mut share, 99, |_| {}), Err(EOPNOTSUPP));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn creates_lists_and_removes() {
+        let (mut share, dir) = new_share("9p-write");
+        call(&mut share, TATTACH, |w| {
+            w.u32(0);
+        })
+        .unwrap();
+        call(&mut share, TMKDIR, |w| {
+            w.u32(0).string("sub").u32(0o755).u32(0);
+        })
+        .unwrap();
+        walk(&mut share, 1, &["sub"]).unwrap();
+        // Tlcreate turns the directory's fid into one for the new file.
+        call(&mut share, TLCREATE, |w| {
+            w.u32(1).string("new.txt").u32(O_RDWR).u32(0o644).u32(0);
+        })
+        .unwrap();
+        let written = call(&mut share, TWRITE, |w| {
+            w.u32(1).u64(0).u32(3).data.extend_from_slice(b"abc");
+        });
+        assert_eq!(written.unwrap(), 3u32.to_le_bytes());
+        assert_eq!(fs::read(dir.join("sub/new.txt")).unwrap(), b"abc");
+        let bad_name = call(&mut share, TLCREATE, |w| {
+            w.u32(0).string("../escape").u32(O_RDWR).u32(0o644).u32(0);
+        });
+        assert_eq!(bad_name, Err(EINVAL));
+
+        walk(&mut share, 2, &[]).unwrap();
+        call(&mut share, TLOPEN, |w| {
+            w.u32(2).u32(0);
+        })
+        .unwrap();
+        let listing = call(&mut share, TREADDIR, |w| {
+            w.u32(2).u64(0).u32(4096);
+        });
+        let mut listed = names(&listing.unwrap());
+        listed[2..].sort();
+        assert_eq!(listed, [".", "..", "hello.txt", "sub"]);
+        // Starting after the entries seen, with room for one more
+        let listing = call(&mut share, TREADDIR, |w| {
+            w.u32(2).u64(1).u32(30);
+        });
+        assert_eq!(names(&listing.unwrap()), [".."]);
+
+        let unlink = |share: &mut Share9p, name: &str, flags| {
+            call(share, TUNLINKAT, |w| {
+                w.u32(0).string(name).u32(flags);
+            })
+        };
+        assert!(unlink(&mut share, "sub", AT_REMOVEDIR).is_err());
+        call(&mut share, TREMOVE, |w| {
+            w.u32(1);
+        })
+        .unwrap();
+        unlink(&mut share, "sub", AT_REMOVEDIR).unwrap();
+        unlink(&mut share, "hello.txt", 0).unwrap();
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn symbolic_links_stay_inside_the_share() {
+        let (mut share, dir) = new_share("9p-symlink");
+        call(&mut share, TATTACH, |w| {
+            w.u32(0);
+        })
+        .unwrap();
+        for (name, target) in [("x", "/"), ("y", "/etc/passwd"), ("z", "hello.txt")] {
+            let created = call(&mut share, TSYMLINK, |w| {
+                w.u32(0).string(name).string(target).u32(0);
+            });
+            assert_eq!(created.unwrap()[0], QTSYMLINK);
+        }
+
+        // The link itself can be walked to, but not through.
+        assert_eq!(walk(&mut share, 1, &["x", "etc", "passwd"]).unwrap()[..3], [1, 0, QTSYMLINK]);
+        assert_eq!(share.fid(1).err(), Some(EBADF));
+        walk(&mut share, 1, &["x"]).unwrap();
+        assert_eq!(share.host_path(1, Some("etc")), Err(EACCES));
+        let listing = call(&mut share, TREADDIR, |w| {
+            w.u32(1).u64(0).u32(4096);
+        });
+        assert_eq!(listing, Err(ENOTDIR));
+
+        // Links are not followed when opened, even to files in the share.
+        for (fid, name) in [(2, "y"), (3, "z")] {
+            walk(&mut share, fid, &[name]).unwrap();
+            let opened = call(&mut share, TLOPEN, |w| {
+                w.u32(fid).u32(0);
+            });
+            assert!(opened.is_err());
+        }
+        let chmod = call(&mut share, TSETATTR, |w| {
+            w.u32(2).u32(SETATTR_MODE).u32(0o777).u32(0).u32(0).u64(0);
+            w.u64(0).u64(0).u64(0).u64(0);
+        });
+        assert_eq!(chmod, Err(EOPNOTSUPP));
+        assert_eq!(fs::read_link(dir.join("x")).unwrap(), PathBuf::from("/"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn open_files_survive_a_snapshot() {
+        let (mut share, dir) = new_share("9p-snapshot");
+        call(&mut share, TATTACH, |w| {
+            w.u32(0);
+        })
+        .unwrap();
+        walk(&mut share, 1, &["hello.txt"]).unwrap();
+        call(&mut share, TLOPEN, |w| {
+            w.u32(1).u32(O_WRONLY | O_APPEND);
+        })
+        .unwrap();
+        walk(&mut share, 2, &[]).unwrap();
+        call(&mut share, TLCREATE, |w| {
+            w.u32(2).string("new.txt").u32(O_RDWR | O_TRUNC).u32(0o644).u32(0);
+        })
+        .unwrap();
+        let mut out = StateWriter::default();
+        share.save(&mut out);
+
+        let (mut restored, _) = new_share("9p-snapshot");
+        restored.restore(&mut StateReader::new(&out.data)).unwrap();
+        for fid in [1, 2] {
+            call(&mut restored, TWRITE, |w| {
+                w.u32(fid).u64(0).u32(1).data.push(b'!');
+            })
+            .unwrap();
+        }
+        assert_eq!(fs::read(dir.join("hello.txt")).unwrap(), b"hello!");
+        assert_eq!(fs::read(dir.join("new.txt")).unwrap(), b"!");
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,353 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::dram::*;
+use crate::virtio::*;
+
+const VIRTIO_ID_BLOCK: u32 = 2;
+
+const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+
+// Request types
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+const VIRTIO_BLK_T_GET_ID: u32 = 8;
+
+// Request status
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_IOERR: u8 = 1;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+pub const SECTOR_SIZE: u64 = 512;
+
+/// Type, reserved and sector fields that start every request.
+const HEADER_SIZE: usize = 16;
+/// Length of the serial number returned by VIRTIO_BLK_T_GET_ID.
+const ID_SIZE: usize = 20;
+
+const OVERLAY_MAGIC: &[u8; 8] = b"RVEMUCOW";
+const OVERLAY_VERSION: u32 = 1;
+/// Magic, version, padding and the number of sectors.
+const OVERLAY_HEADER_SIZE: u64 = 24;
+
+/// Sectors written since the base image was opened, kept in a separate host
+/// file so the base image is never modified.
+///
+/// The file holds a header, a bitmap with one bit per sector of the base
+/// image, then every sector at a fixed offset. Sectors that were never
+/// written are holes, so the file stays sparse.
+struct Overlay {
+    file: File,
+    bitmap: Vec<u8>,
+    data_offset: u64,
+}
+
+impl Overlay {
+    /// Opens an overlay for an image of `sectors` sectors, creating it if
+    /// it does not exist.
+    fn open(path: &Path, sectors: u64) -> io::Result<Self> {
+        let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message);
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+        let bitmap_size = sectors.div_ceil(8);
+        let data_offset = (OVERLAY_HEADER_SIZE + bitmap_size).next_multiple_of(SECTOR_SIZE);
+
+        if file.metadata()?.len() == 0 {
+            let mut header = OVERLAY_MAGIC.to_vec();
+            header.extend_from_slice(&OVERLAY_VERSION.to_le_bytes());
+            header.extend_from_slice(&[0; 4]);
+            header.extend_from_slice(&sectors.to_le_bytes());
+            file.write_all(&header)?;
+            file.set_len(data_offset + sectors * SECTOR_SIZE)?;
+            return Ok(Self {
+                file,
+                bitmap: vec![0; bitmap_size as usize],
+                data_offset,
+            });
+        }
+
+        let mut header = [0; OVERLAY_HEADER_SIZE as usize];
+        file.read_exact(&mut header)?;
+        if &header[0..8] != OVERLAY_MAGIC {
+            return Err(invalid("not a copy-on-write overlay"));
+        }
+        if u32::from_le_bytes(header[8..12].try_into().unwrap()) != OVERLAY_VERSION {
+            return Err(invalid("unsupported overlay version"));
+        }
+        if u64::from_le_bytes(header[16..24].try_into().unwrap()) != sectors {
+            return Err(invalid("overlay was made for an image of a different size"));
+        }
+        let mut bitmap = vec![0; bitmap_size as usize];
+        file.read_exact(&mut bitmap)?;
+
+        Ok(Self {
+            file,
+            bitmap,
+            data_offset,
+        })
+    }
+
+    fn contains(&self, sector: u64) -> bool {
+        self.bitmap[(sector / 8) as usize] & (1 << (sector % 8)) != 0
+    }
+
+    fn read(&mut self, sector: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(self.data_offset + sector * SECTOR_SIZE))?;
+        self.file.read_exact(buf)
+    }
+
+    fn write(&mut self, sector: u64, buf: &[u8]) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(self.data_offset + sector * SECTOR_SIZE))?;
+        self.file.write_all(buf)?;
+        if !self.contains(sector) {
+            let byte = (sector / 8) as usize;
+            self.bitmap[byte] |= 1 << (sector % 8);
+            self.file.seek(SeekFrom::Start(OVERLAY_HEADER_SIZE + byte as u64))?;
+            self.file.write_all(&self.bitmap[byte..byte + 1])?;
+        }
+        Ok(())
+    }
+}
+
+/// A virtio block device backed by a raw host disk image.
+pub struct Block {
+    image: File,
+    overlay: Option<Overlay>,
+    sectors: u64,
+    id: String,
+}
+
+impl Block {
+    /// Opens `image`, read-only if writes go to an `overlay`. A trailing
+    /// partial sector of the image is not visible to the guest.
+    pub fn open(image: &Path, overlay: Option<&Path>) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(overlay.is_none())
+            .open(image)
+            .map_err(|e| with_path(image, e))?;
+        let sectors = file.metadata()?.len() / SECTOR_SIZE;
+        let overlay = overlay
+            .map(|path| Overlay::open(path, sectors).map_err(|e| with_path(path, e)))
+            .transpose()?;
+        let id = image
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+
+        Ok(Self {
+            image: file,
+            overlay,
+            sectors,
+            id,
+        })
+    }
+
+    fn read_sector(&mut self, sector: u64, buf: &mut [u8]) -> io::Result<()> {
+        match self.overlay.as_mut() {
+            Some(overlay) if overlay.contains(sector) => overlay.read(sector, buf),
+            _ => {
+                self.image.seek(SeekFrom::Start(sector * SECTOR_SIZE))?;
+                self.image.read_exact(buf)
+            }
+        }
+    }
+
+    fn write_sector(&mut self, sector: u64, buf: &[u8]) -> io::Result<()> {
+        match self.overlay.as_mut() {
+            Some(overlay) => overlay.write(sector, buf),
+            None => {
+                self.image.seek(SeekFrom::Start(sector * SECTOR_SIZE))?;
+                self.image.write_all(buf)
+            }
+        }
+    }
+
+    /// Checks that `len` bytes from `sector` on are whole sectors of the disk.
+    fn in_range(&self, sector: u64, len: u64) -> bool {
+        len & (SECTOR_SIZE - 1) == 0
+            && sector
+                .checked_add(len / SECTOR_SIZE)
+                .is_some_and(|end| end <= self.sectors)
+    }
+
+    /// Carries out one request and returns its status and the number of
+    /// bytes written to the chain before the status byte.
+    fn request(&mut self, chain: &Chain, dram: &mut Dram) -> (u8, u64) {
+        let request = chain.read(dram);
+        if request.len() < HEADER_SIZE || chain.writable_len() == 0 {
+            return (VIRTIO_BLK_S_IOERR, 0);
+        }
+        let kind = u32::from_le_bytes(request[0..4].try_into().unwrap());
+        let sector = u64::from_le_bytes(request[8..16].try_into().unwrap());
+        // The last writable byte is the status.
+        let space = chain.writable_len() - 1;
+
+        match kind {
+            VIRTIO_BLK_T_IN => {
+                if !self.in_range(sector, space) {
+                    return (VIRTIO_BLK_S_IOERR, 0);
+                }
+                let mut data = vec![0; space as usize];
+                for (i, buf) in data.chunks_mut(SECTOR_SIZE as usize).enumerate() {
+                    if self.read_sector(sector + i as u64, buf).is_err() {
+                        return (VIRTIO_BLK_S_IOERR, 0);
+                    }
+                }
+                (VIRTIO_BLK_S_OK, chain.write(dram, 0, &data))
+            }
+            VIRTIO_BLK_T_OUT => {
+                let data = &request[HEADER_SIZE..];
+                if !self.in_range(sector, data.len() as u64) {
+                    return (VIRTIO_BLK_S_IOERR, 0);
+                }
+                for (i, buf) in data.chunks(SECTOR_SIZE as usize).enumerate() {
+                    if self.write_sector(sector + i as u64, buf).is_err() {
+                        return (VIRTIO_BLK_S_IOERR, 0);
+                    }
+                }
+                (VIRTIO_BLK_S_OK, 0)
+            }
+            VIRTIO_BLK_T_FLUSH => {
+                let file = match &self.overlay {
+                    Some(overlay) => &overlay.file,
+                    None => &self.image,
+                };
+                match file.sync_data() {
+                    Ok(()) => (VIRTIO_BLK_S_OK, 0),
+                    Err(_) => (VIRTIO_BLK_S_IOERR, 0),
+                }
+            }
+            VIRTIO_BLK_T_GET_ID => {
+                let mut id = self.id.as_bytes().to_vec();
+                id.resize(ID_SIZE, 0);
+                let len = space.min(ID_SIZE as u64) as usize;
+                (VIRTIO_BLK_S_OK, chain.write(dram, 0, &id[..len]))
+            }
+            _ => (VIRTIO_BLK_S_UNSUPP, 0),
+        }
+    }
+}
+
+impl VirtioDevice for Block {
+    fn device_id(&self) -> u32 {
+        VIRTIO_ID_BLOCK
+    }
+
+    fn name(&self) -> &'static str {
+        "blk"
+    }
+
+    fn features(&self) -> u64 {
+        VIRTIO_BLK_F_FLUSH
+    }
+
+    fn queues(&self) -> usize {
+        1
+    }
+
+    /// Only the capacity, in 512-byte sectors.
+    fn config(&self) -> Vec<u8> {
+        self.sectors.to_le_bytes().to_vec()
+    }
+
+    fn notify(&mut self, _queue: usize, vq: &mut Virtqueue, dram: &mut Dram) {
+        while let Some(chain) = vq.pop(dram) {
+            let (status, written) = self.request(&chain, dram);
+            let status_offset = chain.writable_len().saturating_sub(1);
+            chain.write(dram, status_offset, &[status]);
+            vq.push(dram, chain, written as u32 + 1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::device::*;
+    use crate::testing::*;
+
+    fn header(kind: u32, sector: u64) -> Vec<u8> {
+        [&kind.to_le_bytes()[..], &[0; 4], &sector.to_le_bytes()].concat()
+    }
+
+    /// Makes a request with room for `space` bytes of data, and returns its
+    /// status and the data the disk wrote.
+    fn request(driver: &mut Driver, request: &[&[u8]], space: u32) -> (u8, Vec<u8>) {
+        let writable = if space == 0 { vec![1] } else { vec![space, 1] };
+        driver.submit(0, request, &writable);
+        driver.notify(0);
+        let (len, mut written) = driver.used(0).unwrap();
+        let status = written.pop().unwrap();
+        written.truncate(len as usize - 1);
+        (status, written)
+    }
+
+    /// A 4-sector image whose bytes are their sector numbers.
+    fn image(name: &str) -> PathBuf {
+        let path = temp_path(name);
+        let data: Vec<u8> = (0..4).flat_map(|sector| [sector; SECTOR_SIZE as usize]).collect();
+        fs::write(&path, data).unwrap();
+        path
+    }
+
+    #[test]
+    fn reads_and_writes_sectors() {
+        let path = image("blk.img");
+        let mut driver = Driver::new(Box::new(Block::open(&path, None).unwrap()));
+        // The capacity
+        assert_eq!(driver.transport.load(0x100, 64), Ok(4));
+
+        let sector = [7; SECTOR_SIZE as usize];
+        assert_eq!(request(&mut driver, &[&header(VIRTIO_BLK_T_OUT, 2), &sector], 0), (VIRTIO_BLK_S_OK, vec![]));
+        let (status, data) = request(&mut driver, &[&header(VIRTIO_BLK_T_IN, 1)], 2 * SECTOR_SIZE as u32);
+        assert_eq!(status, VIRTIO_BLK_S_OK);
+        assert_eq!(data[..512], [1; 512]);
+        assert_eq!(data[512..], [7; 512]);
+        assert_eq!(fs::read(&path).unwrap()[2 * SECTOR_SIZE as usize], 7);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn rejects_bad_requests() {
+        let path = image("blk-bad.img");
+        let mut driver = Driver::new(Box::new(Block::open(&path, None).unwrap()));
+        let past_the_end = request(&mut driver, &[&header(VIRTIO_BLK_T_IN, 3)], 2 * SECTOR_SIZE as u32);
+        assert_eq!(past_the_end, (VIRTIO_BLK_S_IOERR, vec![]));
+        let partial_sector = request(&mut driver, &[&header(VIRTIO_BLK_T_OUT, 0), &[0; 100]], 0);
+        assert_eq!(partial_sector, (VIRTIO_BLK_S_IOERR, vec![]));
+        assert_eq!(request(&mut driver, &[&header(99, 0)], 0), (VIRTIO_BLK_S_UNSUPP, vec![]));
+        assert_eq!(request(&mut driver, &[&[0; 8]], 0), (VIRTIO_BLK_S_IOERR, vec![]));
+
+        // The serial number is the image's name, cut to length.
+        let (status, id) = request(&mut driver, &[&header(VIRTIO_BLK_T_GET_ID, 0)], ID_SIZE as u32);
+        assert_eq!(status, VIRTIO_BLK_S_OK);
+        assert_eq!(id, path.file_name().unwrap().as_encoded_bytes()[..ID_SIZE]);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn overlay_keeps_the_image_unchanged() {
+        let path = image("blk-base.img");
+        let overlay = temp_path("blk-overlay");
+        let mut driver = Driver::new(Box::new(Block::open(&path, Some(&overlay)).unwrap()));
+        let sector = [9; SECTOR_SIZE as usize];
+        assert_eq!(request(&mut driver, &[&header(VIRTIO_BLK_T_OUT, 0), &sector], 0).0, VIRTIO_BLK_S_OK);
+        assert_eq!(fs::read(&path).unwrap()[0], 0);
+
+        // A disk opened again with the overlay sees the write.
+        drop(driver);
+        let mut block = Block::open(&path, Some(&overlay)).unwrap();
+        let mut buf = [0; SECTOR_SIZE as usize];
+        block.read_sector(0, &mut buf).unwrap();
+        assert_eq!(buf, sector);
+        block.read_sector(1, &mut buf).unwrap();
+        assert_eq!(buf, [1; SECTOR_SIZE as usize]);
+        fs::remove_file(path).unwrap();
+        fs::remove_file(overlay).unwrap();
+    }
+}
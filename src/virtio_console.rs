@@ -0,0 +1,386 @@
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crate::config::*;
+use crate::dram::*;
+use crate::replay::*;
+use crate::snapshot::*;
+use crate::virtio::*;
+
+const VIRTIO_ID_CONSOLE: u32 = 3;
+
+const VIRTIO_CONSOLE_F_MULTIPORT: u64 = 1 << 1;
+
+const CONTROL_RECEIVEQ: usize = 2;
+const CONTROL_TRANSMITQ: usize = 3;
+
+// Control events
+const VIRTIO_CONSOLE_DEVICE_READY: u16 = 0;
+const VIRTIO_CONSOLE_DEVICE_ADD: u16 = 1;
+const VIRTIO_CONSOLE_PORT_READY: u16 = 3;
+const VIRTIO_CONSOLE_CONSOLE_PORT: u16 = 4;
+const VIRTIO_CONSOLE_PORT_OPEN: u16 = 6;
+const VIRTIO_CONSOLE_PORT_NAME: u16 = 7;
+
+/// `struct virtio_console_control`: port id, event and value.
+const CONTROL_SIZE: usize = 8;
+
+/// Where a port's output goes and its input comes from.
+enum Host {
+    /// A program connected to a Unix socket, once one has.
+    Socket {
+        peer: Arc<Mutex<Option<UnixStream>>>,
+        input: Receiver<Vec<u8>>,
+        host: HostInput,
+    },
+    /// A file the output is appended to. There is no input.
+    File(File),
+}
+
+struct Port {
+    name: String,
+    host: Host,
+    /// Input waiting for a receive buffer.
+    rx: VecDeque<u8>,
+}
+
+/// A virtio console with several ports. The first one is the console
+/// (`hvc0` under Linux); the others appear as `/dev/virtio-ports/<name>`.
+pub struct Console {
+    ports: Vec<Port>,
+    /// Control messages waiting for a receive buffer.
+    control: VecDeque<Vec<u8>>,
+    /// Output is dropped while set.
+    muted: bool,
+}
+
+impl Console {
+    pub fn new(ports: &[ConsolePort], log: Option<&SharedLog>) -> io::Result<Self> {
+        let ports = ports
+            .iter()
+            .map(|port| {
+                let host = match &port.backend {
+                    PortBackend::Listen(path) => {
+                        let listener = UnixListener::bind(path).map_err(|e| with_path(path, e))?;
+                        let peer = Arc::new(Mutex::new(None));
+                        let (sender, input) = mpsc::channel();
+                        let shared = Arc::clone(&peer);
+                        // One connection at a time; output is dropped while
+                        // nobody is connected.
+                        thread::spawn(move || {
+                            for mut stream in listener.incoming().flatten() {
+                                let Ok(writer) = stream.try_clone() else {
+                                    continue;
+                                };
+                                *shared.lock().unwrap() = Some(writer);
+                                let mut buf = [0; 4096];
+                                while let Ok(len @ 1..) = stream.read(&mut buf) {
+                                    if sender.send(buf[..len].to_vec()).is_err() {
+                                        return;
+                                    }
+                                }
+                                *shared.lock().unwrap() = None;
+                            }
+                        });
+                        let host = HostInput::new(&format!("console:{}", port.name), log);
+                        Host::Socket { peer, input, host }
+                    }
+                    PortBackend::File(path) => {
+                        let file = OpenOptions::new()
+                            .create(true)
+                            .append(true)
+                            .open(path)
+                            .map_err(|e| with_path(path, e))?;
+                        Host::File(file)
+                    }
+                };
+                Ok(Port {
+                    name: port.name.clone(),
+                    host,
+                    rx: VecDeque::new(),
+                })
+            })
+            .collect::<io::Result<Vec<Port>>>()?;
+
+        Ok(Self {
+            ports,
+            control: VecDeque::new(),
+            muted: false,
+        })
+    }
+
+    /// The port whose receive or transmit queue this is.
+    fn port(queue: usize) -> usize {
+        match queue {
+            0 | 1 => 0,
+            _ => queue / 2 - 1,
+        }
+    }
+
+    fn receive_queue(port: usize) -> usize {
+        match port {
+            0 => 0,
+            _ => 2 + 2 * port,
+        }
+    }
+
+    fn send_control(&mut self, id: usize, event: u16, value: u16, data: &[u8]) {
+        let mut message = Vec::with_capacity(CONTROL_SIZE + data.len());
+        message.extend_from_slice(&(id as u32).to_le_bytes());
+        message.extend_from_slice(&event.to_le_bytes());
+        message.extend_from_slice(&value.to_le_bytes());
+        message.extend_from_slice(data);
+        self.control.push_back(message);
+    }
+
+    /// Handles a message from the driver. Ports are added once the driver
+    /// is ready, then named and opened as it readies each of them.
+    fn handle_control(&mut self, message: &[u8]) {
+        if message.len() < CONTROL_SIZE {
+            return;
+        }
+        let id = u32::from_le_bytes(message[0..4].try_into().unwrap()) as usize;
+        let event = u16::from_le_bytes([message[4], message[5]]);
+        match event {
+            VIRTIO_CONSOLE_DEVICE_READY => {
+                for port in 0..self.ports.len() {
+                    self.send_control(port, VIRTIO_CONSOLE_DEVICE_ADD, 0, &[]);
+                }
+            }
+            VIRTIO_CONSOLE_PORT_READY if id < self.ports.len() => {
+                let name = self.ports[id].name.clone();
+                self.send_control(id, VIRTIO_CONSOLE_PORT_NAME, 0, name.as_bytes());
+                if id == 0 {
+                    self.send_control(id, VIRTIO_CONSOLE_CONSOLE_PORT, 1, &[]);
+                }
+                self.send_control(id, VIRTIO_CONSOLE_PORT_OPEN, 1, &[]);
+            }
+            _ => {}
+        }
+    }
+
+    fn write_host(&mut self, port: usize, data: &[u8]) {
+        if self.muted {
+            return;
+        }
+        match &mut self.ports[port].host {
+            Host::Socket { peer, .. } => {
+                let mut peer = peer.lock().unwrap();
+                if let Some(stream) = peer.as_mut() {
+                    if stream.write_all(data).is_err() {
+                        *peer = None;
+                    }
+                }
+            }
+            Host::File(file) => {
+                let _ = file.write_all(data);
+            }
+        }
+    }
+
+    fn deliver_control(&mut self, vq: &mut Virtqueue, dram: &mut Dram) {
+        while !self.control.is_empty() {
+            let Some(chain) = vq.pop(dram) else {
+                break;
+            };
+            let message = self.control.pop_front().unwrap();
+            let written = chain.write(dram, 0, &message);
+            vq.push(dram, chain, written as u32);
+        }
+    }
+
+    fn deliver_input(&mut self, port: usize, vq: &mut Virtqueue, dram: &mut Dram) {
+        while !self.ports[port].rx.is_empty() {
+            let Some(chain) = vq.pop(dram) else {
+                break;
+            };
+            let rx = &mut self.ports[port].rx;
+            let len = (chain.writable_len() as usize).min(rx.len());
+            let data: Vec<u8> = rx.drain(..len).collect();
+            let written = chain.write(dram, 0, &data);
+            vq.push(dram, chain, written as u32);
+        }
+    }
+}
+
+impl VirtioDevice for Console {
+    fn device_id(&self) -> u32 {
+        VIRTIO_ID_CONSOLE
+    }
+
+    fn name(&self) -> &'static str {
+        "console"
+    }
+
+    fn features(&self) -> u64 {
+        VIRTIO_CONSOLE_F_MULTIPORT
+    }
+
+    /// A receive and transmit queue per port, plus the control queues after
+    /// those of the first port.
+    fn queues(&self) -> usize {
+        2 * (self.ports.len() + 1)
+    }
+
+    /// Columns and rows (unknown), then the number of ports.
+    fn config(&self) -> Vec<u8> {
+        let mut config = vec![0; 4];
+        config.extend_from_slice(&(self.ports.len() as u32).to_le_bytes());
+        config
+    }
+
+    fn notify(&mut self, queue: usize, vq: &mut Virtqueue, dram: &mut Dram) {
+        match queue {
+            CONTROL_RECEIVEQ => self.deliver_control(vq, dram),
+            CONTROL_TRANSMITQ => {
+                while let Some(chain) = vq.pop(dram) {
+                    self.handle_control(&chain.read(dram));
+                    vq.push(dram, chain, 0);
+                }
+            }
+            _ if queue & 1 == 0 => self.deliver_input(Self::port(queue), vq, dram),
+            _ => {
+                while let Some(chain) = vq.pop(dram) {
+                    self.write_host(Self::port(queue), &chain.read(dram));
+                    vq.push(dram, chain, 0);
+                }
+            }
+        }
+    }
+
+    fn poll(&mut self, queues: &mut [Virtqueue], dram: &mut Dram) {
+        self.deliver_control(&mut queues[CONTROL_RECEIVEQ], dram);
+        for port in 0..self.ports.len() {
+            let Port { host, rx, .. } = &mut self.ports[port];
+            if let Host::Socket { input, host, .. } = host {
+                rx.extend(host.poll(|| input.try_iter().collect()).concat());
+            }
+            self.deliver_input(port, &mut queues[Self::receive_queue(port)], dram);
+        }
+    }
+
+    fn reset(&mut self) {
+        self.control.clear();
+    }
+
+    fn mute(&mut self, muted: bool) {
+        self.muted = muted;
+    }
+
+    fn save(&self, out: &mut StateWriter) {
+        out.u64(self.control.len() as u64);
+        for message in &self.control {
+            out.bytes(message);
+        }
+        out.u64(self.ports.len() as u64);
+        for port in &self.ports {
+            out.bytes(&port.rx.iter().copied().collect::<Vec<u8>>());
+        }
+    }
+
+    fn restore(&mut self, input: &mut StateReader) -> io::Result<()> {
+        let messages = input.u64()?;
+        self.control = (0..messages)
+            .map(|_| input.bytes().map(<[u8]>::to_vec))
+            .collect::<io::Result<VecDeque<Vec<u8>>>>()?;
+        input.count(self.ports.len(), "console ports")?;
+        for port in self.ports.iter_mut() {
+            port.rx = input.bytes()?.iter().copied().collect();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::time::{Duration, Instant};
+
+    use super::*;
+    use crate::device::*;
+    use crate::testing::*;
+
+    fn control(id: u32, event: u16, value: u16, data: &[u8]) -> Vec<u8> {
+        [&id.to_le_bytes()[..], &event.to_le_bytes(), &value.to_le_bytes(), data].concat()
+    }
+
+    /// Sends a control message and returns the ones the device answers
+    /// with.
+    fn exchange(driver: &mut Driver, message: &[u8]) -> Vec<Vec<u8>> {
+        for _ in 0..4 {
+            driver.submit(CONTROL_RECEIVEQ, &[], &[64]);
+        }
+        driver.submit(CONTROL_TRANSMITQ, &[message], &[]);
+        driver.notify(CONTROL_TRANSMITQ);
+        driver.used(CONTROL_TRANSMITQ).unwrap();
+        let mut replies = Vec::new();
+        while let Some((len, data)) = driver.used(CONTROL_RECEIVEQ) {
+            replies.push(data[..len as usize].to_vec());
+        }
+        replies
+    }
+
+    #[test]
+    fn ports_are_added_named_and_connected() {
+        let log = temp_path("console.log");
+        let socket = temp_path("console.sock");
+        let ports = [
+            ConsolePort { name: "console".into(), backend: PortBackend::File(log.clone()) },
+            ConsolePort { name: "agent".into(), backend: PortBackend::Listen(socket.clone()) },
+        ];
+        let mut driver = Driver::new(Box::new(Console::new(&ports, None).unwrap()));
+        // The number of ports is in the configuration space.
+        assert_eq!(driver.transport.load(0x104, 32), Ok(2));
+
+        let ready = exchange(&mut driver, &control(0, VIRTIO_CONSOLE_DEVICE_READY, 1, &[]));
+        let added = [0, 1].map(|port| control(port, VIRTIO_CONSOLE_DEVICE_ADD, 0, &[]));
+        assert_eq!(ready, added);
+        let console = exchange(&mut driver, &control(0, VIRTIO_CONSOLE_PORT_READY, 1, &[]));
+        assert_eq!(
+            console,
+            [
+                control(0, VIRTIO_CONSOLE_PORT_NAME, 0, b"console"),
+                control(0, VIRTIO_CONSOLE_CONSOLE_PORT, 1, &[]),
+                control(0, VIRTIO_CONSOLE_PORT_OPEN, 1, &[]),
+            ]
+        );
+        let agent = exchange(&mut driver, &control(1, VIRTIO_CONSOLE_PORT_READY, 1, &[]));
+        assert_eq!(
+            agent,
+            [control(1, VIRTIO_CONSOLE_PORT_NAME, 0, b"agent"), control(1, VIRTIO_CONSOLE_PORT_OPEN, 1, &[])]
+        );
+
+        // The console's output goes to its file.
+        driver.submit(1, &[b"hello\n"], &[]);
+        driver.notify(1);
+        assert_eq!(fs::read(&log).unwrap(), b"hello\n");
+
+        // The other port talks to whoever connects to its socket, on queues
+        // 4 and 5.
+        let mut peer = UnixStream::connect(&socket).unwrap();
+        peer.write_all(b"ping").unwrap();
+        driver.submit(4, &[], &[16]);
+        let start = Instant::now();
+        let (len, data) = loop {
+            driver.transport.dma(&mut driver.dram);
+            if let Some(used) = driver.used(4) {
+                break used;
+            }
+            assert!(start.elapsed() < Duration::from_secs(10), "no input arrived");
+            thread::sleep(Duration::from_millis(1));
+        };
+        assert_eq!(&data[..len as usize], b"ping");
+        driver.submit(5, &[b"pong"], &[]);
+        driver.notify(5);
+        let mut reply = [0; 4];
+        peer.read_exact(&mut reply).unwrap();
+        assert_eq!(&reply, b"pong");
+        fs::remove_file(log).unwrap();
+        fs::remove_file(socket).unwrap();
+    }
+}
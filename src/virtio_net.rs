@@ -0,0 +1,343 @@
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crate::config::*;
+use crate::dram::*;
+use crate::pcap::*;
+use crate::replay::*;
+use crate::snapshot::*;
+use crate::virtio::*;
+
+const VIRTIO_ID_NET: u32 = 1;
+
+const VIRTIO_NET_F_MAC: u64 = 1 << 5;
+
+const RECEIVEQ: usize = 0;
+const TRANSMITQ: usize = 1;
+
+/// `struct virtio_net_hdr`, including `num_buffers` since VIRTIO_F_VERSION_1.
+const NET_HDR_SIZE: usize = 12;
+
+/// Frames held for the guest before new ones are dropped, as a full NIC would.
+const RX_BACKLOG: usize = 1024;
+
+/// Largest frame accepted from the peer: a jumbo frame.
+const MAX_FRAME: usize = 9018;
+
+/// Where the frames the guest sends go, and where the frames it receives
+/// come from.
+enum Backend {
+    /// Another emulator on a Unix socket. Frames are prefixed by their
+    /// length as a 32-bit big-endian number, as with QEMU's stream netdev.
+    Stream {
+        peer: Arc<Mutex<Option<UnixStream>>>,
+        incoming: Receiver<Vec<u8>>,
+        host: HostInput,
+    },
+    /// Frames from a capture file, delivered in order as the guest makes
+    /// receive buffers available. Transmitted frames are dropped.
+    Replay,
+}
+
+/// A virtio network card.
+pub struct Net {
+    backend: Backend,
+    mac: [u8; 6],
+    /// Frames waiting for a receive buffer.
+    rx: VecDeque<Vec<u8>>,
+    capture: Option<PcapWriter>,
+    /// Transmitted frames are dropped while set.
+    muted: bool,
+}
+
+impl Net {
+    /// Connects the card to `backend`. Every frame it sends or receives is
+    /// also written to `capture`, if given.
+    pub fn new(
+        backend: &NetBackend,
+        mac: [u8; 6],
+        capture: Option<&Path>,
+        log: Option<&SharedLog>,
+    ) -> io::Result<Self> {
+        let mut rx = VecDeque::new();
+        let backend = match backend {
+            NetBackend::Listen(path) => {
+                let listener = UnixListener::bind(path).map_err(|e| with_path(path, e))?;
+                let peer = Arc::new(Mutex::new(None));
+                let (sender, incoming) = mpsc::channel();
+                let shared = Arc::clone(&peer);
+                // Frames sent before the peer connects are lost, like on an
+                // unplugged cable.
+                thread::spawn(move || {
+                    if let Ok((stream, _)) = listener.accept() {
+                        if let Ok(writer) = stream.try_clone() {
+                            *shared.lock().unwrap() = Some(writer);
+                            receive_frames(stream, sender);
+                        }
+                    }
+                });
+                Backend::Stream {
+                    peer,
+                    incoming,
+                    host: HostInput::new("net", log),
+                }
+            }
+            NetBackend::Connect(path) => {
+                let stream = UnixStream::connect(path).map_err(|e| with_path(path, e))?;
+                let peer = Arc::new(Mutex::new(Some(stream.try_clone()?)));
+                let (sender, incoming) = mpsc::channel();
+                thread::spawn(move || receive_frames(stream, sender));
+                Backend::Stream {
+                    peer,
+                    incoming,
+                    host: HostInput::new("net", log),
+                }
+            }
+            NetBackend::Replay(path) => {
+                // The guest's own frames are in the capture too if it was
+                // recorded by this device; only replay the ones it received.
+                rx = read_pcap(path)
+                    .map_err(|e| with_path(path, e))?
+                    .into_iter()
+                    .filter(|frame| frame.get(6..12) != Some(&mac[..]))
+                    .collect();
+                Backend::Replay
+            }
+        };
+        let capture = capture
+            .map(|path| PcapWriter::create(path).map_err(|e| with_path(path, e)))
+            .transpose()?;
+
+        Ok(Self {
+            backend,
+            mac,
+            rx,
+            capture,
+            muted: false,
+        })
+    }
+
+    fn record(&mut self, frame: &[u8]) {
+        if let Some(capture) = self.capture.as_mut() {
+            if capture.write(frame).is_err() {
+                eprintln!("rvemu: cannot write the network capture; stopping it");
+                self.capture = None;
+            }
+        }
+    }
+
+    fn transmit(&mut self, frame: &[u8]) {
+        if self.muted {
+            return;
+        }
+        self.record(frame);
+        if let Backend::Stream { peer, .. } = &self.backend {
+            let mut peer = peer.lock().unwrap();
+            if let Some(stream) = peer.as_mut() {
+                let mut message = (frame.len() as u32).to_be_bytes().to_vec();
+                message.extend_from_slice(frame);
+                if stream.write_all(&message).is_err() {
+                    *peer = None;
+                }
+            }
+        }
+    }
+
+    /// Copies waiting frames into the receive buffers the guest made
+    /// available.
+    fn deliver(&mut self, vq: &mut Virtqueue, dram: &mut Dram) {
+        while !self.rx.is_empty() {
+            let Some(chain) = vq.pop(dram) else {
+                break;
+            };
+            let frame = self.rx.pop_front().unwrap();
+            if chain.writable_len() < (NET_HDR_SIZE + frame.len()) as u64 {
+                // Too small for the frame; the guest gets an empty buffer back.
+                vq.push(dram, chain, 0);
+                continue;
+            }
+            self.record(&frame);
+            let mut header = [0; NET_HDR_SIZE];
+            header[10] = 1; // num_buffers
+            chain.write(dram, 0, &header);
+            chain.write(dram, NET_HDR_SIZE as u64, &frame);
+            vq.push(dram, chain, (NET_HDR_SIZE + frame.len()) as u32);
+        }
+    }
+}
+
+/// Forwards the frames arriving on `stream` until the peer goes away.
+fn receive_frames(mut stream: UnixStream, sender: Sender<Vec<u8>>) {
+    let mut len = [0; 4];
+    while stream.read_exact(&mut len).is_ok() {
+        let len = u32::from_be_bytes(len) as usize;
+        if len > MAX_FRAME {
+            break;
+        }
+        let mut frame = vec![0; len];
+        if stream.read_exact(&mut frame).is_err() || sender.send(frame).is_err() {
+            break;
+        }
+    }
+}
+
+impl VirtioDevice for Net {
+    fn device_id(&self) -> u32 {
+        VIRTIO_ID_NET
+    }
+
+    fn name(&self) -> &'static str {
+        "net"
+    }
+
+    fn features(&self) -> u64 {
+        VIRTIO_NET_F_MAC
+    }
+
+    fn queues(&self) -> usize {
+        2
+    }
+
+    /// The MAC address.
+    fn config(&self) -> Vec<u8> {
+        self.mac.to_vec()
+    }
+
+    fn notify(&mut self, queue: usize, vq: &mut Virtqueue, dram: &mut Dram) {
+        match queue {
+            RECEIVEQ => self.deliver(vq, dram),
+            TRANSMITQ => {
+                while let Some(chain) = vq.pop(dram) {
+                    let packet = chain.read(dram);
+                    if packet.len() > NET_HDR_SIZE {
+                        self.transmit(&packet[NET_HDR_SIZE..]);
+                    }
+                    vq.push(dram, chain, 0);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn mute(&mut self, muted: bool) {
+        self.muted = muted;
+    }
+
+    fn poll(&mut self, queues: &mut [Virtqueue], dram: &mut Dram) {
+        if let Backend::Stream { incoming, host, .. } = &mut self.backend {
+            for frame in host.poll(|| incoming.try_iter().collect()) {
+                if self.rx.len() < RX_BACKLOG {
+                    self.rx.push_back(frame);
+                }
+            }
+        }
+        self.deliver(&mut queues[RECEIVEQ], dram);
+    }
+
+    /// Frames waiting for the guest. The peer itself is not saved.
+    fn save(&self, out: &mut StateWriter) {
+        out.u64(self.rx.len() as u64);
+        for frame in &self.rx {
+            out.bytes(frame);
+        }
+    }
+
+    fn restore(&mut self, input: &mut StateReader) -> io::Result<()> {
+        let frames = input.u64()?;
+        self.rx = (0..frames)
+            .map(|_| input.bytes().map(<[u8]>::to_vec))
+            .collect::<io::Result<VecDeque<Vec<u8>>>>()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::time::{Duration, Instant};
+
+    use super::*;
+    use crate::device::*;
+    use crate::testing::*;
+
+    const MAC: [u8; 6] = [0x52, 0x54, 0, 0x12, 0x34, 0x56];
+
+    /// An Ethernet frame from `source` to everyone.
+    fn frame(source: [u8; 6], payload: &[u8]) -> Vec<u8> {
+        [&[0xff; 6][..], &source, &[0x88, 0xb5], payload].concat()
+    }
+
+    fn send(driver: &mut Driver, frame: &[u8]) {
+        driver.submit(TRANSMITQ, &[&[0; NET_HDR_SIZE], frame], &[]);
+        driver.notify(TRANSMITQ);
+        assert_eq!(driver.used(TRANSMITQ), Some((0, vec![])));
+    }
+
+    /// Waits for a frame to arrive in a receive buffer the driver made
+    /// available, and returns it without the header.
+    fn receive(driver: &mut Driver) -> Vec<u8> {
+        let start = Instant::now();
+        loop {
+            driver.transport.dma(&mut driver.dram);
+            if let Some((len, data)) = driver.used(RECEIVEQ) {
+                assert_eq!(data[..NET_HDR_SIZE], [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0]);
+                return data[NET_HDR_SIZE..len as usize].to_vec();
+            }
+            assert!(start.elapsed() < Duration::from_secs(10), "no frame arrived");
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn cards_exchange_frames_over_a_socket() {
+        let socket = temp_path("net.sock");
+        let listen = NetBackend::Listen(socket.clone());
+        let mut a = Driver::new(Box::new(Net::new(&listen, MAC, None, None).unwrap()));
+        let other = [0x52, 0x54, 0, 0x12, 0x34, 0x57];
+        let connect = NetBackend::Connect(socket.clone());
+        let mut b = Driver::new(Box::new(Net::new(&connect, other, None, None).unwrap()));
+        // The MAC address is in the configuration space.
+        assert_eq!(b.transport.load(0x100, 32), Ok(0x1200_5452));
+        assert_eq!(b.transport.load(0x104, 16), Ok(0x5734));
+
+        a.submit(RECEIVEQ, &[], &[1514]);
+        send(&mut b, &frame(other, b"ping"));
+        assert_eq!(receive(&mut a), frame(other, b"ping"));
+        b.submit(RECEIVEQ, &[], &[1514]);
+        send(&mut a, &frame(MAC, b"pong"));
+        assert_eq!(receive(&mut b), frame(MAC, b"pong"));
+        fs::remove_file(socket).unwrap();
+    }
+
+    #[test]
+    fn replays_the_frames_it_received() {
+        let recording = temp_path("net-in.pcap");
+        let capture = temp_path("net-out.pcap");
+        let other = [0x52, 0x54, 0, 0x12, 0x34, 0x57];
+        let mut pcap = PcapWriter::create(&recording).unwrap();
+        for frame in [frame(MAC, b"sent"), frame(other, b"first"), frame(other, b"second")] {
+            pcap.write(&frame).unwrap();
+        }
+
+        let replay = NetBackend::Replay(recording.clone());
+        let mut driver = Driver::new(Box::new(Net::new(&replay, MAC, Some(&capture), None).unwrap()));
+        // A buffer too small for the first frame comes back empty, and the
+        // frame is lost.
+        driver.submit(RECEIVEQ, &[], &[NET_HDR_SIZE as u32 + 8]);
+        driver.submit(RECEIVEQ, &[], &[1514]);
+        driver.notify(RECEIVEQ);
+        assert_eq!(driver.used(RECEIVEQ).unwrap().0, 0);
+        assert_eq!(receive(&mut driver), frame(other, b"second"));
+
+        // What the guest sends goes nowhere but to the capture.
+        send(&mut driver, &frame(MAC, b"reply"));
+        assert_eq!(read_pcap(&capture).unwrap(), [frame(other, b"second"), frame(MAC, b"reply")]);
+        fs::remove_file(recording).unwrap();
+        fs::remove_file(capture).unwrap();
+    }
+}